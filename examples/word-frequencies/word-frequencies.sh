@@ -3,8 +3,8 @@
 # Standard paranoia.
 set -euo pipefail
 
-for F in /pfs/texts/*.txt; do
-    outfile="/pfs/out/$(basename "$F")"
+for F in "$FALCONERI_INPUT_DIR"/texts/*.txt; do
+    outfile="$FALCONERI_OUTPUT_DIR/$(basename "$F")"
     cat "$F" |
         tr '[:upper:]' '[:lower:]' |
         tr -c '[:alpha:]' ' ' |
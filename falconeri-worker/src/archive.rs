@@ -0,0 +1,140 @@
+//! Bundling a datum's output files into a single archive.
+//!
+//! Commands which write millions of tiny files make life hard for anyone who
+//! has to list the egress bucket later. If the pipeline spec sets
+//! `transform.archive_outputs`, we pack the output directory into one archive
+//! per datum, and upload that instead.
+
+use std::{
+    fs,
+    io::{self, BufWriter},
+};
+
+use falconeri_common::{pipeline::ArchiveFormat, prelude::*};
+use flate2::{write::GzEncoder, Compression};
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+use crate::scheduling::parse_env_var;
+
+/// Environment variable specifying how to archive our outputs, if at all.
+const ARCHIVE_OUTPUTS_VAR: &str = "FALCONERI_ARCHIVE_OUTPUTS";
+
+/// Have we been asked to archive our outputs? If so, return the format.
+pub fn archive_format_from_env() -> Result<Option<ArchiveFormat>> {
+    parse_env_var::<ArchiveFormat>(ARCHIVE_OUTPUTS_VAR)
+}
+
+/// Pack every regular file under `output_dir` into a new archive at
+/// `archive_path`, returning the number of files archived. Paths in the
+/// archive are relative to `output_dir`.
+#[instrument(skip_all, fields(output_dir = %output_dir.display(), archive_path = %archive_path.display()), level = "debug")]
+pub fn write_archive(
+    format: ArchiveFormat,
+    output_dir: &Path,
+    archive_path: &Path,
+) -> Result<usize> {
+    let files = files_to_archive(output_dir)?;
+    let file = fs::File::create(archive_path)
+        .with_context(|| format!("cannot create {}", archive_path.display()))?;
+    let writer = BufWriter::new(file);
+    let write_err = || format!("error writing {}", archive_path.display());
+    match format {
+        ArchiveFormat::TarGz => {
+            let mut builder =
+                tar::Builder::new(GzEncoder::new(writer, Compression::default()));
+            for (rel_path, local_path) in &files {
+                builder
+                    .append_path_with_name(local_path, rel_path)
+                    .with_context(write_err)?;
+            }
+            builder
+                .into_inner()
+                .and_then(|encoder| encoder.finish())
+                .with_context(write_err)?;
+        }
+        ArchiveFormat::Zip => {
+            let mut zip = ZipWriter::new(writer);
+            for (rel_path, local_path) in &files {
+                let size = local_path
+                    .metadata()
+                    .with_context(|| format!("cannot stat {}", local_path.display()))?
+                    .len();
+                let options = SimpleFileOptions::default()
+                    .compression_method(CompressionMethod::Deflated)
+                    .large_file(size >= u64::from(u32::MAX));
+                zip.start_file(rel_path.as_str(), options)
+                    .with_context(write_err)?;
+                let mut input = fs::File::open(local_path).with_context(|| {
+                    format!("cannot open {}", local_path.display())
+                })?;
+                io::copy(&mut input, &mut zip).with_context(write_err)?;
+            }
+            zip.finish().with_context(write_err)?;
+        }
+    }
+    Ok(files.len())
+}
+
+/// Find the regular files under `output_dir`, returning the relative path
+/// and full path of each, sorted by relative path.
+fn files_to_archive(output_dir: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let output_dir_str = output_dir
+        .to_str()
+        .ok_or_else(|| format_err!("invalid characters in {:?}", output_dir))?;
+    let pattern = format!("{}/**/*", glob::Pattern::escape(output_dir_str));
+    let list_err = || format!("error listing {}", output_dir.display());
+    let mut files = vec![];
+    for local_path in glob::glob(&pattern).with_context(list_err)? {
+        let local_path = local_path.with_context(list_err)?;
+        if local_path.is_dir() {
+            continue;
+        } else if !local_path.is_file() {
+            warn!("can't archive special file {}", local_path.display());
+            continue;
+        }
+        let rel_path = local_path.strip_prefix(output_dir)?;
+        let rel_path = rel_path
+            .to_str()
+            .ok_or_else(|| format_err!("invalid characters in {:?}", rel_path))?
+            .to_owned();
+        files.push((rel_path, local_path));
+    }
+    files.sort();
+    Ok(files)
+}
+
+#[test]
+fn write_archive_packs_output_dir() {
+    let dir = falconeri_common::temp_dir::TempDir::new("falconeri-test").unwrap();
+    let output_dir = dir.join("out");
+    fs::create_dir_all(output_dir.join("sub")).unwrap();
+    fs::write(output_dir.join("a.txt"), "a").unwrap();
+    fs::write(output_dir.join("sub/b.txt"), "bb").unwrap();
+
+    // Tarballs.
+    let tar_path = dir.join("out.tar.gz");
+    assert_eq!(
+        write_archive(ArchiveFormat::TarGz, &output_dir, &tar_path).unwrap(),
+        2
+    );
+    let mut tarball = tar::Archive::new(flate2::read::GzDecoder::new(
+        fs::File::open(&tar_path).unwrap(),
+    ));
+    let names = tarball
+        .entries()
+        .unwrap()
+        .map(|entry| entry.unwrap().path().unwrap().display().to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(names, vec!["a.txt", "sub/b.txt"]);
+
+    // Zip files.
+    let zip_path = dir.join("out.zip");
+    assert_eq!(
+        write_archive(ArchiveFormat::Zip, &output_dir, &zip_path).unwrap(),
+        2
+    );
+    let mut zip = zip::ZipArchive::new(fs::File::open(&zip_path).unwrap()).unwrap();
+    assert_eq!(zip.len(), 2);
+    let contents = io::read_to_string(zip.by_name("sub/b.txt").unwrap()).unwrap();
+    assert_eq!(contents, "bb");
+}
@@ -0,0 +1,142 @@
+//! Capturing the output of our command.
+//!
+//! We keep stdout and stderr interleaved, the way someone watching the command
+//! would have seen them. We also keep each stream on its own, because some
+//! commands write structured output to stdout, and mixing in stderr ruins it.
+//!
+//! While our command runs, we periodically send any new combined output to
+//! `falconerid`, so that people can watch it using `datum logs --follow`.
+
+use std::{convert::Infallible, str, sync::Arc, time::Duration};
+
+use falconeri_common::{cast, prelude::*, rest_api::Client};
+use tokio::sync::RwLock;
+
+/// How often to send new output to `falconerid` while our command runs.
+const OUTPUT_FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Which stream some output came from.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OutputStream {
+    /// Standard output.
+    Stdout,
+    /// Standard error.
+    Stderr,
+}
+
+/// Everything our command has written so far.
+#[derive(Debug, Default)]
+pub struct CapturedOutput {
+    /// stdout and stderr, interleaved in the order we read them.
+    combined: Vec<u8>,
+    /// Just stdout.
+    stdout: Vec<u8>,
+    /// Just stderr.
+    stderr: Vec<u8>,
+    /// How many bytes of `combined` we've already sent to `falconerid`.
+    flushed: usize,
+}
+
+impl CapturedOutput {
+    /// Record `data`, which our command wrote to `stream`.
+    pub fn record(&mut self, stream: OutputStream, data: &[u8]) {
+        self.combined.extend_from_slice(data);
+        match stream {
+            OutputStream::Stdout => self.stdout.extend_from_slice(data),
+            OutputStream::Stderr => self.stderr.extend_from_slice(data),
+        }
+    }
+
+    /// Take any combined output which we haven't sent to `falconerid` yet.
+    /// If the output ends partway through a UTF-8 character, we leave that
+    /// character for next time.
+    pub fn take_unflushed(&mut self) -> Option<String> {
+        let unflushed = &self.combined[self.flushed..];
+        let len = match str::from_utf8(unflushed) {
+            Ok(_) => unflushed.len(),
+            Err(err) if err.error_len().is_none() => err.valid_up_to(),
+            // This isn't UTF-8 at all, so replace it.
+            Err(_) => unflushed.len(),
+        };
+        if len == 0 {
+            return None;
+        }
+        let output = String::from_utf8_lossy(&unflushed[..len]).into_owned();
+        self.flushed += len;
+        Some(output)
+    }
+
+    /// Our combined output, with any invalid UTF-8 replaced.
+    pub fn combined(&self) -> String {
+        String::from_utf8_lossy(&self.combined).into_owned()
+    }
+
+    /// Our stdout, with any invalid UTF-8 replaced.
+    pub fn stdout(&self) -> String {
+        String::from_utf8_lossy(&self.stdout).into_owned()
+    }
+
+    /// Our stderr, with any invalid UTF-8 replaced.
+    pub fn stderr(&self) -> String {
+        String::from_utf8_lossy(&self.stderr).into_owned()
+    }
+}
+
+/// Send new output to `falconerid` every [`OUTPUT_FLUSH_INTERVAL`], until
+/// we're dropped. We stop sending output once we've sent as much as the job
+/// allows us to store in the database, because the final output will be
+/// truncated anyway. Failing to send output doesn't fail the datum.
+#[instrument(skip_all, fields(datum = %datum.id), level = "debug")]
+pub async fn flush_output_periodically(
+    client: &Client,
+    job: &Job,
+    datum: &Datum,
+    output: Arc<RwLock<CapturedOutput>>,
+) -> Infallible {
+    let max_bytes = cast::u64(job.max_inline_output_bytes).unwrap_or(u64::MAX);
+    let mut offset = 0u64;
+    loop {
+        tokio::time::sleep(OUTPUT_FLUSH_INTERVAL).await;
+        let Some(chunk) = output.write().await.take_unflushed() else {
+            continue;
+        };
+        let len = cast::u64(chunk.len());
+        if offset + len > max_bytes {
+            debug!(
+                "datum {} has too much output to show while running",
+                datum.id
+            );
+            return std::future::pending().await;
+        }
+        match client.append_datum_output(datum, offset, &chunk).await {
+            Ok(()) => offset += len,
+            Err(err) => {
+                // Our offset would no longer match, so give up.
+                warn!("could not send output of datum {}: {:#}", datum.id, err);
+                return std::future::pending().await;
+            }
+        }
+    }
+}
+
+#[test]
+fn take_unflushed_waits_for_whole_characters() {
+    let mut output = CapturedOutput::default();
+    assert_eq!(output.take_unflushed(), None);
+    output.record(OutputStream::Stdout, b"caf\xc3");
+    assert_eq!(output.take_unflushed().as_deref(), Some("caf"));
+    output.record(OutputStream::Stderr, b"\xa9\n");
+    assert_eq!(output.take_unflushed().as_deref(), Some("\u{e9}\n"));
+    assert_eq!(output.take_unflushed(), None);
+}
+
+#[test]
+fn captured_output_keeps_streams_separate() {
+    let mut output = CapturedOutput::default();
+    output.record(OutputStream::Stdout, b"{\"a\":");
+    output.record(OutputStream::Stderr, b"warning\n");
+    output.record(OutputStream::Stdout, b"1}\n");
+    assert_eq!(output.combined(), "{\"a\":warning\n1}\n");
+    assert_eq!(output.stdout(), "{\"a\":1}\n");
+    assert_eq!(output.stderr(), "warning\n");
+}
@@ -0,0 +1,216 @@
+//! Per-datum working directories.
+//!
+//! Each datum gets its own directory tree under `/pfs/$DATUM_ID`, so that we
+//! can work on more than one datum at a time, and so that cleaning up after a
+//! datum never touches files belonging to anyone else.
+//!
+//! Images written before this still read `/pfs/$REPO` and write `/pfs/out`.
+//! We only run one command at a time, so just before running it, we point
+//! those paths at the current datum's directories using symlinks.
+
+use std::{fs, os::unix::fs::symlink, path::Component};
+
+use falconeri_common::prelude::*;
+
+/// The root of all our datum directories.
+const PFS_ROOT: &str = "/pfs";
+
+/// The working directories for a single datum.
+#[derive(Debug)]
+pub struct DatumDirs {
+    /// The directory containing every datum's directories.
+    pfs_root: PathBuf,
+    /// The root directory for this datum.
+    root: PathBuf,
+    /// Where we download input files.
+    pub input: PathBuf,
+    /// Where the command should write output files.
+    pub output: PathBuf,
+}
+
+impl DatumDirs {
+    /// The directories for `datum_id`, under `/pfs`.
+    pub fn for_datum(datum_id: Uuid) -> Self {
+        Self::for_datum_in(Path::new(PFS_ROOT), datum_id)
+    }
+
+    /// The directories for `datum_id`, under `pfs_root`.
+    fn for_datum_in(pfs_root: &Path, datum_id: Uuid) -> Self {
+        let root = pfs_root.join(datum_id.to_string());
+        DatumDirs {
+            pfs_root: pfs_root.to_owned(),
+            input: root.join("in"),
+            output: root.join("out"),
+            root,
+        }
+    }
+
+    /// Create empty input and output directories, removing anything left
+    /// over from a previous attempt at this datum.
+    #[instrument(skip_all, fields(root = %self.root.display()), level = "debug")]
+    pub fn create(&self) -> Result<()> {
+        self.remove()?;
+        for dir in [&self.input, &self.output] {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("cannot create {}", dir.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Where should we build an archive named `file_name`? This is outside
+    /// of our output directory, but it will still be cleaned up by
+    /// [`DatumDirs::remove`].
+    pub fn archive_path(&self, file_name: &str) -> PathBuf {
+        self.root.join(file_name)
+    }
+
+    /// Point `/pfs/$REPO` at each of our input directories, and `/pfs/out`
+    /// at our output directory, replacing the links for any previous datum.
+    /// Only call this for the datum whose command is about to run.
+    #[instrument(skip_all, fields(root = %self.root.display()), level = "debug")]
+    pub fn link_legacy_paths(&self) -> Result<()> {
+        self.remove_legacy_links(|_| true)?;
+        let mut links = vec![(self.output.clone(), self.pfs_root.join("out"))];
+        for entry in fs::read_dir(&self.input)
+            .with_context(|| format!("cannot list {}", self.input.display()))?
+        {
+            let entry = entry
+                .with_context(|| format!("cannot list {}", self.input.display()))?;
+            if entry.file_name() == "out" {
+                return Err(format_err!(
+                    "cannot link {} to /pfs/out, because that's our output directory",
+                    entry.path().display(),
+                ));
+            }
+            links.push((entry.path(), self.pfs_root.join(entry.file_name())));
+        }
+        for (target, link) in links {
+            symlink(&target, &link).with_context(|| {
+                format!("cannot link {} to {}", link.display(), target.display())
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Remove any symlinks directly under `/pfs` whose targets match
+    /// `should_remove`. We never remove anything else, so other datums'
+    /// directories and our input cache are safe.
+    fn remove_legacy_links(
+        &self,
+        should_remove: impl Fn(&Path) -> bool,
+    ) -> Result<()> {
+        let list_err = || format!("cannot list {}", self.pfs_root.display());
+        for entry in fs::read_dir(&self.pfs_root).with_context(list_err)? {
+            let path = entry.with_context(list_err)?.path();
+            let Ok(target) = fs::read_link(&path) else {
+                continue;
+            };
+            if should_remove(&target) {
+                fs::remove_file(&path)
+                    .with_context(|| format!("cannot delete {}", path.display()))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Delete this datum's directories, if they exist, and any links to
+    /// them.
+    #[instrument(skip_all, fields(root = %self.root.display()), level = "debug")]
+    pub fn remove(&self) -> Result<()> {
+        if self.pfs_root.exists() {
+            self.remove_legacy_links(|target| target.starts_with(&self.root))?;
+        }
+        if self.root.exists() {
+            fs::remove_dir_all(&self.root)
+                .with_context(|| format!("cannot delete {}", self.root.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Where should we download the input file with the specified
+    /// `local_path`? Input files have paths like `/pfs/$REPO/$FILE`, which we
+    /// map to `/pfs/$DATUM_ID/in/$REPO/$FILE`.
+    pub fn input_path(&self, local_path: &str) -> Result<PathBuf> {
+        let rel_path = Path::new(local_path).strip_prefix(PFS_ROOT).map_err(|_| {
+            format_err!("input path {:?} is not under {}", local_path, PFS_ROOT)
+        })?;
+        let mut path = self.input.clone();
+        for component in rel_path.components() {
+            match component {
+                Component::CurDir => {}
+                Component::Normal(part) => path.push(part),
+                _ => {
+                    return Err(format_err!(
+                        "input path {:?} may not contain {:?}",
+                        local_path,
+                        component
+                    ))
+                }
+            }
+        }
+        Ok(path)
+    }
+
+    /// Environment variables telling our command where to find its files.
+    pub fn env_vars(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("FALCONERI_DATUM_DIR", self.root.display().to_string()),
+            ("FALCONERI_INPUT_DIR", self.input.display().to_string()),
+            ("FALCONERI_OUTPUT_DIR", self.output.display().to_string()),
+        ]
+    }
+}
+
+#[test]
+fn input_paths_are_nested_under_datum_dir() {
+    let datum_id = Uuid::nil();
+    let dirs = DatumDirs::for_datum(datum_id);
+    let root = format!("/pfs/{}", datum_id);
+    assert_eq!(dirs.output, Path::new(&format!("{}/out", root)));
+    assert_eq!(
+        dirs.input_path("/pfs/books/a.txt").unwrap(),
+        Path::new(&format!("{}/in/books/a.txt", root)),
+    );
+    assert_eq!(
+        dirs.input_path("/pfs/books/").unwrap(),
+        Path::new(&format!("{}/in/books", root)),
+    );
+    assert!(dirs.input_path("/pfs/../etc/passwd").is_err());
+    assert!(dirs.input_path("/etc/passwd").is_err());
+}
+
+#[test]
+fn create_and_remove_only_touch_datum_dir() {
+    let pfs_root = falconeri_common::temp_dir::TempDir::new("falconeri-test").unwrap();
+    let other = pfs_root.join("other");
+    fs::create_dir_all(&other).unwrap();
+
+    let dirs = DatumDirs::for_datum_in(&pfs_root, Uuid::new_v4());
+    dirs.create().unwrap();
+    assert!(dirs.input.is_dir());
+    assert!(dirs.output.is_dir());
+
+    // Old images can still use `/pfs/$REPO` and `/pfs/out`.
+    fs::create_dir(dirs.input.join("books")).unwrap();
+    fs::write(dirs.input.join("books/a.txt"), "alpha").unwrap();
+    dirs.link_legacy_paths().unwrap();
+    assert_eq!(
+        fs::read_to_string(pfs_root.join("books/a.txt")).unwrap(),
+        "alpha"
+    );
+    fs::write(pfs_root.join("out/b.txt"), "beta").unwrap();
+    assert!(dirs.output.join("b.txt").is_file());
+
+    // The next datum replaces our links.
+    let next = DatumDirs::for_datum_in(&pfs_root, Uuid::new_v4());
+    next.create().unwrap();
+    next.link_legacy_paths().unwrap();
+    assert!(!pfs_root.join("books").exists());
+    assert_eq!(fs::read_link(pfs_root.join("out")).unwrap(), next.output);
+    next.remove().unwrap();
+    assert!(fs::symlink_metadata(pfs_root.join("out")).is_err());
+
+    dirs.remove().unwrap();
+    assert!(!dirs.root.exists());
+    assert!(other.is_dir());
+}
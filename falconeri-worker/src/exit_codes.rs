@@ -0,0 +1,133 @@
+//! Classifying command failures as retryable or permanent, based on exit
+//! codes.
+//!
+//! We also treat output URI conflicts as permanent, because another datum has
+//! already written our outputs, and it will still have written them next
+//! time.
+
+use std::{env, error, process::ExitStatus};
+
+use falconeri_common::{
+    pipeline::{DEFAULT_PERMANENT_EXIT_CODES, DEFAULT_RETRYABLE_EXIT_CODES},
+    prelude::*,
+    rest_api::{api_error, FalconeriApiError},
+};
+
+/// Environment variable listing retryable exit codes.
+const RETRYABLE_EXIT_CODES_VAR: &str = "FALCONERI_RETRYABLE_EXIT_CODES";
+
+/// Environment variable listing permanent exit codes.
+const PERMANENT_EXIT_CODES_VAR: &str = "FALCONERI_PERMANENT_EXIT_CODES";
+
+/// Our command ran, but exited unsuccessfully.
+#[derive(Debug)]
+pub struct CommandFailed {
+    /// The command we ran.
+    pub cmd: Vec<String>,
+    /// How it exited.
+    pub status: ExitStatus,
+}
+
+impl fmt::Display for CommandFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "command {:?} failed with status {}",
+            self.cmd, self.status
+        )
+    }
+}
+
+impl error::Error for CommandFailed {}
+
+/// Which exit codes mean what.
+#[derive(Debug)]
+pub struct ExitCodes {
+    /// Exit codes which indicate a transient failure.
+    retryable: Vec<i32>,
+    /// Exit codes which indicate that retrying won't help.
+    permanent: Vec<i32>,
+}
+
+impl ExitCodes {
+    /// Load our exit codes from the environment, falling back to the
+    /// defaults.
+    pub fn from_env() -> Result<Self> {
+        let exit_codes = ExitCodes {
+            retryable: exit_codes_from_env(
+                RETRYABLE_EXIT_CODES_VAR,
+                DEFAULT_RETRYABLE_EXIT_CODES,
+            )?,
+            permanent: exit_codes_from_env(
+                PERMANENT_EXIT_CODES_VAR,
+                DEFAULT_PERMANENT_EXIT_CODES,
+            )?,
+        };
+        debug!("exit codes: {:?}", exit_codes);
+        Ok(exit_codes)
+    }
+
+    /// Classify `err`, if it was caused by our command exiting with a code we
+    /// know about, or by `falconerid` refusing our output URIs. Other errors
+    /// are left unclassified, and will be retried normally.
+    pub fn classify(&self, err: &Error) -> Option<FailureClass> {
+        if api_error(err) == Some(FalconeriApiError::Conflict) {
+            return Some(FailureClass::Permanent);
+        }
+        let failed = err.downcast_ref::<CommandFailed>()?;
+        self.classify_exit_code(failed.status.code()?)
+    }
+
+    /// Classify an individual exit code.
+    fn classify_exit_code(&self, code: i32) -> Option<FailureClass> {
+        if self.permanent.contains(&code) {
+            Some(FailureClass::Permanent)
+        } else if self.retryable.contains(&code) {
+            Some(FailureClass::Retryable)
+        } else {
+            None
+        }
+    }
+}
+
+/// Read a comma-separated list of exit codes from `name`, or use `default`.
+fn exit_codes_from_env(name: &str, default: &[i32]) -> Result<Vec<i32>> {
+    match env::var(name) {
+        Ok(value) => parse_exit_codes(&value)
+            .with_context(|| format!("could not parse {}={:?}", name, value)),
+        Err(_) => Ok(default.to_owned()),
+    }
+}
+
+/// Parse a comma-separated list of exit codes. An empty string is an empty
+/// list.
+fn parse_exit_codes(value: &str) -> Result<Vec<i32>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| Ok(s.parse::<i32>()?))
+        .collect()
+}
+
+#[test]
+fn classify_exit_codes() {
+    assert_eq!(parse_exit_codes("").unwrap(), Vec::<i32>::new());
+    assert_eq!(parse_exit_codes("75, 76").unwrap(), vec![75, 76]);
+    assert!(parse_exit_codes("x").is_err());
+
+    let exit_codes = ExitCodes {
+        retryable: vec![75],
+        permanent: vec![64],
+    };
+    assert_eq!(
+        exit_codes.classify_exit_code(75),
+        Some(FailureClass::Retryable)
+    );
+    assert_eq!(
+        exit_codes.classify_exit_code(64),
+        Some(FailureClass::Permanent)
+    );
+    assert_eq!(exit_codes.classify_exit_code(1), None);
+    assert_eq!(exit_codes.classify(&format_err!("download failed")), None);
+}
@@ -0,0 +1,326 @@
+//! Caching input files which are shared between datums.
+//!
+//! Many jobs have datums which all read the same large file, such as a set of
+//! model weights. If the pipeline spec has an `input_cache`, we keep a copy of
+//! each input file we download, named after a hash of its URI and version, and
+//! hard-link it into later datums' input directories instead of downloading it
+//! again. Once the cache grows too large, we evict the least recently used
+//! files.
+//!
+//! We only cache individual files whose storage backend told us their etag or
+//! generation when the datum was created, so that replacing a file in the
+//! bucket never leaves us using a stale copy.
+
+use std::{
+    env, fs, io,
+    time::{Duration, SystemTime},
+};
+
+use falconeri_common::{
+    cast, pipeline::parse_quantity, prelude::*, storage::CloudStorage,
+};
+use sha2::{Digest, Sha256};
+
+use crate::scheduling::parse_env_var;
+
+/// Environment variable specifying the maximum size of our cache, as a
+/// Kubernetes quantity.
+const INPUT_CACHE_SIZE_VAR: &str = "FALCONERI_INPUT_CACHE_SIZE";
+
+/// Environment variable specifying where to keep our cache.
+const INPUT_CACHE_DIR_VAR: &str = "FALCONERI_INPUT_CACHE_DIR";
+
+/// Where we keep our cache by default. This is on the same volume as our datum
+/// directories, so that we can hard-link files into them.
+const DEFAULT_INPUT_CACHE_DIR: &str = "/pfs/.falconeri-cache";
+
+/// The prefix of files which are still being downloaded.
+const PARTIAL_PREFIX: &str = ".partial-";
+
+/// How long to keep partial downloads before assuming that whoever was
+/// downloading them has died. A `host_path` cache may be shared with other
+/// workers, so we can't just delete them all.
+const STALE_PARTIAL_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Did we find a file in our cache?
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheResult {
+    /// We linked the file from our cache.
+    Hit,
+    /// We downloaded the file and added it to our cache.
+    Miss,
+}
+
+/// A cache of input files, shared between datums.
+#[derive(Debug)]
+pub struct InputCache {
+    /// The directory containing our cached files.
+    dir: PathBuf,
+    /// The maximum total size of our cached files.
+    max_bytes: u64,
+}
+
+impl InputCache {
+    /// Create an input cache if our pipeline spec asked for one.
+    pub fn from_env() -> Result<Option<Self>> {
+        let Some(size) = parse_env_var::<String>(INPUT_CACHE_SIZE_VAR)? else {
+            return Ok(None);
+        };
+        let max_bytes = parse_quantity(&size).with_context(|| {
+            format!("could not parse {}={:?}", INPUT_CACHE_SIZE_VAR, size)
+        })?;
+        let dir = env::var(INPUT_CACHE_DIR_VAR)
+            .unwrap_or_else(|_| DEFAULT_INPUT_CACHE_DIR.to_owned());
+        Self::new(PathBuf::from(dir), max_bytes).map(Some)
+    }
+
+    /// Create an input cache in `dir`, holding up to `max_bytes`.
+    fn new(dir: PathBuf, max_bytes: u64) -> Result<Self> {
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("cannot create {}", dir.display()))?;
+        Ok(InputCache { dir, max_bytes })
+    }
+
+    /// Copy `file` to `local_path`, using `storage` to download it into our
+    /// cache if we don't already have it. Returns `None` if we can't cache
+    /// `file`, in which case the caller should download it normally.
+    #[instrument(skip_all, fields(uri = %file.uri), level = "trace")]
+    pub async fn fetch(
+        &self,
+        storage: &dyn CloudStorage,
+        file: &InputFile,
+        local_path: &Path,
+    ) -> Result<Option<CacheResult>> {
+        let Some(key) = cache_key(file) else {
+            return Ok(None);
+        };
+        let cached = self.dir.join(&key);
+        if let Some(parent) = local_path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("cannot create {}", parent.display()))?;
+        }
+
+        // If we already have the file, use it.
+        match link_or_copy(&cached, local_path) {
+            Ok(()) => {
+                trace!("found {} in input cache", file.uri);
+                touch(&cached);
+                return Ok(Some(CacheResult::Hit));
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => {
+                return Err(err).with_context(|| {
+                    format!("cannot copy {} from input cache", file.uri)
+                })
+            }
+        }
+
+        // Otherwise, download it under a temporary name, and only move it
+        // into place once we know it's complete.
+        let partial = self
+            .dir
+            .join(format!("{}{}", PARTIAL_PREFIX, Uuid::new_v4()));
+        if let Err(err) = storage.sync_down(&file.uri, &partial).await {
+            let _ = fs::remove_file(&partial);
+            return Err(err);
+        }
+        link_or_copy(&partial, local_path)
+            .with_context(|| format!("cannot copy {} from input cache", file.uri))?;
+        fs::rename(&partial, &cached)
+            .with_context(|| format!("cannot add {} to input cache", file.uri))?;
+        self.evict()?;
+        Ok(Some(CacheResult::Miss))
+    }
+
+    /// Delete the least recently used files until our cache fits in
+    /// `max_bytes`, and clean up any abandoned partial downloads.
+    #[instrument(skip_all, level = "trace")]
+    fn evict(&self) -> Result<()> {
+        let stale_before = SystemTime::now()
+            .checked_sub(STALE_PARTIAL_AGE)
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        let mut cached = vec![];
+        let entries = self.dir.read_dir().with_context(|| {
+            format!("error listing directory {}", self.dir.display())
+        })?;
+        for entry in entries {
+            let entry = entry.with_context(|| {
+                format!("error listing directory {}", self.dir.display())
+            })?;
+            // Another worker may delete files out from under us.
+            let metadata = match entry.metadata() {
+                Ok(metadata) if metadata.is_file() => metadata,
+                Ok(_) => continue,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => {
+                    return Err(err).with_context(|| {
+                        format!("cannot stat {}", entry.path().display())
+                    })
+                }
+            };
+            let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            if entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with(PARTIAL_PREFIX)
+            {
+                if modified < stale_before {
+                    remove_cached(&entry.path())?;
+                }
+                continue;
+            }
+            cached.push(CachedFile {
+                last_used: modified,
+                bytes: metadata.len(),
+                path: entry.path(),
+            });
+        }
+        for path in files_to_evict(cached, self.max_bytes) {
+            debug!("evicting {} from input cache", path.display());
+            remove_cached(&path)?;
+        }
+        Ok(())
+    }
+}
+
+/// The name of our cached copy of `file`, if we can cache it.
+fn cache_key(file: &InputFile) -> Option<String> {
+    if file.uri.ends_with('/') || (file.etag.is_none() && file.generation.is_none()) {
+        return None;
+    }
+    let mut hasher = Sha256::new();
+    for part in [
+        Some(file.uri.as_str()),
+        file.etag.as_deref(),
+        file.generation.as_deref(),
+    ] {
+        // Tag each part, so that different combinations can't collide.
+        match part {
+            Some(part) => {
+                hasher.update([1]);
+                hasher.update(cast::u64(part.len()).to_be_bytes());
+                hasher.update(part.as_bytes());
+            }
+            None => hasher.update([0]),
+        }
+    }
+    Some(
+        hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect(),
+    )
+}
+
+/// A file in our cache.
+#[derive(Debug)]
+struct CachedFile {
+    /// When this file was last used.
+    last_used: SystemTime,
+    /// The size of this file.
+    bytes: u64,
+    /// The path to this file.
+    path: PathBuf,
+}
+
+/// Which of `cached` should we delete to bring their total size down to
+/// `max_bytes`? Returns the least recently used files first.
+fn files_to_evict(mut cached: Vec<CachedFile>, max_bytes: u64) -> Vec<PathBuf> {
+    cached.sort_by_key(|file| file.last_used);
+    let mut total = cached.iter().map(|file| file.bytes).sum::<u64>();
+    let mut evicted = vec![];
+    for file in cached {
+        if total <= max_bytes {
+            break;
+        }
+        total -= file.bytes;
+        evicted.push(file.path);
+    }
+    evicted
+}
+
+/// Hard-link `src` to `dest`, or copy it if they're on different filesystems.
+/// Fails with `io::ErrorKind::NotFound` if `src` doesn't exist.
+fn link_or_copy(src: &Path, dest: &Path) -> io::Result<()> {
+    match fs::hard_link(src, dest) {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Err(err),
+        // This happens with a `host_path` cache, which is on a different
+        // volume from our datum directories.
+        Err(_) => fs::copy(src, dest).map(|_| ()),
+    }
+}
+
+/// Record that we just used `path`, so that we evict it last.
+fn touch(path: &Path) {
+    let result =
+        fs::File::open(path).and_then(|file| file.set_modified(SystemTime::now()));
+    if let Err(err) = result {
+        warn!("cannot update time of {}: {}", path.display(), err);
+    }
+}
+
+/// Delete `path` from our cache, if nobody else has already done so.
+fn remove_cached(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => {
+            Err(err).with_context(|| format!("cannot delete {}", path.display()))
+        }
+    }
+}
+
+#[test]
+fn cache_key_requires_a_version() {
+    let datum = Datum::factory(&Job::factory());
+    let mut file = InputFile::factory(&datum);
+    file.uri = "gs://bucket/model.bin".to_owned();
+    file.etag = None;
+    file.generation = None;
+    assert_eq!(cache_key(&file), None);
+
+    file.etag = Some("abc".to_owned());
+    let key = cache_key(&file).unwrap();
+    assert_eq!(key.len(), 64);
+    file.generation = Some("1".to_owned());
+    assert_ne!(cache_key(&file).unwrap(), key);
+
+    file.uri = "gs://bucket/models/".to_owned();
+    assert_eq!(cache_key(&file), None);
+}
+
+#[test]
+fn evicts_least_recently_used_files() {
+    let file = |secs: u64, bytes: u64, name: &str| CachedFile {
+        last_used: SystemTime::UNIX_EPOCH + Duration::from_secs(secs),
+        bytes,
+        path: PathBuf::from(name),
+    };
+    let cached = || vec![file(30, 10, "c"), file(10, 10, "a"), file(20, 10, "b")];
+    assert!(files_to_evict(cached(), 30).is_empty());
+    assert_eq!(files_to_evict(cached(), 25), vec![PathBuf::from("a")]);
+    assert_eq!(
+        files_to_evict(cached(), 0),
+        vec![PathBuf::from("a"), PathBuf::from("b"), PathBuf::from("c")]
+    );
+}
+
+#[test]
+fn link_or_copy_and_evict() {
+    let dir = falconeri_common::temp_dir::TempDir::new("falconeri-test").unwrap();
+    let src = dir.join("src");
+    let dest = dir.join("dest");
+    let err = link_or_copy(&src, &dest).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+    fs::write(&src, "weights").unwrap();
+    link_or_copy(&src, &dest).unwrap();
+    assert_eq!(fs::read_to_string(&dest).unwrap(), "weights");
+
+    let cache = InputCache::new(dir.to_path_buf(), 0).unwrap();
+    cache.evict().unwrap();
+    assert!(!src.exists());
+    assert!(!dest.exists());
+}
@@ -1,26 +1,64 @@
 #![deny(unsafe_code)]
 
-use std::{env, fs, io::ErrorKind, process::Stdio, sync::Arc, time::Duration};
+use std::{
+    env, fs,
+    io::{self, ErrorKind},
+    process::Stdio,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use falconeri_common::{
+    cast,
+    decompress::{decompress_in_place, decompressed_path},
+    futures_util::{stream, StreamExt, TryStreamExt},
+    pipeline::ArchiveFormat,
     prelude::*,
-    rest_api::{Client, OutputFilePatch, OutputFilePost},
+    rest_api::{
+        api_error, Client, FalconeriApiError, OutputFilePatch, OutputFilePost,
+    },
     storage::CloudStorage,
     tracing_support::initialize_tracing,
+    vault::VaultSecrets,
 };
+use sha2::{Digest, Sha256};
 use tokio::{
     io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     process::{Child, Command},
     sync::RwLock,
 };
 
+use crate::{
+    captured_output::{flush_output_periodically, CapturedOutput, OutputStream},
+    datum_dirs::DatumDirs,
+    exit_codes::{CommandFailed, ExitCodes},
+    input_cache::CacheResult,
+    metrics::{serve_metrics_if_configured, WorkerMetrics},
+    prefetch::{prefetch_next_datum, ReservedDatum, StagedInputs},
+    scheduling::{parse_env_var, Scheduling},
+    shutdown::Shutdown,
+};
+
+mod archive;
+mod captured_output;
+mod datum_dirs;
+mod exit_codes;
+mod input_cache;
+mod metrics;
+mod prefetch;
+mod resource_usage;
+mod scheduling;
+mod shutdown;
+mod streaming;
+
+/// Set by Kubernetes when we're running as part of an indexed job.
+const JOB_COMPLETION_INDEX_VAR: &str = "JOB_COMPLETION_INDEX";
+
 /// Instructions on how to use this program.
 const USAGE: &str = "Usage: falconeri-worker <job id>";
 
 /// Our main entry point.
-#[tokio::main]
-#[instrument(level = "debug")]
-async fn main() -> Result<()> {
+fn main() -> Result<()> {
     initialize_tracing();
 
     // Parse our arguments (manually, so we don't need to drag in a ton of
@@ -38,65 +76,242 @@ async fn main() -> Result<()> {
         std::process::exit(0);
     }
     let job_id = args[1].parse::<Uuid>().context("can't parse job ID")?;
+
+    // Fetch any secrets we need from Vault, and make them available to
+    // ourselves and to our command. We do this before starting our main
+    // runtime, because setting environment variables is only safe while we
+    // have a single thread.
+    set_vault_secrets_in_env()?;
+
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .context("could not start tokio runtime")?
+        .block_on(run(job_id))
+}
+
+/// Fetch the secrets in [`falconeri_common::vault::VAULT_SECRETS_VAR`] from
+/// Vault, if there are any, and set the corresponding environment variables.
+/// This must be called before we start any other threads.
+fn set_vault_secrets_in_env() -> Result<()> {
+    let Some(vault_secrets) = VaultSecrets::from_env()? else {
+        return Ok(());
+    };
+
+    // Use a temporary single-threaded runtime. Dropping it waits for any
+    // blocking tasks, so none of its threads are left when we set variables.
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .context("could not start tokio runtime")?;
+    let vars = runtime.block_on(vault_secrets.fetch())?;
+    drop(runtime);
+
+    for (name, value) in vars {
+        debug!("setting {} from Vault", name);
+        env::set_var(name, value);
+    }
+    Ok(())
+}
+
+/// Process datums for `job_id` until the job is done.
+#[instrument(level = "debug")]
+async fn run(job_id: Uuid) -> Result<()> {
     debug!("job ID: {}", job_id);
 
+    // In an indexed Kubernetes job, each worker pod has its own completion
+    // index. We still get our datums from `falconerid`, so this is only
+    // useful for debugging.
+    if let Some(index) = parse_env_var::<u32>(JOB_COMPLETION_INDEX_VAR)? {
+        info!("running as completion index {}", index);
+    }
+
     // Create a REST client.
     let client = Client::new(ConnectVia::Cluster).await?;
 
+    // Start serving metrics, if we've been asked to.
+    let metrics = Arc::new(WorkerMetrics::default());
+    serve_metrics_if_configured(metrics.clone()).await?;
+
+    // Decide how to share our pod's resources between downloads and our
+    // command.
+    let scheduling = Scheduling::from_env()?;
+
+    // Should we stream input files through our command instead of using
+    // `/pfs`?
+    let stdin_files = streaming::stdin_files_enabled()?;
+
+    // Decide which failures are worth retrying.
+    let exit_codes = ExitCodes::from_env()?;
+
+    // Notice when our pod is being shut down.
+    let shutdown = Shutdown::listen()?;
+
+    // A datum we reserved while working on the previous one, if any.
+    let mut prefetched: Option<ReservedDatum> = None;
+
     // Loop until the job is done.
     loop {
         // Fetch our job, and make sure that it's still running.
         let mut job = client.job(job_id).await?;
         trace!("job: {:?}", job);
-        if job.status != Status::Running {
+        if !job.status.is_active() {
             break;
         }
 
-        // Get the next datum and process it.
-        if let Some((mut datum, files)) = client.reserve_next_datum(&job).await? {
+        // If we're being shut down, give back any datum we prefetched.
+        if shutdown.is_requested() {
+            release_datums(&client, prefetched.take().map(|r| r.datum)).await;
+            return Err(format_err!("stopping because our pod is shutting down"));
+        }
+
+        // Get the next datum (unless we already have one) and process it.
+        let reserved = match prefetched.take() {
+            Some(reserved) => Some(reserved),
+            None => client
+                .reserve_next_datum(&job, false)
+                .await?
+                .map(|(datum, files)| ReservedDatum::new(datum, files)),
+        };
+        if let Some(ReservedDatum {
+            mut datum,
+            files,
+            staged,
+        }) = reserved
+        {
             // Process our datum, capturing its output.
-            let output = Arc::new(RwLock::new(vec![]));
-            let result = process_datum(
-                &client,
-                &job,
-                &datum,
-                &files,
-                &job.command,
-                output.clone(),
-            )
-            .await;
-            let output_str =
-                String::from_utf8_lossy(&output.read().await).into_owned();
+            let output = Arc::new(RwLock::new(CapturedOutput::default()));
+            let started_at = Instant::now();
+            let mut timings = DatumTimings::default();
+            let mut byte_counts = DatumByteCounts::default();
+            let mut resource_usage = DatumResourceUsage::default();
+            let processed = tokio::select! {
+                result = async {
+                    if stdin_files {
+                        streaming::process_datum(
+                            &client,
+                            &metrics,
+                            &scheduling,
+                            &job,
+                            &datum,
+                            &files,
+                            &job.command,
+                            output.clone(),
+                            &mut timings,
+                            &mut byte_counts,
+                            &mut resource_usage,
+                        )
+                        .await
+                    } else {
+                        process_datum(
+                            &client,
+                            &metrics,
+                            &scheduling,
+                            &job,
+                            &datum,
+                            &files,
+                            staged,
+                            &mut prefetched,
+                            &job.command,
+                            output.clone(),
+                            &mut timings,
+                            &mut byte_counts,
+                            &mut resource_usage,
+                        )
+                        .await
+                    }
+                } => Some(result),
+                () = shutdown.wait() => None,
+                never = flush_output_periodically(&client, &job, &datum, output.clone()) => {
+                    match never {}
+                }
+            };
+
+            // If we're being shut down, abandon our work (which kills our
+            // command) and give our datums back without counting an attempt.
+            let Some(result) = processed else {
+                let prefetched = prefetched.take().map(|r| r.datum);
+                release_datums(&client, Some(datum).into_iter().chain(prefetched))
+                    .await;
+                return Err(format_err!("stopping because our pod is shutting down"));
+            };
+
+            // Clean up this datum's working directories, whether or not we
+            // succeeded. This never touches any other datum's files.
+            if let Err(err) = DatumDirs::for_datum(datum.id).remove() {
+                warn!("could not clean up after datum {}: {:?}", datum.id, err);
+            }
+
+            let status = if result.is_ok() {
+                Status::Done
+            } else {
+                Status::Error
+            };
+            metrics.record_datum(status, started_at.elapsed());
+            let (output_str, streams) = {
+                let output = output.read().await;
+                // When streaming, stdout is uploaded as an output file.
+                let streams = DatumStreams {
+                    stdout: (!stdin_files).then(|| output.stdout()),
+                    stderr: Some(output.stderr()),
+                };
+                (output.combined(), streams)
+            };
+            let (output_str, output_uri) =
+                offload_output_if_too_long(&job, &datum, output_str).await;
+            let streams = truncate_streams(&job, streams, output_uri.as_deref());
 
             // Handle the processing results.
-            match result {
-                Ok(()) => client.mark_datum_as_done(&mut datum, output_str).await?,
+            let report = DatumReport {
+                output: output_str,
+                output_uri,
+                streams,
+                timings,
+                byte_counts,
+                resource_usage,
+            };
+            let marked = match result {
+                Ok(()) => client.mark_datum_as_done(&mut datum, report).await,
                 Err(err) => {
                     error!("failed to process datum {}: {:?}", datum.id, err);
                     let error_message = format!("{:?}", err);
                     let backtrace = format!("{}", err.backtrace());
+                    let failure_class = exit_codes.classify(&err);
                     client
                         .mark_datum_as_error(
                             &mut datum,
-                            output_str,
+                            report,
                             error_message,
                             backtrace,
+                            failure_class,
                         )
-                        .await?
+                        .await
+                }
+            };
+
+            // If the babysitter gave our datum to another pod, perhaps because
+            // we were too slow to send a heartbeat, just move on.
+            match marked {
+                Err(err) if api_error(&err) == Some(FalconeriApiError::Forbidden) => {
+                    warn!("datum {} was taken by another pod: {:#}", datum.id, err)
                 }
+                marked => marked?,
             }
         } else {
             debug!("no datums to process right now");
 
             // Break early if the job is no longer running.
             job = client.job(job_id).await?;
-            if job.status != Status::Running {
+            if !job.status.is_active() {
                 break;
             } else {
                 // We're still running, so wait a while and check to see if the
                 // job finishes or if some datums become available.
                 trace!("waiting for job to finish");
-                tokio::time::sleep(Duration::from_secs(30)).await;
+                tokio::select! {
+                    () = tokio::time::sleep(Duration::from_secs(30)) => {}
+                    () = shutdown.wait() => {}
+                }
             }
         }
     }
@@ -109,69 +324,207 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-/// Process a single datum.
+/// Hand `datums` back to `falconerid`, so that other workers can process them
+/// without waiting for the babysitter to notice that we're gone.
+async fn release_datums(client: &Client, datums: impl IntoIterator<Item = Datum>) {
+    for mut datum in datums {
+        match client.release_datum(&mut datum).await {
+            Ok(()) => info!("released datum {}", datum.id),
+            Err(err) => warn!("could not release datum {}: {:?}", datum.id, err),
+        }
+        if let Err(err) = DatumDirs::for_datum(datum.id).remove() {
+            warn!("could not clean up after datum {}: {:?}", datum.id, err);
+        }
+    }
+}
+
+/// Process a single datum, recording when each phase finishes in `timings`,
+/// how much data we moved in `byte_counts`, and what our command used in
+/// `resource_usage`.
+///
+/// Our input files are downloaded to `/pfs/$DATUM_ID/in`, and our command
+/// writes its output to `/pfs/$DATUM_ID/out`, which are also linked from
+/// `/pfs/$REPO` and `/pfs/out` while it runs. If `staged` is present, we've
+/// already downloaded our input files. If prefetching is enabled, we'll reserve
+/// our next datum while uploading, and store it in `next`.
+#[allow(clippy::too_many_arguments)]
 #[instrument(skip_all, fields(job = %job.id, datum = %datum.id), level = "trace")]
 async fn process_datum(
     client: &Client,
+    metrics: &WorkerMetrics,
+    scheduling: &Scheduling,
     job: &Job,
     datum: &Datum,
     files: &[InputFile],
+    staged: Option<StagedInputs>,
+    next: &mut Option<ReservedDatum>,
     cmd: &[String],
-    to_record: Arc<RwLock<Vec<u8>>>,
+    to_record: Arc<RwLock<CapturedOutput>>,
+    timings: &mut DatumTimings,
+    byte_counts: &mut DatumByteCounts,
+    resource_usage: &mut DatumResourceUsage,
 ) -> Result<()> {
     debug!("processing datum {}", datum.id);
+    timings.started_at = Some(Utc::now().naive_utc());
 
-    // Download each file.
-    reset_work_dirs()?;
-    for file in files {
-        // We don't pass in any `secrets` here, because those are supposed to
-        // be specified in our Kubernetes job when it's created.
-        let storage = <dyn CloudStorage>::for_uri(&file.uri, &[]).await?;
-        storage
-            .sync_down(&file.uri, Path::new(&file.local_path))
-            .await?;
-    }
+    // Download our files, several at a time, unless we've already done so.
+    let dirs = DatumDirs::for_datum(datum.id);
+    reset_work_dir(Path::new("/scratch/"))?;
+    let input_bytes = match staged {
+        Some(staged) => staged.input_bytes,
+        None => {
+            dirs.create()?;
+            download_input_files(metrics, scheduling, &dirs, files).await?
+        }
+    };
+    timings.download_completed_at = Some(Utc::now().naive_utc());
+    byte_counts.input_bytes = Some(cast::i64(input_bytes)?);
 
     // Run our command.
     if cmd.is_empty() {
         return Err(format_err!("job {} command is empty", job.id));
     }
+    let input_paths = files
+        .iter()
+        .map(|f| {
+            let local_path = if f.decompress {
+                decompressed_path(&f.local_path)
+            } else {
+                &f.local_path
+            };
+            Ok(dirs.input_path(local_path)?.display().to_string())
+        })
+        .collect::<Result<Vec<_>>>()?;
+    dirs.link_legacy_paths()?;
+    let cmd = scheduling.wrap_command(cmd);
     let mut child = Command::new(&cmd[0])
         .args(&cmd[1..])
+        .envs(datum_env_vars(job, datum, &input_paths))
+        .envs(dirs.env_vars())
+        .kill_on_drop(true)
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .spawn()
         .with_context(|| format!("could not run {:?}", &cmd[0]))?;
 
-    // Tee stdout and stderr using tokio tasks.
-    tee_child(&mut child, to_record).await?;
-
-    let status = child
-        .wait()
-        .await
-        .with_context(|| format!("error running {:?}", &cmd[0]))?;
+    // Tee stdout and stderr using tokio tasks, and measure our command's
+    // resource usage until it exits.
+    let status = resource_usage::measure(child.id(), resource_usage, async {
+        tee_child(&mut child, to_record).await?;
+        child
+            .wait()
+            .await
+            .with_context(|| format!("error running {:?}", &cmd[0]))
+    })
+    .await?;
+    timings.command_completed_at = Some(Utc::now().naive_utc());
+    metrics.record_command_exit(status.code());
     if !status.success() {
-        return Err(format_err!(
-            "command {:?} failed with status {}",
-            cmd,
-            status
-        ));
+        return Err(CommandFailed { cmd, status }.into());
     }
 
-    // Finish up after the command completes.
-    upload_outputs(client, job, datum)
-        .await
-        .context("could not upload outputs")?;
-    reset_work_dirs()?;
+    // Finish up after the command completes. If we've been asked to, reserve
+    // our next datum and download its inputs while we upload.
+    let upload = upload_outputs(client, metrics, job, datum, &dirs);
+    let output_bytes = if scheduling.prefetch_next_datum && next.is_none() {
+        let prefetch = prefetch_next_datum(client, metrics, scheduling, job);
+        let (upload_result, prefetch_result) = tokio::join!(upload, prefetch);
+        match prefetch_result {
+            Ok(reserved) => *next = reserved,
+            Err(err) => warn!("could not prefetch next datum: {:?}", err),
+        }
+        upload_result
+    } else {
+        upload.await
+    }
+    .context("could not upload outputs")?;
+    timings.upload_completed_at = Some(Utc::now().naive_utc());
+    byte_counts.output_bytes = Some(cast::i64(output_bytes)?);
+    reset_work_dir(Path::new("/scratch/"))?;
     Ok(())
 }
 
+/// Environment variables describing the job and datum we're processing, so
+/// that user code can use them to build idempotency keys. `input_paths`
+/// contains the local paths (or URIs, in streaming mode) of our input files.
+fn datum_env_vars(
+    job: &Job,
+    datum: &Datum,
+    input_paths: &[String],
+) -> Vec<(&'static str, String)> {
+    vec![
+        ("FALCONERI_JOB_ID", job.id.to_string()),
+        ("FALCONERI_JOB_NAME", job.job_name.clone()),
+        ("FALCONERI_DATUM_ID", datum.id.to_string()),
+        ("FALCONERI_ATTEMPT", datum.attempted_run_count.to_string()),
+        ("FALCONERI_INPUT_FILES", input_paths.join("\n")),
+    ]
+}
+
+/// Download `files` into the input directory in `dirs`, several at a time,
+/// returning the number of bytes downloaded.
+#[instrument(skip_all, level = "trace")]
+async fn download_input_files(
+    metrics: &WorkerMetrics,
+    scheduling: &Scheduling,
+    dirs: &DatumDirs,
+    files: &[InputFile],
+) -> Result<u64> {
+    stream::iter(files)
+        .map(|file| download_input_file(metrics, scheduling, dirs, file))
+        .buffer_unordered(scheduling.download_concurrency)
+        .try_fold(0, |total, bytes| async move { Ok(total + bytes) })
+        .await
+}
+
+/// Download a single input file, returning the number of bytes downloaded.
+#[instrument(skip_all, fields(uri = %file.uri), level = "trace")]
+async fn download_input_file(
+    metrics: &WorkerMetrics,
+    scheduling: &Scheduling,
+    dirs: &DatumDirs,
+    file: &InputFile,
+) -> Result<u64> {
+    let storage = <dyn CloudStorage>::for_worker_input_uri(&file.uri).await?;
+    let local_path = dirs.input_path(&file.local_path)?;
+    let started_at = Instant::now();
+    let cached = match &scheduling.input_cache {
+        Some(input_cache) => {
+            input_cache
+                .fetch(storage.as_ref(), file, &local_path)
+                .await?
+        }
+        None => None,
+    };
+    if let Some(cached) = cached {
+        metrics.record_input_cache(cached);
+    } else {
+        storage.sync_down(&file.uri, &local_path).await?;
+    }
+    let bytes = disk_usage(&local_path)?;
+    if cached != Some(CacheResult::Hit) {
+        metrics.record_download(bytes, started_at.elapsed());
+    }
+
+    // Decompress our file if we've been asked to. We do this after using our
+    // input cache, so that the cache holds the original file.
+    if file.decompress {
+        tokio::task::spawn_blocking(move || decompress_in_place(&local_path))
+            .await
+            .context("could not decompress input files")??;
+    }
+    Ok(bytes)
+}
+
 /// Copy the stdout and stderr of `child` to either stdout or stderr,
 /// respectively, and write a copy to `to_record`.
 ///
 /// This function will panic if `child` does not have a `stdout` or `stderr`.
 #[instrument(skip_all, level = "trace")]
-async fn tee_child(child: &mut Child, to_record: Arc<RwLock<Vec<u8>>>) -> Result<()> {
+async fn tee_child(
+    child: &mut Child,
+    to_record: Arc<RwLock<CapturedOutput>>,
+) -> Result<()> {
     let stdout = child
         .stdout
         .take()
@@ -186,10 +539,22 @@ async fn tee_child(child: &mut Child, to_record: Arc<RwLock<Vec<u8>>>) -> Result
 
     // Spawn tasks to handle stdout and stderr concurrently.
     let stdout_handle = tokio::spawn(async move {
-        tee_output(stdout, tokio::io::stdout(), to_record_for_stdout).await
+        tee_output(
+            stdout,
+            tokio::io::stdout(),
+            OutputStream::Stdout,
+            to_record_for_stdout,
+        )
+        .await
     });
     let stderr_handle = tokio::spawn(async move {
-        tee_output(stderr, tokio::io::stderr(), to_record_for_stderr).await
+        tee_output(
+            stderr,
+            tokio::io::stderr(),
+            OutputStream::Stderr,
+            to_record_for_stderr,
+        )
+        .await
     });
 
     // Wait for both to complete.
@@ -199,12 +564,14 @@ async fn tee_child(child: &mut Child, to_record: Arc<RwLock<Vec<u8>>>) -> Result
     Ok(())
 }
 
-/// Copy output from `from_child` to `to_console` and `to_record`.
+/// Copy output from `from_child` to `to_console` and `to_record`, noting that
+/// it came from `stream`.
 #[instrument(skip_all, level = "trace")]
 async fn tee_output<R, W>(
     mut from_child: R,
     mut to_console: W,
-    to_record: Arc<RwLock<Vec<u8>>>,
+    stream: OutputStream,
+    to_record: Arc<RwLock<CapturedOutput>>,
 ) -> Result<()>
 where
     R: AsyncRead + Unpin,
@@ -225,7 +592,7 @@ where
                     .await
                     .context("error writing to console")?;
                 to_console.flush().await.context("error flushing console")?;
-                to_record.write().await.extend_from_slice(data);
+                to_record.write().await.record(stream, data);
             }
             // Retry if reading was interrupted by kernel shenanigans.
             Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
@@ -237,13 +604,100 @@ where
     }
 }
 
-/// Reset our working directories to a default, clean state.
-#[instrument(level = "trace")]
-fn reset_work_dirs() -> Result<()> {
-    reset_work_dir(Path::new("/pfs/"))?;
-    fs::create_dir("/pfs/out").context("cannot create /pfs/out")?;
-    reset_work_dir(Path::new("/scratch/"))?;
-    Ok(())
+/// If `output` is longer than our job allows us to store in the database,
+/// upload the full output and return a truncated copy, plus the URI of the full
+/// output (if we managed to upload it).
+#[instrument(skip_all, fields(job = %job.id, datum = %datum.id), level = "debug")]
+async fn offload_output_if_too_long(
+    job: &Job,
+    datum: &Datum,
+    output: String,
+) -> (String, Option<String>) {
+    let max_bytes = cast::usize(job.max_inline_output_bytes).unwrap_or(usize::MAX);
+    if output.len() <= max_bytes {
+        return (output, None);
+    }
+
+    // Failing to upload our log shouldn't fail the datum, so just warn.
+    let output_uri = match upload_full_output(job, datum, &output).await {
+        Ok(uri) => Some(uri),
+        Err(err) => {
+            warn!(
+                "could not upload full output of datum {}: {:?}",
+                datum.id, err
+            );
+            None
+        }
+    };
+    let truncated = truncate_output(&output, max_bytes, output_uri.as_deref());
+    (truncated, output_uri)
+}
+
+/// Upload `output` to our job's log location, returning the URI.
+#[instrument(skip_all, fields(job = %job.id, datum = %datum.id), level = "debug")]
+async fn upload_full_output(job: &Job, datum: &Datum, output: &str) -> Result<String> {
+    let mut log_dir_uri = job.output_log_uri_or_default();
+    if !log_dir_uri.ends_with('/') {
+        log_dir_uri.push('/');
+    }
+    let file_name = format!("{}.log", datum.id);
+
+    // Our storage backends only know how to upload directories, so put our
+    // log file in a directory of its own. Don't use `/scratch`, because it
+    // belongs to the user's code.
+    let log_dir = env::temp_dir().join(format!("falconeri-output-{}", datum.id));
+    fs::create_dir_all(&log_dir)
+        .with_context(|| format!("cannot create {}", log_dir.display()))?;
+    fs::write(log_dir.join(&file_name), output)
+        .with_context(|| format!("cannot write output to {}", log_dir.display()))?;
+    let storage = <dyn CloudStorage>::for_uri(&log_dir_uri, &[]).await?;
+    let result = storage.sync_up(&log_dir, &log_dir_uri).await;
+    fs::remove_dir_all(&log_dir)
+        .with_context(|| format!("cannot delete {}", log_dir.display()))?;
+    result?;
+
+    Ok(format!("{}{}", log_dir_uri, file_name))
+}
+
+/// Truncate `output` to roughly `max_bytes`, keeping the end (which is where
+/// error messages usually are), and add a note explaining what happened.
+fn truncate_output(
+    output: &str,
+    max_bytes: usize,
+    output_uri: Option<&str>,
+) -> String {
+    let mut start = output.len().saturating_sub(max_bytes);
+    while !output.is_char_boundary(start) {
+        start += 1;
+    }
+    let note = match output_uri {
+        Some(uri) => format!("[output truncated; full output is at {}]\n", uri),
+        None => "[output truncated]\n".to_owned(),
+    };
+    format!("{}{}", note, &output[start..])
+}
+
+/// Truncate each of `streams` which is longer than our job allows us to store
+/// in the database, pointing to the full combined output at `output_uri`.
+fn truncate_streams(
+    job: &Job,
+    streams: DatumStreams,
+    output_uri: Option<&str>,
+) -> DatumStreams {
+    let max_bytes = cast::usize(job.max_inline_output_bytes).unwrap_or(usize::MAX);
+    let truncate = |stream: Option<String>| {
+        stream.map(|s| {
+            if s.len() <= max_bytes {
+                s
+            } else {
+                truncate_output(&s, max_bytes, output_uri)
+            }
+        })
+    };
+    DatumStreams {
+        stdout: truncate(streams.stdout),
+        stderr: truncate(streams.stderr),
+    }
 }
 
 /// Restore a directory to a default, clean state.
@@ -282,14 +736,58 @@ fn reset_work_dir(work_dir: &Path) -> Result<()> {
     Ok(())
 }
 
-/// Upload `/pfs/out` to our output bucket.
+/// Compute the total size of the regular files at or below `path`.
+#[instrument(skip_all, fields(path = %path.display()), level = "trace")]
+fn disk_usage(path: &Path) -> Result<u64> {
+    let metadata = fs::symlink_metadata(path)
+        .with_context(|| format!("cannot stat {}", path.display()))?;
+    if metadata.is_dir() {
+        let mut total = 0;
+        let entries = path
+            .read_dir()
+            .with_context(|| format!("error listing directory {}", path.display()))?;
+        for entry in entries {
+            let entry = entry.with_context(|| {
+                format!("error listing directory {}", path.display())
+            })?;
+            total += disk_usage(&entry.path())?;
+        }
+        Ok(total)
+    } else if metadata.is_file() {
+        Ok(metadata.len())
+    } else {
+        Ok(0)
+    }
+}
+
+/// Upload our datum's output directory to our output bucket, returning the
+/// number of bytes uploaded.
 #[instrument(skip_all, fields(job = %job.id, datum = %datum.id), level = "debug")]
-async fn upload_outputs(client: &Client, job: &Job, datum: &Datum) -> Result<()> {
+async fn upload_outputs(
+    client: &Client,
+    metrics: &WorkerMetrics,
+    job: &Job,
+    datum: &Datum,
+    dirs: &DatumDirs,
+) -> Result<u64> {
+    if let Some(format) = archive::archive_format_from_env()? {
+        return upload_archived_outputs(client, metrics, job, datum, dirs, format)
+            .await;
+    }
+    let output_dir = dirs.output.as_path();
+
     // Collect output file info for the files we're going to upload.
     let mut new_output_files = vec![];
-    let local_paths = glob::glob("/pfs/out/**/*").context("error listing /pfs/out")?;
+    let mut local_files = vec![];
+    let mut upload_bytes = 0;
+    let output_dir_str = output_dir
+        .to_str()
+        .ok_or_else(|| format_err!("invalid characters in {:?}", output_dir))?;
+    let pattern = format!("{}/**/*", glob::Pattern::escape(output_dir_str));
+    let list_err = || format!("error listing {}", output_dir.display());
+    let local_paths = glob::glob(&pattern).with_context(list_err)?;
     for local_path in local_paths {
-        let local_path = local_path.context("error listing /pfs/out")?;
+        let local_path = local_path.with_context(list_err)?;
         let _span =
             debug_span!("upload_output", local_path = %local_path.display()).entered();
 
@@ -301,41 +799,219 @@ async fn upload_outputs(client: &Client, job: &Job, datum: &Datum) -> Result<()>
             continue;
         }
 
+        let size = local_path
+            .metadata()
+            .with_context(|| format!("cannot stat {}", local_path.display()))?
+            .len();
+        upload_bytes += size;
+
         // Get our local path, and strip the prefix.
-        let rel_path = local_path.strip_prefix("/pfs/out/")?;
+        let rel_path = local_path.strip_prefix(output_dir)?;
         let rel_path_str = rel_path
             .to_str()
             .ok_or_else(|| format_err!("invalid characters in {:?}", rel_path))?;
 
         // Build the URI we want to upload to.
-        let mut uri = job.egress_uri.clone();
-        if !uri.ends_with('/') {
-            uri.push('/');
-        }
-        uri.push_str(rel_path_str);
+        let uri = egress_uri_for(job, rel_path_str);
 
+        local_files.push((uri.clone(), local_path.clone(), size));
         new_output_files.push(OutputFilePost { uri });
     }
 
+    // Hash our files, so that downstream systems can check what we uploaded.
+    // This reads every file, so do it on a thread where blocking is OK.
+    let checksums = tokio::task::spawn_blocking(move || {
+        local_files
+            .into_iter()
+            .map(|(uri, local_path, size)| {
+                Ok((uri, (size, file_sha256(&local_path)?)))
+            })
+            .collect::<Result<HashMap<_, _>>>()
+    })
+    .await
+    .context("could not hash output files")??;
+
     // Create database records for the files we're about to upload.
     let output_files = client.create_output_files(datum, &new_output_files).await?;
 
     // Upload all our files in a batch, for maximum performance.
     let storage = <dyn CloudStorage>::for_uri(&job.egress_uri, &[]).await?;
-    let result = storage
-        .sync_up(Path::new("/pfs/out/"), &job.egress_uri)
-        .await;
+    let started_at = Instant::now();
+    let result = storage.sync_up(output_dir, &job.egress_uri).await;
     let status = match result {
-        Ok(()) => Status::Done,
+        Ok(()) => {
+            metrics.record_upload(upload_bytes, started_at.elapsed());
+            Status::Done
+        }
         Err(_) => Status::Error,
     };
 
     // Record what happened.
     let patches = output_files
         .iter()
-        .map(|f| OutputFilePatch { id: f.id, status })
+        .map(|f| {
+            let checksum = checksums.get(&f.uri);
+            OutputFilePatch {
+                id: f.id,
+                status,
+                size: checksum.map(|(size, _)| *size),
+                sha256: checksum.map(|(_, sha256)| sha256.to_owned()),
+            }
+        })
         .collect::<Vec<_>>();
     client.patch_output_files(datum, &patches).await?;
 
-    result
+    result.map(|()| upload_bytes)
+}
+
+/// Bundle our datum's output directory into a single archive named after the
+/// datum, and upload it to our output bucket, returning the number of bytes
+/// uploaded.
+#[instrument(skip_all, fields(job = %job.id, datum = %datum.id, format = %format), level = "debug")]
+async fn upload_archived_outputs(
+    client: &Client,
+    metrics: &WorkerMetrics,
+    job: &Job,
+    datum: &Datum,
+    dirs: &DatumDirs,
+    format: ArchiveFormat,
+) -> Result<u64> {
+    // Build and hash our archive on a thread where blocking is OK.
+    let file_name = format!("{}.{}", datum.id, format.extension());
+    let archive_path = dirs.archive_path(&file_name);
+    let (size, sha256) = {
+        let output_dir = dirs.output.clone();
+        let archive_path = archive_path.clone();
+        tokio::task::spawn_blocking(move || -> Result<_> {
+            let count = archive::write_archive(format, &output_dir, &archive_path)?;
+            debug!("archived {} output files", count);
+            let size = archive_path
+                .metadata()
+                .with_context(|| format!("cannot stat {}", archive_path.display()))?
+                .len();
+            Ok((size, file_sha256(&archive_path)?))
+        })
+        .await
+        .context("could not archive output files")??
+    };
+
+    // Create a database record for our archive.
+    let uri = egress_uri_for(job, &file_name);
+    let output_files = client
+        .create_output_files(datum, &[OutputFilePost { uri: uri.clone() }])
+        .await?;
+
+    // Upload our archive.
+    let storage = <dyn CloudStorage>::for_uri(&job.egress_uri, &[]).await?;
+    let started_at = Instant::now();
+    let result = async {
+        let mut file = tokio::fs::File::open(&archive_path)
+            .await
+            .with_context(|| format!("cannot open {}", archive_path.display()))?;
+        storage.upload_from_reader(&mut file, &uri).await
+    }
+    .await;
+    let status = match result {
+        Ok(_) => {
+            metrics.record_upload(size, started_at.elapsed());
+            Status::Done
+        }
+        Err(_) => Status::Error,
+    };
+
+    // Record what happened.
+    let patches = output_files
+        .iter()
+        .map(|f| OutputFilePatch {
+            id: f.id,
+            status,
+            size: Some(size),
+            sha256: Some(sha256.clone()),
+        })
+        .collect::<Vec<_>>();
+    client.patch_output_files(datum, &patches).await?;
+
+    result.map(|_| size)
+}
+
+/// The URI in our egress bucket for the output file at `rel_path`.
+fn egress_uri_for(job: &Job, rel_path: &str) -> String {
+    let mut uri = job.egress_uri.clone();
+    if !uri.ends_with('/') {
+        uri.push('/');
+    }
+    uri.push_str(rel_path);
+    uri
+}
+
+/// Compute the SHA-256 hash of the file at `path`, in lowercase hexadecimal.
+fn file_sha256(path: &Path) -> Result<String> {
+    let mut file = fs::File::open(path)
+        .with_context(|| format!("cannot open {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)
+        .with_context(|| format!("cannot read {}", path.display()))?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+#[test]
+fn truncate_output_keeps_end() {
+    assert_eq!(
+        truncate_output("abcdef", 3, Some("gs://bucket/logs/x.log")),
+        "[output truncated; full output is at gs://bucket/logs/x.log]\ndef",
+    );
+    assert_eq!(
+        truncate_output("abcdef", 3, None),
+        "[output truncated]\ndef"
+    );
+    // Never split a multi-byte character.
+    assert_eq!(truncate_output("aé", 1, None), "[output truncated]\n");
+}
+
+#[test]
+fn datum_env_vars_describe_datum() {
+    let job = Job::factory();
+    let mut datum = Datum::factory(&job);
+    datum.attempted_run_count = 2;
+    let input_paths = vec!["/pfs/a/in/x.csv".to_owned(), "/pfs/a/in/y.csv".to_owned()];
+    let vars = datum_env_vars(&job, &datum, &input_paths)
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+    assert_eq!(vars["FALCONERI_JOB_ID"], job.id.to_string());
+    assert_eq!(vars["FALCONERI_JOB_NAME"], job.job_name);
+    assert_eq!(vars["FALCONERI_DATUM_ID"], datum.id.to_string());
+    assert_eq!(vars["FALCONERI_ATTEMPT"], "2");
+    assert_eq!(
+        vars["FALCONERI_INPUT_FILES"],
+        "/pfs/a/in/x.csv\n/pfs/a/in/y.csv"
+    );
+}
+
+#[test]
+fn file_sha256_hashes_contents() {
+    let dir = falconeri_common::temp_dir::TempDir::new("falconeri-test").unwrap();
+    let path = dir.join("hello.txt");
+    fs::write(&path, "hello").unwrap();
+    let sha256 = file_sha256(&path);
+    assert_eq!(
+        sha256.unwrap(),
+        "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+    );
+}
+
+#[test]
+fn truncate_streams_truncates_long_streams() {
+    let mut job = Job::factory();
+    job.max_inline_output_bytes = 3;
+    let streams = DatumStreams {
+        stdout: Some("abc".to_owned()),
+        stderr: Some("abcdef".to_owned()),
+    };
+    let streams = truncate_streams(&job, streams, None);
+    assert_eq!(streams.stdout.as_deref(), Some("abc"));
+    assert_eq!(streams.stderr.as_deref(), Some("[output truncated]\ndef"));
 }
@@ -0,0 +1,349 @@
+//! Prometheus metrics for a single worker.
+//!
+//! We deliberately avoid pulling in a metrics library or an HTTP server here,
+//! because this binary gets copied into arbitrary user images. Instead, we keep
+//! a handful of counters and histograms behind a mutex, and serve them in the
+//! Prometheus text format using a tiny hand-written `/metrics` listener.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use falconeri_common::prelude::*;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::input_cache::CacheResult;
+
+/// Environment variable specifying which port to serve `/metrics` on. If this
+/// isn't set, we don't listen at all.
+const METRICS_PORT_VAR: &str = "FALCONERI_WORKER_METRICS_PORT";
+
+/// Histogram bucket boundaries, in seconds. Datums can take anywhere from
+/// milliseconds to many hours, so these are spread out widely.
+const DURATION_BUCKETS: &[f64] = &[
+    0.1,
+    0.5,
+    1.0,
+    5.0,
+    15.0,
+    60.0,
+    300.0,
+    900.0,
+    3600.0,
+    4.0 * 3600.0,
+];
+
+/// A simple Prometheus-style cumulative histogram.
+#[derive(Debug, Default)]
+struct Histogram {
+    /// Counts for each bucket in `DURATION_BUCKETS`. These are _not_
+    /// cumulative; we sum them when rendering.
+    buckets: Vec<u64>,
+    /// The sum of all observed values.
+    sum: f64,
+    /// The number of observed values.
+    count: u64,
+}
+
+impl Histogram {
+    /// Record a duration.
+    fn observe(&mut self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        if self.buckets.is_empty() {
+            self.buckets = vec![0; DURATION_BUCKETS.len()];
+        }
+        if let Some(idx) = DURATION_BUCKETS.iter().position(|&le| secs <= le) {
+            self.buckets[idx] += 1;
+        }
+        self.sum += secs;
+        self.count += 1;
+    }
+
+    /// Write this histogram in Prometheus text format.
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        writeln!(out, "# HELP {} {}", name, help).unwrap();
+        writeln!(out, "# TYPE {} histogram", name).unwrap();
+        let mut cumulative = 0;
+        for (idx, le) in DURATION_BUCKETS.iter().enumerate() {
+            cumulative += self.buckets.get(idx).copied().unwrap_or(0);
+            writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, le, cumulative).unwrap();
+        }
+        writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count).unwrap();
+        writeln!(out, "{}_sum {}", name, self.sum).unwrap();
+        writeln!(out, "{}_count {}", name, self.count).unwrap();
+    }
+}
+
+/// The actual metric values, protected by the mutex in `WorkerMetrics`.
+#[derive(Debug, Default)]
+struct MetricValues {
+    /// Number of datums processed, by final status.
+    datums: BTreeMap<&'static str, u64>,
+    /// How long it took to process each datum, end to end.
+    datum_duration: Histogram,
+    /// Total bytes downloaded.
+    download_bytes: u64,
+    /// How long each input download took.
+    download_duration: Histogram,
+    /// Total bytes uploaded.
+    upload_bytes: u64,
+    /// How long each output upload took.
+    upload_duration: Histogram,
+    /// Number of child processes which exited, by exit code. Processes killed
+    /// by a signal are recorded as `"signal"`.
+    command_exits: BTreeMap<String, u64>,
+    /// Number of input files we looked up in our input cache, by result.
+    input_cache_requests: BTreeMap<&'static str, u64>,
+}
+
+/// Metrics collected by this worker.
+#[derive(Debug, Default)]
+pub struct WorkerMetrics {
+    values: Mutex<MetricValues>,
+}
+
+impl WorkerMetrics {
+    /// Record a datum that finished with the specified status.
+    pub fn record_datum(&self, status: Status, duration: Duration) {
+        let status = match status {
+            Status::Done => "done",
+            _ => "error",
+        };
+        let mut values = self.values.lock().expect("metrics lock poisoned");
+        *values.datums.entry(status).or_default() += 1;
+        values.datum_duration.observe(duration);
+    }
+
+    /// Record a completed download.
+    pub fn record_download(&self, bytes: u64, duration: Duration) {
+        let mut values = self.values.lock().expect("metrics lock poisoned");
+        values.download_bytes += bytes;
+        values.download_duration.observe(duration);
+    }
+
+    /// Record a completed upload.
+    pub fn record_upload(&self, bytes: u64, duration: Duration) {
+        let mut values = self.values.lock().expect("metrics lock poisoned");
+        values.upload_bytes += bytes;
+        values.upload_duration.observe(duration);
+    }
+
+    /// Record the exit code of a child process, or `None` if it was killed
+    /// by a signal.
+    pub fn record_command_exit(&self, code: Option<i32>) {
+        let code = code
+            .map(|c| c.to_string())
+            .unwrap_or_else(|| "signal".to_owned());
+        let mut values = self.values.lock().expect("metrics lock poisoned");
+        *values.command_exits.entry(code).or_default() += 1;
+    }
+
+    /// Record whether we found an input file in our input cache.
+    pub fn record_input_cache(&self, result: CacheResult) {
+        let result = match result {
+            CacheResult::Hit => "hit",
+            CacheResult::Miss => "miss",
+        };
+        let mut values = self.values.lock().expect("metrics lock poisoned");
+        *values.input_cache_requests.entry(result).or_default() += 1;
+    }
+
+    /// Render all our metrics in Prometheus text format.
+    pub fn render(&self) -> String {
+        let values = self.values.lock().expect("metrics lock poisoned");
+        let mut out = String::new();
+
+        writeln!(
+            out,
+            "# HELP falconeri_worker_datums_total Datums processed by this worker."
+        )
+        .unwrap();
+        writeln!(out, "# TYPE falconeri_worker_datums_total counter").unwrap();
+        for (status, count) in &values.datums {
+            writeln!(
+                out,
+                "falconeri_worker_datums_total{{status=\"{}\"}} {}",
+                status, count
+            )
+            .unwrap();
+        }
+        values.datum_duration.render(
+            &mut out,
+            "falconeri_worker_datum_duration_seconds",
+            "Time spent processing each datum.",
+        );
+
+        writeln!(
+            out,
+            "# HELP falconeri_worker_download_bytes_total Bytes of input downloaded."
+        )
+        .unwrap();
+        writeln!(out, "# TYPE falconeri_worker_download_bytes_total counter").unwrap();
+        writeln!(
+            out,
+            "falconeri_worker_download_bytes_total {}",
+            values.download_bytes
+        )
+        .unwrap();
+        values.download_duration.render(
+            &mut out,
+            "falconeri_worker_download_duration_seconds",
+            "Time spent downloading each input file.",
+        );
+
+        writeln!(
+            out,
+            "# HELP falconeri_worker_upload_bytes_total Bytes of output uploaded."
+        )
+        .unwrap();
+        writeln!(out, "# TYPE falconeri_worker_upload_bytes_total counter").unwrap();
+        writeln!(
+            out,
+            "falconeri_worker_upload_bytes_total {}",
+            values.upload_bytes
+        )
+        .unwrap();
+        values.upload_duration.render(
+            &mut out,
+            "falconeri_worker_upload_duration_seconds",
+            "Time spent uploading the outputs of each datum.",
+        );
+
+        writeln!(out, "# HELP falconeri_worker_command_exits_total Child processes exited, by exit code.").unwrap();
+        writeln!(out, "# TYPE falconeri_worker_command_exits_total counter").unwrap();
+        for (code, count) in &values.command_exits {
+            writeln!(
+                out,
+                "falconeri_worker_command_exits_total{{code=\"{}\"}} {}",
+                code, count
+            )
+            .unwrap();
+        }
+
+        writeln!(out, "# HELP falconeri_worker_input_cache_requests_total Input files looked up in the input cache, by result.").unwrap();
+        writeln!(
+            out,
+            "# TYPE falconeri_worker_input_cache_requests_total counter"
+        )
+        .unwrap();
+        for (result, count) in &values.input_cache_requests {
+            writeln!(
+                out,
+                "falconeri_worker_input_cache_requests_total{{result=\"{}\"}} {}",
+                result, count
+            )
+            .unwrap();
+        }
+
+        out
+    }
+}
+
+/// If `FALCONERI_WORKER_METRICS_PORT` is set, serve our metrics on that port
+/// in a background task.
+pub async fn serve_metrics_if_configured(metrics: Arc<WorkerMetrics>) -> Result<()> {
+    let port = match std::env::var(METRICS_PORT_VAR) {
+        Ok(port) => port.parse::<u16>().with_context(|| {
+            format!("could not parse {}={:?}", METRICS_PORT_VAR, port)
+        })?,
+        Err(_) => return Ok(()),
+    };
+    let listener = TcpListener::bind(("0.0.0.0", port))
+        .await
+        .with_context(|| format!("could not listen for metrics on port {}", port))?;
+    debug!("serving metrics on port {}", port);
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let metrics = metrics.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = handle_connection(stream, &metrics).await {
+                            debug!("error serving metrics: {:?}", err);
+                        }
+                    });
+                }
+                Err(err) => warn!("error accepting metrics connection: {}", err),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Answer a single HTTP request. We only care about `GET /metrics`, and we
+/// close the connection after every response.
+#[instrument(skip_all, level = "trace")]
+async fn handle_connection(
+    mut stream: TcpStream,
+    metrics: &WorkerMetrics,
+) -> Result<()> {
+    // We only need the request line, which will fit comfortably in here.
+    let mut buf = vec![0; 4 * 1024];
+    let count = stream
+        .read(&mut buf)
+        .await
+        .context("error reading metrics request")?;
+    let request = String::from_utf8_lossy(&buf[..count]);
+    let request_line = request.lines().next().unwrap_or("");
+
+    let (status, body) = if request_line.starts_with("GET /metrics ") {
+        ("200 OK", metrics.render())
+    } else {
+        ("404 Not Found", "not found\n".to_owned())
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body,
+    );
+    stream
+        .write_all(response.as_bytes())
+        .await
+        .context("error writing metrics response")?;
+    stream
+        .shutdown()
+        .await
+        .context("error closing metrics connection")?;
+    Ok(())
+}
+
+#[test]
+fn render_metrics() {
+    let metrics = WorkerMetrics::default();
+    metrics.record_datum(Status::Done, Duration::from_secs(2));
+    metrics.record_datum(Status::Error, Duration::from_millis(50));
+    metrics.record_download(1024, Duration::from_millis(300));
+    metrics.record_upload(2048, Duration::from_secs(20));
+    metrics.record_command_exit(Some(0));
+    metrics.record_command_exit(Some(0));
+    metrics.record_command_exit(None);
+    metrics.record_input_cache(CacheResult::Hit);
+    metrics.record_input_cache(CacheResult::Hit);
+    metrics.record_input_cache(CacheResult::Miss);
+
+    let rendered = metrics.render();
+    assert!(rendered.contains("falconeri_worker_datums_total{status=\"done\"} 1\n"));
+    assert!(rendered.contains("falconeri_worker_datums_total{status=\"error\"} 1\n"));
+    assert!(rendered
+        .contains("falconeri_worker_datum_duration_seconds_bucket{le=\"0.1\"} 1\n"));
+    assert!(rendered
+        .contains("falconeri_worker_datum_duration_seconds_bucket{le=\"5\"} 2\n"));
+    assert!(rendered.contains("falconeri_worker_datum_duration_seconds_count 2\n"));
+    assert!(rendered.contains("falconeri_worker_download_bytes_total 1024\n"));
+    assert!(rendered.contains("falconeri_worker_upload_bytes_total 2048\n"));
+    assert!(rendered.contains("falconeri_worker_command_exits_total{code=\"0\"} 2\n"));
+    assert!(
+        rendered.contains("falconeri_worker_command_exits_total{code=\"signal\"} 1\n")
+    );
+    assert!(rendered
+        .contains("falconeri_worker_input_cache_requests_total{result=\"hit\"} 2\n"));
+    assert!(rendered
+        .contains("falconeri_worker_input_cache_requests_total{result=\"miss\"} 1\n"));
+}
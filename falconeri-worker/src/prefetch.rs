@@ -0,0 +1,80 @@
+//! Reserving our next datum and downloading its inputs while we're still
+//! uploading the outputs of the current datum.
+//!
+//! Since every datum has its own working directories, we can download the
+//! next datum's inputs directly into place.
+
+use falconeri_common::{prelude::*, rest_api::Client};
+
+use crate::{
+    datum_dirs::DatumDirs, download_input_files, metrics::WorkerMetrics,
+    scheduling::Scheduling,
+};
+
+/// A datum which we've reserved, plus any inputs we've already downloaded.
+pub struct ReservedDatum {
+    /// The datum to process.
+    pub datum: Datum,
+    /// The input files for this datum.
+    pub files: Vec<InputFile>,
+    /// Inputs which we downloaded ahead of time, if any.
+    pub staged: Option<StagedInputs>,
+}
+
+impl ReservedDatum {
+    /// A datum whose inputs have not been downloaded yet.
+    pub fn new(datum: Datum, files: Vec<InputFile>) -> Self {
+        ReservedDatum {
+            datum,
+            files,
+            staged: None,
+        }
+    }
+}
+
+/// Input files which have already been downloaded into a datum's input
+/// directory.
+pub struct StagedInputs {
+    /// How many bytes we downloaded.
+    pub input_bytes: u64,
+}
+
+/// Reserve our next datum and download its inputs into its input directory.
+///
+/// If the download fails, we still return the datum, but without any staged
+/// inputs, and the caller will download them normally.
+#[instrument(skip_all, fields(job = %job.id), level = "debug")]
+pub async fn prefetch_next_datum(
+    client: &Client,
+    metrics: &WorkerMetrics,
+    scheduling: &Scheduling,
+    job: &Job,
+) -> Result<Option<ReservedDatum>> {
+    let (datum, files) = match client.reserve_next_datum(job, true).await? {
+        Some(reserved) => reserved,
+        None => return Ok(None),
+    };
+    debug!("prefetching datum {}", datum.id);
+
+    let dirs = DatumDirs::for_datum(datum.id);
+    let result = async {
+        dirs.create()?;
+        download_input_files(metrics, scheduling, &dirs, &files).await
+    }
+    .await;
+    let staged = match result {
+        Ok(input_bytes) => Some(StagedInputs { input_bytes }),
+        Err(err) => {
+            warn!(
+                "could not prefetch inputs for datum {} (will retry later): {:?}",
+                datum.id, err
+            );
+            None
+        }
+    };
+    Ok(Some(ReservedDatum {
+        datum,
+        files,
+        staged,
+    }))
+}
@@ -0,0 +1,194 @@
+//! Measuring how much memory and CPU time our command uses.
+//!
+//! We can't call `getrusage` without `unsafe`, so we read `/proc` instead.
+//! While our command runs, we sample the memory used by its whole process
+//! tree. Once it exits, we compare our own `cutime` and `cstime` before and
+//! after, which include the CPU time of every descendant which was waited
+//! for.
+
+use std::{
+    collections::HashMap, convert::Infallible, fs, future::Future, time::Duration,
+};
+
+use falconeri_common::{cast, prelude::*};
+
+/// How often to sample our command's memory usage.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Clock ticks per second in `/proc/*/stat`. This is 100 on every Linux
+/// system we care about, and we can't call `sysconf` without `unsafe`.
+const CLOCK_TICKS_PER_SECOND: f64 = 100.0;
+
+/// Run `running`, which should wait for the process `pid` to exit, and record
+/// the resources used by the process and its descendants in `usage`. If we
+/// can't read `/proc`, we leave `usage` alone.
+pub async fn measure<T>(
+    pid: Option<u32>,
+    usage: &mut DatumResourceUsage,
+    running: impl Future<Output = T>,
+) -> T {
+    let cpu_ticks_before = own_children_cpu_ticks()
+        .map_err(|err| debug!("cannot measure CPU time: {:#}", err))
+        .ok();
+    let mut peak_memory_bytes = None;
+    let result = match pid {
+        Some(pid) => tokio::select! {
+            result = running => result,
+            never = sample_memory_periodically(pid, &mut peak_memory_bytes) => {
+                match never {}
+            }
+        },
+        None => running.await,
+    };
+    if let Some(before) = cpu_ticks_before {
+        match own_children_cpu_ticks() {
+            Ok(after) => {
+                usage.cpu_seconds =
+                    Some(after.saturating_sub(before) as f64 / CLOCK_TICKS_PER_SECOND)
+            }
+            Err(err) => debug!("cannot measure CPU time: {:#}", err),
+        }
+    }
+    if let Some(peak) = peak_memory_bytes {
+        usage.peak_memory_bytes = cast::i64(peak).ok();
+    }
+    result
+}
+
+/// Sample the memory used by `pid` and its descendants every
+/// [`SAMPLE_INTERVAL`], keeping the largest value in `peak_memory_bytes`.
+async fn sample_memory_periodically(
+    pid: u32,
+    peak_memory_bytes: &mut Option<u64>,
+) -> Infallible {
+    let mut warned = false;
+    loop {
+        match tokio::task::spawn_blocking(move || tree_memory_bytes(pid)).await {
+            Ok(Ok(bytes)) => {
+                *peak_memory_bytes = Some(peak_memory_bytes.unwrap_or(0).max(bytes))
+            }
+            Ok(Err(err)) if !warned => {
+                debug!("cannot measure memory for process {}: {:#}", pid, err);
+                warned = true;
+            }
+            Ok(Err(_)) => {}
+            Err(err) => error!("memory sampling panicked: {}", err),
+        }
+        tokio::time::sleep(SAMPLE_INTERVAL).await;
+    }
+}
+
+/// How much memory are `pid` and its descendants using? This is the larger of
+/// their combined resident memory right now, and the peak resident memory of
+/// any one of them.
+fn tree_memory_bytes(pid: u32) -> Result<u64> {
+    let mut total_rss = 0;
+    let mut max_hwm = 0;
+    for pid in process_tree(pid)? {
+        // Processes may exit while we're looking at them.
+        let Ok(status) = fs::read_to_string(format!("/proc/{}/status", pid)) else {
+            continue;
+        };
+        let (rss, hwm) = parse_status_memory(&status);
+        total_rss += rss.unwrap_or(0);
+        max_hwm = max_hwm.max(hwm.unwrap_or(0));
+    }
+    Ok(total_rss.max(max_hwm))
+}
+
+/// Find `pid` and all its descendants.
+fn process_tree(pid: u32) -> Result<Vec<u32>> {
+    let mut children = HashMap::<u32, Vec<u32>>::new();
+    for entry in fs::read_dir("/proc").context("cannot list /proc")? {
+        let entry = entry.context("cannot list /proc")?;
+        let Some(child) = entry.file_name().to_str().and_then(|n| n.parse().ok())
+        else {
+            continue;
+        };
+        let Ok(stat) = fs::read_to_string(entry.path().join("stat")) else {
+            continue;
+        };
+        if let Some(parent) = parse_stat(&stat).map(|s| s.ppid) {
+            children.entry(parent).or_default().push(child);
+        }
+    }
+    let mut tree = vec![pid];
+    let mut i = 0;
+    while i < tree.len() {
+        if let Some(kids) = children.get(&tree[i]) {
+            tree.extend(kids);
+        }
+        i += 1;
+    }
+    Ok(tree)
+}
+
+/// How many clock ticks of CPU time have our waited-for descendants used?
+fn own_children_cpu_ticks() -> Result<u64> {
+    let stat = fs::read_to_string("/proc/self/stat")
+        .context("cannot read /proc/self/stat")?;
+    let stat = parse_stat(&stat)
+        .ok_or_else(|| format_err!("cannot parse /proc/self/stat"))?;
+    Ok(stat.cutime + stat.cstime)
+}
+
+/// The fields we need from `/proc/$PID/stat`.
+#[derive(Debug, PartialEq)]
+struct ProcStat {
+    ppid: u32,
+    cutime: u64,
+    cstime: u64,
+}
+
+/// Parse `/proc/$PID/stat`. The command name is in parentheses, and may
+/// contain spaces or parentheses, so we look for the last `)`.
+fn parse_stat(stat: &str) -> Option<ProcStat> {
+    let (_, rest) = stat.rsplit_once(')')?;
+    // Fields after the command name, starting with field 3 (`state`).
+    let fields = rest.split_whitespace().collect::<Vec<_>>();
+    Some(ProcStat {
+        ppid: fields.get(1)?.parse().ok()?,
+        cutime: fields.get(13)?.parse().ok()?,
+        cstime: fields.get(14)?.parse().ok()?,
+    })
+}
+
+/// Parse `VmRSS` and `VmHWM` from `/proc/$PID/status`, in bytes. Kernel
+/// threads have neither.
+fn parse_status_memory(status: &str) -> (Option<u64>, Option<u64>) {
+    let field = |name: &str| {
+        status.lines().find_map(|line| {
+            let value = line.strip_prefix(name)?.strip_prefix(':')?;
+            let kb = value
+                .trim()
+                .strip_suffix("kB")?
+                .trim()
+                .parse::<u64>()
+                .ok()?;
+            Some(kb * 1024)
+        })
+    };
+    (field("VmRSS"), field("VmHWM"))
+}
+
+#[test]
+fn parse_proc_files() {
+    let stat = "1234 (my (odd) cmd) S 42 1234 1234 0 -1 4194560 100 0 0 0 \
+                7 3 250 50 20 0 1 0 100 1000 200";
+    assert_eq!(
+        parse_stat(stat),
+        Some(ProcStat {
+            ppid: 42,
+            cutime: 250,
+            cstime: 50,
+        })
+    );
+    assert_eq!(parse_stat("garbage"), None);
+
+    let status = "Name:\tpython3\nVmHWM:\t  2048 kB\nVmRSS:\t  1024 kB\n";
+    assert_eq!(
+        parse_status_memory(status),
+        (Some(1024 * 1024), Some(2048 * 1024))
+    );
+    assert_eq!(parse_status_memory("Name:\tkthreadd\n"), (None, None));
+}
@@ -0,0 +1,228 @@
+//! Controlling how our child process and our file transfers share the
+//! resources of a worker pod.
+//!
+//! Downloads can easily starve a running command of CPU and disk bandwidth (or
+//! vice versa), so we allow the pipeline spec to run the command with a
+//! different `nice` and `ionice` priority, and we limit how many files we
+//! download at once based on the pod's cgroup limits.
+
+use std::{env, fs, str::FromStr};
+
+use falconeri_common::{cast, pipeline::IoniceClass, prelude::*};
+
+use crate::input_cache::InputCache;
+
+/// Environment variable specifying the CPU niceness of our command.
+const NICE_VAR: &str = "FALCONERI_NICE";
+
+/// Environment variable specifying the I/O scheduling class of our command.
+const IONICE_CLASS_VAR: &str = "FALCONERI_IONICE_CLASS";
+
+/// Environment variable specifying how many files to download at once.
+const DOWNLOAD_CONCURRENCY_VAR: &str = "FALCONERI_DOWNLOAD_CONCURRENCY";
+
+/// Environment variable specifying whether to prefetch our next datum.
+const PREFETCH_NEXT_DATUM_VAR: &str = "FALCONERI_PREFETCH_NEXT_DATUM";
+
+/// The most files we'll download at once unless explicitly told otherwise.
+const MAX_DEFAULT_DOWNLOAD_CONCURRENCY: usize = 8;
+
+/// How much memory we budget for each concurrent download when choosing a
+/// default concurrency. Downloads are streamed, so this is mostly headroom for
+/// buffers and the page cache, leaving the rest of the pod's memory for the
+/// command itself.
+const MEMORY_PER_DOWNLOAD: u64 = 256 * 1024 * 1024;
+
+/// Memory limits above this are really "no limit" on cgroups v1.
+const UNLIMITED_MEMORY_THRESHOLD: u64 = 1 << 60;
+
+/// How our worker should schedule its work.
+#[derive(Debug)]
+pub struct Scheduling {
+    /// The CPU niceness to run our command with, if any.
+    nice: Option<i32>,
+    /// The I/O scheduling class to run our command with, if any.
+    ionice_class: Option<IoniceClass>,
+    /// How many input files to download at once.
+    pub download_concurrency: usize,
+    /// Should we reserve and download our next datum while uploading?
+    pub prefetch_next_datum: bool,
+    /// Where to cache input files shared between datums, if anywhere.
+    pub input_cache: Option<InputCache>,
+}
+
+impl Scheduling {
+    /// Load our scheduling configuration from the environment, falling back to
+    /// defaults based on our cgroup limits.
+    pub fn from_env() -> Result<Self> {
+        let nice = parse_env_var::<i32>(NICE_VAR)?;
+        let ionice_class = parse_env_var::<IoniceClass>(IONICE_CLASS_VAR)?;
+        let download_concurrency =
+            match parse_env_var::<usize>(DOWNLOAD_CONCURRENCY_VAR)? {
+                Some(concurrency) => concurrency.max(1),
+                None => default_download_concurrency(
+                    cgroup_cpu_limit(),
+                    cgroup_memory_limit(),
+                ),
+            };
+        let prefetch_next_datum =
+            parse_env_var::<bool>(PREFETCH_NEXT_DATUM_VAR)?.unwrap_or(false);
+        let input_cache = InputCache::from_env()?;
+        let scheduling = Scheduling {
+            nice,
+            ionice_class,
+            download_concurrency,
+            prefetch_next_datum,
+            input_cache,
+        };
+        debug!("worker scheduling: {:?}", scheduling);
+        Ok(scheduling)
+    }
+
+    /// Wrap `cmd` with `nice` and `ionice` as needed. These must be present
+    /// in the worker image if the corresponding options are used.
+    pub fn wrap_command(&self, cmd: &[String]) -> Vec<String> {
+        let mut wrapped = vec![];
+        if let Some(nice) = self.nice {
+            wrapped.extend(["nice".to_owned(), "-n".to_owned(), nice.to_string()]);
+        }
+        if let Some(ionice_class) = self.ionice_class {
+            wrapped.extend([
+                "ionice".to_owned(),
+                "-c".to_owned(),
+                ionice_class.ionice_arg().to_owned(),
+            ]);
+        }
+        wrapped.extend(cmd.iter().cloned());
+        wrapped
+    }
+}
+
+/// Parse the environment variable `name`, if present.
+pub(crate) fn parse_env_var<T>(name: &str) -> Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    match env::var(name) {
+        Ok(value) => value.parse::<T>().map(Some).map_err(|err| {
+            format_err!("could not parse {}={:?}: {}", name, value, err)
+        }),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Choose how many files to download at once, given our CPU limit (in cores)
+/// and memory limit (in bytes), if any.
+fn default_download_concurrency(cpus: Option<f64>, memory: Option<u64>) -> usize {
+    let mut concurrency = MAX_DEFAULT_DOWNLOAD_CONCURRENCY;
+    if let Some(cpus) = cpus {
+        let by_cpu = cast::usize(cpus.ceil()).unwrap_or(1);
+        concurrency = concurrency.min(by_cpu);
+    }
+    if let Some(memory) = memory {
+        let by_memory =
+            usize::try_from(memory / MEMORY_PER_DOWNLOAD).unwrap_or(usize::MAX);
+        concurrency = concurrency.min(by_memory);
+    }
+    concurrency.max(1)
+}
+
+/// Look up our CPU limit in cores, if we have one. Supports cgroups v2 and v1.
+fn cgroup_cpu_limit() -> Option<f64> {
+    if let Ok(cpu_max) = fs::read_to_string("/sys/fs/cgroup/cpu.max") {
+        return parse_cpu_max(&cpu_max);
+    }
+    let quota = fs::read_to_string("/sys/fs/cgroup/cpu/cpu.cfs_quota_us").ok()?;
+    let period = fs::read_to_string("/sys/fs/cgroup/cpu/cpu.cfs_period_us").ok()?;
+    cpu_limit_from_quota(quota.trim(), period.trim())
+}
+
+/// Parse a cgroups v2 `cpu.max` file, which contains `"$QUOTA $PERIOD"`,
+/// where `$QUOTA` may be `"max"`.
+fn parse_cpu_max(cpu_max: &str) -> Option<f64> {
+    let mut parts = cpu_max.split_whitespace();
+    let quota = parts.next()?;
+    let period = parts.next()?;
+    cpu_limit_from_quota(quota, period)
+}
+
+/// Convert a CFS quota and period into a number of cores. Negative or `"max"`
+/// quotas mean there's no limit.
+fn cpu_limit_from_quota(quota: &str, period: &str) -> Option<f64> {
+    let quota = quota.parse::<f64>().ok()?;
+    let period = period.parse::<f64>().ok()?;
+    if quota <= 0.0 || period <= 0.0 {
+        None
+    } else {
+        Some(quota / period)
+    }
+}
+
+/// Look up our memory limit in bytes, if we have one. Supports cgroups v2 and
+/// v1.
+fn cgroup_memory_limit() -> Option<u64> {
+    let limit = fs::read_to_string("/sys/fs/cgroup/memory.max")
+        .or_else(|_| fs::read_to_string("/sys/fs/cgroup/memory/memory.limit_in_bytes"))
+        .ok()?;
+    parse_memory_limit(&limit)
+}
+
+/// Parse a cgroup memory limit, which may be `"max"` or a very large number if
+/// there's no limit.
+fn parse_memory_limit(limit: &str) -> Option<u64> {
+    let limit = limit.trim().parse::<u64>().ok()?;
+    if limit >= UNLIMITED_MEMORY_THRESHOLD {
+        None
+    } else {
+        Some(limit)
+    }
+}
+
+#[test]
+fn parse_cgroup_limits() {
+    assert_eq!(parse_cpu_max("max 100000\n"), None);
+    assert_eq!(parse_cpu_max("150000 100000\n"), Some(1.5));
+    assert_eq!(cpu_limit_from_quota("-1", "100000"), None);
+    assert_eq!(cpu_limit_from_quota("200000", "100000"), Some(2.0));
+    assert_eq!(parse_memory_limit("max\n"), None);
+    assert_eq!(parse_memory_limit("9223372036854771712\n"), None);
+    assert_eq!(parse_memory_limit("1073741824\n"), Some(1024 * 1024 * 1024));
+}
+
+#[test]
+fn default_download_concurrency_respects_limits() {
+    let gib = 1024 * 1024 * 1024;
+    assert_eq!(
+        default_download_concurrency(None, None),
+        MAX_DEFAULT_DOWNLOAD_CONCURRENCY
+    );
+    assert_eq!(default_download_concurrency(Some(1.5), None), 2);
+    assert_eq!(default_download_concurrency(Some(16.0), Some(gib)), 4);
+    assert_eq!(default_download_concurrency(Some(0.1), Some(64)), 1);
+}
+
+#[test]
+fn wrap_command_with_nice_and_ionice() {
+    let cmd = vec!["python3".to_owned(), "main.py".to_owned()];
+    let scheduling = Scheduling {
+        nice: None,
+        ionice_class: None,
+        download_concurrency: 1,
+        prefetch_next_datum: false,
+        input_cache: None,
+    };
+    assert_eq!(scheduling.wrap_command(&cmd), cmd);
+
+    let scheduling = Scheduling {
+        nice: Some(10),
+        ionice_class: Some(IoniceClass::Idle),
+        download_concurrency: 1,
+        prefetch_next_datum: false,
+        input_cache: None,
+    };
+    assert_eq!(
+        scheduling.wrap_command(&cmd),
+        vec!["nice", "-n", "10", "ionice", "-c", "3", "python3", "main.py"],
+    );
+}
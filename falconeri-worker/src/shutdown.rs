@@ -0,0 +1,52 @@
+//! Noticing when Kubernetes wants to stop our pod.
+//!
+//! When a spot or preemptible node is reclaimed, Kubernetes sends us `SIGTERM`
+//! and gives us a short grace period before killing us. We use that time to
+//! hand our datums back to `falconerid`, so that other workers can pick them
+//! up immediately, instead of waiting for the babysitter to notice that our
+//! pod has vanished.
+
+use falconeri_common::prelude::*;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+};
+
+/// Tracks whether we've been asked to shut down.
+#[derive(Clone)]
+pub struct Shutdown {
+    /// Becomes `true` once we receive `SIGTERM`.
+    requested: watch::Receiver<bool>,
+}
+
+impl Shutdown {
+    /// Start listening for `SIGTERM`.
+    pub fn listen() -> Result<Self> {
+        let mut sigterm =
+            signal(SignalKind::terminate()).context("cannot listen for SIGTERM")?;
+        let (tx, rx) = watch::channel(false);
+        tokio::spawn(async move {
+            if sigterm.recv().await.is_some() {
+                warn!("received SIGTERM, shutting down");
+                // This only fails if nobody is listening any more.
+                let _ = tx.send(true);
+            }
+        });
+        Ok(Shutdown { requested: rx })
+    }
+
+    /// Have we been asked to shut down?
+    pub fn is_requested(&self) -> bool {
+        *self.requested.borrow()
+    }
+
+    /// Wait until we're asked to shut down.
+    pub async fn wait(&self) {
+        let mut requested = self.requested.clone();
+        if requested.wait_for(|&requested| requested).await.is_err() {
+            // Our signal handler went away, so we'll never be asked to shut
+            // down.
+            std::future::pending::<()>().await;
+        }
+    }
+}
@@ -0,0 +1,275 @@
+//! Streaming mode, for commands which are simple filters.
+//!
+//! Instead of downloading input files to `/pfs` and uploading whatever the
+//! command leaves in the output directory, we stream each input file into the
+//! command's standard input, and upload its standard output directly to our
+//! egress bucket. This avoids touching the disk, and lets the command start
+//! working before the first download has finished.
+
+use std::{io, process::Stdio, sync::Arc, time::Instant};
+
+use falconeri_common::{
+    cast,
+    prelude::*,
+    rest_api::{Client, OutputFilePatch, OutputFilePost},
+    storage::CloudStorage,
+};
+use tokio::{
+    io::AsyncWriteExt,
+    process::{ChildStdin, ChildStdout, Command},
+    sync::RwLock,
+};
+
+use crate::{
+    captured_output::{CapturedOutput, OutputStream},
+    datum_env_vars,
+    exit_codes::CommandFailed,
+    metrics::WorkerMetrics,
+    reset_work_dir, resource_usage,
+    scheduling::{parse_env_var, Scheduling},
+    tee_output,
+};
+
+/// Environment variable specifying whether to use streaming mode.
+const STDIN_FILES_VAR: &str = "FALCONERI_STDIN_FILES";
+
+/// Have we been asked to stream input files through our command?
+pub fn stdin_files_enabled() -> Result<bool> {
+    Ok(parse_env_var::<bool>(STDIN_FILES_VAR)?.unwrap_or(false))
+}
+
+/// Process a single datum by streaming its input files through `cmd`,
+/// recording when each phase finishes in `timings`, how much data we moved in
+/// `byte_counts`, and what our command used in `resource_usage`.
+///
+/// The command's standard output is uploaded to `$EGRESS/$DATUM_ID`, and its
+/// standard error is recorded as the datum's output.
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip_all, fields(job = %job.id, datum = %datum.id), level = "trace")]
+pub async fn process_datum(
+    client: &Client,
+    metrics: &WorkerMetrics,
+    scheduling: &Scheduling,
+    job: &Job,
+    datum: &Datum,
+    files: &[InputFile],
+    cmd: &[String],
+    to_record: Arc<RwLock<CapturedOutput>>,
+    timings: &mut DatumTimings,
+    byte_counts: &mut DatumByteCounts,
+    resource_usage: &mut DatumResourceUsage,
+) -> Result<()> {
+    debug!("streaming datum {}", datum.id);
+    timings.started_at = Some(Utc::now().naive_utc());
+    reset_work_dir(Path::new("/scratch/"))?;
+
+    // We can only stream individual files.
+    if let Some(file) = files.iter().find(|f| f.uri.ends_with('/')) {
+        return Err(format_err!(
+            "cannot use `stdin_files` with directory input {}",
+            file.uri
+        ));
+    }
+    if let Some(file) = files.iter().find(|f| f.decompress) {
+        return Err(format_err!(
+            "cannot use `stdin_files` with `decompress` input {}",
+            file.uri
+        ));
+    }
+    if cmd.is_empty() {
+        return Err(format_err!("job {} command is empty", job.id));
+    }
+
+    // Create a database record for the file we're about to upload.
+    let uri = output_uri(job, datum);
+    let output_files = client
+        .create_output_files(datum, &[OutputFilePost { uri: uri.clone() }])
+        .await?;
+
+    // Run our command.
+    let input_uris = files.iter().map(|f| f.uri.clone()).collect::<Vec<_>>();
+    let cmd = scheduling.wrap_command(cmd);
+    let mut child = Command::new(&cmd[0])
+        .args(&cmd[1..])
+        .envs(datum_env_vars(job, datum, &input_uris))
+        .kill_on_drop(true)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("could not run {:?}", &cmd[0]))?;
+    let stdin = child
+        .stdin
+        .take()
+        .expect("child should always have a stdin");
+    let stdout = child
+        .stdout
+        .take()
+        .expect("child should always have a stdout");
+    let stderr = child
+        .stderr
+        .take()
+        .expect("child should always have a stderr");
+
+    // Feed our input files to the command while uploading its output, and
+    // measure its resource usage until it exits.
+    let pid = child.id();
+    let ((feed_result, upload_result, stderr_result), status) =
+        resource_usage::measure(pid, resource_usage, async {
+            let results = tokio::join!(
+                feed_stdin(metrics, files, stdin),
+                upload_stdout(metrics, stdout, &uri),
+                tee_output(
+                    stderr,
+                    tokio::io::stderr(),
+                    OutputStream::Stderr,
+                    to_record
+                ),
+            );
+            (results, child.wait().await)
+        })
+        .await;
+    let status = status.with_context(|| format!("error running {:?}", &cmd[0]))?;
+    timings.command_completed_at = Some(Utc::now().naive_utc());
+    metrics.record_command_exit(status.code());
+
+    // Record what happened to our output.
+    let output_status =
+        if feed_result.is_ok() && upload_result.is_ok() && status.success() {
+            Status::Done
+        } else {
+            Status::Error
+        };
+    let patches = output_files
+        .iter()
+        .map(|f| OutputFilePatch {
+            id: f.id,
+            status: output_status,
+            size: upload_result.as_ref().ok().copied(),
+            sha256: None,
+        })
+        .collect::<Vec<_>>();
+    client.patch_output_files(datum, &patches).await?;
+    timings.upload_completed_at = Some(Utc::now().naive_utc());
+
+    // Report the command's failure first, because that's usually the reason
+    // why our streams failed.
+    if !status.success() {
+        return Err(CommandFailed { cmd, status }.into());
+    }
+    stderr_result?;
+    let (input_bytes, download_completed_at) =
+        feed_result.context("could not stream inputs")?;
+    let output_bytes = upload_result.context("could not upload outputs")?;
+    timings.download_completed_at = Some(download_completed_at);
+    byte_counts.input_bytes = Some(cast::i64(input_bytes)?);
+    byte_counts.output_bytes = Some(cast::i64(output_bytes)?);
+    reset_work_dir(Path::new("/scratch/"))?;
+    Ok(())
+}
+
+/// Where should we upload the output of `datum`?
+fn output_uri(job: &Job, datum: &Datum) -> String {
+    let mut uri = job.egress_uri.clone();
+    if !uri.ends_with('/') {
+        uri.push('/');
+    }
+    uri.push_str(&datum.id.to_string());
+    uri
+}
+
+/// Stream each of `files` into `stdin`, and then close it. Returns the number
+/// of bytes streamed, and when we finished.
+///
+/// If the command closes its standard input before reading everything, as
+/// `head` does, we stop streaming without an error. If the command then
+/// fails, the caller will report that.
+#[instrument(skip_all, level = "trace")]
+async fn feed_stdin(
+    metrics: &WorkerMetrics,
+    files: &[InputFile],
+    mut stdin: ChildStdin,
+) -> Result<(u64, NaiveDateTime)> {
+    let started_at = Instant::now();
+    let mut input_bytes = 0;
+    let result = async {
+        for file in files {
+            let storage = <dyn CloudStorage>::for_worker_input_uri(&file.uri).await?;
+            input_bytes += storage.download_to_writer(&file.uri, &mut stdin).await?;
+        }
+        stdin
+            .shutdown()
+            .await
+            .context("could not close standard input")
+    }
+    .await;
+    match result {
+        Ok(()) => {}
+        Err(err) if is_broken_pipe(&err) => {
+            debug!("command stopped reading its input: {:#}", err);
+        }
+        Err(err) => return Err(err),
+    }
+    metrics.record_download(input_bytes, started_at.elapsed());
+    Ok((input_bytes, Utc::now().naive_utc()))
+}
+
+/// Did `err` happen because the reader of a pipe closed it?
+fn is_broken_pipe(err: &Error) -> bool {
+    err.chain().any(|cause| {
+        cause
+            .downcast_ref::<io::Error>()
+            .is_some_and(|io_err| io_err.kind() == io::ErrorKind::BrokenPipe)
+    })
+}
+
+/// Upload everything written to `stdout` to `uri`, returning the number of
+/// bytes uploaded.
+#[instrument(skip_all, fields(uri = %uri), level = "trace")]
+async fn upload_stdout(
+    metrics: &WorkerMetrics,
+    mut stdout: ChildStdout,
+    uri: &str,
+) -> Result<u64> {
+    let storage = <dyn CloudStorage>::for_uri(uri, &[]).await?;
+    let started_at = Instant::now();
+    let output_bytes = storage.upload_from_reader(&mut stdout, uri).await?;
+    metrics.record_upload(output_bytes, started_at.elapsed());
+    Ok(output_bytes)
+}
+
+#[test]
+fn output_uri_is_named_after_datum() {
+    let mut job = Job::factory();
+    let datum = Datum::factory(&job);
+    job.egress_uri = "gs://bucket/out".to_owned();
+    assert_eq!(
+        output_uri(&job, &datum),
+        format!("gs://bucket/out/{}", datum.id)
+    );
+}
+
+#[tokio::test]
+async fn feed_stdin_stops_when_command_stops_reading() {
+    use falconeri_common::storage::memory::MemoryStorage;
+
+    // Send more than fits in a pipe's buffer, so that we're still writing when
+    // `head` exits.
+    let uri = "mem://streaming-test/head/input.txt";
+    MemoryStorage::shared().insert(uri, vec![b'x'; 1024 * 1024]);
+    let job = Job::factory();
+    let datum = Datum::factory(&job);
+    let mut file = InputFile::factory(&datum);
+    file.uri = uri.to_owned();
+
+    let mut child = Command::new("head")
+        .args(["-c1"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    let stdin = child.stdin.take().unwrap();
+    let metrics = WorkerMetrics::default();
+    feed_stdin(&metrics, &[file], stdin).await.unwrap();
+    assert!(child.wait().await.unwrap().success());
+}
@@ -0,0 +1,87 @@
+//! The `api-token` subcommand, for managing the tokens which let people and
+//! scripts talk to `falconerid` directly.
+
+use clap::Subcommand;
+use falconeri_common::{prelude::*, rest_api::Client};
+use prettytable::{format::consts::FORMAT_CLEAN, row, Table};
+
+use crate::output::OutputFormat;
+
+/// Commands for managing API tokens. These require the admin password, so
+/// run them using `falconeri proxy` rather than with a token.
+#[derive(Debug, Subcommand)]
+pub enum Opt {
+    /// Create a new token, and print it. The token can't be shown again.
+    #[command(name = "create")]
+    Create {
+        /// A name describing who will use this token.
+        name: String,
+
+        /// Only allow this token to submit and change jobs for this team, so
+        /// that the team's quota applies to everything it does.
+        #[arg(long = "team")]
+        team: Option<String>,
+    },
+
+    /// List all tokens, including revoked ones.
+    #[command(name = "list")]
+    List,
+
+    /// Revoke a token, so that it can no longer be used.
+    #[command(name = "revoke")]
+    Revoke {
+        /// The ID of the token to revoke, as shown by `list`.
+        id: Uuid,
+    },
+}
+
+/// Run the `api-token` subcommand.
+#[instrument(skip_all, level = "trace")]
+pub async fn run(opt: &Opt, output: OutputFormat) -> Result<()> {
+    match opt {
+        Opt::Create { name, team } => {
+            let client = Client::new(ConnectVia::Proxy).await?;
+            let created = client.create_api_token(name, team.as_deref()).await?;
+            eprintln!("Created API token {}.", created.api_token.id);
+            println!("{}", created.token);
+            Ok(())
+        }
+        Opt::List => run_list(output).await,
+        Opt::Revoke { id } => {
+            let client = Client::new(ConnectVia::Proxy).await?;
+            client.revoke_api_token(*id).await?;
+            Ok(())
+        }
+    }
+}
+
+/// List API tokens.
+#[instrument(level = "trace")]
+async fn run_list(output: OutputFormat) -> Result<()> {
+    let client = Client::new(ConnectVia::Proxy).await?;
+    let api_tokens = client.list_api_tokens().await?;
+    output.print(&api_tokens, |api_tokens| {
+        print_table(api_tokens);
+        Ok(())
+    })
+}
+
+/// Print `api_tokens` as a table.
+fn print_table(api_tokens: &[ApiToken]) {
+    let mut table = Table::new();
+    table.set_format(*FORMAT_CLEAN);
+    table.add_row(row!["ID", "NAME", "TEAM", "CREATED_AT", "REVOKED_AT"]);
+    for api_token in api_tokens {
+        table.add_row(row![
+            api_token.id,
+            &api_token.name,
+            api_token.team.as_deref().unwrap_or_default(),
+            api_token.created_at,
+            api_token
+                .revoked_at
+                .map(|revoked_at| revoked_at.to_string())
+                .unwrap_or_default(),
+        ]);
+    }
+    table.printstd();
+}
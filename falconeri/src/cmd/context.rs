@@ -0,0 +1,69 @@
+//! The `context` subcommand, for switching between clusters.
+
+use clap::Subcommand;
+use falconeri_common::prelude::*;
+use prettytable::{format::consts::FORMAT_CLEAN, row, Table};
+
+use crate::config::Config;
+
+/// Commands for managing contexts in our config file.
+#[derive(Debug, Subcommand)]
+pub enum Opt {
+    /// List the contexts in our config file.
+    #[command(name = "list")]
+    List,
+
+    /// Use the specified context by default.
+    #[command(name = "use")]
+    Use {
+        /// The name of the context to use.
+        name: String,
+    },
+}
+
+/// Run the `context` subcommand.
+#[instrument(skip_all, level = "trace")]
+pub async fn run(opt: &Opt) -> Result<()> {
+    let path = Config::path()?;
+    let mut config = Config::load(&path)?;
+    match opt {
+        Opt::List => {
+            if config.contexts.is_empty() {
+                eprintln!("No contexts defined in {}", path.display());
+                return Ok(());
+            }
+            let mut table = Table::new();
+            table.set_format(*FORMAT_CLEAN);
+            table.add_row(row![
+                "CURRENT",
+                "NAME",
+                "KUBE_CONTEXT",
+                "NAMESPACE",
+                "FALCONERID_URL"
+            ]);
+            for (name, context) in &config.contexts {
+                let current = if config.current_context.as_ref() == Some(name) {
+                    "*"
+                } else {
+                    ""
+                };
+                table.add_row(row![
+                    current,
+                    name,
+                    context.kube_context.as_deref().unwrap_or("-"),
+                    context.namespace.as_deref().unwrap_or("-"),
+                    context.falconerid_url.as_deref().unwrap_or("-"),
+                ]);
+            }
+            table.printstd();
+            Ok(())
+        }
+        Opt::Use { name } => {
+            config.context(name)?;
+            config.current_context = Some(name.to_owned());
+            config.save(&path)?;
+            println!("Switched to context {:?}", name);
+            Ok(())
+        }
+    }
+}
@@ -1,31 +1,80 @@
 //! The `datum describe` subcommand.
 
-use falconeri_common::{prelude::*, rest_api::Client};
+use falconeri_common::{
+    ops,
+    prelude::*,
+    rest_api::{Client, DatumDescribeResponse},
+};
 
-use crate::description::render_description;
+use crate::{
+    description::{read_exported_description, render_description},
+    output::OutputFormat,
+};
 
 /// Template for human-readable `describe` output.
 const DESCRIBE_TEMPLATE: &str = include_str!("describe.txt.hbs");
 
 /// Run the `datum describe` subcommand.
-pub async fn run(id: Uuid) -> Result<()> {
-    // Look up our data via the REST API.
-    let client = Client::new(ConnectVia::Proxy).await?;
-    let params = client.describe_datum(id).await?;
+pub async fn run(
+    id: Option<Uuid>,
+    from_file: Option<&Path>,
+    output: OutputFormat,
+) -> Result<()> {
+    // Look up our data via the REST API, or from an exported file.
+    let params: DatumDescribeResponse = if let Some(path) = from_file {
+        read_exported_description(path)?
+    } else {
+        let id = id.ok_or_else(|| format_err!("must specify a datum ID"))?;
+        let client = Client::new(ConnectVia::Proxy).await?;
+        ops::describe_datum(&client, id).await?
+    };
 
     // Print the description.
-    print!("{}", render_description(DESCRIBE_TEMPLATE, &params)?);
-    Ok(())
+    output.print(&params, |params| {
+        print!("{}", render_description(DESCRIBE_TEMPLATE, params)?);
+        Ok(())
+    })
 }
 
 #[test]
 fn render_template() {
-    use falconeri_common::rest_api::DatumDescribeResponse;
-
     let job = Job::factory();
-    let datum = Datum::factory(&job);
+    let mut datum = Datum::factory(&job);
+    let now = Utc::now().naive_utc();
+    datum.started_at = Some(now);
+    datum.download_completed_at = Some(now);
+    datum.peak_memory_bytes = Some(1048576);
     let input_file = InputFile::factory(&datum);
     let input_files = vec![input_file];
     let params = DatumDescribeResponse { datum, input_files };
-    render_description(DESCRIBE_TEMPLATE, &params).expect("could not render template");
+    let description = render_description(DESCRIBE_TEMPLATE, &params)
+        .expect("could not render template");
+    assert!(description
+        .contains("gs://example-bucket/input/file.csv  1024 bytes  modified "));
+    assert!(description.contains("Peak Memory Bytes: 1048576\n"));
+    assert!(!description.contains("CPU Seconds"));
+}
+
+#[test]
+fn render_template_with_separate_streams() {
+    let job = Job::factory();
+    let mut datum = Datum::factory(&job);
+    datum.output = Some("combined output".to_owned());
+    let params = DatumDescribeResponse {
+        datum: datum.clone(),
+        input_files: vec![],
+    };
+    let description = render_description(DESCRIBE_TEMPLATE, &params).unwrap();
+    assert!(description.contains("Output:\ncombined output"));
+
+    datum.stdout = Some("result=42".to_owned());
+    datum.stderr = Some("warning".to_owned());
+    let params = DatumDescribeResponse {
+        datum,
+        input_files: vec![],
+    };
+    let description = render_description(DESCRIBE_TEMPLATE, &params).unwrap();
+    assert!(description.contains("Stdout:\nresult=42"));
+    assert!(description.contains("Stderr:\nwarning"));
+    assert!(!description.contains("combined output"));
 }
@@ -0,0 +1,175 @@
+//! The `datum logs` subcommand.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use falconeri_common::{ops, prelude::*, rest_api::Client, storage::CloudStorage};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// How often to check for new output when following a datum.
+const FOLLOW_INTERVAL: Duration = Duration::from_secs(2);
+
+/// One of the output streams of a datum's command.
+#[derive(Clone, Copy, Debug)]
+pub enum LogStream {
+    /// Standard output.
+    Stdout,
+    /// Standard error.
+    Stderr,
+}
+
+/// The `datum logs` subcommand. Prints a datum's output, downloading the full
+/// output if it was too long to store in the database. If `stream` is
+/// specified, we only print that stream, as stored in the database.
+#[instrument(level = "trace")]
+pub async fn run(id: Uuid, stream: Option<LogStream>) -> Result<()> {
+    let client = Client::new(ConnectVia::Proxy).await?;
+    let datum = ops::describe_datum(&client, id).await?.datum;
+
+    // We only upload the combined output, so print separate streams from the
+    // database.
+    if let Some(stream) = stream {
+        let (name, output) = match stream {
+            LogStream::Stdout => ("stdout", &datum.stdout),
+            LogStream::Stderr => ("stderr", &datum.stderr),
+        };
+        return match output {
+            Some(output) => {
+                print!("{}", output);
+                Ok(())
+            }
+            None => Err(format_err!(
+                "datum {} has no separate {} (it may still be running, or have been processed by an older worker)",
+                id,
+                name,
+            )),
+        };
+    }
+
+    if let Some(uri) = &datum.output_uri {
+        let mut stdout = CountingWriter::new(tokio::io::stdout());
+        match print_offloaded_output(uri, &mut stdout).await {
+            Ok(()) => return Ok(()),
+            // If we haven't printed anything yet, the truncated copy still has
+            // the end of the output, and a note saying where the full output
+            // is.
+            Err(err) if stdout.written == 0 => warn!(
+                "could not download full output from {}, so showing the truncated output: {:#}",
+                uri, err
+            ),
+            // Otherwise, printing the truncated copy would garble what we've
+            // already printed.
+            Err(err) => {
+                return Err(err.context(format!(
+                    "error printing full output from {} after {} bytes",
+                    uri, stdout.written
+                )));
+            }
+        }
+    }
+    match &datum.output {
+        Some(output) => print!("{}", output),
+        None => warn!("datum {} has no output yet (status {})", id, datum.status),
+    }
+    Ok(())
+}
+
+/// Print a datum's output as it runs, until it finishes. Workers send new
+/// output every few seconds.
+#[instrument(level = "trace")]
+pub async fn follow(id: Uuid) -> Result<()> {
+    let client = Client::new(ConnectVia::Proxy).await?;
+    let mut printed = String::new();
+    loop {
+        let datum = ops::describe_datum(&client, id).await?.datum;
+        let output = datum.output.unwrap_or_default();
+        if let Some(new_output) = output.strip_prefix(printed.as_str()) {
+            print!("{}", new_output);
+        } else if datum.status.has_finished() {
+            // The final output may be truncated, or include notes from the
+            // worker, so it won't always match what we've shown.
+            warn!(
+                "the final output of datum {} differs from what we've shown; run `falconeri datum logs {}` to see it",
+                id, id
+            );
+        } else {
+            warn!("datum {} was restarted", id);
+            print!("{}", output);
+        }
+        io::stdout().flush()?;
+        printed = output;
+
+        if datum.status.has_finished() {
+            return Ok(());
+        }
+        tokio::time::sleep(FOLLOW_INTERVAL).await;
+    }
+}
+
+/// Copy the output at `uri` to `stdout`.
+#[instrument(skip(stdout), level = "debug")]
+async fn print_offloaded_output(
+    uri: &str,
+    stdout: &mut (dyn AsyncWrite + Send + Unpin),
+) -> Result<()> {
+    let storage = <dyn CloudStorage>::for_uri(uri, &[]).await?;
+    storage.download_to_writer(uri, stdout).await?;
+    stdout.flush().await.context("error writing to stdout")?;
+    Ok(())
+}
+
+/// Wraps a writer, and counts how many bytes have been written to it.
+struct CountingWriter<W> {
+    /// The writer we wrap.
+    inner: W,
+    /// The number of bytes written so far.
+    written: u64,
+}
+
+impl<W> CountingWriter<W> {
+    /// Wrap `inner`.
+    fn new(inner: W) -> Self {
+        CountingWriter { inner, written: 0 }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for CountingWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(count)) = &result {
+            self.written += *count as u64;
+        }
+        result
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[tokio::test]
+async fn counting_writer_counts_bytes() {
+    let mut writer = CountingWriter::new(Vec::new());
+    writer.write_all(b"hello ").await.unwrap();
+    writer.write_all(b"world").await.unwrap();
+    assert_eq!(writer.written, 11);
+    assert_eq!(writer.inner, b"hello world");
+}
@@ -3,7 +3,10 @@
 use clap::Subcommand;
 use falconeri_common::prelude::*;
 
+use crate::output::OutputFormat;
+
 mod describe;
+mod logs;
 
 /// `datum` options.
 #[derive(Debug, Subcommand)]
@@ -12,13 +15,65 @@ pub enum Opt {
     #[command(name = "describe")]
     Describe {
         /// The UUID of the datum to describe.
+        #[arg(required_unless_present = "from_file")]
+        id: Option<Uuid>,
+
+        /// Describe a datum using JSON previously exported from
+        /// `GET /datums/{datum_id}/describe`, instead of asking the server.
+        #[arg(long = "from-file", conflicts_with = "id")]
+        from_file: Option<PathBuf>,
+    },
+
+    /// Print the output of a specific datum.
+    #[command(name = "logs")]
+    Logs {
+        /// The UUID of the datum.
         id: Uuid,
+
+        /// Only print the command's stdout.
+        #[arg(long = "stdout-only", conflicts_with = "stderr_only")]
+        stdout_only: bool,
+
+        /// Only print the command's stderr.
+        #[arg(long = "stderr-only")]
+        stderr_only: bool,
+
+        /// Keep printing new output until the datum finishes.
+        #[arg(
+            long = "follow",
+            short = 'f',
+            conflicts_with_all = ["stdout_only", "stderr_only"]
+        )]
+        follow: bool,
     },
 }
 
-/// Run the `job` subcommand.
-pub async fn run(opt: &Opt) -> Result<()> {
+/// Run the `datum` subcommand.
+pub async fn run(opt: &Opt, output: OutputFormat) -> Result<()> {
     match opt {
-        Opt::Describe { id } => describe::run(*id).await,
+        Opt::Describe { id, from_file } => {
+            describe::run(*id, from_file.as_deref(), output).await
+        }
+        Opt::Logs { .. } if output != OutputFormat::Table => {
+            Err(format_err!("`datum logs` does not support --output"))
+        }
+        Opt::Logs {
+            id, follow: true, ..
+        } => logs::follow(*id).await,
+        Opt::Logs {
+            id,
+            stdout_only,
+            stderr_only,
+            ..
+        } => {
+            let stream = if *stdout_only {
+                Some(logs::LogStream::Stdout)
+            } else if *stderr_only {
+                Some(logs::LogStream::Stderr)
+            } else {
+                None
+            };
+            logs::run(*id, stream).await
+        }
     }
 }
@@ -3,7 +3,10 @@
 use std::process;
 
 use clap::Subcommand;
-use falconeri_common::{db, prelude::*};
+use falconeri_common::{cast, db, prelude::*};
+use prettytable::{format::consts::FORMAT_CLEAN, row, Table};
+
+use crate::output::OutputFormat;
 
 /// Commands for interacting with the database.
 #[derive(Debug, Subcommand)]
@@ -14,6 +17,9 @@ pub enum Opt {
     /// Print our a URL for connecting to the database.
     #[command(name = "url")]
     Url,
+    /// Show which migrations have been applied, and how big our tables are.
+    #[command(name = "status")]
+    Status,
 }
 
 /// Run the `db` subcommand.
@@ -21,10 +27,11 @@ pub enum Opt {
 /// These commands are async because we need to fetch the database URL via kubectl,
 /// but the actual psql execution stays sync (it's interactive with inherited stdio).
 #[instrument(skip_all, level = "trace")]
-pub async fn run(opt: &Opt) -> Result<()> {
+pub async fn run(opt: &Opt, output: OutputFormat) -> Result<()> {
     match opt {
         Opt::Console => run_console().await,
         Opt::Url => run_url().await,
+        Opt::Status => run_status(output).await,
     }
 }
 
@@ -52,3 +59,173 @@ async fn run_url() -> Result<()> {
     println!("{}", url);
     Ok(())
 }
+
+/// The status of our database, as printed by `db status`.
+#[derive(Debug, Serialize)]
+struct DbStatus {
+    /// Every migration which we know about or which has been applied.
+    migrations: Vec<MigrationStatus>,
+    /// Size statistics for our tables, largest first.
+    tables: Vec<db::TableStats>,
+    /// How well we're compressing datum output and backtraces.
+    text_compression: db::TextCompressionStats,
+}
+
+/// Whether a migration has been applied.
+#[derive(Debug, Serialize)]
+struct MigrationStatus {
+    /// The version of the migration.
+    version: String,
+    /// `applied`, `pending`, or `unknown` if the database has a migration
+    /// which this version of falconeri doesn't know about.
+    status: &'static str,
+}
+
+/// Print out our migration status and table sizes.
+#[instrument(level = "trace")]
+async fn run_status(output: OutputFormat) -> Result<()> {
+    let mut conn = db::async_connect(ConnectVia::Proxy).await?;
+
+    // Compare our migrations to the ones in the database.
+    let embedded = db::embedded_migration_versions()?;
+    let applied = db::applied_migration_versions(&mut conn).await?;
+    let mut migrations = embedded
+        .iter()
+        .map(|version| MigrationStatus {
+            version: version.to_owned(),
+            status: if applied.contains(version) {
+                "applied"
+            } else {
+                "pending"
+            },
+        })
+        .collect::<Vec<_>>();
+    migrations.extend(
+        applied
+            .iter()
+            .filter(|version| !embedded.contains(version))
+            .map(|version| MigrationStatus {
+                version: version.to_owned(),
+                status: "unknown",
+            }),
+    );
+
+    let status = DbStatus {
+        migrations,
+        tables: db::table_stats(&mut conn).await?,
+        text_compression: db::text_compression_stats(&mut conn).await?,
+    };
+    output.print(&status, |status| {
+        print_status(status);
+        Ok(())
+    })
+}
+
+/// Print `status` for humans.
+fn print_status(status: &DbStatus) {
+    let mut migrations = Table::new();
+    migrations.set_format(*FORMAT_CLEAN);
+    migrations.add_row(row!["MIGRATION", "STATUS"]);
+    for migration in &status.migrations {
+        migrations.add_row(row![migration.version, migration.status]);
+    }
+    migrations.printstd();
+
+    // Summarize our migration status.
+    let count = |wanted: &str| {
+        status
+            .migrations
+            .iter()
+            .filter(|migration| migration.status == wanted)
+            .count()
+    };
+    let pending = count("pending");
+    let unknown = count("unknown");
+    println!();
+    if pending > 0 {
+        println!(
+            "{} pending migration(s). Run `falconeri migrate` to apply them.",
+            pending
+        );
+    } else {
+        println!("Database schema is up to date.");
+    }
+    if unknown > 0 {
+        println!(
+            "{} migration(s) are unknown to this version of falconeri, which may be older than the server.",
+            unknown
+        );
+    }
+
+    // Show our table sizes.
+    println!();
+    let mut tables = Table::new();
+    tables.set_format(*FORMAT_CLEAN);
+    tables.add_row(row![
+        "TABLE",
+        "LIVE_ROWS",
+        "DEAD_ROWS",
+        "SIZE",
+        "LAST_VACUUM"
+    ]);
+    for stats in &status.tables {
+        tables.add_row(row![
+            stats.table_name,
+            stats.live_rows,
+            stats.dead_rows,
+            format_bytes(stats.total_bytes),
+            stats
+                .last_vacuum_at
+                .map(|at| at.to_string())
+                .unwrap_or_else(|| "-".to_owned()),
+        ]);
+    }
+    tables.printstd();
+
+    // Show how well we're compressing datum output.
+    println!();
+    let compression = &status.text_compression;
+    match compression.ratio() {
+        Some(ratio) => println!(
+            "Datum output and backtraces: in a sample of {} datums, {} values were compressed from {} to {} ({:.1}x smaller).",
+            compression.sampled_datums,
+            compression.compressed_values,
+            format_bytes(compression.uncompressed_bytes),
+            format_bytes(compression.compressed_bytes),
+            ratio,
+        ),
+        None => println!(
+            "No compressed datum output or backtraces in a sample of {} datums.",
+            compression.sampled_datums
+        ),
+    }
+    if compression.pending_datums > 0 {
+        println!(
+            "{} older datums are waiting to have their output compressed by the babysitter.",
+            compression.pending_datums
+        );
+    }
+}
+
+/// Format a number of bytes for display.
+pub fn format_bytes(bytes: i64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = cast::f64(bytes) / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+#[test]
+fn format_bytes_uses_binary_units() {
+    assert_eq!(format_bytes(0), "0 B");
+    assert_eq!(format_bytes(1023), "1023 B");
+    assert_eq!(format_bytes(1536), "1.5 KiB");
+    assert_eq!(format_bytes(8 * 1024 * 1024 * 1024), "8.0 GiB");
+}
@@ -1,14 +1,18 @@
 //! The `deploy` subcommand.
 
-use std::iter;
+use std::{env, fs, iter, time::Duration};
 
-use clap::Args;
+use clap::{Args, Subcommand, ValueEnum};
 use falconeri_common::{
     base64::{prelude::BASE64_STANDARD, Engine},
-    kubernetes,
+    db, falconeri_common_version,
+    kubernetes::{self, base64_encoded_optional_secret_string},
     manifest::render_manifest,
     prelude::*,
     rand::{distr::Alphanumeric, rngs::StdRng, Rng, SeedableRng},
+    rest_api::Client,
+    semver::Version,
+    serde_json,
 };
 
 /// The manifest defining secrets for `falconeri`.
@@ -17,6 +21,20 @@ const SECRET_MANIFEST: &str = include_str!("secret_manifest.yml.hbs");
 /// The manifest we use to deploy `falconeri`.
 const DEPLOY_MANIFEST: &str = include_str!("deploy_manifest.yml.hbs");
 
+/// The `Chart.yaml` file for our Helm chart.
+const HELM_CHART_YAML: &str = concat!(
+    "apiVersion: v2\n",
+    "name: falconeri\n",
+    "description: Distributed batch processing for Kubernetes\n",
+    "type: application\n",
+    "version: ",
+    env!("CARGO_PKG_VERSION"),
+    "\n",
+    "appVersion: \"",
+    env!("CARGO_PKG_VERSION"),
+    "\"\n",
+);
+
 /// Parameters used to generate a secret manifest.
 #[derive(Serialize)]
 struct SecretManifestParams {
@@ -34,6 +52,31 @@ struct SecretManifestParams {
     minio_root_password: String,
     /// The MinIO endpoint URL for the s3 secret.
     minio_endpoint_url: String,
+    /// A secret for pulling images from a private registry, if requested.
+    registry_secret: Option<RegistrySecret>,
+}
+
+/// A Kubernetes secret for pulling images from a private registry.
+#[derive(Serialize)]
+struct RegistrySecret {
+    /// The name of the secret.
+    name: String,
+    /// The base64-encoded contents of a Docker `config.json` file.
+    docker_config_json: String,
+}
+
+impl RegistrySecret {
+    /// Load a registry secret from a Docker `config.json` file.
+    fn from_docker_config(name: &str, path: &Path) -> Result<Self> {
+        let docker_config = fs::read(path)
+            .with_context(|| format!("could not read {}", path.display()))?;
+        serde_json::from_slice::<serde_json::Value>(&docker_config)
+            .with_context(|| format!("could not parse {}", path.display()))?;
+        Ok(RegistrySecret {
+            name: name.to_owned(),
+            docker_config_json: BASE64_STANDARD.encode(&docker_config),
+        })
+    }
 }
 
 /// Per-environment configuration.
@@ -75,15 +118,30 @@ struct Config {
     minio_memory: String,
     /// The number of CPUs to request for MinIO.
     minio_cpu: String,
+    /// Buckets to create in MinIO after it starts.
+    minio_buckets: Vec<String>,
     /// The full container image reference for falconeri.
     image: String,
+    /// A secret containing a `DATABASE_URL` for an externally-managed
+    /// database. If present, we don't deploy PostgreSQL ourselves.
+    external_database_url_secret: Option<String>,
 }
 
 /// Parameters used to generate a deploy manifest.
 #[derive(Serialize)]
-struct DeployManifestParams {
+struct DeployManifestParams<C = Config> {
     all: bool,
-    config: Config,
+    config: C,
+}
+
+/// How should we output our deployment?
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+enum DeployFormat {
+    /// Apply a Kubernetes manifest using `kubectl`.
+    #[default]
+    Manifest,
+    /// Write a Helm chart to `--output-dir`.
+    Helm,
 }
 
 /// Commands for interacting with the database.
@@ -94,6 +152,22 @@ pub struct Opt {
     #[arg(long = "dry-run")]
     dry_run: bool,
 
+    /// Upgrade a running `falconeri`, pausing datum reservations while we
+    /// migrate the database and roll out new `falconerid` servers. Requires
+    /// `falconeri proxy`.
+    #[arg(long = "upgrade", conflicts_with_all = ["dry_run", "output_dir"])]
+    upgrade: bool,
+
+    /// How to output our deployment. `helm` writes a Helm chart to
+    /// `--output-dir` instead of deploying directly, although any missing
+    /// secrets are still created directly.
+    #[arg(long = "format", value_enum, default_value_t)]
+    format: DeployFormat,
+
+    /// The directory in which to write our Helm chart.
+    #[arg(long = "output-dir", required_if_eq("format", "helm"))]
+    output_dir: Option<PathBuf>,
+
     /// Don't include secrets in the manifest.
     #[arg(long = "skip-secrets", visible_alias = "skip-secret")]
     skip_secrets: bool,
@@ -158,25 +232,59 @@ pub struct Opt {
     #[arg(long = "minio-cpu")]
     minio_cpu: Option<String>,
 
+    /// A bucket to create in MinIO. May be specified more than once. Defaults
+    /// to `falconeri-test` for --development.
+    #[arg(long = "minio-bucket")]
+    minio_buckets: Vec<String>,
+
     /// Custom container image for falconeri (production only).
     /// Use this to deploy from a forked repository's CI-built image.
     /// Example: ghcr.io/myorg/falconeri:v2.0.0
     #[arg(long = "image", conflicts_with = "development")]
     image: Option<String>,
+
+    /// Create a secret for pulling worker images from a private registry,
+    /// using the credentials in this Docker `config.json` file (for example,
+    /// `~/.docker/config.json` after running `docker login`). Jobs can use it
+    /// by listing it in `transform.image_pull_secrets`.
+    #[arg(long = "registry-docker-config", conflicts_with = "skip_secrets")]
+    registry_docker_config: Option<PathBuf>,
+
+    /// The name of the registry secret created by `--registry-docker-config`.
+    #[arg(long = "registry-secret-name", default_value = "falconeri-registry")]
+    registry_secret_name: String,
+
+    /// Use an externally-managed PostgreSQL database instead of deploying our
+    /// own. This secret must contain a `DATABASE_URL` key, which may use
+    /// `sslmode` and `sslrootcert` parameters. Other keys in the secret, like
+    /// `ca.crt`, are mounted in `/etc/falconeri/database/`.
+    #[arg(long = "external-database-url-secret")]
+    external_database_url_secret: Option<String>,
+}
+
+/// Subcommands of `falconeri deploy`.
+#[derive(Debug, Subcommand)]
+pub enum DeployCmd {
+    /// Replace the PostgreSQL password in the `falconeri` secret, which is
+    /// also the admin password for `falconerid`, and restart `falconerid`.
+    /// Requires `falconeri proxy`.
+    #[command(name = "rotate-password")]
+    RotatePassword,
+}
+
+/// Generate a password using the system's "secure" random number generator.
+fn random_password(rng: &mut StdRng) -> Vec<u8> {
+    iter::repeat(())
+        .map(|()| rng.sample(Alphanumeric))
+        .take(32)
+        .collect()
 }
 
 /// Deploy `falconeri` to the current Kubernetes cluster.
 pub async fn run(opt: &Opt) -> Result<()> {
-    // Generate passwords using the system's "secure" random number generator.
     let mut rng = StdRng::from_os_rng();
-    let postgres_password: Vec<u8> = iter::repeat(())
-        .map(|()| rng.sample(Alphanumeric))
-        .take(32)
-        .collect();
-    let minio_root_password: Vec<u8> = iter::repeat(())
-        .map(|()| rng.sample(Alphanumeric))
-        .take(32)
-        .collect();
+    let postgres_password = random_password(&mut rng);
+    let minio_root_password = random_password(&mut rng);
 
     // Figure out our configuration.
     let mut config = default_config(opt.development);
@@ -218,9 +326,26 @@ pub async fn run(opt: &Opt) -> Result<()> {
     if let Some(minio_cpu) = &opt.minio_cpu {
         config.minio_cpu = minio_cpu.to_owned();
     }
+    if !opt.minio_buckets.is_empty() {
+        for bucket in &opt.minio_buckets {
+            check_minio_bucket_name(bucket)?;
+        }
+        config.minio_buckets = opt.minio_buckets.clone();
+    }
     if let Some(image) = &opt.image {
         config.image = image.to_owned();
     }
+    if let Some(secret) = &opt.external_database_url_secret {
+        if !opt.dry_run
+            && !kubernetes::resource_exists(&format!("secret/{}", secret)).await?
+        {
+            return Err(format_err!(
+                "--external-database-url-secret {} does not exist",
+                secret
+            ));
+        }
+        config.external_database_url_secret = Some(secret.to_owned());
+    }
 
     // Check which secrets need to be created (only if they don't already exist).
     let include_falconeri =
@@ -241,9 +366,36 @@ pub async fn run(opt: &Opt) -> Result<()> {
         minio_root_user: BASE64_STANDARD.encode("minioadmin"),
         minio_root_password: BASE64_STANDARD.encode(&minio_root_password[..]),
         minio_endpoint_url: "http://falconeri-minio:9000".to_string(),
+        registry_secret: opt
+            .registry_docker_config
+            .as_deref()
+            .map(|path| {
+                RegistrySecret::from_docker_config(&opt.registry_secret_name, path)
+            })
+            .transpose()?,
     };
     let secret_manifest = render_manifest(SECRET_MANIFEST, &secret_params)?;
 
+    // If we're writing a Helm chart, write it and create any missing secrets
+    // directly, because we don't want to store them in the chart.
+    if let DeployFormat::Helm = opt.format {
+        let output_dir = opt
+            .output_dir
+            .as_deref()
+            .ok_or_else(|| format_err!("--format helm requires --output-dir"))?;
+        write_helm_chart(&config, output_dir)?;
+        if opt.dry_run {
+            print!("{}", secret_manifest);
+        } else if !secret_manifest.trim().is_empty() {
+            kubernetes::deploy(&secret_manifest).await?;
+        }
+        return Ok(());
+    }
+
+    // Our bucket-creation job can't be updated in place, so we replace it.
+    let replace_minio_buckets_job =
+        config.enable_minio && !config.minio_buckets.is_empty() && !opt.dry_run;
+
     // Generate our deploy manifest.
     let deploy_params = DeployManifestParams { all: true, config };
     let deploy_manifest = render_manifest(DEPLOY_MANIFEST, &deploy_params)?;
@@ -253,25 +405,221 @@ pub async fn run(opt: &Opt) -> Result<()> {
     manifest.push_str(&secret_manifest);
     manifest.push_str(&deploy_manifest);
 
+    if replace_minio_buckets_job {
+        kubernetes::delete_job(MINIO_BUCKETS_JOB).await?;
+    }
     if opt.dry_run {
         // Print out our manifests.
         print!("{}", manifest);
+    } else if opt.upgrade {
+        upgrade(&manifest).await?;
     } else {
         kubernetes::deploy(&manifest).await?;
     }
     Ok(())
 }
 
+/// The name of the Kubernetes job which creates our MinIO buckets.
+const MINIO_BUCKETS_JOB: &str = "falconeri-minio-buckets";
+
+/// Make sure that `name` is a valid S3 bucket name, because we pass it to a
+/// shell script in our bucket-creation job.
+fn check_minio_bucket_name(name: &str) -> Result<()> {
+    let valid = (3..=63).contains(&name.len())
+        && name.chars().all(|c| {
+            c.is_ascii_lowercase() || c.is_ascii_digit() || c == '.' || c == '-'
+        })
+        && name.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && name.ends_with(|c: char| c.is_ascii_alphanumeric());
+    if valid {
+        Ok(())
+    } else {
+        Err(format_err!("invalid MinIO bucket name {:?}", name))
+    }
+}
+
+/// How many times should we try to resume datum reservations after an
+/// upgrade? Our proxy may need a moment to reconnect to the new `falconerid`.
+const RESUME_ATTEMPTS: u32 = 12;
+
+/// How long should we wait between attempts to resume datum reservations?
+const RESUME_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Upgrade a running `falconeri` by deploying `manifest`.
+///
+/// We pause datum reservations, so that workers don't start new datums while
+/// the database is being migrated or while `falconerid` is restarting. Workers
+/// which are already processing datums will keep going, and retry if they
+/// can't reach `falconerid` for a while.
+async fn upgrade(manifest: &str) -> Result<()> {
+    // We may be upgrading from a server which doesn't support us yet.
+    let client = Client::new_without_version_check(ConnectVia::Proxy).await?;
+
+    // Check whether the upgrade is likely to go smoothly.
+    let server_version = client.server_version().await?;
+    let running_jobs = client
+        .list_jobs()
+        .await?
+        .into_iter()
+        .filter(|job| job.status.is_active())
+        .count();
+    println!(
+        "Upgrading falconerid {} to {} ({} running jobs)",
+        server_version,
+        falconeri_common_version(),
+        running_jobs,
+    );
+    for warning in
+        upgrade_warnings(&server_version, &falconeri_common_version(), running_jobs)
+    {
+        eprintln!("WARNING: {}", warning);
+    }
+
+    // Pause datum reservations.
+    let paused = client.set_reservations_paused(true).await?;
+    if paused {
+        println!("Paused datum reservations");
+    } else {
+        eprintln!(
+            "WARNING: falconerid {} cannot pause datum reservations, so workers will keep starting new datums during the upgrade",
+            server_version,
+        );
+    }
+
+    // Migrate our database and roll out our new servers.
+    let result = async {
+        super::migrate::run().await?;
+        kubernetes::deploy(manifest).await?;
+        kubernetes::kubectl(&[
+            "rollout",
+            "status",
+            "deployment/falconerid",
+            "--timeout=10m",
+        ])
+        .await
+    }
+    .await;
+
+    // Resume datum reservations, even if something went wrong above. If the
+    // old server couldn't pause them, they were never paused.
+    if paused {
+        resume_reservations(&client).await?;
+        println!("Resumed datum reservations");
+    }
+    result
+}
+
+/// Resume datum reservations, retrying while our proxy reconnects.
+async fn resume_reservations(client: &Client) -> Result<()> {
+    let mut attempt = 1;
+    loop {
+        match client.set_reservations_paused(false).await {
+            Ok(_) => return Ok(()),
+            Err(err) if attempt < RESUME_ATTEMPTS => {
+                warn!("could not resume datum reservations, will retry: {}", err);
+                attempt += 1;
+                tokio::time::sleep(RESUME_RETRY_DELAY).await;
+            }
+            Err(err) => {
+                return Err(err.context(
+                    "could not resume datum reservations (run `falconeri deploy --upgrade` again to resume them)",
+                ))
+            }
+        }
+    }
+}
+
+/// Things which might go wrong if we upgrade `falconerid` from `server` to
+/// `ours` while `running_jobs` jobs are running.
+fn upgrade_warnings(
+    server: &Version,
+    ours: &Version,
+    running_jobs: usize,
+) -> Vec<String> {
+    let mut warnings = vec![];
+    if ours < server {
+        warnings.push(format!(
+            "this would downgrade falconerid from {} to {}, and we can't undo database migrations",
+            server, ours,
+        ));
+    }
+    let compatible = server.major == ours.major
+        && (ours.major > 0 || server.minor == ours.minor)
+        && server.pre == ours.pre;
+    if !compatible && running_jobs > 0 {
+        warnings.push(format!(
+            "{} running jobs use falconeri-worker {}, which may not work with falconerid {}; consider waiting for them to finish",
+            running_jobs, server, ours,
+        ));
+    }
+    warnings
+}
+
+/// Write a Helm chart for `config` to `output_dir`.
+///
+/// We render the same manifest that we'd normally deploy, but we replace each
+/// string or numeric `config` value with a reference to a Helm value, and write
+/// the original values to `values.yaml`. Options which change the structure
+/// of the manifest, like `--with-minio`, are fixed when the chart is
+/// generated. So is whether we set a storage class at all, although the
+/// storage class itself is a value.
+fn write_helm_chart(config: &Config, output_dir: &Path) -> Result<()> {
+    let (values_yaml, template_yaml) = render_helm_chart(config)?;
+    let templates_dir = output_dir.join("templates");
+    fs::create_dir_all(&templates_dir)
+        .with_context(|| format!("could not create {}", templates_dir.display()))?;
+    for (path, contents) in [
+        (output_dir.join("Chart.yaml"), HELM_CHART_YAML.to_owned()),
+        (output_dir.join("values.yaml"), values_yaml),
+        (templates_dir.join("falconeri.yaml"), template_yaml),
+    ] {
+        fs::write(&path, contents)
+            .with_context(|| format!("could not write {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Render the `values.yaml` file and the template for a Helm chart.
+fn render_helm_chart(config: &Config) -> Result<(String, String)> {
+    let config = serde_json::to_value(config).context("could not serialize config")?;
+    let config = config
+        .as_object()
+        .ok_or_else(|| format_err!("config should be an object"))?;
+
+    let mut values_yaml = String::new();
+    let mut helm_config = serde_json::Map::new();
+    for (key, value) in config {
+        if value.is_string() || value.is_number() {
+            // JSON strings and numbers are also valid YAML.
+            values_yaml.push_str(&format!("{}: {}\n", key, value));
+            helm_config.insert(
+                key.to_owned(),
+                serde_json::Value::String(format!("{{{{ .Values.{} }}}}", key)),
+            );
+        } else {
+            helm_config.insert(key.to_owned(), value.to_owned());
+        }
+    }
+
+    let params = DeployManifestParams {
+        all: true,
+        config: helm_config,
+    };
+    let template_yaml = render_manifest(DEPLOY_MANIFEST, &params)?;
+    Ok((values_yaml, template_yaml))
+}
+
 /// Undeploy `falconeri`, removing it from the cluster.
 pub async fn run_undeploy(all: bool) -> Result<()> {
     // Clean up things declared by our regular manifest. Use development config
-    // to ensure MinIO resources are included in the manifest for deletion.
-    let params = DeployManifestParams {
-        all,
-        config: default_config(true),
-    };
+    // to ensure MinIO resources are included in the manifest for deletion. Our
+    // bucket-creation job may not exist, so we delete it separately.
+    let mut config = default_config(true);
+    config.minio_buckets.clear();
+    let params = DeployManifestParams { all, config };
     let manifest = render_manifest(DEPLOY_MANIFEST, &params)?;
     kubernetes::undeploy(&manifest).await?;
+    kubernetes::delete_job(MINIO_BUCKETS_JOB).await?;
 
     // Clean up our secrets manually instead of rendering a new manifest.
     if all {
@@ -283,6 +631,162 @@ pub async fn run_undeploy(all: bool) -> Result<()> {
     Ok(())
 }
 
+/// Run a subcommand of `falconeri deploy`.
+pub async fn run_subcommand(cmd: &DeployCmd) -> Result<()> {
+    match cmd {
+        DeployCmd::RotatePassword => rotate_password().await,
+    }
+}
+
+/// The key in our `falconeri` secret where we keep a new password until
+/// we've finished rotating it, so that we never lose it.
+const NEXT_PASSWORD_KEY: &str = "NEXT_POSTGRES_PASSWORD";
+
+/// The passwords in our `falconeri` secret.
+#[derive(Deserialize)]
+struct PasswordSecretData {
+    /// Our current password.
+    #[serde(
+        default,
+        with = "base64_encoded_optional_secret_string",
+        rename = "POSTGRES_PASSWORD"
+    )]
+    current: Option<String>,
+    /// The password we're rotating to, if a rotation is in progress.
+    #[serde(
+        default,
+        with = "base64_encoded_optional_secret_string",
+        rename = "NEXT_POSTGRES_PASSWORD"
+    )]
+    next: Option<String>,
+}
+
+/// Replace our PostgreSQL password.
+///
+/// We do this in an order which we can safely resume if interrupted:
+///
+/// 1. Store the new password in our secret as `NEXT_POSTGRES_PASSWORD`.
+/// 2. Change the password in PostgreSQL. `falconerid` keeps its existing
+///    connections, but it can't open new ones until it restarts.
+/// 3. Replace `POSTGRES_PASSWORD` in our secret, removing the next password.
+/// 4. Restart `falconerid`, which reads its password at startup.
+///
+/// Workers use job-scoped tokens, so running jobs aren't affected.
+async fn rotate_password() -> Result<()> {
+    if env::var("DATABASE_URL").is_ok() {
+        return Err(format_err!(
+            "DATABASE_URL is set, but `deploy rotate-password` only works with our own PostgreSQL server"
+        ));
+    }
+    let external_database = falconerid_uses_external_database().await?;
+
+    // Pick a new password, or finish a rotation which was interrupted.
+    let secret = kubernetes::kubectl_secret::<PasswordSecretData>("falconeri").await?;
+    let old_password = secret
+        .current
+        .ok_or_else(|| format_err!("secret `falconeri` has no POSTGRES_PASSWORD"))?;
+    let new_password = match secret.next {
+        Some(next) => {
+            println!("Resuming an unfinished password rotation");
+            next
+        }
+        None => {
+            let mut rng = StdRng::from_os_rng();
+            let next = String::from_utf8(random_password(&mut rng))
+                .expect("alphanumeric password should be UTF-8");
+            patch_falconeri_secret(&serde_json::json!({
+                NEXT_PASSWORD_KEY: BASE64_STANDARD.encode(&next),
+            }))
+            .await?;
+            next
+        }
+    };
+
+    // Change the password in PostgreSQL. If we can't connect using the old
+    // password, check whether a previous attempt already changed it.
+    if external_database {
+        eprintln!(
+            "WARNING: falconerid uses an external database, so we're only changing its admin password"
+        );
+    } else {
+        let old_url = db::database_url_with_password(ConnectVia::Proxy, &old_password);
+        match db::async_connect_to_url(&old_url).await {
+            Ok(mut conn) => {
+                db::set_current_user_password(&mut conn, &new_password).await?;
+                println!("Changed PostgreSQL password");
+            }
+            Err(err) => {
+                let new_url =
+                    db::database_url_with_password(ConnectVia::Proxy, &new_password);
+                db::async_connect_to_url(&new_url).await.map_err(|_| {
+                    err.context("could not connect to PostgreSQL (is `falconeri proxy` running?)")
+                })?;
+                println!("PostgreSQL password was already changed");
+            }
+        }
+    }
+
+    // Make the new password current, and restart `falconerid` to use it.
+    patch_falconeri_secret(&serde_json::json!({
+        "POSTGRES_PASSWORD": BASE64_STANDARD.encode(&new_password),
+        NEXT_PASSWORD_KEY: null,
+    }))
+    .await?;
+    println!("Updated secret `falconeri`");
+    kubernetes::kubectl(&["rollout", "restart", "deployment/falconerid"]).await?;
+    kubernetes::kubectl(&[
+        "rollout",
+        "status",
+        "deployment/falconerid",
+        "--timeout=10m",
+    ])
+    .await?;
+    println!("Restarted falconerid");
+    Ok(())
+}
+
+/// Apply a merge patch to the `data` of our `falconeri` secret. Keys with
+/// `null` values are removed.
+async fn patch_falconeri_secret(data: &serde_json::Value) -> Result<()> {
+    let patch = serde_json::json!({ "data": data }).to_string();
+    kubernetes::kubectl(&[
+        "patch",
+        "secret",
+        "falconeri",
+        "--type=merge",
+        "-p",
+        &patch,
+    ])
+    .await
+}
+
+/// Is `falconerid` configured to use an external database?
+async fn falconerid_uses_external_database() -> Result<bool> {
+    let deployment = kubernetes::kubectl_parse_json::<serde_json::Value>(&[
+        "get",
+        "deployment",
+        "falconerid",
+        "-o",
+        "json",
+    ])
+    .await?;
+    Ok(deployment_sets_database_url(&deployment))
+}
+
+/// Does any container in `deployment` set `DATABASE_URL`?
+fn deployment_sets_database_url(deployment: &serde_json::Value) -> bool {
+    deployment
+        .pointer("/spec/template/spec/containers")
+        .and_then(|containers| containers.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|container| container.get("env")?.as_array())
+        .flatten()
+        .any(|var| {
+            var.get("name").and_then(|name| name.as_str()) == Some("DATABASE_URL")
+        })
+}
+
 /// Our current default Postgres version.
 const POSTGRES_VERSION: &str = "14";
 
@@ -309,10 +813,12 @@ fn default_config(development: bool) -> Config {
             minio_storage: "256Mi".to_string(),
             minio_memory: "256Mi".to_string(),
             minio_cpu: "100m".to_string(),
+            minio_buckets: vec!["falconeri-test".to_string()],
             image: format!(
                 "ghcr.io/dbcrossbar/falconeri:{}",
                 env!("CARGO_PKG_VERSION")
             ),
+            external_database_url_secret: None,
         }
     } else {
         Config {
@@ -333,10 +839,100 @@ fn default_config(development: bool) -> Config {
             minio_storage: "10Gi".to_string(),
             minio_memory: "512Mi".to_string(),
             minio_cpu: "250m".to_string(),
+            minio_buckets: vec![],
             image: format!(
                 "ghcr.io/dbcrossbar/falconeri:{}",
                 env!("CARGO_PKG_VERSION")
             ),
+            external_database_url_secret: None,
         }
     }
 }
+
+#[test]
+fn helm_chart_uses_values() {
+    let mut config = default_config(false);
+    config.storage_class_name = Some("gp3".to_owned());
+    let (values_yaml, template_yaml) = render_helm_chart(&config).unwrap();
+    assert!(values_yaml.contains("falconerid_replicas: 2\n"));
+    assert!(values_yaml.contains("storage_class_name: \"gp3\"\n"));
+    assert!(!values_yaml.contains("enable_minio"));
+    assert!(template_yaml.contains("replicas: {{ .Values.falconerid_replicas }}"));
+    assert!(
+        template_yaml.contains("storageClassName: {{ .Values.storage_class_name }}")
+    );
+    assert!(!template_yaml.contains("minio"));
+}
+
+#[test]
+fn external_database_skips_postgres() {
+    let mut config = default_config(false);
+    config.external_database_url_secret = Some("rds".to_owned());
+    let params = DeployManifestParams { all: true, config };
+    let manifest = render_manifest(DEPLOY_MANIFEST, &params).unwrap();
+    assert!(!manifest.contains("falconeri-postgres"));
+    assert!(manifest.contains("name: DATABASE_URL"));
+    assert!(manifest.contains("secretName: rds"));
+}
+
+#[test]
+fn minio_buckets_are_created_by_a_job() {
+    let mut config = default_config(true);
+    config.minio_buckets = vec!["inputs".to_owned(), "outputs".to_owned()];
+    let params = DeployManifestParams { all: true, config };
+    let manifest = render_manifest(DEPLOY_MANIFEST, &params).unwrap();
+    assert!(manifest.contains("name: falconeri-minio-buckets"));
+    assert!(manifest.contains("mc mb --ignore-existing local/inputs\n"));
+    assert!(manifest.contains("mc mb --ignore-existing local/outputs\n"));
+
+    let mut config = default_config(true);
+    config.minio_buckets.clear();
+    let params = DeployManifestParams { all: true, config };
+    let manifest = render_manifest(DEPLOY_MANIFEST, &params).unwrap();
+    assert!(!manifest.contains("falconeri-minio-buckets"));
+}
+
+#[test]
+fn minio_bucket_names_are_checked() {
+    assert!(check_minio_bucket_name("falconeri-test").is_ok());
+    assert!(check_minio_bucket_name("my.bucket.1").is_ok());
+    assert!(check_minio_bucket_name("ab").is_err());
+    assert!(check_minio_bucket_name("Uppercase").is_err());
+    assert!(check_minio_bucket_name("-leading").is_err());
+    assert!(check_minio_bucket_name("a; rm -rf /").is_err());
+}
+
+#[test]
+fn upgrade_warnings_detect_incompatibility() {
+    let v = |s: &str| s.parse::<Version>().unwrap();
+    assert!(upgrade_warnings(&v("2.0.0"), &v("2.1.0"), 3).is_empty());
+    assert_eq!(upgrade_warnings(&v("2.1.0"), &v("2.0.0"), 0).len(), 1);
+    assert_eq!(upgrade_warnings(&v("1.0.0"), &v("2.0.0"), 3).len(), 1);
+    assert!(upgrade_warnings(&v("1.0.0"), &v("2.0.0"), 0).is_empty());
+    assert_eq!(
+        upgrade_warnings(&v("2.0.0-alpha.4"), &v("2.0.0-alpha.5"), 1).len(),
+        1
+    );
+}
+
+#[test]
+fn external_databases_are_detected() {
+    let deployment = |env: serde_json::Value| {
+        serde_json::json!({
+            "spec": { "template": { "spec": { "containers": [
+                { "name": "falconerid", "env": env },
+            ] } } }
+        })
+    };
+    assert!(!deployment_sets_database_url(&deployment(
+        serde_json::json!([
+            { "name": "RUST_LOG", "value": "warn" },
+        ])
+    )));
+    assert!(deployment_sets_database_url(&deployment(
+        serde_json::json!([
+            { "name": "DATABASE_URL", "valueFrom": {} },
+        ])
+    )));
+    assert!(!deployment_sets_database_url(&serde_json::json!({})));
+}
@@ -0,0 +1,66 @@
+//! The `dev` subcommand, with helpers for development clusters.
+
+use clap::Subcommand;
+use falconeri_common::{prelude::*, storage::s3::S3Storage, storage::CloudStorage};
+
+/// Commands for working with development clusters.
+#[derive(Debug, Subcommand)]
+pub enum Opt {
+    /// Upload a local directory of test data to a bucket.
+    ///
+    /// `s3://` URIs are uploaded to the MinIO server deployed by
+    /// `falconeri deploy --development`, using `falconeri proxy`. Other URIs
+    /// use your usual credentials.
+    #[command(name = "seed")]
+    Seed {
+        /// The local directory to upload.
+        #[arg(long = "from")]
+        from: PathBuf,
+
+        /// The Kubernetes secret containing credentials for `s3://` URIs.
+        #[arg(long = "secret", default_value = "s3")]
+        secret: String,
+
+        /// The endpoint to use for `s3://` URIs.
+        #[arg(long = "endpoint", default_value = "http://localhost:9000")]
+        endpoint: String,
+
+        /// The URI to upload to, like `s3://falconeri-test/texts/`.
+        uri: String,
+    },
+}
+
+/// Run the `dev` subcommand.
+#[instrument(skip_all, level = "trace")]
+pub async fn run(opt: &Opt) -> Result<()> {
+    match opt {
+        Opt::Seed {
+            from,
+            secret,
+            endpoint,
+            uri,
+        } => run_seed(from, secret, endpoint, uri).await,
+    }
+}
+
+/// Upload `from` to `uri`.
+#[instrument(level = "debug")]
+async fn run_seed(from: &Path, secret: &str, endpoint: &str, uri: &str) -> Result<()> {
+    if !from.is_dir() {
+        return Err(format_err!("{} is not a directory", from.display()));
+    }
+    if !uri.ends_with('/') {
+        return Err(format_err!("{:?} should end with '/'", uri));
+    }
+    let storage: Box<dyn CloudStorage> = if uri.starts_with("s3://") {
+        Box::new(S3Storage::new_with_secret_and_endpoint(secret, endpoint, uri).await?)
+    } else {
+        <dyn CloudStorage>::for_uri(uri, &[]).await?
+    };
+    storage
+        .sync_up(from, uri)
+        .await
+        .with_context(|| format!("could not upload {} to {}", from.display(), uri))?;
+    println!("Uploaded {} to {}", from.display(), uri);
+    Ok(())
+}
@@ -0,0 +1,58 @@
+//! The `job audit` subcommand.
+
+use falconeri_common::{prelude::*, rest_api::Client};
+use prettytable::{format::consts::FORMAT_CLEAN, row, Table};
+
+use crate::output::OutputFormat;
+
+/// The `job audit` subcommand.
+#[instrument(level = "trace")]
+pub async fn run(job_name: &str, output: OutputFormat) -> Result<()> {
+    let client = Client::new(ConnectVia::Proxy).await?;
+    let job = client.find_job_by_name(job_name).await?;
+    let audit = client.audit_job(job.id).await?;
+    output.print(&audit, |audit| {
+        print_table(audit);
+        Ok(())
+    })?;
+    if audit.violations.is_empty() {
+        Ok(())
+    } else {
+        Err(format_err!(
+            "job {} failed {} consistency checks",
+            job_name,
+            audit.violations.len()
+        ))
+    }
+}
+
+/// Print `audit` as a table.
+fn print_table(audit: &JobAudit) {
+    if audit.violations.is_empty() {
+        println!(
+            "All {} datums passed every consistency check.",
+            audit.datum_count
+        );
+    } else {
+        let mut table = Table::new();
+        table.set_format(*FORMAT_CLEAN);
+        table.add_row(row!["CHECK", "DATUM_ID", "MESSAGE"]);
+        for violation in &audit.violations {
+            table.add_row(row![
+                violation.check,
+                violation.datum_id,
+                violation.message
+            ]);
+        }
+        table.printstd();
+    }
+    for check in &audit.skipped_checks {
+        println!(
+            "Skipped {}, because the job's input contains a cross.",
+            check
+        );
+    }
+    for check in &audit.truncated_checks {
+        println!("Only showing some violations of {}.", check);
+    }
+}
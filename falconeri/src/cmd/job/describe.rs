@@ -1,30 +1,95 @@
 //! The `job describe` subcommand.
 
-use falconeri_common::{prelude::*, rest_api::Client};
+use falconeri_common::{
+    ops,
+    prelude::*,
+    rest_api::{Client, JobDescribeResponse},
+};
 
-use crate::description::render_description;
+use crate::{
+    description::{read_exported_description, render_description},
+    output::OutputFormat,
+};
 
 /// Template for human-readable `describe` output.
 const DESCRIBE_TEMPLATE: &str = include_str!("describe.txt.hbs");
 
 /// The `job describe` subcommand.
 #[instrument(level = "trace")]
-pub async fn run(job_name: &str) -> Result<()> {
+pub async fn run(
+    job_name: Option<&str>,
+    from_file: Option<&Path>,
+    all_datums: bool,
+    output: OutputFormat,
+) -> Result<()> {
     // Load the data we want to display.
-    let client = Client::new(ConnectVia::Proxy).await?;
-    let job = client.find_job_by_name(job_name).await?;
-    let params = client.describe_job(job.id).await?;
+    let params: JobDescribeResponse = if let Some(path) = from_file {
+        read_exported_description(path)?
+    } else {
+        let job_name = job_name.ok_or_else(|| format_err!("must specify a job"))?;
+        let client = Client::new(ConnectVia::Proxy).await?;
+        let job = ops::find_job(&client, job_name).await?;
+        if all_datums {
+            describe_with_all_datums(&client, &job).await?
+        } else {
+            ops::describe_job(&client, &job).await?
+        }
+    };
 
     // Print the description.
-    print!("{}", render_description(DESCRIBE_TEMPLATE, &params)?);
-    Ok(())
+    output.print(&params, |params| {
+        print!("{}", render_description(DESCRIBE_TEMPLATE, params)?);
+        Ok(())
+    })
+}
+
+/// Describe `job`, fetching every page of its running and error datums. We
+/// only ask for these pages if the user wants them, because a job with
+/// thousands of failures would take a long time to fetch.
+async fn describe_with_all_datums(
+    client: &Client,
+    job: &Job,
+) -> Result<JobDescribeResponse> {
+    // We're going to replace the datum lists anyway, so don't ask for any.
+    let mut description = client
+        .describe_job_with_datum_limit(job.id, Some(0))
+        .await?;
+
+    let mut after = None;
+    loop {
+        let page = client.job_running_datums(job.id, after).await?;
+        description.running_datums.extend(page.datums);
+        after = page.next_after;
+        if after.is_none() {
+            break;
+        }
+    }
+    let mut after = None;
+    loop {
+        let page = client.job_error_datums(job.id, after).await?;
+        description.error_datums.extend(page.datums);
+        after = page.next_after;
+        if after.is_none() {
+            break;
+        }
+    }
+
+    // Pages are sorted by ID, but we show datums in the order they were
+    // updated, just like the server does.
+    description.running_datums.sort_by_key(|d| d.updated_at);
+    description.error_datums.sort_by_key(|d| d.updated_at);
+    description.running_datums_truncated = false;
+    description.error_datums_truncated = false;
+    Ok(description)
 }
 
 #[test]
 fn render_template() {
-    use falconeri_common::rest_api::JobDescribeResponse;
+    use falconeri_common::rest_api::JobCreationProgress;
 
-    let job = Job::factory();
+    let mut job = Job::factory();
+    job.image_digest = Some("sha256:abcd".to_owned());
+    job.excluded_node_names = vec!["node-a".to_owned(), "node-b".to_owned()];
     let dsc = |status: Status, count: u64, rerunable_count: u64| DatumStatusCount {
         status,
         count,
@@ -37,17 +102,59 @@ fn render_template() {
     ];
     let mut running_datum = Datum::factory(&job);
     running_datum.status = Status::Running;
-    let running_datums = vec![running_datum];
+    let running_datums = vec![DatumSummary::from(&running_datum)];
     let mut error_datum = Datum::factory(&job);
     error_datum.status = Status::Error;
     error_datum.error_message = Some("Ooops.".to_owned());
-    let error_datums = vec![error_datum];
+    let error_datums = vec![DatumSummary::from(&error_datum)];
+    let recent_events = vec![JobEvent::factory(&job)];
+    let datum_timing_stats = DatumTimingStats {
+        datum_count: 3,
+        avg_download_seconds: Some(1.5),
+        avg_command_seconds: Some(20.0),
+        avg_upload_seconds: Some(0.7),
+    };
     let params = JobDescribeResponse {
         job,
         datum_status_counts,
         running_datums,
+        running_datums_truncated: false,
         error_datums,
+        error_datums_truncated: true,
+        datum_timing_stats,
+        input_file_totals: InputFileTotals {
+            file_count: 4,
+            sized_file_count: 3,
+            total_bytes: Some(3072),
+        },
+        creation_progress: Some(JobCreationProgress {
+            datums_created: 4,
+            total_datum_count: Some(10),
+        }),
+        lineage: JobLineage {
+            ancestors: vec![Job::factory()],
+            descendants: vec![],
+        },
+        recent_events,
+        anomalous_nodes: vec![NodeFailureCount {
+            node_name: "node-b".to_owned(),
+            done_count: 1,
+            error_count: 20,
+        }],
     };
 
-    render_description(DESCRIBE_TEMPLATE, &params).expect("could not render template");
+    let description = render_description(DESCRIBE_TEMPLATE, &params)
+        .expect("could not render template");
+    assert!(description.contains("Image Digest: sha256:abcd\n"));
+    assert!(
+        description.contains("Listed input bytes: 3072 (3 files with known sizes)\n")
+    );
+    assert!(
+        description.contains("job_scaled_down  -  scaled down from 10 to 5 workers")
+    );
+    assert!(description.contains("Excluded Nodes: node-a, node-b\n"));
+    assert!(description.contains("(More error datums not shown."));
+    assert!(!description.contains("(More running datums not shown."));
+    assert!(description
+        .contains("Warning: Node node-b failed 20 datums and finished 1, far more"));
 }
@@ -0,0 +1,273 @@
+//! The `job diff` subcommand.
+
+use std::collections::BTreeMap;
+
+use falconeri_common::{
+    prelude::*,
+    rest_api::Client,
+    serde_json::{self, Value},
+};
+use prettytable::{format::consts::FORMAT_CLEAN, row, Table};
+
+use crate::output::OutputFormat;
+
+/// The `job diff` subcommand.
+#[instrument(level = "trace")]
+pub async fn run(
+    job_name_a: &str,
+    job_name_b: &str,
+    output: OutputFormat,
+) -> Result<()> {
+    // Look up both jobs.
+    let client = Client::new(ConnectVia::Proxy).await?;
+    let job_a = client.find_job_by_name(job_name_a).await?;
+    let job_b = client.find_job_by_name(job_name_b).await?;
+    let spec_a = serde_json::to_value(client.job_spec(job_a.id).await?)?;
+    let spec_b = serde_json::to_value(client.job_spec(job_b.id).await?)?;
+    let diff = JobDiff {
+        spec_differences: spec_differences(&spec_a, &spec_b)
+            .into_iter()
+            .map(|(field, a, b)| SpecDifference { field, a, b })
+            .collect(),
+        stats_a: client.job_stats(job_a.id).await?,
+        stats_b: client.job_stats(job_b.id).await?,
+        job_a,
+        job_b,
+    };
+    output.print(&diff, |diff| {
+        print_tables(diff);
+        Ok(())
+    })
+}
+
+/// Two jobs, and how they differ.
+#[derive(Debug, Serialize)]
+struct JobDiff {
+    job_a: Job,
+    job_b: Job,
+    spec_differences: Vec<SpecDifference>,
+    stats_a: JobStats,
+    stats_b: JobStats,
+}
+
+/// A field which differs between two pipeline specs. Missing values are shown
+/// as `-`.
+#[derive(Debug, Serialize)]
+struct SpecDifference {
+    field: String,
+    a: String,
+    b: String,
+}
+
+/// Print `diff` as tables.
+fn print_tables(diff: &JobDiff) {
+    let JobDiff {
+        job_a,
+        job_b,
+        spec_differences,
+        stats_a,
+        stats_b,
+    } = diff;
+
+    // Differences between the pipeline specs.
+    if spec_differences.is_empty() {
+        println!("Pipeline specs are identical.");
+    } else {
+        let mut spec = Table::new();
+        spec.set_format(*FORMAT_CLEAN);
+        spec.add_row(row!["FIELD", &job_a.job_name, &job_b.job_name]);
+        for change in spec_differences {
+            spec.add_row(row![&change.field, &change.a, &change.b]);
+        }
+        spec.printstd();
+    }
+
+    // How each job turned out.
+    println!();
+    let mut outcome = Table::new();
+    outcome.set_format(*FORMAT_CLEAN);
+    outcome.add_row(row!["OUTCOME", &job_a.job_name, &job_b.job_name]);
+    let rows = [
+        ("STATUS", job_a.status.to_string(), job_b.status.to_string()),
+        (
+            "IMAGE_DIGEST",
+            format_optional(job_a.image_digest.as_ref()),
+            format_optional(job_b.image_digest.as_ref()),
+        ),
+        (
+            "TOTAL_DATUMS",
+            format_optional(job_a.total_datum_count.as_ref()),
+            format_optional(job_b.total_datum_count.as_ref()),
+        ),
+        (
+            "DONE",
+            stats_a.done_count.to_string(),
+            stats_b.done_count.to_string(),
+        ),
+        (
+            "ERROR",
+            stats_a.error_count.to_string(),
+            stats_b.error_count.to_string(),
+        ),
+        (
+            "WALL_SECONDS",
+            format_seconds(wall_seconds(job_a)),
+            format_seconds(wall_seconds(job_b)),
+        ),
+        (
+            "P50_SECONDS",
+            format_seconds(stats_a.p50_seconds),
+            format_seconds(stats_b.p50_seconds),
+        ),
+        (
+            "P90_SECONDS",
+            format_seconds(stats_a.p90_seconds),
+            format_seconds(stats_b.p90_seconds),
+        ),
+        (
+            "P99_SECONDS",
+            format_seconds(stats_a.p99_seconds),
+            format_seconds(stats_b.p99_seconds),
+        ),
+        (
+            "INPUT_BYTES",
+            stats_a.input_bytes.to_string(),
+            stats_b.input_bytes.to_string(),
+        ),
+        (
+            "OUTPUT_BYTES",
+            stats_a.output_bytes.to_string(),
+            stats_b.output_bytes.to_string(),
+        ),
+    ];
+    for (name, a, b) in rows {
+        outcome.add_row(row![name, a, b]);
+    }
+    outcome.printstd();
+}
+
+/// Find the fields which differ between two pipeline specs, returning the
+/// path of each field and its values in `a` and `b`.
+fn spec_differences(a: &Value, b: &Value) -> Vec<(String, String, String)> {
+    let mut fields_a = BTreeMap::new();
+    flatten_json("", a, &mut fields_a);
+    let mut fields_b = BTreeMap::new();
+    flatten_json("", b, &mut fields_b);
+
+    let mut paths = fields_a.keys().chain(fields_b.keys()).collect::<Vec<_>>();
+    paths.sort();
+    paths.dedup();
+    paths
+        .into_iter()
+        .filter_map(|path| {
+            let value_a = fields_a.get(path);
+            let value_b = fields_b.get(path);
+            if value_a == value_b {
+                None
+            } else {
+                Some((
+                    path.to_owned(),
+                    format_optional(value_a),
+                    format_optional(value_b),
+                ))
+            }
+        })
+        .collect()
+}
+
+/// Flatten `value` into a map from paths like `transform.image` to compact
+/// JSON values. Arrays of scalars, like `transform.cmd`, are kept whole,
+/// because they're easier to read that way. `null` values are omitted, so
+/// that a missing field and an explicit `null` look the same.
+fn flatten_json(path: &str, value: &Value, out: &mut BTreeMap<String, String>) {
+    let child_path = |key: &str| {
+        if path.is_empty() {
+            key.to_owned()
+        } else {
+            format!("{}.{}", path, key)
+        }
+    };
+    match value {
+        Value::Null => {}
+        Value::Object(fields) if !fields.is_empty() => {
+            for (key, field) in fields {
+                flatten_json(&child_path(key), field, out);
+            }
+        }
+        Value::Array(items) if items.iter().any(|i| i.is_object() || i.is_array()) => {
+            for (idx, item) in items.iter().enumerate() {
+                flatten_json(&format!("{}[{}]", path, idx), item, out);
+            }
+        }
+        Value::String(s) => {
+            out.insert(path.to_owned(), s.to_owned());
+        }
+        other => {
+            out.insert(path.to_owned(), other.to_string());
+        }
+    }
+}
+
+/// How long a finished job took from creation to completion, in seconds.
+fn wall_seconds(job: &Job) -> Option<f64> {
+    match job.status {
+        Status::Done | Status::DoneWithErrors | Status::Error | Status::Canceled => {
+            Some((job.updated_at - job.created_at).num_milliseconds() as f64 / 1000.0)
+        }
+        _ => None,
+    }
+}
+
+/// Format an optional value, using `-` for missing values.
+fn format_optional<T: ToString>(value: Option<T>) -> String {
+    match value {
+        Some(value) => value.to_string(),
+        None => "-".to_owned(),
+    }
+}
+
+/// Format a number of seconds for display.
+fn format_seconds(seconds: Option<f64>) -> String {
+    match seconds {
+        Some(seconds) => format!("{:.1}", seconds),
+        None => "-".to_owned(),
+    }
+}
+
+#[test]
+fn spec_differences_reports_changed_fields() {
+    let a = serde_json::json!({
+        "transform": { "image": "worker:v1", "cmd": ["python3", "run.py"] },
+        "resource_requests": { "memory": "1Gi", "cpu": 1 },
+        "input": { "cross": [{ "atom": { "URI": "gs://a/" } }] },
+        "job_timeout": null,
+    });
+    let b = serde_json::json!({
+        "transform": { "image": "worker:v2", "cmd": ["python3", "run.py"] },
+        "resource_requests": { "memory": "2Gi", "cpu": 1 },
+        "input": { "cross": [{ "atom": { "URI": "gs://b/" } }] },
+        "job_timeout": "1h",
+    });
+    assert_eq!(
+        spec_differences(&a, &b),
+        vec![
+            (
+                "input.cross[0].atom.URI".to_owned(),
+                "gs://a/".to_owned(),
+                "gs://b/".to_owned(),
+            ),
+            ("job_timeout".to_owned(), "-".to_owned(), "1h".to_owned()),
+            (
+                "resource_requests.memory".to_owned(),
+                "1Gi".to_owned(),
+                "2Gi".to_owned(),
+            ),
+            (
+                "transform.image".to_owned(),
+                "worker:v1".to_owned(),
+                "worker:v2".to_owned(),
+            ),
+        ]
+    );
+    assert!(spec_differences(&a, &a).is_empty());
+}
@@ -3,24 +3,38 @@
 use falconeri_common::{prelude::*, rest_api::Client};
 use prettytable::{format::consts::FORMAT_CLEAN, row, Table};
 
+use crate::output::OutputFormat;
+
 /// The `job list` subcommand.
 #[instrument(level = "trace")]
-pub async fn run() -> Result<()> {
+pub async fn run(output: OutputFormat) -> Result<()> {
     // Look up the information to display.
     let client = Client::new(ConnectVia::Proxy).await?;
     let jobs = client.list_jobs().await?;
+    output.print(&jobs, |jobs| {
+        print_table(jobs);
+        Ok(())
+    })
+}
 
+/// Print `jobs` as a table.
+fn print_table(jobs: &[Job]) {
     // Create a new table. This library makes some rather unusual API choices,
     // but it does the job well enough.
     let mut table = Table::new();
     table.set_format(*FORMAT_CLEAN);
-    table.add_row(row!["JOB_NAME", "STATUS", "CREATED_AT"]);
+    table.add_row(row!["JOB_NAME", "STATUS", "CREATED_AT", "SPEC_HASH"]);
 
-    // Print information about each job.
+    // Print information about each job. We abbreviate the spec hash like a git
+    // commit ID, because that's enough to spot duplicates by eye.
     for job in jobs {
-        table.add_row(row![&job.job_name, job.status, job.created_at]);
+        let spec_hash = job
+            .spec_hash
+            .as_deref()
+            .map(|h| h.get(..12).unwrap_or(h))
+            .unwrap_or("-");
+        table.add_row(row![&job.job_name, job.status, job.created_at, spec_hash]);
     }
 
     table.printstd();
-    Ok(())
 }
@@ -1,12 +1,28 @@
 //! The `job` subcommand.
 
 use clap::Subcommand;
-use falconeri_common::{pipeline::PipelineSpec, prelude::*, serde_json};
+use falconeri_common::{
+    ops::RunJobOptions, pipeline::PipelineSpec, prelude::*, rest_api::RerunJobRequest,
+    serde_json,
+};
 
+use crate::output::OutputFormat;
+
+mod audit;
 mod describe;
+mod diff;
 mod list;
+mod plan;
+mod recommend_resources;
+mod rerun;
 mod retry;
 mod run;
+mod scale;
+mod search;
+mod spec;
+mod stats;
+mod stop_streaming;
+mod top;
 // Disabled because it's broken by recurive `"input"` types.
 //
 // mod schema;
@@ -15,17 +31,89 @@ mod wait;
 /// The `job` subcommand.
 #[derive(Debug, Subcommand)]
 pub enum Opt {
+    /// Check that a job's datums were accounted for exactly once, and report
+    /// any violations. Exits with an error if there are any.
+    #[command(name = "audit")]
+    Audit {
+        /// The name of the job to audit.
+        job_name: String,
+    },
+
     /// Describe a specific job.
     #[command(name = "describe")]
     Describe {
         /// The Kubernetes name of the job to describe.
-        job_name: String,
+        #[arg(required_unless_present = "from_file")]
+        job_name: Option<String>,
+
+        /// Describe a job using JSON previously exported from
+        /// `GET /jobs/{job_id}/describe`, instead of asking the server.
+        #[arg(long = "from-file", conflicts_with = "job_name")]
+        from_file: Option<PathBuf>,
+
+        /// List every running and error datum, instead of only the first
+        /// few. This may be slow for jobs with many failures.
+        #[arg(long = "all-datums", conflicts_with = "from_file")]
+        all_datums: bool,
+    },
+
+    /// Compare two jobs' pipeline specs and outcomes.
+    #[command(name = "diff")]
+    Diff {
+        /// The name of the first job to compare.
+        job_name_a: String,
+
+        /// The name of the second job to compare.
+        job_name_b: String,
     },
 
     /// List all jobs.
     #[command(name = "list")]
     List,
 
+    /// Show how a pipeline's inputs would be divided into datums, without
+    /// running it.
+    #[command(name = "plan")]
+    Plan {
+        /// Path to a JSON pipeline spec.
+        pipeline_json: PathBuf,
+    },
+
+    /// Suggest `resource_requests` for a job's pipeline, based on the memory
+    /// and CPU time used by every job with the same pipeline name.
+    #[command(name = "recommend-resources")]
+    RecommendResources {
+        /// The name of a job using the pipeline.
+        job_name: String,
+
+        /// How much to add to the 95th percentile of what was used, as a
+        /// percentage. Defaults to 20.
+        #[arg(long = "headroom")]
+        headroom: Option<u32>,
+    },
+
+    /// Submit a past job's pipeline spec again as a fresh job, optionally
+    /// overriding parts of it.
+    #[command(name = "rerun")]
+    Rerun {
+        /// The name of the job to copy.
+        #[arg(long = "from")]
+        from: String,
+
+        /// Use this tag for the worker image, instead of the original tag.
+        #[arg(long = "image-tag")]
+        image_tag: Option<String>,
+
+        /// Upload output to this URI, instead of the original egress URI.
+        #[arg(long = "egress")]
+        egress: Option<String>,
+
+        /// Run this command, instead of the original command. Must be
+        /// separated from the other arguments by `--`.
+        #[arg(last = true)]
+        cmd: Vec<String>,
+    },
+
     /// Retry failed datums.
     #[command(name = "retry")]
     Retry {
@@ -38,7 +126,100 @@ pub enum Opt {
     Run {
         /// Path to a JSON pipeline spec.
         pipeline_json: PathBuf,
+
+        /// Submit the job even if an identical job is already running.
+        #[arg(long = "force")]
+        force: bool,
+
+        /// Submit the job even if it has more datums than the server normally
+        /// allows.
+        #[arg(long = "override-datum-cap")]
+        override_datum_cap: bool,
+
+        /// A unique key for this submission. If a job was already submitted
+        /// with this key, print its name instead of starting a new job.
+        #[arg(long = "idempotency-key")]
+        idempotency_key: Option<String>,
+
+        /// The exact name to give the job, instead of generating one from the
+        /// pipeline name. Fails if a job with this name already exists.
+        #[arg(long = "name", conflicts_with = "generate_name")]
+        name: Option<String>,
+
+        /// Generate the job name by adding a random suffix to this prefix,
+        /// like Kubernetes' `generateName`. For example, `nightly-etl-`.
+        #[arg(long = "generate-name")]
+        generate_name: Option<String>,
+
+        /// Wait for this job (specified by name or UUID) to finish
+        /// successfully before starting. May be given more than once.
+        #[arg(long = "depends-on")]
+        depends_on: Vec<String>,
+
+        /// Process the output of the job given by `--depends-on`, instead of
+        /// the URI of the pipeline's `atom` input.
+        #[arg(long = "input-from-upstream", requires = "depends_on")]
+        input_from_upstream: bool,
     },
+
+    /// Change how many workers may process a running job's datums at once.
+    #[command(name = "scale")]
+    Scale {
+        /// The name of the job to scale.
+        job_name: String,
+
+        /// The new number of workers.
+        #[arg(value_parser = clap::value_parser!(u32).range(1..))]
+        parallelism: u32,
+    },
+
+    /// Search for jobs by name, or by job and datum error messages.
+    #[command(name = "search")]
+    Search {
+        /// Text to search for. Case-insensitive.
+        query: String,
+
+        /// Only show jobs created since this time. May be a duration like `7d`
+        /// or a UTC date or time like `2026-10-01`.
+        #[arg(long = "since")]
+        since: Option<String>,
+    },
+
+    /// Print the pipeline spec used to create a job, as JSON.
+    #[command(name = "spec")]
+    Spec {
+        /// The name of the job whose spec to print.
+        job_name: String,
+    },
+
+    /// Show statistics about a job's datums.
+    #[command(name = "stats")]
+    Stats {
+        /// The name of the job to show statistics for.
+        job_name: String,
+    },
+
+    /// Stop adding datums to a streaming job. It will finish once its
+    /// remaining datums have been processed.
+    #[command(name = "stop-streaming")]
+    StopStreaming {
+        /// The name of the streaming job to stop.
+        job_name: String,
+    },
+
+    /// Show a live dashboard of running jobs, refreshing periodically. Press
+    /// `q` to quit.
+    #[command(name = "top")]
+    Top {
+        /// How often to refresh, like `5s` or `1m`.
+        #[arg(
+            long = "interval",
+            default_value = "5s",
+            value_parser = humantime::parse_duration
+        )]
+        interval: std::time::Duration,
+    },
+
     // Disabled because `BsonSchema` doesn't handle recursive types.
     //
     // /// Output a JSON schema for a falconeri job.
@@ -50,25 +231,115 @@ pub enum Opt {
     Wait {
         /// The name of the job to wait for.
         job_name: String,
+
+        /// Give up after this long, like `30m` or `2h`, and exit with status
+        /// 2.
+        #[arg(long = "timeout", value_parser = humantime::parse_duration)]
+        timeout: Option<std::time::Duration>,
+
+        /// Don't print anything. Check the exit status instead: 0 if the job
+        /// succeeded, 1 if it failed or was canceled, 2 if we timed out, and 3 if
+        /// we couldn't find the job or reach `falconerid`.
+        #[arg(long = "quiet", short = 'q')]
+        quiet: bool,
     },
 }
 
 /// Run the `job` subcommand.
-pub async fn run(opt: &Opt) -> Result<()> {
+pub async fn run(opt: &Opt, output: OutputFormat) -> Result<()> {
     match opt {
-        Opt::Describe { job_name } => describe::run(job_name).await,
-        Opt::List => list::run().await,
-        Opt::Retry { job_name } => retry::run(job_name).await,
-        Opt::Run { pipeline_json } => {
+        Opt::Audit { job_name } => audit::run(job_name, output).await,
+        Opt::Describe {
+            job_name,
+            from_file,
+            all_datums,
+        } => {
+            describe::run(
+                job_name.as_deref(),
+                from_file.as_deref(),
+                *all_datums,
+                output,
+            )
+            .await
+        }
+        Opt::Diff {
+            job_name_a,
+            job_name_b,
+        } => diff::run(job_name_a, job_name_b, output).await,
+        Opt::List => list::run(output).await,
+        Opt::Plan { pipeline_json } => {
             let f =
                 File::open(pipeline_json).context("can't open pipeline JSON file")?;
             let pipeline_spec: PipelineSpec = serde_json::from_reader(f)
                 .context("can't parse pipeline JSON file")?;
-            run::run(&pipeline_spec).await
+            plan::run(&pipeline_spec, output).await
+        }
+        Opt::RecommendResources { job_name, headroom } => {
+            recommend_resources::run(job_name, *headroom, output).await
+        }
+        Opt::Rerun {
+            from,
+            image_tag,
+            egress,
+            cmd,
+        } => {
+            let request = RerunJobRequest {
+                image_tag: image_tag.clone(),
+                cmd: if cmd.is_empty() {
+                    None
+                } else {
+                    Some(cmd.clone())
+                },
+                egress_uri: egress.clone(),
+            };
+            rerun::run(from, &request, output).await
+        }
+        Opt::Retry { job_name } => retry::run(job_name, output).await,
+        Opt::Run {
+            pipeline_json,
+            force,
+            override_datum_cap,
+            idempotency_key,
+            name,
+            generate_name,
+            depends_on,
+            input_from_upstream,
+        } => {
+            let f =
+                File::open(pipeline_json).context("can't open pipeline JSON file")?;
+            let pipeline_spec: PipelineSpec = serde_json::from_reader(f)
+                .context("can't parse pipeline JSON file")?;
+            let mut options = RunJobOptions::default()
+                .force(*force)
+                .override_datum_cap(*override_datum_cap)
+                .input_from_upstream(*input_from_upstream);
+            options.idempotency_key = idempotency_key.clone();
+            options.name = name.clone();
+            options.generate_name = generate_name.clone();
+            options.depends_on = depends_on.clone();
+            run::run(&pipeline_spec, &options, output).await
+        }
+        Opt::Scale {
+            job_name,
+            parallelism,
+        } => scale::run(job_name, *parallelism, output).await,
+        Opt::Search { query, since } => {
+            search::run(query, since.as_deref(), output).await
+        }
+        Opt::Spec { job_name } => spec::run(job_name, output).await,
+        Opt::Stats { job_name } => stats::run(job_name, output).await,
+        Opt::StopStreaming { job_name } => stop_streaming::run(job_name, output).await,
+        Opt::Top { .. } if output != OutputFormat::Table => {
+            Err(format_err!("`job top` does not support --output"))
         }
+        Opt::Top { interval } => top::run(*interval).await,
         // Disabled because it's broken by recurive `"input"` types.
         //
         // Opt::Schema => schema::run(),
-        Opt::Wait { job_name } => wait::run(job_name).await,
+        Opt::Wait {
+            job_name,
+            timeout,
+            quiet,
+        } => wait::run(job_name, *timeout, *quiet, output).await,
     }
 }
@@ -0,0 +1,68 @@
+//! The `job plan` subcommand.
+
+use falconeri_common::{
+    pipeline::PipelineSpec,
+    prelude::*,
+    rest_api::{Client, JobPlan, PlanJobRequest},
+};
+use prettytable::{format::consts::FORMAT_CLEAN, row, Table};
+
+use crate::output::OutputFormat;
+
+/// The `job plan` subcommand.
+#[instrument(skip_all, level = "trace")]
+pub async fn run(pipeline_spec: &PipelineSpec, output: OutputFormat) -> Result<()> {
+    let client = Client::new(ConnectVia::Proxy).await?;
+    let request = PlanJobRequest {
+        job: pipeline_spec.to_owned(),
+    };
+    let plan = client.plan_job(&request).await?;
+    output.print(&plan, |plan| {
+        print_tables(pipeline_spec, plan);
+        Ok(())
+    })?;
+
+    if let Some(err) = &plan.datum_count_error {
+        eprintln!("\nWARNING: job would not be created: {}", err);
+    }
+    Ok(())
+}
+
+/// Print `plan` as tables.
+fn print_tables(pipeline_spec: &PipelineSpec, plan: &JobPlan) {
+    // Overall summary.
+    let mut summary = Table::new();
+    summary.set_format(*FORMAT_CLEAN);
+    summary.add_row(row!["DATUMS", plan.datum_count]);
+    if pipeline_spec.skip_processed {
+        summary.add_row(row!["SKIPPED_DATUMS", plan.skipped_datum_count]);
+    }
+    summary.add_row(row!["INPUT_FILES", plan.input_file_count]);
+    summary.add_row(row!["TOTAL_BYTES", plan.total_bytes]);
+    summary.add_row(row!["LARGEST_DATUM_BYTES", plan.largest_datum_bytes]);
+    summary.add_row(row!["UNKNOWN_SIZE_FILES", plan.unknown_size_file_count]);
+    summary.printstd();
+
+    // Example datums.
+    if !plan.example_datums.is_empty() {
+        println!();
+        let mut examples = Table::new();
+        examples.set_format(*FORMAT_CLEAN);
+        examples.add_row(row!["EXAMPLE_DATUM", "URI", "LOCAL_PATH", "BYTES"]);
+        for (i, datum) in plan.example_datums.iter().enumerate() {
+            for input_file in &datum.input_files {
+                let size = input_file
+                    .size
+                    .map(|size| size.to_string())
+                    .unwrap_or_else(|| "-".to_owned());
+                examples.add_row(row![
+                    i + 1,
+                    &input_file.uri,
+                    &input_file.local_path,
+                    size
+                ]);
+            }
+        }
+        examples.printstd();
+    }
+}
@@ -0,0 +1,82 @@
+//! The `job recommend-resources` subcommand.
+
+use falconeri_common::{prelude::*, rest_api::Client};
+use prettytable::{format::consts::FORMAT_CLEAN, row, Table};
+
+use crate::{cmd::db::format_bytes, output::OutputFormat};
+
+/// The `job recommend-resources` subcommand.
+#[instrument(level = "trace")]
+pub async fn run(
+    job_name: &str,
+    headroom_percent: Option<u32>,
+    output: OutputFormat,
+) -> Result<()> {
+    let client = Client::new(ConnectVia::Proxy).await?;
+    let job = client.find_job_by_name(job_name).await?;
+    let recommendation = client
+        .recommended_resources(job.id, headroom_percent)
+        .await?;
+    output.print(&recommendation, |recommendation| {
+        print_tables(recommendation);
+        Ok(())
+    })
+}
+
+/// Print `recommendation` as tables.
+fn print_tables(recommendation: &ResourceRecommendation) {
+    let mut summary = Table::new();
+    summary.set_format(*FORMAT_CLEAN);
+    summary.add_row(row!["PIPELINE", &recommendation.pipeline_name]);
+    summary.add_row(row!["JOBS", recommendation.job_count]);
+    summary.add_row(row!["DATUMS", recommendation.datum_count]);
+    summary.add_row(row![
+        "P95_PEAK_MEMORY",
+        recommendation
+            .p95_peak_memory_bytes
+            .map(format_bytes)
+            .unwrap_or_else(|| "-".to_owned()),
+    ]);
+    summary.add_row(row![
+        "P95_CPU_CORES",
+        recommendation
+            .p95_cpu_cores
+            .map(|cores| format!("{:.2}", cores))
+            .unwrap_or_else(|| "-".to_owned()),
+    ]);
+    summary.add_row(row![
+        "HEADROOM",
+        format!("{}%", recommendation.headroom_percent)
+    ]);
+    summary.printstd();
+
+    println!();
+    let mut requests = Table::new();
+    requests.set_format(*FORMAT_CLEAN);
+    requests.add_row(row!["RESOURCE", "CURRENT", "RECOMMENDED"]);
+    let current = recommendation.current.as_ref();
+    requests.add_row(row![
+        "memory",
+        current.map(|c| c.memory.as_str()).unwrap_or("-"),
+        recommendation.memory.as_deref().unwrap_or("-"),
+    ]);
+    requests.add_row(row![
+        "cpu",
+        current
+            .map(|c| c.cpu.to_string())
+            .unwrap_or_else(|| "-".to_owned()),
+        recommendation
+            .cpu
+            .map(|cpu| cpu.to_string())
+            .unwrap_or_else(|| "-".to_owned()),
+    ]);
+    requests.printstd();
+
+    if recommendation.datum_count == 0 {
+        println!();
+        println!(
+            "No datums of pipeline {:?} have reported their resource usage yet.",
+            recommendation.pipeline_name
+        );
+    }
+}
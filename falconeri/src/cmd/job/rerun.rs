@@ -0,0 +1,24 @@
+//! The `job rerun` subcommand.
+
+use falconeri_common::{
+    prelude::*,
+    rest_api::{Client, RerunJobRequest},
+};
+
+use crate::output::OutputFormat;
+
+/// The `job rerun` subcommand.
+#[instrument(skip(request), level = "trace")]
+pub async fn run(
+    from_job_name: &str,
+    request: &RerunJobRequest,
+    output: OutputFormat,
+) -> Result<()> {
+    let client = Client::new(ConnectVia::Proxy).await?;
+    let job = client.find_job_by_name(from_job_name).await?;
+    let new_job = client.rerun_job(&job, request).await?;
+    output.print(&new_job, |new_job| {
+        println!("{}", new_job.job_name);
+        Ok(())
+    })
+}
@@ -2,8 +2,10 @@
 
 use falconeri_common::{prelude::*, rest_api::Client};
 
+use crate::output::OutputFormat;
+
 /// The `job retry` subcommand.
-pub async fn run(job_name: &str) -> Result<()> {
+pub async fn run(job_name: &str, output: OutputFormat) -> Result<()> {
     let mut client = Client::new(ConnectVia::Proxy).await?;
     let job = client.find_job_by_name(job_name).await?;
     // TODO: We need to create a new client here because we don't have HTTP
@@ -11,6 +13,8 @@ pub async fn run(job_name: &str) -> Result<()> {
     // idempotently yet.
     client = Client::new(ConnectVia::Proxy).await?;
     let new_job = client.retry_job(&job).await?;
-    println!("{}", new_job.job_name);
-    Ok(())
+    output.print(&new_job, |new_job| {
+        println!("{}", new_job.job_name);
+        Ok(())
+    })
 }
@@ -1,12 +1,29 @@
 //! The `job run` subcommand.
 
-use falconeri_common::{pipeline::*, prelude::*, rest_api::Client};
+use falconeri_common::{
+    ops::{self, RunJobOptions},
+    pipeline::*,
+    prelude::*,
+    rest_api::Client,
+};
+
+use crate::output::OutputFormat;
 
 /// The `job run` subcommand.
 #[instrument(skip_all, level = "trace")]
-pub async fn run(pipeline_spec: &PipelineSpec) -> Result<()> {
+pub async fn run(
+    pipeline_spec: &PipelineSpec,
+    options: &RunJobOptions,
+    output: OutputFormat,
+) -> Result<()> {
+    // Check explicit names locally, so that typos produce a quick error.
+    if let Some(name) = &options.name {
+        check_job_name(name)?;
+    }
     let client = Client::new(ConnectVia::Proxy).await?;
-    let job = client.new_job(pipeline_spec).await?;
-    println!("{}", job.job_name);
-    Ok(())
+    let job = ops::run_job(&client, pipeline_spec, options).await?;
+    output.print(&job, |job| {
+        println!("{}", job.job_name);
+        Ok(())
+    })
 }
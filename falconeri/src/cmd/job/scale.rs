@@ -0,0 +1,31 @@
+//! The `job scale` subcommand.
+
+use falconeri_common::{
+    prelude::*,
+    rest_api::{Client, JobPatch},
+};
+
+use crate::output::OutputFormat;
+
+/// The `job scale` subcommand.
+#[instrument(level = "trace")]
+pub async fn run(
+    job_name: &str,
+    parallelism: u32,
+    output: OutputFormat,
+) -> Result<()> {
+    let client = Client::new(ConnectVia::Proxy).await?;
+    let job = client.find_job_by_name(job_name).await?;
+    let patch = JobPatch {
+        target_parallelism: Some(parallelism),
+    };
+    let job = client.patch_job(&job, &patch).await?;
+    output.print(&job, |job| {
+        println!(
+            "{} scaled to {} workers",
+            job.job_name,
+            job.target_parallelism.unwrap_or_default()
+        );
+        Ok(())
+    })
+}
@@ -0,0 +1,119 @@
+//! The `job search` subcommand.
+
+use std::time::SystemTime;
+
+use falconeri_common::{
+    chrono::{self, DateTime},
+    prelude::*,
+    rest_api::Client,
+};
+use prettytable::{format::consts::FORMAT_CLEAN, row, Table};
+
+use crate::output::OutputFormat;
+
+/// The maximum number of characters of each error message to show.
+const MAX_ERROR_CHARS: usize = 80;
+
+/// The `job search` subcommand.
+#[instrument(level = "trace")]
+pub async fn run(
+    query: &str,
+    since: Option<&str>,
+    output: OutputFormat,
+) -> Result<()> {
+    let since = since
+        .map(|since| parse_since(since, SystemTime::now()))
+        .transpose()?;
+
+    // Look up the information to display.
+    let client = Client::new(ConnectVia::Proxy).await?;
+    let results = client.search_jobs(query, since).await?;
+    output.print(&results, |results| {
+        print_table(results);
+        Ok(())
+    })
+}
+
+/// Print search results as a table.
+fn print_table(results: &[JobSearchResult]) {
+    let mut table = Table::new();
+    table.set_format(*FORMAT_CLEAN);
+    table.add_row(row![
+        "JOB_NAME",
+        "STATUS",
+        "CREATED_AT",
+        "MATCHING_DATUMS",
+        "ERROR_MESSAGE"
+    ]);
+    for result in results {
+        let error_message = result
+            .matching_error_message
+            .as_deref()
+            .map(summarize_error)
+            .unwrap_or_else(|| "-".to_owned());
+        table.add_row(row![
+            &result.job.job_name,
+            result.job.status,
+            result.job.created_at,
+            result.matching_datum_count,
+            error_message,
+        ]);
+    }
+    table.printstd();
+}
+
+/// Parse a `--since` argument, which may be either a duration like `7d`, or a
+/// UTC date or time like `2026-10-01` or `2026-10-01 12:00:00`.
+fn parse_since(since: &str, now: SystemTime) -> Result<NaiveDateTime> {
+    let time = if let Ok(duration) = humantime::parse_duration(since) {
+        now.checked_sub(duration)
+            .ok_or_else(|| format_err!("--since {} is too far in the past", since))?
+    } else if let Ok(date) = since.parse::<chrono::NaiveDate>() {
+        return Ok(date.and_time(chrono::NaiveTime::MIN));
+    } else {
+        humantime::parse_rfc3339_weak(since).with_context(|| {
+            format!("--since {:?} should be a duration or a date", since)
+        })?
+    };
+    Ok(DateTime::<Utc>::from(time).naive_utc())
+}
+
+/// Shorten an error message to a single line that fits in our table.
+fn summarize_error(error_message: &str) -> String {
+    let first_line = error_message.lines().next().unwrap_or_default();
+    if first_line.chars().count() > MAX_ERROR_CHARS {
+        let mut summary = first_line
+            .chars()
+            .take(MAX_ERROR_CHARS - 3)
+            .collect::<String>();
+        summary.push_str("...");
+        summary
+    } else {
+        first_line.to_owned()
+    }
+}
+
+#[test]
+fn parse_since_accepts_durations_and_dates() {
+    let now = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(10 * 86400);
+    assert_eq!(
+        parse_since("7d", now).unwrap().to_string(),
+        "1970-01-04 00:00:00"
+    );
+    assert_eq!(
+        parse_since("2026-10-01", now).unwrap().to_string(),
+        "2026-10-01 00:00:00"
+    );
+    assert_eq!(
+        parse_since("2026-10-01 12:30:00", now).unwrap().to_string(),
+        "2026-10-01 12:30:00"
+    );
+    assert!(parse_since("last tuesday", now).is_err());
+}
+
+#[test]
+fn summarize_error_uses_first_line() {
+    assert_eq!(summarize_error("OOMKilled\nmore details"), "OOMKilled");
+    let long = "x".repeat(100);
+    assert_eq!(summarize_error(&long).chars().count(), MAX_ERROR_CHARS);
+}
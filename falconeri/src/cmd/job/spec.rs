@@ -0,0 +1,22 @@
+//! The `job spec` subcommand.
+
+use falconeri_common::{prelude::*, rest_api::Client, serde_json};
+
+use crate::output::OutputFormat;
+
+/// The `job spec` subcommand. We print JSON by default, because that's what
+/// `job run` expects.
+#[instrument(level = "trace")]
+pub async fn run(job_name: &str, output: OutputFormat) -> Result<()> {
+    let client = Client::new(ConnectVia::Proxy).await?;
+    let job = client.find_job_by_name(job_name).await?;
+    let pipeline_spec = client.job_spec(job.id).await?;
+    output.print(&pipeline_spec, |pipeline_spec| {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(pipeline_spec)
+                .context("could not serialize pipeline spec")?
+        );
+        Ok(())
+    })
+}
@@ -0,0 +1,105 @@
+//! The `job stats` subcommand.
+
+use falconeri_common::{prelude::*, rest_api::Client};
+use prettytable::{format::consts::FORMAT_CLEAN, row, Table};
+
+use crate::{cmd::db::format_bytes, output::OutputFormat};
+
+/// The `job stats` subcommand.
+#[instrument(level = "trace")]
+pub async fn run(job_name: &str, output: OutputFormat) -> Result<()> {
+    // Look up the information to display.
+    let client = Client::new(ConnectVia::Proxy).await?;
+    let job = client.find_job_by_name(job_name).await?;
+    let stats = client.job_stats(job.id).await?;
+    output.print(&stats, |stats| {
+        print_tables(stats);
+        Ok(())
+    })
+}
+
+/// Print `stats` as tables.
+fn print_tables(stats: &JobStats) {
+    // Overall summary.
+    let mut summary = Table::new();
+    summary.set_format(*FORMAT_CLEAN);
+    summary.add_row(row!["DONE", stats.done_count]);
+    summary.add_row(row!["ERROR", stats.error_count]);
+    summary.add_row(row!["CACHE_HITS", stats.cache_hit_count]);
+    summary.add_row(row!["CACHE_MISSES", stats.cache_miss_count]);
+    summary.add_row(row!["P50_SECONDS", format_seconds(stats.p50_seconds)]);
+    summary.add_row(row!["P90_SECONDS", format_seconds(stats.p90_seconds)]);
+    summary.add_row(row!["P99_SECONDS", format_seconds(stats.p99_seconds)]);
+    summary.add_row(row!["INPUT_BYTES", stats.input_bytes]);
+    summary.add_row(row!["OUTPUT_BYTES", stats.output_bytes]);
+    summary.add_row(row![
+        "P50_PEAK_MEMORY",
+        format_memory(stats.p50_peak_memory_bytes)
+    ]);
+    summary.add_row(row![
+        "P90_PEAK_MEMORY",
+        format_memory(stats.p90_peak_memory_bytes)
+    ]);
+    summary.add_row(row![
+        "P99_PEAK_MEMORY",
+        format_memory(stats.p99_peak_memory_bytes)
+    ]);
+    summary.add_row(row![
+        "P50_CPU_SECONDS",
+        format_seconds(stats.p50_cpu_seconds)
+    ]);
+    summary.add_row(row![
+        "P90_CPU_SECONDS",
+        format_seconds(stats.p90_cpu_seconds)
+    ]);
+    summary.add_row(row![
+        "P99_CPU_SECONDS",
+        format_seconds(stats.p99_cpu_seconds)
+    ]);
+    summary.printstd();
+
+    // Throughput over time.
+    if !stats.throughput.is_empty() {
+        println!();
+        let mut throughput = Table::new();
+        throughput.set_format(*FORMAT_CLEAN);
+        throughput.add_row(row!["HOUR", "DONE"]);
+        for bucket in &stats.throughput {
+            throughput.add_row(row![bucket.hour, bucket.done_count]);
+        }
+        throughput.printstd();
+    }
+
+    // Failures by node.
+    if !stats.nodes.is_empty() {
+        println!();
+        let mut nodes = Table::new();
+        nodes.set_format(*FORMAT_CLEAN);
+        nodes.add_row(row!["NODE_NAME", "DONE", "ERROR", "FAILURE_RATE"]);
+        for node in &stats.nodes {
+            nodes.add_row(row![
+                &node.node_name,
+                node.done_count,
+                node.error_count,
+                format!("{:.1}%", 100.0 * node.failure_rate()),
+            ]);
+        }
+        nodes.printstd();
+    }
+}
+
+/// Format an optional number of bytes of memory for display.
+fn format_memory(bytes: Option<i64>) -> String {
+    match bytes {
+        Some(bytes) => format_bytes(bytes),
+        None => "-".to_owned(),
+    }
+}
+
+/// Format an optional number of seconds for display.
+fn format_seconds(seconds: Option<f64>) -> String {
+    match seconds {
+        Some(seconds) => format!("{:.1}", seconds),
+        None => "-".to_owned(),
+    }
+}
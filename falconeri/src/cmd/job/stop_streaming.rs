@@ -0,0 +1,17 @@
+//! The `job stop-streaming` subcommand.
+
+use falconeri_common::{prelude::*, rest_api::Client};
+
+use crate::output::OutputFormat;
+
+/// The `job stop-streaming` subcommand.
+#[instrument(level = "trace")]
+pub async fn run(job_name: &str, output: OutputFormat) -> Result<()> {
+    let client = Client::new(ConnectVia::Proxy).await?;
+    let job = client.find_job_by_name(job_name).await?;
+    let job = client.stop_streaming(&job).await?;
+    output.print(&job, |job| {
+        println!("{} {}", job.job_name, job.status);
+        Ok(())
+    })
+}
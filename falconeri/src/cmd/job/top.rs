@@ -0,0 +1,370 @@
+//! The `job top` subcommand.
+//!
+//! A live dashboard of running jobs, similar to `top`. We poll the describe
+//! endpoint for each running job, and work out throughput by comparing how
+//! many datums were done at each refresh.
+
+use std::{
+    io,
+    time::{Duration, Instant},
+};
+
+use falconeri_common::{
+    prelude::*,
+    rest_api::{Client, JobDescribeResponse},
+};
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout},
+    style::{Style, Stylize},
+    widgets::{Block, Paragraph, Row, Table},
+    DefaultTerminal, Frame,
+};
+
+/// How many of the slowest running datums to show.
+const SLOW_DATUM_LIMIT: usize = 20;
+
+/// The `job top` subcommand.
+#[instrument(level = "trace")]
+pub async fn run(interval: Duration) -> Result<()> {
+    let client = Client::new(ConnectVia::Proxy).await?;
+    let mut terminal = ratatui::init();
+    let result = run_dashboard(&client, &mut terminal, interval).await;
+    ratatui::restore();
+    result
+}
+
+/// Refresh and redraw our dashboard every `interval`, until the user quits.
+async fn run_dashboard(
+    client: &Client,
+    terminal: &mut DefaultTerminal,
+    interval: Duration,
+) -> Result<()> {
+    let mut snapshot = Snapshot::default();
+    let mut previous_done_counts = HashMap::new();
+    loop {
+        match fetch_descriptions(client).await {
+            Ok(descriptions) => {
+                snapshot = Snapshot::new(
+                    &descriptions,
+                    &previous_done_counts,
+                    Utc::now().naive_utc(),
+                );
+                let now = Instant::now();
+                previous_done_counts = descriptions
+                    .iter()
+                    .map(|d| (d.job.id, (now, done_count(d))))
+                    .collect();
+            }
+            // Keep showing our last snapshot, because a flaky connection is
+            // exactly when people need this the most.
+            Err(err) => snapshot.error = Some(format!("{:#}", err)),
+        }
+        terminal.draw(|frame| draw(frame, &snapshot, interval))?;
+
+        // Wait for the next refresh, handling keys as they arrive.
+        let deadline = Instant::now() + interval;
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() {
+                break;
+            }
+            match next_event(timeout).await? {
+                Some(Event::Key(key)) if key.kind == KeyEventKind::Press => {
+                    match key.code {
+                        KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                        KeyCode::Char('c')
+                            if key.modifiers.contains(KeyModifiers::CONTROL) =>
+                        {
+                            return Ok(())
+                        }
+                        KeyCode::Char('r') => break,
+                        _ => {}
+                    }
+                }
+                Some(Event::Resize(..)) => {
+                    terminal.draw(|frame| draw(frame, &snapshot, interval))?;
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Wait up to `timeout` for a terminal event.
+async fn next_event(timeout: Duration) -> Result<Option<Event>> {
+    let event = tokio::task::spawn_blocking(move || -> io::Result<Option<Event>> {
+        if event::poll(timeout)? {
+            Ok(Some(event::read()?))
+        } else {
+            Ok(None)
+        }
+    })
+    .await?
+    .context("error reading terminal events")?;
+    Ok(event)
+}
+
+/// Describe every job which is currently running.
+async fn fetch_descriptions(client: &Client) -> Result<Vec<JobDescribeResponse>> {
+    let mut descriptions = vec![];
+    for job in client.list_jobs().await? {
+        if job.status.is_active() {
+            descriptions.push(client.describe_job(job.id).await?);
+        }
+    }
+    Ok(descriptions)
+}
+
+/// How many datums of a job have been processed successfully.
+fn done_count(description: &JobDescribeResponse) -> u64 {
+    description
+        .datum_status_counts
+        .iter()
+        .filter(|c| c.status == Status::Done)
+        .map(|c| c.count)
+        .sum()
+}
+
+/// Everything we display in one refresh of the dashboard.
+#[derive(Debug, Default)]
+struct Snapshot {
+    /// When we fetched this data.
+    refreshed_at: Option<NaiveDateTime>,
+    /// One row for each running job.
+    jobs: Vec<JobRow>,
+    /// The running datums which have been running the longest, longest
+    /// first.
+    slow_datums: Vec<SlowDatum>,
+    /// The error from our latest refresh, if it failed.
+    error: Option<String>,
+}
+
+/// A running job.
+#[derive(Debug)]
+struct JobRow {
+    job_name: String,
+    status: Status,
+    ready: u64,
+    running: u64,
+    done: u64,
+    error: u64,
+    /// Datums finished per minute since the last refresh, if we have a
+    /// previous refresh to compare against.
+    done_per_minute: Option<f64>,
+}
+
+/// A datum which is currently running.
+#[derive(Debug)]
+struct SlowDatum {
+    job_name: String,
+    datum_id: Uuid,
+    node_name: Option<String>,
+    running_seconds: i64,
+}
+
+impl Snapshot {
+    /// Summarize `descriptions`. `previous_done_counts` maps job IDs to when
+    /// we last refreshed, and how many datums were done at the time.
+    fn new(
+        descriptions: &[JobDescribeResponse],
+        previous_done_counts: &HashMap<Uuid, (Instant, u64)>,
+        now: NaiveDateTime,
+    ) -> Snapshot {
+        let mut jobs = vec![];
+        let mut slow_datums = vec![];
+        for description in descriptions {
+            let count = |status| {
+                description
+                    .datum_status_counts
+                    .iter()
+                    .filter(|c| c.status == status)
+                    .map(|c| c.count)
+                    .sum::<u64>()
+            };
+            let done = done_count(description);
+            let done_per_minute = previous_done_counts
+                .get(&description.job.id)
+                .and_then(|&(at, previous)| {
+                    let minutes = at.elapsed().as_secs_f64() / 60.0;
+                    if minutes > 0.0 {
+                        Some(done.saturating_sub(previous) as f64 / minutes)
+                    } else {
+                        None
+                    }
+                });
+            jobs.push(JobRow {
+                job_name: description.job.job_name.clone(),
+                status: description.job.status,
+                ready: count(Status::Ready),
+                running: count(Status::Running),
+                done,
+                error: count(Status::Error),
+                done_per_minute,
+            });
+            for datum in &description.running_datums {
+                let started_at = datum.started_at.unwrap_or(datum.updated_at);
+                slow_datums.push(SlowDatum {
+                    job_name: description.job.job_name.clone(),
+                    datum_id: datum.id,
+                    node_name: datum.node_name.clone(),
+                    running_seconds: (now - started_at).num_seconds(),
+                });
+            }
+        }
+        jobs.sort_by(|a, b| a.job_name.cmp(&b.job_name));
+        slow_datums.sort_by(|a, b| b.running_seconds.cmp(&a.running_seconds));
+        slow_datums.truncate(SLOW_DATUM_LIMIT);
+        Snapshot {
+            refreshed_at: Some(now),
+            jobs,
+            slow_datums,
+            error: None,
+        }
+    }
+}
+
+/// Draw our dashboard.
+fn draw(frame: &mut Frame, snapshot: &Snapshot, interval: Duration) {
+    let [header_area, jobs_area, datums_area] = Layout::vertical([
+        Constraint::Length(1),
+        Constraint::Percentage(50),
+        Constraint::Fill(1),
+    ])
+    .areas(frame.area());
+
+    let header = match (&snapshot.error, snapshot.refreshed_at) {
+        (Some(err), _) => Paragraph::new(format!("Refresh failed: {}", err)).red(),
+        (None, Some(at)) => Paragraph::new(format!(
+            "Refreshed at {} UTC, every {}s. Press r to refresh, q to quit.",
+            at.format("%H:%M:%S"),
+            interval.as_secs(),
+        )),
+        (None, None) => Paragraph::new("Loading..."),
+    };
+    frame.render_widget(header, header_area);
+
+    let job_rows = snapshot.jobs.iter().map(|job| {
+        let row = Row::new(vec![
+            job.job_name.clone(),
+            job.status.to_string(),
+            job.ready.to_string(),
+            job.running.to_string(),
+            job.done.to_string(),
+            job.error.to_string(),
+            job.done_per_minute
+                .map(|rate| format!("{:.1}", rate))
+                .unwrap_or_else(|| "-".to_owned()),
+        ]);
+        if job.error > 0 {
+            row.style(Style::new().red())
+        } else {
+            row
+        }
+    });
+    let jobs = Table::new(
+        job_rows,
+        [
+            Constraint::Fill(1),
+            Constraint::Length(10),
+            Constraint::Length(10),
+            Constraint::Length(10),
+            Constraint::Length(10),
+            Constraint::Length(10),
+            Constraint::Length(10),
+        ],
+    )
+    .header(
+        Row::new(vec![
+            "JOB_NAME", "STATUS", "READY", "RUNNING", "DONE", "ERROR", "DONE/MIN",
+        ])
+        .bold(),
+    )
+    .block(Block::bordered().title(format!("Running jobs ({})", snapshot.jobs.len())));
+    frame.render_widget(jobs, jobs_area);
+
+    let datum_rows = snapshot.slow_datums.iter().map(|datum| {
+        Row::new(vec![
+            datum.job_name.clone(),
+            datum.datum_id.to_string(),
+            datum.node_name.clone().unwrap_or_else(|| "-".to_owned()),
+            format_seconds(datum.running_seconds),
+        ])
+    });
+    let datums = Table::new(
+        datum_rows,
+        [
+            Constraint::Fill(1),
+            Constraint::Length(36),
+            Constraint::Fill(1),
+            Constraint::Length(10),
+        ],
+    )
+    .header(Row::new(vec!["JOB_NAME", "DATUM_ID", "NODE_NAME", "RUNNING"]).bold())
+    .block(Block::bordered().title("Slowest running datums"));
+    frame.render_widget(datums, datums_area);
+}
+
+/// Format a number of seconds like `1h02m03s`.
+fn format_seconds(seconds: i64) -> String {
+    let seconds = seconds.max(0);
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours > 0 {
+        format!("{}h{:02}m{:02}s", hours, minutes, seconds)
+    } else if minutes > 0 {
+        format!("{}m{:02}s", minutes, seconds)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+#[test]
+fn snapshot_summarizes_running_jobs() {
+    let mut job = Job::factory();
+    job.status = Status::Running;
+    let now = Utc::now().naive_utc();
+    let running_datum = |minutes| {
+        let mut datum = Datum::factory(&job);
+        datum.status = Status::Running;
+        datum.started_at =
+            Some(now - falconeri_common::chrono::Duration::minutes(minutes));
+        DatumSummary::from(&datum)
+    };
+    let dsc = |status, count| DatumStatusCount {
+        status,
+        count,
+        rerunable_count: 0,
+    };
+    let description = JobDescribeResponse {
+        job: job.clone(),
+        datum_status_counts: vec![
+            dsc(Status::Ready, 5),
+            dsc(Status::Running, 2),
+            dsc(Status::Done, 30),
+            dsc(Status::Error, 1),
+        ],
+        running_datums: vec![running_datum(1), running_datum(90)],
+        running_datums_truncated: false,
+        error_datums: vec![],
+        error_datums_truncated: false,
+        datum_timing_stats: Default::default(),
+        input_file_totals: Default::default(),
+        creation_progress: None,
+        lineage: Default::default(),
+        recent_events: vec![],
+        anomalous_nodes: vec![],
+    };
+
+    let mut previous = HashMap::new();
+    previous.insert(job.id, (Instant::now() - Duration::from_secs(60), 20));
+    let snapshot = Snapshot::new(&[description], &previous, now);
+    assert_eq!(snapshot.jobs.len(), 1);
+    let row = &snapshot.jobs[0];
+    assert_eq!((row.ready, row.running, row.done, row.error), (5, 2, 30, 1));
+    let rate = row.done_per_minute.unwrap();
+    assert!(rate > 9.0 && rate <= 10.0, "unexpected rate {}", rate);
+    assert_eq!(snapshot.slow_datums.len(), 2);
+    assert_eq!(snapshot.slow_datums[0].running_seconds, 90 * 60);
+    assert_eq!(format_seconds(90 * 60 + 5), "1h30m05s");
+    assert_eq!(format_seconds(65), "1m05s");
+}
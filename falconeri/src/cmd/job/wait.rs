@@ -1,17 +1,108 @@
 //! The `job wait` subcommand.
 
-use std::time::Duration;
+use std::{io, process, time::Duration};
 
-use falconeri_common::{prelude::*, rest_api::Client};
+use falconeri_common::{ops, prelude::*, rest_api::Client};
 
-/// The `job wait` subcommand.
-pub async fn run(job_name: &str) -> Result<()> {
-    let client = Client::new(ConnectVia::Proxy).await?;
-    let mut job = client.find_job_by_name(job_name).await?;
-    while !job.status.has_finished() {
-        tokio::time::sleep(Duration::from_secs(30)).await;
-        job = client.job(job.id).await?;
+use crate::output::OutputFormat;
+
+/// How often to check on the job. This is much shorter than
+/// [`ops::DEFAULT_POLL_INTERVAL`], because people are usually waiting for us.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Our exit code if the job failed.
+const EXIT_FAILED: i32 = 1;
+
+/// Our exit code if we timed out.
+const EXIT_TIMED_OUT: i32 = 2;
+
+/// Our exit code if we couldn't find out how the job finished, because we
+/// couldn't find it or couldn't talk to `falconerid`. This is distinct from
+/// [`EXIT_FAILED`], so that CI scripts don't mistake a flaky connection for
+/// a failed job.
+const EXIT_CLIENT_ERROR: i32 = 3;
+
+/// The `job wait` subcommand. If the job fails, we time out, or we can't
+/// reach `falconerid`, we exit the process with a non-zero exit code, so that
+/// CI scripts can check for it.
+pub async fn run(
+    job_name: &str,
+    timeout: Option<Duration>,
+    quiet: bool,
+    output: OutputFormat,
+) -> Result<()> {
+    let job = match wait_for_job(job_name, timeout).await {
+        Ok(Some(job)) => job,
+        Ok(None) => {
+            if !quiet {
+                // `timeout` is always set if we timed out.
+                eprintln!(
+                    "timed out after {} waiting for {}",
+                    humantime::format_duration(timeout.unwrap_or_default()),
+                    job_name
+                );
+            }
+            process::exit(EXIT_TIMED_OUT);
+        }
+        Err(err) => {
+            // Always report errors, even with `--quiet`, because otherwise
+            // there's no way to find out what went wrong.
+            eprintln!("Error: {:?}", err);
+            process::exit(EXIT_CLIENT_ERROR);
+        }
+    };
+    if !quiet {
+        output.print(&job, |job| {
+            println!("{}", job.status);
+            Ok(())
+        })?;
+    }
+    if let Some(code) = exit_code(job.status) {
+        io::stdout().flush()?;
+        process::exit(code);
     }
-    println!("{}", job.status);
     Ok(())
 }
+
+/// Wait for the job named `job_name` to finish, returning `None` if we time
+/// out first.
+async fn wait_for_job(
+    job_name: &str,
+    timeout: Option<Duration>,
+) -> Result<Option<Job>> {
+    let client = Client::new(ConnectVia::Proxy).await?;
+    let job = ops::find_job(&client, job_name).await?;
+    let wait = ops::wait_for_job(&client, &job, POLL_INTERVAL);
+    match timeout {
+        None => Ok(Some(wait.await?)),
+        Some(timeout) => match tokio::time::timeout(timeout, wait).await {
+            Ok(job) => Ok(Some(job?)),
+            Err(_) => Ok(None),
+        },
+    }
+}
+
+/// The exit code for a job which finished with `status`, or `None` if it
+/// succeeded. Jobs which finished with a few errors still count as a
+/// success, because they stayed within their failure threshold.
+fn exit_code(status: Status) -> Option<i32> {
+    match status {
+        Status::Error | Status::Canceled => Some(EXIT_FAILED),
+        _ => None,
+    }
+}
+
+#[test]
+fn exit_codes_for_finished_jobs() {
+    assert_eq!(exit_code(Status::Done), None);
+    assert_eq!(exit_code(Status::DoneWithErrors), None);
+    assert_eq!(exit_code(Status::Error), Some(EXIT_FAILED));
+    assert_eq!(exit_code(Status::Canceled), Some(EXIT_FAILED));
+}
+
+#[test]
+fn exit_codes_are_distinct() {
+    let codes = [EXIT_FAILED, EXIT_TIMED_OUT, EXIT_CLIENT_ERROR];
+    let unique = codes.iter().collect::<std::collections::HashSet<_>>();
+    assert_eq!(unique.len(), codes.len());
+}
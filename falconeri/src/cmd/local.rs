@@ -0,0 +1,415 @@
+//! The `local` subcommand, for running pipelines without a cluster.
+//!
+//! We list a pipeline's inputs and divide them into datums using the same
+//! code as `falconerid`, and then run the pipeline's command for each datum on
+//! this machine, either directly or using `docker run`. Each datum gets its own
+//! directory, standing in for `/pfs/$DATUM_ID` on a worker, and the command
+//! sees the same `FALCONERI_*` environment variables that it would see on a
+//! worker. We keep track of datums in memory, so nothing is written to a
+//! database.
+
+use std::{collections::BTreeMap, fs, future::Future, path::Component, pin::Pin};
+
+use clap::Subcommand;
+use falconeri_common::{
+    decompress::{decompress_in_place, decompressed_path},
+    futures_util::{stream, StreamExt},
+    inputs::{atom_to_datums, cross_datums, DatumData, InputFileData},
+    pipeline::{Input, PipelineSpec},
+    prelude::*,
+    serde_json,
+    storage::{requester_pays_for_uri, CloudStorage},
+};
+use tokio::process::Command;
+
+/// Commands for running pipelines on this machine.
+#[derive(Debug, Subcommand)]
+pub enum Opt {
+    /// Run a pipeline on this machine, without a cluster. Input files are
+    /// downloaded using your local credentials.
+    #[command(name = "run")]
+    Run {
+        /// Path to a JSON pipeline spec.
+        pipeline_json: PathBuf,
+
+        /// Where to put our datum directories and output files. Defaults to a
+        /// directory under your temporary directory.
+        #[arg(long = "work-dir")]
+        work_dir: Option<PathBuf>,
+
+        /// Run the command inside `transform.image` using `docker run`,
+        /// instead of running it directly.
+        #[arg(long = "docker")]
+        docker: bool,
+
+        /// How many datums to run at once.
+        #[arg(long = "parallelism", default_value_t = 1)]
+        parallelism: usize,
+
+        /// Upload output files to the pipeline's egress URI, instead of
+        /// copying them to `out` in our work directory.
+        #[arg(long = "upload")]
+        upload: bool,
+    },
+}
+
+/// Run the `local` subcommand.
+#[instrument(skip_all, level = "trace")]
+pub async fn run(opt: &Opt) -> Result<()> {
+    match opt {
+        Opt::Run {
+            pipeline_json,
+            work_dir,
+            docker,
+            parallelism,
+            upload,
+        } => {
+            let f =
+                File::open(pipeline_json).context("can't open pipeline JSON file")?;
+            let pipeline_spec: PipelineSpec = serde_json::from_reader(f)
+                .context("can't parse pipeline JSON file")?;
+            let work_dir = work_dir.clone().unwrap_or_else(|| {
+                std::env::temp_dir()
+                    .join("falconeri-local")
+                    .join(&pipeline_spec.pipeline.name)
+            });
+            let local_job = LocalJob::new(pipeline_spec, work_dir, *docker, *upload)?;
+            local_job.run((*parallelism).max(1)).await
+        }
+    }
+}
+
+/// A job that we're running locally.
+struct LocalJob {
+    /// Our pipeline spec.
+    pipeline_spec: PipelineSpec,
+    /// A made-up job ID, so that commands can build idempotency keys.
+    job_id: Uuid,
+    /// Where we put our datum directories.
+    work_dir: PathBuf,
+    /// Should we run our command using `docker run`?
+    docker: bool,
+    /// Should we upload our output to the egress URI?
+    upload: bool,
+    /// Requester-pays settings for our inputs, as JSON.
+    requester_pays_json: String,
+}
+
+impl LocalJob {
+    /// Prepare to run `pipeline_spec` locally.
+    fn new(
+        pipeline_spec: PipelineSpec,
+        work_dir: PathBuf,
+        docker: bool,
+        upload: bool,
+    ) -> Result<Self> {
+        if pipeline_spec.transform.stdin_files {
+            return Err(format_err!("local runs do not support stdin_files"));
+        }
+        if pipeline_spec.streaming {
+            return Err(format_err!("local runs do not support streaming jobs"));
+        }
+        if pipeline_spec.transform.cmd.is_empty() {
+            return Err(format_err!("pipeline command is empty"));
+        }
+        let requester_pays_json =
+            serde_json::to_string(&pipeline_spec.input.requester_pays_uris())?;
+        Ok(LocalJob {
+            pipeline_spec,
+            job_id: Uuid::new_v4(),
+            work_dir,
+            docker,
+            upload,
+            requester_pays_json,
+        })
+    }
+
+    /// Where do we put output files if we're not uploading them?
+    fn output_dir(&self) -> PathBuf {
+        self.work_dir.join("out")
+    }
+
+    /// Run all our datums, `parallelism` at a time.
+    #[instrument(skip_all, level = "debug")]
+    async fn run(&self, parallelism: usize) -> Result<()> {
+        let datums = self.list_datums(&self.pipeline_spec.input).await?;
+        fs::create_dir_all(self.output_dir()).with_context(|| {
+            format!("cannot create {}", self.output_dir().display())
+        })?;
+        eprintln!(
+            "Running {} datums in {}",
+            datums.len(),
+            self.work_dir.display()
+        );
+
+        let datum_count = datums.len();
+        let results = stream::iter(datums)
+            .map(|datum| self.run_datum(Uuid::new_v4(), datum.input_files))
+            .buffer_unordered(parallelism)
+            .collect::<Vec<_>>()
+            .await;
+        let error_count = results.iter().filter(|result| result.is_err()).count();
+
+        if error_count > 0 {
+            return Err(format_err!(
+                "{} of {} datums failed",
+                error_count,
+                datum_count
+            ));
+        }
+        if self.upload {
+            eprintln!(
+                "Processed {} datums, with output in {}",
+                datum_count, self.pipeline_spec.egress.uri
+            );
+        } else {
+            eprintln!(
+                "Processed {} datums, with output in {}",
+                datum_count,
+                self.output_dir().display()
+            );
+        }
+        Ok(())
+    }
+
+    /// Divide `input` into datums, using the same code as `falconerid`.
+    fn list_datums<'a>(
+        &'a self,
+        input: &'a Input,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<DatumData>>> + 'a>> {
+        Box::pin(async move {
+            match input {
+                Input::Atom { .. } => atom_to_datums(&[], input).await,
+                Input::Job { job_name, .. } => Err(format_err!(
+                    "local runs cannot read the output of job {}",
+                    job_name
+                )),
+                Input::Cross(inputs) => {
+                    let mut datums = vec![];
+                    for child in inputs {
+                        datums.push(self.list_datums(child).await?);
+                    }
+                    Ok(cross_datums(datums))
+                }
+                Input::Union(inputs) => {
+                    let mut datums = vec![];
+                    for child in inputs {
+                        datums.extend(self.list_datums(child).await?);
+                    }
+                    Ok(datums)
+                }
+            }
+        })
+    }
+
+    /// Get a storage backend for an input file at `uri`.
+    async fn storage_for_input(&self, uri: &str) -> Result<Box<dyn CloudStorage>> {
+        let requester_pays =
+            requester_pays_for_uri(Some(&self.requester_pays_json), uri)?;
+        <dyn CloudStorage>::for_input_uri(uri, &[], requester_pays.as_ref()).await
+    }
+
+    /// Run a single datum, retrying it up to `datum_tries` times.
+    #[instrument(skip_all, fields(datum = %datum_id), level = "debug")]
+    async fn run_datum(
+        &self,
+        datum_id: Uuid,
+        input_files: Vec<InputFileData>,
+    ) -> Result<()> {
+        let tries = self.pipeline_spec.datum_tries.unwrap_or(1).max(1);
+        let mut attempt = 1;
+        loop {
+            match self.attempt_datum(datum_id, &input_files, attempt).await {
+                Ok(()) => return Ok(()),
+                Err(err) if attempt < tries => {
+                    eprintln!("datum {} failed, retrying: {:#}", datum_id, err);
+                    attempt += 1;
+                }
+                Err(err) => {
+                    eprintln!("datum {} failed: {:#}", datum_id, err);
+                    return Err(err);
+                }
+            }
+        }
+    }
+
+    /// Download the inputs for a datum, run our command, and collect the
+    /// outputs.
+    async fn attempt_datum(
+        &self,
+        datum_id: Uuid,
+        input_files: &[InputFileData],
+        attempt: u32,
+    ) -> Result<()> {
+        // Set up our datum directories.
+        let root = self.work_dir.join(datum_id.to_string());
+        let input_dir = root.join("in");
+        let output_dir = root.join("out");
+        if root.exists() {
+            fs::remove_dir_all(&root)
+                .with_context(|| format!("cannot delete {}", root.display()))?;
+        }
+        for dir in [&input_dir, &output_dir] {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("cannot create {}", dir.display()))?;
+        }
+
+        // Download our inputs.
+        let mut input_rel_paths = vec![];
+        for input_file in input_files {
+            let rel_path = input_rel_path(&input_file.local_path)?;
+            let local_path = input_dir.join(&rel_path);
+            if let Some(parent) = local_path.parent() {
+                fs::create_dir_all(parent)
+                    .with_context(|| format!("cannot create {}", parent.display()))?;
+            }
+            let storage = self.storage_for_input(&input_file.uri).await?;
+            storage.sync_down(&input_file.uri, &local_path).await?;
+            if input_file.decompress {
+                decompress_in_place(&local_path)?;
+                input_rel_paths
+                    .push(input_rel_path(decompressed_path(&input_file.local_path))?);
+            } else {
+                input_rel_paths.push(rel_path);
+            }
+        }
+
+        // Run our command. Inside Docker, we mount our datum directory where
+        // a worker would have it.
+        let command_root = if self.docker {
+            Path::new("/pfs").join(datum_id.to_string())
+        } else {
+            root.clone()
+        };
+        let input_paths = input_rel_paths
+            .iter()
+            .map(|rel_path| {
+                command_root.join("in").join(rel_path).display().to_string()
+            })
+            .collect::<Vec<_>>();
+        let mut env = self
+            .pipeline_spec
+            .transform
+            .env
+            .iter()
+            .map(|(name, value)| (name.to_owned(), value.to_owned()))
+            .collect::<BTreeMap<_, _>>();
+        for (name, value) in [
+            ("FALCONERI_JOB_ID", self.job_id.to_string()),
+            (
+                "FALCONERI_JOB_NAME",
+                self.pipeline_spec.pipeline.name.clone(),
+            ),
+            ("FALCONERI_DATUM_ID", datum_id.to_string()),
+            ("FALCONERI_ATTEMPT", attempt.to_string()),
+            ("FALCONERI_INPUT_FILES", input_paths.join("\n")),
+            ("FALCONERI_DATUM_DIR", command_root.display().to_string()),
+            (
+                "FALCONERI_INPUT_DIR",
+                command_root.join("in").display().to_string(),
+            ),
+            (
+                "FALCONERI_OUTPUT_DIR",
+                command_root.join("out").display().to_string(),
+            ),
+        ] {
+            env.insert(name.to_owned(), value);
+        }
+        let cmd = &self.pipeline_spec.transform.cmd;
+        let mut command = if self.docker {
+            let mut command = Command::new("docker");
+            command.args(["run", "--rm", "--entrypoint", cmd[0].as_str(), "-v"]);
+            command.arg(format!("{}:{}", root.display(), command_root.display()));
+            for (name, value) in &env {
+                command.arg("-e").arg(format!("{}={}", name, value));
+            }
+            command.arg(&self.pipeline_spec.transform.image);
+            command.args(&cmd[1..]);
+            command
+        } else {
+            let mut command = Command::new(&cmd[0]);
+            command.args(&cmd[1..]).envs(&env);
+            command
+        };
+        let status = command
+            .status()
+            .await
+            .with_context(|| format!("could not run {:?}", &cmd[0]))?;
+        if !status.success() {
+            return Err(format_err!("command {:?} failed with {}", cmd, status));
+        }
+
+        // Collect our outputs, and clean up.
+        if self.upload {
+            let egress_uri = &self.pipeline_spec.egress.uri;
+            let storage = <dyn CloudStorage>::for_uri(egress_uri, &[]).await?;
+            storage.sync_up(&output_dir, egress_uri).await?;
+        } else {
+            copy_dir_contents(&output_dir, &self.output_dir())?;
+        }
+        fs::remove_dir_all(&root)
+            .with_context(|| format!("cannot delete {}", root.display()))?;
+        Ok(())
+    }
+}
+
+/// Convert an input path like `/pfs/$REPO/$FILE` to `$REPO/$FILE`, refusing
+/// any path which would escape our input directory.
+fn input_rel_path(local_path: &str) -> Result<PathBuf> {
+    let rel_path = Path::new(local_path)
+        .strip_prefix("/pfs")
+        .map_err(|_| format_err!("input path {:?} is not under /pfs", local_path))?;
+    let mut path = PathBuf::new();
+    for component in rel_path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::Normal(part) => path.push(part),
+            _ => {
+                return Err(format_err!(
+                    "input path {:?} may not contain {:?}",
+                    local_path,
+                    component
+                ))
+            }
+        }
+    }
+    Ok(path)
+}
+
+/// Copy everything in `src` into `dest`, replacing any existing files.
+fn copy_dir_contents(src: &Path, dest: &Path) -> Result<()> {
+    fs::create_dir_all(dest)
+        .with_context(|| format!("cannot create {}", dest.display()))?;
+    let entries = src
+        .read_dir()
+        .with_context(|| format!("error listing directory {}", src.display()))?;
+    for entry in entries {
+        let entry = entry
+            .with_context(|| format!("error listing directory {}", src.display()))?;
+        let src_path = entry.path();
+        let dest_path = dest.join(entry.file_name());
+        if src_path.is_dir() {
+            copy_dir_contents(&src_path, &dest_path)?;
+        } else {
+            fs::copy(&src_path, &dest_path).with_context(|| {
+                format!(
+                    "cannot copy {} to {}",
+                    src_path.display(),
+                    dest_path.display()
+                )
+            })?;
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn input_rel_path_stays_in_input_dir() {
+    assert_eq!(
+        input_rel_path("/pfs/books/a.txt").unwrap(),
+        Path::new("books/a.txt")
+    );
+    assert_eq!(input_rel_path("/pfs/books/").unwrap(), Path::new("books"));
+    assert!(input_rel_path("/pfs/../etc/passwd").is_err());
+    assert!(input_rel_path("/etc/passwd").is_err());
+}
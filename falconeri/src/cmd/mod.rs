@@ -1,9 +1,16 @@
 //! Command-line commands.
 
+pub mod api_token;
+pub mod context;
 pub mod datum;
 pub mod db;
 pub mod deploy;
+pub mod dev;
 pub mod job;
+pub mod local;
 pub mod migrate;
+pub mod pipeline;
 pub mod proxy;
+pub mod quota;
 pub mod schema;
+pub mod storage;
@@ -0,0 +1,167 @@
+//! The `pipeline` subcommand, for managing registered pipelines.
+
+use clap::Subcommand;
+use falconeri_common::{
+    pipeline::PipelineSpec,
+    prelude::*,
+    rest_api::{Client, CreatePipelineRequest},
+    schedule::CronSchedule,
+    serde_json,
+};
+use prettytable::{format::consts::FORMAT_CLEAN, row, Table};
+
+use crate::output::OutputFormat;
+
+/// Commands for managing registered pipelines.
+#[derive(Debug, Subcommand)]
+pub enum Opt {
+    /// Register a pipeline, optionally running it on a schedule.
+    #[command(name = "create")]
+    Create {
+        /// The unique name of the pipeline. Jobs started from this pipeline
+        /// will use this as a prefix.
+        name: String,
+
+        /// Path to a JSON pipeline spec.
+        pipeline_json: PathBuf,
+
+        /// A cron schedule, interpreted in UTC. For example, `0 3 * * *`
+        /// runs the pipeline every day at 03:00 UTC.
+        #[arg(long = "schedule")]
+        schedule: Option<String>,
+
+        /// Replace any existing pipeline with the same name.
+        #[arg(long = "replace")]
+        replace: bool,
+    },
+
+    /// List all registered pipelines.
+    #[command(name = "list")]
+    List,
+
+    /// Delete a registered pipeline. Jobs it already started are unaffected.
+    #[command(name = "delete")]
+    Delete {
+        /// The name of the pipeline to delete.
+        name: String,
+    },
+
+    /// Start a job from a registered pipeline right away.
+    #[command(name = "trigger")]
+    Trigger {
+        /// The name of the pipeline to run.
+        name: String,
+
+        /// Start the job even if an identical job is already running.
+        #[arg(long = "force")]
+        force: bool,
+    },
+}
+
+/// Run the `pipeline` subcommand.
+#[instrument(skip_all, level = "trace")]
+pub async fn run(opt: &Opt, output: OutputFormat) -> Result<()> {
+    match opt {
+        Opt::Create {
+            name,
+            pipeline_json,
+            schedule,
+            replace,
+        } => run_create(name, pipeline_json, schedule.as_deref(), *replace).await,
+        Opt::List => run_list(output).await,
+        Opt::Delete { name } => run_delete(name).await,
+        Opt::Trigger { name, force } => run_trigger(name, *force).await,
+    }
+}
+
+/// Register a pipeline.
+#[instrument(skip_all, fields(name = %name), level = "trace")]
+async fn run_create(
+    name: &str,
+    pipeline_json: &Path,
+    schedule: Option<&str>,
+    replace: bool,
+) -> Result<()> {
+    // Check what we can locally, so that typos produce a quick error.
+    check_registered_pipeline_name(name)?;
+    if let Some(schedule) = schedule {
+        schedule.parse::<CronSchedule>()?;
+    }
+    let f = File::open(pipeline_json).context("can't open pipeline JSON file")?;
+    let pipeline_spec: PipelineSpec =
+        serde_json::from_reader(f).context("can't parse pipeline JSON file")?;
+
+    let client = Client::new(ConnectVia::Proxy).await?;
+    let pipeline = client
+        .create_pipeline(&CreatePipelineRequest {
+            name: name.to_owned(),
+            pipeline_spec,
+            schedule: schedule.map(|s| s.to_owned()),
+            replace,
+        })
+        .await?;
+    match pipeline.next_run_at {
+        Some(next_run_at) => {
+            println!("{} (next run at {} UTC)", pipeline.name, next_run_at)
+        }
+        None => println!("{}", pipeline.name),
+    }
+    Ok(())
+}
+
+/// List registered pipelines.
+#[instrument(level = "trace")]
+async fn run_list(output: OutputFormat) -> Result<()> {
+    let client = Client::new(ConnectVia::Proxy).await?;
+    let pipelines = client.list_pipelines().await?;
+    output.print(&pipelines, |pipelines| {
+        print_table(pipelines);
+        Ok(())
+    })
+}
+
+/// Print `pipelines` as a table.
+fn print_table(pipelines: &[RegisteredPipeline]) {
+    let mut table = Table::new();
+    table.set_format(*FORMAT_CLEAN);
+    table.add_row(row![
+        "NAME",
+        "SCHEDULE",
+        "NEXT_RUN_AT",
+        "LAST_RUN_AT",
+        "LAST_JOB_ID"
+    ]);
+    for pipeline in pipelines {
+        table.add_row(row![
+            &pipeline.name,
+            pipeline.schedule.as_deref().unwrap_or("-"),
+            optional_display(pipeline.next_run_at),
+            optional_display(pipeline.last_run_at),
+            optional_display(pipeline.last_job_id),
+        ]);
+    }
+    table.printstd();
+}
+
+/// Delete a registered pipeline.
+#[instrument(level = "trace")]
+async fn run_delete(name: &str) -> Result<()> {
+    let client = Client::new(ConnectVia::Proxy).await?;
+    client.delete_pipeline(name).await
+}
+
+/// Start a job from a registered pipeline, and print its name.
+#[instrument(level = "trace")]
+async fn run_trigger(name: &str, force: bool) -> Result<()> {
+    let client = Client::new(ConnectVia::Proxy).await?;
+    let job = client.trigger_pipeline(name, force).await?;
+    println!("{}", job.job_name);
+    Ok(())
+}
+
+/// Format an optional value for a table, using `-` for missing values.
+fn optional_display<T: fmt::Display>(value: Option<T>) -> String {
+    value
+        .map(|v| v.to_string())
+        .unwrap_or_else(|| "-".to_owned())
+}
@@ -1,31 +1,98 @@
 //! The `proxy` subcommand.
 
-use std::time::{Duration, Instant};
+use std::{
+    collections::HashSet,
+    net::TcpListener,
+    process::Stdio,
+    time::{Duration, Instant},
+};
 
+use clap::Args;
 use falconeri_common::{
     futures_util::future::join_all, kubernetes, prelude::*, tokio,
 };
 use tokio::{
-    process::{Child, Command},
+    io::{AsyncBufReadExt, BufReader},
+    process::Child,
     sync::broadcast,
 };
 
+/// The port used by `falconerid`.
+const API_PORT: u16 = 8089;
+
+/// The port used by PostgreSQL.
+const POSTGRES_PORT: u16 = 5432;
+
+/// Options for the `proxy` subcommand.
+#[derive(Debug, Args)]
+pub struct Opt {
+    /// The local port to forward to `falconerid`. If you change this, set
+    /// `FALCONERID_URL` to match.
+    #[arg(long = "api-port", default_value_t = API_PORT)]
+    api_port: u16,
+
+    /// The local port to forward to PostgreSQL. If you change this, set
+    /// `FALCONERI_PROXY_POSTGRES_PORT` to match.
+    #[arg(long = "postgres-port", default_value_t = POSTGRES_PORT)]
+    postgres_port: u16,
+}
+
 /// A single port-forward connection with automatic reconnection.
 struct PortForward {
     /// Kubernetes resource (e.g., "svc/falconeri-postgres").
     service: String,
-    /// Port mapping (e.g., "5432:5432").
-    port_mapping: String,
+    /// The port to listen on locally.
+    local_port: u16,
+    /// The port to forward to on `service`.
+    remote_port: u16,
     /// Human-readable name for logging.
     name: String,
+    /// The command-line option which changes `local_port`, if any.
+    port_option: Option<&'static str>,
 }
 
 impl PortForward {
-    fn new(service: &str, port_mapping: &str, name: &str) -> Self {
+    fn new(service: &str, local_port: u16, remote_port: u16, name: &str) -> Self {
         Self {
             service: service.to_owned(),
-            port_mapping: port_mapping.to_owned(),
+            local_port,
+            remote_port,
             name: name.to_owned(),
+            port_option: None,
+        }
+    }
+
+    /// Mention `option` in error messages about our local port.
+    fn with_port_option(mut self, option: &'static str) -> Self {
+        self.port_option = Some(option);
+        self
+    }
+
+    /// Print a status line for this port-forward.
+    fn status(&self, status: &str) {
+        println!(
+            "{:<14} localhost:{:<5} -> {}:{}  {}",
+            self.name, self.local_port, self.service, self.remote_port, status
+        );
+    }
+
+    /// Make sure nothing else is listening on our local port. Otherwise,
+    /// `kubectl` would fail in a way that's easy to miss.
+    fn check_local_port(&self) -> Result<()> {
+        match TcpListener::bind(("127.0.0.1", self.local_port)) {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                let hint = self
+                    .port_option
+                    .map(|option| format!(" (use {} to choose another port)", option))
+                    .unwrap_or_default();
+                Err(err).with_context(|| {
+                    format!(
+                        "cannot forward {}, because localhost:{} is already in use{}",
+                        self.name, self.local_port, hint
+                    )
+                })
+            }
         }
     }
 
@@ -45,7 +112,7 @@ impl PortForward {
 
         loop {
             let start = Instant::now();
-            info!("Starting port-forward for {}", self.name);
+            self.status("connecting");
 
             match self.run_once(&mut shutdown_rx).await {
                 Ok(ExitReason::ShutdownRequested) => {
@@ -56,10 +123,19 @@ impl PortForward {
                     if start.elapsed() > STABLE_THRESHOLD {
                         backoff = INITIAL_BACKOFF;
                     }
-                    warn!("{} disconnected, reconnecting in {:?}", self.name, backoff);
+                    self.status(&format!(
+                        "disconnected, reconnecting in {:?}",
+                        backoff
+                    ));
                 }
                 Err(e) => {
-                    warn!("{} error: {:#}, retrying in {:?}", self.name, e, backoff);
+                    if start.elapsed() > STABLE_THRESHOLD {
+                        backoff = INITIAL_BACKOFF;
+                    }
+                    self.status(&format!(
+                        "failed: {:#}, retrying in {:?}",
+                        e, backoff
+                    ));
                 }
             }
 
@@ -82,26 +158,55 @@ impl PortForward {
         &self,
         shutdown_rx: &mut broadcast::Receiver<()>,
     ) -> Result<ExitReason> {
-        let mut child: Child = Command::new("kubectl")
-            .args(["port-forward", &self.service, &self.port_mapping])
+        // Something else may have grabbed our port while we were
+        // disconnected.
+        self.check_local_port()?;
+
+        let port_mapping = format!("{}:{}", self.local_port, self.remote_port);
+        let mut child: Child = kubernetes::kubectl_command()
+            .args(["port-forward", &self.service, &port_mapping])
+            .stdout(Stdio::piped())
             .kill_on_drop(true)
             .spawn()
             .with_context(|| {
                 format!("failed to start port-forward for {}", self.name)
             })?;
 
-        tokio::select! {
-            status = child.wait() => {
-                let status = status.context("failed to wait for kubectl")?;
-                if status.success() {
-                    Ok(ExitReason::ProcessExited)
-                } else {
-                    Err(format_err!("kubectl exited with status: {}", status))
+        // `kubectl` prints "Forwarding from ..." once it's listening, and then
+        // a line for every connection, which we don't want to show.
+        let stdout = child
+            .stdout
+            .take()
+            .ok_or_else(|| format_err!("kubectl has no stdout"))?;
+        let mut lines = BufReader::new(stdout).lines();
+        let mut stdout_open = true;
+        let mut ready = false;
+
+        loop {
+            tokio::select! {
+                line = lines.next_line(), if stdout_open => match line {
+                    Ok(Some(line)) => {
+                        if !ready && line.starts_with("Forwarding from") {
+                            ready = true;
+                            self.status("ready");
+                        } else {
+                            trace!("kubectl: {}", line);
+                        }
+                    }
+                    _ => stdout_open = false,
+                },
+                status = child.wait() => {
+                    let status = status.context("failed to wait for kubectl")?;
+                    return if status.success() {
+                        Ok(ExitReason::ProcessExited)
+                    } else {
+                        Err(format_err!("kubectl exited with status: {}", status))
+                    };
+                }
+                _ = shutdown_rx.recv() => {
+                    // Child will be killed on drop due to kill_on_drop(true).
+                    return Ok(ExitReason::ShutdownRequested);
                 }
-            }
-            _ = shutdown_rx.recv() => {
-                // Child will be killed on drop due to kill_on_drop(true).
-                Ok(ExitReason::ShutdownRequested)
             }
         }
     }
@@ -117,27 +222,61 @@ enum ExitReason {
 
 /// Run the proxy command.
 #[instrument(level = "trace")]
-pub async fn run() -> Result<()> {
+pub async fn run(opt: &Opt) -> Result<()> {
     // Build list of port-forwards to create.
-    let mut forwards = vec![
-        PortForward::new("svc/falconeri-postgres", "5432:5432", "postgres"),
-        PortForward::new("svc/falconerid", "8089:8089", "falconerid"),
-    ];
+    let mut forwards =
+        vec![
+            PortForward::new("svc/falconerid", opt.api_port, API_PORT, "falconerid")
+                .with_port_option("--api-port"),
+        ];
+
+    // Check for our own PostgreSQL, which won't exist if we're using an
+    // externally-managed database.
+    if kubernetes::resource_exists("svc/falconeri-postgres").await? {
+        forwards.push(
+            PortForward::new(
+                "svc/falconeri-postgres",
+                opt.postgres_port,
+                POSTGRES_PORT,
+                "postgres",
+            )
+            .with_port_option("--postgres-port"),
+        );
+    } else {
+        warn!("no svc/falconeri-postgres, so set DATABASE_URL to access the database");
+    }
 
     // Check for optional MinIO.
     if kubernetes::resource_exists("svc/falconeri-minio").await? {
         forwards.push(PortForward::new(
             "svc/falconeri-minio",
-            "9000:9000",
+            9000,
+            9000,
             "minio-api",
         ));
         forwards.push(PortForward::new(
             "svc/falconeri-minio",
-            "9001:9001",
+            9001,
+            9001,
             "minio-console",
         ));
     }
 
+    // Fail now if any of our ports are taken, instead of retrying forever.
+    check_local_ports(&forwards)?;
+    if opt.api_port != API_PORT {
+        println!(
+            "Set FALCONERID_URL=http://localhost:{}/ to use this proxy.",
+            opt.api_port
+        );
+    }
+    if opt.postgres_port != POSTGRES_PORT {
+        println!(
+            "Set FALCONERI_PROXY_POSTGRES_PORT={} to use this proxy.",
+            opt.postgres_port
+        );
+    }
+
     info!(
         "Starting proxy for {} service(s): {}",
         forwards.len(),
@@ -178,6 +317,22 @@ pub async fn run() -> Result<()> {
     Ok(())
 }
 
+/// Make sure that `forwards` use different local ports, and that nothing else
+/// is listening on them.
+fn check_local_ports(forwards: &[PortForward]) -> Result<()> {
+    let mut seen = HashSet::new();
+    for forward in forwards {
+        if !seen.insert(forward.local_port) {
+            return Err(format_err!(
+                "cannot forward more than one service to localhost:{}",
+                forward.local_port
+            ));
+        }
+        forward.check_local_port()?;
+    }
+    Ok(())
+}
+
 /// Wait for a shutdown signal (Ctrl-C or SIGTERM).
 async fn shutdown_signal() {
     use tokio::signal;
@@ -208,3 +363,22 @@ async fn shutdown_signal() {
         }
     }
 }
+
+#[test]
+fn check_local_ports_detects_conflicts() {
+    // Hold a port, so that nobody else can use it.
+    let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+    let taken = listener.local_addr().unwrap().port();
+    let forward = PortForward::new("svc/falconerid", taken, API_PORT, "falconerid")
+        .with_port_option("--api-port");
+    let err = check_local_ports(&[forward]).unwrap_err();
+    assert!(format!("{:#}", err).contains("--api-port"));
+
+    // Duplicate ports.
+    drop(listener);
+    let forwards = [
+        PortForward::new("svc/falconerid", taken, API_PORT, "falconerid"),
+        PortForward::new("svc/falconeri-postgres", taken, POSTGRES_PORT, "postgres"),
+    ];
+    assert!(check_local_ports(&forwards).is_err());
+}
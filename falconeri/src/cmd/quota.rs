@@ -0,0 +1,102 @@
+//! The `quota` subcommand, for limiting how much of the cluster each team may
+//! use.
+
+use clap::Subcommand;
+use falconeri_common::{
+    prelude::*,
+    rest_api::{Client, SetQuotaRequest},
+};
+use prettytable::{format::consts::FORMAT_CLEAN, row, Table};
+
+use crate::output::OutputFormat;
+
+/// Commands for managing team quotas.
+#[derive(Debug, Subcommand)]
+pub enum Opt {
+    /// Create or replace a team's quota. Limits which aren't specified are
+    /// unlimited.
+    #[command(name = "set")]
+    Set {
+        /// The team to limit, as specified by `team` in pipeline specs.
+        team: String,
+
+        /// The maximum number of jobs which may be creating, running or
+        /// streaming at once.
+        #[arg(long = "max-running-jobs")]
+        max_running_jobs: Option<i32>,
+
+        /// The maximum total parallelism of the team's running jobs.
+        #[arg(long = "max-parallelism")]
+        max_parallelism: Option<i32>,
+    },
+
+    /// List all team quotas.
+    #[command(name = "list")]
+    List,
+
+    /// Delete a team's quota, so that its jobs are no longer limited.
+    #[command(name = "delete")]
+    Delete {
+        /// The team whose quota should be deleted.
+        team: String,
+    },
+}
+
+/// Run the `quota` subcommand.
+#[instrument(skip_all, level = "trace")]
+pub async fn run(opt: &Opt, output: OutputFormat) -> Result<()> {
+    match opt {
+        Opt::Set {
+            team,
+            max_running_jobs,
+            max_parallelism,
+        } => {
+            let client = Client::new(ConnectVia::Proxy).await?;
+            let request = SetQuotaRequest {
+                max_running_jobs: *max_running_jobs,
+                max_parallelism: *max_parallelism,
+            };
+            let quota = client.set_quota(team, &request).await?;
+            println!("{}", quota.team);
+            Ok(())
+        }
+        Opt::List => run_list(output).await,
+        Opt::Delete { team } => {
+            let client = Client::new(ConnectVia::Proxy).await?;
+            client.delete_quota(team).await
+        }
+    }
+}
+
+/// List team quotas.
+#[instrument(level = "trace")]
+async fn run_list(output: OutputFormat) -> Result<()> {
+    let client = Client::new(ConnectVia::Proxy).await?;
+    let quotas = client.list_quotas().await?;
+    output.print(&quotas, |quotas| {
+        print_table(quotas);
+        Ok(())
+    })
+}
+
+/// Print `quotas` as a table.
+fn print_table(quotas: &[Quota]) {
+    let mut table = Table::new();
+    table.set_format(*FORMAT_CLEAN);
+    table.add_row(row!["TEAM", "MAX_RUNNING_JOBS", "MAX_PARALLELISM"]);
+    for quota in quotas {
+        table.add_row(row![
+            &quota.team,
+            optional_limit(quota.max_running_jobs),
+            optional_limit(quota.max_parallelism),
+        ]);
+    }
+    table.printstd();
+}
+
+/// Format an optional limit for a table.
+fn optional_limit(limit: Option<i32>) -> String {
+    limit
+        .map(|limit| limit.to_string())
+        .unwrap_or_else(|| "unlimited".to_owned())
+}
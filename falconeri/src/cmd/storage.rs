@@ -0,0 +1,205 @@
+//! The `storage` subcommand, for testing our storage backends by hand.
+
+use clap::Subcommand;
+use falconeri_common::{
+    pipeline::PipelineSpec,
+    prelude::*,
+    secret::Secret,
+    serde_json,
+    storage::{CloudStorage, ListedObject},
+    tokio,
+};
+use prettytable::{format::consts::FORMAT_CLEAN, row, Table};
+
+use crate::output::OutputFormat;
+
+/// How big a buffer should we use when copying between two buckets?
+const COPY_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Commands for accessing cloud storage using falconeri's own backends and
+/// credentials.
+#[derive(Debug, Subcommand)]
+pub enum Opt {
+    /// List the files and subdirectories in a URI.
+    #[command(name = "ls")]
+    Ls {
+        /// Use the secrets in this pipeline spec, as `falconerid` would.
+        #[arg(long = "spec")]
+        spec: Option<PathBuf>,
+
+        /// The URI to list, like `gs://bucket/dir/`.
+        uri: String,
+    },
+
+    /// Copy a file or directory. Either side may be a local path or a URI, but
+    /// directories may only be copied to or from local paths. Directory
+    /// sources and destinations must end with `/`.
+    #[command(name = "cp")]
+    Cp {
+        /// Use the secrets in this pipeline spec, as `falconerid` would.
+        #[arg(long = "spec")]
+        spec: Option<PathBuf>,
+
+        /// The file or directory to copy.
+        src: String,
+
+        /// Where to copy it.
+        dst: String,
+    },
+}
+
+/// Run the `storage` subcommand.
+#[instrument(skip_all, level = "trace")]
+pub async fn run(opt: &Opt, output: OutputFormat) -> Result<()> {
+    match opt {
+        Opt::Ls { spec, uri } => {
+            run_ls(&load_secrets(spec.as_deref())?, uri, output).await
+        }
+        Opt::Cp { spec, src, dst } => {
+            run_cp(&load_secrets(spec.as_deref())?, src, dst).await
+        }
+    }
+}
+
+/// Load the secrets from the pipeline spec at `path`, if any.
+fn load_secrets(path: Option<&Path>) -> Result<Vec<Secret>> {
+    let Some(path) = path else {
+        return Ok(vec![]);
+    };
+    let f = File::open(path).context("can't open pipeline JSON file")?;
+    let pipeline_spec: PipelineSpec =
+        serde_json::from_reader(f).context("can't parse pipeline JSON file")?;
+    Ok(pipeline_spec.transform.secrets)
+}
+
+/// List the contents of `uri`.
+#[instrument(skip(secrets), level = "debug")]
+async fn run_ls(secrets: &[Secret], uri: &str, output: OutputFormat) -> Result<()> {
+    let storage = <dyn CloudStorage>::for_uri(uri, secrets).await?;
+    let objects = storage.list_objects(uri).await?;
+    output.print(&objects, |objects| {
+        print_table(objects);
+        Ok(())
+    })
+}
+
+/// Print `objects` as a table.
+fn print_table(objects: &[ListedObject]) {
+    let mut table = Table::new();
+    table.set_format(*FORMAT_CLEAN);
+    table.add_row(row!["URI", "ETAG", "GENERATION"]);
+    for object in objects {
+        table.add_row(row![
+            &object.uri,
+            object.etag.as_deref().unwrap_or("-"),
+            object.generation.as_deref().unwrap_or("-"),
+        ]);
+    }
+    table.printstd();
+}
+
+/// The different kinds of copies we support.
+#[derive(Debug, PartialEq, Eq)]
+enum CopyKind {
+    /// Download a file or directory.
+    Download,
+    /// Upload a local directory.
+    UploadDir,
+    /// Upload a local file.
+    UploadFile,
+    /// Copy a file from one URI to another.
+    Transfer,
+}
+
+/// Is `path` a URI, instead of a local path?
+fn is_uri(path: &str) -> bool {
+    path.contains("://")
+}
+
+/// Figure out how to copy `src` to `dst`.
+fn plan_copy(src: &str, dst: &str) -> Result<CopyKind> {
+    if src.ends_with('/') != dst.ends_with('/') {
+        return Err(format_err!(
+            "either both or neither of {:?} and {:?} should end with '/'",
+            src,
+            dst,
+        ));
+    }
+    let is_dir = src.ends_with('/');
+    match (is_uri(src), is_uri(dst)) {
+        (true, false) => Ok(CopyKind::Download),
+        (false, true) if is_dir => Ok(CopyKind::UploadDir),
+        (false, true) => Ok(CopyKind::UploadFile),
+        (true, true) if is_dir => Err(format_err!(
+            "cannot copy directories between two URIs, only files"
+        )),
+        (true, true) => Ok(CopyKind::Transfer),
+        (false, false) => {
+            Err(format_err!("either {:?} or {:?} should be a URI", src, dst))
+        }
+    }
+}
+
+/// Copy `src` to `dst`.
+#[instrument(skip(secrets), level = "debug")]
+async fn run_cp(secrets: &[Secret], src: &str, dst: &str) -> Result<()> {
+    match plan_copy(src, dst)? {
+        CopyKind::Download => {
+            let storage = <dyn CloudStorage>::for_uri(src, secrets).await?;
+            storage.sync_down(src, Path::new(dst)).await?;
+        }
+        CopyKind::UploadDir => {
+            let storage = <dyn CloudStorage>::for_uri(dst, secrets).await?;
+            storage.sync_up(Path::new(src), dst).await?;
+        }
+        CopyKind::UploadFile => {
+            let storage = <dyn CloudStorage>::for_uri(dst, secrets).await?;
+            let mut file = tokio::fs::File::open(src)
+                .await
+                .with_context(|| format!("cannot open {}", src))?;
+            storage.upload_from_reader(&mut file, dst).await?;
+        }
+        CopyKind::Transfer => {
+            let src_storage = <dyn CloudStorage>::for_uri(src, secrets).await?;
+            let dst_storage = <dyn CloudStorage>::for_uri(dst, secrets).await?;
+            let (writer, mut reader) = tokio::io::duplex(COPY_BUFFER_SIZE);
+            let download = async {
+                // Close our end of the pipe when we're done, so that the
+                // upload sees the end of the file.
+                let mut writer = writer;
+                src_storage.download_to_writer(src, &mut writer).await
+            };
+            let upload = dst_storage.upload_from_reader(&mut reader, dst);
+            tokio::try_join!(download, upload)?;
+        }
+    }
+    println!("Copied {} to {}", src, dst);
+    Ok(())
+}
+
+#[test]
+fn plan_copy_checks_paths() {
+    assert_eq!(
+        plan_copy("gs://b/a.txt", "a.txt").unwrap(),
+        CopyKind::Download
+    );
+    assert_eq!(
+        plan_copy("gs://b/dir/", "dir/").unwrap(),
+        CopyKind::Download
+    );
+    assert_eq!(
+        plan_copy("dir/", "s3://b/dir/").unwrap(),
+        CopyKind::UploadDir
+    );
+    assert_eq!(
+        plan_copy("a.txt", "s3://b/a.txt").unwrap(),
+        CopyKind::UploadFile
+    );
+    assert_eq!(
+        plan_copy("gs://b/a", "s3://b/a").unwrap(),
+        CopyKind::Transfer
+    );
+    assert!(plan_copy("gs://b/dir/", "s3://b/dir/").is_err());
+    assert!(plan_copy("gs://b/dir/", "dir").is_err());
+    assert!(plan_copy("a.txt", "b.txt").is_err());
+}
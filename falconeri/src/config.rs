@@ -0,0 +1,178 @@
+//! The `falconeri` configuration file.
+//!
+//! This lives at `~/.config/falconeri/config.toml` by default, and describes
+//! the clusters we can talk to:
+//!
+//! ```toml
+//! current_context = "staging"
+//!
+//! [contexts.staging]
+//! kube_context = "gke_example_us-central1_staging"
+//! namespace = "falconeri"
+//!
+//! [contexts.prod]
+//! kube_context = "gke_example_us-central1_prod"
+//! falconerid_url = "http://localhost:18089/"
+//!
+//! [contexts.internal]
+//! falconerid_url = "https://falconeri.internal/"
+//! token = "..."
+//! ```
+
+use std::{collections::BTreeMap, env, fs, io};
+
+use falconeri_common::{connect_via::ProxySettings, prelude::*};
+
+/// Environment variable which overrides the location of our config file.
+const CONFIG_PATH_VAR: &str = "FALCONERI_CONFIG";
+
+/// Our configuration file.
+#[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The context to use when `--context` isn't specified.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub current_context: Option<String>,
+    /// Our contexts, by name.
+    #[serde(default)]
+    pub contexts: BTreeMap<String, Context>,
+}
+
+/// A cluster which we can talk to.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Context {
+    /// The `kubectl` context to use.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kube_context: Option<String>,
+    /// The Kubernetes namespace where falconeri is deployed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    /// The URL of `falconerid`, if it isn't `http://localhost:8089/`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub falconerid_url: Option<String>,
+    /// A bearer token to send to `falconerid`, if we shouldn't use the
+    /// password from the cluster's `falconeri` secret.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+impl Context {
+    /// The settings which `falconeri_common` should use to connect to this
+    /// context.
+    pub fn proxy_settings(&self) -> ProxySettings {
+        ProxySettings {
+            kube_context: self.kube_context.clone(),
+            namespace: self.namespace.clone(),
+            falconerid_url: self.falconerid_url.clone(),
+            token: self.token.clone(),
+        }
+    }
+}
+
+impl Config {
+    /// Where our config file lives. This is `$FALCONERI_CONFIG` if set, or
+    /// `config.toml` in `$XDG_CONFIG_HOME/falconeri` or
+    /// `~/.config/falconeri`.
+    pub fn path() -> Result<PathBuf> {
+        if let Some(path) = env::var_os(CONFIG_PATH_VAR) {
+            return Ok(PathBuf::from(path));
+        }
+        let config_dir = match env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => {
+                let home = env::var_os("HOME").ok_or_else(|| {
+                    format_err!("cannot find config file, because $HOME is not set")
+                })?;
+                PathBuf::from(home).join(".config")
+            }
+        };
+        Ok(config_dir.join("falconeri").join("config.toml"))
+    }
+
+    /// Load our config file from `path`. If it doesn't exist, return an empty
+    /// config.
+    pub fn load(path: &Path) -> Result<Config> {
+        match fs::read_to_string(path) {
+            Ok(text) => Config::parse(&text)
+                .with_context(|| format!("could not parse {}", path.display())),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(err) => {
+                Err(err).with_context(|| format!("could not read {}", path.display()))
+            }
+        }
+    }
+
+    /// Parse a config file.
+    fn parse(text: &str) -> Result<Config> {
+        let config = toml::from_str::<Config>(text)?;
+        if let Some(name) = &config.current_context {
+            config.context(name)?;
+        }
+        Ok(config)
+    }
+
+    /// Save our config file to `path`. Note that this doesn't preserve
+    /// comments.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let text =
+            toml::to_string_pretty(self).context("could not serialize config")?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("could not create {}", dir.display()))?;
+        }
+        fs::write(path, text)
+            .with_context(|| format!("could not write {}", path.display()))
+    }
+
+    /// Look up the context named `name`.
+    pub fn context(&self, name: &str) -> Result<&Context> {
+        self.contexts.get(name).ok_or_else(|| {
+            format_err!("no context named {:?} in falconeri config file", name)
+        })
+    }
+
+    /// Choose the context to use. This is `name` if specified, or our current
+    /// context. If neither is set, we use `kubectl`'s own current context.
+    pub fn choose_context(&self, name: Option<&str>) -> Result<Option<&Context>> {
+        name.or(self.current_context.as_deref())
+            .map(|name| self.context(name))
+            .transpose()
+    }
+}
+
+#[test]
+fn parse_config_and_choose_context() {
+    let config = Config::parse(
+        r#"
+current_context = "staging"
+
+[contexts.staging]
+kube_context = "gke_staging"
+namespace = "falconeri"
+
+[contexts.prod]
+kube_context = "gke_prod"
+falconerid_url = "http://localhost:18089/"
+"#,
+    )
+    .unwrap();
+    let staging = config.choose_context(None).unwrap().unwrap();
+    assert_eq!(staging.kube_context.as_deref(), Some("gke_staging"));
+    assert_eq!(staging.namespace.as_deref(), Some("falconeri"));
+    let prod = config.choose_context(Some("prod")).unwrap().unwrap();
+    assert_eq!(
+        prod.proxy_settings().falconerid_url.as_deref(),
+        Some("http://localhost:18089/")
+    );
+    assert!(config.choose_context(Some("dev")).is_err());
+    assert!(Config::default().choose_context(None).unwrap().is_none());
+
+    // Round trip.
+    let text = toml::to_string_pretty(&config).unwrap();
+    assert_eq!(Config::parse(&text).unwrap(), config);
+
+    // Errors.
+    assert!(Config::parse("current_context = \"missing\"").is_err());
+    assert!(Config::parse("[contexts.prod]\nkube_contxt = \"typo\"").is_err());
+}
@@ -1,6 +1,7 @@
 //! Human-readable descriptions of an object.
 
-use falconeri_common::{handlebars::Handlebars, prelude::*};
+use falconeri_common::{handlebars::Handlebars, prelude::*, serde_json};
+use serde::de::DeserializeOwned;
 
 /// Render the specified textual description, filling in the supplied values
 /// using [Handlebars][].
@@ -11,3 +12,13 @@ pub fn render_description<T: Serialize>(template: &str, params: &T) -> Result<St
     handlebars.set_strict_mode(true);
     Ok(handlebars.render_template(template, &params)?)
 }
+
+/// Load the parameters for a description from a JSON file exported from the
+/// corresponding `describe` REST endpoint. This allows us to inspect jobs
+/// after they've been removed from the cluster.
+pub fn read_exported_description<T: DeserializeOwned>(path: &Path) -> Result<T> {
+    let f = File::open(path)
+        .with_context(|| format!("can't open exported file {}", path.display()))?;
+    serde_json::from_reader(f)
+        .with_context(|| format!("can't parse exported file {}", path.display()))
+}
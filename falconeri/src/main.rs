@@ -1,15 +1,57 @@
 #![deny(unsafe_code)]
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use falconeri_common::{prelude::*, tracing_support::initialize_tracing};
 
+use crate::{config::Config, output::OutputFormat};
+
 mod cmd;
+mod config;
 mod description;
+mod output;
 
 /// Command-line options, parsed using `clap`.
 #[derive(Debug, Parser)]
 #[command(about = "A tool for running batch jobs on Kubernetes.")]
-enum Opt {
+struct Opt {
+    /// How to format output. `json` and `yaml` print the same data as the
+    /// REST API.
+    #[arg(
+        short = 'o',
+        long = "output",
+        global = true,
+        value_enum,
+        default_value_t = OutputFormat::Table
+    )]
+    output: OutputFormat,
+
+    /// Use this context from `~/.config/falconeri/config.toml`, instead of
+    /// the current context.
+    #[arg(long = "context", global = true)]
+    context: Option<String>,
+
+    #[command(subcommand)]
+    cmd: Command,
+}
+
+/// Our subcommands.
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Commands for managing tokens which can be used instead of the admin
+    /// password.
+    #[command(name = "api-token")]
+    ApiToken {
+        #[command(subcommand)]
+        cmd: cmd::api_token::Opt,
+    },
+
+    /// Commands for switching between clusters.
+    #[command(name = "context")]
+    Context {
+        #[command(subcommand)]
+        cmd: cmd::context::Opt,
+    },
+
     /// Datum-related commands.
     #[command(name = "datum")]
     Datum {
@@ -25,10 +67,20 @@ enum Opt {
     },
 
     /// Deploy falconeri onto the current Docker cluster.
-    #[command(name = "deploy")]
+    #[command(name = "deploy", args_conflicts_with_subcommands = true)]
     Deploy {
         #[command(flatten)]
         cmd: Box<cmd::deploy::Opt>,
+
+        #[command(subcommand)]
+        subcmd: Option<cmd::deploy::DeployCmd>,
+    },
+
+    /// Helpers for development clusters.
+    #[command(name = "dev")]
+    Dev {
+        #[command(subcommand)]
+        cmd: cmd::dev::Opt,
     },
 
     /// Job-related commands.
@@ -38,18 +90,49 @@ enum Opt {
         cmd: cmd::job::Opt,
     },
 
+    /// Run pipelines on this machine, without a cluster.
+    #[command(name = "local")]
+    Local {
+        #[command(subcommand)]
+        cmd: cmd::local::Opt,
+    },
+
     /// Manaually migrate falconeri's database schema to the latest version.
     #[command(name = "migrate")]
     Migrate,
 
+    /// Commands for managing registered and scheduled pipelines.
+    #[command(name = "pipeline")]
+    Pipeline {
+        #[command(subcommand)]
+        cmd: cmd::pipeline::Opt,
+    },
+
+    /// Commands for limiting how much of the cluster each team may use.
+    #[command(name = "quota")]
+    Quota {
+        #[command(subcommand)]
+        cmd: cmd::quota::Opt,
+    },
+
     /// Create a proxy connection to the default Kubernetes cluster.
     #[command(name = "proxy")]
-    Proxy,
+    Proxy {
+        #[command(flatten)]
+        cmd: cmd::proxy::Opt,
+    },
 
     /// Output the JSON Schema for pipeline specification files.
     #[command(name = "schema")]
     Schema,
 
+    /// Access cloud storage using falconeri's own storage backends.
+    #[command(name = "storage")]
+    Storage {
+        #[command(subcommand)]
+        cmd: cmd::storage::Opt,
+    },
+
     /// Undeploy `falconeri`, removing it from the cluster.
     #[command(name = "undeploy")]
     Undeploy {
@@ -65,14 +148,42 @@ async fn main() -> Result<()> {
     let opt = Opt::parse();
     debug!("Args: {:?}", opt);
 
-    match opt {
-        Opt::Datum { ref cmd } => cmd::datum::run(cmd).await,
-        Opt::Db { ref cmd } => cmd::db::run(cmd).await,
-        Opt::Deploy { ref cmd } => cmd::deploy::run(cmd).await,
-        Opt::Job { ref cmd } => cmd::job::run(cmd).await,
-        Opt::Migrate => cmd::migrate::run().await,
-        Opt::Proxy => cmd::proxy::run().await,
-        Opt::Schema => cmd::schema::run(),
-        Opt::Undeploy { all } => cmd::deploy::run_undeploy(all).await,
+    let Opt {
+        output,
+        context,
+        cmd: command,
+    } = opt;
+    // Choose which cluster to talk to, unless we're managing contexts.
+    // `FALCONERID_URL` and `FALCONERID_TOKEN` override the chosen context.
+    if !matches!(command, Command::Context { .. }) {
+        let config = Config::load(&Config::path()?)?;
+        config
+            .choose_context(context.as_deref())?
+            .map(|context| context.proxy_settings())
+            .unwrap_or_default()
+            .with_env_overrides()
+            .install()?;
+    }
+
+    match command {
+        Command::ApiToken { ref cmd } => cmd::api_token::run(cmd, output).await,
+        Command::Context { ref cmd } => cmd::context::run(cmd).await,
+        Command::Datum { ref cmd } => cmd::datum::run(cmd, output).await,
+        Command::Db { ref cmd } => cmd::db::run(cmd, output).await,
+        Command::Deploy {
+            subcmd: Some(ref subcmd),
+            ..
+        } => cmd::deploy::run_subcommand(subcmd).await,
+        Command::Deploy { ref cmd, .. } => cmd::deploy::run(cmd).await,
+        Command::Dev { ref cmd } => cmd::dev::run(cmd).await,
+        Command::Job { ref cmd } => cmd::job::run(cmd, output).await,
+        Command::Local { ref cmd } => cmd::local::run(cmd).await,
+        Command::Migrate => cmd::migrate::run().await,
+        Command::Pipeline { ref cmd } => cmd::pipeline::run(cmd, output).await,
+        Command::Proxy { ref cmd } => cmd::proxy::run(cmd).await,
+        Command::Quota { ref cmd } => cmd::quota::run(cmd, output).await,
+        Command::Schema => cmd::schema::run(),
+        Command::Storage { ref cmd } => cmd::storage::run(cmd, output).await,
+        Command::Undeploy { all } => cmd::deploy::run_undeploy(all).await,
     }
 }
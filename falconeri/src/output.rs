@@ -0,0 +1,67 @@
+//! Machine-readable output.
+//!
+//! Our tables and descriptions are meant for humans, and we change them
+//! whenever we like. Scripts should pass `--output json` or `--output yaml`
+//! instead, which prints the same structures returned by the REST API.
+
+use clap::ValueEnum;
+use falconeri_common::{prelude::*, serde_json};
+
+/// How to format the output of a command.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human-readable tables and descriptions.
+    #[default]
+    Table,
+    /// Pretty-printed JSON.
+    Json,
+    /// YAML.
+    Yaml,
+}
+
+impl OutputFormat {
+    /// Print `value` as JSON or YAML. If we're printing tables, call
+    /// `print_table` to do it instead.
+    pub fn print<T: Serialize>(
+        self,
+        value: &T,
+        print_table: impl FnOnce(&T) -> Result<()>,
+    ) -> Result<()> {
+        match self {
+            OutputFormat::Table => print_table(value),
+            OutputFormat::Json | OutputFormat::Yaml => {
+                print!("{}", self.format_structured(value)?);
+                Ok(())
+            }
+        }
+    }
+
+    /// Format `value` as JSON or YAML, ending with a newline.
+    fn format_structured<T: Serialize>(self, value: &T) -> Result<String> {
+        match self {
+            OutputFormat::Table => {
+                Err(format_err!("cannot format structured data as a table"))
+            }
+            OutputFormat::Json => {
+                let mut json = serde_json::to_string_pretty(value)
+                    .context("could not serialize output as JSON")?;
+                json.push('\n');
+                Ok(json)
+            }
+            OutputFormat::Yaml => serde_yaml::to_string(value)
+                .context("could not serialize output as YAML"),
+        }
+    }
+}
+
+#[test]
+fn format_structured_output() {
+    let mut job = Job::factory();
+    job.job_name = "my-job".to_owned();
+    let json = OutputFormat::Json.format_structured(&[&job]).unwrap();
+    let parsed: Vec<Job> = serde_json::from_str(&json).unwrap();
+    assert_eq!(parsed[0].job_name, "my-job");
+    let yaml = OutputFormat::Yaml.format_structured(&job).unwrap();
+    assert!(yaml.contains("job_name: my-job\n"));
+    assert!(OutputFormat::Table.format_structured(&job).is_err());
+}
@@ -0,0 +1,113 @@
+//! Compare `insert_all` with `copy_in` when creating datums and input files.
+//!
+//! This needs a database, so run `falconeri proxy` first (or set
+//! `DATABASE_URL`), and then:
+//!
+//! ```sh
+//! cargo run --release -p falconeri_common --example bulk_insert_benchmark -- 100000
+//! ```
+//!
+//! Everything happens inside a test transaction, so nothing is left behind.
+
+use std::{env, time::Instant};
+
+use falconeri_common::{
+    db, diesel_async::AsyncConnection, prelude::*, serde_json::json,
+};
+
+/// How many datums should we insert with each multi-row `INSERT`? Each input
+/// file uses 4 bind parameters, and PostgreSQL allows at most 65,535.
+const INSERT_BATCH_SIZE: usize = 10_000;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let datum_count = match env::args().nth(1) {
+        Some(count) => count.parse::<usize>().context("invalid datum count")?,
+        None => 100_000,
+    };
+
+    let mut conn = db::async_connect(ConnectVia::Proxy).await?;
+    conn.begin_test_transaction().await?;
+
+    for method in ["insert_all", "copy_in"] {
+        let job = NewJob {
+            id: Uuid::new_v4(),
+            status: Status::Creating,
+            pipeline_spec: json!({}),
+            job_name: format!("bulk-insert-benchmark-{}", method.replace('_', "-")),
+            command: vec!["true".to_owned()],
+            egress_uri: "gs://example-bucket/output/".to_owned(),
+            stop_on_first_error: false,
+            spec_hash: None,
+            max_inline_output_bytes: DEFAULT_MAX_INLINE_OUTPUT_BYTES,
+            output_log_uri: None,
+            autoscale_parallelism: false,
+            target_parallelism: None,
+            idempotency_key: None,
+            deferred_start: None,
+            parent_job_id: None,
+            team: None,
+            image_digest: None,
+            submitted_pipeline_spec: None,
+            retry_backoff_seconds: None,
+            max_failed_datums: None,
+            max_failed_datum_percent: None,
+            output_manifest_uri: None,
+        }
+        .insert(&mut conn)
+        .await?;
+        let (datums, input_files) = sample_rows(job.id, datum_count);
+
+        let started_at = Instant::now();
+        if method == "insert_all" {
+            for (datums, input_files) in datums
+                .chunks(INSERT_BATCH_SIZE)
+                .zip(input_files.chunks(INSERT_BATCH_SIZE))
+            {
+                NewDatum::insert_all(datums, &mut conn).await?;
+                NewInputFile::insert_all(input_files, &mut conn).await?;
+            }
+        } else {
+            NewDatum::copy_in(&datums, &mut conn).await?;
+            NewInputFile::copy_in(&input_files, &mut conn).await?;
+        }
+        let elapsed = started_at.elapsed();
+        println!(
+            "{}: {} datums in {:.2}s ({:.0} datums/s)",
+            method,
+            datum_count,
+            elapsed.as_secs_f64(),
+            datum_count as f64 / elapsed.as_secs_f64(),
+        );
+    }
+    Ok(())
+}
+
+/// Generate `count` datums for `job_id`, each with a single input file.
+fn sample_rows(job_id: Uuid, count: usize) -> (Vec<NewDatum>, Vec<NewInputFile>) {
+    let mut datums = Vec::with_capacity(count);
+    let mut input_files = Vec::with_capacity(count);
+    for i in 0..count {
+        let datum_id = Uuid::new_v4();
+        datums.push(NewDatum {
+            id: datum_id,
+            job_id,
+            maximum_allowed_run_count: 1,
+            status: Status::Ready,
+            input_hash: None,
+            listed_input_bytes: None,
+        });
+        input_files.push(NewInputFile {
+            datum_id,
+            uri: format!("gs://example-bucket/input/file-{:08}.csv", i),
+            local_path: format!("/pfs/input/file-{:08}.csv", i),
+            job_id,
+            etag: None,
+            generation: None,
+            decompress: false,
+            size: None,
+            last_modified: None,
+        });
+    }
+    (datums, input_files)
+}
@@ -0,0 +1,127 @@
+//! Simulate many workers reserving datums at once, the way they do when a
+//! large job starts.
+//!
+//! This needs a database, so run `falconeri proxy` first (or set
+//! `DATABASE_URL`), and then:
+//!
+//! ```sh
+//! cargo run --release -p falconeri_common --example reservation_load_test -- 500
+//! ```
+//!
+//! This creates a temporary job, and deletes it when done.
+
+use std::{collections::HashSet, env, time::Instant};
+
+use falconeri_common::{
+    db, futures_util::future, kubernetes, prelude::*, serde_json::json,
+};
+
+/// How many database connections to use. This matches the default
+/// `FALCONERID_POOL_SIZE`, so we see roughly the same contention as
+/// `falconerid` would.
+const POOL_SIZE: usize = 32;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let worker_count = match env::args().nth(1) {
+        Some(count) => count.parse::<usize>().context("invalid worker count")?,
+        None => 500,
+    };
+
+    let pool = db::async_pool(POOL_SIZE, ConnectVia::Proxy).await?;
+    let mut conn = pool.get().await.context("could not get connection")?;
+
+    // Create a job with one datum per worker.
+    let job = NewJob {
+        id: Uuid::new_v4(),
+        status: Status::Running,
+        pipeline_spec: json!({}),
+        job_name: format!("reservation-load-test-{}", kubernetes::resource_tag())
+            .to_lowercase(),
+        command: vec!["true".to_owned()],
+        egress_uri: "gs://example-bucket/output/".to_owned(),
+        stop_on_first_error: false,
+        spec_hash: None,
+        max_inline_output_bytes: DEFAULT_MAX_INLINE_OUTPUT_BYTES,
+        output_log_uri: None,
+        autoscale_parallelism: false,
+        target_parallelism: None,
+        idempotency_key: None,
+        deferred_start: None,
+        parent_job_id: None,
+        team: None,
+        image_digest: None,
+        submitted_pipeline_spec: None,
+        retry_backoff_seconds: None,
+        max_failed_datums: None,
+        max_failed_datum_percent: None,
+        output_manifest_uri: None,
+    }
+    .insert(&mut conn)
+    .await?;
+    let datums = (0..worker_count)
+        .map(|_| NewDatum {
+            id: Uuid::new_v4(),
+            job_id: job.id,
+            maximum_allowed_run_count: 1,
+            status: Status::Ready,
+            input_hash: None,
+            listed_input_bytes: None,
+        })
+        .collect::<Vec<_>>();
+    NewDatum::copy_in(&datums, &mut conn).await?;
+    drop(conn);
+
+    // Have every worker try to reserve a datum at the same time.
+    let started_at = Instant::now();
+    let reservations = future::join_all((0..worker_count).map(|i| {
+        let pool = pool.clone();
+        let job = &job;
+        async move {
+            let mut conn = pool.get().await.context("could not get connection")?;
+            let pod_name = format!("load-test-pod-{}", i);
+            job.reserve_next_datum("load-test-node", &pod_name, false, &mut conn)
+                .await
+        }
+    }))
+    .await;
+    let elapsed = started_at.elapsed();
+
+    // Make sure every worker got its own datum.
+    let mut reserved = HashSet::new();
+    let mut failures = 0;
+    for reservation in reservations {
+        match reservation {
+            Ok(Some((datum, _))) => {
+                if !reserved.insert(datum.id) {
+                    failures += 1;
+                    eprintln!("datum {} was reserved twice", datum.id);
+                }
+            }
+            Ok(None) => {
+                failures += 1;
+                eprintln!("a worker could not reserve a datum");
+            }
+            Err(err) => {
+                failures += 1;
+                eprintln!("error reserving datum: {:?}", err);
+            }
+        }
+    }
+    println!(
+        "{} reservations in {:.2}s ({:.0}/s), {} failures",
+        worker_count,
+        elapsed.as_secs_f64(),
+        worker_count as f64 / elapsed.as_secs_f64(),
+        failures,
+    );
+
+    // Clean up.
+    let mut conn = pool.get().await.context("could not get connection")?;
+    job.delete(&mut conn).await?;
+    if failures > 0 {
+        Err(format_err!("{} reservations failed", failures))
+    } else {
+        Ok(())
+    }
+}
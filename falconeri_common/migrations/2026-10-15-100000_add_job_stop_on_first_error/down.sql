@@ -0,0 +1 @@
+ALTER TABLE jobs DROP COLUMN stop_on_first_error;
@@ -0,0 +1,2 @@
+-- Should we stop the entire job as soon as one datum fails for good?
+ALTER TABLE jobs ADD COLUMN stop_on_first_error boolean NOT NULL DEFAULT false;
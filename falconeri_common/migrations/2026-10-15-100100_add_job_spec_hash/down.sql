@@ -0,0 +1,3 @@
+DROP INDEX jobs_spec_hash_status;
+
+ALTER TABLE jobs DROP COLUMN spec_hash;
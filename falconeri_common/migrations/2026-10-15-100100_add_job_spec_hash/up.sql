@@ -0,0 +1,5 @@
+-- A SHA-256 hash of the canonical JSON form of the submitted pipeline spec,
+-- used to detect duplicate submissions. This is NULL for older jobs.
+ALTER TABLE jobs ADD COLUMN spec_hash text;
+
+CREATE INDEX jobs_spec_hash_status ON jobs (spec_hash, status);
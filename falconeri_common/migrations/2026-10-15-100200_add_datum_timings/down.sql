@@ -0,0 +1,5 @@
+ALTER TABLE datums
+    DROP started_at,
+    DROP download_completed_at,
+    DROP command_completed_at,
+    DROP upload_completed_at;
@@ -0,0 +1,6 @@
+-- Timestamps reported by the worker for each phase of processing a datum.
+ALTER TABLE datums
+    ADD started_at timestamp,
+    ADD download_completed_at timestamp,
+    ADD command_completed_at timestamp,
+    ADD upload_completed_at timestamp;
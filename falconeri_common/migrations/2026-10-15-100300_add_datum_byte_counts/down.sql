@@ -0,0 +1,3 @@
+ALTER TABLE datums
+    DROP input_bytes,
+    DROP output_bytes;
@@ -0,0 +1,5 @@
+-- Bytes downloaded and uploaded while processing each datum, as reported by the
+-- worker.
+ALTER TABLE datums
+    ADD input_bytes bigint,
+    ADD output_bytes bigint;
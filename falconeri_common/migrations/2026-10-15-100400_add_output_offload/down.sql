@@ -0,0 +1,5 @@
+ALTER TABLE datums DROP output_uri;
+
+ALTER TABLE jobs
+    DROP max_inline_output_bytes,
+    DROP output_log_uri;
@@ -0,0 +1,7 @@
+-- Limit how much datum output we store inline, and remember where we put the
+-- full output when it's too large.
+ALTER TABLE jobs
+    ADD max_inline_output_bytes bigint NOT NULL DEFAULT 1048576,
+    ADD output_log_uri text;
+
+ALTER TABLE datums ADD output_uri text;
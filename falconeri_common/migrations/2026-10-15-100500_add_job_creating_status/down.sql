@@ -0,0 +1,7 @@
+ALTER TABLE jobs
+    DROP error_message,
+    DROP total_datum_count;
+
+-- PostgreSQL can't remove values from an enum type, so just make sure nothing
+-- uses 'creating' any more.
+UPDATE jobs SET status = 'error' WHERE status = 'creating';
@@ -0,0 +1,8 @@
+-- Jobs with many input files are created in the background, so we need a
+-- status for jobs which aren't ready to run yet, a place to record how many
+-- datums we expect, and a place to report errors.
+ALTER TYPE status ADD VALUE IF NOT EXISTS 'creating' BEFORE 'ready';
+
+ALTER TABLE jobs
+    ADD error_message text,
+    ADD total_datum_count bigint;
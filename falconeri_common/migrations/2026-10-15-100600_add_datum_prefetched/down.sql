@@ -0,0 +1,6 @@
+DROP INDEX one_running_datum_per_pod_name;
+CREATE UNIQUE INDEX one_running_datum_per_pod_name
+  ON datums (job_id, pod_name)
+  WHERE (status = 'running');
+
+ALTER TABLE datums DROP prefetched;
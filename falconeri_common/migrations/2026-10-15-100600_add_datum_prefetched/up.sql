@@ -0,0 +1,9 @@
+-- Workers may reserve one extra datum, so that they can download its inputs
+-- while they're still uploading the outputs of their current datum. Allow one
+-- running datum per pod, plus one prefetched datum.
+ALTER TABLE datums ADD prefetched boolean NOT NULL DEFAULT false;
+
+DROP INDEX one_running_datum_per_pod_name;
+CREATE UNIQUE INDEX one_running_datum_per_pod_name
+  ON datums (job_id, pod_name, prefetched)
+  WHERE (status = 'running');
@@ -0,0 +1,3 @@
+ALTER TABLE datums DROP failure_class;
+
+DROP TYPE failure_class;
@@ -0,0 +1,5 @@
+-- Workers may classify a failure as retryable or permanent, based on the
+-- command's exit code. Permanent failures are never re-run.
+CREATE TYPE failure_class AS ENUM ('retryable', 'permanent');
+
+ALTER TABLE datums ADD failure_class failure_class;
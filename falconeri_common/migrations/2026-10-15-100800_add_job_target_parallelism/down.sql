@@ -0,0 +1,3 @@
+ALTER TABLE jobs
+    DROP target_parallelism,
+    DROP autoscale_parallelism;
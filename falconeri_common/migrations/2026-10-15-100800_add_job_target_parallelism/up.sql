@@ -0,0 +1,5 @@
+-- Allow jobs to scale down their Kubernetes parallelism as they run out of
+-- datums.
+ALTER TABLE jobs
+    ADD autoscale_parallelism boolean NOT NULL DEFAULT false,
+    ADD target_parallelism integer;
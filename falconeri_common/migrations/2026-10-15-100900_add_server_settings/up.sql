@@ -0,0 +1,9 @@
+-- Settings which apply to the whole server, stored in a single row. These are
+-- shared by all copies of `falconerid`.
+CREATE TABLE server_settings (
+    id boolean PRIMARY KEY DEFAULT true CHECK (id),
+    updated_at timestamp NOT NULL DEFAULT now(),
+    reservations_paused boolean NOT NULL DEFAULT false
+);
+
+INSERT INTO server_settings DEFAULT VALUES;
@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS datums_error_message_trgm;
+DROP INDEX IF EXISTS jobs_error_message_trgm;
+DROP INDEX IF EXISTS jobs_job_name_trgm;
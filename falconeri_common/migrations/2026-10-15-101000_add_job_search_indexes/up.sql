@@ -0,0 +1,24 @@
+-- Trigram indices make `ILIKE '%...%'` searches over job names and error
+-- messages fast.
+--
+-- Creating `pg_trgm` needs privileges which managed databases may not give
+-- us. If we can't create it, we skip these indices, and searches still work,
+-- only more slowly. A database administrator can create the extension and
+-- the indices later; see "Using an external database" in the guide.
+DO $$
+BEGIN
+    CREATE EXTENSION IF NOT EXISTS pg_trgm;
+EXCEPTION WHEN insufficient_privilege THEN
+    RAISE WARNING 'could not create extension pg_trgm, so job search will not be indexed: %', SQLERRM;
+END
+$$;
+
+DO $$
+BEGIN
+    IF EXISTS (SELECT 1 FROM pg_extension WHERE extname = 'pg_trgm') THEN
+        CREATE INDEX jobs_job_name_trgm ON jobs USING gin (job_name gin_trgm_ops);
+        CREATE INDEX jobs_error_message_trgm ON jobs USING gin (error_message gin_trgm_ops);
+        CREATE INDEX datums_error_message_trgm ON datums USING gin (error_message gin_trgm_ops);
+    END IF;
+END
+$$;
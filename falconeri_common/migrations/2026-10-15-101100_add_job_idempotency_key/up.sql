@@ -0,0 +1,5 @@
+-- A client-supplied key which prevents the same job from being submitted
+-- twice. NULL values don't conflict with each other.
+ALTER TABLE jobs ADD COLUMN idempotency_key text;
+
+CREATE UNIQUE INDEX jobs_idempotency_key ON jobs (idempotency_key);
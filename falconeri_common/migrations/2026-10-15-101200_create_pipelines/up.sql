@@ -0,0 +1,15 @@
+-- Pipeline specs registered with the server, which may be run on a schedule.
+CREATE TABLE pipelines (
+    id uuid PRIMARY KEY DEFAULT uuid_generate_v4(),
+    created_at timestamp NOT NULL DEFAULT now(),
+    updated_at timestamp NOT NULL DEFAULT now(),
+    name text NOT NULL UNIQUE CHECK (name ~ '^[a-z0-9](?:[a-z0-9-]{0,61}[a-z0-9])?$'),
+    pipeline_spec jsonb NOT NULL,
+    -- A cron expression, interpreted in UTC.
+    schedule text,
+    next_run_at timestamp,
+    last_run_at timestamp,
+    last_job_id uuid REFERENCES jobs (id) ON DELETE SET NULL
+);
+
+CREATE INDEX pipelines_next_run_at ON pipelines (next_run_at);
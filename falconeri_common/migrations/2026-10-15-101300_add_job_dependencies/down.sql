@@ -0,0 +1,7 @@
+ALTER TABLE jobs DROP deferred_start;
+
+DROP TABLE job_dependencies;
+
+-- PostgreSQL can't remove values from an enum type, so just make sure nothing
+-- uses 'waiting' any more.
+UPDATE jobs SET status = 'error' WHERE status = 'waiting';
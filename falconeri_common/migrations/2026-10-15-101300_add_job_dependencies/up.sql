@@ -0,0 +1,13 @@
+-- Jobs may wait for other jobs to finish before they start.
+ALTER TYPE status ADD VALUE IF NOT EXISTS 'waiting' BEFORE 'creating';
+
+CREATE TABLE job_dependencies (
+    job_id uuid NOT NULL REFERENCES jobs (id) ON DELETE CASCADE,
+    upstream_job_id uuid NOT NULL REFERENCES jobs (id) ON DELETE CASCADE,
+    PRIMARY KEY (job_id, upstream_job_id)
+);
+
+CREATE INDEX job_dependencies_upstream_job_id ON job_dependencies (upstream_job_id);
+
+-- Everything we need to start a waiting job once its dependencies are done.
+ALTER TABLE jobs ADD deferred_start jsonb;
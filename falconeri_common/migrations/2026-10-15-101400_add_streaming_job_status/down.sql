@@ -0,0 +1,3 @@
+-- PostgreSQL can't remove values from an enum type, so just make sure nothing
+-- uses 'streaming' any more. These jobs will finish normally.
+UPDATE jobs SET status = 'running' WHERE status = 'streaming';
@@ -0,0 +1,3 @@
+-- Streaming jobs stay open, and falconerid adds datums to them as new input
+-- files appear.
+ALTER TYPE status ADD VALUE IF NOT EXISTS 'streaming' AFTER 'running';
@@ -0,0 +1,7 @@
+DROP INDEX datums_done_input_hash;
+
+ALTER TABLE datums DROP input_hash;
+
+-- PostgreSQL can't remove values from an enum type, so just make sure nothing
+-- uses 'skipped' any more.
+UPDATE datums SET status = 'done' WHERE status = 'skipped';
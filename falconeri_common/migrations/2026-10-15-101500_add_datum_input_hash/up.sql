@@ -0,0 +1,7 @@
+-- Datums whose inputs were already processed successfully by the same
+-- pipeline may be skipped.
+ALTER TYPE status ADD VALUE IF NOT EXISTS 'skipped' AFTER 'done';
+
+ALTER TABLE datums ADD input_hash text;
+
+CREATE INDEX datums_done_input_hash ON datums (input_hash) WHERE status = 'done';
@@ -0,0 +1,2 @@
+ALTER TABLE input_files DROP generation;
+ALTER TABLE input_files DROP etag;
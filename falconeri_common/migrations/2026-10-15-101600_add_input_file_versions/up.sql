@@ -0,0 +1,4 @@
+-- Remember which version of each input object we saw when creating a datum,
+-- so that we can notice when an object is replaced.
+ALTER TABLE input_files ADD etag text;
+ALTER TABLE input_files ADD generation text;
@@ -0,0 +1,3 @@
+DROP INDEX jobs_parent_job_id;
+
+ALTER TABLE jobs DROP parent_job_id;
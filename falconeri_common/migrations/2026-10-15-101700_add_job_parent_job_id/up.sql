@@ -0,0 +1,5 @@
+-- Jobs created by `falconeri job rerun` remember which job they were cloned
+-- from.
+ALTER TABLE jobs ADD parent_job_id uuid REFERENCES jobs (id) ON DELETE SET NULL;
+
+CREATE INDEX jobs_parent_job_id ON jobs (parent_job_id);
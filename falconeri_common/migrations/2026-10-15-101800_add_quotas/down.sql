@@ -0,0 +1,5 @@
+DROP INDEX jobs_team_status;
+
+ALTER TABLE jobs DROP team;
+
+DROP TABLE quotas;
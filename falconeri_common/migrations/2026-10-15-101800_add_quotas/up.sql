@@ -0,0 +1,13 @@
+-- Limits on how much of the cluster each team's jobs may use at once.
+CREATE TABLE quotas (
+    team text PRIMARY KEY,
+    created_at timestamp NOT NULL DEFAULT now(),
+    updated_at timestamp NOT NULL DEFAULT now(),
+    max_running_jobs integer CHECK (max_running_jobs >= 0),
+    max_parallelism integer CHECK (max_parallelism >= 0)
+);
+
+-- The team which owns each job, if any.
+ALTER TABLE jobs ADD team text;
+
+CREATE INDEX jobs_team_status ON jobs (team, status) WHERE team IS NOT NULL;
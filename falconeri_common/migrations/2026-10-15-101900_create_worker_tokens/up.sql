@@ -0,0 +1,11 @@
+-- Tokens which let worker pods call falconerid on behalf of a single job,
+-- without knowing our database password. We only store hashes of tokens.
+CREATE TABLE worker_tokens (
+    id uuid PRIMARY KEY DEFAULT uuid_generate_v4(),
+    created_at timestamp NOT NULL DEFAULT now(),
+    job_id uuid NOT NULL REFERENCES jobs (id) ON DELETE CASCADE,
+    token_hash text NOT NULL UNIQUE,
+    expires_at timestamp
+);
+
+CREATE INDEX worker_tokens_job_id ON worker_tokens (job_id);
@@ -0,0 +1 @@
+ALTER TABLE jobs DROP image_digest;
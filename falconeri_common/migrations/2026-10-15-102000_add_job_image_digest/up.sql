@@ -0,0 +1,3 @@
+-- The digest of the worker image which a job actually ran, so that we can
+-- reproduce it even if its tag has moved.
+ALTER TABLE jobs ADD image_digest text;
@@ -0,0 +1 @@
+ALTER TABLE jobs DROP submitted_pipeline_spec;
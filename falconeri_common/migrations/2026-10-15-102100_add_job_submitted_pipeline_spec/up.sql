@@ -0,0 +1,4 @@
+-- The complete pipeline spec which was submitted for a job, so that it can be
+-- recovered exactly. `pipeline_spec` only contains the parts we need to run
+-- the job.
+ALTER TABLE jobs ADD submitted_pipeline_spec jsonb;
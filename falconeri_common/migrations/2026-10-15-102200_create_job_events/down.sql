@@ -0,0 +1,2 @@
+DROP TABLE job_events;
+DROP TYPE job_event_kind;
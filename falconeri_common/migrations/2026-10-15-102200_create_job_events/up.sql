@@ -0,0 +1,21 @@
+-- Actions taken on a job's behalf outside of the normal flow of datums, such
+-- as the babysitter reaping a zombie datum, so that users can see them
+-- without reading our server logs.
+CREATE TYPE job_event_kind AS ENUM (
+    'job_creation_stalled',
+    'job_vanished',
+    'job_scaled_down',
+    'datum_reaped',
+    'datum_rescheduled'
+);
+
+CREATE TABLE job_events (
+    id uuid PRIMARY KEY DEFAULT uuid_generate_v4(),
+    created_at timestamp NOT NULL DEFAULT now(),
+    job_id uuid NOT NULL REFERENCES jobs (id) ON DELETE CASCADE,
+    datum_id uuid REFERENCES datums (id) ON DELETE CASCADE,
+    kind job_event_kind NOT NULL,
+    message text NOT NULL
+);
+
+CREATE INDEX job_events_job_id_created_at ON job_events (job_id, created_at);
@@ -0,0 +1,2 @@
+ALTER TABLE datums DROP retry_after;
+ALTER TABLE jobs DROP retry_backoff_seconds;
@@ -0,0 +1,5 @@
+-- Jobs may wait between automatic retries of a failed datum, doubling the
+-- delay after each attempt. Datums which are waiting can't be reserved until
+-- `retry_after`.
+ALTER TABLE jobs ADD retry_backoff_seconds integer;
+ALTER TABLE datums ADD retry_after timestamp;
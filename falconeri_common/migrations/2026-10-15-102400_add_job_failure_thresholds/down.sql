@@ -0,0 +1,7 @@
+ALTER TABLE jobs
+    DROP max_failed_datums,
+    DROP max_failed_datum_percent;
+
+-- PostgreSQL can't remove values from an enum type, so just make sure nothing
+-- uses 'done_with_errors' any more.
+UPDATE jobs SET status = 'error' WHERE status = 'done_with_errors';
@@ -0,0 +1,7 @@
+-- Jobs may tolerate a limited number of failed datums. If they stay within
+-- that limit, they finish with status 'done_with_errors' instead of 'error'.
+ALTER TYPE status ADD VALUE IF NOT EXISTS 'done_with_errors' AFTER 'done';
+
+ALTER TABLE jobs
+    ADD max_failed_datums integer,
+    ADD max_failed_datum_percent double precision;
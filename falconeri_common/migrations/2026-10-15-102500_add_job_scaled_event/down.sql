@@ -0,0 +1,3 @@
+-- PostgreSQL can't remove values from an enum type, so just make sure nothing
+-- uses 'job_scaled' any more.
+DELETE FROM job_events WHERE kind = 'job_scaled';
@@ -0,0 +1,2 @@
+-- Users may change a running job's parallelism using `falconeri job scale`.
+ALTER TYPE job_event_kind ADD VALUE IF NOT EXISTS 'job_scaled' AFTER 'job_scaled_down';
@@ -0,0 +1,9 @@
+ALTER TABLE jobs
+    DROP output_manifest_uri,
+    DROP output_manifest_written_at;
+
+DROP INDEX output_files_job_id_uri;
+
+ALTER TABLE output_files
+    DROP size,
+    DROP sha256;
@@ -0,0 +1,12 @@
+-- Workers report the size and checksum of each file they upload, so that we
+-- can write a manifest of a job's output.
+ALTER TABLE output_files
+    ADD size bigint,
+    ADD sha256 text;
+
+CREATE INDEX output_files_job_id_uri ON output_files (job_id, uri);
+
+-- Where to write a job's manifest, and when we wrote it.
+ALTER TABLE jobs
+    ADD output_manifest_uri text,
+    ADD output_manifest_written_at timestamp;
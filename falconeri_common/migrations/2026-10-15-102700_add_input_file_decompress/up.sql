@@ -0,0 +1,2 @@
+-- Inputs may ask workers to decompress files after downloading them.
+ALTER TABLE input_files ADD decompress boolean NOT NULL DEFAULT false;
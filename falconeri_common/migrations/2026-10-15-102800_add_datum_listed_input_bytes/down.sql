@@ -0,0 +1 @@
+ALTER TABLE datums DROP listed_input_bytes;
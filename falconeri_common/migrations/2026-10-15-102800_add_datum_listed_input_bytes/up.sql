@@ -0,0 +1,2 @@
+-- Record how many bytes of input we found for each datum when listing inputs.
+ALTER TABLE datums ADD listed_input_bytes bigint;
@@ -0,0 +1,2 @@
+ALTER TABLE input_files DROP last_modified;
+ALTER TABLE input_files DROP size;
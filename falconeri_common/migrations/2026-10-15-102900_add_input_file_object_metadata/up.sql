@@ -0,0 +1,3 @@
+-- Record the size and modification time of input objects when we list them.
+ALTER TABLE input_files ADD size bigint;
+ALTER TABLE input_files ADD last_modified timestamp;
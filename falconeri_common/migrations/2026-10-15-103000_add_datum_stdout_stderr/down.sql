@@ -0,0 +1 @@
+ALTER TABLE datums DROP stdout, DROP stderr;
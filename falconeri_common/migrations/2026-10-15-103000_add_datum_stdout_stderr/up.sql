@@ -0,0 +1,2 @@
+-- Store each datum's stdout and stderr separately, as well as combined.
+ALTER TABLE datums ADD stdout text, ADD stderr text;
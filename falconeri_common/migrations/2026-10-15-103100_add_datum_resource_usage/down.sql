@@ -0,0 +1 @@
+ALTER TABLE datums DROP peak_memory_bytes, DROP cpu_seconds;
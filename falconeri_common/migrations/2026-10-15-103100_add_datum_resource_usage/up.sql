@@ -0,0 +1,2 @@
+-- Store the peak memory and CPU time used by each datum's command.
+ALTER TABLE datums ADD peak_memory_bytes bigint, ADD cpu_seconds double precision;
@@ -0,0 +1,4 @@
+-- PostgreSQL can't remove values from an enum type, so just make sure nothing
+-- uses 'node_excluded' any more.
+DELETE FROM job_events WHERE kind = 'node_excluded';
+ALTER TABLE jobs DROP excluded_node_names;
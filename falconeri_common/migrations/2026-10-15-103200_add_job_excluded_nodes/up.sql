@@ -0,0 +1,4 @@
+-- Nodes which the babysitter stopped giving a job's datums to, because they
+-- were failing far more of them than other nodes.
+ALTER TABLE jobs ADD excluded_node_names text[] NOT NULL DEFAULT '{}';
+ALTER TYPE job_event_kind ADD VALUE IF NOT EXISTS 'node_excluded' AFTER 'datum_rescheduled';
@@ -0,0 +1,7 @@
+-- We can't decompress zstd in SQL, so compressed output and backtraces are
+-- lost.
+ALTER TABLE datums
+    DROP output_zstd,
+    DROP output_zstd_length,
+    DROP backtrace_zstd,
+    DROP backtrace_zstd_length;
@@ -0,0 +1,8 @@
+-- Store long datum output and backtraces compressed using zstd, along with
+-- their uncompressed length. Existing rows keep their plain text until the
+-- babysitter gets around to compressing them.
+ALTER TABLE datums
+    ADD output_zstd bytea,
+    ADD output_zstd_length bigint,
+    ADD backtrace_zstd bytea,
+    ADD backtrace_zstd_length bigint;
@@ -0,0 +1 @@
+DROP INDEX CONCURRENTLY IF EXISTS datums_active_job_id_status;
@@ -0,0 +1,10 @@
+-- Index only the datums which workers are still reserving and running. On
+-- large installations, almost every datum is `done`, so this is much smaller
+-- than `datum_job_id_status`, which we keep for counting datums by status.
+--
+-- We build this concurrently so that upgrading doesn't block workers, which
+-- means it must be the only statement in this migration. If it fails, drop
+-- the invalid index by hand before trying again.
+CREATE INDEX CONCURRENTLY IF NOT EXISTS datums_active_job_id_status
+    ON datums (job_id, status)
+    WHERE status IN ('ready', 'running');
@@ -0,0 +1 @@
+DROP INDEX CONCURRENTLY IF EXISTS input_files_job_id;
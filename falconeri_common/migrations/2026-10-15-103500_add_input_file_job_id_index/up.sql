@@ -0,0 +1,7 @@
+-- Find the input files of a job without going through `datums`. This is used
+-- when streaming jobs look for new inputs, and when we describe and audit
+-- jobs.
+--
+-- See `2026-10-15-103400_add_active_datum_index` for why this is built
+-- concurrently, in a migration of its own.
+CREATE INDEX CONCURRENTLY IF NOT EXISTS input_files_job_id ON input_files (job_id);
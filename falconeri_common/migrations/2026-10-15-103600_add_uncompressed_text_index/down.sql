@@ -0,0 +1 @@
+DROP INDEX CONCURRENTLY IF EXISTS datums_uncompressed_text;
@@ -0,0 +1,8 @@
+-- Find rows which still need to be compressed, without scanning the whole
+-- table. The length must match `MIN_COMPRESSED_TEXT_BYTES`.
+--
+-- We build this concurrently so that upgrading doesn't block workers, which
+-- means it must be the only statement in this migration. If it fails, drop
+-- the invalid index by hand before trying again.
+CREATE INDEX CONCURRENTLY IF NOT EXISTS datums_uncompressed_text ON datums (id)
+    WHERE octet_length(output) >= 1024 OR octet_length(backtrace) >= 1024;
@@ -0,0 +1,10 @@
+-- Revocable tokens which let people and scripts call falconerid through an
+-- ingress, without knowing our database password. We only store hashes of
+-- tokens.
+CREATE TABLE api_tokens (
+    id uuid PRIMARY KEY DEFAULT uuid_generate_v4(),
+    created_at timestamp NOT NULL DEFAULT now(),
+    name text NOT NULL,
+    token_hash text NOT NULL UNIQUE,
+    revoked_at timestamp
+);
@@ -0,0 +1,4 @@
+-- API tokens may belong to a team. Tokens with a team may only submit and
+-- change that team's jobs, so that a team can't escape its quota by choosing
+-- another team name in its pipeline specs.
+ALTER TABLE api_tokens ADD team text;
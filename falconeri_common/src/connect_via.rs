@@ -1,6 +1,6 @@
 //! How should we connect to PostgreSQL and `falconerid`?
 
-use std::{future::Future, time::Duration};
+use std::{env, future::Future, sync::OnceLock, time::Duration};
 
 use backon::{BlockingRetryable, ExponentialBuilder, Retryable};
 
@@ -15,6 +15,81 @@ pub enum ConnectVia {
     Cluster,
 }
 
+/// Settings which change how we connect using [`ConnectVia::Proxy`]. The CLI
+/// fills these in from the current context in its config file.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ProxySettings {
+    /// The `kubectl` context to use, instead of `kubectl`'s current context.
+    pub kube_context: Option<String>,
+    /// The Kubernetes namespace to use, instead of the context's default.
+    pub namespace: Option<String>,
+    /// The URL of `falconerid`, instead of `http://localhost:8089/`.
+    pub falconerid_url: Option<String>,
+    /// A token to send to `falconerid`, instead of using the password from
+    /// the `falconeri` secret. We send this as a bearer token.
+    pub token: Option<String>,
+}
+
+/// Environment variable which overrides [`ProxySettings::falconerid_url`].
+const FALCONERID_URL_VAR: &str = "FALCONERID_URL";
+
+/// Environment variable which overrides [`ProxySettings::token`].
+const FALCONERID_TOKEN_VAR: &str = "FALCONERID_TOKEN";
+
+/// Our global proxy settings, if they've been installed.
+static PROXY_SETTINGS: OnceLock<ProxySettings> = OnceLock::new();
+
+impl ProxySettings {
+    /// Use these settings for the rest of this process. This may only be
+    /// called once, before we connect to anything.
+    pub fn install(self) -> Result<()> {
+        PROXY_SETTINGS
+            .set(self)
+            .map_err(|_| format_err!("proxy settings were already installed"))
+    }
+
+    /// Get the settings for this process, or the defaults if none were
+    /// installed.
+    pub fn current() -> &'static ProxySettings {
+        PROXY_SETTINGS.get_or_init(ProxySettings::default)
+    }
+
+    /// Override our `falconerid` URL and token using `FALCONERID_URL` and
+    /// `FALCONERID_TOKEN`, if they're set. This allows talking to a
+    /// `falconerid` behind an ingress without a config file.
+    pub fn with_env_overrides(self) -> ProxySettings {
+        let var = |name| env::var(name).ok().filter(|value| !value.is_empty());
+        self.with_overrides(var(FALCONERID_URL_VAR), var(FALCONERID_TOKEN_VAR))
+    }
+
+    /// Replace our URL and token with any values that are present.
+    fn with_overrides(
+        mut self,
+        falconerid_url: Option<String>,
+        token: Option<String>,
+    ) -> ProxySettings {
+        if falconerid_url.is_some() {
+            self.falconerid_url = falconerid_url;
+        }
+        if token.is_some() {
+            self.token = token;
+        }
+        self
+    }
+
+    /// Extra arguments to pass to every `kubectl` command.
+    pub fn kubectl_args(&self) -> Vec<String> {
+        let mut args = vec![];
+        if let Some(kube_context) = &self.kube_context {
+            args.push(format!("--context={}", kube_context));
+        }
+        if let Some(namespace) = &self.namespace {
+            args.push(format!("--namespace={}", namespace));
+        }
+        args
+    }
+}
+
 impl ConnectVia {
     /// Should we retry failed connections?
     #[instrument(level = "trace")]
@@ -65,10 +140,60 @@ impl ConnectVia {
     where
         F: FnMut() -> Fut,
         Fut: Future<Output = Result<T>>,
+    {
+        self.retry_if_appropriate_when_async(|_| true, f).await
+    }
+
+    /// Like `retry_if_appropriate_async`, but only retry errors for which
+    /// `is_retryable` returns true.
+    #[instrument(skip(is_retryable, f), level = "trace")]
+    pub async fn retry_if_appropriate_when_async<W, F, Fut, T>(
+        self,
+        mut is_retryable: W,
+        f: F,
+    ) -> Result<T>
+    where
+        W: FnMut(&Error) -> bool,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
     {
         f.retry(Self::backoff_config())
-            .when(|_| self.should_retry_by_default())
+            .when(|err| self.should_retry_by_default() && is_retryable(err))
             .notify(|err, _dur| error!("retrying after error: {}", err))
             .await
     }
 }
+
+#[test]
+fn proxy_settings_kubectl_args() {
+    assert!(ProxySettings::default().kubectl_args().is_empty());
+    let settings = ProxySettings {
+        kube_context: Some("gke_prod".to_owned()),
+        namespace: Some("falconeri".to_owned()),
+        ..ProxySettings::default()
+    };
+    assert_eq!(
+        settings.kubectl_args(),
+        vec!["--context=gke_prod", "--namespace=falconeri"]
+    );
+}
+
+#[test]
+fn proxy_settings_env_overrides() {
+    let settings = ProxySettings {
+        kube_context: Some("gke_prod".to_owned()),
+        falconerid_url: Some("http://localhost:18089/".to_owned()),
+        token: Some("secret".to_owned()),
+        ..ProxySettings::default()
+    };
+    let overridden = settings
+        .clone()
+        .with_overrides(Some("https://falconeri.internal".to_owned()), None);
+    assert_eq!(
+        overridden.falconerid_url.as_deref(),
+        Some("https://falconeri.internal")
+    );
+    assert_eq!(overridden.token.as_deref(), Some("secret"));
+    assert_eq!(overridden.kube_context.as_deref(), Some("gke_prod"));
+    assert_eq!(settings.clone().with_overrides(None, None), settings);
+}
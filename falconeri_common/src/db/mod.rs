@@ -0,0 +1,400 @@
+//! Database utilities.
+
+use std::{env, fs::read_to_string};
+
+use anyhow::anyhow;
+use diesel::{
+    pg::Pg,
+    query_builder::{AstPass, Query, QueryFragment, QueryId},
+    sql_types::Text,
+    QueryResult,
+};
+pub use diesel_async::{
+    pooled_connection::deadpool::{
+        Object as PooledConnection, Pool as AsyncPoolInner,
+    },
+    AsyncPgConnection,
+};
+use diesel_async::{
+    pooled_connection::{AsyncDieselConnectionManager, ManagerConfig},
+    AsyncMigrationHarness,
+};
+use diesel_migrations::{MigrationHarness, MigrationSource};
+
+use crate::{
+    kubernetes::{base64_encoded_secret_string, kubectl_secret},
+    prelude::*,
+};
+
+mod tls;
+
+pub use self::tls::{SslMode, TlsSettings};
+
+/// Embed our migrations directly into the executable. We use a
+/// submodule so we can configure warnings.
+#[allow(unused_imports)]
+mod migrations {
+    use diesel_migrations::{embed_migrations, EmbeddedMigrations};
+    pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("./migrations");
+}
+
+/// The data we store in our secret.
+#[derive(Debug, Deserialize)]
+struct FalconeriSecretData {
+    #[serde(with = "base64_encoded_secret_string", rename = "POSTGRES_PASSWORD")]
+    postgres_password: String,
+}
+
+/// Look up our PostgreSQL password in our cluster's `falconeri` secret.
+#[instrument(level = "trace")]
+pub async fn postgres_password(via: ConnectVia) -> Result<String> {
+    match via {
+        ConnectVia::Proxy => {
+            trace!("Fetching POSTGRES_PASSWORD from secret `falconeri`");
+            // We implement the following as Rust:
+            //
+            // kubectl get secret falconeri -o json |
+            //     jq -r .data.POSTGRES_PASSWORD |
+            //     base64 --decode
+            let secret_data: FalconeriSecretData = kubectl_secret("falconeri").await?;
+            Ok(secret_data.postgres_password)
+        }
+        ConnectVia::Cluster => {
+            // This should be mounted into our container.
+            Ok(read_to_string("/etc/falconeri/secrets/POSTGRES_PASSWORD")
+                .context("could not read /etc/falconeri/secrets/POSTGRES_PASSWORD")?)
+        }
+    }
+}
+
+/// Get an appropriate database URL.
+#[instrument(level = "trace")]
+pub async fn database_url(via: ConnectVia) -> Result<String> {
+    // Check the environment first, so it can be overridden for testing outside
+    // of a full Kubernetes setup, or to point at an externally-managed
+    // database. This may include `sslmode` and `sslrootcert` parameters.
+    if let Ok(database_url) = env::var("DATABASE_URL") {
+        return Ok(database_url);
+    }
+
+    // Build a URL.
+    let password = postgres_password(via).await?;
+    Ok(database_url_with_password(via, &password))
+}
+
+/// Build a URL for our own PostgreSQL server, using `password`. This ignores
+/// `DATABASE_URL`.
+pub fn database_url_with_password(via: ConnectVia, password: &str) -> String {
+    match via {
+        ConnectVia::Proxy => {
+            let host = env::var("FALCONERI_PROXY_HOST")
+                .unwrap_or_else(|_| "localhost".to_string());
+            // Set by people who run `falconeri proxy --postgres-port`.
+            let port = env::var("FALCONERI_PROXY_POSTGRES_PORT")
+                .unwrap_or_else(|_| "5432".to_string());
+            format!("postgres://postgres:{}@{}:{}/", password, host, port)
+        }
+        ConnectVia::Cluster => {
+            format!("postgres://postgres:{}@falconeri-postgres:5432/", password,)
+        }
+    }
+}
+
+/// An async database connection pool using deadpool.
+pub type AsyncPool = AsyncPoolInner<AsyncPgConnection>;
+
+/// A pooled async database connection.
+pub type AsyncPooledConn = PooledConnection<AsyncPgConnection>;
+
+/// Create an async connection pool using the specified parameters.
+#[instrument(level = "trace")]
+pub async fn async_pool(pool_size: usize, via: ConnectVia) -> Result<AsyncPool> {
+    let database_url = database_url(via).await?;
+    async_pool_for_url(pool_size, &database_url)
+}
+
+/// Create an async connection pool for `database_url`, which may include
+/// `sslmode` and `sslrootcert` parameters.
+pub fn async_pool_for_url(pool_size: usize, database_url: &str) -> Result<AsyncPool> {
+    let mut manager_config = ManagerConfig::default();
+    manager_config.custom_setup = Box::new(tls::establish_for_pool);
+    let config = AsyncDieselConnectionManager::<AsyncPgConnection>::new_with_config(
+        database_url,
+        manager_config,
+    );
+    AsyncPoolInner::builder(config)
+        .max_size(pool_size)
+        .build()
+        .context("could not create async database pool")
+}
+
+/// Establish a direct async connection to the database.
+///
+/// This is used for migrations where we need a raw connection rather than
+/// a pooled one.
+#[instrument(level = "trace")]
+pub async fn async_connect(via: ConnectVia) -> Result<AsyncPgConnection> {
+    let url = database_url(via).await?;
+    via.retry_if_appropriate_async(|| async { async_connect_to_url(&url).await })
+        .await
+}
+
+/// Establish a direct async connection to `database_url`, without retrying.
+pub async fn async_connect_to_url(database_url: &str) -> Result<AsyncPgConnection> {
+    tls::establish(database_url)
+        .await
+        .context("Error connecting to database")
+}
+
+/// Describe `database_url` without revealing its password, and make sure that
+/// we can load any TLS certificates it needs.
+pub fn check_database_url(database_url: &str) -> Result<String> {
+    let (settings, _) = TlsSettings::from_database_url(database_url)?;
+    settings.validate()?;
+    let url = url::Url::parse(database_url).context("could not parse database URL")?;
+    Ok(format!(
+        "host={} port={} database={} sslmode={}{}",
+        url.host_str().unwrap_or("localhost"),
+        url.port().unwrap_or(5432),
+        url.path().trim_start_matches('/'),
+        settings.mode,
+        settings
+            .root_cert
+            .map(|path| format!(" sslrootcert={}", path.display()))
+            .unwrap_or_default(),
+    ))
+}
+
+/// A row from diesel's `__diesel_schema_migrations` table.
+#[derive(Debug, QueryableByName)]
+struct AppliedMigration {
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    version: String,
+}
+
+/// List the versions of all migrations embedded in this executable, in
+/// order.
+pub fn embedded_migration_versions() -> Result<Vec<String>> {
+    let migrations =
+        MigrationSource::<diesel::pg::Pg>::migrations(&migrations::MIGRATIONS)
+            .map_err(|e| anyhow!("could not list embedded migrations: {}", e))?;
+    Ok(migrations
+        .iter()
+        .map(|m| m.name().version().to_string())
+        .collect())
+}
+
+/// List the versions of all migrations which have been applied to the
+/// database.
+#[instrument(skip_all, level = "trace")]
+pub async fn applied_migration_versions(
+    conn: &mut AsyncPgConnection,
+) -> Result<Vec<String>> {
+    use diesel_async::RunQueryDsl;
+
+    let applied = diesel::sql_query(
+        "SELECT version FROM __diesel_schema_migrations ORDER BY version",
+    )
+    .load::<AppliedMigration>(conn)
+    .await
+    .context("could not list applied migrations")?;
+    Ok(applied.into_iter().map(|m| m.version).collect())
+}
+
+/// List the versions of embedded migrations which have not yet been applied
+/// to the database.
+#[instrument(skip_all, level = "trace")]
+pub async fn pending_migration_versions(
+    conn: &mut AsyncPgConnection,
+) -> Result<Vec<String>> {
+    let applied = applied_migration_versions(conn).await?;
+    Ok(embedded_migration_versions()?
+        .into_iter()
+        .filter(|version| !applied.contains(version))
+        .collect())
+}
+
+/// Wraps a query, and runs `EXPLAIN` on it instead. Unlike formatting the
+/// query's SQL ourselves, this passes the query's bind parameters to
+/// PostgreSQL, so the plan matches the one used for the real query. Each row
+/// of the result is a line of the plan.
+pub struct Explain<Q>(pub Q);
+
+impl<Q> QueryId for Explain<Q> {
+    type QueryId = ();
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<Q> Query for Explain<Q> {
+    type SqlType = Text;
+}
+
+impl<Q: QueryFragment<Pg>> QueryFragment<Pg> for Explain<Q> {
+    fn walk_ast<'b>(&'b self, mut out: AstPass<'_, 'b, Pg>) -> QueryResult<()> {
+        out.push_sql("EXPLAIN ");
+        self.0.walk_ast(out.reborrow())
+    }
+}
+
+/// Size statistics for a table in our database.
+#[derive(Debug, QueryableByName, Serialize)]
+pub struct TableStats {
+    /// The name of the table.
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    pub table_name: String,
+    /// PostgreSQL's estimate of the number of live rows.
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub live_rows: i64,
+    /// PostgreSQL's estimate of the number of dead rows, which will be
+    /// cleaned up by the next vacuum.
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub dead_rows: i64,
+    /// The total size of the table on disk, including indices and TOAST data.
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub total_bytes: i64,
+    /// When this table was last vacuumed, either manually or automatically.
+    #[diesel(sql_type = diesel::sql_types::Nullable<diesel::sql_types::Timestamp>)]
+    pub last_vacuum_at: Option<NaiveDateTime>,
+}
+
+/// Look up size statistics for all our tables, largest first.
+#[instrument(skip_all, level = "trace")]
+pub async fn table_stats(conn: &mut AsyncPgConnection) -> Result<Vec<TableStats>> {
+    use diesel_async::RunQueryDsl;
+
+    diesel::sql_query(
+        "SELECT
+             relname::text AS table_name,
+             n_live_tup AS live_rows,
+             n_dead_tup AS dead_rows,
+             pg_total_relation_size(relid) AS total_bytes,
+             GREATEST(last_vacuum, last_autovacuum)::timestamp AS last_vacuum_at
+         FROM pg_stat_user_tables
+         ORDER BY total_bytes DESC, table_name",
+    )
+    .load::<TableStats>(conn)
+    .await
+    .context("could not look up table statistics")
+}
+
+/// How many datums should [`text_compression_stats`] sample?
+const TEXT_COMPRESSION_SAMPLE_SIZE: i64 = 10_000;
+
+/// How well we're compressing datum output and backtraces, based on a sample
+/// of datums.
+#[derive(Debug, QueryableByName, Serialize)]
+pub struct TextCompressionStats {
+    /// The number of datums we sampled.
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub sampled_datums: i64,
+    /// The number of sampled outputs and backtraces stored compressed.
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub compressed_values: i64,
+    /// The total size of the sampled compressed values.
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub compressed_bytes: i64,
+    /// The total size of the sampled compressed values before compression.
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub uncompressed_bytes: i64,
+    /// The number of datums in the whole table whose output or backtrace
+    /// was stored before we started compressing them, and which the
+    /// babysitter will compress.
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub pending_datums: i64,
+}
+
+impl TextCompressionStats {
+    /// How many times smaller our compressed values are than the originals,
+    /// if we've compressed anything.
+    pub fn ratio(&self) -> Option<f64> {
+        if self.compressed_bytes > 0 {
+            Some(cast::f64(self.uncompressed_bytes) / cast::f64(self.compressed_bytes))
+        } else {
+            None
+        }
+    }
+}
+
+/// Look up how well we're compressing datum output and backtraces. To avoid
+/// scanning the whole `datums` table, we measure compression using the first
+/// [`TEXT_COMPRESSION_SAMPLE_SIZE`] datums we find, and count pending datums
+/// using the `datums_uncompressed_text` index.
+#[instrument(skip_all, level = "trace")]
+pub async fn text_compression_stats(
+    conn: &mut AsyncPgConnection,
+) -> Result<TextCompressionStats> {
+    use diesel::sql_types::BigInt;
+    use diesel_async::RunQueryDsl;
+
+    diesel::sql_query(
+        "SELECT
+             count(*) AS sampled_datums,
+             count(output_zstd) + count(backtrace_zstd) AS compressed_values,
+             coalesce(sum(octet_length(output_zstd)), 0)::bigint
+                 + coalesce(sum(octet_length(backtrace_zstd)), 0)::bigint
+                 AS compressed_bytes,
+             coalesce(sum(output_zstd_length), 0)::bigint
+                 + coalesce(sum(backtrace_zstd_length), 0)::bigint
+                 AS uncompressed_bytes,
+             (SELECT count(*) FROM datums
+                  WHERE octet_length(output) >= 1024
+                      OR octet_length(backtrace) >= 1024)
+                 AS pending_datums
+         FROM (
+             SELECT output_zstd, output_zstd_length,
+                    backtrace_zstd, backtrace_zstd_length
+             FROM datums
+             LIMIT $1
+         ) AS sample",
+    )
+    .bind::<BigInt, _>(TEXT_COMPRESSION_SAMPLE_SIZE)
+    .get_result::<TextCompressionStats>(conn)
+    .await
+    .context("could not look up text compression statistics")
+}
+
+/// Run any pending migrations.
+///
+/// Uses `AsyncMigrationHarness` which internally uses `block_in_place` to run
+/// diesel's sync migration infrastructure without blocking the async runtime.
+#[instrument(skip_all, level = "trace")]
+pub fn run_pending_migrations(conn: AsyncPgConnection) -> Result<AsyncPgConnection> {
+    debug!("Running pending migrations");
+    let mut harness = AsyncMigrationHarness::new(conn);
+    harness
+        .run_pending_migrations(migrations::MIGRATIONS)
+        .map_err(|e| anyhow!("could not run migrations: {}", e))?;
+    Ok(harness.into_inner())
+}
+
+/// Change the password of the PostgreSQL role we're connected as. Existing
+/// connections using the old password keep working until they're closed.
+#[instrument(skip_all, level = "trace")]
+pub async fn set_current_user_password(
+    conn: &mut AsyncPgConnection,
+    password: &str,
+) -> Result<()> {
+    use diesel_async::RunQueryDsl;
+
+    // `ALTER ROLE` doesn't accept bind parameters, so we need to quote the
+    // password ourselves.
+    diesel::sql_query(format!(
+        "ALTER ROLE CURRENT_USER WITH PASSWORD {}",
+        quote_sql_string(password)
+    ))
+    .execute(conn)
+    .await
+    .context("could not change database password")?;
+    Ok(())
+}
+
+/// Quote `s` as a PostgreSQL string literal.
+fn quote_sql_string(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+#[test]
+fn sql_strings_are_quoted() {
+    assert_eq!(quote_sql_string("abc123"), "'abc123'");
+    assert_eq!(quote_sql_string("it's"), "'it''s'");
+}
@@ -0,0 +1,147 @@
+//! Decompressing input files after we download them.
+//!
+//! If an `atom` input sets `decompress`, then after downloading each of its
+//! files, we decompress any file with a recognized extension and remove the
+//! extension from its name. So `gs://bucket/in/data.csv.gz` appears in `/pfs`
+//! as `/pfs/in/data.csv`, and the compressed file is deleted.
+
+use std::{fs, io};
+
+use flate2::read::MultiGzDecoder;
+use walkdir::WalkDir;
+
+use crate::prelude::*;
+
+/// A compression format which we know how to decompress.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// `gzip`, with the extension `.gz`.
+    Gzip,
+    /// Zstandard, with the extension `.zst`.
+    Zstd,
+}
+
+impl Compression {
+    /// Guess how `path` is compressed from its extension. Returns the
+    /// compression format, and `path` without the extension. Files named
+    /// just `.gz` are left alone.
+    pub fn for_path(path: &str) -> Option<(Compression, &str)> {
+        let (compression, stripped) = if let Some(stripped) = path.strip_suffix(".gz")
+        {
+            (Compression::Gzip, stripped)
+        } else if let Some(stripped) = path.strip_suffix(".zst") {
+            (Compression::Zstd, stripped)
+        } else {
+            return None;
+        };
+        if stripped.is_empty() || stripped.ends_with('/') {
+            None
+        } else {
+            Some((compression, stripped))
+        }
+    }
+}
+
+/// Where a file downloaded to `local_path` will be once we've decompressed
+/// it. Directories and files which don't have a recognized extension stay
+/// where they are.
+pub fn decompressed_path(local_path: &str) -> &str {
+    match Compression::for_path(local_path) {
+        Some((_, stripped)) => stripped,
+        None => local_path,
+    }
+}
+
+/// Decompress `path` if it's a compressed file, or every compressed file
+/// under `path` if it's a directory. This blocks, so async code should call
+/// it using `spawn_blocking`.
+#[instrument(skip_all, fields(path = %path.display()), level = "debug")]
+pub fn decompress_in_place(path: &Path) -> Result<()> {
+    // List everything before we start, so that we never decompress a file
+    // twice.
+    let mut files = vec![];
+    for entry in WalkDir::new(path) {
+        let entry =
+            entry.with_context(|| format!("error listing {}", path.display()))?;
+        if entry.file_type().is_file() {
+            files.push(entry.into_path());
+        }
+    }
+    for file in files {
+        decompress_file(&file)?;
+    }
+    Ok(())
+}
+
+/// Decompress the file at `path`, if it has a recognized extension.
+fn decompress_file(path: &Path) -> Result<()> {
+    let path_str = path
+        .to_str()
+        .ok_or_else(|| format_err!("invalid characters in {:?}", path))?;
+    let Some((compression, dest)) = Compression::for_path(path_str) else {
+        return Ok(());
+    };
+    let dest = Path::new(dest);
+    if dest.exists() {
+        return Err(format_err!(
+            "cannot decompress {}, because {} already exists",
+            path.display(),
+            dest.display()
+        ));
+    }
+    trace!("decompressing {} to {}", path.display(), dest.display());
+
+    let input = fs::File::open(path)
+        .with_context(|| format!("cannot open {}", path.display()))?;
+    let mut output = fs::File::create(dest)
+        .with_context(|| format!("cannot create {}", dest.display()))?;
+    let result = match compression {
+        Compression::Gzip => {
+            io::copy(&mut MultiGzDecoder::new(input), &mut output).map(|_| ())
+        }
+        Compression::Zstd => zstd::stream::copy_decode(input, &mut output),
+    };
+    if let Err(err) = result {
+        let _ = fs::remove_file(dest);
+        return Err(err)
+            .with_context(|| format!("cannot decompress {}", path.display()));
+    }
+    fs::remove_file(path)
+        .with_context(|| format!("cannot delete {}", path.display()))?;
+    Ok(())
+}
+
+#[test]
+fn decompressed_paths_drop_extensions() {
+    assert_eq!(decompressed_path("/pfs/in/a.csv.gz"), "/pfs/in/a.csv");
+    assert_eq!(decompressed_path("/pfs/in/a.csv.zst"), "/pfs/in/a.csv");
+    assert_eq!(decompressed_path("/pfs/in/a.csv"), "/pfs/in/a.csv");
+    assert_eq!(decompressed_path("/pfs/in/"), "/pfs/in/");
+    assert_eq!(decompressed_path("/pfs/in/.gz"), "/pfs/in/.gz");
+}
+
+#[test]
+fn decompress_in_place_handles_files_and_dirs() {
+    let dir = crate::temp_dir::TempDir::new("falconeri-test").unwrap();
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    let mut gz =
+        flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    gz.write_all(b"gzipped").unwrap();
+    fs::write(dir.join("a.txt.gz"), gz.finish().unwrap()).unwrap();
+    fs::write(
+        dir.join("sub/b.txt.zst"),
+        zstd::encode_all(&b"zstandard"[..], 0).unwrap(),
+    )
+    .unwrap();
+    fs::write(dir.join("c.txt"), "plain").unwrap();
+
+    decompress_in_place(&dir).unwrap();
+    assert_eq!(fs::read_to_string(dir.join("a.txt")).unwrap(), "gzipped");
+    assert_eq!(
+        fs::read_to_string(dir.join("sub/b.txt")).unwrap(),
+        "zstandard"
+    );
+    assert_eq!(fs::read_to_string(dir.join("c.txt")).unwrap(), "plain");
+    assert!(!dir.join("a.txt.gz").exists());
+    assert!(!dir.join("sub/b.txt.zst").exists());
+}
@@ -0,0 +1,387 @@
+//! Dividing a pipeline's `"input"` clause into datums.
+//!
+//! This is shared by `falconerid`, which stores datums in the database, and
+//! by `falconeri local run`, which runs them on the local machine, so that
+//! both split inputs the same way. Inputs which read the output of another
+//! job need the database, so callers handle `Input::Job` themselves.
+
+use crate::{
+    models::{NewDatum, NewInputFile},
+    pipeline::{Glob, Input, PartitionBy},
+    prelude::*,
+    secret::Secret,
+    storage::{CloudStorage, ListedObject},
+};
+
+/// A datum which we haven't assigned an ID yet. This is essentially a
+/// `NewDatum` and a `Vec<NewInputFile>`, but without UUIDs, which makes it
+/// easier to combine datums.
+#[derive(Clone, Debug)]
+pub struct DatumData {
+    /// The input files for this datum.
+    pub input_files: Vec<InputFileData>,
+}
+
+impl DatumData {
+    /// Convert this into an actual `NewDatum` and a `Vec<NewInputFile>`.
+    pub fn into_new_datum_and_input_files(
+        self,
+        job_id: Uuid,
+        maximum_allowed_run_count: i32,
+    ) -> (NewDatum, Vec<NewInputFile>) {
+        let datum_id = Uuid::new_v4();
+        let listed_input_bytes = self.listed_input_bytes();
+        let input_files = self
+            .input_files
+            .into_iter()
+            .map(|f| f.into_new_input_file(job_id, datum_id))
+            .collect::<Vec<_>>();
+        // We can't tell when the contents of a directory change without
+        // listing it recursively, which is slow, so we leave that to
+        // `hash_directory_inputs`.
+        let input_hash = if input_files.iter().any(|f| f.is_directory()) {
+            None
+        } else {
+            Some(NewInputFile::input_hash(&input_files))
+        };
+        let datum = NewDatum {
+            id: datum_id,
+            job_id,
+            maximum_allowed_run_count,
+            status: Status::Ready,
+            input_hash,
+            listed_input_bytes,
+        };
+        (datum, input_files)
+    }
+
+    /// The total size of our input files, or `None` if we don't know the size
+    /// of every file.
+    pub fn listed_input_bytes(&self) -> Option<i64> {
+        let mut total = 0u64;
+        for input_file in &self.input_files {
+            total = total.saturating_add(input_file.size?);
+        }
+        i64::try_from(total).ok()
+    }
+}
+
+/// An input file for a [`DatumData`]. This is essentially a `NewInputFile`,
+/// but without UUIDs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InputFileData {
+    /// The URI of the file.
+    pub uri: String,
+    /// Where the file should be placed on a worker, like `/pfs/$REPO/$FILE`.
+    pub local_path: String,
+    /// The entity tag of the file, if our storage backend told us.
+    pub etag: Option<String>,
+    /// The generation of the file, if our storage backend told us.
+    pub generation: Option<String>,
+    /// The size of this file, if our storage backend told us.
+    pub size: Option<u64>,
+    /// When this file was last modified, if our storage backend told us.
+    pub last_modified: Option<NaiveDateTime>,
+    /// Should the worker decompress this file after downloading it?
+    pub decompress: bool,
+}
+
+impl InputFileData {
+    /// Convert this into an actual `NewInputFile`.
+    fn into_new_input_file(self, job_id: Uuid, datum_id: Uuid) -> NewInputFile {
+        NewInputFile {
+            job_id,
+            datum_id,
+            uri: self.uri,
+            local_path: self.local_path,
+            etag: self.etag,
+            generation: self.generation,
+            decompress: self.decompress,
+            size: self.size.and_then(|size| i64::try_from(size).ok()),
+            last_modified: self.last_modified,
+        }
+    }
+}
+
+/// List the files in an `Input::Atom`, and divide them into datums,
+/// partitioning them if the input asks us to. Returns an error for any other
+/// kind of input.
+#[instrument(skip_all, level = "trace")]
+pub async fn atom_to_datums(
+    secrets: &[Secret],
+    input: &Input,
+) -> Result<Vec<DatumData>> {
+    let Input::Atom {
+        uri,
+        repo,
+        glob,
+        decompress,
+        partitions,
+        partition_by,
+        ..
+    } = input
+    else {
+        return Err(format_err!("expected an atom input, found {:?}", input));
+    };
+
+    // Figure out what files to process. We do this for _both_
+    // `Glob::TopLevelDirectoryEntries` and `Glob::WholeRepo`, because we want
+    // to verify that we can actually list the contents of a `Glob::WholeRepo`
+    // _before_ spinning up a big cluster job.
+    let requester_pays = input.requester_pays_uris().remove(uri);
+    let storage =
+        <dyn CloudStorage>::for_input_uri(uri, secrets, requester_pays.as_ref())
+            .await?;
+    let objects = storage.list_objects(uri).await?;
+
+    let datums = listed_objects_to_datums(uri, repo, *glob, objects, *decompress)?;
+    match partitions {
+        None => Ok(datums),
+        Some(partitions) => {
+            partition_datums(datums, *glob, *partitions, *partition_by)
+        }
+    }
+}
+
+/// Divide the `objects` listed in the atom input `uri` into datums.
+fn listed_objects_to_datums(
+    uri: &str,
+    repo: &str,
+    glob: Glob,
+    objects: Vec<ListedObject>,
+    decompress: bool,
+) -> Result<Vec<DatumData>> {
+    // Normalize our URI to always include a slash, because repositories must
+    // currently be directories.
+    let mut base = uri.to_owned();
+    if !base.ends_with('/') {
+        base.push('/');
+    }
+
+    match glob {
+        // Our input file is just the entire repo, as a directory.
+        Glob::WholeRepo => Ok(vec![DatumData {
+            input_files: vec![InputFileData {
+                uri: base,
+                local_path: format!("/pfs/{}/", repo),
+                etag: None,
+                generation: None,
+                size: objects.iter().map(|object| object.size).sum(),
+                last_modified: None,
+                decompress,
+            }],
+        }]),
+
+        // Each top-level file or directory in `base` should be translated into
+        // a separate datum.
+        Glob::TopLevelDirectoryEntries => objects
+            .into_iter()
+            .map(|object| {
+                let local_path = uri_to_local_path(&base, &object.uri, repo)?;
+                Ok(DatumData {
+                    input_files: vec![InputFileData {
+                        uri: object.uri,
+                        local_path,
+                        etag: object.etag,
+                        generation: object.generation,
+                        size: object.size,
+                        last_modified: object.last_modified,
+                        decompress,
+                    }],
+                })
+            })
+            .collect(),
+    }
+}
+
+/// Group the datums for the entries of an `Input::Atom` into at most
+/// `partitions` larger datums, each with the input files of several entries.
+fn partition_datums(
+    datums: Vec<DatumData>,
+    glob: Glob,
+    partitions: u32,
+    partition_by: PartitionBy,
+) -> Result<Vec<DatumData>> {
+    if glob != Glob::TopLevelDirectoryEntries {
+        return Err(format_err!("\"partitions\" requires a glob of \"/*\""));
+    }
+    if partitions == 0 {
+        return Err(format_err!("\"partitions\" must be at least 1"));
+    }
+    let items = datums
+        .into_iter()
+        .map(|datum| {
+            let size = datum.listed_input_bytes().map(cast::u64).transpose()?;
+            Ok((datum, size))
+        })
+        .collect::<Result<_>>()?;
+    Ok(partition_by
+        .partition(items, partitions)
+        .into_iter()
+        .map(|group| DatumData {
+            input_files: group
+                .into_iter()
+                .flat_map(|datum| datum.input_files)
+                .collect(),
+        })
+        .collect())
+}
+
+/// Combine the datums of each input in an `Input::Cross`, returning one datum
+/// for every combination. The cross product of no inputs has no datums.
+///
+/// SECURITY: This assumes it runs on reasonably trusted and plausible inputs.
+/// You can cause a denial-of-service by calculating the cross product of
+/// enormous repos. But since our input comes from a local user, this is fine
+/// for now.
+pub fn cross_datums(inputs: Vec<Vec<DatumData>>) -> Vec<DatumData> {
+    let mut inputs = inputs.into_iter();
+    let Some(mut output) = inputs.next() else {
+        return vec![];
+    };
+    for datums_1 in inputs {
+        let mut combined = Vec::with_capacity(output.len() * datums_1.len());
+        for datum_0 in &output {
+            for datum_1 in &datums_1 {
+                let mut input_files = Vec::with_capacity(
+                    datum_0.input_files.len() + datum_1.input_files.len(),
+                );
+                input_files.extend(datum_0.input_files.iter().cloned());
+                input_files.extend(datum_1.input_files.iter().cloned());
+                combined.push(DatumData { input_files });
+            }
+        }
+        output = combined;
+    }
+    output
+}
+
+/// Given a URI and a repo name, construct a local path starting with "/pfs"
+/// pointing to where we should download the file.
+pub fn uri_to_local_path(base_uri: &str, uri: &str, repo: &str) -> Result<String> {
+    // Check some preconditions. These could probably be assertions; other code
+    // should ensure that these are always true.
+    if !base_uri.ends_with('/') {
+        return Err(format_err!("expected {} to end with a '/'", base_uri));
+    }
+    if !uri.starts_with(base_uri) {
+        return Err(format_err!("expected {} to be in {}", uri, base_uri));
+    }
+
+    // Extract just the local portion of `uri` not included in `base_uri`.
+    let rel_uri = &uri[base_uri.len()..];
+    if rel_uri.is_empty() {
+        Err(format_err!("{:?} ends with '/'", uri))
+    } else {
+        Ok(format!("/pfs/{}/{}", repo, rel_uri))
+    }
+}
+
+#[test]
+fn uri_to_local_path_works() {
+    let path =
+        uri_to_local_path("gs://bucket/path/", "gs://bucket/path/data1.csv", "myrepo")
+            .unwrap();
+    assert_eq!(path, "/pfs/myrepo/data1.csv");
+
+    // Directories use this convention for now?
+    let dpath =
+        uri_to_local_path("gs://bucket/path/", "gs://bucket/path/data1/", "myrepo")
+            .unwrap();
+    assert_eq!(dpath, "/pfs/myrepo/data1/");
+}
+
+#[test]
+fn atom_and_cross_datums() {
+    let object = |uri: &str, size| ListedObject {
+        uri: uri.to_owned(),
+        etag: None,
+        generation: None,
+        size: Some(size),
+        last_modified: None,
+    };
+    let objects = vec![object("gs://b/in/a.csv", 1), object("gs://b/in/b.csv", 2)];
+    let datums = listed_objects_to_datums(
+        "gs://b/in",
+        "in",
+        Glob::TopLevelDirectoryEntries,
+        objects.clone(),
+        true,
+    )
+    .unwrap();
+    assert_eq!(datums.len(), 2);
+    assert_eq!(datums[1].input_files[0].local_path, "/pfs/in/b.csv");
+    assert!(datums[1].input_files[0].decompress);
+
+    let whole =
+        listed_objects_to_datums("gs://b/in", "in", Glob::WholeRepo, objects, false)
+            .unwrap();
+    assert_eq!(
+        whole[0].input_files,
+        vec![InputFileData {
+            uri: "gs://b/in/".to_owned(),
+            local_path: "/pfs/in/".to_owned(),
+            etag: None,
+            generation: None,
+            size: Some(3),
+            last_modified: None,
+            decompress: false,
+        }]
+    );
+
+    let crossed = cross_datums(vec![datums, whole]);
+    assert_eq!(crossed.len(), 2);
+    assert_eq!(crossed[0].input_files.len(), 2);
+    assert_eq!(crossed[0].input_files[1].uri, "gs://b/in/");
+    assert!(cross_datums(vec![]).is_empty());
+}
+
+#[test]
+fn partition_datums_merges_input_files() {
+    let datum = |name: &str, size| DatumData {
+        input_files: vec![InputFileData {
+            uri: format!("gs://bucket/in/{}", name),
+            local_path: format!("/pfs/in/{}", name),
+            etag: None,
+            generation: None,
+            size,
+            last_modified: None,
+            decompress: false,
+        }],
+    };
+    let datums = vec![
+        datum("big", Some(60_000_000_000)),
+        datum("a", Some(3_000)),
+        datum("b", Some(3_000)),
+    ];
+    let partitioned = partition_datums(
+        datums.clone(),
+        Glob::TopLevelDirectoryEntries,
+        2,
+        PartitionBy::Size,
+    )
+    .unwrap();
+    assert_eq!(partitioned.len(), 2);
+    assert_eq!(partitioned[0].input_files.len(), 1);
+    assert_eq!(partitioned[1].input_files.len(), 2);
+    assert_eq!(partitioned[1].listed_input_bytes(), Some(6_000));
+
+    let (new_datum, input_files) = partitioned[1]
+        .clone()
+        .into_new_datum_and_input_files(Uuid::new_v4(), 1);
+    assert_eq!(new_datum.listed_input_bytes, Some(6_000));
+    assert_eq!(input_files.len(), 2);
+    assert_eq!(datum("c", None).listed_input_bytes(), None);
+
+    assert!(
+        partition_datums(datums.clone(), Glob::WholeRepo, 2, PartitionBy::Size)
+            .is_err()
+    );
+    assert!(partition_datums(
+        datums,
+        Glob::TopLevelDirectoryEntries,
+        0,
+        PartitionBy::Count
+    )
+    .is_err());
+}
@@ -7,12 +7,20 @@ use serde::de::{Deserialize, DeserializeOwned};
 use serde_json;
 use tokio::{io::AsyncWriteExt, process::Command};
 
-use crate::prelude::*;
+use crate::{connect_via::ProxySettings, prelude::*};
+
+/// Build a `kubectl` command, using the Kubernetes context and namespace from
+/// our [`ProxySettings`], if any.
+pub fn kubectl_command() -> Command {
+    let mut command = Command::new("kubectl");
+    command.args(ProxySettings::current().kubectl_args());
+    command
+}
 
 /// Run `kubectl`, passing any output through to the console.
 #[instrument(level = "trace")]
 pub async fn kubectl(args: &[&str]) -> Result<()> {
-    let status = Command::new("kubectl")
+    let status = kubectl_command()
         .args(args)
         .status()
         .await
@@ -27,7 +35,7 @@ pub async fn kubectl(args: &[&str]) -> Result<()> {
 /// specified type.
 #[instrument(level = "trace")]
 pub async fn kubectl_parse_json<T: DeserializeOwned>(args: &[&str]) -> Result<T> {
-    let output = Command::new("kubectl")
+    let output = kubectl_command()
         .args(args)
         // Pass `stderr` through on console instead of capturing.
         .stderr(Stdio::inherit())
@@ -44,7 +52,7 @@ pub async fn kubectl_parse_json<T: DeserializeOwned>(args: &[&str]) -> Result<T>
 /// Run `kubectl` with the specified input.
 #[instrument(skip(input), level = "trace")]
 pub async fn kubectl_with_input(args: &[&str], input: &str) -> Result<()> {
-    let mut child = Command::new("kubectl")
+    let mut child = kubectl_command()
         .args(args)
         .stdin(Stdio::piped())
         .spawn()
@@ -65,10 +73,67 @@ pub async fn kubectl_with_input(args: &[&str], input: &str) -> Result<()> {
     Ok(())
 }
 
+/// Run `kubectl` with the specified input, and return its output.
+#[instrument(skip(input), level = "trace")]
+pub async fn kubectl_with_input_and_output(
+    args: &[&str],
+    input: &str,
+) -> Result<String> {
+    let mut child = kubectl_command()
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        // Pass `stderr` through on console instead of capturing.
+        .stderr(Stdio::inherit())
+        .spawn()
+        .with_context(|| format!("error starting kubectl with {:?}", args))?;
+    let mut stdin = child.stdin.take().expect("child stdin is missing");
+    stdin
+        .write_all(input.as_bytes())
+        .await
+        .with_context(|| format!("error writing input to kubectl {:?}", args))?;
+    drop(stdin); // Close stdin so kubectl knows we're done
+    let output = child
+        .wait_with_output()
+        .await
+        .with_context(|| format!("error running kubectl with {:?}", args))?;
+    if !output.status.success() {
+        return Err(format_err!("error running kubectl with {:?}", args));
+    }
+    String::from_utf8(output.stdout)
+        .with_context(|| format!("non-UTF-8 output from kubectl {:?}", args))
+}
+
+/// Apply a strategic merge `patch` to `manifest` locally, without talking to
+/// the cluster, and return the patched manifest (as JSON, which is also valid
+/// YAML).
+///
+/// We let `kubectl` do this, because it knows how to merge each kind of list
+/// in each Kubernetes resource.
+pub async fn strategic_merge_patch_local(
+    manifest: &str,
+    patch: &serde_json::Value,
+) -> Result<String> {
+    let patch = serde_json::to_string(patch).context("could not serialize patch")?;
+    kubectl_with_input_and_output(
+        &[
+            "patch",
+            "--local",
+            "--filename=-",
+            "--type=strategic",
+            "--patch",
+            &patch,
+            "--output=json",
+        ],
+        manifest,
+    )
+    .await
+}
+
 /// Does `kubectl` exit successfully when called with the specified arguments?
 #[instrument(level = "trace")]
 pub async fn kubectl_succeeds(args: &[&str]) -> Result<bool> {
-    let output = Command::new("kubectl").args(args).output().await?;
+    let output = kubectl_command().args(args).output().await?;
     Ok(output.status.success())
 }
 
@@ -232,6 +297,56 @@ pub async fn get_running_pod_names() -> Result<HashSet<String>> {
     Ok(names)
 }
 
+/// Get the image digests of the `worker` containers in the pods of our
+/// Kubernetes jobs, as reported by Kubernetes once each container has
+/// started. Returns a map from job names to digests, like `sha256:...`.
+#[instrument(level = "trace")]
+pub async fn get_worker_image_digests() -> Result<HashMap<String, String>> {
+    let pods = kubectl_parse_json::<serde_json::Value>(&[
+        "get",
+        "pods",
+        "--selector=created-by=falconeri",
+        "--output=json",
+    ])
+    .await?;
+    Ok(worker_image_digests(&pods))
+}
+
+/// Find the image digests of the `worker` containers in a list of pods.
+fn worker_image_digests(pods: &serde_json::Value) -> HashMap<String, String> {
+    let mut digests = HashMap::new();
+    let pods = pods["items"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    for pod in pods {
+        let Some(job_name) = pod["metadata"]["labels"]["job-name"].as_str() else {
+            continue;
+        };
+        let statuses = pod["status"]["containerStatuses"]
+            .as_array()
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        let digest = statuses
+            .iter()
+            .filter(|status| status["name"] == "worker")
+            .filter_map(|status| status["imageID"].as_str())
+            .find_map(image_id_digest);
+        if let Some(digest) = digest {
+            digests.insert(job_name.to_owned(), digest);
+        }
+    }
+    digests
+}
+
+/// Extract the digest from a container's `imageID`, which may look like
+/// `docker.io/library/alpine@sha256:...`, `docker-pullable://alpine@sha256:...`
+/// or just `sha256:...` for locally built images.
+fn image_id_digest(image_id: &str) -> Option<String> {
+    let digest = image_id.rsplit('@').next()?;
+    digest.starts_with("sha256:").then(|| digest.to_owned())
+}
+
 /// Get a set of all job names present on the cluster.
 #[instrument(level = "trace")]
 pub async fn get_all_job_names() -> Result<HashSet<String>> {
@@ -275,6 +390,18 @@ pub async fn delete(resource_id: &str) -> Result<()> {
     kubectl(&["delete", resource_id]).await
 }
 
+/// Delete the specified Kubernetes batch job (and its pods), if it exists.
+pub async fn delete_job(job_name: &str) -> Result<()> {
+    kubectl(&["delete", "job", job_name, "--ignore-not-found"]).await
+}
+
+/// Change the number of pods the specified Kubernetes batch job may run at
+/// once.
+pub async fn set_job_parallelism(job_name: &str, parallelism: i32) -> Result<()> {
+    let patch = format!(r#"{{"spec":{{"parallelism":{}}}}}"#, parallelism);
+    kubectl(&["patch", "job", job_name, "--type=merge", "-p", &patch]).await
+}
+
 /// Generate a hopefully unique tag for a Kubernetes resource. To keep
 /// Kubernetes happy, this must be a legal DNS name component (but we have a
 /// database constraint to enforce that).
@@ -309,3 +436,31 @@ pub fn use_local_image() -> bool {
         .map(|v| v == "true" || v == "1")
         .unwrap_or(false)
 }
+
+#[test]
+fn worker_image_digests_are_found() {
+    let pods = serde_json::json!({
+        "items": [
+            {
+                "metadata": { "labels": { "job-name": "job-a" } },
+                "status": { "containerStatuses": [
+                    { "name": "cache", "imageID": "docker.io/library/redis@sha256:aaa" },
+                    { "name": "worker", "imageID": "docker-pullable://ghcr.io/org/worker@sha256:bbb" },
+                ] },
+            },
+            {
+                // Still pulling its image.
+                "metadata": { "labels": { "job-name": "job-b" } },
+                "status": { "containerStatuses": [{ "name": "worker", "imageID": "" }] },
+            },
+            {
+                "metadata": { "labels": { "job-name": "job-c" } },
+                "status": {},
+            },
+        ],
+    });
+    let digests = worker_image_digests(&pods);
+    assert_eq!(digests.len(), 1);
+    assert_eq!(digests["job-a"], "sha256:bbb");
+    assert_eq!(image_id_digest("sha256:ccc").as_deref(), Some("sha256:ccc"));
+}
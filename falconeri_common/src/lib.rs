@@ -31,15 +31,24 @@ pub use uuid;
 
 pub mod connect_via;
 pub mod db;
+pub mod decompress;
+pub mod inputs;
 pub mod kubernetes;
 pub mod manifest;
 pub mod models;
+pub mod ops;
 pub mod pipeline;
 pub mod rest_api;
+pub mod schedule;
 mod schema;
 pub mod secret;
 pub mod storage;
+pub mod temp_dir;
+#[cfg(feature = "testing")]
+pub mod testing;
 pub mod tracing_support;
+pub mod vault;
+pub mod version;
 
 /// Common imports used by many modules.
 pub mod prelude {
@@ -70,8 +79,8 @@ pub use anyhow::Error;
 /// Result type for this crate's functions.
 pub use anyhow::Result;
 
-/// The version of `falconeri_common` that we're using. This can be used
-/// to make sure that our various clients and servers match.
+/// The version of `falconeri_common` that we're using. See [`version`] for
+/// how we decide whether clients and servers can work together.
 pub fn falconeri_common_version() -> semver::Version {
     env!("CARGO_PKG_VERSION")
         .parse::<semver::Version>()
@@ -0,0 +1,123 @@
+use diesel::dsl;
+use diesel_async::RunQueryDsl;
+use utoipa::ToSchema;
+
+use super::worker_token::{hash_token, random_token};
+use crate::{prelude::*, schema::*};
+
+/// A revocable token which lets people and scripts call `falconerid` without
+/// knowing our admin password, usually through an ingress. We only store a
+/// hash of each token, and we never return that hash from the API.
+#[derive(Clone, Debug, Deserialize, Queryable, Serialize, ToSchema)]
+pub struct ApiToken {
+    /// The unique ID of this token.
+    pub id: Uuid,
+    /// When this token was created.
+    pub created_at: NaiveDateTime,
+    /// A name describing who uses this token.
+    pub name: String,
+    /// When this token was revoked, if it has been.
+    pub revoked_at: Option<NaiveDateTime>,
+    /// The team this token belongs to, if any. Tokens with a team may only
+    /// submit and change jobs for that team.
+    pub team: Option<String>,
+}
+
+/// The columns to select when loading an `ApiToken`, in order.
+const API_TOKEN_COLUMNS: (
+    api_tokens::id,
+    api_tokens::created_at,
+    api_tokens::name,
+    api_tokens::revoked_at,
+    api_tokens::team,
+) = (
+    api_tokens::id,
+    api_tokens::created_at,
+    api_tokens::name,
+    api_tokens::revoked_at,
+    api_tokens::team,
+);
+
+impl ApiToken {
+    /// Create a new token named `name`, optionally belonging to `team`.
+    /// Returns the token's record and the token itself, which we can't
+    /// recover later.
+    #[instrument(skip_all, fields(name = %name), level = "trace")]
+    pub async fn mint(
+        name: &str,
+        team: Option<&str>,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<(ApiToken, String)> {
+        let token = random_token();
+        let api_token = diesel::insert_into(api_tokens::table)
+            .values((
+                api_tokens::name.eq(name),
+                api_tokens::token_hash.eq(hash_token(&token)),
+                api_tokens::team.eq(team),
+            ))
+            .returning(API_TOKEN_COLUMNS)
+            .get_result(conn)
+            .await
+            .with_context(|| format!("could not create API token {:?}", name))?;
+        Ok((api_token, token))
+    }
+
+    /// List all tokens, including revoked ones, oldest first.
+    #[instrument(skip_all, level = "trace")]
+    pub async fn list(conn: &mut AsyncPgConnection) -> Result<Vec<ApiToken>> {
+        api_tokens::table
+            .select(API_TOKEN_COLUMNS)
+            .order_by(api_tokens::created_at)
+            .load(conn)
+            .await
+            .context("could not list API tokens")
+    }
+
+    /// Revoke the token with ID `id`, so that it can no longer be used.
+    /// Returns `None` if there is no such token. Revoking a token twice is
+    /// harmless, and keeps the original `revoked_at`.
+    #[instrument(skip_all, fields(id = %id), level = "trace")]
+    pub async fn revoke(
+        id: Uuid,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<Option<ApiToken>> {
+        let revoked = diesel::update(
+            api_tokens::table
+                .find(id)
+                .filter(api_tokens::revoked_at.is_null()),
+        )
+        .set(api_tokens::revoked_at.eq(dsl::now))
+        .returning(API_TOKEN_COLUMNS)
+        .get_result(conn)
+        .await
+        .optional()
+        .with_context(|| format!("could not revoke API token {}", id))?;
+        if revoked.is_some() {
+            return Ok(revoked);
+        }
+        api_tokens::table
+            .find(id)
+            .select(API_TOKEN_COLUMNS)
+            .first(conn)
+            .await
+            .optional()
+            .with_context(|| format!("could not load API token {}", id))
+    }
+
+    /// Look up `token`, returning `None` if it doesn't exist or has been
+    /// revoked.
+    #[instrument(skip_all, level = "trace")]
+    pub async fn find_valid(
+        token: &str,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<Option<ApiToken>> {
+        api_tokens::table
+            .filter(api_tokens::token_hash.eq(hash_token(token)))
+            .filter(api_tokens::revoked_at.is_null())
+            .select(API_TOKEN_COLUMNS)
+            .first(conn)
+            .await
+            .optional()
+            .context("could not look up API token")
+    }
+}
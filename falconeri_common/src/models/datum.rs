@@ -1,6 +1,11 @@
-use std::fmt;
+use std::{collections::HashSet, fmt};
 
-use diesel_async::RunQueryDsl;
+use diesel::{
+    deserialize::{self, Queryable},
+    pg::Pg,
+    sql_types::BigInt,
+};
+use diesel_async::{scoped_futures::ScopedFutureExt, AsyncConnection, RunQueryDsl};
 use utoipa::ToSchema;
 
 use crate::{kubernetes, prelude::*, schema::*};
@@ -84,10 +89,16 @@ impl fmt::Display for DatumStateError {
 
 impl std::error::Error for DatumStateError {}
 
+/// Output and backtraces at least this long are compressed using zstd before
+/// we store them. Shorter text wouldn't get much smaller. This must match the
+/// `datums_uncompressed_text` index.
+pub const MIN_COMPRESSED_TEXT_BYTES: usize = 1024;
+
 /// A single chunk of work, consisting of one or more files.
-#[derive(
-    Associations, Debug, Deserialize, Identifiable, Queryable, Serialize, ToSchema,
-)]
+///
+/// When we load this from the database, we decompress `output` and
+/// `backtrace` if they were stored compressed.
+#[derive(Associations, Debug, Deserialize, Identifiable, Serialize, ToSchema)]
 #[diesel(belongs_to(Job, foreign_key = job_id))]
 pub struct Datum {
     /// The unique ID of this datum.
@@ -120,6 +131,362 @@ pub struct Datum {
     /// several queries, and (2) it gives us the option of allowing extra
     /// retries on a particular datum someday.
     pub maximum_allowed_run_count: i32,
+    /// When the worker started processing this datum.
+    pub started_at: Option<NaiveDateTime>,
+    /// When the worker finished downloading our input files.
+    pub download_completed_at: Option<NaiveDateTime>,
+    /// When our command finished running.
+    pub command_completed_at: Option<NaiveDateTime>,
+    /// When the worker finished uploading our output files.
+    pub upload_completed_at: Option<NaiveDateTime>,
+    /// How many bytes of input did we download?
+    pub input_bytes: Option<i64>,
+    /// How many bytes of output did we upload?
+    pub output_bytes: Option<i64>,
+    /// If `output` was too long to store in the database and was truncated,
+    /// this is where we uploaded the full output.
+    pub output_uri: Option<String>,
+    /// Was this datum reserved by a worker which is still busy with another
+    /// datum, so that it can download our inputs ahead of time?
+    #[serde(default)]
+    pub prefetched: bool,
+    /// If this datum failed, did the worker think it was worth retrying?
+    #[serde(default)]
+    pub failure_class: Option<FailureClass>,
+    /// A hash of this datum's input files, used to skip inputs which were
+    /// already processed. Missing for older datums.
+    #[serde(default)]
+    pub input_hash: Option<String>,
+    /// If this datum failed and is waiting to be retried, workers may not
+    /// reserve it until this time.
+    #[serde(default)]
+    pub retry_after: Option<NaiveDateTime>,
+    /// The total size of this datum's input files, as reported when we listed
+    /// them. Missing if we couldn't find the size of every file.
+    #[serde(default)]
+    pub listed_input_bytes: Option<i64>,
+    /// The stdout of the code which processed the datum, which may be
+    /// truncated like `output`. Missing for older datums, and for streaming
+    /// jobs, which upload stdout as an output file.
+    #[serde(default)]
+    pub stdout: Option<String>,
+    /// The stderr of the code which processed the datum, which may be
+    /// truncated like `output`. Missing for older datums.
+    #[serde(default)]
+    pub stderr: Option<String>,
+    /// The peak memory used by the code which processed the datum, in bytes.
+    /// Missing if the worker couldn't measure it.
+    #[serde(default)]
+    pub peak_memory_bytes: Option<i64>,
+    /// The CPU time used by the code which processed the datum, in seconds.
+    /// Missing if the worker couldn't measure it.
+    #[serde(default)]
+    pub cpu_seconds: Option<f64>,
+}
+
+/// Timestamps for each phase of processing a datum, as reported by the worker.
+/// Any phase which was never reached will be `None`.
+#[derive(AsChangeset, Clone, Debug, Default, Deserialize, Serialize, ToSchema)]
+#[diesel(table_name = datums, treat_none_as_null = true)]
+pub struct DatumTimings {
+    /// When the worker started processing this datum.
+    pub started_at: Option<NaiveDateTime>,
+    /// When the worker finished downloading our input files.
+    pub download_completed_at: Option<NaiveDateTime>,
+    /// When our command finished running.
+    pub command_completed_at: Option<NaiveDateTime>,
+    /// When the worker finished uploading our output files.
+    pub upload_completed_at: Option<NaiveDateTime>,
+}
+
+/// How much data the worker transferred while processing a datum. Any value
+/// the worker didn't measure will be `None`.
+#[derive(AsChangeset, Clone, Debug, Default, Deserialize, Serialize, ToSchema)]
+#[diesel(table_name = datums, treat_none_as_null = true)]
+pub struct DatumByteCounts {
+    /// How many bytes of input did we download?
+    pub input_bytes: Option<i64>,
+    /// How many bytes of output did we upload?
+    pub output_bytes: Option<i64>,
+}
+
+/// The stdout and stderr of the code which processed a datum, captured
+/// separately, as reported by the worker. Either may be `None` if the worker
+/// didn't capture it.
+#[derive(AsChangeset, Clone, Debug, Default, Deserialize, Serialize, ToSchema)]
+#[diesel(table_name = datums, treat_none_as_null = true)]
+pub struct DatumStreams {
+    /// The command's stdout.
+    pub stdout: Option<String>,
+    /// The command's stderr.
+    pub stderr: Option<String>,
+}
+
+/// The resources used by the code which processed a datum, as reported by the
+/// worker. Any value the worker couldn't measure will be `None`.
+#[derive(AsChangeset, Clone, Debug, Default, Deserialize, Serialize, ToSchema)]
+#[diesel(table_name = datums, treat_none_as_null = true)]
+pub struct DatumResourceUsage {
+    /// The peak resident memory of the command and its children, in bytes.
+    pub peak_memory_bytes: Option<i64>,
+    /// The user and system CPU time used by the command and its children, in
+    /// seconds.
+    pub cpu_seconds: Option<f64>,
+}
+
+/// Everything a worker reports about a datum when it finishes processing it,
+/// whether or not it succeeded.
+#[derive(Clone, Debug, Default)]
+pub struct DatumReport {
+    /// The output of processing the datum. This may be truncated if it was
+    /// too long.
+    pub output: String,
+    /// If `output` was truncated, where we uploaded the full output.
+    pub output_uri: Option<String>,
+    /// The command's stdout and stderr, captured separately.
+    pub streams: DatumStreams,
+    /// When each phase of processing the datum finished.
+    pub timings: DatumTimings,
+    /// How much data we transferred.
+    pub byte_counts: DatumByteCounts,
+    /// How much memory and CPU time our command used.
+    pub resource_usage: DatumResourceUsage,
+}
+
+/// The columns of a [`Datum`] needed to list it, without its inputs or
+/// potentially large output. Loading these is much cheaper than loading full
+/// datums for a job with many running or failed datums.
+#[derive(Clone, Debug, Deserialize, Queryable, Serialize, ToSchema)]
+pub struct DatumSummary {
+    /// The unique ID of this datum.
+    pub id: Uuid,
+    /// The current status of this datum.
+    pub status: Status,
+    /// The Kubernetes pod which most recently ran this datum.
+    pub pod_name: Option<String>,
+    /// The Kubernetes node which most recently ran this datum.
+    pub node_name: Option<String>,
+    /// When the most recent attempt to process this datum started.
+    pub started_at: Option<NaiveDateTime>,
+    /// When this datum was last updated.
+    pub updated_at: NaiveDateTime,
+    /// The error message for a failed datum.
+    pub error_message: Option<String>,
+}
+
+impl DatumSummary {
+    /// The columns to select when loading a `DatumSummary`, in order.
+    #[allow(clippy::type_complexity)]
+    pub const COLUMNS: (
+        datums::id,
+        datums::status,
+        datums::pod_name,
+        datums::node_name,
+        datums::started_at,
+        datums::updated_at,
+        datums::error_message,
+    ) = (
+        datums::id,
+        datums::status,
+        datums::pod_name,
+        datums::node_name,
+        datums::started_at,
+        datums::updated_at,
+        datums::error_message,
+    );
+}
+
+impl From<&Datum> for DatumSummary {
+    fn from(datum: &Datum) -> Self {
+        DatumSummary {
+            id: datum.id,
+            status: datum.status,
+            pod_name: datum.pod_name.clone(),
+            node_name: datum.node_name.clone(),
+            started_at: datum.started_at,
+            updated_at: datum.updated_at,
+            error_message: datum.error_message.clone(),
+        }
+    }
+}
+
+/// A datum as stored in the database, with `output` and `backtrace` either
+/// stored as plain text or compressed using zstd. See [`Datum`] for what the
+/// other fields mean.
+#[derive(Queryable)]
+struct StoredDatum {
+    id: Uuid,
+    created_at: NaiveDateTime,
+    updated_at: NaiveDateTime,
+    status: Status,
+    job_id: Uuid,
+    error_message: Option<String>,
+    node_name: Option<String>,
+    pod_name: Option<String>,
+    backtrace: Option<String>,
+    output: Option<String>,
+    attempted_run_count: i32,
+    maximum_allowed_run_count: i32,
+    started_at: Option<NaiveDateTime>,
+    download_completed_at: Option<NaiveDateTime>,
+    command_completed_at: Option<NaiveDateTime>,
+    upload_completed_at: Option<NaiveDateTime>,
+    input_bytes: Option<i64>,
+    output_bytes: Option<i64>,
+    output_uri: Option<String>,
+    prefetched: bool,
+    failure_class: Option<FailureClass>,
+    input_hash: Option<String>,
+    retry_after: Option<NaiveDateTime>,
+    listed_input_bytes: Option<i64>,
+    stdout: Option<String>,
+    stderr: Option<String>,
+    peak_memory_bytes: Option<i64>,
+    cpu_seconds: Option<f64>,
+    output_zstd: Option<Vec<u8>>,
+    // Only used by `db::text_compression_stats`.
+    _output_zstd_length: Option<i64>,
+    backtrace_zstd: Option<Vec<u8>>,
+    // Only used by `db::text_compression_stats`.
+    _backtrace_zstd_length: Option<i64>,
+}
+
+impl Queryable<datums::SqlType, Pg> for Datum {
+    type Row = <StoredDatum as Queryable<datums::SqlType, Pg>>::Row;
+
+    fn build(row: Self::Row) -> deserialize::Result<Self> {
+        let stored = StoredDatum::build(row)?;
+        let backtrace = load_text(stored.backtrace, stored.backtrace_zstd)
+            .with_context(|| {
+                format!("could not load backtrace of datum {}", stored.id)
+            })?;
+        let output =
+            load_text(stored.output, stored.output_zstd).with_context(|| {
+                format!("could not load output of datum {}", stored.id)
+            })?;
+        Ok(Datum {
+            id: stored.id,
+            created_at: stored.created_at,
+            updated_at: stored.updated_at,
+            status: stored.status,
+            job_id: stored.job_id,
+            error_message: stored.error_message,
+            node_name: stored.node_name,
+            pod_name: stored.pod_name,
+            backtrace,
+            output,
+            attempted_run_count: stored.attempted_run_count,
+            maximum_allowed_run_count: stored.maximum_allowed_run_count,
+            started_at: stored.started_at,
+            download_completed_at: stored.download_completed_at,
+            command_completed_at: stored.command_completed_at,
+            upload_completed_at: stored.upload_completed_at,
+            input_bytes: stored.input_bytes,
+            output_bytes: stored.output_bytes,
+            output_uri: stored.output_uri,
+            prefetched: stored.prefetched,
+            failure_class: stored.failure_class,
+            input_hash: stored.input_hash,
+            retry_after: stored.retry_after,
+            listed_input_bytes: stored.listed_input_bytes,
+            stdout: stored.stdout,
+            stderr: stored.stderr,
+            peak_memory_bytes: stored.peak_memory_bytes,
+            cpu_seconds: stored.cpu_seconds,
+        })
+    }
+}
+
+/// A datum's output, ready to store in the database.
+#[derive(AsChangeset)]
+#[diesel(table_name = datums, treat_none_as_null = true)]
+struct StoredOutput<'a> {
+    output: Option<&'a str>,
+    output_zstd: Option<Vec<u8>>,
+    output_zstd_length: Option<i64>,
+}
+
+impl<'a> StoredOutput<'a> {
+    /// Prepare `output` for storage, compressing it if it's long.
+    fn new(output: &'a str) -> Result<Self> {
+        Ok(match compress_text(output)? {
+            Some((zstd, length)) => StoredOutput {
+                output: None,
+                output_zstd: Some(zstd),
+                output_zstd_length: Some(length),
+            },
+            None => StoredOutput {
+                output: Some(output),
+                output_zstd: None,
+                output_zstd_length: None,
+            },
+        })
+    }
+}
+
+/// A datum's backtrace, ready to store in the database.
+#[derive(AsChangeset)]
+#[diesel(table_name = datums, treat_none_as_null = true)]
+struct StoredBacktrace<'a> {
+    backtrace: Option<&'a str>,
+    backtrace_zstd: Option<Vec<u8>>,
+    backtrace_zstd_length: Option<i64>,
+}
+
+impl<'a> StoredBacktrace<'a> {
+    /// Prepare `backtrace` for storage, compressing it if it's long.
+    fn new(backtrace: &'a str) -> Result<Self> {
+        Ok(match compress_text(backtrace)? {
+            Some((zstd, length)) => StoredBacktrace {
+                backtrace: None,
+                backtrace_zstd: Some(zstd),
+                backtrace_zstd_length: Some(length),
+            },
+            None => StoredBacktrace {
+                backtrace: Some(backtrace),
+                backtrace_zstd: None,
+                backtrace_zstd_length: None,
+            },
+        })
+    }
+}
+
+/// Output and backtraces stored before we compressed them, loaded by
+/// [`Datum::compress_stored_text`].
+#[derive(QueryableByName)]
+struct UncompressedText {
+    #[diesel(sql_type = diesel::sql_types::Uuid)]
+    id: Uuid,
+    #[diesel(sql_type = diesel::sql_types::Nullable<diesel::sql_types::Text>)]
+    output: Option<String>,
+    #[diesel(sql_type = diesel::sql_types::Nullable<diesel::sql_types::Text>)]
+    backtrace: Option<String>,
+}
+
+/// Compress `text` if it's at least [`MIN_COMPRESSED_TEXT_BYTES`] long,
+/// returning the compressed text and its original length.
+fn compress_text(text: &str) -> Result<Option<(Vec<u8>, i64)>> {
+    if text.len() < MIN_COMPRESSED_TEXT_BYTES {
+        return Ok(None);
+    }
+    let zstd =
+        zstd::bulk::compress(text.as_bytes(), 0).context("could not compress text")?;
+    Ok(Some((zstd, cast::i64(text.len())?)))
+}
+
+/// Load text which may have been stored as `plain` text, or compressed as
+/// `zstd`.
+fn load_text(plain: Option<String>, zstd: Option<Vec<u8>>) -> Result<Option<String>> {
+    match zstd {
+        Some(zstd) => {
+            let bytes =
+                zstd::decode_all(&zstd[..]).context("could not decompress text")?;
+            Ok(Some(
+                String::from_utf8(bytes).context("decompressed text is not UTF-8")?,
+            ))
+        }
+        None => Ok(plain),
+    }
 }
 
 impl Datum {
@@ -133,7 +500,8 @@ impl Datum {
             .with_context(|| format!("could not load datum {}", id))
     }
 
-    /// Find all datums with the specified status that belong to a running job.
+    /// Find all datums with the specified status that belong to a running or
+    /// streaming job.
     #[instrument(skip_all, fields(status = %status), level = "trace")]
     pub async fn active_with_status(
         status: Status,
@@ -141,7 +509,7 @@ impl Datum {
     ) -> Result<Vec<Datum>> {
         let datums = datums::table
             .inner_join(jobs::table)
-            .filter(jobs::status.eq(Status::Running))
+            .filter(jobs::status.eq_any(vec![Status::Running, Status::Streaming]))
             .filter(datums::status.eq(status))
             .select(datums::all_columns)
             .load::<Datum>(conn)
@@ -173,14 +541,15 @@ impl Datum {
 
     /// Find all datums which have errored, but that we can re-run.
     ///
-    /// This will only return datums associated with running jobs.
+    /// This will only return datums associated with running or streaming jobs.
     #[instrument(skip_all, level = "trace")]
     pub async fn rerunable(conn: &mut AsyncPgConnection) -> Result<Vec<Datum>> {
         let datums = datums::table
             .inner_join(jobs::table)
-            .filter(jobs::status.eq(Status::Running))
+            .filter(jobs::status.eq_any(vec![Status::Running, Status::Streaming]))
             .filter(datums::status.eq(Status::Error))
             .filter(datums::attempted_run_count.lt(datums::maximum_allowed_run_count))
+            .filter(datums::failure_class.is_distinct_from(FailureClass::Permanent))
             .select(datums::all_columns)
             .load::<Datum>(conn)
             .await
@@ -198,6 +567,7 @@ impl Datum {
     pub fn is_rerunable(&self) -> bool {
         self.status == Status::Error
             && self.attempted_run_count < self.maximum_allowed_run_count
+            && self.failure_class != Some(FailureClass::Permanent)
     }
 
     /// Get the input files for this datum.
@@ -290,7 +660,7 @@ impl Datum {
     #[instrument(skip_all, fields(datum = %self.id), level = "trace")]
     pub async fn mark_as_done(
         &mut self,
-        output: &str,
+        report: &DatumReport,
         conn: &mut AsyncPgConnection,
     ) -> Result<()> {
         let now = Utc::now().naive_utc();
@@ -298,7 +668,12 @@ impl Datum {
             .set((
                 datums::updated_at.eq(now),
                 datums::status.eq(&Status::Done),
-                datums::output.eq(output),
+                StoredOutput::new(&report.output)?,
+                datums::output_uri.eq(&report.output_uri),
+                &report.streams,
+                &report.timings,
+                &report.byte_counts,
+                &report.resource_usage,
             ))
             .get_result(conn)
             .await
@@ -310,9 +685,10 @@ impl Datum {
     #[instrument(skip_all, fields(datum = %self.id), level = "trace")]
     pub async fn mark_as_error(
         &mut self,
-        output: &str,
+        report: &DatumReport,
         error_message: &str,
         backtrace: &str,
+        failure_class: Option<FailureClass>,
         conn: &mut AsyncPgConnection,
     ) -> Result<()> {
         let now = Utc::now().naive_utc();
@@ -320,9 +696,15 @@ impl Datum {
             .set((
                 datums::updated_at.eq(now),
                 datums::status.eq(&Status::Error),
-                datums::output.eq(output),
+                StoredOutput::new(&report.output)?,
+                datums::output_uri.eq(&report.output_uri),
+                &report.streams,
                 datums::error_message.eq(&error_message),
-                datums::backtrace.eq(&backtrace),
+                StoredBacktrace::new(backtrace)?,
+                datums::failure_class.eq(failure_class),
+                &report.timings,
+                &report.byte_counts,
+                &report.resource_usage,
             ))
             .get_result(conn)
             .await
@@ -330,13 +712,16 @@ impl Datum {
         Ok(())
     }
 
-    /// Mark this datum as eligible to be re-run another time.
+    /// Mark this datum as eligible to be re-run another time. If
+    /// `retry_delay` is specified, workers may not reserve it until that much
+    /// time has passed.
     ///
     /// We assume that the datum's row is locked by `lock_for_update` when we
     /// are called.
     #[instrument(skip_all, fields(datum = %self.id), level = "trace")]
     pub async fn mark_as_eligible_for_rerun(
         &mut self,
+        retry_delay: Option<chrono::Duration>,
         conn: &mut AsyncPgConnection,
     ) -> Result<()> {
         let now = Utc::now().naive_utc();
@@ -344,6 +729,8 @@ impl Datum {
             .set((
                 datums::updated_at.eq(now),
                 datums::status.eq(&Status::Ready),
+                datums::failure_class.eq(None::<FailureClass>),
+                datums::retry_after.eq(retry_delay.map(|delay| now + delay)),
                 // Don't do this here! This is done when we start running in
                 // `actually_reserve_next_datum`.
                 //
@@ -355,6 +742,146 @@ impl Datum {
         Ok(())
     }
 
+    /// What our output should be after a worker appends `chunk`, starting at
+    /// byte `offset` of the output it has sent so far for this attempt.
+    /// Returns `None` if the worker already sent `chunk`, and is retrying. An
+    /// `offset` of 0 replaces any output left over from earlier attempts.
+    pub fn output_after_append(
+        &self,
+        offset: usize,
+        chunk: &str,
+    ) -> Result<Option<String>> {
+        let existing = self.output.as_deref().unwrap_or("");
+        if offset == 0 {
+            Ok(Some(chunk.to_owned()))
+        } else if existing.len() == offset {
+            Ok(Some(format!("{}{}", existing, chunk)))
+        } else if existing.len() == offset + chunk.len() && existing.ends_with(chunk) {
+            Ok(None)
+        } else {
+            Err(format_err!(
+                "cannot append output at offset {} to datum {}, which has {} bytes of output",
+                offset,
+                self.id,
+                existing.len()
+            ))
+        }
+    }
+
+    /// Replace the output of this running datum, so that people can watch
+    /// it. The worker replaces it again when the datum finishes.
+    ///
+    /// We assume that the datum's row is locked by `lock_and_verify_owner`
+    /// when we are called.
+    #[instrument(skip_all, fields(datum = %self.id), level = "trace")]
+    pub async fn update_running_output(
+        &mut self,
+        output: String,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<()> {
+        diesel::update(datums::table.filter(datums::id.eq(&self.id)))
+            .set(StoredOutput::new(&output)?)
+            .execute(conn)
+            .await
+            .context("can't update datum output")?;
+        self.output = Some(output);
+        Ok(())
+    }
+
+    /// Compress the output and backtraces of up to `limit` datums which were
+    /// stored before we started compressing them. Running datums are skipped,
+    /// because their workers may still be appending output. Returns the
+    /// number of datums we compressed.
+    #[instrument(skip_all, level = "trace")]
+    pub async fn compress_stored_text(
+        limit: i64,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<usize> {
+        conn.transaction(|conn| {
+            async move {
+                // This matches the `datums_uncompressed_text` index, so we
+                // don't need to scan the whole table.
+                let rows = diesel::sql_query(
+                    "SELECT id, output, backtrace FROM datums \
+                     WHERE (octet_length(output) >= 1024 OR octet_length(backtrace) >= 1024) \
+                         AND status <> 'running' \
+                     LIMIT $1 \
+                     FOR UPDATE SKIP LOCKED",
+                )
+                .bind::<BigInt, _>(limit)
+                .load::<UncompressedText>(conn)
+                .await
+                .context("could not load uncompressed datum output")?;
+                for row in &rows {
+                    let output = row.output.as_deref().map(StoredOutput::new).transpose()?;
+                    let backtrace = row
+                        .backtrace
+                        .as_deref()
+                        .map(StoredBacktrace::new)
+                        .transpose()?;
+                    diesel::update(datums::table.find(row.id))
+                        .set((output, backtrace))
+                        .execute(conn)
+                        .await
+                        .with_context(|| {
+                            format!("could not compress output of datum {}", row.id)
+                        })?;
+                }
+                Ok::<_, Error>(rows.len())
+            }
+            .scope_boxed()
+        })
+        .await
+    }
+
+    /// Return this datum to `Status::Ready` without counting the current
+    /// attempt, because the worker processing it is being shut down.
+    ///
+    /// We assume that the datum's row is locked by `lock_and_verify_owner`
+    /// when we are called.
+    #[instrument(skip_all, fields(datum = %self.id), level = "trace")]
+    pub async fn release(&mut self, conn: &mut AsyncPgConnection) -> Result<()> {
+        let now = Utc::now().naive_utc();
+        *self = diesel::update(datums::table.filter(datums::id.eq(&self.id)))
+            .set((
+                datums::updated_at.eq(now),
+                datums::status.eq(&Status::Ready),
+                // Undo the increment in `actually_reserve_next_datum`.
+                datums::attempted_run_count.eq(datums::attempted_run_count - 1),
+                datums::prefetched.eq(false),
+            ))
+            .get_result(conn)
+            .await
+            .context("can't release datum")?;
+        Ok(())
+    }
+
+    /// Cancel all datums belonging to `job_id` which have not yet finished.
+    /// Used when we give up on a job early.
+    #[instrument(skip_all, fields(job = %job_id), level = "trace")]
+    pub async fn cancel_unfinished_for_job_id(
+        job_id: Uuid,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<()> {
+        let now = Utc::now().naive_utc();
+        let canceled = diesel::update(
+            datums::table.filter(
+                datums::job_id
+                    .eq(&job_id)
+                    .and(datums::status.eq_any(vec![Status::Ready, Status::Running])),
+            ),
+        )
+        .set((
+            datums::updated_at.eq(now),
+            datums::status.eq(&Status::Canceled),
+        ))
+        .execute(conn)
+        .await
+        .context("can't cancel unfinished datums")?;
+        debug!("canceled {} unfinished datums", canceled);
+        Ok(())
+    }
+
     /// Update the status of our associate job, if it has finished.
     ///
     /// This calls [`Job::update_status_if_done`].
@@ -367,6 +894,30 @@ impl Datum {
         job.update_status_if_done(conn).await
     }
 
+    /// When a worker finishes its current datum, any datum which it
+    /// prefetched becomes its new current datum.
+    #[instrument(skip_all, fields(job = %job_id, pod_name = %pod_name), level = "trace")]
+    pub async fn promote_prefetched_for_pod(
+        job_id: Uuid,
+        pod_name: &str,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<()> {
+        diesel::update(
+            datums::table.filter(
+                datums::job_id
+                    .eq(&job_id)
+                    .and(datums::pod_name.eq(pod_name))
+                    .and(datums::status.eq(Status::Running))
+                    .and(datums::prefetched.eq(true)),
+            ),
+        )
+        .set(datums::prefetched.eq(false))
+        .execute(conn)
+        .await
+        .context("could not promote prefetched datum")?;
+        Ok(())
+    }
+
     /// Generate a sample value for testing.
     pub fn factory(job: &Job) -> Self {
         let now = Utc::now().naive_utc();
@@ -383,8 +934,50 @@ impl Datum {
             output: None,
             attempted_run_count: 0,
             maximum_allowed_run_count: 1,
+            started_at: None,
+            download_completed_at: None,
+            command_completed_at: None,
+            upload_completed_at: None,
+            input_bytes: None,
+            output_bytes: None,
+            output_uri: None,
+            prefetched: false,
+            failure_class: None,
+            input_hash: None,
+            retry_after: None,
+            listed_input_bytes: None,
+            stdout: None,
+            stderr: None,
+            peak_memory_bytes: None,
+            cpu_seconds: None,
         }
     }
+
+    /// Of `input_hashes`, which have already been processed successfully by a
+    /// job for the pipeline `pipeline_name`?
+    #[instrument(skip_all, fields(pipeline_name = %pipeline_name), level = "trace")]
+    pub async fn processed_input_hashes(
+        pipeline_name: &str,
+        input_hashes: &[String],
+        conn: &mut AsyncPgConnection,
+    ) -> Result<HashSet<String>> {
+        let hashes = datums::table
+            .inner_join(jobs::table)
+            .filter(datums::status.eq(Status::Done))
+            .filter(datums::input_hash.eq_any(input_hashes))
+            .filter(
+                jobs::pipeline_spec
+                    .retrieve_as_object("pipeline")
+                    .retrieve_as_text("name")
+                    .eq(pipeline_name),
+            )
+            .select(datums::input_hash)
+            .distinct()
+            .load::<Option<String>>(conn)
+            .await
+            .context("could not look up previously processed datums")?;
+        Ok(hashes.into_iter().flatten().collect())
+    }
 }
 
 /// Data required to create a new `Datum`.
@@ -401,6 +994,12 @@ pub struct NewDatum {
     /// How many times are we allowed to attempt to process this datum before
     /// failing for good?
     pub maximum_allowed_run_count: i32,
+    /// The initial status of this datum. Normally `Status::Ready`.
+    pub status: Status,
+    /// A hash of this datum's input files. See `NewInputFile::input_hash`.
+    pub input_hash: Option<String>,
+    /// The total size of this datum's input files, if we know it.
+    pub listed_input_bytes: Option<i64>,
 }
 
 impl NewDatum {
@@ -418,4 +1017,63 @@ impl NewDatum {
             .context("error inserting datums")?;
         Ok(())
     }
+
+    /// Insert new datums using PostgreSQL's `COPY FROM STDIN`. This is much
+    /// faster than [`NewDatum::insert_all`] for large jobs, and it isn't
+    /// limited by the maximum number of bind parameters per statement.
+    #[instrument(skip_all, level = "trace")]
+    pub async fn copy_in(datums: &[Self], conn: &mut AsyncPgConnection) -> Result<()> {
+        trace!(datum_count = datums.len(), "copying in datums");
+        diesel::copy_from(datums::table)
+            .from_insertable(datums)
+            .execute(conn)
+            .await
+            .context("error copying in datums")?;
+        Ok(())
+    }
+}
+
+#[test]
+fn output_after_append_handles_retries_and_new_attempts() {
+    let job = Job::factory();
+    let mut datum = Datum::factory(&job);
+    datum.output = Some("old attempt".to_owned());
+    assert_eq!(
+        datum.output_after_append(0, "abc").unwrap().as_deref(),
+        Some("abc")
+    );
+    datum.output = Some("abc".to_owned());
+    assert_eq!(
+        datum.output_after_append(3, "def").unwrap().as_deref(),
+        Some("abcdef")
+    );
+    datum.output = Some("abcdef".to_owned());
+    assert_eq!(datum.output_after_append(3, "def").unwrap(), None);
+    assert!(datum.output_after_append(1, "xyz").is_err());
+}
+
+#[test]
+fn long_text_is_compressed() {
+    assert!(compress_text("short").unwrap().is_none());
+    let output = StoredOutput::new("short").unwrap();
+    assert_eq!(output.output, Some("short"));
+    assert!(output.output_zstd.is_none());
+
+    let long = "line of output\n".repeat(1000);
+    let backtrace = StoredBacktrace::new(&long).unwrap();
+    assert!(backtrace.backtrace.is_none());
+    assert_eq!(
+        backtrace.backtrace_zstd_length,
+        Some(cast::i64(long.len()).unwrap())
+    );
+    let zstd = backtrace.backtrace_zstd.unwrap();
+    assert!(zstd.len() < long.len() / 10);
+    assert_eq!(load_text(None, Some(zstd)).unwrap(), Some(long));
+    assert_eq!(
+        load_text(Some("plain".to_owned()), None)
+            .unwrap()
+            .as_deref(),
+        Some("plain")
+    );
+    assert!(load_text(None, Some(b"not zstd".to_vec())).is_err());
 }
@@ -1,7 +1,11 @@
+use std::collections::HashSet;
+
+use diesel::dsl;
 use diesel_async::RunQueryDsl;
+use sha2::{Digest, Sha256};
 use utoipa::ToSchema;
 
-use crate::{prelude::*, schema::*};
+use crate::{prelude::*, schema::*, storage::ListedObject};
 
 /// An input file which needs to be downloaded to the worker container.
 #[derive(
@@ -21,6 +25,24 @@ pub struct InputFile {
     pub local_path: String,
     /// The job to which this input file belongs.
     pub job_id: Uuid,
+    /// The entity tag of the object when we created this datum, if known.
+    #[serde(default)]
+    pub etag: Option<String>,
+    /// The generation or version ID of the object when we created this datum,
+    /// if known.
+    #[serde(default)]
+    pub generation: Option<String>,
+    /// Should the worker decompress this file after downloading it? See
+    /// [`crate::decompress`].
+    #[serde(default)]
+    pub decompress: bool,
+    /// The size of the object in bytes when we created this datum, if known.
+    #[serde(default)]
+    pub size: Option<i64>,
+    /// When the object was last modified, as of when we created this datum,
+    /// if known.
+    #[serde(default)]
+    pub last_modified: Option<NaiveDateTime>,
 }
 
 impl InputFile {
@@ -42,6 +64,49 @@ impl InputFile {
             .grouped_by(datums))
     }
 
+    /// Get the URIs of all the input files belonging to a job.
+    #[instrument(skip_all, fields(job = %job_id), level = "trace")]
+    pub async fn uris_for_job(
+        job_id: Uuid,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<HashSet<String>> {
+        let uris = input_files::table
+            .filter(input_files::job_id.eq(job_id))
+            .select(input_files::uri)
+            .load::<String>(conn)
+            .await
+            .with_context(|| {
+                format!("could not load input files for job {}", job_id)
+            })?;
+        Ok(uris.into_iter().collect())
+    }
+
+    /// Count the input files belonging to a job, and add up their sizes.
+    #[instrument(skip_all, fields(job = %job_id), level = "trace")]
+    pub async fn totals_for_job(
+        job_id: Uuid,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<InputFileTotals> {
+        use diesel::sql_types::{BigInt, Nullable};
+
+        let (file_count, sized_file_count, total_bytes): (i64, i64, Option<i64>) =
+            input_files::table
+                .filter(input_files::job_id.eq(job_id))
+                .select(dsl::sql::<(BigInt, BigInt, Nullable<BigInt>)>(
+                    "count(*), count(size), sum(size)::int8",
+                ))
+                .get_result(conn)
+                .await
+                .with_context(|| {
+                    format!("could not total input files for job {}", job_id)
+                })?;
+        Ok(InputFileTotals {
+            file_count: cast::u64(file_count)?,
+            sized_file_count: cast::u64(sized_file_count)?,
+            total_bytes,
+        })
+    }
+
     /// Generate a sample value for testing.
     pub fn factory(datum: &Datum) -> Self {
         let now = Utc::now().naive_utc();
@@ -52,12 +117,28 @@ impl InputFile {
             uri: "gs://example-bucket/input/file.csv".to_owned(),
             local_path: "/pfs/input/file.csv".to_owned(),
             job_id: datum.job_id,
+            etag: None,
+            generation: None,
+            decompress: false,
+            size: Some(1024),
+            last_modified: Some(now),
         }
     }
 }
 
+/// The number of input files belonging to a job, and their total size.
+#[derive(Debug, Default, Deserialize, Serialize, ToSchema)]
+pub struct InputFileTotals {
+    /// The number of input files.
+    pub file_count: u64,
+    /// The number of input files whose size we know.
+    pub sized_file_count: u64,
+    /// The total size of the input files whose size we know.
+    pub total_bytes: Option<i64>,
+}
+
 /// Data required to create a new `InputFile`.
-#[derive(Debug, Insertable)]
+#[derive(Clone, Debug, Insertable)]
 #[diesel(table_name = input_files)]
 pub struct NewInputFile {
     /// The ID of the datum to which this file belongs.
@@ -68,9 +149,77 @@ pub struct NewInputFile {
     pub local_path: String,
     /// The job to which this input file belongs.
     pub job_id: Uuid,
+    /// The entity tag of the object, if known.
+    pub etag: Option<String>,
+    /// The generation or version ID of the object, if known.
+    pub generation: Option<String>,
+    /// Should the worker decompress this file after downloading it?
+    pub decompress: bool,
+    /// The size of the object in bytes, if known.
+    pub size: Option<i64>,
+    /// When the object was last modified, if known.
+    pub last_modified: Option<NaiveDateTime>,
 }
 
 impl NewInputFile {
+    /// Is this input file a directory?
+    pub fn is_directory(&self) -> bool {
+        self.uri.ends_with('/')
+    }
+
+    /// Compute a hash of a datum's input files, as a lowercase hex string. This
+    /// doesn't depend on the order of the files, but it does include their
+    /// etags and generations, so it changes when an object is replaced.
+    ///
+    /// Directories don't have etags or generations, so this can't tell when
+    /// their contents change. For datums with directory inputs, use
+    /// [`NewInputFile::input_hash_with_directory_contents`] instead.
+    pub fn input_hash(input_files: &[Self]) -> String {
+        Self::input_hash_with_directory_contents(input_files, &HashMap::new())
+    }
+
+    /// Like [`NewInputFile::input_hash`], but also include every object in
+    /// `directory_contents`, which maps the URI of each directory in
+    /// `input_files` to all the objects under it, so that the hash changes
+    /// when anything in those directories changes.
+    pub fn input_hash_with_directory_contents(
+        input_files: &[Self],
+        directory_contents: &HashMap<String, Vec<ListedObject>>,
+    ) -> String {
+        let entry = |local_path: &str,
+                     uri: &str,
+                     etag: Option<&str>,
+                     generation: Option<&str>| {
+            format!(
+                "{}\0{}\0{}\0{}\n",
+                local_path,
+                uri,
+                etag.unwrap_or(""),
+                generation.unwrap_or(""),
+            )
+        };
+        let mut entries = vec![];
+        for f in input_files {
+            entries.push(entry(
+                &f.local_path,
+                &f.uri,
+                f.etag.as_deref(),
+                f.generation.as_deref(),
+            ));
+            for object in directory_contents.get(&f.uri).into_iter().flatten() {
+                entries.push(entry(
+                    &f.local_path,
+                    &object.uri,
+                    object.etag.as_deref(),
+                    object.generation.as_deref(),
+                ));
+            }
+        }
+        entries.sort();
+        let digest = Sha256::digest(entries.concat().as_bytes());
+        digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
     /// Insert a new job into the database.
     #[instrument(skip_all, level = "trace")]
     pub async fn insert_all(
@@ -88,4 +237,106 @@ impl NewInputFile {
             .context("error inserting input file")?;
         Ok(())
     }
+
+    /// Insert new input files using PostgreSQL's `COPY FROM STDIN`. This is
+    /// much faster than [`NewInputFile::insert_all`] for large jobs, and it
+    /// isn't limited by the maximum number of bind parameters per statement.
+    #[instrument(skip_all, level = "trace")]
+    pub async fn copy_in(
+        input_files: &[Self],
+        conn: &mut AsyncPgConnection,
+    ) -> Result<()> {
+        trace!(
+            input_file_count = input_files.len(),
+            "copying in input files"
+        );
+        diesel::copy_from(input_files::table)
+            .from_insertable(input_files)
+            .execute(conn)
+            .await
+            .context("error copying in input files")?;
+        Ok(())
+    }
+}
+
+#[test]
+fn input_hash_ignores_file_order() {
+    let file = |uri: &str, local_path: &str| NewInputFile {
+        datum_id: Uuid::new_v4(),
+        uri: uri.to_owned(),
+        local_path: local_path.to_owned(),
+        job_id: Uuid::new_v4(),
+        etag: None,
+        generation: None,
+        decompress: false,
+        size: None,
+        last_modified: None,
+    };
+    let a = file("gs://bucket/a.csv", "/pfs/in/a.csv");
+    let b = file("gs://bucket/b.csv", "/pfs/in/b.csv");
+    let hash = NewInputFile::input_hash(&[a.clone(), b.clone()]);
+    assert_eq!(hash.len(), 64);
+    assert_eq!(hash, NewInputFile::input_hash(&[b, a.clone()]));
+    assert_ne!(hash, NewInputFile::input_hash(&[a]));
+}
+
+#[test]
+fn input_hash_changes_when_object_is_replaced() {
+    let file = |generation: &str| NewInputFile {
+        datum_id: Uuid::new_v4(),
+        uri: "gs://bucket/a.csv".to_owned(),
+        local_path: "/pfs/in/a.csv".to_owned(),
+        job_id: Uuid::new_v4(),
+        etag: Some("CJ7f".to_owned()),
+        generation: Some(generation.to_owned()),
+        decompress: false,
+        size: None,
+        last_modified: None,
+    };
+    assert_eq!(
+        NewInputFile::input_hash(&[file("1")]),
+        NewInputFile::input_hash(&[file("1")]),
+    );
+    assert_ne!(
+        NewInputFile::input_hash(&[file("1")]),
+        NewInputFile::input_hash(&[file("2")]),
+    );
+}
+
+#[test]
+fn input_hash_changes_when_directory_contents_change() {
+    let dir = NewInputFile {
+        datum_id: Uuid::new_v4(),
+        uri: "gs://bucket/in/".to_owned(),
+        local_path: "/pfs/in/".to_owned(),
+        job_id: Uuid::new_v4(),
+        etag: None,
+        generation: None,
+        decompress: false,
+        size: None,
+        last_modified: None,
+    };
+    assert!(dir.is_directory());
+    let contents = |generation: &str| {
+        HashMap::from([(
+            dir.uri.clone(),
+            vec![ListedObject {
+                uri: "gs://bucket/in/a.csv".to_owned(),
+                generation: Some(generation.to_owned()),
+                ..ListedObject::default()
+            }],
+        )])
+    };
+    let files = [dir.clone()];
+    let hash =
+        NewInputFile::input_hash_with_directory_contents(&files, &contents("1"));
+    assert_eq!(
+        hash,
+        NewInputFile::input_hash_with_directory_contents(&files, &contents("1")),
+    );
+    assert_ne!(
+        hash,
+        NewInputFile::input_hash_with_directory_contents(&files, &contents("2")),
+    );
+    assert_ne!(hash, NewInputFile::input_hash(&files));
 }
@@ -1,13 +1,45 @@
+use std::error;
+
 use cast;
 use diesel::dsl;
 use diesel_async::{scoped_futures::ScopedFutureExt, AsyncConnection, RunQueryDsl};
 use serde_json;
 use utoipa::ToSchema;
 
-use crate::{prelude::*, schema::*};
+use crate::{
+    pipeline::{Input, ResourceRequests},
+    prelude::*,
+    schema::*,
+};
+
+/// An identical job is already running, and the caller didn't ask us to start
+/// another one anyway.
+#[derive(Debug)]
+pub struct DuplicateJob {
+    /// The name of the job which is already running.
+    pub job_name: String,
+}
+
+impl fmt::Display for DuplicateJob {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "an identical job is already running: {} (use --force to run it anyway)",
+            self.job_name
+        )
+    }
+}
+
+impl error::Error for DuplicateJob {}
+
+/// The first half of the PostgreSQL advisory lock key used by
+/// [`Job::lock_spec_hash`]. The second half is a hash of the spec hash. This is
+/// arbitrary, but it must be the same in every `falconerid`. (It spells "spec"
+/// in ASCII.)
+const SPEC_HASH_LOCK_NAMESPACE: i32 = 0x7370_6563;
 
 /// A distributed data processing job.
-#[derive(Debug, Deserialize, Identifiable, Queryable, Serialize, ToSchema)]
+#[derive(Clone, Debug, Deserialize, Identifiable, Queryable, Serialize, ToSchema)]
 pub struct Job {
     /// The unique ID of this job.
     pub id: Uuid,
@@ -27,6 +59,193 @@ pub struct Job {
     pub command: Vec<String>,
     /// The output bucket or bucket path.
     pub egress_uri: String,
+    /// Should we stop the whole job as soon as any datum fails permanently?
+    #[serde(default)]
+    pub stop_on_first_error: bool,
+    /// A hash of the canonical form of our original pipeline spec, used to
+    /// detect duplicate submissions. Missing for older jobs.
+    pub spec_hash: Option<String>,
+    /// The maximum number of bytes of output to store inline for each datum.
+    #[serde(default = "default_max_inline_output_bytes")]
+    pub max_inline_output_bytes: i64,
+    /// Where to upload datum output which is too large to store inline.
+    /// Missing for older jobs; see [`Job::output_log_uri_or_default`].
+    pub output_log_uri: Option<String>,
+    /// Why this job failed, if it failed for reasons which have nothing to do
+    /// with any particular datum (for example, while we were creating it).
+    pub error_message: Option<String>,
+    /// How many datums this job will have, once we've finished creating it.
+    /// Missing for older jobs, and for jobs whose inputs haven't been listed
+    /// yet.
+    pub total_datum_count: Option<i64>,
+    /// Should we scale down our Kubernetes job's parallelism as we run out of
+    /// datums?
+    #[serde(default)]
+    pub autoscale_parallelism: bool,
+    /// The parallelism we last asked Kubernetes to use for this job. Missing
+    /// for older jobs.
+    #[serde(default)]
+    pub target_parallelism: Option<i32>,
+    /// A client-supplied key which prevents this job from being submitted
+    /// twice.
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+    /// For jobs with status `Status::Waiting`, everything `falconerid` needs
+    /// to start the job once its dependencies have finished.
+    #[serde(default)]
+    pub deferred_start: Option<serde_json::Value>,
+    /// The job this job was cloned from by `falconeri job rerun`, if any.
+    #[serde(default)]
+    pub parent_job_id: Option<Uuid>,
+    /// The team which owns this job, used to enforce quotas.
+    #[serde(default)]
+    pub team: Option<String>,
+    /// The digest of the worker image which this job ran, like
+    /// `sha256:...`. Missing until a worker pod has started, unless the
+    /// pipeline spec pinned the image by digest.
+    #[serde(default)]
+    pub image_digest: Option<String>,
+    /// The complete pipeline spec which was submitted for this job. Missing
+    /// for older jobs. This can be large, so we don't include it in API
+    /// responses; use `GET /jobs/{job_id}/spec` instead.
+    #[serde(default, skip_serializing)]
+    pub submitted_pipeline_spec: Option<serde_json::Value>,
+    /// How long to wait before automatically retrying a failed datum for the
+    /// first time. The delay doubles after each attempt. If missing, we retry
+    /// immediately.
+    #[serde(default)]
+    pub retry_backoff_seconds: Option<i32>,
+    /// How many datums may fail permanently before we fail the whole job. See
+    /// [`Job::failure_threshold_exceeded`].
+    #[serde(default)]
+    pub max_failed_datums: Option<i32>,
+    /// What percentage of datums may fail permanently before we fail the
+    /// whole job.
+    #[serde(default)]
+    pub max_failed_datum_percent: Option<f64>,
+    /// Where to write a manifest of this job's output files once it
+    /// finishes, if anywhere.
+    #[serde(default)]
+    pub output_manifest_uri: Option<String>,
+    /// When we wrote this job's output manifest.
+    #[serde(default)]
+    pub output_manifest_written_at: Option<NaiveDateTime>,
+    /// Nodes whose pods may no longer reserve this job's datums, because the
+    /// babysitter found that they were failing far more datums than other
+    /// nodes.
+    #[serde(default)]
+    pub excluded_node_names: Vec<String>,
+}
+
+/// The default value of `Job::max_inline_output_bytes`. This must match the
+/// default in our database schema.
+pub const DEFAULT_MAX_INLINE_OUTPUT_BYTES: i64 = 1024 * 1024;
+
+/// Helper for `serde(default)`.
+fn default_max_inline_output_bytes() -> i64 {
+    DEFAULT_MAX_INLINE_OUTPUT_BYTES
+}
+
+/// The longest we'll wait before automatically retrying a failed datum, no
+/// matter how many times it has failed.
+pub const MAX_RETRY_BACKOFF_SECONDS: i64 = 60 * 60;
+
+/// The longest job name that Kubernetes allows us to use.
+pub const MAX_JOB_NAME_LEN: usize = 63;
+
+/// Make sure that `job_name` can be used as a Kubernetes job name. This
+/// matches the check constraint on `jobs.job_name`.
+pub fn check_job_name(job_name: &str) -> Result<()> {
+    check_dns_label("job name", job_name)
+}
+
+/// Make sure that `name` is a valid DNS label, as used for many Kubernetes
+/// resource names. `what` describes the name, for use in error messages.
+pub fn check_dns_label(what: &str, name: &str) -> Result<()> {
+    let valid_chars = name
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+    if name.is_empty()
+        || name.len() > MAX_JOB_NAME_LEN
+        || !valid_chars
+        || name.starts_with('-')
+        || name.ends_with('-')
+    {
+        return Err(format_err!(
+            "{} {:?} must be 1 to {} lowercase letters, digits or hyphens, and must start and end with a letter or digit",
+            what,
+            name,
+            MAX_JOB_NAME_LEN,
+        ));
+    }
+    Ok(())
+}
+
+/// Where should we upload full datum output for a job with `egress_uri`, if
+/// the pipeline spec doesn't say?
+///
+/// We use a sibling of the egress prefix, so that logs don't get mixed in
+/// with the job's output: `gs://bucket/out/` becomes
+/// `gs://bucket/out-falconeri-logs/`. If the egress URI is the root of a
+/// bucket, there's no sibling, so we use `falconeri-logs/` in that bucket.
+pub fn default_output_log_uri(egress_uri: &str) -> String {
+    let trimmed = egress_uri.trim_end_matches('/');
+    let is_bucket_root = match trimmed.split_once("://") {
+        Some((_scheme, path)) => !path.contains('/'),
+        None => false,
+    };
+    if is_bucket_root {
+        format!("{}/falconeri-logs/", trimmed)
+    } else {
+        format!("{}-falconeri-logs/", trimmed)
+    }
+}
+
+/// Build the query which counts how many pods other than `$pod_name` are
+/// running datums for `$job_id`. This is a macro, so that
+/// `Job::explain_reservation_queries` can explain exactly the query we run.
+macro_rules! other_busy_pods_query {
+    ($job_id:expr, $pod_name:expr) => {
+        datums::table
+            .filter(datums::job_id.eq($job_id))
+            .filter(datums::status.eq(Status::Running))
+            .filter(datums::pod_name.ne($pod_name))
+            .select(dsl::count_distinct(datums::pod_name))
+    };
+}
+
+/// Build the `UPDATE` statement which reserves a ready datum for `$job_id`,
+/// returning the datum. Like [`other_busy_pods_query`], this is a macro so
+/// that we can explain it.
+macro_rules! reserve_ready_datum_query {
+    ($job_id:expr, $now:expr, $node_name:expr, $pod_name:expr, $prefetch:expr) => {{
+        let next_ready_datum = datums::table
+            .select(datums::id)
+            .filter(
+                datums::job_id
+                    .eq($job_id)
+                    .and(datums::status.eq(Status::Ready))
+                    // Skip datums which are waiting to be retried.
+                    .and(
+                        datums::retry_after
+                            .is_null()
+                            .or(datums::retry_after.le($now)),
+                    ),
+            )
+            .limit(1)
+            .for_update()
+            .skip_locked();
+        diesel::update(datums::table.filter(datums::id.eq_any(next_ready_datum))).set(
+            (
+                datums::updated_at.eq($now),
+                datums::status.eq(Status::Running),
+                datums::node_name.eq(Some($node_name)),
+                datums::pod_name.eq(Some($pod_name)),
+                datums::attempted_run_count.eq(datums::attempted_run_count + 1),
+                datums::prefetched.eq($prefetch),
+            ),
+        )
+    }};
 }
 
 impl Job {
@@ -40,6 +259,25 @@ impl Job {
             .with_context(|| format!("could not load job {}", id))
     }
 
+    /// Find a job by either its UUID or its job name, if it exists.
+    #[instrument(skip_all, fields(id_or_name = %id_or_name), level = "trace")]
+    pub async fn find_by_id_or_name(
+        id_or_name: &str,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<Option<Job>> {
+        let query = match id_or_name.parse::<Uuid>() {
+            Ok(id) => jobs::table.filter(jobs::id.eq(id)).into_boxed(),
+            Err(_) => jobs::table
+                .filter(jobs::job_name.eq(id_or_name))
+                .into_boxed(),
+        };
+        query
+            .first(conn)
+            .await
+            .optional()
+            .with_context(|| format!("could not look up job {}", id_or_name))
+    }
+
     /// Find a job by job name.
     #[instrument(skip_all, fields(job_name = %job_name), level = "trace")]
     pub async fn find_by_job_name(
@@ -53,6 +291,18 @@ impl Job {
             .with_context(|| format!("could not load job {:?}", job_name))
     }
 
+    /// Is there already a job named `job_name`?
+    #[instrument(skip_all, fields(job_name = %job_name), level = "trace")]
+    pub async fn job_name_exists(
+        job_name: &str,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<bool> {
+        diesel::select(dsl::exists(jobs::table.filter(jobs::job_name.eq(job_name))))
+            .get_result(conn)
+            .await
+            .with_context(|| format!("could not check for job {:?}", job_name))
+    }
+
     /// Find all jobs with specified status.
     #[instrument(skip_all, fields(status = %status), level = "trace")]
     pub async fn find_by_status(
@@ -66,6 +316,95 @@ impl Job {
             .with_context(|| format!("could not load jobs with status {}", status))
     }
 
+    /// Find jobs which have finished successfully, and which still need to
+    /// write an output manifest.
+    #[instrument(skip_all, level = "trace")]
+    pub async fn find_needing_output_manifests(
+        conn: &mut AsyncPgConnection,
+    ) -> Result<Vec<Job>> {
+        jobs::table
+            .filter(jobs::status.eq_any(vec![Status::Done, Status::DoneWithErrors]))
+            .filter(jobs::output_manifest_uri.is_not_null())
+            .filter(jobs::output_manifest_written_at.is_null())
+            .load(conn)
+            .await
+            .context("could not load jobs needing output manifests")
+    }
+
+    /// Find a running (or still being created) job with the specified
+    /// pipeline spec hash, if any.
+    #[instrument(skip_all, fields(spec_hash = %spec_hash), level = "trace")]
+    pub async fn find_running_by_spec_hash(
+        spec_hash: &str,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<Option<Job>> {
+        jobs::table
+            .filter(jobs::spec_hash.eq(spec_hash))
+            .filter(jobs::status.eq_any(vec![
+                Status::Waiting,
+                Status::Creating,
+                Status::Running,
+            ]))
+            .order_by(jobs::created_at.desc())
+            .first(conn)
+            .await
+            .optional()
+            .with_context(|| {
+                format!("could not look up jobs with spec hash {}", spec_hash)
+            })
+    }
+
+    /// Lock `spec_hash` until the end of the current transaction, so that two
+    /// callers can't both see that no identical job is running and then both
+    /// start one. Must be called inside a transaction.
+    #[instrument(skip_all, fields(spec_hash = %spec_hash), level = "trace")]
+    pub async fn lock_spec_hash(
+        spec_hash: &str,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<()> {
+        use diesel::sql_types::{Integer, Text};
+        diesel::sql_query("SELECT pg_advisory_xact_lock($1, hashtext($2))")
+            .bind::<Integer, _>(SPEC_HASH_LOCK_NAMESPACE)
+            .bind::<Text, _>(spec_hash)
+            .execute(conn)
+            .await
+            .with_context(|| format!("could not lock spec hash {}", spec_hash))?;
+        Ok(())
+    }
+
+    /// Fail with [`DuplicateJob`] if an identical job is already running. To
+    /// avoid races, call this in the same transaction which inserts the new
+    /// job.
+    #[instrument(skip_all, fields(spec_hash = %spec_hash), level = "trace")]
+    pub async fn refuse_duplicate(
+        spec_hash: &str,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<()> {
+        Job::lock_spec_hash(spec_hash, conn).await?;
+        if let Some(existing) = Job::find_running_by_spec_hash(spec_hash, conn).await?
+        {
+            return Err(DuplicateJob {
+                job_name: existing.job_name,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Find the job submitted with `idempotency_key`, if any.
+    #[instrument(skip_all, level = "trace")]
+    pub async fn find_by_idempotency_key(
+        idempotency_key: &str,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<Option<Job>> {
+        jobs::table
+            .filter(jobs::idempotency_key.eq(idempotency_key))
+            .first(conn)
+            .await
+            .optional()
+            .context("could not look up job by idempotency key")
+    }
+
     /// Get all known jobs.
     #[instrument(skip_all, level = "trace")]
     pub async fn list(conn: &mut AsyncPgConnection) -> Result<Vec<Job>> {
@@ -76,19 +415,91 @@ impl Job {
             .context("could not list jobs")
     }
 
+    /// Search for jobs whose names or error messages contain `query`, or
+    /// which have datums whose error messages contain `query`. Matching is
+    /// case-insensitive. Only jobs created since `since` are returned, newest
+    /// first.
+    #[instrument(skip_all, fields(query = %query), level = "trace")]
+    pub async fn search(
+        query: &str,
+        since: Option<NaiveDateTime>,
+        limit: i64,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<Vec<JobSearchResult>> {
+        use diesel::sql_types::{BigInt, Nullable, Text, Timestamp};
+
+        // Find matching jobs, using our trigram indices.
+        let matches = diesel::sql_query(
+            "WITH matching_datums AS ( \
+                 SELECT job_id, \
+                     count(*) AS matching_datum_count, \
+                     min(error_message) AS error_message \
+                 FROM datums \
+                 WHERE error_message ILIKE $1 \
+                     AND ($2::timestamp IS NULL OR created_at >= $2) \
+                 GROUP BY job_id \
+             ) \
+             SELECT jobs.id AS job_id, \
+                 coalesce(m.matching_datum_count, 0) AS matching_datum_count, \
+                 CASE WHEN jobs.error_message ILIKE $1 THEN jobs.error_message \
+                     ELSE m.error_message END AS matching_error_message \
+             FROM jobs LEFT JOIN matching_datums m ON m.job_id = jobs.id \
+             WHERE (jobs.job_name ILIKE $1 \
+                     OR jobs.error_message ILIKE $1 \
+                     OR m.job_id IS NOT NULL) \
+                 AND ($2::timestamp IS NULL OR jobs.created_at >= $2) \
+             ORDER BY jobs.created_at DESC \
+             LIMIT $3",
+        )
+        .bind::<Text, _>(ilike_pattern(query))
+        .bind::<Nullable<Timestamp>, _>(since)
+        .bind::<BigInt, _>(limit)
+        .load::<JobSearchMatch>(conn)
+        .await
+        .context("could not search jobs")?;
+
+        // Load the full jobs, and return them in the same order.
+        let ids = matches.iter().map(|m| m.job_id).collect::<Vec<_>>();
+        let mut jobs = jobs::table
+            .filter(jobs::id.eq_any(&ids))
+            .load::<Job>(conn)
+            .await
+            .context("could not load matching jobs")?
+            .into_iter()
+            .map(|job| (job.id, job))
+            .collect::<HashMap<_, _>>();
+        Ok(matches
+            .into_iter()
+            .filter_map(|m| {
+                Some(JobSearchResult {
+                    job: jobs.remove(&m.job_id)?,
+                    matching_datum_count: m.matching_datum_count,
+                    matching_error_message: m.matching_error_message,
+                })
+            })
+            .collect())
+    }
+
     /// Look up the next datum available to process, and set the status to
     /// `"processing"`. This is intended to be atomic from an SQL perspective.
-    #[instrument(skip_all, fields(job = %self.id, node_name = %node_name, pod_name = %pod_name), level = "trace")]
+    ///
+    /// If `prefetch` is true, the worker is still busy with its current datum,
+    /// and wants to start downloading the inputs for its next one. Each pod
+    /// may have one current datum and one prefetched datum.
+    #[instrument(skip_all, fields(job = %self.id, node_name = %node_name, pod_name = %pod_name, prefetch = prefetch), level = "trace")]
     pub async fn reserve_next_datum(
         &self,
         node_name: &str,
         pod_name: &str,
+        prefetch: bool,
         conn: &mut AsyncPgConnection,
     ) -> Result<Option<(Datum, Vec<InputFile>)>> {
         // Check for existing reservation (which shouldn't happen unless
         // a reservation got lost somewhere between `falconeri-postgres` and
         // `falconeri-worker`), and if none exists, make a new one.
-        let mut datum = self.find_already_reserved_datum(pod_name, conn).await?;
+        let mut datum = self
+            .find_already_reserved_datum(pod_name, prefetch, conn)
+            .await?;
         if let Some(ref datum) = datum {
             warn!(
                 "pod {} tried to reserve datum {} more than once",
@@ -96,7 +507,7 @@ impl Job {
             );
         } else {
             datum = self
-                .actually_reserve_next_datum(node_name, pod_name, conn)
+                .actually_reserve_next_datum(node_name, pod_name, prefetch, conn)
                 .await?;
         }
 
@@ -123,6 +534,7 @@ impl Job {
     async fn find_already_reserved_datum(
         &self,
         pod_name: &str,
+        prefetch: bool,
         conn: &mut AsyncPgConnection,
     ) -> Result<Option<Datum>> {
         Ok(datums::table
@@ -130,7 +542,8 @@ impl Job {
                 datums::job_id
                     .eq(&self.id)
                     .and(datums::pod_name.eq(pod_name))
-                    .and(datums::status.eq(Status::Running)),
+                    .and(datums::status.eq(Status::Running))
+                    .and(datums::prefetched.eq(prefetch)),
             )
             .get_result(conn)
             .await
@@ -139,54 +552,88 @@ impl Job {
 
     /// Internal helper for `reserve_next_datum` which performs the actual
     /// atomic reservation part itself, if we actually need to do so.
+    ///
+    /// This is a single `UPDATE` statement, which picks a ready datum using
+    /// `FOR UPDATE SKIP LOCKED`. This way, when hundreds of workers start at
+    /// once, each of them grabs a different datum instead of waiting for the
+    /// others' row locks, and we hold our lock for only one round trip.
     #[instrument(skip_all, fields(job = %self.id, node_name = %node_name, pod_name = %pod_name), level = "trace")]
     async fn actually_reserve_next_datum(
         &self,
         node_name: &str,
         pod_name: &str,
+        prefetch: bool,
         conn: &mut AsyncPgConnection,
     ) -> Result<Option<Datum>> {
-        let job_id = self.id;
-        let node_name = node_name.to_owned();
-        let pod_name = pod_name.to_owned();
-        conn.transaction(|conn| {
-            async move {
-                let datum_id: Option<Uuid> = datums::table
-                    .select(datums::id)
-                    .for_update()
-                    .skip_locked()
-                    .filter(
-                        datums::job_id
-                            .eq(&job_id)
-                            .and(datums::status.eq(Status::Ready)),
-                    )
-                    .first(conn)
-                    .await
-                    .optional()
-                    .context("error trying to reserve next datum")?;
-                if let Some(datum_id) = datum_id {
-                    let to_update = datums::table.filter(datums::id.eq(&datum_id));
-                    let now = Utc::now().naive_utc();
-                    let datum: Datum = diesel::update(to_update)
-                        .set((
-                            datums::updated_at.eq(now),
-                            datums::status.eq(&Status::Running),
-                            datums::node_name.eq(&Some(&node_name)),
-                            datums::pod_name.eq(&Some(&pod_name)),
-                            datums::attempted_run_count
-                                .eq(datums::attempted_run_count + 1),
-                        ))
-                        .get_result(conn)
-                        .await
-                        .context("cannot mark datum as 'processing'")?;
-                    Ok(Some(datum))
-                } else {
-                    Ok(None)
-                }
+        // Don't give datums to pods on nodes which were failing most of them.
+        if self.excluded_node_names.iter().any(|n| n == node_name) {
+            debug!("node {} is excluded, so not reserving a datum", node_name);
+            return Ok(None);
+        }
+
+        // Don't let more than `target_parallelism` pods process datums at
+        // once, so that users can scale a job down without waiting for
+        // Kubernetes. This check isn't atomic, so we may briefly overshoot
+        // when several pods ask at once.
+        if self.target_parallelism.is_some() {
+            let other_busy_pods = other_busy_pods_query!(&self.id, pod_name)
+                .first::<i64>(conn)
+                .await
+                .context("could not count busy pods")?;
+            if !self.has_room_for_pod(other_busy_pods) {
+                debug!(
+                    "{} other pods are busy, so not reserving a datum",
+                    other_busy_pods
+                );
+                return Ok(None);
             }
-            .scope_boxed()
-        })
-        .await
+        }
+
+        let now = Utc::now().naive_utc();
+        reserve_ready_datum_query!(&self.id, now, node_name, pod_name, prefetch)
+            .get_result(conn)
+            .await
+            .optional()
+            .context("error trying to reserve next datum")
+    }
+
+    /// Explain the queries which `reserve_next_datum` runs for this job, so
+    /// that tests can check which indexes they use. Returns each query's SQL
+    /// and its plan. Nothing is actually reserved.
+    #[cfg(feature = "testing")]
+    #[instrument(skip_all, fields(job = %self.id, pod_name = %pod_name), level = "trace")]
+    pub async fn explain_reservation_queries(
+        &self,
+        node_name: &str,
+        pod_name: &str,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<Vec<(String, String)>> {
+        use diesel::{debug_query, pg::Pg};
+
+        use crate::db::Explain;
+
+        let now = Utc::now().naive_utc();
+        let busy_pods = other_busy_pods_query!(&self.id, pod_name);
+        let reserve =
+            reserve_ready_datum_query!(&self.id, now, node_name, pod_name, false);
+        Ok(vec![
+            (
+                debug_query::<Pg, _>(&busy_pods).to_string(),
+                Explain(busy_pods)
+                    .load::<String>(conn)
+                    .await
+                    .context("could not explain busy pods query")?
+                    .join("\n"),
+            ),
+            (
+                debug_query::<Pg, _>(&reserve).to_string(),
+                Explain(reserve)
+                    .load::<String>(conn)
+                    .await
+                    .context("could not explain reservation query")?
+                    .join("\n"),
+            ),
+        ])
     }
 
     /// Get the number of datums with each status.
@@ -220,7 +667,7 @@ impl Job {
                 diesel::sql_types::BigInt,
                 diesel::sql_types::BigInt,
             )>(
-                "status, count(*), count(*) filter (where status = 'error' and attempted_run_count < maximum_allowed_run_count)",
+                "status, count(*), count(*) filter (where status = 'error' and attempted_run_count < maximum_allowed_run_count and failure_class is distinct from 'permanent')",
             ))
             .order_by(datums::status)
             .load(conn)
@@ -240,20 +687,356 @@ impl Job {
             .collect::<Result<_>>()
     }
 
-    /// Get all our our currently running datums (the ones being processed by
-    /// a worker somewhere).
+    /// Get the average time spent in each phase of processing for this job's
+    /// successful datums.
+    #[instrument(skip_all, fields(job = %self.id), level = "trace")]
+    pub async fn datum_timing_stats(
+        &self,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<DatumTimingStats> {
+        use diesel::sql_types::{BigInt, Double, Nullable};
+
+        // Only look at datums which reported timings, so that datums processed
+        // by older workers don't drag down the averages.
+        let (datum_count, avg_download, avg_command, avg_upload): (
+            i64,
+            Option<f64>,
+            Option<f64>,
+            Option<f64>,
+        ) = datums::table
+            .filter(datums::job_id.eq(self.id))
+            .filter(datums::status.eq(Status::Done))
+            .filter(datums::started_at.is_not_null())
+            .select(dsl::sql::<(
+                BigInt,
+                Nullable<Double>,
+                Nullable<Double>,
+                Nullable<Double>,
+            )>(
+                "count(*), \
+                 round(avg(extract(epoch from download_completed_at - started_at)), 1)::float8, \
+                 round(avg(extract(epoch from command_completed_at - download_completed_at)), 1)::float8, \
+                 round(avg(extract(epoch from upload_completed_at - command_completed_at)), 1)::float8",
+            ))
+            .get_result(conn)
+            .await
+            .context("cannot load datum timing stats")?;
+        Ok(DatumTimingStats {
+            datum_count: cast::u64(datum_count)?,
+            avg_download_seconds: avg_download,
+            avg_command_seconds: avg_command,
+            avg_upload_seconds: avg_upload,
+        })
+    }
+
+    /// Compute statistics about this job's datums.
+    #[instrument(skip_all, fields(job = %self.id), level = "trace")]
+    pub async fn stats(&self, conn: &mut AsyncPgConnection) -> Result<JobStats> {
+        use diesel::sql_types::Uuid as SqlUuid;
+
+        // Percentiles of total datum processing time, plus bytes transferred.
+        let totals = diesel::sql_query(
+            "SELECT \
+                 count(*) FILTER (WHERE status = 'done') AS done_count, \
+                 count(*) FILTER (WHERE status = 'error') AS error_count, \
+                 count(*) FILTER (WHERE status = 'skipped') AS skipped_count, \
+                 count(*) AS total_count, \
+                 percentile_cont(0.5) WITHIN GROUP (ORDER BY extract(epoch FROM upload_completed_at - started_at)::float8) FILTER (WHERE status = 'done') AS p50_seconds, \
+                 percentile_cont(0.9) WITHIN GROUP (ORDER BY extract(epoch FROM upload_completed_at - started_at)::float8) FILTER (WHERE status = 'done') AS p90_seconds, \
+                 percentile_cont(0.99) WITHIN GROUP (ORDER BY extract(epoch FROM upload_completed_at - started_at)::float8) FILTER (WHERE status = 'done') AS p99_seconds, \
+                 coalesce(sum(input_bytes), 0)::bigint AS input_bytes, \
+                 coalesce(sum(output_bytes), 0)::bigint AS output_bytes, \
+                 percentile_disc(0.5) WITHIN GROUP (ORDER BY peak_memory_bytes) AS p50_peak_memory_bytes, \
+                 percentile_disc(0.9) WITHIN GROUP (ORDER BY peak_memory_bytes) AS p90_peak_memory_bytes, \
+                 percentile_disc(0.99) WITHIN GROUP (ORDER BY peak_memory_bytes) AS p99_peak_memory_bytes, \
+                 percentile_cont(0.5) WITHIN GROUP (ORDER BY cpu_seconds) AS p50_cpu_seconds, \
+                 percentile_cont(0.9) WITHIN GROUP (ORDER BY cpu_seconds) AS p90_cpu_seconds, \
+                 percentile_cont(0.99) WITHIN GROUP (ORDER BY cpu_seconds) AS p99_cpu_seconds \
+             FROM datums WHERE job_id = $1",
+        )
+        .bind::<SqlUuid, _>(self.id)
+        .get_result::<JobStatsTotals>(conn)
+        .await
+        .context("cannot load job statistics")?;
+
+        // How many datums finished in each hour?
+        let throughput = diesel::sql_query(
+            "SELECT date_trunc('hour', updated_at) AS hour, count(*) AS done_count \
+             FROM datums WHERE job_id = $1 AND status = 'done' \
+             GROUP BY hour ORDER BY hour",
+        )
+        .bind::<SqlUuid, _>(self.id)
+        .load::<ThroughputBucket>(conn)
+        .await
+        .context("cannot load job throughput")?;
+
+        let nodes = self.node_failure_counts(conn).await?;
+
+        // Datums which `skip_processed` didn't skip were cache misses.
+        let skip_processed = self.pipeline_spec["skip_processed"]
+            .as_bool()
+            .unwrap_or(false);
+        let cache_miss_count = if skip_processed {
+            totals.total_count - totals.skipped_count
+        } else {
+            0
+        };
+
+        Ok(JobStats {
+            done_count: cast::u64(totals.done_count)?,
+            error_count: cast::u64(totals.error_count)?,
+            cache_hit_count: cast::u64(totals.skipped_count)?,
+            cache_miss_count: cast::u64(cache_miss_count)?,
+            p50_seconds: totals.p50_seconds,
+            p90_seconds: totals.p90_seconds,
+            p99_seconds: totals.p99_seconds,
+            input_bytes: cast::u64(totals.input_bytes)?,
+            output_bytes: cast::u64(totals.output_bytes)?,
+            p50_peak_memory_bytes: totals.p50_peak_memory_bytes,
+            p90_peak_memory_bytes: totals.p90_peak_memory_bytes,
+            p99_peak_memory_bytes: totals.p99_peak_memory_bytes,
+            p50_cpu_seconds: totals.p50_cpu_seconds,
+            p90_cpu_seconds: totals.p90_cpu_seconds,
+            p99_cpu_seconds: totals.p99_cpu_seconds,
+            throughput,
+            nodes,
+        })
+    }
+
+    /// How many of this job's datums succeeded and failed on each node?
+    #[instrument(skip_all, fields(job = %self.id), level = "trace")]
+    pub async fn node_failure_counts(
+        &self,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<Vec<NodeFailureCount>> {
+        use diesel::sql_types::Uuid as SqlUuid;
+
+        diesel::sql_query(
+            "SELECT node_name, \
+                 count(*) FILTER (WHERE status = 'done') AS done_count, \
+                 count(*) FILTER (WHERE status = 'error') AS error_count \
+             FROM datums \
+             WHERE job_id = $1 AND node_name IS NOT NULL \
+                 AND status IN ('done', 'error') \
+             GROUP BY node_name ORDER BY node_name",
+        )
+        .bind::<SqlUuid, _>(self.id)
+        .load::<NodeFailureCount>(conn)
+        .await
+        .context("cannot load per-node failures")
+    }
+
+    /// Stop giving this job's datums to pods on `node_name`.
+    #[instrument(skip_all, fields(job = %self.id, node_name = %node_name), level = "trace")]
+    pub async fn exclude_node(
+        &mut self,
+        node_name: &str,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<()> {
+        *self = diesel::update(jobs::table)
+            .filter(jobs::id.eq(&self.id))
+            .set(
+                jobs::excluded_node_names
+                    .eq(jobs::excluded_node_names.concat(vec![node_name.to_owned()])),
+            )
+            .get_result(conn)
+            .await
+            .with_context(|| format!("could not exclude node {}", node_name))?;
+        Ok(())
+    }
+
+    /// Suggest resource requests for this job's pipeline, based on the
+    /// resources used by the datums of every job with the same pipeline name.
+    /// We add `headroom_percent` to the 95th percentile of what was used.
+    #[instrument(skip_all, fields(job = %self.id), level = "trace")]
+    pub async fn recommended_resources(
+        &self,
+        headroom_percent: u32,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<ResourceRecommendation> {
+        use diesel::sql_types::Text;
+
+        let pipeline_name = self.pipeline_spec["pipeline"]["name"]
+            .as_str()
+            .ok_or_else(|| format_err!("job {} has no pipeline name", self.job_name))?
+            .to_owned();
+
+        // Failed datums count too, because a datum which ran out of memory is
+        // exactly the kind we want to know about.
+        let usage = diesel::sql_query(
+            "SELECT \
+                 count(DISTINCT datums.job_id) AS job_count, \
+                 count(*) AS datum_count, \
+                 percentile_disc(0.95) WITHIN GROUP (ORDER BY peak_memory_bytes) AS p95_peak_memory_bytes, \
+                 percentile_cont(0.95) WITHIN GROUP (ORDER BY cpu_seconds / nullif(extract(epoch FROM command_completed_at - download_completed_at)::float8, 0)) AS p95_cpu_cores \
+             FROM datums JOIN jobs ON jobs.id = datums.job_id \
+             WHERE jobs.pipeline_spec->'pipeline'->>'name' = $1 \
+                 AND datums.status IN ('done', 'error') \
+                 AND (peak_memory_bytes IS NOT NULL OR cpu_seconds IS NOT NULL)",
+        )
+        .bind::<Text, _>(&pipeline_name)
+        .get_result::<ResourceUsageTotals>(conn)
+        .await
+        .context("cannot load resource usage")?;
+
+        let current =
+            serde_json::from_value(self.pipeline_spec["resource_requests"].clone())
+                .ok();
+        Ok(ResourceRecommendation {
+            pipeline_name,
+            job_count: cast::u64(usage.job_count)?,
+            datum_count: cast::u64(usage.datum_count)?,
+            headroom_percent,
+            p95_peak_memory_bytes: usage.p95_peak_memory_bytes,
+            p95_cpu_cores: usage.p95_cpu_cores,
+            current,
+            memory: usage
+                .p95_peak_memory_bytes
+                .map(|bytes| recommended_memory(bytes, headroom_percent)),
+            cpu: usage
+                .p95_cpu_cores
+                .map(|cores| recommended_cpu(cores, headroom_percent)),
+        })
+    }
+
+    /// Check that this job's datums were accounted for exactly once, and
+    /// report any violations. We look at a consistent snapshot of the
+    /// database, but a running job may still report violations which are
+    /// about to be fixed, so this is most useful once a job has finished.
+    #[instrument(skip_all, fields(job = %self.id), level = "trace")]
+    pub async fn audit(&self, conn: &mut AsyncPgConnection) -> Result<JobAudit> {
+        use diesel::sql_types::{BigInt, Uuid as SqlUuid};
+
+        // Inputs containing a `cross` put each input file in many datums.
+        let has_cross =
+            serde_json::from_value::<Input>(self.pipeline_spec["input"].clone())
+                .map(|input| input.has_cross())
+                .unwrap_or(true);
+        let job_id = self.id;
+        conn.transaction(|conn| {
+            async move {
+                diesel::sql_query(
+                    "SET TRANSACTION ISOLATION LEVEL REPEATABLE READ, READ ONLY",
+                )
+                .execute(conn)
+                .await
+                .context("cannot start audit transaction")?;
+                let datum_count = datums::table
+                    .filter(datums::job_id.eq(job_id))
+                    .count()
+                    .get_result::<i64>(conn)
+                    .await
+                    .context("cannot count datums")?;
+
+                let mut audit = JobAudit {
+                    job_id,
+                    datum_count: cast::u64(datum_count)?,
+                    skipped_checks: vec![],
+                    truncated_checks: vec![],
+                    violations: vec![],
+                };
+                for &check in AuditCheck::ALL {
+                    if check == AuditCheck::DuplicateInputFile && has_cross {
+                        audit.skipped_checks.push(check);
+                        continue;
+                    }
+                    let mut rows = diesel::sql_query(check.sql())
+                        .bind::<SqlUuid, _>(job_id)
+                        .bind::<BigInt, _>(AUDIT_VIOLATION_LIMIT + 1)
+                        .load::<AuditViolationRow>(conn)
+                        .await
+                        .with_context(|| {
+                            format!("cannot run audit check {}", check)
+                        })?;
+                    if rows.len() > cast::usize(AUDIT_VIOLATION_LIMIT)? {
+                        rows.truncate(cast::usize(AUDIT_VIOLATION_LIMIT)?);
+                        audit.truncated_checks.push(check);
+                    }
+                    audit.violations.extend(rows.into_iter().map(|row| {
+                        AuditViolation {
+                            check,
+                            datum_id: row.datum_id,
+                            message: row.message,
+                        }
+                    }));
+                }
+                Ok::<_, Error>(audit)
+            }
+            .scope_boxed()
+        })
+        .await
+    }
+
+    /// Get our datums with `status`, least recently updated first. If `limit`
+    /// is specified, return at most that many.
     #[instrument(skip_all, fields(job = %self.id, status = %status), level = "trace")]
     pub async fn datums_with_status(
         &self,
         status: Status,
+        limit: Option<i64>,
         conn: &mut AsyncPgConnection,
     ) -> Result<Vec<Datum>> {
-        Datum::belonging_to(self)
+        let mut query = Datum::belonging_to(self)
+            .filter(datums::status.eq(&status))
+            .order(datums::updated_at)
+            .into_boxed();
+        if let Some(limit) = limit {
+            query = query.limit(limit);
+        }
+        query
+            .load(conn)
+            .await
+            .with_context(|| format!("cannot load {} datums for job", status))
+    }
+
+    /// Like [`Job::datums_with_status`], but only load a [`DatumSummary`] of
+    /// each datum.
+    #[instrument(skip_all, fields(job = %self.id, status = %status), level = "trace")]
+    pub async fn datum_summaries_with_status(
+        &self,
+        status: Status,
+        limit: Option<i64>,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<Vec<DatumSummary>> {
+        let mut query = Datum::belonging_to(self)
             .filter(datums::status.eq(&status))
             .order(datums::updated_at)
+            .select(DatumSummary::COLUMNS)
+            .into_boxed();
+        if let Some(limit) = limit {
+            query = query.limit(limit);
+        }
+        query
+            .load(conn)
+            .await
+            .with_context(|| format!("cannot load {} datums for job", status))
+    }
+
+    /// Get a page of summaries of our datums with `status`, sorted by ID.
+    /// Unlike [`Job::datums_with_status`], this order doesn't change as datums
+    /// are updated, so pass the last ID of each page as `after` to get the
+    /// next page.
+    #[instrument(skip_all, fields(job = %self.id, status = %status, after = ?after), level = "trace")]
+    pub async fn datums_with_status_page(
+        &self,
+        status: Status,
+        after: Option<Uuid>,
+        limit: i64,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<Vec<DatumSummary>> {
+        let mut query = Datum::belonging_to(self)
+            .filter(datums::status.eq(&status))
+            .order(datums::id)
+            .select(DatumSummary::COLUMNS)
+            .limit(limit)
+            .into_boxed();
+        if let Some(after) = after {
+            query = query.filter(datums::id.gt(after));
+        }
+        query
             .load(conn)
             .await
-            .context("cannot load running datums for job")
+            .with_context(|| format!("cannot load page of {} datums for job", status))
     }
 
     /// Find and lock a job by ID using `SELECT FOR UPDATE`. Must be called
@@ -317,11 +1100,15 @@ impl Job {
                     let mut rerunable = 0;
                     for status_count in status_counts {
                         match status_count.status {
-                            Status::Ready | Status::Running => {
+                            Status::Waiting
+                            | Status::Creating
+                            | Status::Ready
+                            | Status::Running
+                            | Status::Streaming => {
                                 assert_eq!(status_count.rerunable_count, 0);
                                 unfinished += status_count.count;
                             }
-                            Status::Done => {
+                            Status::Done | Status::DoneWithErrors | Status::Skipped => {
                                 assert_eq!(status_count.rerunable_count, 0);
                                 successful += status_count.count;
                             }
@@ -340,13 +1127,34 @@ impl Job {
                     }
 
                     // Decide what to do, if anything.
-                    let job_status = if unfinished > 0 || rerunable > 0 {
+                    let total = unfinished + successful + failed + rerunable;
+                    let job_status = if failed > 0 && job.stop_on_first_error {
+                        debug!(
+                            "{} datums had errors and job has stop_on_first_error, canceling remaining datums",
+                            failed
+                        );
+                        Datum::cancel_unfinished_for_job_id(job_id, conn).await?;
+                        Some(Status::Error)
+                    } else if job.failure_threshold_exceeded(failed, total) {
+                        debug!(
+                            "{} of {} datums had errors, exceeding failure threshold, canceling remaining datums",
+                            failed, total
+                        );
+                        Datum::cancel_unfinished_for_job_id(job_id, conn).await?;
+                        Some(Status::Error)
+                    } else if unfinished > 0 || rerunable > 0 {
                         trace!(
                             "{} datums remaining, {} rerunable, not updating job status",
                             unfinished,
                             rerunable
                         );
                         None
+                    } else if failed > 0 && job.has_failure_threshold() {
+                        debug!(
+                            "{} datums had errors, within failure threshold, marking job as done with errors",
+                            failed
+                        );
+                        Some(Status::DoneWithErrors)
                     } else if failed > 0 {
                         debug!("{} datums had errors, marking job as error", failed);
                         Some(Status::Error)
@@ -401,6 +1209,412 @@ impl Job {
         Ok(())
     }
 
+    /// Record the total number of datums which this job will have, once we
+    /// finish creating it.
+    #[instrument(skip_all, fields(job = %self.id), level = "trace")]
+    pub async fn set_total_datum_count(
+        &mut self,
+        total_datum_count: i64,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<()> {
+        *self = diesel::update(jobs::table)
+            .filter(jobs::id.eq(&self.id))
+            .set((
+                jobs::updated_at.eq(Utc::now().naive_utc()),
+                jobs::total_datum_count.eq(total_datum_count),
+            ))
+            .get_result(conn)
+            .await
+            .context("could not update job datum count")?;
+        Ok(())
+    }
+
+    /// Delete all of this job's datums, and (by cascading) their input files.
+    /// We use this to clean up after we fail to create a job part way
+    /// through. Returns the number of datums deleted.
+    #[instrument(skip_all, fields(job = %self.id), level = "trace")]
+    pub async fn delete_datums(&self, conn: &mut AsyncPgConnection) -> Result<usize> {
+        diesel::delete(datums::table.filter(datums::job_id.eq(&self.id)))
+            .execute(conn)
+            .await
+            .with_context(|| format!("could not delete datums of job {}", self.id))
+    }
+
+    /// Record the digest of the worker image which this job is running.
+    #[instrument(skip_all, fields(job = %self.id, image_digest = %image_digest), level = "trace")]
+    pub async fn set_image_digest(
+        &mut self,
+        image_digest: &str,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<()> {
+        *self = diesel::update(jobs::table)
+            .filter(jobs::id.eq(&self.id))
+            .set(jobs::image_digest.eq(image_digest))
+            .get_result(conn)
+            .await
+            .context("could not update job image digest")?;
+        Ok(())
+    }
+
+    /// Record that we've written this job's output manifest.
+    #[instrument(skip_all, fields(job = %self.id), level = "trace")]
+    pub async fn mark_output_manifest_written(
+        &mut self,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<()> {
+        *self = diesel::update(jobs::table)
+            .filter(jobs::id.eq(&self.id))
+            .set(jobs::output_manifest_written_at.eq(Utc::now().naive_utc()))
+            .get_result(conn)
+            .await
+            .context("could not record output manifest")?;
+        Ok(())
+    }
+
+    /// Record the parallelism we've asked Kubernetes to use for this job.
+    #[instrument(skip_all, fields(job = %self.id, target_parallelism = target_parallelism), level = "trace")]
+    pub async fn set_target_parallelism(
+        &mut self,
+        target_parallelism: i32,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<()> {
+        *self = diesel::update(jobs::table)
+            .filter(jobs::id.eq(&self.id))
+            .set((
+                jobs::updated_at.eq(Utc::now().naive_utc()),
+                jobs::target_parallelism.eq(target_parallelism),
+            ))
+            .get_result(conn)
+            .await
+            .context("could not update job target parallelism")?;
+        Ok(())
+    }
+
+    /// May another pod start processing datums, given that
+    /// `other_busy_pods` are already processing them? Jobs without a
+    /// `target_parallelism` are never limited.
+    pub fn has_room_for_pod(&self, other_busy_pods: i64) -> bool {
+        match self.target_parallelism {
+            Some(limit) => other_busy_pods < i64::from(limit),
+            None => true,
+        }
+    }
+
+    /// If this job autoscales, how much parallelism should it use now, given
+    /// the counts of its datums? Returns `None` if we shouldn't change
+    /// anything.
+    ///
+    /// We only ever scale down. Workers which find no datums keep polling
+    /// until the job finishes, so lowering the Kubernetes job's parallelism is
+    /// what frees their nodes: Kubernetes deletes the extra pods. It may pick
+    /// a busy pod, which hands its datums back when it receives `SIGTERM`. We
+    /// never scale below the number of datums which still need a worker, so
+    /// that we don't keep a datum waiting.
+    pub fn scaled_down_parallelism(
+        &self,
+        status_counts: &[DatumStatusCount],
+    ) -> Option<i32> {
+        let current = self.target_parallelism?;
+        if !self.autoscale_parallelism || self.status != Status::Running {
+            return None;
+        }
+        let unfinished = status_counts
+            .iter()
+            .map(|c| match c.status {
+                Status::Ready | Status::Running => c.count,
+                Status::Error => c.rerunable_count,
+                _ => 0,
+            })
+            .sum::<u64>();
+        let wanted = i32::try_from(unfinished).unwrap_or(i32::MAX).max(1);
+        if wanted < current {
+            Some(wanted)
+        } else {
+            None
+        }
+    }
+
+    /// How long should a datum which has been attempted `attempted_run_count`
+    /// times wait before we retry it? We double `retry_backoff_seconds` after
+    /// each attempt, up to [`MAX_RETRY_BACKOFF_SECONDS`]. Returns `None` if
+    /// we should retry immediately.
+    pub fn retry_delay(&self, attempted_run_count: i32) -> Option<chrono::Duration> {
+        let base = i64::from(self.retry_backoff_seconds?);
+        if base <= 0 {
+            return None;
+        }
+        let doublings = u32::try_from(attempted_run_count.max(1) - 1)
+            .unwrap_or(0)
+            .min(32);
+        let seconds = base
+            .saturating_mul(1 << doublings)
+            .min(MAX_RETRY_BACKOFF_SECONDS);
+        Some(chrono::Duration::seconds(seconds))
+    }
+
+    /// Record that we're still busy creating this job, so that the
+    /// babysitter doesn't decide we've died.
+    #[instrument(skip_all, fields(job = %job_id), level = "trace")]
+    pub async fn touch(job_id: Uuid, conn: &mut AsyncPgConnection) -> Result<()> {
+        diesel::update(jobs::table)
+            .filter(jobs::id.eq(&job_id))
+            .set(jobs::updated_at.eq(Utc::now().naive_utc()))
+            .execute(conn)
+            .await
+            .context("could not update job")?;
+        Ok(())
+    }
+
+    /// Get the jobs which this job is waiting for.
+    #[instrument(skip_all, fields(job = %self.id), level = "trace")]
+    pub async fn upstream_jobs(
+        &self,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<Vec<Job>> {
+        let upstream_job_ids = job_dependencies::table
+            .filter(job_dependencies::job_id.eq(&self.id))
+            .select(job_dependencies::upstream_job_id);
+        jobs::table
+            .filter(jobs::id.eq_any(upstream_job_ids))
+            .order_by(jobs::created_at)
+            .load(conn)
+            .await
+            .with_context(|| format!("could not load upstream jobs of {}", self.id))
+    }
+
+    /// Find the jobs this job was retried or rerun from, and the jobs which
+    /// were retried or rerun from it, following `parent_job_id`.
+    #[instrument(skip_all, fields(job = %self.id), level = "trace")]
+    pub async fn lineage(&self, conn: &mut AsyncPgConnection) -> Result<JobLineage> {
+        use diesel::sql_types::{Integer, Uuid as SqlUuid};
+
+        // Walk up and down the tree in the database, so that long chains of
+        // retries don't need a query per generation. Ancestors get negative
+        // generations, and descendants get positive ones.
+        let members = diesel::sql_query(
+            "WITH RECURSIVE ancestors(id, parent_job_id, generation) AS ( \
+                 SELECT id, parent_job_id, 0 FROM jobs WHERE id = $1 \
+                 UNION ALL \
+                 SELECT jobs.id, jobs.parent_job_id, a.generation - 1 \
+                 FROM jobs JOIN ancestors a ON jobs.id = a.parent_job_id \
+                 WHERE a.generation > -$2 \
+             ), descendants(id, generation) AS ( \
+                 SELECT id, 0 FROM jobs WHERE id = $1 \
+                 UNION ALL \
+                 SELECT jobs.id, d.generation + 1 \
+                 FROM jobs JOIN descendants d ON jobs.parent_job_id = d.id \
+                 WHERE d.generation < $2 \
+             ) \
+             SELECT id AS job_id, generation FROM ancestors WHERE generation < 0 \
+             UNION ALL \
+             SELECT id AS job_id, generation FROM descendants WHERE generation > 0",
+        )
+        .bind::<SqlUuid, _>(self.id)
+        .bind::<Integer, _>(MAX_LINEAGE_DEPTH)
+        .load::<JobLineageMember>(conn)
+        .await
+        .with_context(|| format!("could not find lineage of job {}", self.id))?;
+        if members
+            .iter()
+            .any(|m| m.generation.abs() >= MAX_LINEAGE_DEPTH)
+        {
+            warn!("lineage of job {} is too deep, truncating", self.id);
+        }
+
+        // Load the full jobs, and sort them by generation, and then by when
+        // they were created.
+        let ids = members.iter().map(|m| m.job_id).collect::<Vec<_>>();
+        let mut jobs = jobs::table
+            .filter(jobs::id.eq_any(&ids))
+            .load::<Job>(conn)
+            .await
+            .with_context(|| format!("could not load lineage of job {}", self.id))?
+            .into_iter()
+            .map(|job| (job.id, job))
+            .collect::<HashMap<_, _>>();
+        let mut members = members
+            .into_iter()
+            .filter_map(|m| Some((m.generation, jobs.remove(&m.job_id)?)))
+            .collect::<Vec<_>>();
+        members.sort_by_key(|(generation, job)| (*generation, job.created_at));
+        let (ancestors, descendants) = members
+            .into_iter()
+            .partition::<Vec<_>, _>(|(generation, _)| *generation < 0);
+        Ok(JobLineage {
+            ancestors: ancestors.into_iter().map(|(_, job)| job).collect(),
+            descendants: descendants.into_iter().map(|(_, job)| job).collect(),
+        })
+    }
+
+    /// Mark a waiting job as being created, and clear `deferred_start`.
+    /// Returns `false` if somebody else already started this job.
+    #[instrument(skip_all, fields(job = %self.id), level = "trace")]
+    pub async fn mark_waiting_job_as_creating(
+        &mut self,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<bool> {
+        debug!("marking job {} as creating", self.job_name);
+        let updated = diesel::update(jobs::table)
+            .filter(jobs::id.eq(&self.id))
+            .filter(jobs::status.eq(Status::Waiting))
+            .set((
+                jobs::updated_at.eq(Utc::now().naive_utc()),
+                jobs::status.eq(Status::Creating),
+                jobs::deferred_start.eq(None::<serde_json::Value>),
+            ))
+            .get_result(conn)
+            .await
+            .optional()
+            .context("could not mark job as creating")?;
+        match updated {
+            Some(updated) => {
+                *self = updated;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Mark a job which has finished being created as running.
+    #[instrument(skip_all, fields(job = %self.id), level = "trace")]
+    pub async fn mark_as_running(
+        &mut self,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<()> {
+        self.mark_created_job_as(Status::Running, conn).await
+    }
+
+    /// Mark a job which has finished being created as streaming.
+    #[instrument(skip_all, fields(job = %self.id), level = "trace")]
+    pub async fn mark_as_streaming(
+        &mut self,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<()> {
+        self.mark_created_job_as(Status::Streaming, conn).await
+    }
+
+    /// Helper for `mark_as_running` and `mark_as_streaming`.
+    async fn mark_created_job_as(
+        &mut self,
+        status: Status,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<()> {
+        debug!("marking job {} as {}", self.job_name, status);
+        *self = diesel::update(jobs::table)
+            .filter(jobs::id.eq(&self.id))
+            .filter(jobs::status.eq(Status::Creating))
+            .set((
+                jobs::updated_at.eq(Utc::now().naive_utc()),
+                jobs::status.eq(status),
+            ))
+            .get_result(conn)
+            .await
+            .with_context(|| format!("could not mark job as {}", status))?;
+        Ok(())
+    }
+
+    /// Stop adding new datums to a streaming job. It will finish normally
+    /// once its remaining datums have been processed.
+    #[instrument(skip_all, fields(job = %self.id), level = "trace")]
+    pub async fn stop_streaming(
+        &mut self,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<()> {
+        debug!("stopping streaming job {}", self.job_name);
+        *self = diesel::update(jobs::table)
+            .filter(jobs::id.eq(&self.id))
+            .filter(jobs::status.eq(Status::Streaming))
+            .set((
+                jobs::updated_at.eq(Utc::now().naive_utc()),
+                jobs::status.eq(Status::Running),
+            ))
+            .get_result(conn)
+            .await
+            .optional()
+            .context("could not stop streaming job")?
+            .ok_or_else(|| format_err!("job {} is not streaming", self.job_name))?;
+        self.update_status_if_done(conn).await
+    }
+
+    /// Mark this job as having errored for a reason unrelated to any
+    /// particular datum, and record why.
+    #[instrument(skip_all, fields(job = %self.id), level = "trace")]
+    pub async fn mark_as_error_with_message(
+        &mut self,
+        error_message: &str,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<()> {
+        debug!(
+            "marking job {} as having errored: {}",
+            self.job_name, error_message
+        );
+        *self = diesel::update(jobs::table)
+            .filter(jobs::id.eq(&self.id))
+            .set((
+                jobs::updated_at.eq(Utc::now().naive_utc()),
+                jobs::status.eq(Status::Error),
+                jobs::error_message.eq(error_message),
+            ))
+            .get_result(conn)
+            .await
+            .context("could not update job status")?;
+        Ok(())
+    }
+
+    /// Delete this job, along with all its datums, input files and output
+    /// files. This is mostly useful for cleaning up after tests.
+    #[instrument(skip_all, fields(job = %self.id), level = "trace")]
+    pub async fn delete(self, conn: &mut AsyncPgConnection) -> Result<()> {
+        diesel::delete(jobs::table.filter(jobs::id.eq(&self.id)))
+            .execute(conn)
+            .await
+            .with_context(|| format!("could not delete job {}", self.job_name))?;
+        Ok(())
+    }
+
+    /// Was this job stopped before all datums finished, because a datum
+    /// failed and we had `stop_on_first_error` set, or because too many
+    /// datums failed?
+    ///
+    /// This may also return true for jobs which finished with errors in the
+    /// normal fashion, but that's harmless for our callers.
+    pub fn was_stopped_early(&self) -> bool {
+        (self.stop_on_first_error || self.has_failure_threshold())
+            && self.status == Status::Error
+    }
+
+    /// Does this job tolerate some failed datums? If so, it finishes with
+    /// `Status::DoneWithErrors` instead of `Status::Error` when it has a few
+    /// failures.
+    pub fn has_failure_threshold(&self) -> bool {
+        self.max_failed_datums.is_some() || self.max_failed_datum_percent.is_some()
+    }
+
+    /// Have more datums failed than this job tolerates? `failed` counts datums
+    /// which have failed permanently, and `total` counts all the job's datums.
+    /// Once this is true, it stays true, so we can stop the job immediately.
+    pub fn failure_threshold_exceeded(&self, failed: u64, total: u64) -> bool {
+        if let Some(max) = self.max_failed_datums {
+            if failed > u64::try_from(max).unwrap_or(0) {
+                return true;
+            }
+        }
+        if let Some(percent) = self.max_failed_datum_percent {
+            if total > 0 && failed as f64 * 100.0 > percent * total as f64 {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Where should we upload datum output which is too large to store
+    /// inline?
+    pub fn output_log_uri_or_default(&self) -> String {
+        self.output_log_uri
+            .clone()
+            .unwrap_or_else(|| default_output_log_uri(&self.egress_uri))
+    }
+
     /// Generate a sample value for testing.
     pub fn factory() -> Self {
         let now = Utc::now().naive_utc();
@@ -413,6 +1627,26 @@ impl Job {
             job_name: "my-job-123az".to_owned(), // TODO: Make unique.
             command: vec!["echo".to_owned(), "hi".to_owned()],
             egress_uri: "gs://example-bucket/output/".to_owned(),
+            stop_on_first_error: false,
+            spec_hash: None,
+            max_inline_output_bytes: DEFAULT_MAX_INLINE_OUTPUT_BYTES,
+            output_log_uri: None,
+            error_message: None,
+            total_datum_count: None,
+            autoscale_parallelism: false,
+            target_parallelism: None,
+            idempotency_key: None,
+            deferred_start: None,
+            parent_job_id: None,
+            team: None,
+            image_digest: None,
+            submitted_pipeline_spec: None,
+            retry_backoff_seconds: None,
+            max_failed_datums: None,
+            max_failed_datum_percent: None,
+            output_manifest_uri: None,
+            output_manifest_written_at: None,
+            excluded_node_names: vec![],
         }
     }
 }
@@ -429,12 +1663,459 @@ pub struct DatumStatusCount {
     pub rerunable_count: u64,
 }
 
+/// How many generations of retries and reruns we follow in
+/// [`Job::lineage`]. This protects us against very long chains, and against
+/// cycles in `parent_job_id`.
+const MAX_LINEAGE_DEPTH: i32 = 1000;
+
+/// The jobs related to a job by retries and reruns.
+#[derive(Debug, Default, Deserialize, Serialize, ToSchema)]
+pub struct JobLineage {
+    /// The jobs this job was retried or rerun from, oldest first.
+    pub ancestors: Vec<Job>,
+    /// The jobs retried or rerun from this job, directly or indirectly, in
+    /// order of creation.
+    pub descendants: Vec<Job>,
+}
+
+/// Average time spent in each phase of processing a job's datums.
+#[derive(Debug, Default, Deserialize, Serialize, ToSchema)]
+pub struct DatumTimingStats {
+    /// The number of successful datums with timing information.
+    pub datum_count: u64,
+    /// Average seconds spent downloading input files.
+    pub avg_download_seconds: Option<f64>,
+    /// Average seconds spent running the command.
+    pub avg_command_seconds: Option<f64>,
+    /// Average seconds spent uploading output files.
+    pub avg_upload_seconds: Option<f64>,
+}
+
+/// Statistics about a job's datums.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct JobStats {
+    /// The number of datums processed successfully.
+    pub done_count: u64,
+    /// The number of datums which failed.
+    pub error_count: u64,
+    /// The number of datums skipped by `skip_processed`, because their inputs
+    /// were already processed.
+    #[serde(default)]
+    pub cache_hit_count: u64,
+    /// The number of datums which `skip_processed` couldn't skip, because
+    /// their inputs were new or had changed.
+    #[serde(default)]
+    pub cache_miss_count: u64,
+    /// Median seconds to process a successful datum, if known.
+    pub p50_seconds: Option<f64>,
+    /// 90th percentile seconds to process a successful datum, if known.
+    pub p90_seconds: Option<f64>,
+    /// 99th percentile seconds to process a successful datum, if known.
+    pub p99_seconds: Option<f64>,
+    /// Total bytes of input downloaded by workers.
+    pub input_bytes: u64,
+    /// Total bytes of output uploaded by workers.
+    pub output_bytes: u64,
+    /// Median peak memory used by a datum's command, over all datums which
+    /// reported it.
+    #[serde(default)]
+    pub p50_peak_memory_bytes: Option<i64>,
+    /// 90th percentile peak memory used by a datum's command.
+    #[serde(default)]
+    pub p90_peak_memory_bytes: Option<i64>,
+    /// 99th percentile peak memory used by a datum's command.
+    #[serde(default)]
+    pub p99_peak_memory_bytes: Option<i64>,
+    /// Median CPU seconds used by a datum's command, over all datums which
+    /// reported it.
+    #[serde(default)]
+    pub p50_cpu_seconds: Option<f64>,
+    /// 90th percentile CPU seconds used by a datum's command.
+    #[serde(default)]
+    pub p90_cpu_seconds: Option<f64>,
+    /// 99th percentile CPU seconds used by a datum's command.
+    #[serde(default)]
+    pub p99_cpu_seconds: Option<f64>,
+    /// Datums completed per hour.
+    pub throughput: Vec<ThroughputBucket>,
+    /// Successes and failures on each Kubernetes node.
+    pub nodes: Vec<NodeFailureCount>,
+}
+
+/// Raw totals loaded by [`Job::stats`].
+#[derive(QueryableByName)]
+struct JobStatsTotals {
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    done_count: i64,
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    error_count: i64,
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    skipped_count: i64,
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    total_count: i64,
+    #[diesel(sql_type = diesel::sql_types::Nullable<diesel::sql_types::Double>)]
+    p50_seconds: Option<f64>,
+    #[diesel(sql_type = diesel::sql_types::Nullable<diesel::sql_types::Double>)]
+    p90_seconds: Option<f64>,
+    #[diesel(sql_type = diesel::sql_types::Nullable<diesel::sql_types::Double>)]
+    p99_seconds: Option<f64>,
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    input_bytes: i64,
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    output_bytes: i64,
+    #[diesel(sql_type = diesel::sql_types::Nullable<diesel::sql_types::BigInt>)]
+    p50_peak_memory_bytes: Option<i64>,
+    #[diesel(sql_type = diesel::sql_types::Nullable<diesel::sql_types::BigInt>)]
+    p90_peak_memory_bytes: Option<i64>,
+    #[diesel(sql_type = diesel::sql_types::Nullable<diesel::sql_types::BigInt>)]
+    p99_peak_memory_bytes: Option<i64>,
+    #[diesel(sql_type = diesel::sql_types::Nullable<diesel::sql_types::Double>)]
+    p50_cpu_seconds: Option<f64>,
+    #[diesel(sql_type = diesel::sql_types::Nullable<diesel::sql_types::Double>)]
+    p90_cpu_seconds: Option<f64>,
+    #[diesel(sql_type = diesel::sql_types::Nullable<diesel::sql_types::Double>)]
+    p99_cpu_seconds: Option<f64>,
+}
+
+/// The number of datums completed during a single hour.
+#[derive(Debug, Deserialize, QueryableByName, Serialize, ToSchema)]
+pub struct ThroughputBucket {
+    /// The start of the hour.
+    #[diesel(sql_type = diesel::sql_types::Timestamp)]
+    pub hour: NaiveDateTime,
+    /// The number of datums completed successfully during this hour.
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub done_count: i64,
+}
+
+/// How many datums succeeded and failed on a single node.
+#[derive(Clone, Debug, Deserialize, QueryableByName, Serialize, ToSchema)]
+pub struct NodeFailureCount {
+    /// The Kubernetes node name.
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    pub node_name: String,
+    /// The number of datums which succeeded on this node.
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub done_count: i64,
+    /// The number of datums which failed on this node.
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub error_count: i64,
+}
+
+/// How many datums must have finished on a node before we'll call its
+/// failure rate anomalous.
+const MIN_ANOMALOUS_NODE_DATUMS: i64 = 5;
+
+/// The lowest failure rate we'll call anomalous.
+const MIN_ANOMALOUS_FAILURE_RATE: f64 = 0.5;
+
+/// How many times higher than the other nodes' failure rate a node's must be
+/// before we call it anomalous.
+const ANOMALOUS_FAILURE_RATE_MULTIPLE: f64 = 3.0;
+
+impl NodeFailureCount {
+    /// Find the nodes in `nodes` which are failing far more datums than the
+    /// others, which usually means that something is wrong with the node
+    /// rather than the datums. If every node is failing, that's probably the
+    /// job's fault, so we return nothing.
+    pub fn anomalous(nodes: &[NodeFailureCount]) -> Vec<&NodeFailureCount> {
+        nodes
+            .iter()
+            .filter(|node| {
+                let (others_done, others_error) = nodes
+                    .iter()
+                    .filter(|other| other.node_name != node.node_name)
+                    .fold((0, 0), |(done, error), other| {
+                        (done + other.done_count, error + other.error_count)
+                    });
+                let others = NodeFailureCount {
+                    node_name: String::new(),
+                    done_count: others_done,
+                    error_count: others_error,
+                };
+                node.done_count + node.error_count >= MIN_ANOMALOUS_NODE_DATUMS
+                    && others.done_count + others.error_count
+                        >= MIN_ANOMALOUS_NODE_DATUMS
+                    && node.failure_rate() >= MIN_ANOMALOUS_FAILURE_RATE
+                    && node.failure_rate()
+                        >= ANOMALOUS_FAILURE_RATE_MULTIPLE * others.failure_rate()
+            })
+            .collect()
+    }
+
+    /// The fraction of datums which failed on this node.
+    pub fn failure_rate(&self) -> f64 {
+        let total = self.done_count + self.error_count;
+        if total == 0 {
+            0.0
+        } else {
+            self.error_count as f64 / total as f64
+        }
+    }
+}
+
+/// Resource requests suggested by [`Job::recommended_resources`].
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct ResourceRecommendation {
+    /// The pipeline name whose jobs we looked at.
+    pub pipeline_name: String,
+    /// The number of jobs with datums which reported their resource usage.
+    pub job_count: u64,
+    /// The number of datums which reported their resource usage.
+    pub datum_count: u64,
+    /// The percentage we added to the 95th percentile of what was used.
+    pub headroom_percent: u32,
+    /// 95th percentile peak memory used by a datum's command, in bytes.
+    pub p95_peak_memory_bytes: Option<i64>,
+    /// 95th percentile CPU cores used by a datum's command, averaged over
+    /// the time it was running.
+    pub p95_cpu_cores: Option<f64>,
+    /// The resource requests in this job's pipeline spec, if any.
+    pub current: Option<ResourceRequests>,
+    /// The suggested `resource_requests.memory`, as a Kubernetes quantity.
+    /// Missing if no datums reported their memory usage.
+    pub memory: Option<String>,
+    /// The suggested `resource_requests.cpu`. Missing if no datums reported
+    /// their CPU time.
+    pub cpu: Option<f32>,
+}
+
+/// Raw totals loaded by [`Job::recommended_resources`].
+#[derive(QueryableByName)]
+struct ResourceUsageTotals {
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    job_count: i64,
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    datum_count: i64,
+    #[diesel(sql_type = diesel::sql_types::Nullable<diesel::sql_types::BigInt>)]
+    p95_peak_memory_bytes: Option<i64>,
+    #[diesel(sql_type = diesel::sql_types::Nullable<diesel::sql_types::Double>)]
+    p95_cpu_cores: Option<f64>,
+}
+
+/// Add `headroom_percent` to `bytes`, and round up to a whole number of
+/// mebibytes.
+fn recommended_memory(bytes: i64, headroom_percent: u32) -> String {
+    const MIB: i128 = 1024 * 1024;
+    let scaled = i128::from(bytes.max(0)) * (100 + i128::from(headroom_percent));
+    let mib = (scaled + 100 * MIB - 1) / (100 * MIB);
+    format!("{}Mi", mib.max(1))
+}
+
+/// Add `headroom_percent` to `cores`, and round up to a tenth of a core.
+fn recommended_cpu(cores: f64, headroom_percent: u32) -> f32 {
+    let tenths = (cores * f64::from(100 + headroom_percent) / 10.0).ceil();
+    (tenths.max(1.0) / 10.0) as f32
+}
+
+/// The most violations of each check reported by [`Job::audit`].
+const AUDIT_VIOLATION_LIMIT: i64 = 1000;
+
+/// The results of [`Job::audit`].
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct JobAudit {
+    /// The job we audited.
+    pub job_id: Uuid,
+    /// The number of datums belonging to the job.
+    pub datum_count: u64,
+    /// Checks which don't apply to this job, and which we didn't run.
+    #[serde(default)]
+    pub skipped_checks: Vec<AuditCheck>,
+    /// Checks which found more violations than we report.
+    #[serde(default)]
+    pub truncated_checks: Vec<AuditCheck>,
+    /// Everything we found which shouldn't be possible.
+    pub violations: Vec<AuditViolation>,
+}
+
+/// A consistency check run by [`Job::audit`].
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditCheck {
+    /// An input file belongs to a datum of a different job.
+    InputFileInOtherJob,
+    /// An input file belongs to more than one datum. Skipped for inputs
+    /// containing a `cross`.
+    DuplicateInputFile,
+    /// A datum is done, but has no output files, and its worker didn't
+    /// report that it wrote none.
+    DoneWithoutOutputs,
+    /// A datum is done, but some of its output files were never uploaded.
+    DoneWithUnfinishedOutputs,
+    /// A datum is done, but is still reserved as a pod's next datum.
+    DoneButReserved,
+}
+
+impl AuditCheck {
+    /// Every check, in the order we run them.
+    pub const ALL: &'static [AuditCheck] = &[
+        AuditCheck::InputFileInOtherJob,
+        AuditCheck::DuplicateInputFile,
+        AuditCheck::DoneWithoutOutputs,
+        AuditCheck::DoneWithUnfinishedOutputs,
+        AuditCheck::DoneButReserved,
+    ];
+
+    /// The name of this check, as used in JSON.
+    pub fn name(self) -> &'static str {
+        match self {
+            AuditCheck::InputFileInOtherJob => "input_file_in_other_job",
+            AuditCheck::DuplicateInputFile => "duplicate_input_file",
+            AuditCheck::DoneWithoutOutputs => "done_without_outputs",
+            AuditCheck::DoneWithUnfinishedOutputs => "done_with_unfinished_outputs",
+            AuditCheck::DoneButReserved => "done_but_reserved",
+        }
+    }
+
+    /// SQL which finds violations of this check. Takes the job ID as `$1`
+    /// and the maximum number of rows to return as `$2`.
+    fn sql(self) -> &'static str {
+        match self {
+            AuditCheck::InputFileInOtherJob => {
+                "SELECT input_files.datum_id, \
+                     format('input file %s belongs to job %s, but its datum belongs to job %s', \
+                         input_files.uri, input_files.job_id, datums.job_id) AS message \
+                 FROM input_files JOIN datums ON datums.id = input_files.datum_id \
+                 WHERE (input_files.job_id = $1 OR datums.job_id = $1) \
+                     AND input_files.job_id <> datums.job_id \
+                 ORDER BY input_files.uri \
+                 LIMIT $2"
+            }
+            AuditCheck::DuplicateInputFile => {
+                "SELECT input_files.datum_id, \
+                     format('input file %s belongs to %s datums', \
+                         input_files.uri, duplicates.datum_count) AS message \
+                 FROM input_files \
+                 JOIN ( \
+                     SELECT uri, count(DISTINCT datum_id) AS datum_count \
+                     FROM input_files \
+                     WHERE job_id = $1 \
+                     GROUP BY uri \
+                     HAVING count(DISTINCT datum_id) > 1 \
+                 ) AS duplicates ON duplicates.uri = input_files.uri \
+                 WHERE input_files.job_id = $1 \
+                 ORDER BY input_files.uri, input_files.datum_id \
+                 LIMIT $2"
+            }
+            AuditCheck::DoneWithoutOutputs => {
+                "SELECT id AS datum_id, \
+                     'datum is done, but has no output files, and its worker did not report that it wrote none' AS message \
+                 FROM datums \
+                 WHERE job_id = $1 \
+                     AND status = 'done' \
+                     AND (output_bytes IS NULL OR output_bytes <> 0) \
+                     AND NOT EXISTS ( \
+                         SELECT 1 FROM output_files WHERE output_files.datum_id = datums.id \
+                     ) \
+                 ORDER BY id \
+                 LIMIT $2"
+            }
+            AuditCheck::DoneWithUnfinishedOutputs => {
+                "SELECT datums.id AS datum_id, \
+                     format('datum is done, but output file %s has status %s', \
+                         output_files.uri, output_files.status) AS message \
+                 FROM datums JOIN output_files ON output_files.datum_id = datums.id \
+                 WHERE datums.job_id = $1 \
+                     AND datums.status = 'done' \
+                     AND output_files.status <> 'done' \
+                 ORDER BY datums.id, output_files.uri \
+                 LIMIT $2"
+            }
+            AuditCheck::DoneButReserved => {
+                "SELECT id AS datum_id, \
+                     format('datum is done, but is still reserved as the next datum of pod %s', \
+                         pod_name) AS message \
+                 FROM datums \
+                 WHERE job_id = $1 AND status = 'done' AND prefetched \
+                 ORDER BY id \
+                 LIMIT $2"
+            }
+        }
+    }
+}
+
+impl fmt::Display for AuditCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// A violation found by [`Job::audit`].
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct AuditViolation {
+    /// The check which found this violation.
+    pub check: AuditCheck,
+    /// The datum involved.
+    pub datum_id: Uuid,
+    /// What we found.
+    pub message: String,
+}
+
+/// A raw violation loaded by [`Job::audit`].
+#[derive(QueryableByName)]
+struct AuditViolationRow {
+    #[diesel(sql_type = diesel::sql_types::Uuid)]
+    datum_id: Uuid,
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    message: String,
+}
+
+/// A job found by [`Job::search`].
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct JobSearchResult {
+    /// The matching job.
+    pub job: Job,
+    /// The number of this job's datums whose error messages matched.
+    pub matching_datum_count: i64,
+    /// A matching error message from the job or one of its datums, if any.
+    pub matching_error_message: Option<String>,
+}
+
+/// A job in the lineage loaded by [`Job::lineage`], without its details.
+#[derive(QueryableByName)]
+struct JobLineageMember {
+    #[diesel(sql_type = diesel::sql_types::Uuid)]
+    job_id: Uuid,
+    /// How many generations away from the original job this job is. Negative
+    /// for ancestors, and positive for descendants.
+    #[diesel(sql_type = diesel::sql_types::Integer)]
+    generation: i32,
+}
+
+/// Raw search results loaded by [`Job::search`].
+#[derive(QueryableByName)]
+struct JobSearchMatch {
+    #[diesel(sql_type = diesel::sql_types::Uuid)]
+    job_id: Uuid,
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    matching_datum_count: i64,
+    #[diesel(sql_type = diesel::sql_types::Nullable<diesel::sql_types::Text>)]
+    matching_error_message: Option<String>,
+}
+
+/// Build an `ILIKE` pattern which matches any string containing `query`,
+/// treating any wildcards in `query` literally.
+fn ilike_pattern(query: &str) -> String {
+    let mut pattern = String::with_capacity(query.len() + 2);
+    pattern.push('%');
+    for c in query.chars() {
+        if matches!(c, '\\' | '%' | '_') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('%');
+    pattern
+}
+
 /// Data required to create a new `Job`.
 #[derive(Debug, Insertable)]
 #[diesel(table_name = jobs)]
 pub struct NewJob {
     /// The unique ID for this job.
     pub id: Uuid,
+    /// The initial status of this job.
+    pub status: Status,
     /// A copy of our original pipeline spec (just for debugging).
     pub pipeline_spec: serde_json::Value,
     /// The Kubenetes `Job` name for this job.
@@ -443,6 +2124,40 @@ pub struct NewJob {
     pub command: Vec<String>,
     /// The output bucket or bucket path.
     pub egress_uri: String,
+    /// Should we stop the whole job as soon as any datum fails permanently?
+    pub stop_on_first_error: bool,
+    /// A hash of the canonical form of our original pipeline spec.
+    pub spec_hash: Option<String>,
+    /// The maximum number of bytes of output to store inline for each datum.
+    pub max_inline_output_bytes: i64,
+    /// Where to upload datum output which is too large to store inline.
+    pub output_log_uri: Option<String>,
+    /// Should we scale down our Kubernetes job's parallelism as we run out of
+    /// datums?
+    pub autoscale_parallelism: bool,
+    /// The parallelism we initially ask Kubernetes to use.
+    pub target_parallelism: Option<i32>,
+    /// A client-supplied key which prevents this job from being submitted
+    /// twice.
+    pub idempotency_key: Option<String>,
+    /// For waiting jobs, everything we need to start the job later.
+    pub deferred_start: Option<serde_json::Value>,
+    /// The job this job was cloned from, if any.
+    pub parent_job_id: Option<Uuid>,
+    /// The team which owns this job.
+    pub team: Option<String>,
+    /// The digest of the worker image, if the pipeline spec pinned it.
+    pub image_digest: Option<String>,
+    /// The complete pipeline spec which was submitted for this job.
+    pub submitted_pipeline_spec: Option<serde_json::Value>,
+    /// How long to wait before retrying a failed datum for the first time.
+    pub retry_backoff_seconds: Option<i32>,
+    /// How many datums may fail before we fail the whole job.
+    pub max_failed_datums: Option<i32>,
+    /// What percentage of datums may fail before we fail the whole job.
+    pub max_failed_datum_percent: Option<f64>,
+    /// Where to write a manifest of this job's output files.
+    pub output_manifest_uri: Option<String>,
 }
 
 impl NewJob {
@@ -456,3 +2171,189 @@ impl NewJob {
             .context("error inserting job")
     }
 }
+
+/// Records that a job must wait for an upstream job to finish before it can
+/// start.
+#[derive(Debug, Insertable)]
+#[diesel(table_name = job_dependencies)]
+pub struct NewJobDependency {
+    /// The job which is waiting.
+    pub job_id: Uuid,
+    /// The job it's waiting for.
+    pub upstream_job_id: Uuid,
+}
+
+impl NewJobDependency {
+    /// Insert new job dependencies into the database.
+    #[instrument(skip_all, level = "trace")]
+    pub async fn insert_all(
+        dependencies: &[Self],
+        conn: &mut AsyncPgConnection,
+    ) -> Result<()> {
+        diesel::insert_into(job_dependencies::table)
+            .values(dependencies)
+            .execute(conn)
+            .await
+            .context("error inserting job dependencies")?;
+        Ok(())
+    }
+}
+
+#[test]
+fn failure_thresholds_are_enforced() {
+    let mut job = Job::factory();
+    assert!(!job.has_failure_threshold());
+    assert!(!job.failure_threshold_exceeded(100, 100));
+
+    job.max_failed_datums = Some(2);
+    assert!(job.has_failure_threshold());
+    assert!(!job.failure_threshold_exceeded(2, 100));
+    assert!(job.failure_threshold_exceeded(3, 100));
+
+    job.max_failed_datums = None;
+    job.max_failed_datum_percent = Some(5.0);
+    assert!(!job.failure_threshold_exceeded(5, 100));
+    assert!(job.failure_threshold_exceeded(6, 100));
+    assert!(!job.failure_threshold_exceeded(0, 0));
+}
+
+#[test]
+fn retry_delay_doubles_up_to_limit() {
+    let mut job = Job::factory();
+    assert_eq!(job.retry_delay(1), None);
+    job.retry_backoff_seconds = Some(30);
+    assert_eq!(job.retry_delay(1), Some(chrono::Duration::seconds(30)));
+    assert_eq!(job.retry_delay(2), Some(chrono::Duration::seconds(60)));
+    assert_eq!(job.retry_delay(3), Some(chrono::Duration::seconds(120)));
+    assert_eq!(
+        job.retry_delay(100),
+        Some(chrono::Duration::seconds(MAX_RETRY_BACKOFF_SECONDS))
+    );
+    job.retry_backoff_seconds = Some(0);
+    assert_eq!(job.retry_delay(2), None);
+}
+
+#[test]
+fn scaled_down_parallelism_tracks_unfinished_datums() {
+    let count = |status, count, rerunable_count| DatumStatusCount {
+        status,
+        count,
+        rerunable_count,
+    };
+    let mut job = Job::factory();
+    job.target_parallelism = Some(10);
+    let status_counts = vec![
+        count(Status::Ready, 2, 0),
+        count(Status::Running, 3, 0),
+        count(Status::Done, 50, 0),
+        count(Status::Error, 4, 1),
+    ];
+
+    // Jobs only scale if they ask to.
+    assert_eq!(job.scaled_down_parallelism(&status_counts), None);
+
+    job.autoscale_parallelism = true;
+    assert_eq!(job.scaled_down_parallelism(&status_counts), Some(6));
+
+    // We never scale up, and we never scale to zero.
+    job.target_parallelism = Some(4);
+    assert_eq!(job.scaled_down_parallelism(&status_counts), None);
+    assert_eq!(
+        job.scaled_down_parallelism(&[count(Status::Done, 50, 0)]),
+        Some(1)
+    );
+}
+
+#[test]
+fn has_room_for_pod_respects_target_parallelism() {
+    let mut job = Job::factory();
+    assert!(job.has_room_for_pod(1000));
+
+    job.target_parallelism = Some(3);
+    assert!(job.has_room_for_pod(2));
+    assert!(!job.has_room_for_pod(3));
+}
+
+#[test]
+fn ilike_pattern_escapes_wildcards() {
+    assert_eq!(ilike_pattern("OOM"), "%OOM%");
+    assert_eq!(ilike_pattern("100%_done\\"), "%100\\%\\_done\\\\%");
+}
+
+#[test]
+fn check_job_name_matches_kubernetes_rules() {
+    assert!(check_job_name("nightly-etl-2026-10-15").is_ok());
+    assert!(check_job_name(&"a".repeat(MAX_JOB_NAME_LEN)).is_ok());
+    assert!(check_job_name("").is_err());
+    assert!(check_job_name(&"a".repeat(MAX_JOB_NAME_LEN + 1)).is_err());
+    assert!(check_job_name("Nightly").is_err());
+    assert!(check_job_name("nightly_etl").is_err());
+    assert!(check_job_name("-nightly").is_err());
+    assert!(check_job_name("nightly-").is_err());
+}
+
+#[test]
+fn recommended_resources_add_headroom_and_round_up() {
+    assert_eq!(recommended_memory(1000 * 1024 * 1024, 20), "1200Mi");
+    assert_eq!(recommended_memory(1024 * 1024 + 1, 0), "2Mi");
+    assert_eq!(recommended_memory(0, 20), "1Mi");
+    assert!((recommended_cpu(0.5, 20) - 0.6).abs() < f32::EPSILON);
+    assert!((recommended_cpu(1.01, 0) - 1.1).abs() < f32::EPSILON);
+    assert!((recommended_cpu(0.0, 20) - 0.1).abs() < f32::EPSILON);
+}
+
+#[test]
+fn anomalous_nodes_fail_much_more_than_others() {
+    let node = |name: &str, done_count, error_count| NodeFailureCount {
+        node_name: name.to_owned(),
+        done_count,
+        error_count,
+    };
+    let names = |nodes: &[NodeFailureCount]| {
+        NodeFailureCount::anomalous(nodes)
+            .into_iter()
+            .map(|n| n.node_name.clone())
+            .collect::<Vec<_>>()
+    };
+
+    // One bad node.
+    let nodes = [node("a", 50, 1), node("b", 45, 2), node("c", 0, 20)];
+    assert_eq!(names(&nodes), vec!["c"]);
+
+    // Too few datums on the bad node to be sure.
+    let nodes = [node("a", 50, 1), node("c", 0, 4)];
+    assert!(names(&nodes).is_empty());
+
+    // Every node is failing, so it's probably the job.
+    let nodes = [node("a", 10, 30), node("b", 12, 28)];
+    assert!(names(&nodes).is_empty());
+
+    // Only one node, so there's nothing to compare against.
+    assert!(names(&[node("a", 0, 20)]).is_empty());
+}
+
+#[test]
+fn audit_check_names_match_json() {
+    for &check in AuditCheck::ALL {
+        assert_eq!(
+            serde_json::to_value(check).unwrap(),
+            serde_json::Value::String(check.name().to_owned())
+        );
+    }
+}
+
+#[test]
+fn default_output_log_uri_is_outside_egress() {
+    assert_eq!(
+        default_output_log_uri("gs://bucket/out/"),
+        "gs://bucket/out-falconeri-logs/",
+    );
+    assert_eq!(
+        default_output_log_uri("s3://bucket/a/b"),
+        "s3://bucket/a/b-falconeri-logs/",
+    );
+    assert_eq!(
+        default_output_log_uri("gs://bucket/"),
+        "gs://bucket/falconeri-logs/",
+    );
+}
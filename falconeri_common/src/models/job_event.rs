@@ -0,0 +1,87 @@
+use diesel_async::RunQueryDsl;
+use utoipa::ToSchema;
+
+use crate::{prelude::*, schema::*};
+
+/// Something which happened to a job outside of the normal flow of datums,
+/// usually an action taken by the babysitter. We record these so that users
+/// can see them without reading our server logs.
+#[derive(Clone, Debug, Deserialize, Identifiable, Queryable, Serialize, ToSchema)]
+#[diesel(table_name = job_events)]
+pub struct JobEvent {
+    /// The unique ID of this event.
+    pub id: Uuid,
+    /// When this event happened.
+    pub created_at: NaiveDateTime,
+    /// The job to which this event happened.
+    pub job_id: Uuid,
+    /// The datum to which this event happened, if any.
+    pub datum_id: Option<Uuid>,
+    /// What kind of event this was.
+    pub kind: JobEventKind,
+    /// A human-readable description of this event.
+    pub message: String,
+}
+
+impl JobEvent {
+    /// Record an event for `job_id`. Call this inside the same transaction as
+    /// the action it describes, so that we only record actions which
+    /// actually happened.
+    #[instrument(skip_all, fields(job_id = %job_id, kind = %kind), level = "trace")]
+    pub async fn record(
+        job_id: Uuid,
+        datum_id: Option<Uuid>,
+        kind: JobEventKind,
+        message: &str,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<()> {
+        diesel::insert_into(job_events::table)
+            .values((
+                job_events::job_id.eq(job_id),
+                job_events::datum_id.eq(datum_id),
+                job_events::kind.eq(kind),
+                job_events::message.eq(message),
+            ))
+            .execute(conn)
+            .await
+            .with_context(|| {
+                format!("could not record {} event for {}", kind, job_id)
+            })?;
+        Ok(())
+    }
+
+    /// Find the most recent events for `job_id`, oldest first. If `limit` is
+    /// specified, return at most that many events.
+    #[instrument(skip_all, fields(job_id = %job_id), level = "trace")]
+    pub async fn recent_for_job(
+        job_id: Uuid,
+        limit: Option<i64>,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<Vec<JobEvent>> {
+        let mut query = job_events::table
+            .filter(job_events::job_id.eq(job_id))
+            .order_by((job_events::created_at.desc(), job_events::id.desc()))
+            .into_boxed();
+        if let Some(limit) = limit {
+            query = query.limit(limit);
+        }
+        let mut events = query
+            .load::<JobEvent>(conn)
+            .await
+            .with_context(|| format!("could not load events for job {}", job_id))?;
+        events.reverse();
+        Ok(events)
+    }
+
+    /// Generate a sample value for testing.
+    pub fn factory(job: &Job) -> Self {
+        JobEvent {
+            id: Uuid::new_v4(),
+            created_at: Utc::now().naive_utc(),
+            job_id: job.id,
+            datum_id: None,
+            kind: JobEventKind::JobScaledDown,
+            message: "scaled down from 10 to 5 workers".to_owned(),
+        }
+    }
+}
@@ -5,12 +5,21 @@ use utoipa::ToSchema;
 
 use crate::prelude::*;
 
+mod api_token;
 mod datum;
 mod input_file;
 mod job;
+mod job_event;
 mod output_file;
+mod quota;
+mod registered_pipeline;
+mod server_settings;
+mod worker_token;
 
-pub use self::{datum::*, input_file::*, job::*, output_file::*};
+pub use self::{
+    api_token::*, datum::*, input_file::*, job::*, job_event::*, output_file::*,
+    quota::*, registered_pipeline::*, server_settings::*, worker_token::*,
+};
 
 /// Custom SQL types.
 pub mod sql_types {
@@ -18,6 +27,16 @@ pub mod sql_types {
     #[derive(QueryId, SqlType)]
     #[diesel(postgres_type(name = "status"))]
     pub struct Status;
+
+    /// A failure class enumeration type for use in Diesel's `table!` macro.
+    #[derive(QueryId, SqlType)]
+    #[diesel(postgres_type(name = "failure_class"))]
+    pub struct FailureClass;
+
+    /// A job event kind enumeration type for use in Diesel's `table!` macro.
+    #[derive(QueryId, SqlType)]
+    #[diesel(postgres_type(name = "job_event_kind"))]
+    pub struct JobEventKind;
 }
 
 /// Possible status values.
@@ -38,12 +57,28 @@ pub mod sql_types {
 #[diesel(sql_type = sql_types::Status)]
 #[serde(rename_all = "snake_case")]
 pub enum Status {
+    /// This job is waiting for other jobs to finish before it can start. Only
+    /// used for jobs.
+    Waiting,
+    /// This job is still being created, and its datums are being added in
+    /// the background. Only used for jobs.
+    Creating,
     /// This record is ready to be processed.
     Ready,
     /// This record is currently being processed.
     Running,
+    /// This job is running, and new datums will be added as new input files
+    /// appear, until somebody stops it. Only used for jobs.
+    Streaming,
     /// This record has been successfully processed.
     Done,
+    /// This job finished, but some of its datums failed. There were few
+    /// enough failures to stay within the job's failure threshold. Only used
+    /// for jobs.
+    DoneWithErrors,
+    /// This datum's inputs were already processed successfully by an earlier
+    /// job, so we didn't process them again. Only used for datums.
+    Skipped,
     /// This record could not be processed.
     Error,
     /// This record has been canceled, and further processing should be
@@ -56,18 +91,44 @@ impl Status {
     /// or been cancelled.
     pub fn has_finished(self) -> bool {
         match self {
-            Status::Ready | Status::Running => false,
-            Status::Done | Status::Error | Status::Canceled => true,
+            Status::Waiting
+            | Status::Creating
+            | Status::Ready
+            | Status::Running
+            | Status::Streaming => false,
+            Status::Done
+            | Status::DoneWithErrors
+            | Status::Skipped
+            | Status::Error
+            | Status::Canceled => true,
         }
     }
+
+    /// Return true if this is the status of a job which finished well enough
+    /// for later jobs to use its output. This includes jobs whose failures
+    /// stayed within their failure threshold.
+    pub fn has_succeeded(self) -> bool {
+        matches!(self, Status::Done | Status::DoneWithErrors)
+    }
+
+    /// Return true if this is the status of a job whose workers should be
+    /// processing datums.
+    pub fn is_active(self) -> bool {
+        matches!(self, Status::Running | Status::Streaming)
+    }
 }
 
 impl fmt::Display for Status {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let s = match *self {
+            Status::Waiting => "waiting",
+            Status::Creating => "creating",
             Status::Ready => "ready",
             Status::Running => "running",
+            Status::Streaming => "streaming",
             Status::Done => "done",
+            Status::DoneWithErrors => "done_with_errors",
+            Status::Skipped => "skipped",
             Status::Error => "error",
             Status::Canceled => "canceled",
         };
@@ -78,9 +139,14 @@ impl fmt::Display for Status {
 impl ::diesel::serialize::ToSql<sql_types::Status, Pg> for Status {
     fn to_sql(&self, out: &mut serialize::Output<'_, '_, Pg>) -> serialize::Result {
         match *self {
+            Status::Waiting => out.write_all(b"waiting")?,
+            Status::Creating => out.write_all(b"creating")?,
             Status::Ready => out.write_all(b"ready")?,
             Status::Running => out.write_all(b"running")?,
+            Status::Streaming => out.write_all(b"streaming")?,
             Status::Done => out.write_all(b"done")?,
+            Status::DoneWithErrors => out.write_all(b"done_with_errors")?,
+            Status::Skipped => out.write_all(b"skipped")?,
             Status::Error => out.write_all(b"error")?,
             Status::Canceled => out.write_all(b"canceled")?,
         }
@@ -91,9 +157,14 @@ impl ::diesel::serialize::ToSql<sql_types::Status, Pg> for Status {
 impl ::diesel::deserialize::FromSql<sql_types::Status, Pg> for Status {
     fn from_sql(bytes: <Pg as Backend>::RawValue<'_>) -> deserialize::Result<Self> {
         match <String as diesel::deserialize::FromSql<diesel::sql_types::Text, Pg>>::from_sql(bytes)?.as_str() {
+            "waiting" => Ok(Status::Waiting),
+            "creating" => Ok(Status::Creating),
             "ready" => Ok(Status::Ready),
             "running" => Ok(Status::Running),
+            "streaming" => Ok(Status::Streaming),
             "done" => Ok(Status::Done),
+            "done_with_errors" => Ok(Status::DoneWithErrors),
+            "skipped" => Ok(Status::Skipped),
             "error" => Ok(Status::Error),
             "canceled" => Ok(Status::Canceled),
             val => {
@@ -102,3 +173,146 @@ impl ::diesel::deserialize::FromSql<sql_types::Status, Pg> for Status {
         }
     }
 }
+
+/// Why did a datum fail? Workers classify failures using the exit code of
+/// their command, when possible.
+#[derive(
+    AsExpression,
+    Clone,
+    Copy,
+    Debug,
+    Deserialize,
+    Eq,
+    FromSqlRow,
+    PartialEq,
+    Serialize,
+    ToSchema,
+)]
+#[diesel(sql_type = sql_types::FailureClass)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureClass {
+    /// The failure was probably transient, and the datum may be retried.
+    Retryable,
+    /// The datum will never succeed, so don't retry it.
+    Permanent,
+}
+
+impl fmt::Display for FailureClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match *self {
+            FailureClass::Retryable => "retryable",
+            FailureClass::Permanent => "permanent",
+        };
+        s.fmt(f)
+    }
+}
+
+impl ::diesel::serialize::ToSql<sql_types::FailureClass, Pg> for FailureClass {
+    fn to_sql(&self, out: &mut serialize::Output<'_, '_, Pg>) -> serialize::Result {
+        match *self {
+            FailureClass::Retryable => out.write_all(b"retryable")?,
+            FailureClass::Permanent => out.write_all(b"permanent")?,
+        }
+        Ok(serialize::IsNull::No)
+    }
+}
+
+impl ::diesel::deserialize::FromSql<sql_types::FailureClass, Pg> for FailureClass {
+    fn from_sql(bytes: <Pg as Backend>::RawValue<'_>) -> deserialize::Result<Self> {
+        match <String as diesel::deserialize::FromSql<diesel::sql_types::Text, Pg>>::from_sql(bytes)?.as_str() {
+            "retryable" => Ok(FailureClass::Retryable),
+            "permanent" => Ok(FailureClass::Permanent),
+            val => Err(format!(
+                "Unrecognized failure class value from database: {}",
+                val
+            )
+            .into()),
+        }
+    }
+}
+
+/// What kind of thing happened to a job. See [`JobEvent`].
+#[derive(
+    AsExpression,
+    Clone,
+    Copy,
+    Debug,
+    Deserialize,
+    Eq,
+    FromSqlRow,
+    PartialEq,
+    Serialize,
+    ToSchema,
+)]
+#[diesel(sql_type = sql_types::JobEventKind)]
+#[serde(rename_all = "snake_case")]
+pub enum JobEventKind {
+    /// The job stopped making progress while it was being created, so we
+    /// marked it as an error.
+    JobCreationStalled,
+    /// The job's Kubernetes job disappeared, so we marked it as an error.
+    JobVanished,
+    /// We scaled down the job's parallelism because it was running out of
+    /// datums.
+    JobScaledDown,
+    /// A user changed the job's parallelism.
+    JobScaled,
+    /// A datum's worker pod disappeared, so we marked the datum as an error.
+    DatumReaped,
+    /// A failed datum was scheduled to run again.
+    DatumRescheduled,
+    /// We stopped giving the job's datums to pods on a node which was failing
+    /// far more of them than other nodes.
+    NodeExcluded,
+}
+
+impl fmt::Display for JobEventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match *self {
+            JobEventKind::JobCreationStalled => "job_creation_stalled",
+            JobEventKind::JobVanished => "job_vanished",
+            JobEventKind::JobScaledDown => "job_scaled_down",
+            JobEventKind::JobScaled => "job_scaled",
+            JobEventKind::DatumReaped => "datum_reaped",
+            JobEventKind::DatumRescheduled => "datum_rescheduled",
+            JobEventKind::NodeExcluded => "node_excluded",
+        };
+        s.fmt(f)
+    }
+}
+
+impl ::diesel::serialize::ToSql<sql_types::JobEventKind, Pg> for JobEventKind {
+    fn to_sql(&self, out: &mut serialize::Output<'_, '_, Pg>) -> serialize::Result {
+        match *self {
+            JobEventKind::JobCreationStalled => {
+                out.write_all(b"job_creation_stalled")?
+            }
+            JobEventKind::JobVanished => out.write_all(b"job_vanished")?,
+            JobEventKind::JobScaledDown => out.write_all(b"job_scaled_down")?,
+            JobEventKind::JobScaled => out.write_all(b"job_scaled")?,
+            JobEventKind::DatumReaped => out.write_all(b"datum_reaped")?,
+            JobEventKind::DatumRescheduled => out.write_all(b"datum_rescheduled")?,
+            JobEventKind::NodeExcluded => out.write_all(b"node_excluded")?,
+        }
+        Ok(serialize::IsNull::No)
+    }
+}
+
+impl ::diesel::deserialize::FromSql<sql_types::JobEventKind, Pg> for JobEventKind {
+    fn from_sql(bytes: <Pg as Backend>::RawValue<'_>) -> deserialize::Result<Self> {
+        match <String as diesel::deserialize::FromSql<diesel::sql_types::Text, Pg>>::from_sql(bytes)?.as_str() {
+            "job_creation_stalled" => Ok(JobEventKind::JobCreationStalled),
+            "job_vanished" => Ok(JobEventKind::JobVanished),
+            "job_scaled_down" => Ok(JobEventKind::JobScaledDown),
+            "job_scaled" => Ok(JobEventKind::JobScaled),
+            "datum_reaped" => Ok(JobEventKind::DatumReaped),
+            "datum_rescheduled" => Ok(JobEventKind::DatumRescheduled),
+            "node_excluded" => Ok(JobEventKind::NodeExcluded),
+            val => Err(format!(
+                "Unrecognized job event kind value from database: {}",
+                val
+            )
+            .into()),
+        }
+    }
+}
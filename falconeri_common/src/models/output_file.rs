@@ -1,8 +1,56 @@
+use std::{collections::HashSet, error};
+
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
 use diesel_async::RunQueryDsl;
 use utoipa::ToSchema;
 
 use crate::{prelude::*, schema::*};
 
+/// A datum tried to write output URIs which another datum in the same job has
+/// already written. This usually means that the pipeline's input grouping
+/// sends two datums to the same output path, and one would silently overwrite
+/// the other.
+#[derive(Debug)]
+pub struct OutputUriConflict {
+    /// The datum which tried to write the URIs.
+    pub datum_id: Uuid,
+    /// The URIs which were already taken.
+    pub uris: Vec<String>,
+    /// The datums which already wrote them, if we know.
+    pub other_datum_ids: Vec<Uuid>,
+}
+
+impl fmt::Display for OutputUriConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "datum {} cannot write output URIs {:?}, because ",
+            self.datum_id, self.uris
+        )?;
+        if self.other_datum_ids.is_empty() {
+            write!(f, "they were already written by another datum")?;
+        } else {
+            let others = self
+                .other_datum_ids
+                .iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<_>>();
+            write!(
+                f,
+                "they were already written by datum {}",
+                others.join(", ")
+            )?;
+        }
+        write!(
+            f,
+            " in the same job (check that your input grouping doesn't send \
+             two datums to the same output path)"
+        )
+    }
+}
+
+impl error::Error for OutputUriConflict {}
+
 /// An output file uploaded from a worker.
 #[derive(
     Associations, Debug, Deserialize, Identifiable, Queryable, Serialize, ToSchema,
@@ -25,6 +73,13 @@ pub struct OutputFile {
     pub datum_id: Uuid,
     /// The URI to which we uploaded this file.
     pub uri: String,
+    /// The size of this file in bytes, if the worker reported it.
+    #[serde(default)]
+    pub size: Option<i64>,
+    /// The SHA-256 hash of this file, in lowercase hexadecimal, if the worker
+    /// reported it.
+    #[serde(default)]
+    pub sha256: Option<String>,
 }
 
 impl OutputFile {
@@ -38,8 +93,127 @@ impl OutputFile {
             .with_context(|| format!("could not load output file {}", id))
     }
 
-    /// Fetch all the input files corresponding to `datums`, returning grouped
-    /// in the same order.
+    /// Make sure that no other datum in `datum`'s job has already written any
+    /// of `uris`, and that `uris` has no duplicates. Returns an
+    /// [`OutputUriConflict`] error if it does.
+    #[instrument(skip_all, fields(datum = %datum.id), level = "trace")]
+    pub async fn check_uris_unclaimed(
+        datum: &Datum,
+        uris: &[&str],
+        conn: &mut AsyncPgConnection,
+    ) -> Result<()> {
+        let duplicates = duplicate_uris(uris);
+        if !duplicates.is_empty() {
+            return Err(OutputUriConflict {
+                datum_id: datum.id,
+                uris: duplicates,
+                other_datum_ids: vec![datum.id],
+            }
+            .into());
+        }
+
+        let claimed = output_files::table
+            .filter(output_files::job_id.eq(datum.job_id))
+            .filter(output_files::datum_id.ne(datum.id))
+            .filter(output_files::uri.eq_any(uris))
+            .select((output_files::datum_id, output_files::uri))
+            .order_by(output_files::uri)
+            .load::<(Uuid, String)>(conn)
+            .await
+            .with_context(|| {
+                format!("could not check output URIs for datum {}", datum.id)
+            })?;
+        if claimed.is_empty() {
+            return Ok(());
+        }
+        let mut other_datum_ids =
+            claimed.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+        other_datum_ids.sort();
+        other_datum_ids.dedup();
+        Err(OutputUriConflict {
+            datum_id: datum.id,
+            uris: claimed.into_iter().map(|(_, uri)| uri).collect(),
+            other_datum_ids,
+        }
+        .into())
+    }
+
+    /// Get all the output files which `job_id` successfully uploaded, sorted by
+    /// URI.
+    #[instrument(skip_all, fields(job = %job_id), level = "trace")]
+    pub async fn done_for_job(
+        job_id: Uuid,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<Vec<OutputFile>> {
+        output_files::table
+            .filter(output_files::job_id.eq(job_id))
+            .filter(output_files::status.eq(Status::Done))
+            .order_by(output_files::uri)
+            .load(conn)
+            .await
+            .with_context(|| format!("could not load output files for job {}", job_id))
+    }
+
+    /// Get up to `limit` output files successfully uploaded by any of
+    /// `job_ids`, sorted by URI, starting after the URI `after`. If several
+    /// jobs uploaded the same URI, we only return the newest file.
+    ///
+    /// Pass the last URI of each page as `after` to get the next page.
+    #[instrument(skip_all, fields(job_ids = ?job_ids, after = ?after), level = "trace")]
+    pub async fn done_page_for_jobs(
+        job_ids: &[Uuid],
+        after: Option<&str>,
+        limit: i64,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<Vec<OutputFile>> {
+        // Every URI sorts after the empty string.
+        output_files::table
+            .filter(output_files::job_id.eq_any(job_ids))
+            .filter(output_files::status.eq(Status::Done))
+            .filter(output_files::uri.gt(after.unwrap_or("")))
+            .distinct_on(output_files::uri)
+            .order_by((output_files::uri, output_files::created_at.desc()))
+            .limit(limit)
+            .load(conn)
+            .await
+            .with_context(|| format!("could not load output files for {:?}", job_ids))
+    }
+
+    /// Get the URIs of the output files registered by `datum` which no other
+    /// datum has registered. These are the files which we can safely delete
+    /// from storage before re-running `datum`.
+    #[instrument(skip_all, fields(datum_id = %datum.id), level = "trace")]
+    pub async fn orphaned_uris_for_datum(
+        datum: &Datum,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<Vec<String>> {
+        let uris = OutputFile::belonging_to(datum)
+            .select(output_files::uri)
+            .order_by(output_files::uri)
+            .load::<String>(conn)
+            .await
+            .with_context(|| {
+                format!("could not load output files for datum {}", datum.id)
+            })?;
+        let shared_uris = output_files::table
+            .filter(output_files::uri.eq_any(&uris))
+            .filter(output_files::datum_id.ne(datum.id))
+            .select(output_files::uri)
+            .distinct()
+            .load::<String>(conn)
+            .await
+            .with_context(|| {
+                format!("could not check output files for datum {}", datum.id)
+            })?;
+        let mut orphaned_uris = uris
+            .into_iter()
+            .filter(|uri| !shared_uris.contains(uri))
+            .collect::<Vec<_>>();
+        orphaned_uris.dedup();
+        Ok(orphaned_uris)
+    }
+
+    /// Delete all the output files belonging to `datum`.
     #[instrument(skip_all, fields(datum_id = %datum.id), level = "trace")]
     pub async fn delete_for_datum(
         datum: &Datum,
@@ -69,6 +243,36 @@ impl OutputFile {
         Ok(())
     }
 
+    /// Record the `sizes` and `sha256s` reported for the output files with
+    /// the specified `ids`. All three slices must have the same length.
+    #[instrument(skip_all, fields(count = ids.len()), level = "trace")]
+    pub async fn record_sizes_and_checksums(
+        ids: &[Uuid],
+        sizes: &[Option<i64>],
+        sha256s: &[Option<String>],
+        conn: &mut AsyncPgConnection,
+    ) -> Result<()> {
+        use diesel::sql_types::{Array, BigInt, Nullable, Text, Uuid as SqlUuid};
+
+        if ids.is_empty() {
+            return Ok(());
+        }
+        // Update all our files in a single round trip.
+        diesel::sql_query(
+            "UPDATE output_files \
+             SET size = v.size, sha256 = v.sha256 \
+             FROM unnest($1, $2, $3) AS v(id, size, sha256) \
+             WHERE output_files.id = v.id",
+        )
+        .bind::<Array<SqlUuid>, _>(ids)
+        .bind::<Array<Nullable<BigInt>>, _>(sizes)
+        .bind::<Array<Nullable<Text>>, _>(sha256s)
+        .execute(conn)
+        .await
+        .context("can't record output file sizes")?;
+        Ok(())
+    }
+
     /// Mark the specified output files as having been unsuccessfully processed.
     #[instrument(skip_all, fields(ids = ?ids), level = "trace")]
     pub async fn mark_ids_as_error(
@@ -136,7 +340,9 @@ pub struct NewOutputFile {
 }
 
 impl NewOutputFile {
-    /// Insert new output files into the database.
+    /// Insert new output files into the database. If another datum wins a
+    /// race to write the same URI, this returns an [`OutputUriConflict`]
+    /// error.
     #[instrument(skip_all, level = "trace")]
     pub async fn insert_all(
         output_files: &[Self],
@@ -146,11 +352,53 @@ impl NewOutputFile {
             output_file_count = output_files.len(),
             "inserting output files"
         );
-        let output_files = diesel::insert_into(output_files::table)
+        let inserted = diesel::insert_into(output_files::table)
             .values(output_files)
             .get_results::<OutputFile>(conn)
-            .await
-            .context("error inserting datums")?;
-        Ok(output_files)
+            .await;
+        match inserted {
+            Ok(inserted) => Ok(inserted),
+            Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _))
+                if !output_files.is_empty() =>
+            {
+                Err(OutputUriConflict {
+                    datum_id: output_files[0].datum_id,
+                    uris: output_files.iter().map(|f| f.uri.clone()).collect(),
+                    other_datum_ids: vec![],
+                }
+                .into())
+            }
+            Err(err) => Err(err).context("error inserting output files"),
+        }
     }
 }
+
+/// Find any URIs which appear more than once in `uris`, in order.
+fn duplicate_uris(uris: &[&str]) -> Vec<String> {
+    let mut seen = HashSet::new();
+    let mut duplicates = vec![];
+    for &uri in uris {
+        if !seen.insert(uri) && !duplicates.iter().any(|d| d == uri) {
+            duplicates.push(uri.to_owned());
+        }
+    }
+    duplicates
+}
+
+#[test]
+fn duplicate_uris_are_reported_once() {
+    assert!(duplicate_uris(&["gs://b/a", "gs://b/b"]).is_empty());
+    assert_eq!(
+        duplicate_uris(&["gs://b/a", "gs://b/b", "gs://b/a", "gs://b/a"]),
+        vec!["gs://b/a".to_owned()]
+    );
+
+    let conflict = OutputUriConflict {
+        datum_id: Uuid::nil(),
+        uris: vec!["gs://b/a".to_owned()],
+        other_datum_ids: vec![],
+    };
+    let message = conflict.to_string();
+    assert!(message.contains("\"gs://b/a\""));
+    assert!(message.contains("already written by another datum"));
+}
@@ -0,0 +1,336 @@
+use std::{error, fmt, result};
+
+use diesel::dsl;
+use diesel_async::RunQueryDsl;
+use utoipa::ToSchema;
+
+use crate::{prelude::*, schema::*};
+
+/// The team whose quota applies to jobs which don't name a team. All such
+/// jobs share this quota.
+pub const DEFAULT_QUOTA_TEAM: &str = "default";
+
+/// The first half of the PostgreSQL advisory lock key used by
+/// [`Quota::lock_team`]. The second half is a hash of the team name. This is
+/// arbitrary, but it must be the same in every `falconerid`. (It spells "quot"
+/// in ASCII.)
+const TEAM_LOCK_NAMESPACE: i32 = 0x7175_6f74;
+
+/// Limits on how much of the cluster a team's jobs may use at once. Jobs
+/// belong to the team named by `team` in their pipeline spec, or to
+/// [`DEFAULT_QUOTA_TEAM`] if they don't name one.
+#[derive(Clone, Debug, Deserialize, Identifiable, Queryable, Serialize, ToSchema)]
+#[diesel(table_name = quotas, primary_key(team))]
+pub struct Quota {
+    /// The team to which this quota applies.
+    pub team: String,
+    /// When this quota was created.
+    pub created_at: NaiveDateTime,
+    /// When this quota was last updated.
+    pub updated_at: NaiveDateTime,
+    /// The maximum number of jobs which may be creating, running or streaming
+    /// at once. Unlimited if missing.
+    pub max_running_jobs: Option<i32>,
+    /// The maximum total parallelism of the team's creating, running and
+    /// streaming jobs. Unlimited if missing.
+    pub max_parallelism: Option<i32>,
+}
+
+impl Quota {
+    /// Find the quota for `team`, if there is one.
+    #[instrument(skip_all, fields(team = %team), level = "trace")]
+    pub async fn find(
+        team: &str,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<Option<Quota>> {
+        quotas::table
+            .find(team)
+            .first(conn)
+            .await
+            .optional()
+            .with_context(|| format!("could not load quota for team {:?}", team))
+    }
+
+    /// List all quotas, sorted by team.
+    #[instrument(skip_all, level = "trace")]
+    pub async fn list(conn: &mut AsyncPgConnection) -> Result<Vec<Quota>> {
+        quotas::table
+            .order_by(quotas::team)
+            .load(conn)
+            .await
+            .context("could not list quotas")
+    }
+
+    /// Delete this quota.
+    #[instrument(skip_all, fields(team = %self.team), level = "trace")]
+    pub async fn delete(self, conn: &mut AsyncPgConnection) -> Result<()> {
+        diesel::delete(quotas::table.find(&self.team))
+            .execute(conn)
+            .await
+            .with_context(|| format!("could not delete quota for {}", self.team))?;
+        Ok(())
+    }
+
+    /// Lock `team`'s quota until the end of the current transaction, so that
+    /// two callers can't both see that the team has room and then both use
+    /// it. Must be called inside a transaction.
+    #[instrument(skip_all, fields(team = %team), level = "trace")]
+    pub async fn lock_team(team: &str, conn: &mut AsyncPgConnection) -> Result<()> {
+        use diesel::sql_types::{Integer, Text};
+        diesel::sql_query("SELECT pg_advisory_xact_lock($1, hashtext($2))")
+            .bind::<Integer, _>(TEAM_LOCK_NAMESPACE)
+            .bind::<Text, _>(team)
+            .execute(conn)
+            .await
+            .with_context(|| format!("could not lock quota for team {:?}", team))?;
+        Ok(())
+    }
+
+    /// Make sure that `team` can start another job with `parallelism`
+    /// workers. Returns a [`QuotaExceeded`] error if it can't. Jobs without a
+    /// team use the [`DEFAULT_QUOTA_TEAM`] quota, and teams without a quota
+    /// are never limited.
+    ///
+    /// This locks the team's quota, so call it in the same transaction which
+    /// inserts the job.
+    #[instrument(skip_all, fields(team = ?team), level = "trace")]
+    pub async fn enforce(
+        team: Option<&str>,
+        parallelism: u32,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<()> {
+        let team = team.unwrap_or(DEFAULT_QUOTA_TEAM);
+        Quota::lock_team(team, conn).await?;
+        let Some(quota) = Quota::find(team, conn).await? else {
+            return Ok(());
+        };
+        let usage = QuotaUsage::for_team(team, conn).await?;
+        quota.check(&usage, i64::from(parallelism))?;
+        Ok(())
+    }
+
+    /// Make sure that `team` can add `additional_parallelism` workers to one
+    /// of its existing jobs. Unlike [`Quota::enforce`], this doesn't check
+    /// the number of running jobs, because the job is already running.
+    ///
+    /// This locks the team's quota, so call it in the same transaction which
+    /// updates the job's parallelism.
+    #[instrument(skip_all, fields(team = ?team), level = "trace")]
+    pub async fn enforce_scale_up(
+        team: Option<&str>,
+        additional_parallelism: u32,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<()> {
+        let team = team.unwrap_or(DEFAULT_QUOTA_TEAM);
+        Quota::lock_team(team, conn).await?;
+        let Some(quota) = Quota::find(team, conn).await? else {
+            return Ok(());
+        };
+        let usage = QuotaUsage::for_team(team, conn).await?;
+        quota.check_parallelism(&usage, i64::from(additional_parallelism))?;
+        Ok(())
+    }
+
+    /// Would starting a job with `parallelism` workers exceed this quota,
+    /// given the team's current `usage`?
+    pub fn check(
+        &self,
+        usage: &QuotaUsage,
+        parallelism: i64,
+    ) -> result::Result<(), QuotaExceeded> {
+        if let Some(max_running_jobs) = self.max_running_jobs {
+            if usage.running_jobs >= i64::from(max_running_jobs) {
+                return Err(QuotaExceeded::RunningJobs {
+                    team: self.team.clone(),
+                    max_running_jobs,
+                    running_jobs: usage.running_jobs,
+                });
+            }
+        }
+        self.check_parallelism(usage, parallelism)
+    }
+
+    /// Would adding `parallelism` workers exceed this quota's total
+    /// parallelism, given the team's current `usage`?
+    pub fn check_parallelism(
+        &self,
+        usage: &QuotaUsage,
+        parallelism: i64,
+    ) -> result::Result<(), QuotaExceeded> {
+        if let Some(max_parallelism) = self.max_parallelism {
+            if usage.parallelism + parallelism > i64::from(max_parallelism) {
+                return Err(QuotaExceeded::Parallelism {
+                    team: self.team.clone(),
+                    max_parallelism,
+                    parallelism_in_use: usage.parallelism,
+                    requested_parallelism: parallelism,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How much of the cluster a team's jobs are currently using.
+#[derive(Debug, Default, Deserialize, Serialize, ToSchema)]
+pub struct QuotaUsage {
+    /// The number of jobs which are creating, running or streaming.
+    pub running_jobs: i64,
+    /// The total target parallelism of those jobs.
+    pub parallelism: i64,
+}
+
+impl QuotaUsage {
+    /// Look up how much of the cluster `team` is using. For
+    /// [`DEFAULT_QUOTA_TEAM`], this includes jobs without a team.
+    #[instrument(skip_all, fields(team = %team), level = "trace")]
+    pub async fn for_team(
+        team: &str,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<QuotaUsage> {
+        let query = jobs::table
+            .filter(jobs::status.eq_any(vec![
+                Status::Creating,
+                Status::Running,
+                Status::Streaming,
+            ]))
+            .select((dsl::count_star(), dsl::sum(jobs::target_parallelism)))
+            .into_boxed();
+        let query = if team == DEFAULT_QUOTA_TEAM {
+            query.filter(jobs::team.is_null().or(jobs::team.eq(team)))
+        } else {
+            query.filter(jobs::team.eq(team))
+        };
+        let (running_jobs, parallelism) = query
+            .first::<(i64, Option<i64>)>(conn)
+            .await
+            .with_context(|| format!("could not look up usage of team {:?}", team))?;
+        Ok(QuotaUsage {
+            running_jobs,
+            parallelism: parallelism.unwrap_or(0),
+        })
+    }
+}
+
+/// A job can't start because its team is using too much of the cluster.
+#[derive(Debug)]
+pub enum QuotaExceeded {
+    /// The team already has as many running jobs as it's allowed.
+    RunningJobs {
+        /// The team which is over quota.
+        team: String,
+        /// The team's limit.
+        max_running_jobs: i32,
+        /// How many jobs the team is running.
+        running_jobs: i64,
+    },
+    /// The new job would take the team over its total parallelism.
+    Parallelism {
+        /// The team which is over quota.
+        team: String,
+        /// The team's limit.
+        max_parallelism: i32,
+        /// The parallelism of the team's existing jobs.
+        parallelism_in_use: i64,
+        /// The parallelism of the new job.
+        requested_parallelism: i64,
+    },
+}
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuotaExceeded::RunningJobs {
+                team,
+                max_running_jobs,
+                running_jobs,
+            } => write!(
+                f,
+                "team {} is running {} jobs, and its quota allows at most {}; try again once some of them have finished",
+                team, running_jobs, max_running_jobs
+            ),
+            QuotaExceeded::Parallelism {
+                team,
+                max_parallelism,
+                parallelism_in_use,
+                requested_parallelism,
+            } => write!(
+                f,
+                "team {} is using {} of its {} workers, and this job needs {} more; use fewer workers or try again once some jobs have finished",
+                team, parallelism_in_use, max_parallelism, requested_parallelism
+            ),
+        }
+    }
+}
+
+impl error::Error for QuotaExceeded {}
+
+/// Data required to create or replace a `Quota`.
+#[derive(Debug, Insertable)]
+#[diesel(table_name = quotas)]
+pub struct NewQuota {
+    /// The team to which this quota applies.
+    pub team: String,
+    /// The maximum number of jobs which may be running at once.
+    pub max_running_jobs: Option<i32>,
+    /// The maximum total parallelism of the team's running jobs.
+    pub max_parallelism: Option<i32>,
+}
+
+impl NewQuota {
+    /// Insert this quota, replacing any existing quota for the same team.
+    #[instrument(skip_all, fields(team = %self.team), level = "trace")]
+    pub async fn upsert(&self, conn: &mut AsyncPgConnection) -> Result<Quota> {
+        diesel::insert_into(quotas::table)
+            .values(self)
+            .on_conflict(quotas::team)
+            .do_update()
+            .set((
+                quotas::updated_at.eq(Utc::now().naive_utc()),
+                quotas::max_running_jobs.eq(self.max_running_jobs),
+                quotas::max_parallelism.eq(self.max_parallelism),
+            ))
+            .get_result(conn)
+            .await
+            .with_context(|| format!("could not set quota for team {}", self.team))
+    }
+}
+
+#[test]
+fn check_enforces_limits() {
+    let now = Utc::now().naive_utc();
+    let quota = Quota {
+        team: "analytics".to_owned(),
+        created_at: now,
+        updated_at: now,
+        max_running_jobs: Some(2),
+        max_parallelism: Some(10),
+    };
+    let usage = |running_jobs, parallelism| QuotaUsage {
+        running_jobs,
+        parallelism,
+    };
+    assert!(quota.check(&usage(1, 6), 4).is_ok());
+    assert!(matches!(
+        quota.check(&usage(2, 0), 1),
+        Err(QuotaExceeded::RunningJobs { .. })
+    ));
+    assert!(matches!(
+        quota.check(&usage(1, 6), 5),
+        Err(QuotaExceeded::Parallelism { .. })
+    ));
+
+    let unlimited = Quota {
+        max_running_jobs: None,
+        max_parallelism: None,
+        ..quota
+    };
+    assert!(unlimited.check(&usage(100, 1000), 1000).is_ok());
+
+    // Scaling up an existing job only checks the total parallelism.
+    assert!(quota.check_parallelism(&usage(2, 6), 4).is_ok());
+    assert!(matches!(
+        quota.check_parallelism(&usage(2, 6), 5),
+        Err(QuotaExceeded::Parallelism { .. })
+    ));
+}
@@ -0,0 +1,251 @@
+use diesel_async::RunQueryDsl;
+use utoipa::ToSchema;
+
+use crate::{pipeline::PipelineSpec, prelude::*, schedule::CronSchedule, schema::*};
+
+/// A pipeline spec registered with the server, which can be run on demand
+/// or on a cron schedule.
+#[derive(Clone, Debug, Deserialize, Identifiable, Queryable, Serialize, ToSchema)]
+#[diesel(table_name = pipelines)]
+pub struct RegisteredPipeline {
+    /// The unique ID of this pipeline.
+    pub id: Uuid,
+    /// When this pipeline was registered.
+    pub created_at: NaiveDateTime,
+    /// When this pipeline was last updated.
+    pub updated_at: NaiveDateTime,
+    /// The unique name of this pipeline. Jobs started from this pipeline are
+    /// named using this as a prefix.
+    pub name: String,
+    /// The pipeline spec used to create jobs.
+    pub pipeline_spec: serde_json::Value,
+    /// A cron schedule, interpreted in UTC, if this pipeline runs
+    /// automatically.
+    pub schedule: Option<String>,
+    /// When this pipeline should next run automatically.
+    pub next_run_at: Option<NaiveDateTime>,
+    /// When we last started a job from this pipeline.
+    pub last_run_at: Option<NaiveDateTime>,
+    /// The last job started from this pipeline, if it still exists.
+    pub last_job_id: Option<Uuid>,
+}
+
+impl RegisteredPipeline {
+    /// Find a pipeline by name.
+    #[instrument(skip_all, fields(name = %name), level = "trace")]
+    pub async fn find_by_name(
+        name: &str,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<Option<RegisteredPipeline>> {
+        pipelines::table
+            .filter(pipelines::name.eq(name))
+            .first(conn)
+            .await
+            .optional()
+            .with_context(|| format!("could not load pipeline {:?}", name))
+    }
+
+    /// List all registered pipelines, sorted by name.
+    #[instrument(skip_all, level = "trace")]
+    pub async fn list(
+        conn: &mut AsyncPgConnection,
+    ) -> Result<Vec<RegisteredPipeline>> {
+        pipelines::table
+            .order_by(pipelines::name)
+            .load(conn)
+            .await
+            .context("could not list pipelines")
+    }
+
+    /// Find all pipelines which are scheduled to run at or before `now`.
+    #[instrument(skip_all, level = "trace")]
+    pub async fn find_due(
+        now: NaiveDateTime,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<Vec<RegisteredPipeline>> {
+        pipelines::table
+            .filter(pipelines::next_run_at.le(now))
+            .order_by(pipelines::next_run_at)
+            .load(conn)
+            .await
+            .context("could not look up scheduled pipelines")
+    }
+
+    /// Parse our pipeline spec.
+    pub fn pipeline_spec(&self) -> Result<PipelineSpec> {
+        serde_json::from_value(self.pipeline_spec.clone()).with_context(|| {
+            format!("could not parse pipeline spec for pipeline {}", self.name)
+        })
+    }
+
+    /// Parse our cron schedule, if we have one.
+    pub fn cron_schedule(&self) -> Result<Option<CronSchedule>> {
+        self.schedule.as_deref().map(str::parse).transpose()
+    }
+
+    /// Claim the scheduled run at `self.next_run_at`, and schedule our next
+    /// run for `next_run_at`. Returns `false` if another copy of `falconerid`
+    /// claimed this run first.
+    #[instrument(skip_all, fields(pipeline = %self.id), level = "trace")]
+    pub async fn claim_scheduled_run(
+        &mut self,
+        next_run_at: Option<NaiveDateTime>,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<bool> {
+        let claimed = diesel::update(pipelines::table)
+            .filter(pipelines::id.eq(&self.id))
+            .filter(pipelines::next_run_at.eq(self.next_run_at))
+            .set((
+                pipelines::updated_at.eq(Utc::now().naive_utc()),
+                pipelines::next_run_at.eq(next_run_at),
+            ))
+            .get_result::<RegisteredPipeline>(conn)
+            .await
+            .optional()
+            .context("could not claim scheduled pipeline run")?;
+        match claimed {
+            Some(claimed) => {
+                *self = claimed;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Record that we started `job` from this pipeline.
+    #[instrument(skip_all, fields(pipeline = %self.id, job = %job.id), level = "trace")]
+    pub async fn record_run(
+        &mut self,
+        job: &Job,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<()> {
+        let now = Utc::now().naive_utc();
+        *self = diesel::update(pipelines::table)
+            .filter(pipelines::id.eq(&self.id))
+            .set((
+                pipelines::updated_at.eq(now),
+                pipelines::last_run_at.eq(now),
+                pipelines::last_job_id.eq(job.id),
+            ))
+            .get_result(conn)
+            .await
+            .context("could not record pipeline run")?;
+        Ok(())
+    }
+
+    /// Delete this pipeline. Jobs started from it are not affected.
+    #[instrument(skip_all, fields(pipeline = %self.id), level = "trace")]
+    pub async fn delete(self, conn: &mut AsyncPgConnection) -> Result<()> {
+        diesel::delete(pipelines::table.filter(pipelines::id.eq(&self.id)))
+            .execute(conn)
+            .await
+            .with_context(|| format!("could not delete pipeline {}", self.name))?;
+        Ok(())
+    }
+}
+
+/// Make sure that `name` can be used as a pipeline name. Since we use it to
+/// name jobs, it must be a valid Kubernetes name.
+pub fn check_registered_pipeline_name(name: &str) -> Result<()> {
+    check_dns_label("pipeline name", name)
+}
+
+/// Data required to register a new pipeline.
+#[derive(AsChangeset, Debug, Insertable)]
+#[diesel(table_name = pipelines, treat_none_as_null = true)]
+pub struct NewRegisteredPipeline {
+    /// The unique name of this pipeline.
+    pub name: String,
+    /// The pipeline spec used to create jobs.
+    pub pipeline_spec: serde_json::Value,
+    /// A cron schedule, interpreted in UTC.
+    pub schedule: Option<String>,
+    /// When this pipeline should next run automatically.
+    pub next_run_at: Option<NaiveDateTime>,
+}
+
+impl NewRegisteredPipeline {
+    /// Build a new pipeline, calculating when it should first run.
+    pub fn new(
+        name: &str,
+        pipeline_spec: &PipelineSpec,
+        schedule: Option<&str>,
+        now: NaiveDateTime,
+    ) -> Result<Self> {
+        check_registered_pipeline_name(name)?;
+        let schedule = schedule.map(str::parse::<CronSchedule>).transpose()?;
+        let next_run_at = match &schedule {
+            Some(schedule) => Some(schedule.next_after(now).ok_or_else(|| {
+                format_err!("cron schedule {} will never run", schedule)
+            })?),
+            None => None,
+        };
+        Ok(NewRegisteredPipeline {
+            name: name.to_owned(),
+            pipeline_spec: serde_json::to_value(pipeline_spec)
+                .context("could not serialize pipeline spec")?,
+            schedule: schedule.map(|schedule| schedule.to_string()),
+            next_run_at,
+        })
+    }
+
+    /// Insert a new pipeline into the database. Returns `None` if a pipeline
+    /// with the same name already exists, even if it was inserted by a
+    /// concurrent request.
+    #[instrument(skip_all, fields(name = %self.name), level = "trace")]
+    pub async fn insert(
+        &self,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<Option<RegisteredPipeline>> {
+        diesel::insert_into(pipelines::table)
+            .values(self)
+            .on_conflict(pipelines::name)
+            .do_nothing()
+            .get_result(conn)
+            .await
+            .optional()
+            .with_context(|| format!("error inserting pipeline {}", self.name))
+    }
+
+    /// Insert a new pipeline, or replace the spec and schedule of an existing
+    /// pipeline with the same name.
+    #[instrument(skip_all, fields(name = %self.name), level = "trace")]
+    pub async fn upsert(
+        &self,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<RegisteredPipeline> {
+        diesel::insert_into(pipelines::table)
+            .values(self)
+            .on_conflict(pipelines::name)
+            .do_update()
+            .set((self, pipelines::updated_at.eq(Utc::now().naive_utc())))
+            .get_result(conn)
+            .await
+            .with_context(|| format!("error updating pipeline {}", self.name))
+    }
+}
+
+#[test]
+fn new_registered_pipeline_schedules_first_run() {
+    let json = include_str!("../example_pipeline_spec.json");
+    let pipeline_spec: PipelineSpec = serde_json::from_str(json).unwrap();
+    let now = "2026-10-15T12:00:00".parse::<NaiveDateTime>().unwrap();
+
+    let nightly =
+        NewRegisteredPipeline::new("nightly", &pipeline_spec, Some("0 3 * * *"), now)
+            .unwrap();
+    assert_eq!(
+        nightly.next_run_at.unwrap().to_string(),
+        "2026-10-16 03:00:00"
+    );
+
+    let manual =
+        NewRegisteredPipeline::new("manual", &pipeline_spec, None, now).unwrap();
+    assert_eq!(manual.next_run_at, None);
+
+    assert!(NewRegisteredPipeline::new("Nightly", &pipeline_spec, None, now).is_err());
+    assert!(
+        NewRegisteredPipeline::new("nightly", &pipeline_spec, Some("bogus"), now)
+            .is_err()
+    );
+}
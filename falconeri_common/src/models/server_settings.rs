@@ -0,0 +1,46 @@
+use diesel_async::RunQueryDsl;
+use utoipa::ToSchema;
+
+use crate::{prelude::*, schema::*};
+
+/// Settings which apply to the whole server. There is exactly one row of
+/// these, shared by all copies of `falconerid`.
+#[derive(Clone, Debug, Deserialize, Identifiable, Queryable, Serialize, ToSchema)]
+#[diesel(table_name = server_settings)]
+pub struct ServerSettings {
+    /// Always `true`, because there's only one row.
+    pub id: bool,
+    /// When these settings were last updated.
+    pub updated_at: NaiveDateTime,
+    /// Should we refuse to hand out datums to workers? Workers will wait and
+    /// try again. This is used while upgrading `falconerid`.
+    pub reservations_paused: bool,
+}
+
+impl ServerSettings {
+    /// Load our server settings.
+    #[instrument(skip_all, level = "trace")]
+    pub async fn load(conn: &mut AsyncPgConnection) -> Result<ServerSettings> {
+        server_settings::table
+            .find(true)
+            .first(conn)
+            .await
+            .context("could not load server settings")
+    }
+
+    /// Pause or resume datum reservations.
+    #[instrument(skip_all, fields(paused = paused), level = "trace")]
+    pub async fn set_reservations_paused(
+        paused: bool,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<ServerSettings> {
+        diesel::update(server_settings::table.find(true))
+            .set((
+                server_settings::updated_at.eq(Utc::now().naive_utc()),
+                server_settings::reservations_paused.eq(paused),
+            ))
+            .get_result(conn)
+            .await
+            .context("could not update server settings")
+    }
+}
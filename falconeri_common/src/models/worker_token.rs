@@ -0,0 +1,107 @@
+use std::time::Duration;
+
+use diesel_async::RunQueryDsl;
+use rand::{rng, RngCore};
+use sha2::{Digest, Sha256};
+
+use crate::{prelude::*, schema::*};
+
+/// A token which lets worker pods call `falconerid` on behalf of a single
+/// job. We give workers these instead of our database password, and we only
+/// store a hash of each token.
+#[derive(Clone, Debug, Identifiable, Queryable)]
+#[diesel(table_name = worker_tokens)]
+pub struct WorkerToken {
+    /// The unique ID of this token.
+    pub id: Uuid,
+    /// When this token was created.
+    pub created_at: NaiveDateTime,
+    /// The job whose workers may use this token.
+    pub job_id: Uuid,
+    /// A hash of the token. See [`hash_token`].
+    pub token_hash: String,
+    /// When this token stops working, if ever.
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+impl WorkerToken {
+    /// Create a new token for `job`, which expires after `lifetime` (if
+    /// specified). Returns the token itself, which we can't recover later.
+    #[instrument(skip_all, fields(job = %job.id), level = "trace")]
+    pub async fn mint(
+        job: &Job,
+        lifetime: Option<Duration>,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<String> {
+        let token = random_token();
+        let expires_at = lifetime
+            .map(|lifetime| -> Result<NaiveDateTime> {
+                let lifetime = chrono::Duration::from_std(lifetime)
+                    .context("worker token lifetime is too long")?;
+                Ok(Utc::now().naive_utc() + lifetime)
+            })
+            .transpose()?;
+        diesel::insert_into(worker_tokens::table)
+            .values((
+                worker_tokens::job_id.eq(job.id),
+                worker_tokens::token_hash.eq(hash_token(&token)),
+                worker_tokens::expires_at.eq(expires_at),
+            ))
+            .execute(conn)
+            .await
+            .with_context(|| {
+                format!("could not create worker token for {}", job.id)
+            })?;
+        Ok(token)
+    }
+
+    /// Find the job which may use `token`, if the token exists and hasn't
+    /// expired.
+    #[instrument(skip_all, level = "trace")]
+    pub async fn job_id_for_token(
+        token: &str,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<Option<Uuid>> {
+        let now = Utc::now().naive_utc();
+        worker_tokens::table
+            .filter(worker_tokens::token_hash.eq(hash_token(token)))
+            .filter(
+                worker_tokens::expires_at
+                    .is_null()
+                    .or(worker_tokens::expires_at.gt(now)),
+            )
+            .select(worker_tokens::job_id)
+            .first(conn)
+            .await
+            .optional()
+            .context("could not look up worker token")
+    }
+}
+
+/// Generate a new random token, long enough that it can't be guessed.
+pub(crate) fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    rng().fill_bytes(&mut bytes);
+    hex_string(&bytes)
+}
+
+/// Hash a worker token or an [`ApiToken`] for storage. Our tokens are long
+/// random strings, so a plain SHA-256 hash is enough to keep them safe if our
+/// database leaks.
+pub fn hash_token(token: &str) -> String {
+    hex_string(&Sha256::digest(token.as_bytes()))
+}
+
+/// Format `bytes` as lowercase hexadecimal.
+fn hex_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[test]
+fn token_hashes_are_stable() {
+    assert_eq!(
+        hash_token("secret"),
+        "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b"
+    );
+    assert_ne!(hash_token("secret"), hash_token("secret2"));
+}
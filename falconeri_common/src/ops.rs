@@ -0,0 +1,168 @@
+//! High-level job operations, for programs which embed Falconeri.
+//!
+//! This module is the supported way to submit and monitor Falconeri jobs from
+//! another Rust program, without shelling out to the `falconeri` CLI. The CLI
+//! itself is a thin wrapper around these functions.
+//!
+//! We try to keep these functions and their options compatible between
+//! releases, but we don't promise semver stability. The types they return,
+//! such as [`Job`] and [`JobDescribeResponse`], are shared with our REST API
+//! and our own binaries, and they gain new fields as Falconeri grows. Pin the
+//! exact version of `falconeri_common` you build against. Option structs are
+//! marked `#[non_exhaustive]`, so construct them using `Default` and their
+//! builder methods.
+//!
+//! ```no_run
+//! use falconeri_common::{ops, pipeline::PipelineSpec, prelude::*, rest_api::Client};
+//!
+//! # async fn example(pipeline_spec: PipelineSpec) -> Result<()> {
+//! let client = Client::new(ConnectVia::Cluster).await?;
+//! let options = ops::RunJobOptions::default().force(true);
+//! let job = ops::run_job(&client, &pipeline_spec, &options).await?;
+//! let job = ops::wait_for_job(&client, &job, ops::DEFAULT_POLL_INTERVAL).await?;
+//! println!("{} finished with status {}", job.job_name, job.status);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::time::Duration;
+
+use crate::{
+    pipeline::PipelineSpec,
+    prelude::*,
+    rest_api::{Client, CreateJobRequest, DatumDescribeResponse, JobDescribeResponse},
+};
+
+/// How often [`wait_for_job`] should check on a job, unless told otherwise.
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Options for [`run_job`].
+#[derive(Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct RunJobOptions {
+    /// Submit the job even if an identical job is already running.
+    pub force: bool,
+    /// Submit the job even if it has more datums than the server normally
+    /// allows.
+    pub override_datum_cap: bool,
+    /// A unique key for this submission. If a job was already submitted with
+    /// this key, we return it instead of creating a new job, which makes it
+    /// safe to retry submissions.
+    pub idempotency_key: Option<String>,
+    /// The exact name to give the job. Submission fails if a job with this
+    /// name already exists.
+    pub name: Option<String>,
+    /// A prefix for the job name, to which the server adds a random tag.
+    pub generate_name: Option<String>,
+    /// Jobs (by name or UUID) which must finish successfully before this job
+    /// starts.
+    pub depends_on: Vec<String>,
+    /// Process the output of the only job in `depends_on`, instead of the
+    /// URI of the pipeline's input.
+    pub input_from_upstream: bool,
+}
+
+impl RunJobOptions {
+    /// Set `force`.
+    pub fn force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    /// Set `override_datum_cap`.
+    pub fn override_datum_cap(mut self, override_datum_cap: bool) -> Self {
+        self.override_datum_cap = override_datum_cap;
+        self
+    }
+
+    /// Set `idempotency_key`.
+    pub fn idempotency_key(mut self, idempotency_key: impl Into<String>) -> Self {
+        self.idempotency_key = Some(idempotency_key.into());
+        self
+    }
+
+    /// Set `name`.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Set `generate_name`.
+    pub fn generate_name(mut self, generate_name: impl Into<String>) -> Self {
+        self.generate_name = Some(generate_name.into());
+        self
+    }
+
+    /// Add a job to `depends_on`.
+    pub fn depends_on(mut self, job_id_or_name: impl Into<String>) -> Self {
+        self.depends_on.push(job_id_or_name.into());
+        self
+    }
+
+    /// Set `input_from_upstream`.
+    pub fn input_from_upstream(mut self, input_from_upstream: bool) -> Self {
+        self.input_from_upstream = input_from_upstream;
+        self
+    }
+}
+
+/// Submit a new job.
+///
+/// The returned job will normally have status [`Status::Creating`], because
+/// the server creates datums in the background, or [`Status::Waiting`] if it
+/// depends on jobs which haven't finished yet.
+#[instrument(skip_all, level = "trace")]
+pub async fn run_job(
+    client: &Client,
+    pipeline_spec: &PipelineSpec,
+    options: &RunJobOptions,
+) -> Result<Job> {
+    let request = CreateJobRequest {
+        job: pipeline_spec.clone(),
+        force: options.force,
+        override_datum_cap: options.override_datum_cap,
+        idempotency_key: options.idempotency_key.clone(),
+        name: options.name.clone(),
+        generate_name: options.generate_name.clone(),
+        depends_on: options.depends_on.clone(),
+        input_from_upstream: options.input_from_upstream,
+    };
+    client.new_job(&request).await
+}
+
+/// Look up a job by its Kubernetes job name.
+#[instrument(skip_all, fields(job_name = %job_name), level = "trace")]
+pub async fn find_job(client: &Client, job_name: &str) -> Result<Job> {
+    client.find_job_by_name(job_name).await
+}
+
+/// Wait until `job` has finished, checking every `poll_interval`, and return
+/// the finished job.
+#[instrument(skip_all, fields(job = %job.id), level = "trace")]
+pub async fn wait_for_job(
+    client: &Client,
+    job: &Job,
+    poll_interval: Duration,
+) -> Result<Job> {
+    let mut job = client.job(job.id).await?;
+    while !job.status.has_finished() {
+        tokio::time::sleep(poll_interval).await;
+        job = client.job(job.id).await?;
+    }
+    Ok(job)
+}
+
+/// Get a detailed description of a job, including datum counts and errors.
+#[instrument(skip_all, fields(job = %job.id), level = "trace")]
+pub async fn describe_job(client: &Client, job: &Job) -> Result<JobDescribeResponse> {
+    client.describe_job(job.id).await
+}
+
+/// Get a detailed description of a datum, including its input files.
+#[instrument(skip_all, fields(datum = %datum_id), level = "trace")]
+pub async fn describe_datum(
+    client: &Client,
+    datum_id: Uuid,
+) -> Result<DatumDescribeResponse> {
+    client.describe_datum(datum_id).await
+}
@@ -4,12 +4,24 @@
 //!
 //! [pipespec]: http://docs.pachyderm.io/en/latest/reference/pipeline_spec.html
 
-use std::time::Duration;
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BinaryHeap},
+    fmt::Write as _,
+    str::FromStr,
+    time::Duration,
+};
 
 use schemars::JsonSchema;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
 use utoipa::ToSchema;
 
-use crate::{prelude::*, secret::Secret};
+use crate::{
+    prelude::*,
+    secret::{Secret, VaultConfig},
+    storage::RequesterPays,
+};
 
 /// Represents a pipeline `*.json` file.
 ///
@@ -28,21 +40,168 @@ pub struct PipelineSpec {
     pub resource_requests: ResourceRequests,
     /// The maximum number of times to retry a single datum.
     pub datum_tries: Option<u32>,
+    /// EXTENSION: How many seconds to wait before automatically retrying a
+    /// failed datum for the first time. The delay doubles after each attempt,
+    /// up to one hour. By default, we retry failed datums immediately.
+    #[serde(default)]
+    pub retry_backoff_seconds: Option<u32>,
     /// Timeout a running job after this many seconds have elapsed.
     #[serde(default, with = "humantime_serde")]
     #[schemars(with = "Option<String>")]
     #[schema(value_type = Option<String>)]
     pub job_timeout: Option<Duration>,
+    /// EXTENSION: How many datums do we expect this job to have? If the
+    /// actual number is outside this range, job creation will fail. This
+    /// protects against mistakes in input URIs and globs.
+    #[serde(default)]
+    pub expected_datum_count: Option<DatumCountRange>,
+    /// EXTENSION: Stop the entire job as soon as a single datum fails
+    /// permanently (after using up all of its `datum_tries`), instead of
+    /// processing the remaining datums. May also be written as `fail_fast`.
+    #[serde(default, alias = "fail_fast")]
+    pub stop_on_first_error: bool,
+    /// EXTENSION: How many datums may fail permanently before we fail the
+    /// whole job. If a job has any failures, but stays within this limit, it
+    /// finishes with status `done_with_errors`. If it exceeds this limit, we
+    /// stop it immediately, as if `stop_on_first_error` were set.
+    #[serde(default)]
+    pub max_failed_datums: Option<u32>,
+    /// EXTENSION: Like `max_failed_datums`, but as a percentage of the job's
+    /// datums, from 0 to 100.
+    #[serde(default)]
+    pub max_failed_datum_percent: Option<f64>,
+    /// EXTENSION: Keep this job open, and add a new datum whenever a new file
+    /// appears under the input URI, until the job is stopped using `falconeri
+    /// job stop-streaming`. Requires a single `atom` input with glob `/*`.
+    #[serde(default)]
+    pub streaming: bool,
+    /// EXTENSION: Skip any datum whose input files were already processed
+    /// successfully by an earlier job with the same pipeline name, and mark
+    /// it as `Status::Skipped` instead of running it again.
+    #[serde(default)]
+    pub skip_processed: bool,
+    /// EXTENSION: The team which owns this job. If the team has a quota,
+    /// the job may only start if the team has room for it.
+    #[serde(default)]
+    pub team: Option<String>,
+    /// EXTENSION: The maximum number of bytes of output to store in the
+    /// database for each datum. Longer output will be truncated, and the full
+    /// output will be uploaded to `output_log_uri`. Defaults to 1 MiB.
+    #[serde(default)]
+    pub max_inline_output_bytes: Option<u64>,
+    /// EXTENSION: Where to upload the full output of datums whose output is
+    /// too large to store in the database. Defaults to a sibling of the egress
+    /// URI; see [`crate::models::default_output_log_uri`].
+    #[serde(default)]
+    pub output_log_uri: Option<String>,
     /// EXTENSION: Kubernetes node selectors describing the nodes where we can
     /// run this job.
     #[serde(default)]
     pub node_selector: HashMap<String, String>,
+    /// EXTENSION: A Kubernetes strategic merge patch to apply to the pod
+    /// template of our worker job, for cluster-specific settings which we
+    /// don't otherwise support, such as `runtimeClassName`, annotations or a
+    /// `securityContext`.
+    #[serde(default)]
+    pub pod_template_patch: Option<Value>,
+    /// EXTENSION: How to allocate the `/scratch` volume for each worker. By
+    /// default, this uses an unsized `emptyDir` on the node's disk.
+    #[serde(default)]
+    pub scratch_volume: Option<ScratchVolume>,
+    /// EXTENSION: Cache input files on each worker, so that datums which
+    /// share input files don't download them again.
+    #[serde(default)]
+    pub input_cache: Option<InputCache>,
+    /// EXTENSION: Empty volumes to create in each worker pod, so that our
+    /// worker can share files with `init_containers` and `sidecars`.
+    #[serde(default)]
+    pub shared_volumes: Vec<SharedVolume>,
+    /// EXTENSION: Containers to run to completion in each worker pod before
+    /// our worker starts, for example to warm a cache.
+    #[serde(default)]
+    pub init_containers: Vec<ExtraContainer>,
+    /// EXTENSION: Containers to run alongside our worker, for example a local
+    /// caching proxy. These are stopped once our worker exits.
+    #[serde(default)]
+    pub sidecars: Vec<ExtraContainer>,
     /// Specify our input data.
     pub input: Input,
     /// Where to put the data when we're done with it.
     pub egress: Egress,
 }
 
+impl PipelineSpec {
+    /// Compute a SHA-256 hash of this spec in a canonical JSON form, as a
+    /// lowercase hex string. Two specs which differ only in key order or
+    /// whitespace will have the same hash.
+    pub fn canonical_hash(&self) -> Result<String> {
+        let value =
+            serde_json::to_value(self).context("could not serialize pipeline spec")?;
+        let mut canonical = String::new();
+        write_canonical_json(&value, &mut canonical)?;
+        let digest = Sha256::digest(canonical.as_bytes());
+        let mut hex = String::with_capacity(2 * digest.len());
+        for byte in digest {
+            write!(hex, "{:02x}", byte).expect("writing to string failed");
+        }
+        Ok(hex)
+    }
+}
+
+/// Write `value` as compact JSON with object keys sorted. We do this by hand
+/// because `HashMap` fields like `transform.env` serialize in arbitrary order,
+/// and `serde_json` may preserve that order depending on enabled features.
+fn write_canonical_json(value: &Value, out: &mut String) -> Result<()> {
+    match value {
+        Value::Array(items) => {
+            out.push('[');
+            for (idx, item) in items.iter().enumerate() {
+                if idx > 0 {
+                    out.push(',');
+                }
+                write_canonical_json(item, out)?;
+            }
+            out.push(']');
+        }
+        Value::Object(map) => {
+            let mut keys = map.keys().collect::<Vec<_>>();
+            keys.sort();
+            out.push('{');
+            for (idx, key) in keys.into_iter().enumerate() {
+                if idx > 0 {
+                    out.push(',');
+                }
+                out.push_str(&serde_json::to_string(key)?);
+                out.push(':');
+                write_canonical_json(&map[key], out)?;
+            }
+            out.push('}');
+        }
+        scalar => out.push_str(&serde_json::to_string(scalar)?),
+    }
+    Ok(())
+}
+
+/// An inclusive range of acceptable datum counts.
+#[derive(Clone, Debug, Deserialize, JsonSchema, PartialEq, Serialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct DatumCountRange {
+    /// The minimum number of datums, if any.
+    #[serde(default)]
+    pub min: Option<u64>,
+    /// The maximum number of datums, if any.
+    #[serde(default)]
+    pub max: Option<u64>,
+}
+
+impl DatumCountRange {
+    /// Does this range contain `count`?
+    pub fn contains(&self, count: u64) -> bool {
+        self.min.map_or(true, |min| min <= count)
+            && self.max.map_or(true, |max| count <= max)
+    }
+}
+
 /// Metadata about this pipeline.
 #[derive(Clone, Debug, Deserialize, JsonSchema, PartialEq, Serialize, ToSchema)]
 #[serde(deny_unknown_fields)]
@@ -71,8 +230,141 @@ pub struct Transform {
     /// want to declare secrets as part of our `Input::Atom` values.
     #[serde(default)]
     pub secrets: Vec<Secret>,
-    /// The Kubernetes service account to use for this job.
+    /// EXTENSION: How to log into HashiCorp Vault, if any of our `secrets`
+    /// come from Vault.
+    pub vault: Option<VaultConfig>,
+    /// The Kubernetes service account to use for this job's worker pods.
+    /// With GKE Workload Identity or EKS IAM roles for service accounts, this
+    /// controls which cloud permissions the workers have. May also be
+    /// written as `service_account_name`, like in Kubernetes.
+    #[serde(alias = "service_account_name")]
     pub service_account: Option<String>,
+    /// EXTENSION: The names of Kubernetes `kubernetes.io/dockerconfigjson`
+    /// secrets to use when pulling `image` from a private registry.
+    #[serde(default)]
+    pub image_pull_secrets: Vec<String>,
+    /// EXTENSION: Run `cmd` with this CPU niceness (see `nice(1)`). Higher
+    /// values give `cmd` a lower priority.
+    pub nice: Option<i32>,
+    /// EXTENSION: Run `cmd` with this I/O scheduling class (see `ionice(1)`).
+    pub ionice_class: Option<IoniceClass>,
+    /// EXTENSION: How many input files should each worker download at once?
+    /// Defaults to a value based on the worker's cgroup CPU and memory limits.
+    pub download_concurrency: Option<u32>,
+    /// EXTENSION: While uploading the outputs of one datum, should each worker
+    /// reserve its next datum and start downloading the inputs? This uses
+    /// extra disk space, but it helps keep `cmd` busy.
+    #[serde(default)]
+    pub prefetch_next_datum: bool,
+    /// EXTENSION: Instead of downloading input files to `/pfs`, stream each
+    /// input file into `cmd`'s standard input, and upload its standard output
+    /// directly to `egress`. Useful for simple filters.
+    #[serde(default)]
+    pub stdin_files: bool,
+    /// EXTENSION: Exit codes which mean that `cmd` failed for a transient
+    /// reason, and that the datum may be retried (if `datum_tries` allows).
+    #[serde(default = "default_retryable_exit_codes")]
+    pub retryable_exit_codes: Vec<i32>,
+    /// EXTENSION: Exit codes which mean that `cmd` will never succeed on this
+    /// datum, so that it should not be retried.
+    #[serde(default = "default_permanent_exit_codes")]
+    pub permanent_exit_codes: Vec<i32>,
+    /// EXTENSION: Bundle everything `cmd` writes to `/pfs/out` into a single
+    /// archive named after the datum, and upload that instead of the
+    /// individual files. Useful for commands which write many tiny files.
+    pub archive_outputs: Option<ArchiveFormat>,
+}
+
+/// By default, `EX_TEMPFAIL` from `sysexits.h` means "try again later".
+pub const DEFAULT_RETRYABLE_EXIT_CODES: &[i32] = &[75];
+
+/// By default, `EX_USAGE` from `sysexits.h` means "don't bother retrying".
+pub const DEFAULT_PERMANENT_EXIT_CODES: &[i32] = &[64];
+
+/// Helper for `serde(default)`.
+fn default_retryable_exit_codes() -> Vec<i32> {
+    DEFAULT_RETRYABLE_EXIT_CODES.to_owned()
+}
+
+/// Helper for `serde(default)`.
+fn default_permanent_exit_codes() -> Vec<i32> {
+    DEFAULT_PERMANENT_EXIT_CODES.to_owned()
+}
+
+/// An I/O scheduling class for `ionice(1)`. We don't support the "realtime"
+/// class, because it requires special privileges.
+#[derive(
+    Clone, Copy, Debug, Deserialize, Eq, JsonSchema, PartialEq, Serialize, ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum IoniceClass {
+    /// The normal I/O scheduling class.
+    BestEffort,
+    /// Only perform I/O when nobody else wants the disk.
+    Idle,
+}
+
+impl IoniceClass {
+    /// The argument to pass to `ionice -c`.
+    pub fn ionice_arg(self) -> &'static str {
+        match self {
+            IoniceClass::BestEffort => "2",
+            IoniceClass::Idle => "3",
+        }
+    }
+}
+
+impl FromStr for IoniceClass {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "best_effort" => Ok(IoniceClass::BestEffort),
+            "idle" => Ok(IoniceClass::Idle),
+            _ => Err(format_err!("unknown ionice class {:?}", s)),
+        }
+    }
+}
+
+/// An archive format for `transform.archive_outputs`.
+#[derive(
+    Clone, Copy, Debug, Deserialize, Eq, JsonSchema, PartialEq, Serialize, ToSchema,
+)]
+pub enum ArchiveFormat {
+    /// A gzipped tarball.
+    #[serde(rename = "tar.gz")]
+    TarGz,
+    /// A zip file.
+    #[serde(rename = "zip")]
+    Zip,
+}
+
+impl ArchiveFormat {
+    /// The file extension of this format, without a leading `.`.
+    pub fn extension(self) -> &'static str {
+        match self {
+            ArchiveFormat::TarGz => "tar.gz",
+            ArchiveFormat::Zip => "zip",
+        }
+    }
+}
+
+impl fmt::Display for ArchiveFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.extension().fmt(f)
+    }
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "tar.gz" => Ok(ArchiveFormat::TarGz),
+            "zip" => Ok(ArchiveFormat::Zip),
+            _ => Err(format_err!("unknown archive format {:?}", s)),
+        }
+    }
 }
 
 /// How much parallelism should we use?
@@ -81,6 +373,15 @@ pub struct Transform {
 pub struct ParallelismSpec {
     /// The number of workers to run.
     pub constant: u32,
+    /// EXTENSION: Reduce the number of workers as the job runs out of datums,
+    /// so that idle workers don't hold on to nodes at the end of a job.
+    #[serde(default)]
+    pub autoscale: bool,
+    /// EXTENSION: Run workers as a Kubernetes indexed job, with one completion
+    /// per worker. This is useful on clusters which restrict plain parallel
+    /// jobs. Workers still get their datums from `falconerid`.
+    #[serde(default)]
+    pub indexed: bool,
 }
 
 /// How many resources should we allocate for each worker?
@@ -95,6 +396,117 @@ pub struct ResourceRequests {
     pub cpu: f32,
 }
 
+/// How to allocate the `/scratch` volume for each worker.
+#[derive(Clone, Debug, Deserialize, JsonSchema, PartialEq, Serialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ScratchVolume {
+    /// How big should the volume be? Uses Kubernetes quantities like `"50Gi"`.
+    pub size: String,
+    /// If specified, create a dedicated persistent volume for each worker
+    /// using this storage class, instead of using the node's disk. The volume
+    /// is deleted along with the worker pod.
+    #[serde(default)]
+    pub storage_class_name: Option<String>,
+}
+
+/// How to cache input files on each worker.
+#[derive(Clone, Debug, Deserialize, JsonSchema, PartialEq, Serialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct InputCache {
+    /// The maximum total size of the cached files. Uses Kubernetes
+    /// quantities like `"20Gi"`. The least recently used files are evicted
+    /// first.
+    pub size: String,
+    /// If specified, keep the cache in this directory on the node, so that
+    /// it's shared by all the workers on the node, and kept between jobs.
+    /// Otherwise, each worker has its own cache, stored alongside its
+    /// downloaded inputs.
+    #[serde(default)]
+    pub host_path: Option<String>,
+}
+
+impl InputCache {
+    /// The maximum total size of the cached files, in bytes.
+    pub fn max_bytes(&self) -> Result<u64> {
+        parse_quantity(&self.size)
+            .with_context(|| format!("invalid input cache size {:?}", self.size))
+    }
+}
+
+/// Parse a Kubernetes quantity like `"20Gi"` or `"500M"` as a number of bytes.
+/// We only support integers with binary or decimal suffixes.
+pub fn parse_quantity(quantity: &str) -> Result<u64> {
+    const SUFFIXES: &[(&str, u64)] = &[
+        ("Ki", 1 << 10),
+        ("Mi", 1 << 20),
+        ("Gi", 1 << 30),
+        ("Ti", 1 << 40),
+        ("Pi", 1 << 50),
+        ("k", 1_000),
+        ("M", 1_000_000),
+        ("G", 1_000_000_000),
+        ("T", 1_000_000_000_000),
+        ("P", 1_000_000_000_000_000),
+    ];
+    let quantity = quantity.trim();
+    let (number, multiplier) = SUFFIXES
+        .iter()
+        .find_map(|&(suffix, multiplier)| {
+            quantity
+                .strip_suffix(suffix)
+                .map(|number| (number, multiplier))
+        })
+        .unwrap_or((quantity, 1));
+    let number = number
+        .parse::<u64>()
+        .with_context(|| format!("cannot parse quantity {:?}", quantity))?;
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format_err!("quantity {:?} is too large", quantity))
+}
+
+/// An empty volume shared between the containers in a worker pod.
+#[derive(Clone, Debug, Deserialize, JsonSchema, PartialEq, Serialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct SharedVolume {
+    /// The name of the volume, which extra containers can use to mount it.
+    pub name: String,
+    /// Where to mount this volume in our worker container.
+    pub mount_path: String,
+}
+
+/// An extra container to run in each worker pod.
+#[derive(Clone, Debug, Deserialize, JsonSchema, PartialEq, Serialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ExtraContainer {
+    /// The name of this container. Must be unique within the pod.
+    pub name: String,
+    /// The Docker image to run.
+    pub image: String,
+    /// The command to run, with arguments. Defaults to the image's entry
+    /// point.
+    #[serde(default)]
+    pub command: Vec<String>,
+    /// How many resources should we allocate for this container?
+    #[serde(default)]
+    pub resources: Option<ResourceRequests>,
+    /// Volumes to mount in this container.
+    #[serde(default)]
+    pub volume_mounts: Vec<VolumeMount>,
+}
+
+/// A volume mounted in an extra container.
+#[derive(Clone, Debug, Deserialize, JsonSchema, PartialEq, Serialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct VolumeMount {
+    /// The name of the volume to mount. This may be one of our
+    /// `shared_volumes`, or `pfs` or `scratch` to share our worker's working
+    /// directories.
+    pub name: String,
+    /// Where to mount the volume in the container.
+    pub mount_path: String,
+}
+
 /// Specify our input data.
 #[derive(Clone, Debug, Deserialize, JsonSchema, PartialEq, Serialize, ToSchema)]
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
@@ -106,10 +518,47 @@ pub enum Input {
         #[serde(rename = "URI")]
         uri: String,
         /// The repo name, used as to construct a path of the form
-        /// `/pfs/$repo/`, which will be used to hold the downloaded data.
+        /// `/pfs/$DATUM_ID/in/$repo/`, which will be used to hold the
+        /// downloaded data.
         repo: String,
         /// How to distribute the files in the repo over our workers.
         glob: Glob,
+        /// EXTENSION: Is `uri` in a requester-pays bucket? If so, we pay for
+        /// our own requests to it.
+        #[serde(default)]
+        requester_pays: bool,
+        /// EXTENSION: The project to bill for requests to a requester-pays
+        /// bucket. Required for GCS.
+        #[serde(default)]
+        billing_project: Option<String>,
+        /// EXTENSION: Should workers decompress input files with recognized
+        /// extensions, like `.gz` and `.zst`, after downloading them? See
+        /// [`crate::decompress`].
+        #[serde(default)]
+        decompress: bool,
+        /// EXTENSION: Group the entries matched by `glob` into at most this
+        /// many datums, instead of giving each entry its own datum. Requires
+        /// a `glob` of `/*`.
+        #[serde(default)]
+        partitions: Option<u32>,
+        /// EXTENSION: How to group entries when `partitions` is set.
+        #[serde(default)]
+        partition_by: PartitionBy,
+    },
+    /// EXTENSION: The output of a previous falconeri job. Unlike an `atom`
+    /// pointing at the job's egress bucket, this processes exactly the files
+    /// which the job successfully uploaded.
+    Job {
+        /// The name of the upstream job.
+        job_name: String,
+        /// The repo name, used to construct paths of the form
+        /// `/pfs/$repo/...`. Defaults to the upstream job's pipeline name.
+        #[serde(default)]
+        repo: Option<String>,
+        /// How to distribute the output files over our workers. Defaults to
+        /// `/*`, which puts each output file in its own datum.
+        #[serde(default = "default_job_input_glob")]
+        glob: Glob,
     },
     /// Cross product of two other inputs, producing every possible combination.
     #[schema(no_recursion)]
@@ -119,6 +568,127 @@ pub enum Input {
     Union(Vec<Input>),
 }
 
+impl Input {
+    /// How to pay for requests to each `atom` input in a requester-pays
+    /// bucket, indexed by URI.
+    pub fn requester_pays_uris(&self) -> BTreeMap<String, RequesterPays> {
+        match self {
+            Input::Atom {
+                uri,
+                requester_pays: true,
+                billing_project,
+                ..
+            } => BTreeMap::from([(
+                uri.clone(),
+                RequesterPays {
+                    billing_project: billing_project.clone(),
+                },
+            )]),
+            Input::Atom { .. } | Input::Job { .. } => BTreeMap::new(),
+            Input::Cross(inputs) | Input::Union(inputs) => inputs
+                .iter()
+                .flat_map(|input| input.requester_pays_uris())
+                .collect(),
+        }
+    }
+
+    /// Does this input contain a `cross`? If so, the same input file may
+    /// legitimately appear in several datums.
+    pub fn has_cross(&self) -> bool {
+        match self {
+            Input::Atom { .. } | Input::Job { .. } => false,
+            Input::Cross(_) => true,
+            Input::Union(inputs) => inputs.iter().any(|input| input.has_cross()),
+        }
+    }
+
+    /// The names of any jobs whose output this input reads.
+    pub fn upstream_job_names(&self) -> Vec<&str> {
+        match self {
+            Input::Atom { .. } => vec![],
+            Input::Job { job_name, .. } => vec![job_name.as_str()],
+            Input::Cross(inputs) | Input::Union(inputs) => inputs
+                .iter()
+                .flat_map(|input| input.upstream_job_names())
+                .collect(),
+        }
+    }
+}
+
+/// Helper for `serde(default)`.
+fn default_job_input_glob() -> Glob {
+    Glob::TopLevelDirectoryEntries
+}
+
+/// How to group the entries of an input into datums when `partitions` is set.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    Deserialize,
+    Eq,
+    JsonSchema,
+    PartialEq,
+    Serialize,
+    ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum PartitionBy {
+    /// Give each datum the same number of entries, keeping neighboring
+    /// entries together.
+    #[default]
+    Count,
+    /// Give each datum roughly the same number of bytes, so that one huge
+    /// file doesn't share a datum with lots of other files. Entries whose
+    /// size we don't know, like directories, count as 0 bytes.
+    Size,
+}
+
+impl PartitionBy {
+    /// Group `items`, each with an optional size in bytes, into at most
+    /// `partitions` groups. Each group keeps its items in their original
+    /// order, and we never return empty groups.
+    pub fn partition<T>(
+        self,
+        items: Vec<(T, Option<u64>)>,
+        partitions: u32,
+    ) -> Vec<Vec<T>> {
+        let partitions = cast::usize(partitions).clamp(1, items.len().max(1));
+        let mut group_for_item = vec![0; items.len()];
+        match self {
+            PartitionBy::Count => {
+                for (idx, group) in group_for_item.iter_mut().enumerate() {
+                    *group = idx * partitions / items.len();
+                }
+            }
+            PartitionBy::Size => {
+                // Place the largest items first, each in the group with the
+                // fewest bytes so far.
+                let mut order = (0..items.len()).collect::<Vec<_>>();
+                order.sort_by_key(|&idx| Reverse(items[idx].1.unwrap_or(0)));
+                let mut totals = (0..partitions)
+                    .map(|group| Reverse((0u64, group)))
+                    .collect::<BinaryHeap<_>>();
+                for idx in order {
+                    let Reverse((bytes, group)) =
+                        totals.pop().expect("should always have a group");
+                    group_for_item[idx] = group;
+                    let bytes = bytes.saturating_add(items[idx].1.unwrap_or(0));
+                    totals.push(Reverse((bytes, group)));
+                }
+            }
+        }
+
+        let mut groups = (0..partitions).map(|_| vec![]).collect::<Vec<_>>();
+        for ((item, _), group) in items.into_iter().zip(group_for_item) {
+            groups[group].push(item);
+        }
+        groups.retain(|group| !group.is_empty());
+        groups
+    }
+}
+
 /// How to distribute files from an input across workers. We only support two
 /// kinds of glob patterns for now.
 #[derive(
@@ -141,6 +711,29 @@ pub struct Egress {
     /// A cloud bucket URI in which to place our output data.
     #[serde(rename = "URI")]
     pub uri: String,
+    /// EXTENSION: How to encrypt our output files. By default, we use the
+    /// bucket's default encryption.
+    #[serde(default)]
+    pub encryption: Option<EgressEncryption>,
+    /// EXTENSION: The storage class of our output files, such as
+    /// `STANDARD_IA` on S3 or `NEARLINE` on GCS. By default, we use the
+    /// bucket's default storage class.
+    #[serde(default)]
+    pub storage_class: Option<String>,
+    /// EXTENSION: Where to write a manifest of our output files once the job
+    /// finishes, as JSON Lines.
+    #[serde(default)]
+    pub manifest_uri: Option<String>,
+}
+
+/// How to encrypt output files.
+#[derive(Clone, Debug, Deserialize, JsonSchema, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct EgressEncryption {
+    /// The KMS key used to encrypt output files. On S3, this is a key ID or
+    /// ARN, and objects use SSE-KMS. On GCS, this is the key's full resource
+    /// name, `projects/*/locations/*/keyRings/*/cryptoKeys/*`.
+    pub kms_key: String,
 }
 
 #[test]
@@ -176,23 +769,80 @@ fn parse_nested_inputs() {
             uri: "gs://example-bucket/dewey-decimal-categories/".to_owned(),
             repo: "dewey-decimal-categories".to_owned(),
             glob: Glob::WholeRepo,
+            requester_pays: false,
+            billing_project: None,
+            decompress: false,
+            partitions: None,
+            partition_by: PartitionBy::Count,
         },
         Input::Union(vec![
             Input::Atom {
                 uri: "gs://example-bucket/books/".to_owned(),
                 repo: "books".to_owned(),
                 glob: Glob::TopLevelDirectoryEntries,
+                requester_pays: false,
+                billing_project: None,
+                decompress: false,
+                partitions: None,
+                partition_by: PartitionBy::Count,
             },
             Input::Atom {
                 uri: "gs://example-bucket/more-books/".to_owned(),
                 repo: "more-books".to_owned(),
                 glob: Glob::TopLevelDirectoryEntries,
+                requester_pays: false,
+                billing_project: None,
+                decompress: false,
+                partitions: None,
+                partition_by: PartitionBy::Count,
             },
         ]),
     ]);
     assert_eq!(parsed, expected);
 }
 
+#[test]
+fn parse_job_inputs() {
+    let json = r#"
+{
+    "cross": [{
+        "job": {
+            "job_name": "extract-text-x7k2m9q4ab"
+        }
+    }, {
+        "job": {
+            "job_name": "fetch-dictionaries-h3b8c1d2ef",
+            "repo": "dictionaries",
+            "glob": "/"
+        }
+    }]
+}
+"#;
+    let parsed: Input = serde_json::from_str(json).expect("parse error");
+    let expected = Input::Cross(vec![
+        Input::Job {
+            job_name: "extract-text-x7k2m9q4ab".to_owned(),
+            repo: None,
+            glob: Glob::TopLevelDirectoryEntries,
+        },
+        Input::Job {
+            job_name: "fetch-dictionaries-h3b8c1d2ef".to_owned(),
+            repo: Some("dictionaries".to_owned()),
+            glob: Glob::WholeRepo,
+        },
+    ]);
+    assert_eq!(parsed, expected);
+    assert_eq!(
+        parsed.upstream_job_names(),
+        vec!["extract-text-x7k2m9q4ab", "fetch-dictionaries-h3b8c1d2ef"]
+    );
+    assert!(parsed.has_cross());
+    let Input::Cross(inputs) = parsed else {
+        unreachable!()
+    };
+    assert!(!Input::Union(inputs).has_cross());
+}
+
 #[test]
 fn parse_pipeline_spec() {
     use serde_json;
@@ -236,7 +886,208 @@ fn parse_pipeline_spec() {
             uri: "gs://example-bucket/books/".to_owned(),
             repo: "books".to_owned(),
             glob: Glob::TopLevelDirectoryEntries,
+            requester_pays: false,
+            billing_project: None,
+            decompress: false,
+            partitions: None,
+            partition_by: PartitionBy::Count,
         }
     );
     assert_eq!(parsed.egress.uri, "gs://example-bucket/words/");
+    assert_eq!(parsed.egress.encryption, None);
+    assert_eq!(parsed.egress.storage_class, None);
+}
+
+#[test]
+fn parse_egress_options() {
+    let json = r#"
+{
+    "URI": "s3://example-bucket/archive/",
+    "encryption": { "kms_key": "arn:aws:kms:us-east-1:111122223333:key/example" },
+    "storage_class": "STANDARD_IA"
+}
+"#;
+    let parsed: Egress = serde_json::from_str(json).expect("parse error");
+    assert_eq!(
+        parsed.encryption,
+        Some(EgressEncryption {
+            kms_key: "arn:aws:kms:us-east-1:111122223333:key/example".to_owned(),
+        })
+    );
+    assert_eq!(parsed.storage_class.as_deref(), Some("STANDARD_IA"));
+}
+
+#[test]
+fn parse_archive_formats() {
+    for format in [ArchiveFormat::TarGz, ArchiveFormat::Zip] {
+        let json = serde_json::to_string(&format).unwrap();
+        assert_eq!(json, format!("\"{}\"", format.extension()));
+        assert_eq!(
+            serde_json::from_str::<ArchiveFormat>(&json).unwrap(),
+            format
+        );
+        assert_eq!(format.to_string().parse::<ArchiveFormat>().unwrap(), format);
+    }
+    assert!("tgz".parse::<ArchiveFormat>().is_err());
+}
+
+#[test]
+fn requester_pays_uris_finds_nested_atoms() {
+    let json = r#"
+{
+    "cross": [{
+        "atom": {
+            "URI": "gs://public-data/genomes/",
+            "repo": "genomes",
+            "glob": "/*",
+            "requester_pays": true,
+            "billing_project": "research"
+        }
+    }, {
+        "atom": {
+            "URI": "gs://example-bucket/samples/",
+            "repo": "samples",
+            "glob": "/"
+        }
+    }]
+}
+"#;
+    let parsed: Input = serde_json::from_str(json).expect("parse error");
+    assert_eq!(
+        parsed.requester_pays_uris(),
+        BTreeMap::from([(
+            "gs://public-data/genomes/".to_owned(),
+            RequesterPays {
+                billing_project: Some("research".to_owned()),
+            },
+        )])
+    );
+}
+
+#[test]
+fn canonical_hash_ignores_formatting() {
+    let json = include_str!("example_pipeline_spec.json");
+    let parsed: PipelineSpec = serde_json::from_str(json).expect("parse error");
+    let hash = parsed.canonical_hash().unwrap();
+    assert_eq!(hash.len(), 64);
+
+    // Re-serializing with different formatting gives the same hash.
+    let reformatted = serde_json::to_string_pretty(&parsed).unwrap();
+    let reparsed: PipelineSpec =
+        serde_json::from_str(&reformatted).expect("parse error");
+    assert_eq!(reparsed.canonical_hash().unwrap(), hash);
+
+    // But changing the spec changes the hash.
+    let mut changed = parsed.clone();
+    changed.parallelism_spec.constant += 1;
+    assert_ne!(changed.canonical_hash().unwrap(), hash);
+}
+
+#[test]
+fn canonical_json_sorts_keys() {
+    let value = serde_json::json!({ "b": [1, { "d": null, "c": "x" }], "a": true });
+    let mut out = String::new();
+    write_canonical_json(&value, &mut out).unwrap();
+    assert_eq!(out, r#"{"a":true,"b":[1,{"c":"x","d":null}]}"#);
+}
+
+#[test]
+fn datum_count_range_contains() {
+    let range = DatumCountRange {
+        min: Some(10),
+        max: Some(20),
+    };
+    assert!(!range.contains(9));
+    assert!(range.contains(10));
+    assert!(range.contains(20));
+    assert!(!range.contains(21));
+
+    let unbounded = DatumCountRange {
+        min: None,
+        max: None,
+    };
+    assert!(unbounded.contains(0));
+    assert!(unbounded.contains(u64::MAX));
+}
+
+#[test]
+fn parse_quantity_handles_suffixes() {
+    assert_eq!(parse_quantity("1024").unwrap(), 1024);
+    assert_eq!(parse_quantity("20Gi").unwrap(), 20 * 1024 * 1024 * 1024);
+    assert_eq!(parse_quantity("500M").unwrap(), 500_000_000);
+    assert_eq!(parse_quantity(" 2Ki ").unwrap(), 2048);
+    assert!(parse_quantity("1.5Gi").is_err());
+    assert!(parse_quantity("lots").is_err());
+    assert!(parse_quantity("").is_err());
+    assert!(parse_quantity("100000000Pi").is_err());
+}
+
+#[test]
+fn parse_vault_secrets() {
+    let transform: Transform = serde_json::from_value(serde_json::json!({
+        "cmd": ["true"],
+        "image": "alpine",
+        "vault": { "address": "https://vault:8200", "role": "worker" },
+        "secrets": [
+            { "name": "s3", "key": "KEY", "env_var": "KEY" },
+            { "vault_path": "secret/data/s3", "field": "key", "env_var": "KEY" },
+        ],
+    }))
+    .unwrap();
+    assert_eq!(
+        transform.secrets[1],
+        Secret::Vault {
+            vault_path: "secret/data/s3".to_owned(),
+            field: "key".to_owned(),
+            env_var: "KEY".to_owned(),
+        },
+    );
+    let vault = transform.vault.unwrap();
+    assert_eq!(vault.auth_mount, "kubernetes");
+    assert_eq!(vault.token_path, None);
+}
+
+#[test]
+fn service_account_name_is_an_alias() {
+    let transform: Transform = serde_json::from_value(serde_json::json!({
+        "cmd": ["true"],
+        "image": "alpine",
+        "service_account_name": "extract-text",
+    }))
+    .unwrap();
+    assert_eq!(transform.service_account.as_deref(), Some("extract-text"));
+}
+
+#[test]
+fn fail_fast_is_an_alias() {
+    let mut json = serde_json::from_str::<serde_json::Value>(include_str!(
+        "example_pipeline_spec.json"
+    ))
+    .unwrap();
+    json["fail_fast"] = serde_json::json!(true);
+    let spec: PipelineSpec = serde_json::from_value(json).unwrap();
+    assert!(spec.stop_on_first_error);
+}
+
+#[test]
+fn partition_by_count_and_size() {
+    let items = vec![
+        ("a", Some(1)),
+        ("b", Some(1)),
+        ("c", Some(60)),
+        ("d", Some(1)),
+        ("e", None),
+    ];
+    assert_eq!(
+        PartitionBy::Count.partition(items.clone(), 2),
+        vec![vec!["a", "b", "c"], vec!["d", "e"]],
+    );
+    assert_eq!(
+        PartitionBy::Size.partition(items.clone(), 2),
+        vec![vec!["c"], vec!["a", "b", "d", "e"]],
+    );
+    assert_eq!(PartitionBy::Size.partition(items, 10).len(), 5);
+    assert!(PartitionBy::Count
+        .partition(Vec::<((), _)>::new(), 3)
+        .is_empty());
 }
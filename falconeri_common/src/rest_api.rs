@@ -1,27 +1,280 @@
 //! The REST API for `falconerid`, including data types and a client.
 
+use std::{error, future::Future, time::Duration};
+
+use reqwest::{header::HeaderValue, StatusCode};
 use serde::de::DeserializeOwned;
 use url::Url;
 use utoipa::ToSchema;
 
 use crate::{
-    db,
+    base64::{prelude::BASE64_STANDARD, Engine},
+    connect_via::ProxySettings,
+    db, falconeri_common_version,
     kubernetes::{node_name, pod_name},
     pipeline::PipelineSpec,
     prelude::*,
+    semver,
+    version::Compatibility,
 };
 
+/// HTTP header used by workers to identify their pod, so that `falconerid`
+/// can rate limit each worker separately.
+pub const POD_NAME_HEADER: &str = "x-falconeri-pod-name";
+
+/// The file where worker pods find a token which lets them call `falconerid`
+/// on behalf of their job.
+pub const WORKER_TOKEN_PATH: &str = "/etc/falconeri/worker-token/token";
+
+/// The username which workers send with their token.
+pub const WORKER_USERNAME: &str = "worker";
+
+/// HTTP header containing a unique ID for each request, which `falconerid`
+/// includes in its logs and error responses.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// How many idle connections to `falconerid` should each worker keep open?
+/// We have hundreds of workers, so we keep this small, but reusing even one
+/// connection avoids opening a new one for every request.
+const CLUSTER_MAX_IDLE_CONNECTIONS: usize = 1;
+
+/// How long should we keep idle connections open on the cluster?
+const CLUSTER_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often should we send TCP keep-alive probes on open connections?
+const TCP_KEEPALIVE: Duration = Duration::from_secs(60);
+
+/// HTTP statuses which mean that `falconerid`, or a proxy in front of it, is
+/// overloaded or restarting. These are safe to retry for idempotent requests.
+const RETRYABLE_STATUSES: &[StatusCode] = &[
+    StatusCode::BAD_GATEWAY,
+    StatusCode::SERVICE_UNAVAILABLE,
+    StatusCode::GATEWAY_TIMEOUT,
+];
+
+/// The kinds of error which `falconerid` may return. Callers can use
+/// [`api_error`] to find out which kind of error a request failed with.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FalconeriApiError {
+    /// The request was invalid (400).
+    BadRequest,
+    /// We were missing credentials, or they were invalid (401).
+    Unauthorized,
+    /// We may not do this. For example, we tried to update a datum which was
+    /// reserved by another pod (403).
+    Forbidden,
+    /// The requested resource does not exist (404).
+    NotFound,
+    /// The request conflicts with existing work (409).
+    Conflict,
+    /// We're making too many requests, or we're over a quota (429).
+    #[serde(rename = "too_many_requests")]
+    RateLimited,
+    /// `falconerid`, or a proxy in front of it, is too busy or restarting
+    /// (502, 503 or 504).
+    #[serde(rename = "service_unavailable")]
+    Unavailable,
+    /// Something went wrong inside `falconerid` (500).
+    Internal,
+    /// An error we don't know about, probably from a newer `falconerid`.
+    #[serde(other)]
+    Unknown,
+}
+
+impl FalconeriApiError {
+    /// Guess the kind of error from an HTTP status, for responses without an
+    /// [`ErrorResponse`] body.
+    pub fn from_status(status: StatusCode) -> Self {
+        match status {
+            StatusCode::BAD_REQUEST => FalconeriApiError::BadRequest,
+            StatusCode::UNAUTHORIZED => FalconeriApiError::Unauthorized,
+            StatusCode::FORBIDDEN => FalconeriApiError::Forbidden,
+            StatusCode::NOT_FOUND => FalconeriApiError::NotFound,
+            StatusCode::CONFLICT => FalconeriApiError::Conflict,
+            StatusCode::TOO_MANY_REQUESTS => FalconeriApiError::RateLimited,
+            status if RETRYABLE_STATUSES.contains(&status) => {
+                FalconeriApiError::Unavailable
+            }
+            status if status.is_server_error() => FalconeriApiError::Internal,
+            _ => FalconeriApiError::Unknown,
+        }
+    }
+}
+
+/// If `err` was caused by an error response from `falconerid`, what kind of
+/// error was it?
+pub fn api_error(err: &Error) -> Option<FalconeriApiError> {
+    err.chain()
+        .find_map(|cause| cause.downcast_ref::<UnexpectedStatus>())
+        .map(|unexpected| unexpected.kind)
+}
+
+/// An unexpected HTTP status returned by `falconerid`.
+#[derive(Debug)]
+pub struct UnexpectedStatus {
+    /// The status we received.
+    pub status: StatusCode,
+    /// The URL we requested.
+    pub url: Url,
+    /// The body of the response. If `falconerid` returned an
+    /// [`ErrorResponse`], this is just its message.
+    pub body: String,
+    /// The kind of error. If `falconerid` didn't return an
+    /// [`ErrorResponse`], we guess this from `status`.
+    pub kind: FalconeriApiError,
+    /// The ID of the failed request, for finding it in `falconerid`'s logs.
+    pub request_id: Option<String>,
+    /// Did the response include a `Retry-After` header? `falconerid` only
+    /// sends this when it rejected a request without doing anything.
+    pub retry_after: bool,
+}
+
+impl fmt::Display for UnexpectedStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unexpected HTTP status {} for {}", self.status, self.url)?;
+        if let Some(request_id) = &self.request_id {
+            write!(f, " (request ID {})", request_id)?;
+        }
+        write!(f, ":\n{}", self.body)
+    }
+}
+
+impl error::Error for UnexpectedStatus {}
+
+/// Describe an unexpected `status`. Old versions of `falconerid`, and
+/// proxies, return plain text, so we only use `body` as an [`ErrorResponse`]
+/// if it parses as one.
+fn unexpected_status(
+    status: StatusCode,
+    url: &Url,
+    body: String,
+    header_request_id: Option<String>,
+    retry_after: bool,
+) -> UnexpectedStatus {
+    let (body, kind, request_id) = match serde_json::from_str::<ErrorResponse>(&body) {
+        Ok(envelope) => (
+            envelope.message,
+            envelope.code,
+            envelope.request_id.or(header_request_id),
+        ),
+        Err(_) => (
+            body,
+            FalconeriApiError::from_status(status),
+            header_request_id,
+        ),
+    };
+    UnexpectedStatus {
+        status,
+        url: url.clone(),
+        body,
+        kind,
+        request_id,
+        retry_after,
+    }
+}
+
+/// How we authenticate with `falconerid`.
+enum Credentials {
+    /// HTTP Basic Auth, used with our admin password and worker tokens.
+    Basic { username: String, password: String },
+    /// A bearer token, used when we talk to `falconerid` directly, often
+    /// through an ingress.
+    Bearer(String),
+}
+
+impl Credentials {
+    /// Choose our API credentials. Worker pods have a token which only works
+    /// for their own job. If our settings include a token, we use that.
+    /// Otherwise, we use our database password for API access, too.
+    async fn for_connection(via: ConnectVia) -> Result<Credentials> {
+        if via == ConnectVia::Cluster && Path::new(WORKER_TOKEN_PATH).exists() {
+            let token = tokio::fs::read_to_string(WORKER_TOKEN_PATH)
+                .await
+                .with_context(|| format!("could not read {}", WORKER_TOKEN_PATH))?;
+            return Ok(Credentials::Basic {
+                username: WORKER_USERNAME.to_owned(),
+                password: token.trim().to_owned(),
+            });
+        }
+        if via == ConnectVia::Proxy {
+            if let Some(token) = &ProxySettings::current().token {
+                return Ok(Credentials::Bearer(token.to_owned()));
+            }
+        }
+        let password = db::postgres_password(via).await?;
+        Ok(Credentials::Basic {
+            username: "falconeri".to_owned(),
+            password,
+        })
+    }
+
+    /// The value of our `Authorization` header.
+    fn authorization(&self) -> Result<HeaderValue> {
+        let value = match self {
+            Credentials::Basic { username, password } => format!(
+                "Basic {}",
+                BASE64_STANDARD.encode(format!("{}:{}", username, password))
+            ),
+            Credentials::Bearer(token) => format!("Bearer {}", token),
+        };
+        let mut value = HeaderValue::from_str(&value)
+            .context("falconerid credentials contain invalid characters")?;
+        value.set_sensitive(true);
+        Ok(value)
+    }
+}
+
+/// Should we retry a request which failed with `err`?
+///
+/// We can always retry requests which never reached `falconerid`, and
+/// requests which it rejected with `429 Too Many Requests`, or with a
+/// `Retry-After` header, without doing anything. If `idempotent` is true,
+/// repeating the request has the same effect as sending it once, so we can
+/// also retry timeouts, dropped connections and [`RETRYABLE_STATUSES`]. We
+/// never retry other errors, which will probably just happen again.
+fn is_retryable_error(err: &Error, idempotent: bool) -> bool {
+    for cause in err.chain() {
+        if let Some(unexpected) = cause.downcast_ref::<UnexpectedStatus>() {
+            return unexpected.status == StatusCode::TOO_MANY_REQUESTS
+                || (unexpected.retry_after
+                    && RETRYABLE_STATUSES.contains(&unexpected.status))
+                || (idempotent && RETRYABLE_STATUSES.contains(&unexpected.status));
+        }
+        if let Some(err) = cause.downcast_ref::<reqwest::Error>() {
+            return err.is_connect()
+                || (idempotent && (err.is_timeout() || err.is_request()));
+        }
+    }
+    false
+}
+
+/// The body of an error response from `falconerid`.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct ErrorResponse {
+    /// The kind of error.
+    pub code: FalconeriApiError,
+    /// A description of the error.
+    pub message: String,
+    /// The ID of the failed request, for finding it in `falconerid`'s logs.
+    pub request_id: Option<String>,
+}
+
 /// Request the reservation of a datum.
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
 pub struct DatumReservationRequest {
     /// The Kubernetes node name which will process this datum.
     pub node_name: String,
     /// The Kubernetes pod name which will process this datum.
     pub pod_name: String,
+    /// Is the worker still busy with another datum, and reserving this one
+    /// so it can download the inputs ahead of time?
+    #[serde(default)]
+    pub prefetch: bool,
 }
 
 /// Information about a reserved datum.
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
 pub struct DatumReservationResponse {
     /// The reserved datum to process.
     pub datum: Datum,
@@ -35,14 +288,69 @@ pub struct DatumPatch {
     /// The new status for the datum. Must be either `Status::Done` or
     /// `Status::Error`.
     pub status: Status,
-    /// The output of procesisng the datum.
+    /// The output of procesisng the datum. This may be truncated if it was
+    /// too long.
     pub output: String,
+    /// If `output` was truncated, where we uploaded the full output.
+    #[serde(default)]
+    pub output_uri: Option<String>,
+    /// The command's stdout and stderr, captured separately. These may be
+    /// truncated like `output`. Older workers won't send this.
+    #[serde(default)]
+    pub streams: DatumStreams,
     /// If and only if `status` is `Status::Error`, this should be the error
     /// message.
     pub error_message: Option<String>,
     /// If and only if `status` is `Status::Error`, this should be the error
     /// backtrace.
     pub backtrace: Option<String>,
+    /// If `status` is `Status::Error`, the worker may tell us whether this
+    /// failure is worth retrying. Older workers won't send this.
+    #[serde(default)]
+    pub failure_class: Option<FailureClass>,
+    /// When each phase of processing this datum finished. Older workers won't
+    /// send this.
+    #[serde(default)]
+    pub timings: DatumTimings,
+    /// How much data we transferred. Older workers won't send this.
+    #[serde(default)]
+    pub byte_counts: DatumByteCounts,
+    /// How much memory and CPU time our command used. Older workers won't
+    /// send this.
+    #[serde(default)]
+    pub resource_usage: DatumResourceUsage,
+}
+
+impl DatumPatch {
+    /// Create a patch which sets `status` and records `report`, without any
+    /// error information.
+    pub fn new(status: Status, report: DatumReport) -> Self {
+        DatumPatch {
+            status,
+            output: report.output,
+            output_uri: report.output_uri,
+            streams: report.streams,
+            error_message: None,
+            backtrace: None,
+            failure_class: None,
+            timings: report.timings,
+            byte_counts: report.byte_counts,
+            resource_usage: report.resource_usage,
+        }
+    }
+
+    /// The parts of this patch which are common to successful and failed
+    /// datums.
+    pub fn report(&self) -> DatumReport {
+        DatumReport {
+            output: self.output.clone(),
+            output_uri: self.output_uri.clone(),
+            streams: self.streams.clone(),
+            timings: self.timings.clone(),
+            byte_counts: self.byte_counts.clone(),
+            resource_usage: self.resource_usage.clone(),
+        }
+    }
 }
 
 /// Information about an output file that we can update.
@@ -53,6 +361,13 @@ pub struct OutputFilePatch {
     /// The status of the output file. Must be either `Status::Done` or
     /// `Status::Error`.
     pub status: Status,
+    /// The size of the uploaded file in bytes, if known.
+    #[serde(default)]
+    pub size: Option<u64>,
+    /// The SHA-256 hash of the uploaded file, in lowercase hexadecimal, if
+    /// known.
+    #[serde(default)]
+    pub sha256: Option<String>,
 }
 
 /// Data for creating an output file via POST.
@@ -73,10 +388,53 @@ pub struct JobDescribeResponse {
     pub job: Job,
     /// Counts of datums by status.
     pub datum_status_counts: Vec<DatumStatusCount>,
-    /// Currently running datums.
-    pub running_datums: Vec<Datum>,
-    /// Datums that have errored.
-    pub error_datums: Vec<Datum>,
+    /// Currently running datums, least recently updated first. This only
+    /// includes as many datums as the `limit` passed to the server.
+    pub running_datums: Vec<DatumSummary>,
+    /// Were there more running datums than we included? If so, use
+    /// `GET /jobs/{job_id}/running_datums` to list them all.
+    #[serde(default)]
+    pub running_datums_truncated: bool,
+    /// Datums that have errored, least recently updated first. Like
+    /// `running_datums`, this may be truncated.
+    pub error_datums: Vec<DatumSummary>,
+    /// Were there more error datums than we included? If so, use
+    /// `GET /jobs/{job_id}/error_datums` to list them all.
+    #[serde(default)]
+    pub error_datums_truncated: bool,
+    /// Average time spent in each phase of processing a datum. (Defaulted so
+    /// that we can still read exports from older servers.)
+    #[serde(default)]
+    pub datum_timing_stats: DatumTimingStats,
+    /// How many input files this job has, and how big they were when we
+    /// listed them.
+    #[serde(default)]
+    pub input_file_totals: InputFileTotals,
+    /// How far we've gotten creating this job's datums, if the job is still
+    /// being created.
+    #[serde(default)]
+    pub creation_progress: Option<JobCreationProgress>,
+    /// The jobs this job was retried or rerun from, and vice versa.
+    #[serde(default)]
+    pub lineage: JobLineage,
+    /// The most recent actions taken on this job's behalf, such as reaping
+    /// zombie datums, oldest first.
+    #[serde(default)]
+    pub recent_events: Vec<JobEvent>,
+    /// Nodes which are failing far more of this job's datums than other
+    /// nodes.
+    #[serde(default)]
+    pub anomalous_nodes: Vec<NodeFailureCount>,
+}
+
+/// How far we've gotten creating a job's datums in the background.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct JobCreationProgress {
+    /// The number of datums created so far.
+    pub datums_created: u64,
+    /// The total number of datums we expect to create, if we've finished
+    /// listing the job's inputs.
+    pub total_datum_count: Option<u64>,
 }
 
 /// Response for datum describe endpoint.
@@ -99,6 +457,70 @@ pub struct JobResponse {
     pub job: Job,
 }
 
+/// Response wrapper for job statistics.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct JobStatsResponse {
+    /// Statistics about the job's datums.
+    pub job_stats: JobStats,
+}
+
+/// Response wrapper for recommended resource requests.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct RecommendedResourcesResponse {
+    /// Resource requests suggested by the resources used by previous runs.
+    pub recommended_resources: ResourceRecommendation,
+}
+
+/// Response wrapper for a job audit.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct JobAuditResponse {
+    /// The results of checking the job's datum accounting.
+    pub job_audit: JobAudit,
+}
+
+/// Response wrapper for a job plan.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct JobPlanResponse {
+    /// How the job's inputs would be divided into datums.
+    pub job_plan: JobPlan,
+}
+
+/// Response wrapper for job events.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct JobEventsResponse {
+    /// Actions taken on the job's behalf, oldest first.
+    pub job_events: Vec<JobEvent>,
+}
+
+/// Response wrapper for a page of a job's output files.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct JobOutputFilesResponse {
+    /// Output files which the job uploaded successfully, sorted by URI.
+    pub output_files: Vec<OutputFile>,
+    /// Pass this as `after` to get the next page. Missing if this is the last
+    /// page.
+    #[serde(default)]
+    pub next_after: Option<String>,
+}
+
+/// Response wrapper for a page of a job's running or error datums.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct JobDatumsResponse {
+    /// Datums with the requested status, sorted by ID.
+    pub datums: Vec<DatumSummary>,
+    /// Pass this as `after` to get the next page. Missing if this is the last
+    /// page.
+    #[serde(default)]
+    pub next_after: Option<Uuid>,
+}
+
+/// Response wrapper for job lineage.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct JobLineageResponse {
+    /// The jobs related to this job by retries and reruns.
+    pub job_lineage: JobLineage,
+}
+
 /// Response wrapper for a list of jobs.
 #[derive(Debug, Deserialize, Serialize, ToSchema)]
 pub struct JobsResponse {
@@ -106,6 +528,13 @@ pub struct JobsResponse {
     pub jobs: Vec<Job>,
 }
 
+/// Response wrapper for job search results.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct JobSearchResponse {
+    /// The matching jobs, newest first.
+    pub jobs: Vec<JobSearchResult>,
+}
+
 /// Response wrapper for a single datum.
 #[derive(Debug, Deserialize, Serialize, ToSchema)]
 pub struct DatumResponse {
@@ -125,6 +554,200 @@ pub struct OutputFilesResponse {
 pub struct CreateJobRequest {
     /// The pipeline spec to create the job from.
     pub job: PipelineSpec,
+    /// Create the job even if an identical job is already running.
+    #[serde(default)]
+    pub force: bool,
+    /// Create the job even if it has more datums than the server normally
+    /// allows.
+    #[serde(default)]
+    pub override_datum_cap: bool,
+    /// A unique key for this submission. If a job was already submitted with
+    /// this key, the server returns it instead of creating a new job. May
+    /// also be passed using an `Idempotency-Key` header.
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+    /// The exact name to give the job. The server will refuse to create the
+    /// job if this name is already in use.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// A prefix for the job name, to which the server adds a random tag, like
+    /// Kubernetes' `metadata.generateName`. Cannot be used with `name`.
+    #[serde(default)]
+    pub generate_name: Option<String>,
+    /// Jobs which must finish successfully before this job starts, specified
+    /// by name or UUID. Until then, the job has status `waiting`.
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+    /// Replace the URI of the pipeline's input, which must be a single
+    /// `atom`, with the egress URI of the only job in `depends_on`.
+    #[serde(default)]
+    pub input_from_upstream: bool,
+}
+
+/// Request to plan a job, without creating it.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct PlanJobRequest {
+    /// The pipeline spec to plan.
+    pub job: PipelineSpec,
+}
+
+/// How a job's inputs would be divided into datums.
+#[derive(Debug, Default, Deserialize, Serialize, ToSchema)]
+pub struct JobPlan {
+    /// The number of datums the job would have.
+    pub datum_count: u64,
+    /// The number of datums which would be skipped, because the pipeline uses
+    /// `skip_processed` and has already processed their inputs.
+    pub skipped_datum_count: u64,
+    /// The total number of input files in all datums.
+    pub input_file_count: u64,
+    /// The total size of all input files whose size we know.
+    pub total_bytes: u64,
+    /// The number of input files whose size we don't know, such as
+    /// directories or the output of other jobs.
+    pub unknown_size_file_count: u64,
+    /// The total size of the largest datum's input files.
+    pub largest_datum_bytes: u64,
+    /// The first few datums, as examples.
+    pub example_datums: Vec<PlannedDatum>,
+    /// Why we would refuse to create the job, if the datum count is outside
+    /// of `expected_datum_count` or the server's limit.
+    pub datum_count_error: Option<String>,
+}
+
+/// A datum in a [`JobPlan`].
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct PlannedDatum {
+    /// The total size of this datum's input files whose size we know.
+    pub bytes: u64,
+    /// This datum's input files.
+    pub input_files: Vec<PlannedInputFile>,
+}
+
+/// An input file in a [`PlannedDatum`].
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct PlannedInputFile {
+    /// The URI of the file.
+    pub uri: String,
+    /// Where the file would be downloaded on the worker.
+    pub local_path: String,
+    /// The size of the file, if we know it.
+    pub size: Option<u64>,
+}
+
+/// Request to rerun a past job as a fresh job, with optional overrides.
+#[derive(Debug, Default, Deserialize, Serialize, ToSchema)]
+pub struct RerunJobRequest {
+    /// Replace the tag of the original `transform.image`.
+    #[serde(default)]
+    pub image_tag: Option<String>,
+    /// Replace the original `transform.cmd`.
+    #[serde(default)]
+    pub cmd: Option<Vec<String>>,
+    /// Replace the original `egress.URI`.
+    #[serde(default)]
+    pub egress_uri: Option<String>,
+}
+
+/// Changes to make to a running job.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, ToSchema)]
+pub struct JobPatch {
+    /// The maximum number of workers which may process this job's datums at
+    /// once.
+    #[serde(default)]
+    pub target_parallelism: Option<u32>,
+}
+
+/// Request wrapper for registering a pipeline.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct CreatePipelineRequest {
+    /// The unique name of the pipeline.
+    pub name: String,
+    /// The pipeline spec used to create jobs.
+    pub pipeline_spec: PipelineSpec,
+    /// A cron schedule, interpreted in UTC, such as `0 3 * * *`. If missing,
+    /// the pipeline only runs when triggered.
+    #[serde(default)]
+    pub schedule: Option<String>,
+    /// Replace any existing pipeline with the same name.
+    #[serde(default)]
+    pub replace: bool,
+}
+
+/// Response wrapper for a single registered pipeline.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct RegisteredPipelineResponse {
+    /// The pipeline.
+    pub pipeline: RegisteredPipeline,
+}
+
+/// Response wrapper for a list of registered pipelines.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct RegisteredPipelinesResponse {
+    /// The pipelines, sorted by name.
+    pub pipelines: Vec<RegisteredPipeline>,
+}
+
+/// Request to create or replace a team's quota. Missing limits are
+/// unlimited.
+#[derive(Debug, Default, Deserialize, Serialize, ToSchema)]
+pub struct SetQuotaRequest {
+    /// The maximum number of jobs which may be creating, running or streaming
+    /// at once.
+    #[serde(default)]
+    pub max_running_jobs: Option<i32>,
+    /// The maximum total parallelism of the team's running jobs.
+    #[serde(default)]
+    pub max_parallelism: Option<i32>,
+}
+
+/// Response wrapper for a single quota.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct QuotaResponse {
+    /// The quota.
+    pub quota: Quota,
+}
+
+/// Response wrapper for a list of quotas.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct QuotasResponse {
+    /// The quotas, sorted by team.
+    pub quotas: Vec<Quota>,
+}
+
+/// Request to create an API token.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct CreateApiTokenRequest {
+    /// A name describing who will use this token.
+    pub name: String,
+    /// The team this token belongs to. If set, the token may only submit and
+    /// change jobs for this team.
+    #[serde(default)]
+    pub team: Option<String>,
+}
+
+/// Response for creating an API token.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct CreateApiTokenResponse {
+    /// The new token's record.
+    pub api_token: ApiToken,
+    /// The token itself. We only store a hash of it, so this is the only
+    /// chance to see it.
+    pub token: String,
+}
+
+/// Response wrapper for a single API token.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct ApiTokenResponse {
+    /// The API token.
+    pub api_token: ApiToken,
+}
+
+/// Response wrapper for a list of API tokens.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct ApiTokensResponse {
+    /// The API tokens, oldest first.
+    pub api_tokens: Vec<ApiToken>,
 }
 
 /// Request wrapper for updating a datum (worker endpoint).
@@ -136,6 +759,56 @@ pub struct UpdateDatumRequest {
     pub datum: DatumPatch,
 }
 
+/// Request wrapper for releasing a datum (worker endpoint).
+///
+/// Used with `POST /datums/{datum_id}/release`.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct ReleaseDatumRequest {
+    /// The pod making this request (for ownership verification).
+    pub pod_name: String,
+}
+
+/// Request wrapper for appending to a running datum's output (worker
+/// endpoint).
+///
+/// Used with `PATCH /datums/{datum_id}/append_output`.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct AppendDatumOutputRequest {
+    /// The pod making this request (for ownership verification).
+    pub pod_name: String,
+    /// How many bytes of output we've already sent for this attempt. If this
+    /// is 0, we replace any output from earlier attempts.
+    pub offset: u64,
+    /// The output to append.
+    pub output: String,
+}
+
+/// Request to pause or resume datum reservations.
+///
+/// Used with `PUT /admin/reservations`.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct ReservationsRequest {
+    /// Should workers be prevented from reserving datums?
+    pub paused: bool,
+}
+
+/// Information about the server's version.
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub struct VersionResponse {
+    /// The version of `falconeri_common` used by the server.
+    pub version: String,
+    /// A semver requirement describing which client versions the server
+    /// supports.
+    pub supported_client_versions: String,
+}
+
+/// Whether datum reservations are paused.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct ReservationsResponse {
+    /// Are workers prevented from reserving datums?
+    pub paused: bool,
+}
+
 /// Request wrapper for creating output files (worker endpoint).
 ///
 /// Used with `POST /datums/{datum_id}/output_files`.
@@ -162,54 +835,452 @@ pub struct UpdateOutputFilesRequest {
 pub struct Client {
     via: ConnectVia,
     url: Url,
-    username: String,
-    password: String,
+    /// Our precomputed `Authorization` header.
+    authorization: HeaderValue,
     client: reqwest::Client,
+    /// The node and pod names we report when acting as a worker. If these
+    /// are missing, we use `FALCONERI_NODE_NAME` and `FALCONERI_POD_NAME`.
+    worker_names: Option<(String, String)>,
 }
 
 impl Client {
     /// Create a new client, connecting to `falconerid` as specified.
+    ///
+    /// We check that the server supports our version once, here. If the
+    /// versions differ but should still work together, we log a warning.
     #[instrument(level = "trace")]
     pub async fn new(via: ConnectVia) -> Result<Client> {
+        let client = Self::new_without_version_check(via).await?;
+        client.check_server_version().await?;
+        Ok(client)
+    }
+
+    /// Create a new client without checking whether the server supports our
+    /// version. This is useful when we're about to upgrade the server.
+    #[instrument(level = "trace")]
+    pub async fn new_without_version_check(via: ConnectVia) -> Result<Client> {
         // Choose an appropriate URL.
         let url = match via {
-            ConnectVia::Cluster => "http://falconerid:8089/",
-            ConnectVia::Proxy => "http://localhost:8089/",
-        }
-        .parse()
-        .expect("could not parse URL in source code");
+            ConnectVia::Cluster => "http://falconerid:8089/".to_owned(),
+            ConnectVia::Proxy => match &ProxySettings::current().falconerid_url {
+                // We join relative paths onto our URL, so it needs to end in
+                // `/`.
+                Some(url) if url.ends_with('/') => url.to_owned(),
+                Some(url) => format!("{}/", url),
+                None => "http://localhost:8089/".to_owned(),
+            },
+        };
+        let url = url
+            .parse()
+            .with_context(|| format!("could not parse falconerid URL {:?}", url))?;
 
-        // Get our credentials. For now, we use our database password for API
-        // access, too.
-        let username = "falconeri".to_owned();
-        let password = db::postgres_password(via).await?;
+        // Get our credentials.
+        let authorization = Credentials::for_connection(via).await?.authorization()?;
 
         // Decide how long to keep connections open.
-        let max_idle = match via {
-            // If we're running on the cluster, connection startup is cheap but
-            // we may have hundreds of inbound connections, so drop connections
-            // as fast as possible. This could be improved by putting an async
-            // proxy server in front of `falconerid`, if we want that.
-            ConnectVia::Cluster => 0,
+        let (max_idle, idle_timeout) = match via {
+            // If we're running on the cluster, we may have hundreds of workers,
+            // so keep very few idle connections, and close them quickly.
+            // `falconerid` limits how many requests it handles at once, and
+            // we retry when it's too busy.
+            ConnectVia::Cluster => {
+                (CLUSTER_MAX_IDLE_CONNECTIONS, CLUSTER_IDLE_TIMEOUT)
+            }
             // Otherwise allow the maximum possible number of connections.
-            ConnectVia::Proxy => usize::MAX,
+            ConnectVia::Proxy => (usize::MAX, Duration::from_secs(90)),
         };
 
-        // Create our HTTP client.
+        // Create our HTTP client. We enable `gzip` and `deflate` so that large
+        // responses are compressed by `falconerid`.
         let client = reqwest::Client::builder()
             .pool_max_idle_per_host(max_idle)
+            .pool_idle_timeout(idle_timeout)
+            .tcp_keepalive(TCP_KEEPALIVE)
+            .gzip(true)
+            .deflate(true)
             .build()
             .context("cannot build HTTP client")?;
 
         Ok(Client {
             via,
             url,
-            username,
-            password,
+            authorization,
             client,
+            worker_names: None,
         })
     }
 
+    /// Create a new client which connects to the `falconerid` at `url` using
+    /// `password`, without checking its version or retrying failed requests.
+    /// This is mostly useful for tests which run `falconerid` in-process.
+    #[instrument(level = "trace", skip(password))]
+    pub fn new_with_url(url: &str, password: &str) -> Result<Client> {
+        // We join relative paths onto our URL, so it needs to end in `/`.
+        let mut url = url.to_owned();
+        if !url.ends_with('/') {
+            url.push('/');
+        }
+        let url = url
+            .parse()
+            .with_context(|| format!("could not parse URL {:?}", url))?;
+        let client = reqwest::Client::builder()
+            .gzip(true)
+            .deflate(true)
+            .build()
+            .context("cannot build HTTP client")?;
+        Ok(Client {
+            via: ConnectVia::Proxy,
+            url,
+            authorization: Credentials::Basic {
+                username: "falconeri".to_owned(),
+                password: password.to_owned(),
+            }
+            .authorization()?,
+            client,
+            worker_names: None,
+        })
+    }
+
+    /// Identify ourselves as the worker `pod_name` running on `node_name`,
+    /// instead of looking up our names in the environment.
+    pub fn with_worker_names(mut self, node_name: &str, pod_name: &str) -> Self {
+        self.worker_names = Some((node_name.to_owned(), pod_name.to_owned()));
+        self
+    }
+
+    /// The name of the node we're running on, when acting as a worker.
+    fn node_name(&self) -> Result<String> {
+        match &self.worker_names {
+            Some((node_name, _)) => Ok(node_name.to_owned()),
+            None => node_name(),
+        }
+    }
+
+    /// The name of the pod we're running in, when acting as a worker.
+    fn pod_name(&self) -> Result<String> {
+        match &self.worker_names {
+            Some((_, pod_name)) => Ok(pod_name.to_owned()),
+            None => pod_name(),
+        }
+    }
+
+    /// Get the version of `falconeri_common` used by the server.
+    ///
+    /// `GET /version`
+    #[instrument(level = "trace", skip_all)]
+    pub async fn server_version(&self) -> Result<semver::Version> {
+        let (version, _) = self.server_version_info().await?;
+        Ok(version)
+    }
+
+    /// Make sure that the server supports our version.
+    #[instrument(level = "trace", skip_all)]
+    pub async fn check_server_version(&self) -> Result<()> {
+        let (server, supported_clients) = self.server_version_info().await?;
+        Compatibility::check(
+            &falconeri_common_version(),
+            &server,
+            supported_clients.as_ref(),
+        )
+        .into_result()
+    }
+
+    /// Get the server's version, and the client versions it supports. Older
+    /// servers only return a plain-text version, so the supported client
+    /// versions may be missing.
+    ///
+    /// `GET /version`
+    #[instrument(level = "trace", skip_all)]
+    async fn server_version_info(
+        &self,
+    ) -> Result<(semver::Version, Option<semver::VersionReq>)> {
+        let url = self.url.join("version")?;
+        let (is_json, body) = self
+            .retry_idempotent(|| async {
+                let resp = self
+                    .client
+                    .get(url.clone())
+                    .header(reqwest::header::AUTHORIZATION, self.authorization.clone())
+                    .header(reqwest::header::ACCEPT, "application/json")
+                    .send()
+                    .await
+                    .with_context(|| format!("error getting {}", url))?;
+                if !resp.status().is_success() {
+                    return Err(self.handle_error_response(&url, resp).await);
+                }
+                let is_json = resp
+                    .headers()
+                    .get(reqwest::header::CONTENT_TYPE)
+                    .and_then(|v| v.to_str().ok())
+                    .is_some_and(|v| v.starts_with("application/json"));
+                let body = resp
+                    .text()
+                    .await
+                    .with_context(|| format!("error reading {}", url))?;
+                Ok((is_json, body))
+            })
+            .await?;
+        parse_version_body(is_json, &body)
+    }
+
+    /// Pause or resume datum reservations for all jobs. While reservations are
+    /// paused, workers wait and try again. Returns `false` if the server is
+    /// too old to support this.
+    ///
+    /// `PUT /admin/reservations`
+    #[instrument(level = "trace", skip_all, fields(paused = paused))]
+    pub async fn set_reservations_paused(&self, paused: bool) -> Result<bool> {
+        let url = self.url.join("admin/reservations")?;
+        let request = ReservationsRequest { paused };
+        self.retry_idempotent(|| async {
+            let resp = self
+                .client
+                .put(url.clone())
+                .header(reqwest::header::AUTHORIZATION, self.authorization.clone())
+                .json(&request)
+                .send()
+                .await
+                .with_context(|| format!("error putting {}", url))?;
+            if resp.status() == reqwest::StatusCode::NOT_FOUND {
+                return Ok(false);
+            }
+            let _: ReservationsResponse =
+                self.handle_json_response(&url, resp).await?;
+            Ok(true)
+        })
+        .await
+    }
+
+    /// Register a pipeline, which may run on a schedule. We only retry if
+    /// `request.replace` is set, because otherwise a retry would fail when
+    /// our first attempt succeeded.
+    ///
+    /// `POST /pipelines`
+    #[instrument(level = "trace", skip_all, fields(name = %request.name))]
+    pub async fn create_pipeline(
+        &self,
+        request: &CreatePipelineRequest,
+    ) -> Result<RegisteredPipeline> {
+        let url = self.url.join("pipelines")?;
+        let post = || async {
+            let resp = self
+                .client
+                .post(url.clone())
+                .header(reqwest::header::AUTHORIZATION, self.authorization.clone())
+                .json(request)
+                .send()
+                .await
+                .with_context(|| format!("error posting {}", url))?;
+            self.handle_json_response(&url, resp).await
+        };
+        let response: RegisteredPipelineResponse = if request.replace {
+            self.retry_idempotent(post).await?
+        } else {
+            post().await?
+        };
+        Ok(response.pipeline)
+    }
+
+    /// List all registered pipelines.
+    ///
+    /// `GET /pipelines`
+    #[instrument(level = "trace", skip_all)]
+    pub async fn list_pipelines(&self) -> Result<Vec<RegisteredPipeline>> {
+        let url = self.url.join("pipelines")?;
+        let response: RegisteredPipelinesResponse = self
+            .retry_idempotent(|| async {
+                let resp = self
+                    .client
+                    .get(url.clone())
+                    .header(reqwest::header::AUTHORIZATION, self.authorization.clone())
+                    .send()
+                    .await
+                    .with_context(|| format!("error getting {}", url))?;
+                self.handle_json_response(&url, resp).await
+            })
+            .await?;
+        Ok(response.pipelines)
+    }
+
+    /// Delete a registered pipeline. Jobs which it already started are not
+    /// affected.
+    ///
+    /// `DELETE /pipelines/{name}`
+    #[instrument(level = "trace", skip_all, fields(name = %name))]
+    pub async fn delete_pipeline(&self, name: &str) -> Result<()> {
+        let url = self.url.join(&format!("pipelines/{}", name))?;
+        self.retry_idempotent(|| async {
+            let resp = self
+                .client
+                .delete(url.clone())
+                .header(reqwest::header::AUTHORIZATION, self.authorization.clone())
+                .send()
+                .await
+                .with_context(|| format!("error deleting {}", url))?;
+            self.handle_empty_response(&url, resp).await
+        })
+        .await
+    }
+
+    /// List all team quotas.
+    ///
+    /// `GET /quotas`
+    #[instrument(level = "trace", skip_all)]
+    pub async fn list_quotas(&self) -> Result<Vec<Quota>> {
+        let url = self.url.join("quotas")?;
+        let response: QuotasResponse = self
+            .retry_idempotent(|| async {
+                let resp = self
+                    .client
+                    .get(url.clone())
+                    .header(reqwest::header::AUTHORIZATION, self.authorization.clone())
+                    .send()
+                    .await
+                    .with_context(|| format!("error getting {}", url))?;
+                self.handle_json_response(&url, resp).await
+            })
+            .await?;
+        Ok(response.quotas)
+    }
+
+    /// Create or replace the quota for `team`.
+    ///
+    /// `PUT /quotas/{team}`
+    #[instrument(level = "trace", skip_all, fields(team = %team))]
+    pub async fn set_quota(
+        &self,
+        team: &str,
+        request: &SetQuotaRequest,
+    ) -> Result<Quota> {
+        let url = self.url.join(&format!("quotas/{}", team))?;
+        let response: QuotaResponse = self
+            .retry_idempotent(|| async {
+                let resp = self
+                    .client
+                    .put(url.clone())
+                    .header(reqwest::header::AUTHORIZATION, self.authorization.clone())
+                    .json(request)
+                    .send()
+                    .await
+                    .with_context(|| format!("error putting {}", url))?;
+                self.handle_json_response(&url, resp).await
+            })
+            .await?;
+        Ok(response.quota)
+    }
+
+    /// Delete the quota for `team`, so that its jobs are no longer limited.
+    ///
+    /// `DELETE /quotas/{team}`
+    #[instrument(level = "trace", skip_all, fields(team = %team))]
+    pub async fn delete_quota(&self, team: &str) -> Result<()> {
+        let url = self.url.join(&format!("quotas/{}", team))?;
+        self.retry_idempotent(|| async {
+            let resp = self
+                .client
+                .delete(url.clone())
+                .header(reqwest::header::AUTHORIZATION, self.authorization.clone())
+                .send()
+                .await
+                .with_context(|| format!("error deleting {}", url))?;
+            self.handle_empty_response(&url, resp).await
+        })
+        .await
+    }
+
+    /// Create an API token named `name`, optionally belonging to `team`,
+    /// returning its record and the token itself.
+    ///
+    /// Not idempotent, so we don't retry.
+    ///
+    /// `POST /api_tokens`
+    #[instrument(level = "trace", skip_all, fields(name = %name))]
+    pub async fn create_api_token(
+        &self,
+        name: &str,
+        team: Option<&str>,
+    ) -> Result<CreateApiTokenResponse> {
+        let url = self.url.join("api_tokens")?;
+        let request = CreateApiTokenRequest {
+            name: name.to_owned(),
+            team: team.map(|team| team.to_owned()),
+        };
+        let resp = self
+            .client
+            .post(url.clone())
+            .header(reqwest::header::AUTHORIZATION, self.authorization.clone())
+            .json(&request)
+            .send()
+            .await
+            .with_context(|| format!("error posting {}", url))?;
+        self.handle_json_response(&url, resp).await
+    }
+
+    /// List all API tokens, including revoked ones.
+    ///
+    /// `GET /api_tokens`
+    #[instrument(level = "trace", skip_all)]
+    pub async fn list_api_tokens(&self) -> Result<Vec<ApiToken>> {
+        let url = self.url.join("api_tokens")?;
+        let response: ApiTokensResponse = self
+            .retry_idempotent(|| async {
+                let resp = self
+                    .client
+                    .get(url.clone())
+                    .header(reqwest::header::AUTHORIZATION, self.authorization.clone())
+                    .send()
+                    .await
+                    .with_context(|| format!("error getting {}", url))?;
+                self.handle_json_response(&url, resp).await
+            })
+            .await?;
+        Ok(response.api_tokens)
+    }
+
+    /// Revoke the API token `id`, so that it can no longer be used.
+    ///
+    /// `DELETE /api_tokens/{id}`
+    #[instrument(level = "trace", skip_all, fields(id = %id))]
+    pub async fn revoke_api_token(&self, id: Uuid) -> Result<ApiToken> {
+        let url = self.url.join(&format!("api_tokens/{}", id))?;
+        let response: ApiTokenResponse = self
+            .retry_idempotent(|| async {
+                let resp = self
+                    .client
+                    .delete(url.clone())
+                    .header(reqwest::header::AUTHORIZATION, self.authorization.clone())
+                    .send()
+                    .await
+                    .with_context(|| format!("error deleting {}", url))?;
+                self.handle_json_response(&url, resp).await
+            })
+            .await?;
+        Ok(response.api_token)
+    }
+
+    /// Start a job from a registered pipeline right away. Unless `force` is
+    /// true, the server will refuse if an identical job is already running.
+    ///
+    /// Not idempotent, so we don't retry.
+    ///
+    /// `POST /pipelines/{name}/trigger`
+    #[instrument(level = "trace", skip_all, fields(name = %name))]
+    pub async fn trigger_pipeline(&self, name: &str, force: bool) -> Result<Job> {
+        let mut url = self.url.join(&format!("pipelines/{}/trigger", name))?;
+        if force {
+            url.query_pairs_mut().append_pair("force", "true");
+        }
+        let resp = self
+            .client
+            .post(url.clone())
+            .header(reqwest::header::AUTHORIZATION, self.authorization.clone())
+            .send()
+            .await
+            .with_context(|| format!("error posting {}", url))?;
+        let response: JobResponse = self.handle_json_response(&url, resp).await?;
+        Ok(response.job)
+    }
+
     /// List all jobs.
     ///
     /// `GET /jobs/list`
@@ -217,12 +1288,11 @@ impl Client {
     pub async fn list_jobs(&self) -> Result<Vec<Job>> {
         let url = self.url.join("jobs/list")?;
         let response: JobsResponse = self
-            .via
-            .retry_if_appropriate_async(|| async {
+            .retry_idempotent(|| async {
                 let resp = self
                     .client
                     .get(url.clone())
-                    .basic_auth(&self.username, Some(&self.password))
+                    .header(reqwest::header::AUTHORIZATION, self.authorization.clone())
                     .send()
                     .await
                     .with_context(|| format!("error getting {}", url))?;
@@ -232,29 +1302,60 @@ impl Client {
         Ok(response.jobs)
     }
 
-    /// Create a job. This does not automatically retry on network failure,
-    /// because it's very expensive and not idempotent (and only called by
-    /// `falconeri` and never `falconeri-worker`).
+    /// Create a job. Without an `idempotency_key`, this does not
+    /// automatically retry on network failure, because it's very expensive
+    /// and not idempotent (and only called by `falconeri` and never
+    /// `falconeri-worker`). With an `idempotency_key`, retrying is safe,
+    /// because the server will return the job created by our first attempt.
+    ///
+    /// See [`CreateJobRequest`] for the available options.
     ///
     /// `POST /jobs`
     #[instrument(skip_all, level = "trace")]
-    pub async fn new_job(&self, pipeline_spec: &PipelineSpec) -> Result<Job> {
+    pub async fn new_job(&self, request: &CreateJobRequest) -> Result<Job> {
         let url = self.url.join("jobs")?;
-        let request = CreateJobRequest {
-            job: pipeline_spec.clone(),
+        let post = || async {
+            let resp = self
+                .client
+                .post(url.clone())
+                .header(reqwest::header::AUTHORIZATION, self.authorization.clone())
+                .json(request)
+                .send()
+                .await
+                .with_context(|| format!("error posting {}", url))?;
+            self.handle_json_response(&url, resp).await
+        };
+        let response: JobResponse = if request.idempotency_key.is_some() {
+            self.retry_idempotent(post).await?
+        } else {
+            post().await?
         };
-        let resp = self
-            .client
-            .post(url.clone())
-            .basic_auth(&self.username, Some(&self.password))
-            .json(&request)
-            .send()
-            .await
-            .with_context(|| format!("error posting {}", url))?;
-        let response: JobResponse = self.handle_json_response(&url, resp).await?;
         Ok(response.job)
     }
 
+    /// Plan a job without creating it, listing its inputs and dividing them
+    /// into datums.
+    ///
+    /// `POST /jobs/plan`
+    #[instrument(skip_all, level = "trace")]
+    pub async fn plan_job(&self, request: &PlanJobRequest) -> Result<JobPlan> {
+        let url = self.url.join("jobs/plan")?;
+        let response: JobPlanResponse = self
+            .retry_idempotent(|| async {
+                let resp = self
+                    .client
+                    .post(url.clone())
+                    .header(reqwest::header::AUTHORIZATION, self.authorization.clone())
+                    .json(request)
+                    .send()
+                    .await
+                    .with_context(|| format!("error posting {}", url))?;
+                self.handle_json_response(&url, resp).await
+            })
+            .await?;
+        Ok(response.job_plan)
+    }
+
     /// Fetch a job by ID.
     ///
     /// `GET /jobs/<job_id>`
@@ -262,12 +1363,11 @@ impl Client {
     pub async fn job(&self, id: Uuid) -> Result<Job> {
         let url = self.url.join(&format!("jobs/{}", id))?;
         let response: JobResponse = self
-            .via
-            .retry_if_appropriate_async(|| async {
+            .retry_idempotent(|| async {
                 let resp = self
                     .client
                     .get(url.clone())
-                    .basic_auth(&self.username, Some(&self.password))
+                    .header(reqwest::header::AUTHORIZATION, self.authorization.clone())
                     .send()
                     .await
                     .with_context(|| format!("error getting {}", url))?;
@@ -287,12 +1387,11 @@ impl Client {
             .append_pair("job_name", job_name)
             .finish();
         let response: JobResponse = self
-            .via
-            .retry_if_appropriate_async(|| async {
+            .retry_idempotent(|| async {
                 let resp = self
                     .client
                     .get(url.clone())
-                    .basic_auth(&self.username, Some(&self.password))
+                    .header(reqwest::header::AUTHORIZATION, self.authorization.clone())
                     .send()
                     .await
                     .with_context(|| format!("error getting {}", url))?;
@@ -302,24 +1401,293 @@ impl Client {
         Ok(response.job)
     }
 
+    /// Search for jobs whose names or error messages contain `query`, or
+    /// whose datums have matching error messages.
+    ///
+    /// `GET /jobs/search?q=...&since=...`
+    #[instrument(skip_all, fields(query = %query), level = "trace")]
+    pub async fn search_jobs(
+        &self,
+        query: &str,
+        since: Option<NaiveDateTime>,
+    ) -> Result<Vec<JobSearchResult>> {
+        let mut url = self.url.join("jobs/search")?;
+        {
+            let mut query_pairs = url.query_pairs_mut();
+            query_pairs.append_pair("q", query);
+            if let Some(since) = since {
+                query_pairs.append_pair(
+                    "since",
+                    &since.format("%Y-%m-%dT%H:%M:%S").to_string(),
+                );
+            }
+        }
+        let response: JobSearchResponse = self
+            .retry_idempotent(|| async {
+                let resp = self
+                    .client
+                    .get(url.clone())
+                    .header(reqwest::header::AUTHORIZATION, self.authorization.clone())
+                    .send()
+                    .await
+                    .with_context(|| format!("error getting {}", url))?;
+                self.handle_json_response(&url, resp).await
+            })
+            .await?;
+        Ok(response.jobs)
+    }
+
     /// Get detailed job information for display.
     ///
     /// `GET /jobs/{job_id}/describe`
     #[instrument(skip_all, fields(job_id = %job_id), level = "trace")]
     pub async fn describe_job(&self, job_id: Uuid) -> Result<JobDescribeResponse> {
-        let url = self.url.join(&format!("jobs/{}/describe", job_id))?;
-        self.via
-            .retry_if_appropriate_async(|| async {
+        self.describe_job_with_datum_limit(job_id, None).await
+    }
+
+    /// Get detailed job information for display, including at most
+    /// `datum_limit` running and error datums. If this isn't specified, the
+    /// server picks a limit.
+    ///
+    /// `GET /jobs/{job_id}/describe?limit={datum_limit}`
+    #[instrument(skip_all, fields(job_id = %job_id, datum_limit = ?datum_limit), level = "trace")]
+    pub async fn describe_job_with_datum_limit(
+        &self,
+        job_id: Uuid,
+        datum_limit: Option<u64>,
+    ) -> Result<JobDescribeResponse> {
+        let mut url = self.url.join(&format!("jobs/{}/describe", job_id))?;
+        if let Some(datum_limit) = datum_limit {
+            url.query_pairs_mut()
+                .append_pair("limit", &datum_limit.to_string());
+        }
+        self.retry_idempotent(|| async {
+            let resp = self
+                .client
+                .get(url.clone())
+                .header(reqwest::header::AUTHORIZATION, self.authorization.clone())
+                .send()
+                .await
+                .with_context(|| format!("error getting {}", url))?;
+            self.handle_json_response(&url, resp).await
+        })
+        .await
+    }
+
+    /// Get a page of a job's running datums, sorted by ID. Pass the previous
+    /// page's `next_after` as `after` to get the next page.
+    ///
+    /// `GET /jobs/{job_id}/running_datums`
+    #[instrument(skip_all, fields(job_id = %job_id, after = ?after), level = "trace")]
+    pub async fn job_running_datums(
+        &self,
+        job_id: Uuid,
+        after: Option<Uuid>,
+    ) -> Result<JobDatumsResponse> {
+        self.job_datums_page(job_id, "running_datums", after).await
+    }
+
+    /// Get a page of a job's error datums, sorted by ID. Pass the previous
+    /// page's `next_after` as `after` to get the next page.
+    ///
+    /// `GET /jobs/{job_id}/error_datums`
+    #[instrument(skip_all, fields(job_id = %job_id, after = ?after), level = "trace")]
+    pub async fn job_error_datums(
+        &self,
+        job_id: Uuid,
+        after: Option<Uuid>,
+    ) -> Result<JobDatumsResponse> {
+        self.job_datums_page(job_id, "error_datums", after).await
+    }
+
+    /// Get a page of datums from `GET /jobs/{job_id}/{list}`.
+    async fn job_datums_page(
+        &self,
+        job_id: Uuid,
+        list: &str,
+        after: Option<Uuid>,
+    ) -> Result<JobDatumsResponse> {
+        let mut url = self.url.join(&format!("jobs/{}/{}", job_id, list))?;
+        if let Some(after) = after {
+            url.query_pairs_mut()
+                .append_pair("after", &after.to_string());
+        }
+        self.retry_idempotent(|| async {
+            let resp = self
+                .client
+                .get(url.clone())
+                .header(reqwest::header::AUTHORIZATION, self.authorization.clone())
+                .send()
+                .await
+                .with_context(|| format!("error getting {}", url))?;
+            self.handle_json_response(&url, resp).await
+        })
+        .await
+    }
+
+    /// Get statistics about a job's datums.
+    ///
+    /// `GET /jobs/{job_id}/stats`
+    #[instrument(skip_all, fields(job_id = %job_id), level = "trace")]
+    pub async fn job_stats(&self, job_id: Uuid) -> Result<JobStats> {
+        let url = self.url.join(&format!("jobs/{}/stats", job_id))?;
+        let response: JobStatsResponse = self
+            .retry_idempotent(|| async {
                 let resp = self
                     .client
                     .get(url.clone())
-                    .basic_auth(&self.username, Some(&self.password))
+                    .header(reqwest::header::AUTHORIZATION, self.authorization.clone())
                     .send()
                     .await
                     .with_context(|| format!("error getting {}", url))?;
                 self.handle_json_response(&url, resp).await
             })
-            .await
+            .await?;
+        Ok(response.job_stats)
+    }
+
+    /// Suggest resource requests for a job's pipeline, based on the resources
+    /// used by every job with the same pipeline name. If `headroom_percent`
+    /// is missing, the server chooses a default.
+    ///
+    /// `GET /jobs/{job_id}/recommended_resources`
+    #[instrument(skip_all, fields(job_id = %job_id), level = "trace")]
+    pub async fn recommended_resources(
+        &self,
+        job_id: Uuid,
+        headroom_percent: Option<u32>,
+    ) -> Result<ResourceRecommendation> {
+        let mut url = self
+            .url
+            .join(&format!("jobs/{}/recommended_resources", job_id))?;
+        if let Some(headroom_percent) = headroom_percent {
+            url.query_pairs_mut()
+                .append_pair("headroom_percent", &headroom_percent.to_string());
+        }
+        let response: RecommendedResourcesResponse = self
+            .retry_idempotent(|| async {
+                let resp = self
+                    .client
+                    .get(url.clone())
+                    .header(reqwest::header::AUTHORIZATION, self.authorization.clone())
+                    .send()
+                    .await
+                    .with_context(|| format!("error getting {}", url))?;
+                self.handle_json_response(&url, resp).await
+            })
+            .await?;
+        Ok(response.recommended_resources)
+    }
+
+    /// Check that a job's datums were accounted for exactly once.
+    ///
+    /// `GET /jobs/{job_id}/audit`
+    #[instrument(skip_all, fields(job_id = %job_id), level = "trace")]
+    pub async fn audit_job(&self, job_id: Uuid) -> Result<JobAudit> {
+        let url = self.url.join(&format!("jobs/{}/audit", job_id))?;
+        let response: JobAuditResponse = self
+            .retry_idempotent(|| async {
+                let resp = self
+                    .client
+                    .get(url.clone())
+                    .header(reqwest::header::AUTHORIZATION, self.authorization.clone())
+                    .send()
+                    .await
+                    .with_context(|| format!("error getting {}", url))?;
+                self.handle_json_response(&url, resp).await
+            })
+            .await?;
+        Ok(response.job_audit)
+    }
+
+    /// Get the jobs which a job was retried or rerun from, and vice versa.
+    ///
+    /// `GET /jobs/{job_id}/lineage`
+    #[instrument(skip_all, fields(job_id = %job_id), level = "trace")]
+    pub async fn job_lineage(&self, job_id: Uuid) -> Result<JobLineage> {
+        let url = self.url.join(&format!("jobs/{}/lineage", job_id))?;
+        let response: JobLineageResponse = self
+            .retry_idempotent(|| async {
+                let resp = self
+                    .client
+                    .get(url.clone())
+                    .header(reqwest::header::AUTHORIZATION, self.authorization.clone())
+                    .send()
+                    .await
+                    .with_context(|| format!("error getting {}", url))?;
+                self.handle_json_response(&url, resp).await
+            })
+            .await?;
+        Ok(response.job_lineage)
+    }
+
+    /// Get the actions taken on a job's behalf, such as reaping zombie datums,
+    /// oldest first.
+    ///
+    /// `GET /jobs/{job_id}/events`
+    #[instrument(skip_all, fields(job_id = %job_id), level = "trace")]
+    pub async fn job_events(&self, job_id: Uuid) -> Result<Vec<JobEvent>> {
+        let url = self.url.join(&format!("jobs/{}/events", job_id))?;
+        let response: JobEventsResponse = self
+            .retry_idempotent(|| async {
+                let resp = self
+                    .client
+                    .get(url.clone())
+                    .header(reqwest::header::AUTHORIZATION, self.authorization.clone())
+                    .send()
+                    .await
+                    .with_context(|| format!("error getting {}", url))?;
+                self.handle_json_response(&url, resp).await
+            })
+            .await?;
+        Ok(response.job_events)
+    }
+
+    /// Get a page of the output files which a job uploaded successfully,
+    /// sorted by URI. To get the next page, pass the previous page's
+    /// `next_after` as `after`.
+    ///
+    /// `GET /jobs/{job_id}/output_files`
+    #[instrument(skip_all, fields(job_id = %job_id, after = ?after), level = "trace")]
+    pub async fn job_output_files(
+        &self,
+        job_id: Uuid,
+        after: Option<&str>,
+    ) -> Result<JobOutputFilesResponse> {
+        let mut url = self.url.join(&format!("jobs/{}/output_files", job_id))?;
+        if let Some(after) = after {
+            url.query_pairs_mut().append_pair("after", after);
+        }
+        self.retry_idempotent(|| async {
+            let resp = self
+                .client
+                .get(url.clone())
+                .header(reqwest::header::AUTHORIZATION, self.authorization.clone())
+                .send()
+                .await
+                .with_context(|| format!("error getting {}", url))?;
+            self.handle_json_response(&url, resp).await
+        })
+        .await
+    }
+
+    /// Get the pipeline spec which was submitted to create a job.
+    ///
+    /// `GET /jobs/{job_id}/spec`
+    #[instrument(skip_all, fields(job_id = %job_id), level = "trace")]
+    pub async fn job_spec(&self, job_id: Uuid) -> Result<PipelineSpec> {
+        let url = self.url.join(&format!("jobs/{}/spec", job_id))?;
+        self.retry_idempotent(|| async {
+            let resp = self
+                .client
+                .get(url.clone())
+                .header(reqwest::header::AUTHORIZATION, self.authorization.clone())
+                .send()
+                .await
+                .with_context(|| format!("error getting {}", url))?;
+            self.handle_json_response(&url, resp).await
+        })
+        .await
     }
 
     /// Retry a job by ID.
@@ -333,7 +1701,68 @@ impl Client {
         let resp = self
             .client
             .post(url.clone())
-            .basic_auth(&self.username, Some(&self.password))
+            .header(reqwest::header::AUTHORIZATION, self.authorization.clone())
+            .send()
+            .await
+            .with_context(|| format!("error posting {}", url))?;
+        let response: JobResponse = self.handle_json_response(&url, resp).await?;
+        Ok(response.job)
+    }
+
+    /// Submit a fresh copy of `job`, with any overrides in `request`.
+    ///
+    /// `POST /jobs/<job_id>/rerun`
+    #[instrument(skip_all, fields(job = %job.id), level = "trace")]
+    pub async fn rerun_job(
+        &self,
+        job: &Job,
+        request: &RerunJobRequest,
+    ) -> Result<Job> {
+        let url = self.url.join(&format!("jobs/{}/rerun", job.id))?;
+        let resp = self
+            .client
+            .post(url.clone())
+            .header(reqwest::header::AUTHORIZATION, self.authorization.clone())
+            .json(request)
+            .send()
+            .await
+            .with_context(|| format!("error posting {}", url))?;
+        let response: JobResponse = self.handle_json_response(&url, resp).await?;
+        Ok(response.job)
+    }
+
+    /// Change a running job, for example to scale it up or down.
+    ///
+    /// `PATCH /jobs/<job_id>`
+    #[instrument(skip_all, fields(job = %job.id), level = "trace")]
+    pub async fn patch_job(&self, job: &Job, patch: &JobPatch) -> Result<Job> {
+        let url = self.url.join(&format!("jobs/{}", job.id))?;
+        let response: JobResponse = self
+            .retry_idempotent(|| async {
+                let resp = self
+                    .client
+                    .patch(url.clone())
+                    .header(reqwest::header::AUTHORIZATION, self.authorization.clone())
+                    .json(patch)
+                    .send()
+                    .await
+                    .with_context(|| format!("error patching {}", url))?;
+                self.handle_json_response(&url, resp).await
+            })
+            .await?;
+        Ok(response.job)
+    }
+
+    /// Stop adding new datums to a streaming job.
+    ///
+    /// `POST /jobs/<job_id>/stop_streaming`
+    #[instrument(skip_all, fields(job = %job.id), level = "trace")]
+    pub async fn stop_streaming(&self, job: &Job) -> Result<Job> {
+        let url = self.url.join(&format!("jobs/{}/stop_streaming", job.id))?;
+        let resp = self
+            .client
+            .post(url.clone())
+            .header(reqwest::header::AUTHORIZATION, self.authorization.clone())
             .send()
             .await
             .with_context(|| format!("error posting {}", url))?;
@@ -345,26 +1774,32 @@ impl Client {
     /// the corresponding input files. This can only be called from inside a
     /// pod.
     ///
+    /// If `prefetch` is true, we're still working on another datum, and we
+    /// want to start downloading this datum's inputs ahead of time.
+    ///
     /// `POST /jobs/<job_id>/reserve_next_datum`
-    #[instrument(skip_all, fields(job = %job.id), level = "trace")]
+    #[instrument(skip_all, fields(job = %job.id, prefetch = prefetch), level = "trace")]
     pub async fn reserve_next_datum(
         &self,
         job: &Job,
+        prefetch: bool,
     ) -> Result<Option<(Datum, Vec<InputFile>)>> {
         let url = self
             .url
             .join(&format!("jobs/{}/reserve_next_datum", job.id))?;
+        let request = DatumReservationRequest {
+            node_name: self.node_name()?,
+            pod_name: self.pod_name()?,
+            prefetch,
+        };
         let resv_resp: Option<DatumReservationResponse> = self
-            .via
-            .retry_if_appropriate_async(|| async {
+            .retry_non_idempotent(|| async {
                 let resp = self
                     .client
                     .post(url.clone())
-                    .basic_auth(&self.username, Some(&self.password))
-                    .json(&DatumReservationRequest {
-                        node_name: node_name()?,
-                        pod_name: pod_name()?,
-                    })
+                    .header(reqwest::header::AUTHORIZATION, self.authorization.clone())
+                    .header(POD_NAME_HEADER, &request.pod_name)
+                    .json(&request)
                     .send()
                     .await
                     .with_context(|| format!("error posting {}", url))?;
@@ -379,14 +1814,9 @@ impl Client {
     pub async fn mark_datum_as_done(
         &self,
         datum: &mut Datum,
-        output: String,
+        report: DatumReport,
     ) -> Result<()> {
-        let patch = DatumPatch {
-            status: Status::Done,
-            output,
-            error_message: None,
-            backtrace: None,
-        };
+        let patch = DatumPatch::new(Status::Done, report);
         self.patch_datum(datum, &patch).await
     }
 
@@ -396,15 +1826,16 @@ impl Client {
     pub async fn mark_datum_as_error(
         &self,
         datum: &mut Datum,
-        output: String,
+        report: DatumReport,
         error_message: String,
         backtrace: String,
+        failure_class: Option<FailureClass>,
     ) -> Result<()> {
         let patch = DatumPatch {
-            status: Status::Error,
-            output,
             error_message: Some(error_message),
             backtrace: Some(backtrace),
+            failure_class,
+            ..DatumPatch::new(Status::Error, report)
         };
         self.patch_datum(datum, &patch).await
     }
@@ -416,16 +1847,16 @@ impl Client {
     async fn patch_datum(&self, datum: &mut Datum, patch: &DatumPatch) -> Result<()> {
         let url = self.url.join(&format!("datums/{}", datum.id))?;
         let request = UpdateDatumRequest {
-            pod_name: pod_name()?,
+            pod_name: self.pod_name()?,
             datum: patch.clone(),
         };
         let response: DatumResponse = self
-            .via
-            .retry_if_appropriate_async(|| async {
+            .retry_idempotent(|| async {
                 let resp = self
                     .client
                     .patch(url.clone())
-                    .basic_auth(&self.username, Some(&self.password))
+                    .header(reqwest::header::AUTHORIZATION, self.authorization.clone())
+                    .header(POD_NAME_HEADER, &request.pod_name)
                     .json(&request)
                     .send()
                     .await
@@ -446,18 +1877,79 @@ impl Client {
         datum_id: Uuid,
     ) -> Result<DatumDescribeResponse> {
         let url = self.url.join(&format!("datums/{}/describe", datum_id))?;
-        self.via
-            .retry_if_appropriate_async(|| async {
+        self.retry_idempotent(|| async {
+            let resp = self
+                .client
+                .get(url.clone())
+                .header(reqwest::header::AUTHORIZATION, self.authorization.clone())
+                .send()
+                .await
+                .with_context(|| format!("error getting {}", url))?;
+            self.handle_json_response(&url, resp).await
+        })
+        .await
+    }
+
+    /// Give up on `datum` without counting it as an attempt, so that another
+    /// worker can process it. Used when our pod is being shut down.
+    ///
+    /// `POST /datums/{datum_id}/release`
+    #[instrument(skip_all, fields(datum = %datum.id), level = "trace")]
+    pub async fn release_datum(&self, datum: &mut Datum) -> Result<()> {
+        let url = self.url.join(&format!("datums/{}/release", datum.id))?;
+        let request = ReleaseDatumRequest {
+            pod_name: self.pod_name()?,
+        };
+        let response: DatumResponse = self
+            .retry_non_idempotent(|| async {
                 let resp = self
                     .client
-                    .get(url.clone())
-                    .basic_auth(&self.username, Some(&self.password))
+                    .post(url.clone())
+                    .header(reqwest::header::AUTHORIZATION, self.authorization.clone())
+                    .json(&request)
                     .send()
                     .await
-                    .with_context(|| format!("error getting {}", url))?;
+                    .with_context(|| format!("error posting {}", url))?;
                 self.handle_json_response(&url, resp).await
             })
-            .await
+            .await?;
+        *datum = response.datum;
+        Ok(())
+    }
+
+    /// Append `output` to the output of a running datum, so that people can
+    /// watch it. `offset` is how many bytes we've already sent for this
+    /// attempt, which makes this safe to retry.
+    ///
+    /// `PATCH /datums/{datum_id}/append_output`
+    #[instrument(level = "trace", skip_all, fields(datum = %datum.id))]
+    pub async fn append_datum_output(
+        &self,
+        datum: &Datum,
+        offset: u64,
+        output: &str,
+    ) -> Result<()> {
+        let url = self
+            .url
+            .join(&format!("datums/{}/append_output", datum.id))?;
+        let request = AppendDatumOutputRequest {
+            pod_name: self.pod_name()?,
+            offset,
+            output: output.to_owned(),
+        };
+        self.retry_idempotent(|| async {
+            let resp = self
+                .client
+                .patch(url.clone())
+                .header(reqwest::header::AUTHORIZATION, self.authorization.clone())
+                .header(POD_NAME_HEADER, &request.pod_name)
+                .json(&request)
+                .send()
+                .await
+                .with_context(|| format!("error patching {}", url))?;
+            self.handle_empty_response(&url, resp).await
+        })
+        .await
     }
 
     /// Create new output files for a datum.
@@ -473,7 +1965,7 @@ impl Client {
             .url
             .join(&format!("datums/{}/output_files", datum.id))?;
         let request = CreateOutputFilesRequest {
-            pod_name: pod_name()?,
+            pod_name: self.pod_name()?,
             output_files: output_files.to_vec(),
         };
         // TODO: We might want finer-grained retry here? This isn't remotely
@@ -481,12 +1973,12 @@ impl Client {
         // the retries should just fail until we give up, then we'll eventually
         // fail the datum, allowing it to be retried.
         let response: OutputFilesResponse = self
-            .via
-            .retry_if_appropriate_async(|| async {
+            .retry_non_idempotent(|| async {
                 let resp = self
                     .client
                     .post(url.clone())
-                    .basic_auth(&self.username, Some(&self.password))
+                    .header(reqwest::header::AUTHORIZATION, self.authorization.clone())
+                    .header(POD_NAME_HEADER, &request.pod_name)
                     .json(&request)
                     .send()
                     .await
@@ -510,22 +2002,22 @@ impl Client {
             .url
             .join(&format!("datums/{}/output_files", datum.id))?;
         let request = UpdateOutputFilesRequest {
-            pod_name: pod_name()?,
+            pod_name: self.pod_name()?,
             output_files: patches.to_vec(),
         };
-        self.via
-            .retry_if_appropriate_async(|| async {
-                let resp = self
-                    .client
-                    .patch(url.clone())
-                    .basic_auth(&self.username, Some(&self.password))
-                    .json(&request)
-                    .send()
-                    .await
-                    .with_context(|| format!("error patching {}", url))?;
-                self.handle_empty_response(&url, resp).await
-            })
-            .await
+        self.retry_idempotent(|| async {
+            let resp = self
+                .client
+                .patch(url.clone())
+                .header(reqwest::header::AUTHORIZATION, self.authorization.clone())
+                .header(POD_NAME_HEADER, &request.pod_name)
+                .json(&request)
+                .send()
+                .await
+                .with_context(|| format!("error patching {}", url))?;
+            self.handle_empty_response(&url, resp).await
+        })
+        .await
     }
 
     /// Check the HTTP status code and parse a JSON response.
@@ -571,13 +2063,45 @@ impl Client {
         resp: reqwest::Response,
     ) -> Error {
         let status = resp.status();
+        let retry_after = resp.headers().contains_key(reqwest::header::RETRY_AFTER);
+        let header_request_id = resp
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(|id| id.to_owned());
         match resp.text().await {
             Ok(body) => {
-                format_err!("unexpected HTTP status {} for {}:\n{}", status, url, body,)
+                unexpected_status(status, url, body, header_request_id, retry_after)
+                    .into()
             }
             Err(err) => err.into(),
         }
     }
+
+    /// Run `f`, which makes an idempotent request, retrying any failures
+    /// which might go away. See [`is_retryable_error`].
+    async fn retry_idempotent<F, Fut, T>(&self, f: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        self.via
+            .retry_if_appropriate_when_async(|err| is_retryable_error(err, true), f)
+            .await
+    }
+
+    /// Run `f`, which makes a request which may not be repeated once
+    /// `falconerid` has started to process it. We only retry failures which
+    /// happened before that.
+    async fn retry_non_idempotent<F, Fut, T>(&self, f: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        self.via
+            .retry_if_appropriate_when_async(|err| is_retryable_error(err, false), f)
+            .await
+    }
 }
 
 impl fmt::Debug for Client {
@@ -585,11 +2109,140 @@ impl fmt::Debug for Client {
         f.debug_struct("Client")
             .field("via", &self.via)
             .field("url", &self.url)
-            .field("username", &self.username)
             // We don't need these for debugging.
             //
-            // .field("password", &self.password)
+            // .field("authorization", &self.authorization)
             // .field("client", &self.client)
             .finish()
     }
 }
+
+/// Parse the body of a `GET /version` response, which may be either a
+/// [`VersionResponse`] or (for older servers) a plain-text version.
+fn parse_version_body(
+    is_json: bool,
+    body: &str,
+) -> Result<(semver::Version, Option<semver::VersionReq>)> {
+    if is_json {
+        let response = serde_json::from_str::<VersionResponse>(body)
+            .with_context(|| format!("could not parse version response {:?}", body))?;
+        let version =
+            response
+                .version
+                .parse::<semver::Version>()
+                .with_context(|| {
+                    format!("could not parse server version {:?}", response.version)
+                })?;
+        let supported = response
+            .supported_client_versions
+            .parse::<semver::VersionReq>()
+            .with_context(|| {
+                format!(
+                    "could not parse supported client versions {:?}",
+                    response.supported_client_versions
+                )
+            })?;
+        Ok((version, Some(supported)))
+    } else {
+        let version = body
+            .trim()
+            .parse::<semver::Version>()
+            .with_context(|| format!("could not parse server version {:?}", body))?;
+        Ok((version, None))
+    }
+}
+
+#[test]
+fn parse_old_and_new_version_responses() {
+    let (version, supported) = parse_version_body(false, "2.0.0-alpha.4\n").unwrap();
+    assert_eq!(version.to_string(), "2.0.0-alpha.4");
+    assert!(supported.is_none());
+
+    let body = r#"{"version":"2.1.0","supported_client_versions":">=2.0.0, <3.0.0"}"#;
+    let (version, supported) = parse_version_body(true, body).unwrap();
+    assert_eq!(version.to_string(), "2.1.0");
+    assert!(supported.unwrap().matches(&"2.0.5".parse().unwrap()));
+}
+
+#[test]
+fn only_transient_errors_are_retried() {
+    let error_with_retry_after = |status, retry_after| -> Error {
+        Error::from(UnexpectedStatus {
+            status,
+            url: "http://falconerid:8089/datums/x".parse().unwrap(),
+            body: "oops".to_owned(),
+            kind: FalconeriApiError::from_status(status),
+            request_id: None,
+            retry_after,
+        })
+        .context("error patching datum")
+    };
+    let error = |status| error_with_retry_after(status, false);
+
+    let unavailable = error(StatusCode::SERVICE_UNAVAILABLE);
+    assert!(is_retryable_error(&unavailable, true));
+    assert!(!is_retryable_error(&unavailable, false));
+    let busy = error_with_retry_after(StatusCode::SERVICE_UNAVAILABLE, true);
+    assert!(is_retryable_error(&busy, false));
+    let too_many = error(StatusCode::TOO_MANY_REQUESTS);
+    assert!(is_retryable_error(&too_many, true));
+    assert!(is_retryable_error(&too_many, false));
+    assert!(!is_retryable_error(&error(StatusCode::NOT_FOUND), true));
+    assert!(!is_retryable_error(&format_err!("bad JSON"), true));
+    assert!(unavailable
+        .root_cause()
+        .to_string()
+        .starts_with("unexpected HTTP status 503 Service Unavailable for"));
+}
+
+#[test]
+fn error_envelopes_are_parsed() {
+    let url = "http://falconerid:8089/jobs/x".parse::<Url>().unwrap();
+    let body = r#"{"code":"not_found","message":"no such job","request_id":"abc"}"#;
+    let unexpected =
+        unexpected_status(StatusCode::NOT_FOUND, &url, body.to_owned(), None, false);
+    assert_eq!(unexpected.kind, FalconeriApiError::NotFound);
+    assert_eq!(unexpected.body, "no such job");
+    assert_eq!(
+        unexpected.to_string(),
+        "unexpected HTTP status 404 Not Found for http://falconerid:8089/jobs/x (request ID abc):\nno such job"
+    );
+
+    // Proxies send plain text, but may still pass along our request ID.
+    let unexpected = unexpected_status(
+        StatusCode::BAD_GATEWAY,
+        &url,
+        "bad gateway".to_owned(),
+        Some("def".to_owned()),
+        false,
+    );
+    assert_eq!(unexpected.kind, FalconeriApiError::Unavailable);
+    assert_eq!(unexpected.body, "bad gateway");
+    assert_eq!(unexpected.request_id.as_deref(), Some("def"));
+}
+
+#[test]
+fn callers_can_branch_on_api_errors() {
+    let url = "http://falconerid:8089/datums/x".parse::<Url>().unwrap();
+    let body = r#"{"code":"forbidden","message":"datum reserved by another pod"}"#;
+    let err = Error::from(unexpected_status(
+        StatusCode::FORBIDDEN,
+        &url,
+        body.to_owned(),
+        None,
+        false,
+    ))
+    .context("error patching datum");
+    assert_eq!(api_error(&err), Some(FalconeriApiError::Forbidden));
+    assert_eq!(api_error(&format_err!("bad JSON")), None);
+
+    // Newer servers may send codes we don't know about.
+    let body = r#"{"code":"something_new","message":"huh"}"#;
+    let unexpected =
+        unexpected_status(StatusCode::IM_A_TEAPOT, &url, body.to_owned(), None, false);
+    assert_eq!(unexpected.kind, FalconeriApiError::Unknown);
+    assert_eq!(
+        serde_json::to_string(&FalconeriApiError::RateLimited).unwrap(),
+        r#""too_many_requests""#
+    );
+}
@@ -0,0 +1,78 @@
+//! Cron schedules for registered pipelines.
+
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+
+use crate::prelude::*;
+
+/// A cron schedule, interpreted in UTC.
+///
+/// We accept standard five-field expressions like `0 3 * * *` (minute, hour,
+/// day of month, month, day of week), as well as the six- and seven-field
+/// expressions supported by the `cron` crate, which add seconds and years.
+#[derive(Clone, Debug)]
+pub struct CronSchedule {
+    /// The expression we were created from.
+    expression: String,
+    /// Our parsed schedule.
+    schedule: cron::Schedule,
+}
+
+impl CronSchedule {
+    /// When should this schedule next run, strictly after `after`?
+    pub fn next_after(&self, after: NaiveDateTime) -> Option<NaiveDateTime> {
+        let after = DateTime::<Utc>::from_naive_utc_and_offset(after, Utc);
+        self.schedule
+            .after(&after)
+            .next()
+            .map(|next| next.naive_utc())
+    }
+}
+
+impl FromStr for CronSchedule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let expression = s.trim();
+        // The `cron` crate requires a seconds field, so add one if we were
+        // given a standard five-field expression.
+        let with_seconds = if expression.split_whitespace().count() == 5 {
+            format!("0 {}", expression)
+        } else {
+            expression.to_owned()
+        };
+        let schedule = with_seconds
+            .parse::<cron::Schedule>()
+            .map_err(|err| format_err!("invalid cron schedule {:?}: {}", s, err))?;
+        Ok(CronSchedule {
+            expression: expression.to_owned(),
+            schedule,
+        })
+    }
+}
+
+impl fmt::Display for CronSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.expression.fmt(f)
+    }
+}
+
+#[test]
+fn five_field_schedules_run_on_the_minute() {
+    let schedule = "0 3 * * *".parse::<CronSchedule>().unwrap();
+    let now = "2026-10-15T12:34:56".parse::<NaiveDateTime>().unwrap();
+    assert_eq!(
+        schedule.next_after(now).unwrap().to_string(),
+        "2026-10-16 03:00:00"
+    );
+    assert_eq!(schedule.to_string(), "0 3 * * *");
+
+    let every_quarter_hour = "*/15 * * * *".parse::<CronSchedule>().unwrap();
+    assert_eq!(
+        every_quarter_hour.next_after(now).unwrap().to_string(),
+        "2026-10-15 12:45:00"
+    );
+
+    assert!("not a schedule".parse::<CronSchedule>().is_err());
+}
@@ -1,6 +1,17 @@
+table! {
+    api_tokens (id) {
+        id -> Uuid,
+        created_at -> Timestamp,
+        name -> Text,
+        token_hash -> Text,
+        revoked_at -> Nullable<Timestamp>,
+        team -> Nullable<Text>,
+    }
+}
+
 table! {
     use diesel::sql_types::*;
-    use crate::models::sql_types::Status;
+    use crate::models::sql_types::{FailureClass, Status};
 
     datums (id) {
         id -> Uuid,
@@ -15,6 +26,26 @@ table! {
         output -> Nullable<Text>,
         attempted_run_count -> Int4,
         maximum_allowed_run_count -> Int4,
+        started_at -> Nullable<Timestamp>,
+        download_completed_at -> Nullable<Timestamp>,
+        command_completed_at -> Nullable<Timestamp>,
+        upload_completed_at -> Nullable<Timestamp>,
+        input_bytes -> Nullable<Int8>,
+        output_bytes -> Nullable<Int8>,
+        output_uri -> Nullable<Text>,
+        prefetched -> Bool,
+        failure_class -> Nullable<FailureClass>,
+        input_hash -> Nullable<Text>,
+        retry_after -> Nullable<Timestamp>,
+        listed_input_bytes -> Nullable<Int8>,
+        stdout -> Nullable<Text>,
+        stderr -> Nullable<Text>,
+        peak_memory_bytes -> Nullable<Int8>,
+        cpu_seconds -> Nullable<Float8>,
+        output_zstd -> Nullable<Bytea>,
+        output_zstd_length -> Nullable<Int8>,
+        backtrace_zstd -> Nullable<Bytea>,
+        backtrace_zstd_length -> Nullable<Int8>,
     }
 }
 
@@ -28,6 +59,25 @@ table! {
         uri -> Text,
         local_path -> Text,
         job_id -> Uuid,
+        etag -> Nullable<Text>,
+        generation -> Nullable<Text>,
+        decompress -> Bool,
+        size -> Nullable<Int8>,
+        last_modified -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::models::sql_types::JobEventKind;
+
+    job_events (id) {
+        id -> Uuid,
+        created_at -> Timestamp,
+        job_id -> Uuid,
+        datum_id -> Nullable<Uuid>,
+        kind -> JobEventKind,
+        message -> Text,
     }
 }
 
@@ -44,6 +94,33 @@ table! {
         job_name -> Text,
         command -> Array<Text>,
         egress_uri -> Text,
+        stop_on_first_error -> Bool,
+        spec_hash -> Nullable<Text>,
+        max_inline_output_bytes -> Int8,
+        output_log_uri -> Nullable<Text>,
+        error_message -> Nullable<Text>,
+        total_datum_count -> Nullable<Int8>,
+        autoscale_parallelism -> Bool,
+        target_parallelism -> Nullable<Int4>,
+        idempotency_key -> Nullable<Text>,
+        deferred_start -> Nullable<Jsonb>,
+        parent_job_id -> Nullable<Uuid>,
+        team -> Nullable<Text>,
+        image_digest -> Nullable<Text>,
+        submitted_pipeline_spec -> Nullable<Jsonb>,
+        retry_backoff_seconds -> Nullable<Int4>,
+        max_failed_datums -> Nullable<Int4>,
+        max_failed_datum_percent -> Nullable<Float8>,
+        output_manifest_uri -> Nullable<Text>,
+        output_manifest_written_at -> Nullable<Timestamp>,
+        excluded_node_names -> Array<Text>,
+    }
+}
+
+table! {
+    job_dependencies (job_id, upstream_job_id) {
+        job_id -> Uuid,
+        upstream_job_id -> Uuid,
     }
 }
 
@@ -59,12 +136,74 @@ table! {
         job_id -> Uuid,
         datum_id -> Uuid,
         uri -> Text,
+        size -> Nullable<Int8>,
+        sha256 -> Nullable<Text>,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+
+    pipelines (id) {
+        id -> Uuid,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+        name -> Text,
+        pipeline_spec -> Jsonb,
+        schedule -> Nullable<Text>,
+        next_run_at -> Nullable<Timestamp>,
+        last_run_at -> Nullable<Timestamp>,
+        last_job_id -> Nullable<Uuid>,
+    }
+}
+
+table! {
+    quotas (team) {
+        team -> Text,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+        max_running_jobs -> Nullable<Int4>,
+        max_parallelism -> Nullable<Int4>,
+    }
+}
+
+table! {
+    server_settings (id) {
+        id -> Bool,
+        updated_at -> Timestamp,
+        reservations_paused -> Bool,
+    }
+}
+
+table! {
+    worker_tokens (id) {
+        id -> Uuid,
+        created_at -> Timestamp,
+        job_id -> Uuid,
+        token_hash -> Text,
+        expires_at -> Nullable<Timestamp>,
     }
 }
 
 joinable!(datums -> jobs (job_id));
 joinable!(input_files -> datums (datum_id));
+joinable!(job_events -> datums (datum_id));
+joinable!(job_events -> jobs (job_id));
 joinable!(output_files -> datums (datum_id));
 joinable!(output_files -> jobs (job_id));
+joinable!(pipelines -> jobs (last_job_id));
+joinable!(worker_tokens -> jobs (job_id));
 
-allow_tables_to_appear_in_same_query!(datums, input_files, jobs, output_files,);
+allow_tables_to_appear_in_same_query!(
+    api_tokens,
+    datums,
+    input_files,
+    job_dependencies,
+    job_events,
+    jobs,
+    output_files,
+    pipelines,
+    quotas,
+    server_settings,
+    worker_tokens,
+);
@@ -35,4 +35,43 @@ pub enum Secret {
         #[serde(default)]
         optional: bool,
     },
+
+    /// EXTENSION: A field of a HashiCorp Vault secret, which workers should
+    /// fetch when they start and place in an environment variable. Requires
+    /// `transform.vault`.
+    Vault {
+        /// The Vault API path of the secret, without the leading `/v1/`. For
+        /// version 2 KV secrets engines, this includes `data/`, as in
+        /// `secret/data/s3`.
+        vault_path: String,
+        /// The field within the secret to use.
+        field: String,
+        /// The environment variable name into which to place the value.
+        env_var: String,
+    },
+}
+
+/// EXTENSION: How workers should log into HashiCorp Vault to fetch
+/// [`Secret::Vault`] secrets.
+#[derive(Clone, Debug, Deserialize, JsonSchema, PartialEq, Serialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct VaultConfig {
+    /// The URL of the Vault server, like `https://vault.example.com:8200`.
+    pub address: String,
+    /// The role to log in as using Vault's Kubernetes auth method, which
+    /// authenticates using our worker pods' service account.
+    #[serde(default)]
+    pub role: Option<String>,
+    /// Where Vault's Kubernetes auth method is mounted.
+    #[serde(default = "default_vault_auth_mount")]
+    pub auth_mount: String,
+    /// Instead of logging in, read a Vault token from this file. Use this
+    /// with a Vault Agent sidecar which keeps the token up to date.
+    #[serde(default)]
+    pub token_path: Option<String>,
+}
+
+/// Helper for `serde(default)`.
+fn default_vault_auth_mount() -> String {
+    "kubernetes".to_owned()
 }
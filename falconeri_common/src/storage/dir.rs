@@ -0,0 +1,350 @@
+//! A fake storage backend which keeps "buckets" in a local directory, for
+//! use in tests.
+//!
+//! A URI like `test://bucket/path/file.txt` refers to the local file
+//! `<root>/bucket/path/file.txt`. This is only available with the `testing`
+//! feature.
+
+use std::{fs, sync::Arc};
+
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use lazy_static::lazy_static;
+use object_store::{
+    local::LocalFileSystem, path::Path as ObjectPath, Attributes, ObjectStore,
+};
+use regex::Regex;
+use tokio::{
+    fs as async_fs,
+    io::{AsyncRead, AsyncWrite},
+};
+use walkdir::WalkDir;
+
+use super::{
+    check_file_uri, delete_object, stream_download_to_file, stream_download_to_writer,
+    stream_upload_from_file, stream_upload_from_reader, CloudStorage, ListedObject,
+    MultipartOptions, StorageBackend,
+};
+use crate::{prelude::*, secret::Secret};
+
+/// Parse a directory storage URL into (scheme, bucket, key).
+fn parse_dir_url(url: &str) -> Result<(&str, &str, &str)> {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(
+            "^(?P<scheme>[A-Za-z0-9+.-]+)://(?P<bucket>[^/]+)(?:/(?P<key>.*))?$"
+        )
+        .expect("couldn't parse built-in regex");
+    }
+
+    let caps = RE
+        .captures(url)
+        .ok_or_else(|| format_err!("the URL {:?} could not be parsed", url))?;
+    let scheme = caps
+        .name("scheme")
+        .expect("missing hard-coded capture???")
+        .as_str();
+    let bucket = caps
+        .name("bucket")
+        .expect("missing hard-coded capture???")
+        .as_str();
+    let key = caps.name("key").map(|m| m.as_str()).unwrap_or("");
+
+    Ok((scheme, bucket, key))
+}
+
+/// Storage for a single "bucket", which is a subdirectory of a local
+/// directory.
+pub struct DirStorage {
+    store: Arc<dyn ObjectStore>,
+    scheme: String,
+    bucket: String,
+    multipart: MultipartOptions,
+}
+
+impl DirStorage {
+    /// Create a new `DirStorage` backend for the bucket containing
+    /// `bucket_uri`, storing each bucket as a subdirectory of `root`.
+    #[instrument(skip_all, fields(root = %root.display()), level = "trace")]
+    pub fn new(root: &Path, bucket_uri: &str) -> Result<Self> {
+        let (scheme, bucket, _) = parse_dir_url(bucket_uri)?;
+        let bucket_dir = root.join(bucket);
+        fs::create_dir_all(&bucket_dir)
+            .with_context(|| format!("cannot create {}", bucket_dir.display()))?;
+        let store = LocalFileSystem::new_with_prefix(&bucket_dir)
+            .with_context(|| format!("cannot open {}", bucket_dir.display()))?;
+        Ok(DirStorage {
+            store: Arc::new(store),
+            scheme: scheme.to_owned(),
+            bucket: bucket.to_owned(),
+            // Use small parts, so that tests exercise multipart uploads.
+            multipart: MultipartOptions {
+                part_size: 64 * 1024,
+                max_concurrency: 2,
+            },
+        })
+    }
+}
+
+impl fmt::Debug for DirStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DirStorage")
+            .field("scheme", &self.scheme)
+            .field("bucket", &self.bucket)
+            .finish()
+    }
+}
+
+#[async_trait]
+impl CloudStorage for DirStorage {
+    #[instrument(skip_all, fields(uri = %uri), level = "trace")]
+    async fn list_objects(&self, uri: &str) -> Result<Vec<ListedObject>> {
+        trace!("listing {}", uri);
+
+        let (_, _, key) = parse_dir_url(uri)?;
+        let mut prefix = key.to_owned();
+        if !key.is_empty() && !key.ends_with('/') {
+            prefix.push('/');
+        }
+
+        let prefix_path = if prefix.is_empty() {
+            None
+        } else {
+            Some(ObjectPath::from(prefix.as_str()))
+        };
+
+        let mut results = Vec::new();
+        let mut stream = self.store.list(prefix_path.as_ref());
+        while let Some(meta) = stream
+            .try_next()
+            .await
+            .context("error listing local objects")?
+        {
+            results.push(ListedObject {
+                uri: format!("{}://{}/{}", self.scheme, self.bucket, meta.location),
+                etag: meta.e_tag,
+                generation: meta.version,
+                size: Some(meta.size),
+                last_modified: Some(meta.last_modified.naive_utc()),
+            });
+        }
+
+        // If `uri` is a file, we won't find anything under it.
+        if results.is_empty() && !key.is_empty() && !key.ends_with('/') {
+            if let Ok(meta) = self.store.head(&ObjectPath::from(key)).await {
+                results.push(ListedObject {
+                    uri: uri.to_owned(),
+                    etag: meta.e_tag,
+                    generation: meta.version,
+                    size: Some(meta.size),
+                    last_modified: Some(meta.last_modified.naive_utc()),
+                });
+            }
+        }
+
+        Ok(results)
+    }
+
+    #[instrument(skip_all, fields(uri = %uri, local_path = %local_path.display()), level = "trace")]
+    async fn sync_down(&self, uri: &str, local_path: &Path) -> Result<()> {
+        trace!("downloading {} to {}", uri, local_path.display());
+
+        let (_, _, key) = parse_dir_url(uri)?;
+
+        if uri.ends_with('/') {
+            async_fs::create_dir_all(local_path)
+                .await
+                .context("cannot create local download directory")?;
+
+            let prefix = ObjectPath::from(key);
+            let mut stream = self.store.list(Some(&prefix));
+            while let Some(meta) = stream
+                .try_next()
+                .await
+                .context("error listing local objects")?
+            {
+                let object_key = meta.location.to_string();
+                let relative_path = object_key
+                    .strip_prefix(key)
+                    .unwrap_or(&object_key)
+                    .trim_start_matches('/');
+                if relative_path.is_empty() {
+                    continue;
+                }
+
+                let file_path = local_path.join(relative_path);
+                if let Some(parent) = file_path.parent() {
+                    async_fs::create_dir_all(parent)
+                        .await
+                        .context("cannot create local subdirectory")?;
+                }
+                stream_download_to_file(&self.store, &meta.location, &file_path)
+                    .await?;
+            }
+        } else {
+            if let Some(parent) = local_path.parent() {
+                async_fs::create_dir_all(parent)
+                    .await
+                    .context("cannot create local download directory")?;
+            }
+            let object_path = ObjectPath::from(key);
+            stream_download_to_file(&self.store, &object_path, local_path).await?;
+        }
+
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(local_path = %local_path.display(), uri = %uri), level = "trace")]
+    async fn sync_up(&self, local_path: &Path, uri: &str) -> Result<()> {
+        trace!("uploading {} to {}", local_path.display(), uri);
+
+        let (_, _, key) = parse_dir_url(uri)?;
+        let base_key = key.trim_end_matches('/');
+
+        for entry in WalkDir::new(local_path).into_iter().filter_map(|e| e.ok()) {
+            if !entry.file_type().is_file() {
+                continue;
+            }
+
+            let file_path = entry.path();
+            let relative_path = file_path
+                .strip_prefix(local_path)
+                .context("failed to compute relative path")?;
+            let object_key = if base_key.is_empty() {
+                relative_path.to_string_lossy().to_string()
+            } else {
+                format!("{}/{}", base_key, relative_path.to_string_lossy())
+            };
+
+            let object_path = ObjectPath::from(object_key.as_str());
+            stream_upload_from_file(
+                &self.store,
+                file_path,
+                &object_path,
+                &self.multipart,
+                &Attributes::new(),
+            )
+            .await
+            .with_context(|| format!("error uploading to {}", object_key))?;
+        }
+
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(uri = %uri), level = "trace")]
+    async fn download_to_writer(
+        &self,
+        uri: &str,
+        writer: &mut (dyn AsyncWrite + Send + Unpin),
+    ) -> Result<u64> {
+        check_file_uri(uri)?;
+        let (_, _, key) = parse_dir_url(uri)?;
+        let object_path = ObjectPath::from(key);
+        stream_download_to_writer(&self.store, &object_path, writer)
+            .await
+            .with_context(|| format!("error downloading from {}", uri))
+    }
+
+    #[instrument(skip_all, fields(uri = %uri), level = "trace")]
+    async fn upload_from_reader(
+        &self,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+        uri: &str,
+    ) -> Result<u64> {
+        check_file_uri(uri)?;
+        let (_, _, key) = parse_dir_url(uri)?;
+        let object_path = ObjectPath::from(key);
+        stream_upload_from_reader(
+            &self.store,
+            reader,
+            &object_path,
+            &self.multipart,
+            &Attributes::new(),
+        )
+        .await
+        .with_context(|| format!("error uploading to {}", uri))
+    }
+
+    #[instrument(skip_all, fields(uri = %uri), level = "trace")]
+    async fn delete(&self, uri: &str) -> Result<()> {
+        check_file_uri(uri)?;
+        let (_, _, key) = parse_dir_url(uri)?;
+        delete_object(&self.store, &ObjectPath::from(key))
+            .await
+            .with_context(|| format!("error deleting {}", uri))
+    }
+}
+
+/// A [`StorageBackend`] which opens [`DirStorage`] buckets under a local
+/// directory.
+#[derive(Clone, Debug)]
+pub struct DirStorageBackend {
+    root: PathBuf,
+}
+
+impl DirStorageBackend {
+    /// Create a backend which keeps each bucket in a subdirectory of `root`.
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        DirStorageBackend { root: root.into() }
+    }
+}
+
+#[async_trait]
+impl StorageBackend for DirStorageBackend {
+    async fn open(
+        &self,
+        bucket_uri: &str,
+        _secrets: &[Secret],
+    ) -> Result<Box<dyn CloudStorage>> {
+        Ok(Box::new(DirStorage::new(&self.root, bucket_uri)?))
+    }
+}
+
+#[test]
+fn url_parsing() {
+    assert_eq!(
+        parse_dir_url("test://bucket/a/b.txt").unwrap(),
+        ("test", "bucket", "a/b.txt")
+    );
+    assert_eq!(
+        parse_dir_url("test://bucket").unwrap(),
+        ("test", "bucket", "")
+    );
+    assert!(parse_dir_url("/local/path").is_err());
+}
+
+#[tokio::test]
+async fn dir_storage_round_trip() {
+    let root = crate::temp_dir::TempDir::new("falconeri-test").unwrap();
+    let storage = DirStorage::new(&root, "test://bucket/").unwrap();
+
+    let src = root.join("src");
+    fs::create_dir_all(src.join("sub")).unwrap();
+    fs::write(src.join("a.txt"), "a").unwrap();
+    fs::write(src.join("sub/b.txt"), "b").unwrap();
+    storage.sync_up(&src, "test://bucket/in/").await.unwrap();
+
+    let mut uris = storage.list("test://bucket/in/").await.unwrap();
+    uris.sort();
+    assert_eq!(
+        uris,
+        vec!["test://bucket/in/a.txt", "test://bucket/in/sub/b.txt"]
+    );
+    let objects = storage
+        .list_objects("test://bucket/in/a.txt")
+        .await
+        .unwrap();
+    assert_eq!(objects.len(), 1);
+    assert_eq!(objects[0].size, Some(1));
+    assert!(objects[0].etag.is_some());
+
+    let dest = root.join("dest/");
+    storage.sync_down("test://bucket/in/", &dest).await.unwrap();
+    assert_eq!(fs::read_to_string(dest.join("sub/b.txt")).unwrap(), "b");
+
+    let mut copied = vec![];
+    storage
+        .download_to_writer("test://bucket/in/a.txt", &mut copied)
+        .await
+        .unwrap();
+    assert_eq!(copied, b"a");
+}
@@ -1,18 +1,29 @@
 //! Support for Google Cloud Storage using the native object_store crate.
 
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use async_trait::async_trait;
+use backon::{BackoffBuilder, ExponentialBuilder};
 use futures::TryStreamExt;
 use lazy_static::lazy_static;
 use object_store::{
-    gcp::GoogleCloudStorageBuilder, path::Path as ObjectPath, ObjectStore,
+    gcp::{GoogleCloudStorage as GcsStore, GoogleCloudStorageBuilder},
+    path::Path as ObjectPath,
+    ClientOptions, CredentialProvider, ObjectStore,
 };
 use regex::Regex;
-use tokio::fs as async_fs;
+use reqwest::{header, StatusCode};
+use tokio::{
+    fs as async_fs,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite},
+};
+use url::Url;
 use walkdir::WalkDir;
 
-use super::{stream_download_to_file, stream_upload_from_file, CloudStorage};
+use super::{
+    check_file_uri, delete_object, stream_download_to_file, stream_download_to_writer,
+    CloudStorage, ListedObject, RequesterPays, UploadOptions,
+};
 use crate::{
     kubernetes::{base64_encoded_optional_secret_string, kubectl_secret},
     prelude::*,
@@ -47,10 +58,147 @@ fn parse_gs_url(url: &str) -> Result<(&str, &str)> {
     Ok((bucket, key))
 }
 
+/// The base URL for GCS resumable uploads.
+const UPLOAD_URL: &str = "https://storage.googleapis.com/upload/storage/v1/b/";
+
+/// How much data we upload in each chunk of a resumable upload. This must be
+/// a multiple of 256 KiB. We keep one chunk in memory per upload, so that we
+/// can retry it.
+const CHUNK_SIZE: usize = 16 * 1024 * 1024;
+
+/// How long we'll wait for a single chunk to upload.
+const CHUNK_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// How we back off when retrying a chunk.
+fn chunk_backoff() -> ExponentialBuilder {
+    ExponentialBuilder::default()
+        .with_min_delay(Duration::from_millis(500))
+        .with_max_delay(Duration::from_secs(60))
+        .with_jitter()
+        .with_max_times(10)
+}
+
+/// What happened when we sent part of a resumable upload.
+#[derive(Debug, PartialEq, Eq)]
+enum ChunkResponse {
+    /// The upload is finished.
+    Complete,
+    /// The server has persisted this many bytes, and wants more.
+    Incomplete(u64),
+    /// Something went wrong, but we can ask the server how much it has and
+    /// try again.
+    Retry(String),
+}
+
+/// Format a `Content-Range` header for `len` bytes starting at `offset`. If
+/// we know the `total` size, this is the final chunk.
+fn content_range(offset: u64, len: usize, total: Option<u64>) -> String {
+    let total = total
+        .map(|total| total.to_string())
+        .unwrap_or_else(|| "*".to_owned());
+    if len == 0 {
+        format!("bytes */{}", total)
+    } else {
+        format!("bytes {}-{}/{}", offset, offset + cast::u64(len) - 1, total)
+    }
+}
+
+/// Parse the `Range` header of a `308 Resume Incomplete` response, returning
+/// how many bytes the server has persisted.
+fn persisted_bytes(range: Option<&str>) -> Result<u64> {
+    match range {
+        None => Ok(0),
+        Some(range) => {
+            let end = range
+                .strip_prefix("bytes=0-")
+                .ok_or_else(|| format_err!("unexpected upload range {:?}", range))?;
+            Ok(end
+                .parse::<u64>()
+                .with_context(|| format!("unexpected upload range {:?}", range))?
+                + 1)
+        }
+    }
+}
+
+/// Extra headers to send with every request. For requester-pays buckets, we
+/// use `x-goog-user-project`, which is equivalent to the `userProject` query
+/// parameter, but which we can add to every request.
+fn default_headers(
+    requester_pays: Option<&RequesterPays>,
+) -> Result<header::HeaderMap> {
+    let mut headers = header::HeaderMap::new();
+    if let Some(requester_pays) = requester_pays {
+        let billing_project =
+            requester_pays.billing_project.as_deref().ok_or_else(|| {
+                format_err!("GCS requester-pays buckets require a billing_project")
+            })?;
+        headers.insert(
+            "x-goog-user-project",
+            header::HeaderValue::from_str(billing_project).with_context(|| {
+                format!("invalid billing project {:?}", billing_project)
+            })?,
+        );
+    }
+    Ok(headers)
+}
+
+/// The URL used to start a resumable upload of `key` to `bucket`.
+fn resumable_upload_url(bucket: &str, key: &str, options: &UploadOptions) -> Url {
+    let mut url = Url::parse(UPLOAD_URL).expect("invalid built-in URL");
+    url.path_segments_mut()
+        .expect("built-in URL cannot be a base")
+        .pop_if_empty()
+        .push(bucket)
+        .push("o");
+    {
+        let mut query = url.query_pairs_mut();
+        query
+            .append_pair("uploadType", "resumable")
+            .append_pair("name", key);
+        if let Some(kms_key) = &options.kms_key {
+            query.append_pair("kmsKeyName", kms_key);
+        }
+    }
+    url
+}
+
+/// The object metadata we send when starting a resumable upload, if any.
+fn resumable_upload_metadata(options: &UploadOptions) -> Option<serde_json::Value> {
+    options
+        .storage_class
+        .as_ref()
+        .map(|storage_class| serde_json::json!({ "storageClass": storage_class }))
+}
+
+/// Read from `reader` until `buf` contains `CHUNK_SIZE` bytes or we reach the
+/// end of the input.
+async fn fill_chunk(
+    reader: &mut (dyn AsyncRead + Send + Unpin),
+    buf: &mut Vec<u8>,
+) -> Result<()> {
+    buf.clear();
+    while buf.len() < CHUNK_SIZE {
+        let n = (&mut *reader)
+            .take(cast::u64(CHUNK_SIZE - buf.len()))
+            .read_to_end(buf)
+            .await?;
+        if n == 0 {
+            break;
+        }
+    }
+    Ok(())
+}
+
 /// Backend for talking to Google Cloud Storage using native Rust (no gsutil).
 pub struct GoogleCloudStorage {
     store: Arc<dyn ObjectStore>,
+    /// The same store, so we can get credentials for resumable uploads.
+    gcs: Arc<GcsStore>,
+    /// An HTTP client for resumable uploads.
+    http: reqwest::Client,
     bucket: String,
+    /// How to store the objects we upload.
+    upload_options: UploadOptions,
 }
 
 impl GoogleCloudStorage {
@@ -61,6 +209,25 @@ impl GoogleCloudStorage {
     #[allow(clippy::new_ret_no_self)]
     #[instrument(skip_all, level = "trace")]
     pub async fn new(secrets: &[Secret], bucket_uri: &str) -> Result<Self> {
+        Self::new_helper(secrets, bucket_uri, None).await
+    }
+
+    /// Create a new `GoogleCloudStorage` backend for a requester-pays bucket,
+    /// billing our requests to `requester_pays.billing_project`.
+    #[instrument(skip_all, level = "trace")]
+    pub async fn new_requester_pays(
+        secrets: &[Secret],
+        bucket_uri: &str,
+        requester_pays: &RequesterPays,
+    ) -> Result<Self> {
+        Self::new_helper(secrets, bucket_uri, Some(requester_pays)).await
+    }
+
+    async fn new_helper(
+        secrets: &[Secret],
+        bucket_uri: &str,
+        requester_pays: Option<&RequesterPays>,
+    ) -> Result<Self> {
         let secret = secrets
             .iter()
             .find(|s| matches!(s, Secret::Env { env_var, .. } if env_var == "GOOGLE_SERVICE_ACCOUNT_KEY"));
@@ -71,12 +238,13 @@ impl GoogleCloudStorage {
                 None
             };
 
-        Self::build_from_secret(secret_data, bucket_uri)
+        Self::build_from_secret(secret_data, bucket_uri, requester_pays)
     }
 
     fn build_from_secret(
         secret_data: Option<GcsSecretData>,
         bucket_uri: &str,
+        requester_pays: Option<&RequesterPays>,
     ) -> Result<Self> {
         let (bucket, _) = parse_gs_url(bucket_uri)?;
 
@@ -96,13 +264,216 @@ impl GoogleCloudStorage {
             builder = builder.with_service_account_key(&service_account_key);
         }
 
-        let store = builder.build().context("failed to build GCS client")?;
+        let headers = default_headers(requester_pays)?;
+        if !headers.is_empty() {
+            builder = builder.with_client_options(
+                ClientOptions::new().with_default_headers(headers.clone()),
+            );
+        }
+
+        let gcs = Arc::new(builder.build().context("failed to build GCS client")?);
+        let http = reqwest::Client::builder()
+            .default_headers(headers)
+            .build()
+            .context("cannot build HTTP client")?;
 
         Ok(GoogleCloudStorage {
-            store: Arc::new(store),
+            store: gcs.clone(),
+            gcs,
+            http,
             bucket: bucket.to_owned(),
+            upload_options: UploadOptions::from_env(),
         })
     }
+
+    /// Get an `Authorization` header value for GCS, if we have credentials.
+    async fn bearer(&self) -> Result<Option<String>> {
+        let credential = self
+            .gcs
+            .credentials()
+            .get_credential()
+            .await
+            .context("could not get GCS credentials")?;
+        if credential.bearer.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(format!("Bearer {}", credential.bearer)))
+        }
+    }
+
+    /// Upload everything in `reader` to `key` using a [resumable upload][],
+    /// returning the number of bytes uploaded. This works for objects of any
+    /// size, and if a chunk fails, we only need to retry that chunk.
+    ///
+    /// [resumable upload]: https://cloud.google.com/storage/docs/performing-resumable-uploads
+    #[instrument(skip_all, fields(key = %key), level = "trace")]
+    async fn resumable_upload(
+        &self,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+        key: &str,
+    ) -> Result<u64> {
+        let session = self.start_resumable_upload(key).await?;
+        let mut buf = Vec::with_capacity(CHUNK_SIZE);
+        let mut offset = 0;
+        loop {
+            fill_chunk(reader, &mut buf).await?;
+            // A short chunk is our last one. If the input is an exact multiple
+            // of `CHUNK_SIZE`, we'll send an empty final chunk.
+            let total =
+                (buf.len() < CHUNK_SIZE).then_some(offset + cast::u64(buf.len()));
+            self.upload_chunk(&session, &buf, offset, total).await?;
+            offset += cast::u64(buf.len());
+            if total.is_some() {
+                return Ok(offset);
+            }
+        }
+    }
+
+    /// Start a resumable upload session for `key`, returning the session URI.
+    async fn start_resumable_upload(&self, key: &str) -> Result<String> {
+        let url = resumable_upload_url(&self.bucket, key, &self.upload_options);
+        let mut request = self
+            .http
+            .post(url)
+            .header("X-Upload-Content-Type", "application/octet-stream");
+        request = match resumable_upload_metadata(&self.upload_options) {
+            Some(metadata) => request.json(&metadata),
+            None => request.header(header::CONTENT_LENGTH, 0),
+        };
+        if let Some(bearer) = self.bearer().await? {
+            request = request.header(header::AUTHORIZATION, bearer);
+        }
+        let resp = request
+            .send()
+            .await
+            .with_context(|| format!("error starting GCS upload of {}", key))?;
+        if !resp.status().is_success() {
+            let status = resp.status();
+            let body = resp.text().await.unwrap_or_default();
+            return Err(format_err!(
+                "could not start GCS upload of {}: {}\n{}",
+                key,
+                status,
+                body
+            ));
+        }
+        resp.headers()
+            .get(header::LOCATION)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_owned())
+            .ok_or_else(|| {
+                format_err!("GCS did not return an upload session for {}", key)
+            })
+    }
+
+    /// Upload `chunk`, which starts at `start`, retrying as needed. If we know
+    /// the `total` size of the object, this is the final chunk.
+    async fn upload_chunk(
+        &self,
+        session: &str,
+        chunk: &[u8],
+        start: u64,
+        total: Option<u64>,
+    ) -> Result<()> {
+        let end = start + cast::u64(chunk.len());
+        let mut backoff = chunk_backoff().build();
+        let mut offset = start;
+        loop {
+            let remaining = &chunk[usize::try_from(offset - start)?..];
+            match self.put_chunk(session, remaining, offset, total).await? {
+                ChunkResponse::Complete if total.is_some() => return Ok(()),
+                ChunkResponse::Complete => {
+                    return Err(format_err!("GCS finished our upload too early"))
+                }
+                // The server may persist less than we sent, so keep going
+                // until it has the whole chunk.
+                ChunkResponse::Incomplete(persisted) if persisted >= end => {
+                    if total.is_none() {
+                        return Ok(());
+                    }
+                    offset = end;
+                }
+                ChunkResponse::Incomplete(persisted) => {
+                    if persisted < start {
+                        return Err(format_err!(
+                            "GCS lost data from our upload (has {} bytes, expected {})",
+                            persisted,
+                            start
+                        ));
+                    }
+                    offset = persisted;
+                }
+                ChunkResponse::Retry(message) => {
+                    let Some(delay) = backoff.next() else {
+                        return Err(format_err!(
+                            "giving up on GCS upload chunk at {}: {}",
+                            offset,
+                            message
+                        ));
+                    };
+                    warn!(
+                        "retrying GCS upload chunk at {} in {:?}: {}",
+                        offset, delay, message
+                    );
+                    tokio::time::sleep(delay).await;
+                    // Find out how much the server actually received.
+                    match self.put_chunk(session, &[], start, None).await? {
+                        ChunkResponse::Incomplete(persisted) if persisted >= start => {
+                            offset = persisted.min(end);
+                        }
+                        ChunkResponse::Complete if total.is_some() => return Ok(()),
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+
+    /// Send a single `PUT` for a resumable upload. If `data` is empty and
+    /// `total` is `None`, this just asks the server how much it has.
+    async fn put_chunk(
+        &self,
+        session: &str,
+        data: &[u8],
+        offset: u64,
+        total: Option<u64>,
+    ) -> Result<ChunkResponse> {
+        let mut request = self
+            .http
+            .put(session)
+            .timeout(CHUNK_TIMEOUT)
+            .header(
+                header::CONTENT_RANGE,
+                content_range(offset, data.len(), total),
+            )
+            .body(data.to_vec());
+        if let Some(bearer) = self.bearer().await? {
+            request = request.header(header::AUTHORIZATION, bearer);
+        }
+        let resp = match request.send().await {
+            Ok(resp) => resp,
+            Err(err) => return Ok(ChunkResponse::Retry(err.to_string())),
+        };
+        let status = resp.status();
+        if status.is_success() {
+            Ok(ChunkResponse::Complete)
+        } else if status == StatusCode::PERMANENT_REDIRECT {
+            // GCS uses 308 to mean "Resume Incomplete".
+            let range = resp
+                .headers()
+                .get(header::RANGE)
+                .and_then(|v| v.to_str().ok());
+            Ok(ChunkResponse::Incomplete(persisted_bytes(range)?))
+        } else if status.is_server_error()
+            || status == StatusCode::TOO_MANY_REQUESTS
+            || status == StatusCode::REQUEST_TIMEOUT
+        {
+            Ok(ChunkResponse::Retry(format!("HTTP status {}", status)))
+        } else {
+            let body = resp.text().await.unwrap_or_default();
+            Err(format_err!("GCS upload failed with {}:\n{}", status, body))
+        }
+    }
 }
 
 impl fmt::Debug for GoogleCloudStorage {
@@ -116,7 +487,7 @@ impl fmt::Debug for GoogleCloudStorage {
 #[async_trait]
 impl CloudStorage for GoogleCloudStorage {
     #[instrument(skip_all, fields(uri = %uri), level = "trace")]
-    async fn list(&self, uri: &str) -> Result<Vec<String>> {
+    async fn list_objects(&self, uri: &str) -> Result<Vec<ListedObject>> {
         trace!("listing {}", uri);
 
         let (bucket, key) = parse_gs_url(uri)?;
@@ -141,7 +512,13 @@ impl CloudStorage for GoogleCloudStorage {
         {
             let path_str = meta.location.to_string();
             if path_str != prefix {
-                results.push(format!("gs://{}/{}", bucket, path_str));
+                results.push(ListedObject {
+                    uri: format!("gs://{}/{}", bucket, path_str),
+                    etag: meta.e_tag,
+                    generation: meta.version,
+                    size: Some(meta.size),
+                    last_modified: Some(meta.last_modified.naive_utc()),
+                });
             }
         }
 
@@ -228,14 +605,53 @@ impl CloudStorage for GoogleCloudStorage {
                 format!("{}/{}", base_key, relative_path.to_string_lossy())
             };
 
-            let object_path = ObjectPath::from(object_key.as_str());
-            stream_upload_from_file(&self.store, file_path, &object_path)
+            let file = async_fs::File::open(file_path).await.with_context(|| {
+                format!("cannot open local file: {}", file_path.display())
+            })?;
+            let mut reader = tokio::io::BufReader::new(file);
+            self.resumable_upload(&mut reader, &object_key)
                 .await
                 .with_context(|| format!("error uploading to GCS: {}", object_key))?;
         }
 
         Ok(())
     }
+
+    #[instrument(skip_all, fields(uri = %uri), level = "trace")]
+    async fn download_to_writer(
+        &self,
+        uri: &str,
+        writer: &mut (dyn AsyncWrite + Send + Unpin),
+    ) -> Result<u64> {
+        check_file_uri(uri)?;
+        let (_, key) = parse_gs_url(uri)?;
+        let object_path = ObjectPath::from(key);
+        stream_download_to_writer(&self.store, &object_path, writer)
+            .await
+            .with_context(|| format!("error downloading from GCS: {}", uri))
+    }
+
+    #[instrument(skip_all, fields(uri = %uri), level = "trace")]
+    async fn upload_from_reader(
+        &self,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+        uri: &str,
+    ) -> Result<u64> {
+        check_file_uri(uri)?;
+        let (_, key) = parse_gs_url(uri)?;
+        self.resumable_upload(reader, key)
+            .await
+            .with_context(|| format!("error uploading to GCS: {}", uri))
+    }
+
+    #[instrument(skip_all, fields(uri = %uri), level = "trace")]
+    async fn delete(&self, uri: &str) -> Result<()> {
+        check_file_uri(uri)?;
+        let (_, key) = parse_gs_url(uri)?;
+        delete_object(&self.store, &ObjectPath::from(key))
+            .await
+            .with_context(|| format!("error deleting from GCS: {}", uri))
+    }
 }
 
 #[test]
@@ -252,3 +668,61 @@ fn url_parsing() {
     );
     assert!(parse_gs_url("s3://foo/").is_err());
 }
+
+#[test]
+fn resumable_upload_headers() {
+    assert_eq!(content_range(0, 10, None), "bytes 0-9/*");
+    assert_eq!(content_range(10, 5, Some(15)), "bytes 10-14/15");
+    assert_eq!(content_range(15, 0, Some(15)), "bytes */15");
+    assert_eq!(content_range(0, 0, None), "bytes */*");
+    assert_eq!(persisted_bytes(None).unwrap(), 0);
+    assert_eq!(persisted_bytes(Some("bytes=0-262143")).unwrap(), 262_144);
+    assert!(persisted_bytes(Some("bytes=5-10")).is_err());
+    assert_eq!(CHUNK_SIZE % (256 * 1024), 0);
+}
+
+#[tokio::test]
+async fn fill_chunk_reads_whole_chunks() {
+    let data = vec![7u8; CHUNK_SIZE + 10];
+    let mut reader: &[u8] = &data;
+    let mut buf = vec![];
+    fill_chunk(&mut reader, &mut buf).await.unwrap();
+    assert_eq!(buf.len(), CHUNK_SIZE);
+    fill_chunk(&mut reader, &mut buf).await.unwrap();
+    assert_eq!(buf.len(), 10);
+    fill_chunk(&mut reader, &mut buf).await.unwrap();
+    assert!(buf.is_empty());
+}
+
+#[test]
+fn resumable_upload_applies_options() {
+    let url = resumable_upload_url("bucket", "out/a b", &UploadOptions::default());
+    assert_eq!(
+        url.as_str(),
+        "https://storage.googleapis.com/upload/storage/v1/b/bucket/o?uploadType=resumable&name=out%2Fa+b"
+    );
+    assert!(resumable_upload_metadata(&UploadOptions::default()).is_none());
+
+    let options = UploadOptions {
+        kms_key: Some("projects/p/locations/l/keyRings/r/cryptoKeys/k".to_owned()),
+        storage_class: Some("NEARLINE".to_owned()),
+    };
+    let url = resumable_upload_url("bucket", "out", &options);
+    assert!(url.query_pairs().any(|(k, v)| k == "kmsKeyName"
+        && v == "projects/p/locations/l/keyRings/r/cryptoKeys/k"));
+    assert_eq!(
+        resumable_upload_metadata(&options).unwrap(),
+        serde_json::json!({ "storageClass": "NEARLINE" })
+    );
+}
+
+#[test]
+fn requester_pays_sets_user_project() {
+    assert!(default_headers(None).unwrap().is_empty());
+    let headers = default_headers(Some(&RequesterPays {
+        billing_project: Some("my-project".to_owned()),
+    }))
+    .unwrap();
+    assert_eq!(headers["x-goog-user-project"], "my-project");
+    assert!(default_headers(Some(&RequesterPays::default())).is_err());
+}
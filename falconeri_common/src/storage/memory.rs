@@ -0,0 +1,312 @@
+//! An in-memory storage backend, for unit tests.
+//!
+//! This is only available with the `memory-storage` feature, which registers
+//! it for `mem://` URIs. All `mem://` URIs in a process share the same
+//! objects, so a test can write input files using one
+//! `Box<dyn CloudStorage>` and read them back using another.
+
+use std::{
+    fs,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use walkdir::WalkDir;
+
+use super::{check_file_uri, CloudStorage, ListedObject, StorageBackend};
+use crate::{prelude::*, secret::Secret};
+
+lazy_static! {
+    /// The objects shared by all `mem://` URIs.
+    static ref SHARED: MemoryStorage = MemoryStorage::new();
+}
+
+/// The generation of the last object we stored, in any `MemoryStorage`.
+static LAST_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// An object stored in memory.
+#[derive(Debug)]
+struct MemoryObject {
+    /// The contents of the object.
+    data: Vec<u8>,
+    /// Increases every time an object is written.
+    generation: u64,
+}
+
+/// Storage which keeps a `HashMap` from URIs to bytes.
+///
+/// Clones share the same objects. URIs of any scheme may be used, so a
+/// `MemoryStorage` can also stand in for a real backend when testing code
+/// which takes a `Box<dyn CloudStorage>`.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryStorage {
+    objects: Arc<Mutex<HashMap<String, MemoryObject>>>,
+}
+
+impl MemoryStorage {
+    /// Create a new, empty `MemoryStorage`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The storage used for `mem://` URIs.
+    pub fn shared() -> Self {
+        SHARED.clone()
+    }
+
+    /// Store `data` at `uri`, replacing any existing object.
+    pub fn insert<D: Into<Vec<u8>>>(&self, uri: &str, data: D) {
+        let object = MemoryObject {
+            data: data.into(),
+            generation: LAST_GENERATION.fetch_add(1, Ordering::SeqCst) + 1,
+        };
+        self.lock().insert(uri.to_owned(), object);
+    }
+
+    /// Get a copy of the object at `uri`, if it exists.
+    pub fn get(&self, uri: &str) -> Option<Vec<u8>> {
+        self.lock().get(uri).map(|object| object.data.clone())
+    }
+
+    /// Delete the object at `uri`, returning true if it existed.
+    pub fn remove(&self, uri: &str) -> bool {
+        self.lock().remove(uri).is_some()
+    }
+
+    /// The URIs of all our objects, in sorted order.
+    pub fn uris(&self) -> Vec<String> {
+        let mut uris = self.lock().keys().cloned().collect::<Vec<_>>();
+        uris.sort();
+        uris
+    }
+
+    /// Lock our objects.
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, MemoryObject>> {
+        self.objects.lock().expect("memory storage lock poisoned")
+    }
+
+    /// Get a copy of the file at `uri`.
+    fn get_file(&self, uri: &str) -> Result<Vec<u8>> {
+        check_file_uri(uri)?;
+        self.get(uri)
+            .ok_or_else(|| format_err!("cannot find object {}", uri))
+    }
+
+    /// Find all the objects under `dir_uri`, which must end with `/`, with
+    /// their paths relative to `dir_uri`.
+    fn objects_under(&self, dir_uri: &str) -> Vec<(String, Vec<u8>)> {
+        let mut objects = self
+            .lock()
+            .iter()
+            .filter_map(|(uri, object)| {
+                let rel_path = uri.strip_prefix(dir_uri)?;
+                Some((rel_path.to_owned(), object.data.clone()))
+            })
+            .filter(|(rel_path, _)| !rel_path.is_empty())
+            .collect::<Vec<_>>();
+        objects.sort();
+        objects
+    }
+}
+
+/// Calculate an etag for `data`.
+fn etag(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[async_trait]
+impl CloudStorage for MemoryStorage {
+    #[instrument(skip_all, fields(uri = %uri), level = "trace")]
+    async fn list_objects(&self, uri: &str) -> Result<Vec<ListedObject>> {
+        let objects = self.lock();
+        let listed = |uri: &str, object: &MemoryObject| ListedObject {
+            uri: uri.to_owned(),
+            etag: Some(etag(&object.data)),
+            generation: Some(object.generation.to_string()),
+            size: Some(cast::u64(object.data.len())),
+            last_modified: None,
+        };
+
+        // If `uri` is a file, just return it.
+        if let Some(object) = objects.get(uri) {
+            return Ok(vec![listed(uri, object)]);
+        }
+
+        let mut prefix = uri.to_owned();
+        if !prefix.ends_with('/') {
+            prefix.push('/');
+        }
+        let mut results = objects
+            .iter()
+            .filter(|(object_uri, _)| object_uri.starts_with(&prefix))
+            .map(|(object_uri, object)| listed(object_uri, object))
+            .collect::<Vec<_>>();
+        results.sort_by(|a, b| a.uri.cmp(&b.uri));
+        Ok(results)
+    }
+
+    #[instrument(skip_all, fields(uri = %uri, local_path = %local_path.display()), level = "trace")]
+    async fn sync_down(&self, uri: &str, local_path: &Path) -> Result<()> {
+        trace!("downloading {} to {}", uri, local_path.display());
+        let files = if uri.ends_with('/') {
+            self.objects_under(uri)
+                .into_iter()
+                .map(|(rel_path, data)| (local_path.join(rel_path), data))
+                .collect::<Vec<_>>()
+        } else {
+            vec![(local_path.to_owned(), self.get_file(uri)?)]
+        };
+        for (path, data) in files {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)
+                    .with_context(|| format!("cannot create {}", parent.display()))?;
+            }
+            fs::write(&path, data)
+                .with_context(|| format!("cannot write {}", path.display()))?;
+        }
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(local_path = %local_path.display(), uri = %uri), level = "trace")]
+    async fn sync_up(&self, local_path: &Path, uri: &str) -> Result<()> {
+        trace!("uploading {} to {}", local_path.display(), uri);
+        let base_uri = uri.trim_end_matches('/');
+        for entry in WalkDir::new(local_path) {
+            let entry = entry
+                .with_context(|| format!("error listing {}", local_path.display()))?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let rel_path = entry
+                .path()
+                .strip_prefix(local_path)
+                .context("failed to compute relative path")?;
+            let data = fs::read(entry.path())
+                .with_context(|| format!("cannot read {}", entry.path().display()))?;
+            self.insert(
+                &format!("{}/{}", base_uri, rel_path.to_string_lossy()),
+                data,
+            );
+        }
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(uri = %uri), level = "trace")]
+    async fn download_to_writer(
+        &self,
+        uri: &str,
+        writer: &mut (dyn AsyncWrite + Send + Unpin),
+    ) -> Result<u64> {
+        let data = self.get_file(uri)?;
+        writer.write_all(&data).await?;
+        writer.flush().await?;
+        Ok(cast::u64(data.len()))
+    }
+
+    #[instrument(skip_all, fields(uri = %uri), level = "trace")]
+    async fn upload_from_reader(
+        &self,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+        uri: &str,
+    ) -> Result<u64> {
+        check_file_uri(uri)?;
+        let mut data = vec![];
+        reader
+            .read_to_end(&mut data)
+            .await
+            .with_context(|| format!("error reading data for {}", uri))?;
+        let bytes = cast::u64(data.len());
+        self.insert(uri, data);
+        Ok(bytes)
+    }
+
+    #[instrument(skip_all, fields(uri = %uri), level = "trace")]
+    async fn delete(&self, uri: &str) -> Result<()> {
+        check_file_uri(uri)?;
+        self.remove(uri);
+        Ok(())
+    }
+}
+
+/// Our backend for `mem://` URIs.
+pub(crate) struct MemoryBackend;
+
+#[async_trait]
+impl StorageBackend for MemoryBackend {
+    async fn open(
+        &self,
+        _bucket_uri: &str,
+        _secrets: &[Secret],
+    ) -> Result<Box<dyn CloudStorage>> {
+        Ok(Box::new(MemoryStorage::shared()))
+    }
+}
+
+#[tokio::test]
+async fn memory_storage_round_trip() {
+    let storage = MemoryStorage::new();
+    storage.insert("gs://bucket/in/a.txt", "a");
+    storage.insert("gs://bucket/in/sub/b.txt", "b");
+    storage.insert("gs://bucket/other.txt", "other");
+
+    assert_eq!(
+        storage.list("gs://bucket/in/").await.unwrap(),
+        vec!["gs://bucket/in/a.txt", "gs://bucket/in/sub/b.txt"],
+    );
+    let objects = storage.list_objects("gs://bucket/in/a.txt").await.unwrap();
+    assert_eq!(objects.len(), 1);
+    assert_eq!(objects[0].size, Some(1));
+    let generation = objects[0].generation.clone();
+    storage.insert("gs://bucket/in/a.txt", "A");
+    let objects = storage.list_objects("gs://bucket/in/a.txt").await.unwrap();
+    assert_ne!(objects[0].generation, generation);
+
+    let dir = crate::temp_dir::TempDir::new("falconeri-test").unwrap();
+    storage
+        .sync_down("gs://bucket/in/", &dir.join("in/"))
+        .await
+        .unwrap();
+    assert_eq!(fs::read_to_string(dir.join("in/sub/b.txt")).unwrap(), "b");
+    storage
+        .sync_up(&dir.join("in"), "gs://bucket/copy/")
+        .await
+        .unwrap();
+    assert_eq!(storage.get("gs://bucket/copy/a.txt").unwrap(), b"A");
+
+    let mut copied = vec![];
+    storage
+        .download_to_writer("gs://bucket/other.txt", &mut copied)
+        .await
+        .unwrap();
+    assert_eq!(copied, b"other");
+    assert!(storage
+        .download_to_writer("gs://bucket/missing", &mut copied)
+        .await
+        .is_err());
+
+    storage.delete("gs://bucket/other.txt").await.unwrap();
+    assert!(storage.get("gs://bucket/other.txt").is_none());
+    storage.delete("gs://bucket/other.txt").await.unwrap();
+    assert!(storage.delete("gs://bucket/in/").await.is_err());
+}
+
+#[tokio::test]
+async fn mem_uris_share_objects() {
+    let uri = format!("mem://bucket-{}/a.txt", Uuid::new_v4());
+    let storage = <dyn CloudStorage>::for_uri(&uri, &[]).await.unwrap();
+    storage
+        .upload_from_reader(&mut &b"shared"[..], &uri)
+        .await
+        .unwrap();
+    assert_eq!(MemoryStorage::shared().get(&uri).unwrap(), b"shared");
+    assert!(MemoryStorage::shared().remove(&uri));
+}
@@ -4,13 +4,64 @@ use std::sync::Arc;
 
 use async_trait::async_trait;
 use futures::TryStreamExt;
-use object_store::{path::Path as ObjectPath, ObjectStore, ObjectStoreExt};
-use tokio::{fs as async_fs, io::AsyncWriteExt};
+use object_store::{
+    path::Path as ObjectPath, Attributes, ObjectStore, ObjectStoreExt,
+    PutMultipartOptions, PutOptions,
+};
+use tokio::{
+    fs as async_fs,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+};
 
 use crate::{prelude::*, secret::Secret};
 
+#[cfg(feature = "testing")]
+pub mod dir;
 pub mod gs;
+#[cfg(feature = "memory-storage")]
+pub mod memory;
+mod registry;
 pub mod s3;
+pub mod sftp;
+
+pub use self::registry::{register_backend, registered_schemes, StorageBackend};
+
+/// Environment variable mapping the URI prefixes of requester-pays inputs to
+/// their [`RequesterPays`] settings, as a JSON object. `falconerid` sets this
+/// for workers.
+pub const REQUESTER_PAYS_VAR: &str = "FALCONERI_REQUESTER_PAYS";
+
+/// How to pay for requests to a [requester-pays][gcs] bucket, where we, not
+/// the bucket's owner, pay for access.
+///
+/// [gcs]: https://cloud.google.com/storage/docs/requester-pays
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+pub struct RequesterPays {
+    /// The project to bill for requests. Required by GCS, and ignored by S3,
+    /// which bills the account that owns our credentials.
+    #[serde(default)]
+    pub billing_project: Option<String>,
+}
+
+/// Look up the requester-pays settings for `uri` in the value of
+/// [`REQUESTER_PAYS_VAR`], which maps URI prefixes to settings. Uses the
+/// longest matching prefix.
+pub fn requester_pays_for_uri(
+    requester_pays_json: Option<&str>,
+    uri: &str,
+) -> Result<Option<RequesterPays>> {
+    let Some(requester_pays_json) = requester_pays_json else {
+        return Ok(None);
+    };
+    let prefixes =
+        serde_json::from_str::<HashMap<String, RequesterPays>>(requester_pays_json)
+            .with_context(|| format!("could not parse {}", REQUESTER_PAYS_VAR))?;
+    Ok(prefixes
+        .into_iter()
+        .filter(|(prefix, _)| uri.starts_with(prefix.as_str()))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, requester_pays)| requester_pays))
+}
 
 /// Stream a download from the object store to a local file.
 ///
@@ -21,31 +72,106 @@ pub(crate) async fn stream_download_to_file(
     object_path: &ObjectPath,
     local_path: &Path,
 ) -> Result<()> {
+    let mut file = async_fs::File::create(local_path).await.with_context(|| {
+        format!("cannot create local file: {}", local_path.display())
+    })?;
+    stream_download_to_writer(store, object_path, &mut file)
+        .await
+        .with_context(|| format!("error writing to file: {}", local_path.display()))?;
+    Ok(())
+}
+
+/// Stream a download from the object store to `writer`, returning the number
+/// of bytes written.
+pub(crate) async fn stream_download_to_writer<W>(
+    store: &Arc<dyn ObjectStore>,
+    object_path: &ObjectPath,
+    writer: &mut W,
+) -> Result<u64>
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
     let get_result = store
         .get(object_path)
         .await
         .with_context(|| format!("error fetching object: {}", object_path))?;
 
     let mut stream = get_result.into_stream();
-    let mut file = async_fs::File::create(local_path).await.with_context(|| {
-        format!("cannot create local file: {}", local_path.display())
-    })?;
-
+    let mut bytes = 0;
     while let Some(chunk) = stream
         .try_next()
         .await
         .with_context(|| format!("error streaming object: {}", object_path))?
     {
-        file.write_all(&chunk).await.with_context(|| {
-            format!("error writing to file: {}", local_path.display())
-        })?;
+        writer.write_all(&chunk).await?;
+        bytes += cast::u64(chunk.len());
     }
+    writer.flush().await?;
 
-    file.flush()
-        .await
-        .with_context(|| format!("error flushing file: {}", local_path.display()))?;
+    Ok(bytes)
+}
 
-    Ok(())
+/// Delete an object from the object store. Succeeds if the object doesn't
+/// exist.
+pub(crate) async fn delete_object(
+    store: &Arc<dyn ObjectStore>,
+    object_path: &ObjectPath,
+) -> Result<()> {
+    match store.delete(object_path).await {
+        Ok(()) | Err(object_store::Error::NotFound { .. }) => Ok(()),
+        Err(err) => {
+            Err(err).with_context(|| format!("error deleting object: {}", object_path))
+        }
+    }
+}
+
+/// Environment variable naming the KMS key used to encrypt uploads.
+const EGRESS_KMS_KEY_VAR: &str = "FALCONERI_EGRESS_KMS_KEY";
+
+/// Environment variable naming the storage class of uploaded objects.
+const EGRESS_STORAGE_CLASS_VAR: &str = "FALCONERI_EGRESS_STORAGE_CLASS";
+
+/// How to store the objects we upload. `falconerid` sets these for workers
+/// using the `egress` section of the pipeline spec.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct UploadOptions {
+    /// The KMS key used to encrypt new objects. For S3, this is a key ID or
+    /// ARN. For GCS, this is the full resource name of the key.
+    pub kms_key: Option<String>,
+    /// The storage class of new objects, such as `STANDARD_IA` or `NEARLINE`.
+    pub storage_class: Option<String>,
+}
+
+impl UploadOptions {
+    /// Read our upload options from `EGRESS_KMS_KEY_VAR` and
+    /// `EGRESS_STORAGE_CLASS_VAR`.
+    pub(crate) fn from_env() -> Self {
+        let var = |name| std::env::var(name).ok().filter(|value| !value.is_empty());
+        UploadOptions {
+            kms_key: var(EGRESS_KMS_KEY_VAR),
+            storage_class: var(EGRESS_STORAGE_CLASS_VAR),
+        }
+    }
+}
+
+/// How to split uploads into parts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct MultipartOptions {
+    /// The size of each part. Objects smaller than this are uploaded using a
+    /// single request.
+    pub part_size: usize,
+    /// The maximum number of parts to upload at once. We buffer up to this
+    /// many parts in memory, plus the one we're reading.
+    pub max_concurrency: usize,
+}
+
+impl Default for MultipartOptions {
+    fn default() -> Self {
+        MultipartOptions {
+            part_size: 8 * 1024 * 1024,
+            max_concurrency: 4,
+        }
+    }
 }
 
 /// Stream an upload from a local file to the object store.
@@ -56,39 +182,126 @@ pub(crate) async fn stream_upload_from_file(
     store: &Arc<dyn ObjectStore>,
     local_path: &Path,
     object_path: &ObjectPath,
+    options: &MultipartOptions,
+    attributes: &Attributes,
 ) -> Result<()> {
     let file = async_fs::File::open(local_path).await.with_context(|| {
         format!("cannot open local file: {}", local_path.display())
     })?;
-
-    let upload = store.put_multipart(object_path).await.with_context(|| {
-        format!("error starting multipart upload: {}", object_path)
-    })?;
-
-    let mut write = object_store::WriteMultipart::new(upload);
-
     let mut reader = tokio::io::BufReader::with_capacity(8 * 1024 * 1024, file);
-    let mut buf = vec![0u8; 8 * 1024 * 1024];
+    stream_upload_from_reader(store, &mut reader, object_path, options, attributes)
+        .await
+        .with_context(|| format!("error reading file: {}", local_path.display()))?;
+    Ok(())
+}
 
-    loop {
-        let n = tokio::io::AsyncReadExt::read(&mut reader, &mut buf)
+/// Stream an upload from `reader` to the object store, returning the number of
+/// bytes uploaded.
+///
+/// If the input fits in a single part, we upload it with one request.
+/// Otherwise, we use a multipart upload, uploading up to
+/// `options.max_concurrency` parts at once. If anything goes wrong, we abort
+/// the multipart upload, so that the store doesn't keep our parts around.
+/// New objects are created with the specified `attributes`.
+pub(crate) async fn stream_upload_from_reader<R>(
+    store: &Arc<dyn ObjectStore>,
+    reader: &mut R,
+    object_path: &ObjectPath,
+    options: &MultipartOptions,
+    attributes: &Attributes,
+) -> Result<u64>
+where
+    R: AsyncRead + Unpin + ?Sized,
+{
+    // Read our first part. If that's everything, we don't need a multipart
+    // upload.
+    let mut buf = Vec::with_capacity(options.part_size);
+    (&mut *reader)
+        .take(cast::u64(options.part_size))
+        .read_to_end(&mut buf)
+        .await?;
+    if buf.len() < options.part_size {
+        let bytes = cast::u64(buf.len());
+        let put_options = PutOptions {
+            attributes: attributes.clone(),
+            ..PutOptions::default()
+        };
+        store
+            .put_opts(object_path, buf.into(), put_options)
             .await
-            .with_context(|| {
-                format!("error reading file: {}", local_path.display())
-            })?;
+            .with_context(|| format!("error uploading: {}", object_path))?;
+        return Ok(bytes);
+    }
 
-        if n == 0 {
-            break;
-        }
+    let multipart_options = PutMultipartOptions {
+        attributes: attributes.clone(),
+        ..PutMultipartOptions::default()
+    };
+    let upload = store
+        .put_multipart_opts(object_path, multipart_options)
+        .await
+        .with_context(|| {
+            format!("error starting multipart upload: {}", object_path)
+        })?;
+    let mut write =
+        object_store::WriteMultipart::new_with_chunk_size(upload, options.part_size);
+    let mut bytes = cast::u64(buf.len());
+    write.write(&buf);
 
-        write.write(&buf[..n]);
+    let result = async {
+        let mut buf = vec![0u8; 1024 * 1024];
+        loop {
+            // Don't read faster than we can upload.
+            write
+                .wait_for_capacity(options.max_concurrency)
+                .await
+                .with_context(|| format!("error uploading part: {}", object_path))?;
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                // Wait for our parts to finish, so we can abort if they fail.
+                return write.wait_for_capacity(0).await.with_context(|| {
+                    format!("error uploading part: {}", object_path)
+                });
+            }
+            write.write(&buf[..n]);
+            bytes += cast::u64(n);
+        }
+    }
+    .await;
+    if let Err(err) = result {
+        if let Err(abort_err) = write.abort().await {
+            warn!(
+                "could not abort multipart upload of {}: {}",
+                object_path, abort_err
+            );
+        }
+        return Err(err);
     }
 
+    // `finish` uploads our last part, and aborts the upload itself if it
+    // can't complete it.
     write.finish().await.with_context(|| {
         format!("error completing multipart upload: {}", object_path)
     })?;
 
-    Ok(())
+    Ok(bytes)
+}
+
+/// An object returned by [`CloudStorage::list_objects`].
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ListedObject {
+    /// The URI of the object.
+    pub uri: String,
+    /// The entity tag of the object, if the backend provides one.
+    pub etag: Option<String>,
+    /// The generation or version ID of the object, if the backend provides
+    /// one.
+    pub generation: Option<String>,
+    /// The size of the object in bytes, if the backend provides one. This is
+    /// `None` for directories.
+    pub size: Option<u64>,
+    /// When the object was last modified, if the backend provides it.
+    pub last_modified: Option<NaiveDateTime>,
 }
 
 /// Abstract interface to different kinds of cloud storage backends.
@@ -96,7 +309,18 @@ pub(crate) async fn stream_upload_from_file(
 pub trait CloudStorage: Send + Sync {
     /// List all the files and subdirectories immediately present in `uri` if
     /// `uri` is a directory, or just return `uri` if it points to a file.
-    async fn list(&self, uri: &str) -> Result<Vec<String>>;
+    async fn list(&self, uri: &str) -> Result<Vec<String>> {
+        Ok(self
+            .list_objects(uri)
+            .await?
+            .into_iter()
+            .map(|object| object.uri)
+            .collect())
+    }
+
+    /// Like [`CloudStorage::list`], but also return version information for
+    /// each object, so that callers can tell when an object is replaced.
+    async fn list_objects(&self, uri: &str) -> Result<Vec<ListedObject>>;
 
     /// Synchronize `uri` down to `local_path` recursively. Does not delete any
     /// existing destination files. The contents of `uri` should be exactly
@@ -114,6 +338,35 @@ pub trait CloudStorage: Send + Sync {
     /// exactly represented in `uri`, without the trailing subdirectory name
     /// being inserted—this is a straight directory-to-directory sync.
     async fn sync_up(&self, local_path: &Path, uri: &str) -> Result<()>;
+
+    /// Stream the contents of the file at `uri` to `writer`, returning the
+    /// number of bytes copied. `uri` must not end in `/`.
+    async fn download_to_writer(
+        &self,
+        uri: &str,
+        writer: &mut (dyn AsyncWrite + Send + Unpin),
+    ) -> Result<u64>;
+
+    /// Stream the contents of `reader` to a new file at `uri`, returning the
+    /// number of bytes copied. `uri` must not end in `/`.
+    async fn upload_from_reader(
+        &self,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+        uri: &str,
+    ) -> Result<u64>;
+
+    /// Delete the file at `uri`. Succeeds if there is no such file. `uri`
+    /// must not end in `/`.
+    async fn delete(&self, uri: &str) -> Result<()>;
+}
+
+/// Make sure that `uri` refers to a file, not a directory.
+pub(crate) fn check_file_uri(uri: &str) -> Result<()> {
+    if uri.ends_with('/') {
+        Err(format_err!("{:?} is a directory, not a file", uri))
+    } else {
+        Ok(())
+    }
 }
 
 impl dyn CloudStorage {
@@ -121,7 +374,8 @@ impl dyn CloudStorage {
     ///
     /// The `bucket_uri` is used to determine both the storage backend type
     /// (based on the URI scheme like `gs://` or `s3://`) and the bucket name.
-    /// It can be any URI within the bucket we want to access.
+    /// It can be any URI within the bucket we want to access. Backends for
+    /// other schemes can be added using [`register_backend`].
     ///
     /// If we know about any secrets, we can pass them as the `secrets` array,
     /// and the storage driver can check to see if there are any secrets it can
@@ -130,17 +384,61 @@ impl dyn CloudStorage {
         bucket_uri: &str,
         secrets: &[Secret],
     ) -> Result<Box<dyn CloudStorage>> {
-        if bucket_uri.starts_with("gs://") {
-            Ok(Box::new(
-                gs::GoogleCloudStorage::new(secrets, bucket_uri).await?,
-            ))
-        } else if bucket_uri.starts_with("s3://") {
-            Ok(Box::new(s3::S3Storage::new(secrets, bucket_uri).await?))
-        } else {
-            Err(format_err!(
-                "cannot find storage backend for {}",
-                bucket_uri
-            ))
+        registry::backend_for_uri(bucket_uri)?
+            .open(bucket_uri, secrets)
+            .await
+    }
+
+    /// Like [`CloudStorage::for_uri`], but if `requester_pays` is specified,
+    /// we pay for requests to the bucket ourselves.
+    pub async fn for_input_uri(
+        bucket_uri: &str,
+        secrets: &[Secret],
+        requester_pays: Option<&RequesterPays>,
+    ) -> Result<Box<dyn CloudStorage>> {
+        let backend = registry::backend_for_uri(bucket_uri)?;
+        match requester_pays {
+            Some(requester_pays) => {
+                backend
+                    .open_requester_pays(bucket_uri, secrets, requester_pays)
+                    .await
+            }
+            None => backend.open(bucket_uri, secrets).await,
         }
     }
+
+    /// Get the storage backend for an input file at `uri` on a worker, using
+    /// the requester-pays settings in [`REQUESTER_PAYS_VAR`].
+    pub async fn for_worker_input_uri(uri: &str) -> Result<Box<dyn CloudStorage>> {
+        let requester_pays_json = std::env::var(REQUESTER_PAYS_VAR).ok();
+        let requester_pays =
+            requester_pays_for_uri(requester_pays_json.as_deref(), uri)?;
+        // We don't pass in any `secrets` here, because those are supposed to
+        // be specified in our Kubernetes job when it's created.
+        Self::for_input_uri(uri, &[], requester_pays.as_ref()).await
+    }
+}
+
+#[test]
+fn requester_pays_uses_longest_prefix() {
+    let json = r#"{
+        "gs://public/": {},
+        "gs://public/genomes/": { "billing_project": "research" }
+    }"#;
+    assert_eq!(requester_pays_for_uri(None, "gs://public/a").unwrap(), None);
+    assert_eq!(
+        requester_pays_for_uri(Some(json), "gs://other/a").unwrap(),
+        None
+    );
+    assert_eq!(
+        requester_pays_for_uri(Some(json), "gs://public/a").unwrap(),
+        Some(RequesterPays::default())
+    );
+    assert_eq!(
+        requester_pays_for_uri(Some(json), "gs://public/genomes/1.vcf").unwrap(),
+        Some(RequesterPays {
+            billing_project: Some("research".to_owned()),
+        })
+    );
+    assert!(requester_pays_for_uri(Some("[]"), "gs://public/a").is_err());
 }
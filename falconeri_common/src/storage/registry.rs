@@ -0,0 +1,223 @@
+//! A registry of storage backends, indexed by URI scheme.
+//!
+//! We register `gs://`, `s3://` and `sftp://` ourselves, plus `mem://` if the
+//! `memory-storage` feature is enabled. Programs which link against
+//! `falconeri_common` can add their own backends by calling
+//! [`register_backend`] before they touch any storage.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, RwLock},
+};
+
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+
+use super::{gs, s3, sftp, CloudStorage, RequesterPays};
+use crate::{prelude::*, secret::Secret};
+
+/// Knows how to create a [`CloudStorage`] for URIs with a particular scheme.
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    /// Create a storage client for the bucket containing `bucket_uri`. See
+    /// [`CloudStorage::for_uri`] for how `secrets` are used.
+    async fn open(
+        &self,
+        bucket_uri: &str,
+        secrets: &[Secret],
+    ) -> Result<Box<dyn CloudStorage>>;
+
+    /// Like [`StorageBackend::open`], but for a bucket where we pay for our
+    /// own requests. By default, backends don't support this.
+    async fn open_requester_pays(
+        &self,
+        bucket_uri: &str,
+        _secrets: &[Secret],
+        _requester_pays: &RequesterPays,
+    ) -> Result<Box<dyn CloudStorage>> {
+        Err(format_err!(
+            "cannot access {} as a requester-pays bucket",
+            bucket_uri
+        ))
+    }
+}
+
+/// Our built-in Google Cloud Storage backend.
+struct GoogleCloudStorageBackend;
+
+#[async_trait]
+impl StorageBackend for GoogleCloudStorageBackend {
+    async fn open(
+        &self,
+        bucket_uri: &str,
+        secrets: &[Secret],
+    ) -> Result<Box<dyn CloudStorage>> {
+        Ok(Box::new(
+            gs::GoogleCloudStorage::new(secrets, bucket_uri).await?,
+        ))
+    }
+
+    async fn open_requester_pays(
+        &self,
+        bucket_uri: &str,
+        secrets: &[Secret],
+        requester_pays: &RequesterPays,
+    ) -> Result<Box<dyn CloudStorage>> {
+        Ok(Box::new(
+            gs::GoogleCloudStorage::new_requester_pays(
+                secrets,
+                bucket_uri,
+                requester_pays,
+            )
+            .await?,
+        ))
+    }
+}
+
+/// Our built-in S3 backend.
+struct S3Backend;
+
+#[async_trait]
+impl StorageBackend for S3Backend {
+    async fn open(
+        &self,
+        bucket_uri: &str,
+        secrets: &[Secret],
+    ) -> Result<Box<dyn CloudStorage>> {
+        Ok(Box::new(s3::S3Storage::new(secrets, bucket_uri).await?))
+    }
+
+    async fn open_requester_pays(
+        &self,
+        bucket_uri: &str,
+        secrets: &[Secret],
+        requester_pays: &RequesterPays,
+    ) -> Result<Box<dyn CloudStorage>> {
+        Ok(Box::new(
+            s3::S3Storage::new_requester_pays(secrets, bucket_uri, requester_pays)
+                .await?,
+        ))
+    }
+}
+
+/// Our built-in SFTP backend.
+struct SftpBackend;
+
+#[async_trait]
+impl StorageBackend for SftpBackend {
+    async fn open(
+        &self,
+        bucket_uri: &str,
+        secrets: &[Secret],
+    ) -> Result<Box<dyn CloudStorage>> {
+        Ok(Box::new(sftp::SftpStorage::new(secrets, bucket_uri).await?))
+    }
+}
+
+lazy_static! {
+    /// Our registered backends, by scheme.
+    static ref BACKENDS: RwLock<BTreeMap<String, Arc<dyn StorageBackend>>> = {
+        let mut backends = BTreeMap::<String, Arc<dyn StorageBackend>>::new();
+        backends.insert("gs".to_owned(), Arc::new(GoogleCloudStorageBackend));
+        backends.insert("s3".to_owned(), Arc::new(S3Backend));
+        backends.insert("sftp".to_owned(), Arc::new(SftpBackend));
+        #[cfg(feature = "memory-storage")]
+        backends.insert("mem".to_owned(), Arc::new(super::memory::MemoryBackend));
+        RwLock::new(backends)
+    };
+}
+
+/// Register `backend` to handle URIs beginning with `scheme://`, replacing
+/// any existing backend for `scheme` (including our built-in ones).
+pub fn register_backend<B>(scheme: &str, backend: B) -> Result<()>
+where
+    B: StorageBackend + 'static,
+{
+    if scheme.is_empty()
+        || !scheme
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.')
+    {
+        return Err(format_err!("invalid storage URI scheme {:?}", scheme));
+    }
+    BACKENDS
+        .write()
+        .expect("storage backend lock poisoned")
+        .insert(scheme.to_ascii_lowercase(), Arc::new(backend));
+    Ok(())
+}
+
+/// The URI schemes for which we have storage backends, in sorted order.
+pub fn registered_schemes() -> Vec<String> {
+    BACKENDS
+        .read()
+        .expect("storage backend lock poisoned")
+        .keys()
+        .cloned()
+        .collect()
+}
+
+/// Find the backend for `uri`.
+pub(crate) fn backend_for_uri(uri: &str) -> Result<Arc<dyn StorageBackend>> {
+    let backends = BACKENDS.read().expect("storage backend lock poisoned");
+    uri_scheme(uri)
+        .and_then(|scheme| backends.get(&scheme.to_ascii_lowercase()))
+        .cloned()
+        .ok_or_else(|| {
+            let schemes = backends
+                .keys()
+                .map(|s| format!("{}://", s))
+                .collect::<Vec<_>>();
+            format_err!(
+                "cannot find storage backend for {} (supported: {})",
+                uri,
+                schemes.join(", ")
+            )
+        })
+}
+
+/// Get the scheme of `uri`, without the trailing `://`.
+fn uri_scheme(uri: &str) -> Option<&str> {
+    uri.split_once("://").map(|(scheme, _)| scheme)
+}
+
+#[tokio::test]
+async fn registered_backends_are_used() {
+    /// A backend which refuses to open anything, so we can tell it was used.
+    struct RefusingBackend;
+
+    #[async_trait]
+    impl StorageBackend for RefusingBackend {
+        async fn open(
+            &self,
+            bucket_uri: &str,
+            _secrets: &[Secret],
+        ) -> Result<Box<dyn CloudStorage>> {
+            Err(format_err!("refusing to open {}", bucket_uri))
+        }
+    }
+
+    assert_eq!(uri_scheme("gs://bucket/path"), Some("gs"));
+    assert_eq!(uri_scheme("/local/path"), None);
+    assert!(register_backend("", RefusingBackend).is_err());
+    assert!(register_backend("bad/scheme", RefusingBackend).is_err());
+
+    let err = <dyn CloudStorage>::for_uri("inhouse://bucket/x", &[])
+        .await
+        .err()
+        .unwrap();
+    let supported = if cfg!(feature = "memory-storage") {
+        "gs://, mem://, s3://, sftp://"
+    } else {
+        "gs://, s3://, sftp://"
+    };
+    assert!(err.to_string().contains(supported), "{}", err);
+
+    register_backend("inhouse", RefusingBackend).unwrap();
+    assert!(registered_schemes().contains(&"inhouse".to_owned()));
+    let err = <dyn CloudStorage>::for_uri("inhouse://bucket/x", &[])
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(err.to_string(), "refusing to open inhouse://bucket/x");
+}
@@ -5,12 +5,21 @@ use std::sync::Arc;
 use async_trait::async_trait;
 use futures::TryStreamExt;
 use lazy_static::lazy_static;
-use object_store::{aws::AmazonS3Builder, path::Path as ObjectPath, ObjectStore};
+use object_store::{
+    aws::AmazonS3Builder, path::Path as ObjectPath, Attribute, Attributes, ObjectStore,
+};
 use regex::Regex;
-use tokio::fs as async_fs;
+use tokio::{
+    fs as async_fs,
+    io::{AsyncRead, AsyncWrite},
+};
 use walkdir::WalkDir;
 
-use super::{stream_download_to_file, stream_upload_from_file, CloudStorage};
+use super::{
+    check_file_uri, delete_object, stream_download_to_file, stream_download_to_writer,
+    stream_upload_from_file, stream_upload_from_reader, CloudStorage, ListedObject,
+    MultipartOptions, RequesterPays, UploadOptions,
+};
 use crate::{
     kubernetes::{
         base64_encoded_optional_secret_string, base64_encoded_secret_string,
@@ -58,10 +67,72 @@ fn parse_s3_url(url: &str) -> Result<(&str, &str)> {
     Ok((bucket, key))
 }
 
+/// Environment variable specifying the size of each part of a multipart
+/// upload, in MiB.
+const PART_SIZE_VAR: &str = "FALCONERI_S3_PART_SIZE_MB";
+
+/// Environment variable specifying how many parts to upload at once.
+const UPLOAD_CONCURRENCY_VAR: &str = "FALCONERI_S3_UPLOAD_CONCURRENCY";
+
+/// Our default part size, in MiB. S3 allows at most 10,000 parts, so this
+/// allows objects of up to about 320 GiB.
+const DEFAULT_PART_SIZE_MB: usize = 32;
+
+/// Our default upload concurrency.
+const DEFAULT_UPLOAD_CONCURRENCY: usize = 4;
+
+/// Parse our multipart upload options from the values of `PART_SIZE_VAR` and
+/// `UPLOAD_CONCURRENCY_VAR`.
+fn parse_multipart_options(
+    part_size_mb: Option<&str>,
+    concurrency: Option<&str>,
+) -> Result<MultipartOptions> {
+    let part_size_mb = match part_size_mb {
+        Some(value) => value.trim().parse::<usize>().with_context(|| {
+            format!("could not parse {}={:?}", PART_SIZE_VAR, value)
+        })?,
+        None => DEFAULT_PART_SIZE_MB,
+    };
+    // These are S3's limits.
+    if !(5..=5 * 1024).contains(&part_size_mb) {
+        return Err(format_err!(
+            "{} must be between 5 and 5120, not {}",
+            PART_SIZE_VAR,
+            part_size_mb
+        ));
+    }
+    let max_concurrency = match concurrency {
+        Some(value) => value.trim().parse::<usize>().with_context(|| {
+            format!("could not parse {}={:?}", UPLOAD_CONCURRENCY_VAR, value)
+        })?,
+        None => DEFAULT_UPLOAD_CONCURRENCY,
+    };
+    if max_concurrency == 0 {
+        return Err(format_err!("{} must be at least 1", UPLOAD_CONCURRENCY_VAR));
+    }
+    Ok(MultipartOptions {
+        part_size: part_size_mb * 1024 * 1024,
+        max_concurrency,
+    })
+}
+
+/// The attributes we give to new objects.
+fn upload_attributes(options: &UploadOptions) -> Attributes {
+    let mut attributes = Attributes::new();
+    if let Some(storage_class) = &options.storage_class {
+        attributes.insert(Attribute::StorageClass, storage_class.clone().into());
+    }
+    attributes
+}
+
 /// Backend for talking to AWS S3 using native Rust (no external CLI).
 pub struct S3Storage {
     store: Arc<dyn ObjectStore>,
     bucket: String,
+    /// How we split large uploads into parts.
+    multipart: MultipartOptions,
+    /// Attributes for new objects, such as their storage class.
+    attributes: Attributes,
 }
 
 impl S3Storage {
@@ -72,6 +143,26 @@ impl S3Storage {
     #[allow(clippy::new_ret_no_self)]
     #[instrument(skip_all, level = "trace")]
     pub async fn new(secrets: &[Secret], bucket_uri: &str) -> Result<Self> {
+        Self::new_helper(secrets, bucket_uri, None).await
+    }
+
+    /// Create a new `S3Storage` backend for a requester-pays bucket. We send
+    /// `x-amz-request-payer: requester` with each request, and AWS bills the
+    /// account which owns our credentials.
+    #[instrument(skip_all, level = "trace")]
+    pub async fn new_requester_pays(
+        secrets: &[Secret],
+        bucket_uri: &str,
+        requester_pays: &RequesterPays,
+    ) -> Result<Self> {
+        Self::new_helper(secrets, bucket_uri, Some(requester_pays)).await
+    }
+
+    async fn new_helper(
+        secrets: &[Secret],
+        bucket_uri: &str,
+        requester_pays: Option<&RequesterPays>,
+    ) -> Result<Self> {
         let secret = secrets
             .iter()
             .find(|s| matches!(s, Secret::Env { env_var, .. } if env_var == "AWS_ACCESS_KEY_ID"));
@@ -82,7 +173,7 @@ impl S3Storage {
                 None
             };
 
-        Self::build_from_secret(secret_data, bucket_uri)
+        Self::build_from_secret(secret_data, bucket_uri, requester_pays)
     }
 
     /// Construct a new `S3Storage` backend using an AWS access key from
@@ -93,12 +184,30 @@ impl S3Storage {
     #[instrument(skip_all, fields(secret_name = %secret_name), level = "trace")]
     pub async fn new_with_secret(secret_name: &str, bucket_uri: &str) -> Result<Self> {
         let secret_data: Option<S3SecretData> = kubectl_secret(secret_name).await?;
-        Self::build_from_secret(secret_data, bucket_uri)
+        Self::build_from_secret(secret_data, bucket_uri, None)
+    }
+
+    /// Like [`S3Storage::new_with_secret`], but connect to `endpoint_url`
+    /// instead of any endpoint in the secret. This allows us to reach an
+    /// in-cluster MinIO server through `falconeri proxy`.
+    #[instrument(skip_all, fields(secret_name = %secret_name), level = "trace")]
+    pub async fn new_with_secret_and_endpoint(
+        secret_name: &str,
+        endpoint_url: &str,
+        bucket_uri: &str,
+    ) -> Result<Self> {
+        let mut secret_data: Option<S3SecretData> =
+            kubectl_secret(secret_name).await?;
+        if let Some(secret_data) = &mut secret_data {
+            secret_data.aws_endpoint_url = Some(endpoint_url.to_owned());
+        }
+        Self::build_from_secret(secret_data, bucket_uri, None)
     }
 
     fn build_from_secret(
         secret_data: Option<S3SecretData>,
         bucket_uri: &str,
+        requester_pays: Option<&RequesterPays>,
     ) -> Result<Self> {
         let (bucket, _) = parse_s3_url(bucket_uri)?;
 
@@ -122,11 +231,28 @@ impl S3Storage {
             }
         }
 
+        if requester_pays.is_some() {
+            builder = builder.with_request_payer(true);
+        }
+
+        // Encryption is configured on the client, and applies to everything we
+        // upload.
+        let upload_options = UploadOptions::from_env();
+        if let Some(kms_key) = &upload_options.kms_key {
+            builder = builder.with_sse_kms_encryption(kms_key);
+        }
+
         let store = builder.build().context("failed to build S3 client")?;
+        let multipart = parse_multipart_options(
+            std::env::var(PART_SIZE_VAR).ok().as_deref(),
+            std::env::var(UPLOAD_CONCURRENCY_VAR).ok().as_deref(),
+        )?;
 
         Ok(S3Storage {
             store: Arc::new(store),
             bucket: bucket.to_owned(),
+            multipart,
+            attributes: upload_attributes(&upload_options),
         })
     }
 }
@@ -142,7 +268,7 @@ impl fmt::Debug for S3Storage {
 #[async_trait]
 impl CloudStorage for S3Storage {
     #[instrument(skip_all, fields(uri = %uri), level = "trace")]
-    async fn list(&self, uri: &str) -> Result<Vec<String>> {
+    async fn list_objects(&self, uri: &str) -> Result<Vec<ListedObject>> {
         trace!("listing {}", uri);
 
         let (bucket, key) = parse_s3_url(uri)?;
@@ -167,7 +293,13 @@ impl CloudStorage for S3Storage {
         {
             let path_str = meta.location.to_string();
             if path_str != prefix {
-                results.push(format!("s3://{}/{}", bucket, path_str));
+                results.push(ListedObject {
+                    uri: format!("s3://{}/{}", bucket, path_str),
+                    etag: meta.e_tag,
+                    generation: meta.version,
+                    size: Some(meta.size),
+                    last_modified: Some(meta.last_modified.naive_utc()),
+                });
             }
         }
 
@@ -255,13 +387,62 @@ impl CloudStorage for S3Storage {
             };
 
             let object_path = ObjectPath::from(object_key.as_str());
-            stream_upload_from_file(&self.store, file_path, &object_path)
-                .await
-                .with_context(|| format!("error uploading to S3: {}", object_key))?;
+            stream_upload_from_file(
+                &self.store,
+                file_path,
+                &object_path,
+                &self.multipart,
+                &self.attributes,
+            )
+            .await
+            .with_context(|| format!("error uploading to S3: {}", object_key))?;
         }
 
         Ok(())
     }
+
+    #[instrument(skip_all, fields(uri = %uri), level = "trace")]
+    async fn download_to_writer(
+        &self,
+        uri: &str,
+        writer: &mut (dyn AsyncWrite + Send + Unpin),
+    ) -> Result<u64> {
+        check_file_uri(uri)?;
+        let (_, key) = parse_s3_url(uri)?;
+        let object_path = ObjectPath::from(key);
+        stream_download_to_writer(&self.store, &object_path, writer)
+            .await
+            .with_context(|| format!("error downloading from S3: {}", uri))
+    }
+
+    #[instrument(skip_all, fields(uri = %uri), level = "trace")]
+    async fn upload_from_reader(
+        &self,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+        uri: &str,
+    ) -> Result<u64> {
+        check_file_uri(uri)?;
+        let (_, key) = parse_s3_url(uri)?;
+        let object_path = ObjectPath::from(key);
+        stream_upload_from_reader(
+            &self.store,
+            reader,
+            &object_path,
+            &self.multipart,
+            &self.attributes,
+        )
+        .await
+        .with_context(|| format!("error uploading to S3: {}", uri))
+    }
+
+    #[instrument(skip_all, fields(uri = %uri), level = "trace")]
+    async fn delete(&self, uri: &str) -> Result<()> {
+        check_file_uri(uri)?;
+        let (_, key) = parse_s3_url(uri)?;
+        delete_object(&self.store, &ObjectPath::from(key))
+            .await
+            .with_context(|| format!("error deleting from S3: {}", uri))
+    }
 }
 
 #[test]
@@ -278,3 +459,30 @@ fn url_parsing() {
     );
     assert!(parse_s3_url("gs://foo/").is_err());
 }
+
+#[test]
+fn multipart_options_parsing() {
+    let default = parse_multipart_options(None, None).unwrap();
+    assert_eq!(default.part_size, 32 * 1024 * 1024);
+    assert_eq!(default.max_concurrency, 4);
+    let custom = parse_multipart_options(Some("128"), Some(" 8 ")).unwrap();
+    assert_eq!(custom.part_size, 128 * 1024 * 1024);
+    assert_eq!(custom.max_concurrency, 8);
+    assert!(parse_multipart_options(Some("4"), None).is_err());
+    assert!(parse_multipart_options(Some("6000"), None).is_err());
+    assert!(parse_multipart_options(None, Some("0")).is_err());
+    assert!(parse_multipart_options(Some("big"), None).is_err());
+}
+
+#[test]
+fn storage_class_becomes_attribute() {
+    assert!(upload_attributes(&UploadOptions::default()).is_empty());
+    let attributes = upload_attributes(&UploadOptions {
+        kms_key: None,
+        storage_class: Some("STANDARD_IA".to_owned()),
+    });
+    assert_eq!(
+        attributes.get(&Attribute::StorageClass).map(|v| v.as_ref()),
+        Some("STANDARD_IA")
+    );
+}
@@ -0,0 +1,583 @@
+//! Support for SFTP servers, for partners who deliver data that way.
+//!
+//! URIs look like `sftp://user@host:port/path/to/file`. The user and port are
+//! optional. Paths are always absolute, because SFTP servers disagree about
+//! where relative paths start.
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use regex::Regex;
+use russh::{
+    client,
+    keys::{decode_secret_key, HashAlg, PrivateKeyWithHashAlg, PublicKey},
+};
+use russh_sftp::client::SftpSession;
+use tokio::{
+    fs as async_fs,
+    io::{self as async_io, AsyncRead, AsyncWrite, AsyncWriteExt},
+};
+use walkdir::WalkDir;
+
+use super::{check_file_uri, CloudStorage, ListedObject};
+use crate::{
+    kubernetes::{base64_encoded_optional_secret_string, kubectl_secret},
+    prelude::*,
+    secret::Secret,
+};
+
+/// The port to use if the URI doesn't specify one.
+const DEFAULT_PORT: u16 = 22;
+
+/// An SFTP secret fetched from Kubernetes. Either `SFTP_PASSWORD` or
+/// `SFTP_PRIVATE_KEY` should be present.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE", deny_unknown_fields)]
+struct SftpSecretData {
+    /// The user to log in as, if it isn't part of the URI.
+    #[serde(default, with = "base64_encoded_optional_secret_string")]
+    sftp_username: Option<String>,
+    /// A password.
+    #[serde(default, with = "base64_encoded_optional_secret_string")]
+    sftp_password: Option<String>,
+    /// A private key, in OpenSSH or PEM format.
+    #[serde(default, with = "base64_encoded_optional_secret_string")]
+    sftp_private_key: Option<String>,
+    /// The passphrase for `sftp_private_key`, if it's encrypted.
+    #[serde(default, with = "base64_encoded_optional_secret_string")]
+    sftp_private_key_passphrase: Option<String>,
+    /// The server's public key, in OpenSSH format (for example,
+    /// `ssh-ed25519 AAAA...`). If present, we refuse to talk to servers with
+    /// any other key.
+    #[serde(default, with = "base64_encoded_optional_secret_string")]
+    sftp_host_key: Option<String>,
+    /// Set to `true` to talk to servers without checking their host key.
+    /// Without this, we refuse to connect unless `sftp_host_key` is set,
+    /// because we'd be sending our credentials to whoever answers.
+    #[serde(default, with = "base64_encoded_optional_secret_string")]
+    sftp_insecure_accept_any_host_key: Option<String>,
+}
+
+impl SftpSecretData {
+    /// Look up our credentials in environment variables. This is how workers
+    /// see the secrets listed in their pipeline spec.
+    fn from_env() -> Self {
+        let var = |name: &str| std::env::var(name).ok();
+        SftpSecretData {
+            sftp_username: var("SFTP_USERNAME"),
+            sftp_password: var("SFTP_PASSWORD"),
+            sftp_private_key: var("SFTP_PRIVATE_KEY"),
+            sftp_private_key_passphrase: var("SFTP_PRIVATE_KEY_PASSPHRASE"),
+            sftp_host_key: var("SFTP_HOST_KEY"),
+            sftp_insecure_accept_any_host_key: var(
+                "SFTP_INSECURE_ACCEPT_ANY_HOST_KEY",
+            ),
+        }
+    }
+
+    /// Decide how to check the server's host key.
+    fn host_key_check(&self) -> Result<HostKeyCheck> {
+        if let Some(key) = &self.sftp_host_key {
+            let key = PublicKey::from_openssh(key.trim())
+                .context("could not parse SFTP_HOST_KEY")?;
+            return Ok(HostKeyCheck::Expect(key));
+        }
+        let accept_any = self
+            .sftp_insecure_accept_any_host_key
+            .as_deref()
+            .map(|v| v == "true" || v == "1")
+            .unwrap_or(false);
+        if accept_any {
+            Ok(HostKeyCheck::AcceptAny)
+        } else {
+            Err(format_err!(
+                "refusing to connect to an SFTP server without SFTP_HOST_KEY (set SFTP_INSECURE_ACCEPT_ANY_HOST_KEY=true to skip this check)"
+            ))
+        }
+    }
+}
+
+/// How to check an SFTP server's host key.
+enum HostKeyCheck {
+    /// Only accept this key.
+    Expect(PublicKey),
+    /// Accept any key. This was explicitly requested using
+    /// `SFTP_INSECURE_ACCEPT_ANY_HOST_KEY`.
+    AcceptAny,
+}
+
+/// The parts of an `sftp://` URI.
+#[derive(Debug, PartialEq, Eq)]
+struct SftpUrl<'a> {
+    /// The user in the URI, if any.
+    username: Option<&'a str>,
+    /// The server's host name.
+    host: &'a str,
+    /// The server's port.
+    port: u16,
+    /// The absolute path on the server.
+    path: &'a str,
+}
+
+/// Parse an SFTP URL.
+fn parse_sftp_url(url: &str) -> Result<SftpUrl<'_>> {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(
+            "^sftp://(?:(?P<user>[^@/]+)@)?(?P<host>[^:/@]+)(?::(?P<port>[0-9]+))?(?P<path>/.*)?$"
+        )
+        .expect("couldn't parse built-in regex");
+    }
+
+    let caps = RE
+        .captures(url)
+        .ok_or_else(|| format_err!("the URL {:?} could not be parsed", url))?;
+    let port = match caps.name("port") {
+        Some(port) => port
+            .as_str()
+            .parse::<u16>()
+            .with_context(|| format!("invalid port in {:?}", url))?,
+        None => DEFAULT_PORT,
+    };
+    Ok(SftpUrl {
+        username: caps.name("user").map(|m| m.as_str()),
+        host: caps
+            .name("host")
+            .expect("missing hard-coded capture???")
+            .as_str(),
+        port,
+        path: caps.name("path").map(|m| m.as_str()).unwrap_or("/"),
+    })
+}
+
+/// Join `name` onto the directory `dir`.
+fn join_path(dir: &str, name: &str) -> String {
+    format!("{}/{}", dir.trim_end_matches('/'), name)
+}
+
+/// Callbacks for our SSH connection.
+struct SshHandler {
+    /// How to check the server's host key.
+    host_key_check: HostKeyCheck,
+}
+
+impl client::Handler for SshHandler {
+    type Error = russh::Error;
+
+    async fn check_server_key(
+        &mut self,
+        server_public_key: &PublicKey,
+    ) -> std::result::Result<bool, Self::Error> {
+        match &self.host_key_check {
+            HostKeyCheck::Expect(expected) => {
+                Ok(expected.key_data() == server_public_key.key_data())
+            }
+            HostKeyCheck::AcceptAny => {
+                warn!(
+                    "accepting SFTP host key {} without verification, because SFTP_INSECURE_ACCEPT_ANY_HOST_KEY is set",
+                    server_public_key.fingerprint(HashAlg::Sha256),
+                );
+                Ok(true)
+            }
+        }
+    }
+}
+
+/// Backend for talking to an SFTP server.
+pub struct SftpStorage {
+    /// The `sftp://user@host:port` part of our URIs.
+    authority: String,
+    /// Our SFTP session.
+    sftp: SftpSession,
+    /// Our SSH connection, which must outlive `sftp`.
+    _ssh: client::Handle<SshHandler>,
+}
+
+impl SftpStorage {
+    /// Connect to the SFTP server named in `bucket_uri`.
+    ///
+    /// If `secrets` contains a secret mapped to `SFTP_PASSWORD` or
+    /// `SFTP_PRIVATE_KEY`, we fetch our credentials from that Kubernetes
+    /// secret. Otherwise, we look for them in environment variables.
+    #[allow(clippy::new_ret_no_self)]
+    #[instrument(skip_all, level = "trace")]
+    pub async fn new(secrets: &[Secret], bucket_uri: &str) -> Result<Self> {
+        let secret = secrets.iter().find(|s| {
+            matches!(s, Secret::Env { env_var, .. }
+                if env_var == "SFTP_PASSWORD" || env_var == "SFTP_PRIVATE_KEY")
+        });
+        let secret_data = if let Some(Secret::Env { name, .. }) = secret {
+            kubectl_secret::<SftpSecretData>(name).await?
+        } else {
+            SftpSecretData::from_env()
+        };
+        Self::connect(secret_data, bucket_uri).await
+    }
+
+    /// Connect and log in using `secret_data`.
+    async fn connect(secret_data: SftpSecretData, bucket_uri: &str) -> Result<Self> {
+        let url = parse_sftp_url(bucket_uri)?;
+        let username = url
+            .username
+            .map(|u| u.to_owned())
+            .or(secret_data.sftp_username)
+            .ok_or_else(|| {
+                format_err!("no SFTP user in {:?} or SFTP_USERNAME", bucket_uri)
+            })?;
+        let host_key_check = secret_data.host_key_check()?;
+
+        // Connect and authenticate.
+        let config = Arc::new(client::Config::default());
+        let mut ssh = client::connect(
+            config,
+            (url.host, url.port),
+            SshHandler { host_key_check },
+        )
+        .await
+        .with_context(|| format!("could not connect to {}:{}", url.host, url.port))?;
+        let authenticated = if let Some(private_key) = &secret_data.sftp_private_key {
+            let key = decode_secret_key(
+                private_key,
+                secret_data.sftp_private_key_passphrase.as_deref(),
+            )
+            .context("could not decode SFTP_PRIVATE_KEY")?;
+            let hash_alg = ssh.best_supported_rsa_hash().await?.flatten();
+            ssh.authenticate_publickey(
+                &username,
+                PrivateKeyWithHashAlg::new(Arc::new(key), hash_alg),
+            )
+            .await?
+            .success()
+        } else if let Some(password) = &secret_data.sftp_password {
+            ssh.authenticate_password(&username, password)
+                .await?
+                .success()
+        } else {
+            return Err(format_err!(
+                "need SFTP_PASSWORD or SFTP_PRIVATE_KEY to access {}",
+                bucket_uri
+            ));
+        };
+        if !authenticated {
+            return Err(format_err!(
+                "SFTP server {} rejected our credentials for {}",
+                url.host,
+                username
+            ));
+        }
+
+        // Start SFTP.
+        let channel = ssh.channel_open_session().await?;
+        channel.request_subsystem(true, "sftp").await?;
+        let sftp = SftpSession::new(channel.into_stream())
+            .await
+            .context("could not start SFTP session")?;
+
+        let authority = match url.port {
+            DEFAULT_PORT => format!("sftp://{}@{}", username, url.host),
+            port => format!("sftp://{}@{}:{}", username, url.host, port),
+        };
+        Ok(SftpStorage {
+            authority,
+            sftp,
+            _ssh: ssh,
+        })
+    }
+
+    /// Get the remote path for `uri`.
+    fn remote_path<'a>(&self, uri: &'a str) -> Result<&'a str> {
+        Ok(parse_sftp_url(uri)?.path)
+    }
+
+    /// Build a URI for `path` on our server.
+    fn uri_for(&self, path: &str) -> String {
+        format!("{}{}", self.authority, path)
+    }
+
+    /// Is `path` a directory?
+    async fn is_dir(&self, path: &str) -> Result<bool> {
+        let metadata = self
+            .sftp
+            .metadata(path)
+            .await
+            .with_context(|| format!("could not stat {}", self.uri_for(path)))?;
+        Ok(metadata.is_dir())
+    }
+
+    /// Recursively list all the files under `dir`, returning their paths.
+    async fn walk(&self, dir: &str) -> Result<Vec<String>> {
+        let mut files = vec![];
+        let mut pending = vec![dir.to_owned()];
+        while let Some(dir) = pending.pop() {
+            let entries =
+                self.sftp.read_dir(dir.as_str()).await.with_context(|| {
+                    format!("could not list {}", self.uri_for(&dir))
+                })?;
+            for entry in entries {
+                let name = entry.file_name();
+                if name == "." || name == ".." {
+                    continue;
+                }
+                let path = join_path(&dir, &name);
+                if entry.file_type().is_dir() {
+                    pending.push(path);
+                } else {
+                    files.push(path);
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    /// Download the file at `remote` to `local_path`.
+    async fn download_file(&self, remote: &str, local_path: &Path) -> Result<()> {
+        if let Some(parent) = local_path.parent() {
+            async_fs::create_dir_all(parent)
+                .await
+                .context("cannot create local download directory")?;
+        }
+        let mut file =
+            async_fs::File::create(local_path).await.with_context(|| {
+                format!("cannot create local file: {}", local_path.display())
+            })?;
+        self.download_to_writer(&self.uri_for(remote), &mut file)
+            .await?;
+        Ok(())
+    }
+
+    /// Create `dir` and any missing parents on the server.
+    async fn create_dir_all(&self, dir: &str) -> Result<()> {
+        let mut path = String::new();
+        for component in dir.split('/').filter(|c| !c.is_empty()) {
+            path.push('/');
+            path.push_str(component);
+            if !self.sftp.try_exists(path.as_str()).await? {
+                self.sftp.create_dir(path.as_str()).await.with_context(|| {
+                    format!("could not create {}", self.uri_for(&path))
+                })?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for SftpStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SftpStorage")
+            .field("authority", &self.authority)
+            .finish()
+    }
+}
+
+#[async_trait]
+impl CloudStorage for SftpStorage {
+    #[instrument(skip_all, fields(uri = %uri), level = "trace")]
+    async fn list_objects(&self, uri: &str) -> Result<Vec<ListedObject>> {
+        trace!("listing {}", uri);
+        let path = self.remote_path(uri)?;
+        if !self.is_dir(path).await? {
+            return Ok(vec![ListedObject {
+                uri: uri.to_owned(),
+                ..ListedObject::default()
+            }]);
+        }
+
+        let entries = self
+            .sftp
+            .read_dir(path)
+            .await
+            .with_context(|| format!("could not list {}", uri))?;
+        let mut results = vec![];
+        for entry in entries {
+            let name = entry.file_name();
+            if name == "." || name == ".." {
+                continue;
+            }
+            let mut entry_path = join_path(path, &name);
+            if entry.file_type().is_dir() {
+                entry_path.push('/');
+            }
+            // SFTP has no etags, but a change in size or modification time
+            // means the file was replaced.
+            let metadata = entry.metadata();
+            let generation = metadata
+                .mtime
+                .map(|mtime| format!("{}:{}", mtime, metadata.size.unwrap_or(0)));
+            let last_modified = metadata
+                .mtime
+                .and_then(|mtime| {
+                    chrono::DateTime::from_timestamp(i64::from(mtime), 0)
+                })
+                .map(|mtime| mtime.naive_utc());
+            let size = if entry.file_type().is_dir() {
+                None
+            } else {
+                metadata.size
+            };
+            results.push(ListedObject {
+                uri: self.uri_for(&entry_path),
+                etag: None,
+                generation,
+                size,
+                last_modified,
+            });
+        }
+        results.sort_by(|a, b| a.uri.cmp(&b.uri));
+        Ok(results)
+    }
+
+    #[instrument(skip_all, fields(uri = %uri, local_path = %local_path.display()), level = "trace")]
+    async fn sync_down(&self, uri: &str, local_path: &Path) -> Result<()> {
+        trace!("downloading {} to {}", uri, local_path.display());
+        let path = self.remote_path(uri)?;
+        if uri.ends_with('/') {
+            async_fs::create_dir_all(local_path)
+                .await
+                .context("cannot create local download directory")?;
+            for remote in self.walk(path).await? {
+                let relative = remote
+                    .strip_prefix(path)
+                    .unwrap_or(&remote)
+                    .trim_start_matches('/');
+                self.download_file(&remote, &local_path.join(relative))
+                    .await?;
+            }
+        } else {
+            self.download_file(path, local_path).await?;
+        }
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(local_path = %local_path.display(), uri = %uri), level = "trace")]
+    async fn sync_up(&self, local_path: &Path, uri: &str) -> Result<()> {
+        trace!("uploading {} to {}", local_path.display(), uri);
+        let base_path = self.remote_path(uri)?.trim_end_matches('/');
+        for entry in WalkDir::new(local_path).into_iter().filter_map(|e| e.ok()) {
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let file_path = entry.path();
+            let relative_path = file_path
+                .strip_prefix(local_path)
+                .context("failed to compute relative path")?;
+            let remote = if relative_path.as_os_str().is_empty() {
+                base_path.to_owned()
+            } else {
+                join_path(base_path, &relative_path.to_string_lossy())
+            };
+            if let Some((parent, _)) = remote.rsplit_once('/') {
+                self.create_dir_all(parent).await?;
+            }
+            let mut file =
+                async_fs::File::open(file_path).await.with_context(|| {
+                    format!("cannot open local file: {}", file_path.display())
+                })?;
+            self.upload_from_reader(&mut file, &self.uri_for(&remote))
+                .await?;
+        }
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(uri = %uri), level = "trace")]
+    async fn download_to_writer(
+        &self,
+        uri: &str,
+        writer: &mut (dyn AsyncWrite + Send + Unpin),
+    ) -> Result<u64> {
+        check_file_uri(uri)?;
+        let path = self.remote_path(uri)?;
+        let mut file = self
+            .sftp
+            .open(path)
+            .await
+            .with_context(|| format!("could not open {}", uri))?;
+        let bytes = async_io::copy(&mut file, writer)
+            .await
+            .with_context(|| format!("error downloading from SFTP: {}", uri))?;
+        writer.flush().await?;
+        Ok(bytes)
+    }
+
+    #[instrument(skip_all, fields(uri = %uri), level = "trace")]
+    async fn upload_from_reader(
+        &self,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+        uri: &str,
+    ) -> Result<u64> {
+        check_file_uri(uri)?;
+        let path = self.remote_path(uri)?;
+        let mut file = self
+            .sftp
+            .create(path)
+            .await
+            .with_context(|| format!("could not create {}", uri))?;
+        let bytes = async_io::copy(reader, &mut file)
+            .await
+            .with_context(|| format!("error uploading to SFTP: {}", uri))?;
+        file.shutdown()
+            .await
+            .with_context(|| format!("error closing {}", uri))?;
+        Ok(bytes)
+    }
+
+    #[instrument(skip_all, fields(uri = %uri), level = "trace")]
+    async fn delete(&self, uri: &str) -> Result<()> {
+        check_file_uri(uri)?;
+        let path = self.remote_path(uri)?;
+        if self.sftp.try_exists(path).await? {
+            self.sftp
+                .remove_file(path)
+                .await
+                .with_context(|| format!("could not delete {}", uri))?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn url_parsing() {
+    assert_eq!(
+        parse_sftp_url("sftp://partner@sftp.example.com/outgoing/").unwrap(),
+        SftpUrl {
+            username: Some("partner"),
+            host: "sftp.example.com",
+            port: 22,
+            path: "/outgoing/",
+        }
+    );
+    assert_eq!(
+        parse_sftp_url("sftp://sftp.example.com:2222").unwrap(),
+        SftpUrl {
+            username: None,
+            host: "sftp.example.com",
+            port: 2222,
+            path: "/",
+        }
+    );
+    assert!(parse_sftp_url("sftp://host:99999/").is_err());
+    assert!(parse_sftp_url("s3://bucket/").is_err());
+    assert_eq!(join_path("/outgoing/", "a.csv"), "/outgoing/a.csv");
+    assert_eq!(join_path("/", "a.csv"), "/a.csv");
+}
+
+#[test]
+fn host_key_is_required_unless_insecure_access_is_requested() {
+    let secret_data = SftpSecretData::default();
+    assert!(secret_data.host_key_check().is_err());
+
+    let secret_data = SftpSecretData {
+        sftp_insecure_accept_any_host_key: Some("true".to_owned()),
+        ..SftpSecretData::default()
+    };
+    assert!(matches!(
+        secret_data.host_key_check().unwrap(),
+        HostKeyCheck::AcceptAny
+    ));
+
+    let secret_data = SftpSecretData {
+        sftp_host_key: Some("not a key".to_owned()),
+        sftp_insecure_accept_any_host_key: Some("true".to_owned()),
+        ..SftpSecretData::default()
+    };
+    assert!(secret_data.host_key_check().is_err());
+}
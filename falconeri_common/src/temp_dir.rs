@@ -0,0 +1,60 @@
+//! Temporary directories which clean up after themselves.
+
+use std::{env, fs, ops::Deref};
+
+use crate::prelude::*;
+
+/// A fresh, empty directory under `env::temp_dir()`. The directory and
+/// everything in it are removed when this is dropped.
+#[derive(Debug)]
+pub struct TempDir {
+    /// The path to our directory.
+    path: PathBuf,
+}
+
+impl TempDir {
+    /// Create a new temporary directory whose name starts with `prefix`.
+    pub fn new(prefix: &str) -> Result<Self> {
+        let path = env::temp_dir().join(format!("{}-{}", prefix, Uuid::new_v4()));
+        fs::create_dir_all(&path)
+            .with_context(|| format!("cannot create {}", path.display()))?;
+        Ok(TempDir { path })
+    }
+
+    /// The path to our directory.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_dir_all(&self.path) {
+            warn!("could not remove {}: {}", self.path.display(), err);
+        }
+    }
+}
+
+#[test]
+fn temp_dir_is_removed_when_dropped() {
+    let dir = TempDir::new("falconeri-test").unwrap();
+    let path = dir.path().to_owned();
+    fs::write(dir.join("a.txt"), "a").unwrap();
+    assert!(path.join("a.txt").is_file());
+    drop(dir);
+    assert!(!path.exists());
+}
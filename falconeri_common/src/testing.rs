@@ -0,0 +1,375 @@
+//! Helpers for end-to-end tests which run without Kubernetes.
+//!
+//! This module is only available with the `testing` feature. It provides:
+//!
+//! - [`TestDatabase`], a throwaway PostgreSQL server running in Docker, with
+//!   our migrations already applied.
+//! - [`TestStorage`], a fake bucket stored in a temporary directory, which
+//!   can be accessed using `test://` URIs.
+//! - [`FakeWorker`], which processes datums like `falconeri-worker`, but
+//!   calls a Rust closure instead of running a command.
+//!
+//! `falconerid` is a binary, so we can't run it from here. Instead, its own
+//! tests serve its router in-process, using these helpers.
+
+use std::{env, fs, path::Component, time::Duration};
+
+use lazy_static::lazy_static;
+use testcontainers_modules::{
+    postgres::Postgres,
+    testcontainers::{runners::AsyncRunner, ContainerAsync},
+};
+use walkdir::WalkDir;
+
+use crate::{
+    db,
+    prelude::*,
+    rest_api::{Client, OutputFilePatch, OutputFilePost},
+    storage::{dir::DirStorageBackend, register_backend, CloudStorage},
+};
+
+/// The URI scheme used by [`TestStorage`].
+pub const TEST_STORAGE_SCHEME: &str = "test";
+
+/// How long should [`FakeWorker`] wait before checking for more datums?
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The pod and node name used by [`FakeWorker`].
+const FAKE_WORKER_NAME: &str = "falconeri-test-worker";
+
+lazy_static! {
+    /// Where we keep the buckets for all our [`TestStorage`] instances.
+    static ref TEST_STORAGE_ROOT: PathBuf = env::temp_dir()
+        .join(format!("falconeri-test-storage-{}", std::process::id()));
+}
+
+/// A PostgreSQL database running in a Docker container. The container is
+/// stopped when this is dropped.
+pub struct TestDatabase {
+    /// Our container. We need to keep this around to keep it running.
+    _container: ContainerAsync<Postgres>,
+    /// The URL of our database.
+    url: String,
+}
+
+impl TestDatabase {
+    /// Start a new database and run our migrations. Requires Docker.
+    #[instrument(level = "debug")]
+    pub async fn start() -> Result<Self> {
+        let container = Postgres::default()
+            .start()
+            .await
+            .context("could not start PostgreSQL container (is Docker running?)")?;
+        let host = container
+            .get_host()
+            .await
+            .context("could not get PostgreSQL container host")?;
+        let port = container
+            .get_host_port_ipv4(5432)
+            .await
+            .context("could not get PostgreSQL container port")?;
+        let url = format!("postgres://postgres:postgres@{}:{}/postgres", host, port);
+
+        let conn = db::async_connect_to_url(&url).await?;
+        db::run_pending_migrations(conn)?;
+        Ok(TestDatabase {
+            _container: container,
+            url,
+        })
+    }
+
+    /// The URL of our database.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Create a connection pool for our database.
+    pub fn pool(&self, pool_size: usize) -> Result<db::AsyncPool> {
+        db::async_pool_for_url(pool_size, &self.url)
+    }
+}
+
+impl fmt::Debug for TestDatabase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Don't print our container, which is very noisy.
+        f.debug_struct("TestDatabase").finish_non_exhaustive()
+    }
+}
+
+/// A fake bucket stored in a local directory, which can be accessed using
+/// `test://` URIs from anywhere in this process. Each `TestStorage` has its
+/// own randomly-named bucket, which is deleted when it is dropped.
+#[derive(Debug)]
+pub struct TestStorage {
+    /// The name of our bucket.
+    bucket: String,
+}
+
+impl TestStorage {
+    /// Create a new, empty bucket, and register our storage backend for
+    /// `test://` URIs if necessary.
+    pub fn new() -> Result<Self> {
+        register_backend(
+            TEST_STORAGE_SCHEME,
+            DirStorageBackend::new(TEST_STORAGE_ROOT.clone()),
+        )?;
+        let storage = TestStorage {
+            bucket: format!("bucket-{}", Uuid::new_v4()),
+        };
+        let dir = storage.local_path("");
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("cannot create {}", dir.display()))?;
+        Ok(storage)
+    }
+
+    /// The URI of `path` in our bucket, like `test://bucket-.../path`.
+    pub fn uri(&self, path: &str) -> String {
+        format!(
+            "{}://{}/{}",
+            TEST_STORAGE_SCHEME,
+            self.bucket,
+            path.trim_start_matches('/')
+        )
+    }
+
+    /// The local path where we store `path` in our bucket.
+    pub fn local_path(&self, path: &str) -> PathBuf {
+        TEST_STORAGE_ROOT
+            .join(&self.bucket)
+            .join(path.trim_start_matches('/'))
+    }
+
+    /// Write `contents` to `path` in our bucket.
+    pub fn write<C: AsRef<[u8]>>(&self, path: &str, contents: C) -> Result<()> {
+        let local_path = self.local_path(path);
+        if let Some(parent) = local_path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("cannot create {}", parent.display()))?;
+        }
+        fs::write(&local_path, contents)
+            .with_context(|| format!("cannot write {}", local_path.display()))
+    }
+
+    /// Read `path` from our bucket.
+    pub fn read_to_string(&self, path: &str) -> Result<String> {
+        let local_path = self.local_path(path);
+        fs::read_to_string(&local_path)
+            .with_context(|| format!("cannot read {}", local_path.display()))
+    }
+}
+
+impl Drop for TestStorage {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(self.local_path(""));
+    }
+}
+
+/// A worker which processes datums in-process, calling a closure instead of
+/// running the pipeline's command. It talks to `falconerid` using the same
+/// API calls as `falconeri-worker`, so it exercises reservations, output
+/// files and job completion.
+#[derive(Debug)]
+pub struct FakeWorker {
+    /// Our connection to `falconerid`.
+    client: Client,
+    /// Where we keep our datum directories.
+    work_dir: PathBuf,
+}
+
+impl FakeWorker {
+    /// Create a new worker which talks to `falconerid` using `client`.
+    ///
+    /// Real workers get their node and pod names from Kubernetes, so we
+    /// use [`FAKE_WORKER_NAME`] for both.
+    pub fn new(client: Client) -> Result<Self> {
+        let client = client.with_worker_names(FAKE_WORKER_NAME, FAKE_WORKER_NAME);
+        let work_dir =
+            env::temp_dir().join(format!("falconeri-test-worker-{}", Uuid::new_v4()));
+        fs::create_dir_all(&work_dir)
+            .with_context(|| format!("cannot create {}", work_dir.display()))?;
+        Ok(FakeWorker { client, work_dir })
+    }
+
+    /// Process datums from `job` until it finishes, and return the finished
+    /// job.
+    ///
+    /// For each datum, we download its input files into a fresh directory,
+    /// laid out like `/pfs`, and call `process(input_dir, output_dir)`. If
+    /// that succeeds, we upload everything in `output_dir` to the job's
+    /// egress URI and mark the datum as done. Otherwise, we mark it as
+    /// failed, and `falconerid` may give it to us again.
+    #[instrument(skip_all, fields(job = %job.id), level = "debug")]
+    pub async fn run<F>(&self, job: &Job, mut process: F) -> Result<Job>
+    where
+        F: FnMut(&Path, &Path) -> Result<()>,
+    {
+        loop {
+            match self.client.reserve_next_datum(job, false).await? {
+                Some((mut datum, input_files)) => {
+                    self.process_datum(job, &mut datum, &input_files, &mut process)
+                        .await?;
+                }
+                None => {
+                    let job = self.client.job(job.id).await?;
+                    if job.status.has_finished() {
+                        return Ok(job);
+                    }
+                    tokio::time::sleep(POLL_INTERVAL).await;
+                }
+            }
+        }
+    }
+
+    /// Process a single datum.
+    #[instrument(skip_all, fields(datum = %datum.id), level = "debug")]
+    async fn process_datum<F>(
+        &self,
+        job: &Job,
+        datum: &mut Datum,
+        input_files: &[InputFile],
+        process: &mut F,
+    ) -> Result<()>
+    where
+        F: FnMut(&Path, &Path) -> Result<()>,
+    {
+        let datum_dir = self.work_dir.join(datum.id.to_string());
+        let input_dir = datum_dir.join("in");
+        let output_dir = datum_dir.join("out");
+        fs::create_dir_all(&input_dir)
+            .with_context(|| format!("cannot create {}", input_dir.display()))?;
+        fs::create_dir_all(&output_dir)
+            .with_context(|| format!("cannot create {}", output_dir.display()))?;
+
+        let mut result = async {
+            for file in input_files {
+                let local_path = input_path(&input_dir, &file.local_path)?;
+                let storage = <dyn CloudStorage>::for_uri(&file.uri, &[]).await?;
+                storage.sync_down(&file.uri, &local_path).await?;
+            }
+            process(&input_dir, &output_dir)
+        }
+        .await;
+        if result.is_ok() {
+            result = self.upload_outputs(job, datum, &output_dir).await;
+        }
+        let _ = fs::remove_dir_all(&datum_dir);
+
+        match result {
+            Ok(()) => {
+                self.client
+                    .mark_datum_as_done(datum, DatumReport::default())
+                    .await
+            }
+            Err(err) => {
+                self.client
+                    .mark_datum_as_error(
+                        datum,
+                        DatumReport::default(),
+                        format!("{}", err),
+                        format!("{:?}", err),
+                        None,
+                    )
+                    .await
+            }
+        }
+    }
+
+    /// Upload `output_dir` to our job's egress URI, recording our output
+    /// files.
+    async fn upload_outputs(
+        &self,
+        job: &Job,
+        datum: &Datum,
+        output_dir: &Path,
+    ) -> Result<()> {
+        let mut egress_uri = job.egress_uri.clone();
+        if !egress_uri.ends_with('/') {
+            egress_uri.push('/');
+        }
+        let mut new_output_files = vec![];
+        for entry in WalkDir::new(output_dir) {
+            let entry = entry
+                .with_context(|| format!("error listing {}", output_dir.display()))?;
+            if entry.file_type().is_file() {
+                let rel_path = entry.path().strip_prefix(output_dir)?;
+                new_output_files.push(OutputFilePost {
+                    uri: format!("{}{}", egress_uri, rel_path.display()),
+                });
+            }
+        }
+
+        let output_files = self
+            .client
+            .create_output_files(datum, &new_output_files)
+            .await?;
+        let storage = <dyn CloudStorage>::for_uri(&egress_uri, &[]).await?;
+        let result = storage.sync_up(output_dir, &egress_uri).await;
+        let status = if result.is_ok() {
+            Status::Done
+        } else {
+            Status::Error
+        };
+        let patches = output_files
+            .iter()
+            .map(|f| OutputFilePatch {
+                id: f.id,
+                status,
+                size: None,
+                sha256: None,
+            })
+            .collect::<Vec<_>>();
+        self.client.patch_output_files(datum, &patches).await?;
+        result
+    }
+}
+
+impl Drop for FakeWorker {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.work_dir);
+    }
+}
+
+/// Map an input file's `local_path`, like `/pfs/repo/file`, into
+/// `input_dir`.
+fn input_path(input_dir: &Path, local_path: &str) -> Result<PathBuf> {
+    let rel_path = Path::new(local_path)
+        .strip_prefix("/pfs")
+        .map_err(|_| format_err!("input path {:?} is not under /pfs", local_path))?;
+    let mut path = input_dir.to_owned();
+    for component in rel_path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::Normal(part) => path.push(part),
+            _ => {
+                return Err(format_err!(
+                    "input path {:?} may not contain {:?}",
+                    local_path,
+                    component
+                ))
+            }
+        }
+    }
+    Ok(path)
+}
+
+#[test]
+fn test_storage_maps_uris_to_local_paths() {
+    let storage = TestStorage::new().unwrap();
+    let uri = storage.uri("/in/a.txt");
+    assert!(uri.starts_with("test://bucket-"), "{}", uri);
+    assert!(uri.ends_with("/in/a.txt"), "{}", uri);
+
+    storage.write("in/a.txt", "a").unwrap();
+    assert_eq!(storage.read_to_string("in/a.txt").unwrap(), "a");
+    let dir = storage.local_path("");
+    drop(storage);
+    assert!(!dir.exists());
+
+    let input_dir = Path::new("/tmp/in");
+    assert_eq!(
+        input_path(input_dir, "/pfs/books/a.txt").unwrap(),
+        Path::new("/tmp/in/books/a.txt"),
+    );
+    assert!(input_path(input_dir, "/pfs/../etc/passwd").is_err());
+    assert!(input_path(input_dir, "/etc/passwd").is_err());
+}
@@ -0,0 +1,230 @@
+//! Fetching secrets from HashiCorp Vault.
+//!
+//! `falconerid` passes each job's [`VaultConfig`] and [`Secret::Vault`]
+//! secrets to its workers in [`VAULT_SECRETS_VAR`]. When a worker starts, it
+//! logs into Vault, fetches each secret, and places it in an environment
+//! variable, which our command inherits. This way, the secrets never need to
+//! be stored in Kubernetes.
+
+use std::{env, fs, time::Duration};
+
+use crate::{
+    prelude::*,
+    secret::{Secret, VaultConfig},
+};
+
+/// Environment variable containing a JSON-encoded [`VaultSecrets`] value.
+/// `falconerid` sets this for workers whose jobs use Vault secrets.
+pub const VAULT_SECRETS_VAR: &str = "FALCONERI_VAULT_SECRETS";
+
+/// Where Kubernetes mounts our service account token, which we use to log
+/// into Vault.
+const SERVICE_ACCOUNT_TOKEN_PATH: &str =
+    "/var/run/secrets/kubernetes.io/serviceaccount/token";
+
+/// How long to wait for Vault to respond.
+const VAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The Vault secrets needed by a job's workers.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct VaultSecrets {
+    /// How to log into Vault.
+    pub config: VaultConfig,
+    /// The secrets to fetch. These will all be [`Secret::Vault`] values.
+    pub secrets: Vec<Secret>,
+}
+
+impl VaultSecrets {
+    /// Load our Vault secrets from [`VAULT_SECRETS_VAR`], if it's set.
+    pub fn from_env() -> Result<Option<Self>> {
+        match env::var(VAULT_SECRETS_VAR) {
+            Ok(json) => {
+                Ok(Some(serde_json::from_str(&json).with_context(|| {
+                    format!("could not parse {}", VAULT_SECRETS_VAR)
+                })?))
+            }
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Collect the Vault secrets in `secrets`, if there are any. Fails if we
+    /// have Vault secrets but no `config`.
+    pub fn for_secrets(
+        config: Option<&VaultConfig>,
+        secrets: &[Secret],
+    ) -> Result<Option<Self>> {
+        let secrets = secrets
+            .iter()
+            .filter(|s| matches!(s, Secret::Vault { .. }))
+            .cloned()
+            .collect::<Vec<_>>();
+        if secrets.is_empty() {
+            return Ok(None);
+        }
+        let config = config.ok_or_else(|| {
+            format_err!("Vault secrets require `transform.vault` to be specified")
+        })?;
+        match (&config.role, &config.token_path) {
+            (Some(_), None) | (None, Some(_)) => {}
+            _ => {
+                return Err(format_err!(
+                "`transform.vault` must specify exactly one of `role` or `token_path`"
+            ))
+            }
+        }
+        Ok(Some(VaultSecrets {
+            config: config.to_owned(),
+            secrets,
+        }))
+    }
+
+    /// Fetch our secrets from Vault, returning a list of environment
+    /// variables and their values.
+    #[instrument(skip_all, level = "debug")]
+    pub async fn fetch(&self) -> Result<Vec<(String, String)>> {
+        let client = reqwest::Client::builder()
+            .timeout(VAULT_TIMEOUT)
+            .build()
+            .context("could not create Vault client")?;
+        let token = self.token(&client).await?;
+        let mut vars = vec![];
+        for secret in &self.secrets {
+            if let Secret::Vault {
+                vault_path,
+                field,
+                env_var,
+            } = secret
+            {
+                let url = self.url(vault_path);
+                let body = client
+                    .get(&url)
+                    .header("X-Vault-Token", &token)
+                    .send()
+                    .await
+                    .and_then(|resp| resp.error_for_status())
+                    .with_context(|| {
+                        format!("could not fetch Vault secret {}", vault_path)
+                    })?
+                    .json::<serde_json::Value>()
+                    .await
+                    .with_context(|| {
+                        format!("could not parse Vault secret {}", vault_path)
+                    })?;
+                let value = secret_field(&body, field).ok_or_else(|| {
+                    format_err!("Vault secret {} has no field {:?}", vault_path, field)
+                })?;
+                vars.push((env_var.to_owned(), value));
+            }
+        }
+        Ok(vars)
+    }
+
+    /// Get a Vault token, either from a file or by logging in.
+    async fn token(&self, client: &reqwest::Client) -> Result<String> {
+        if let Some(token_path) = &self.config.token_path {
+            let token = fs::read_to_string(token_path).with_context(|| {
+                format!("could not read Vault token {}", token_path)
+            })?;
+            return Ok(token.trim().to_owned());
+        }
+        let role = self
+            .config
+            .role
+            .as_ref()
+            .ok_or_else(|| format_err!("no Vault role specified"))?;
+        let jwt =
+            fs::read_to_string(SERVICE_ACCOUNT_TOKEN_PATH).with_context(|| {
+                format!("could not read {}", SERVICE_ACCOUNT_TOKEN_PATH)
+            })?;
+        let url = self.url(&format!(
+            "auth/{}/login",
+            self.config.auth_mount.trim_matches('/')
+        ));
+        let body = client
+            .post(&url)
+            .json(&serde_json::json!({ "role": role, "jwt": jwt.trim() }))
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .with_context(|| format!("could not log into Vault as {:?}", role))?
+            .json::<serde_json::Value>()
+            .await
+            .context("could not parse Vault login response")?;
+        body.pointer("/auth/client_token")
+            .and_then(|token| token.as_str())
+            .map(|token| token.to_owned())
+            .ok_or_else(|| format_err!("Vault login response had no client token"))
+    }
+
+    /// The URL of `path` in the Vault API.
+    fn url(&self, path: &str) -> String {
+        format!(
+            "{}/v1/{}",
+            self.config.address.trim_end_matches('/'),
+            path.trim_start_matches('/'),
+        )
+    }
+}
+
+/// Find `field` in a Vault secret. Version 2 KV secrets engines nest the
+/// secret's fields inside `data.data`, alongside `data.metadata`.
+fn secret_field(body: &serde_json::Value, field: &str) -> Option<String> {
+    let data = body.get("data")?;
+    let fields = match (data.get("data"), data.get("metadata")) {
+        (Some(fields), Some(_)) if fields.is_object() => fields,
+        _ => data,
+    };
+    match fields.get(field)? {
+        serde_json::Value::String(s) => Some(s.to_owned()),
+        other => Some(other.to_string()),
+    }
+}
+
+#[test]
+fn vault_secrets_require_config() {
+    let vault_secret = Secret::Vault {
+        vault_path: "secret/data/s3".to_owned(),
+        field: "access_key_id".to_owned(),
+        env_var: "AWS_ACCESS_KEY_ID".to_owned(),
+    };
+    let env_secret = Secret::Env {
+        name: "s3".to_owned(),
+        key: "AWS_SECRET_ACCESS_KEY".to_owned(),
+        env_var: "AWS_SECRET_ACCESS_KEY".to_owned(),
+        optional: false,
+    };
+    let mut config = VaultConfig {
+        address: "https://vault:8200/".to_owned(),
+        role: Some("falconeri".to_owned()),
+        auth_mount: "kubernetes".to_owned(),
+        token_path: None,
+    };
+
+    assert!(VaultSecrets::for_secrets(None, &[env_secret.clone()])
+        .unwrap()
+        .is_none());
+    assert!(VaultSecrets::for_secrets(None, &[vault_secret.clone()]).is_err());
+    let secrets =
+        VaultSecrets::for_secrets(Some(&config), &[env_secret, vault_secret.clone()])
+            .unwrap()
+            .unwrap();
+    assert_eq!(secrets.secrets, vec![vault_secret.clone()]);
+    assert_eq!(
+        secrets.url("secret/data/s3"),
+        "https://vault:8200/v1/secret/data/s3"
+    );
+
+    config.token_path = Some("/vault/secrets/token".to_owned());
+    assert!(VaultSecrets::for_secrets(Some(&config), &[vault_secret]).is_err());
+}
+
+#[test]
+fn vault_secret_fields_are_found() {
+    let kv1 = serde_json::json!({ "data": { "key": "v1" } });
+    assert_eq!(secret_field(&kv1, "key").as_deref(), Some("v1"));
+    let kv2 = serde_json::json!({
+        "data": { "data": { "key": "v2" }, "metadata": { "version": 3 } },
+    });
+    assert_eq!(secret_field(&kv2, "key").as_deref(), Some("v2"));
+    assert_eq!(secret_field(&kv2, "missing"), None);
+}
@@ -0,0 +1,132 @@
+//! Which versions of our clients and servers can talk to each other.
+//!
+//! We follow semver: clients and servers with the same major version should
+//! work together, although a client may not be able to use features which are
+//! newer than the server. Servers also advertise the oldest client they
+//! support, so that we can drop support for old pre-release clients.
+
+use semver::{Version, VersionReq};
+
+use crate::{falconeri_common_version, prelude::*};
+
+/// The oldest client which this version of `falconerid` supports. Bump this
+/// when making a breaking change to an existing endpoint.
+pub const MIN_CLIENT_VERSION: &str = "2.0.0-alpha.5";
+
+/// Which client versions does this server support?
+pub fn supported_client_versions() -> VersionReq {
+    let ours = falconeri_common_version();
+    format!(">={}, <{}.0.0", MIN_CLIENT_VERSION, ours.major + 1)
+        .parse::<VersionReq>()
+        .expect("could not parse built-in version requirement")
+}
+
+/// Can a client and a server work together?
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Compatibility {
+    /// The versions are compatible.
+    Compatible,
+    /// The versions should work together, but some features may not be
+    /// available. Contains a warning message.
+    Skewed(String),
+    /// The versions can't work together. Contains an error message.
+    Incompatible(String),
+}
+
+impl Compatibility {
+    /// Check whether `client` can talk to `server`. If the server advertised
+    /// the client versions it supports, we trust it. Otherwise, we require
+    /// the same major version.
+    pub fn check(
+        client: &Version,
+        server: &Version,
+        supported_clients: Option<&VersionReq>,
+    ) -> Compatibility {
+        let supported = match supported_clients {
+            Some(req) => req.matches(client),
+            None => client.major == server.major,
+        };
+        if !supported {
+            let range = supported_clients
+                .map(|req| format!(" (it supports clients {})", req))
+                .unwrap_or_default();
+            Compatibility::Incompatible(format!(
+                "falconeri {} cannot talk to falconerid {}{}; please install a matching version of falconeri, or upgrade falconerid",
+                client, server, range,
+            ))
+        } else if client.major != server.major
+            || client.minor != server.minor
+            || client.pre != server.pre
+        {
+            Compatibility::Skewed(format!(
+                "falconeri {} is talking to falconerid {}; this should work, but some features may not be available",
+                client, server,
+            ))
+        } else {
+            Compatibility::Compatible
+        }
+    }
+
+    /// Log any warnings, and convert incompatibilities into errors.
+    pub fn into_result(self) -> Result<()> {
+        match self {
+            Compatibility::Compatible => Ok(()),
+            Compatibility::Skewed(warning) => {
+                warn!("{}", warning);
+                Ok(())
+            }
+            Compatibility::Incompatible(message) => Err(format_err!("{}", message)),
+        }
+    }
+}
+
+#[test]
+fn check_compatibility() {
+    let v = |s: &str| s.parse::<Version>().unwrap();
+    let req = |s: &str| s.parse::<VersionReq>().unwrap();
+    let supported = req(">=2.0.0-alpha.5, <3.0.0");
+
+    assert_eq!(
+        Compatibility::check(&v("2.1.0"), &v("2.1.3"), Some(&supported)),
+        Compatibility::Compatible,
+    );
+    assert!(matches!(
+        Compatibility::check(&v("2.0.0"), &v("2.1.0"), Some(&supported)),
+        Compatibility::Skewed(_),
+    ));
+    assert!(matches!(
+        Compatibility::check(
+            &v("2.0.0-alpha.6"),
+            &v("2.0.0-alpha.5"),
+            Some(&supported)
+        ),
+        Compatibility::Skewed(_),
+    ));
+    assert!(matches!(
+        Compatibility::check(
+            &v("2.0.0-alpha.4"),
+            &v("2.0.0-alpha.5"),
+            Some(&supported)
+        ),
+        Compatibility::Incompatible(_),
+    ));
+    assert!(matches!(
+        Compatibility::check(&v("3.0.0"), &v("2.1.0"), Some(&supported)),
+        Compatibility::Incompatible(_),
+    ));
+
+    // Old servers don't tell us what they support.
+    assert!(matches!(
+        Compatibility::check(&v("2.1.0"), &v("2.0.0"), None),
+        Compatibility::Skewed(_),
+    ));
+    assert!(matches!(
+        Compatibility::check(&v("2.0.0"), &v("1.0.0"), None),
+        Compatibility::Incompatible(_),
+    ));
+}
+
+#[test]
+fn we_support_ourselves() {
+    assert!(supported_client_versions().matches(&falconeri_common_version()));
+}
@@ -7,25 +7,154 @@
 //!
 //! Using PostgreSQL to store state is one of the simplest ways to build a
 //! medium-reliability, small-scale distributed job system.
+//!
+//! To avoid duplicate work, each babysitter tries to take a PostgreSQL
+//! advisory lock before sweeping, and only the one which holds it (the
+//! "leader") actually sweeps. The lock belongs to the leader's database
+//! session, so if the leader dies, PostgreSQL releases the lock and another
+//! babysitter takes over on its next sweep. But our sweeps must still be safe
+//! to run concurrently, because two babysitters may briefly overlap while
+//! PostgreSQL notices that an old leader has vanished.
 
-use std::{panic::AssertUnwindSafe, process, time::Duration};
+use std::{
+    fmt::Write as _,
+    panic::AssertUnwindSafe,
+    process,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
 use falconeri_common::{
-    chrono, db,
-    diesel_async::{scoped_futures::ScopedFutureExt, AsyncConnection},
+    cast, chrono, db,
+    diesel::sql_types::{BigInt, Bool},
+    diesel_async::{scoped_futures::ScopedFutureExt, AsyncConnection, RunQueryDsl},
     futures_util::FutureExt,
-    kubernetes::get_all_job_names,
+    kubernetes::{get_all_job_names, get_worker_image_digests, set_job_parallelism},
     prelude::*,
+    storage::CloudStorage,
 };
 
+use crate::{
+    output_manifest::write_output_manifest,
+    start_job::{job_pipeline_spec, stop_batch_job},
+};
+
+/// How long should we wait between babysitter sweeps?
+const SWEEP_INTERVAL: Duration = Duration::from_secs(2 * 60);
+
+/// How long can a job stay in `Status::Creating` without making progress before
+/// we assume that the `falconerid` creating it has died? Listing the inputs of
+/// a very large job can take a while, so this is generous.
+const STALLED_CREATION_TIMEOUT_MINUTES: i64 = 60;
+
+/// Environment variable which tells us to stop giving a job's datums to pods
+/// on nodes which are failing far more of them than other nodes.
+const EXCLUDE_FAILING_NODES_VAR: &str = "FALCONERID_EXCLUDE_FAILING_NODES";
+
+/// How many datums stored before we started compressing output should we
+/// compress in each transaction?
+const COMPRESS_BATCH_SIZE: i64 = 100;
+
+/// How long should each sweep spend compressing older datum output? We keep
+/// compressing batches until we run out of datums or time.
+const COMPRESS_TIME_BUDGET: Duration = Duration::from_secs(30);
+
+/// The key of the PostgreSQL advisory lock held by the babysitter leader. This
+/// is arbitrary, but it must be the same in every `falconerid`. (It spells
+/// "falconer" in ASCII.)
+const LEADER_LOCK_KEY: i64 = 0x6661_6c63_6f6e_6572;
+
+/// Records when the babysitter last started a sweep, so that our readiness
+/// probe can detect a babysitter which has hung or died, and whether it's
+/// currently the leader.
+#[derive(Clone, Debug)]
+pub struct BabysitterHeartbeat {
+    /// When the babysitter last checked in.
+    last_beat: Arc<Mutex<Instant>>,
+    /// Does this babysitter currently hold the leader lock?
+    is_leader: Arc<AtomicBool>,
+}
+
+impl BabysitterHeartbeat {
+    /// Create a new heartbeat, starting now.
+    pub(crate) fn new() -> Self {
+        Self {
+            last_beat: Arc::new(Mutex::new(Instant::now())),
+            is_leader: Arc::default(),
+        }
+    }
+
+    /// Record that the babysitter is still alive, and whether it's the
+    /// leader. Babysitters which aren't the leader still count as alive,
+    /// because they're ready to take over.
+    fn beat(&self, is_leader: bool) {
+        *self
+            .last_beat
+            .lock()
+            .expect("babysitter heartbeat lock poisoned") = Instant::now();
+        self.is_leader.store(is_leader, Ordering::Relaxed);
+    }
+
+    /// Has the babysitter checked in recently? We allow a generous margin,
+    /// because a single sweep may take a while on a busy cluster.
+    pub fn is_alive(&self) -> bool {
+        let last = *self
+            .last_beat
+            .lock()
+            .expect("babysitter heartbeat lock poisoned");
+        last.elapsed() < 3 * SWEEP_INTERVAL
+    }
+
+    /// Write our metrics in Prometheus text format.
+    pub fn render_metrics(&self, out: &mut String) {
+        let name = "falconerid_babysitter_leader";
+        writeln!(
+            out,
+            "# HELP {} 1 if this babysitter is the leader which sweeps jobs.",
+            name
+        )
+        .unwrap();
+        writeln!(out, "# TYPE {} gauge", name).unwrap();
+        writeln!(
+            out,
+            "{} {}",
+            name,
+            u8::from(self.is_leader.load(Ordering::Relaxed))
+        )
+        .unwrap();
+    }
+}
+
+/// Result of `pg_try_advisory_lock`.
+#[derive(QueryableByName)]
+struct LockResult {
+    #[diesel(sql_type = Bool)]
+    locked: bool,
+}
+
 /// Spawn a tokio task and run the babysitter in it. This should run indefinitely.
+///
+/// Returns the task handle and a heartbeat which can be used to check on the
+/// babysitter. If `skip_kubernetes` is true, we don't delete the Kubernetes
+/// jobs of jobs which we stop.
 #[instrument(skip_all, level = "trace")]
-pub fn start_babysitter(pool: db::AsyncPool) -> tokio::task::JoinHandle<()> {
-    tokio::spawn(async move {
+pub fn start_babysitter(
+    pool: db::AsyncPool,
+    skip_kubernetes: bool,
+) -> (tokio::task::JoinHandle<()>, BabysitterHeartbeat) {
+    let heartbeat = BabysitterHeartbeat::new();
+    let task_heartbeat = heartbeat.clone();
+    let handle = tokio::spawn(async move {
         // If this task panics, attempt to shut down the entire process, forcing
         // Kubernetes to make noise and restart this `falconerid`. The last thing we
         // want is for the babysitter to silently fail.
-        let result = AssertUnwindSafe(run_babysitter(pool)).catch_unwind().await;
+        let result =
+            AssertUnwindSafe(run_babysitter(pool, task_heartbeat, skip_kubernetes))
+                .catch_unwind()
+                .await;
 
         if let Err(err) = result {
             // Extract information about the panic, if it's one of the common types.
@@ -46,36 +175,151 @@ pub fn start_babysitter(pool: db::AsyncPool) -> tokio::task::JoinHandle<()> {
             eprintln!("BABYSITTER PANIC, aborting: {}", msg);
             process::abort();
         }
-    })
+    });
+    (handle, heartbeat)
 }
 
 /// Actually run the babysitter.
 #[instrument(skip_all, level = "trace")]
-async fn run_babysitter(pool: db::AsyncPool) {
+async fn run_babysitter(
+    pool: db::AsyncPool,
+    heartbeat: BabysitterHeartbeat,
+    skip_kubernetes: bool,
+) {
+    // While we're the leader, we keep the connection which holds our lock,
+    // and use it for our sweeps.
+    let mut leader_conn: Option<db::AsyncPooledConn> = None;
     loop {
         // We always want to retry all errors. This way, if PostgreSQL is still
         // starting up, or if someone retarted it, we'll eventually recover.
-        if let Err(err) = check_running_jobs(&pool).await {
-            error!("error checking running jobs (will retry later): {:?}", err);
+        if leader_conn.is_none() {
+            match try_to_become_leader(&pool).await {
+                Ok(conn) => leader_conn = conn,
+                Err(err) => {
+                    error!(
+                        "error electing babysitter leader (will retry later): {:?}",
+                        err
+                    )
+                }
+            }
+        }
+        heartbeat.beat(leader_conn.is_some());
+
+        if let Some(conn) = leader_conn.as_mut() {
+            if let Err(err) = check_running_jobs(conn, skip_kubernetes).await {
+                error!("error checking running jobs (will retry later): {:?}", err);
+
+                // If we lost our database session, we also lost our lock, so
+                // close the connection and run for leader again next time.
+                if !connection_is_alive(conn).await {
+                    warn!("lost babysitter leader connection");
+                    if let Some(conn) = leader_conn.take() {
+                        drop(db::AsyncPooledConn::take(conn));
+                    }
+                }
+            }
+        } else {
+            debug!("another babysitter is the leader, skipping sweep");
         }
-        tokio::time::sleep(Duration::from_secs(2 * 60)).await;
+        tokio::time::sleep(SWEEP_INTERVAL).await;
     }
 }
 
-/// Check our running jobs for various situations we might might need to deal
-/// with.
+/// Try to take the babysitter leader lock. If we succeed, return the
+/// connection which holds it, which must be kept open for as long as we want
+/// to remain the leader.
 #[instrument(skip_all, level = "debug")]
-async fn check_running_jobs(pool: &db::AsyncPool) -> Result<()> {
+async fn try_to_become_leader(
+    pool: &db::AsyncPool,
+) -> Result<Option<db::AsyncPooledConn>> {
     let mut conn = pool
         .get()
         .await
         .context("could not get connection from pool")?;
-    check_for_finished_and_vanished_jobs(&mut conn).await?;
-    check_for_zombie_datums(&mut conn).await?;
+    let result = diesel::sql_query("SELECT pg_try_advisory_lock($1) AS locked")
+        .bind::<BigInt, _>(LEADER_LOCK_KEY)
+        .get_result::<LockResult>(&mut conn)
+        .await
+        .context("could not try to take babysitter leader lock")?;
+    if result.locked {
+        info!("this babysitter is now the leader");
+        Ok(Some(conn))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Can we still talk to the database using `conn`?
+async fn connection_is_alive(conn: &mut AsyncPgConnection) -> bool {
+    diesel::sql_query("SELECT 1").execute(conn).await.is_ok()
+}
+
+/// Check our running jobs for various situations we might might need to deal
+/// with.
+#[instrument(skip_all, level = "debug")]
+async fn check_running_jobs(
+    conn: &mut AsyncPgConnection,
+    skip_kubernetes: bool,
+) -> Result<()> {
+    check_for_stalled_job_creation(conn).await?;
+    check_for_finished_and_vanished_jobs(conn, skip_kubernetes).await?;
+    check_for_zombie_datums(conn, skip_kubernetes).await?;
     // Note that any datums marked as `Status::Error` by
     // `check_for_zombie_datums` above may then be retried normally by
     // `check_for_datums_which_can_be_rerun` (if they're eligible).
-    check_for_datums_which_can_be_rerun(&mut conn).await
+    check_for_datums_which_can_be_rerun(conn).await?;
+    check_for_jobs_to_scale_down(conn).await?;
+    check_for_failing_nodes(conn).await?;
+    check_for_unrecorded_image_digests(conn).await?;
+    check_for_unwritten_output_manifests(conn).await?;
+    compress_old_datum_text(conn).await
+}
+
+/// Check for jobs which are still being created, but whose `falconerid` seems
+/// to have died (for example, because it was restarted during a deploy).
+#[instrument(skip_all, level = "debug")]
+async fn check_for_stalled_job_creation(conn: &mut AsyncPgConnection) -> Result<()> {
+    let jobs = Job::find_by_status(Status::Creating, conn).await?;
+    for mut job in jobs {
+        conn.transaction(|conn| {
+            async move {
+                // We may be racing the task that's creating this job, so lock
+                // it and check again.
+                job.lock_for_update(conn).await?;
+                let cutoff = Utc::now().naive_utc()
+                    - chrono::Duration::minutes(STALLED_CREATION_TIMEOUT_MINUTES);
+                if job.status == Status::Creating && job.updated_at < cutoff {
+                    warn!(
+                        "job {} has made no progress since {}, setting status to 'error'",
+                        job.job_name, job.updated_at
+                    );
+                    let message = format!(
+                        "job made no progress while being created since {}, marked as error",
+                        job.updated_at
+                    );
+                    // Don't leave behind the datums it already inserted.
+                    job.delete_datums(conn).await?;
+                    job.mark_as_error_with_message(
+                        "falconerid stopped while creating this job",
+                        conn,
+                    )
+                    .await?;
+                    JobEvent::record(
+                        job.id,
+                        None,
+                        JobEventKind::JobCreationStalled,
+                        &message,
+                        conn,
+                    )
+                    .await?;
+                }
+                Ok::<_, Error>(())
+            }
+            .scope_boxed()
+        })
+        .await?;
+    }
+    Ok(())
 }
 
 /// Check for jobs which should already be marked as finished, or which have
@@ -83,12 +327,15 @@ async fn check_running_jobs(pool: &db::AsyncPool) -> Result<()> {
 #[instrument(skip_all, level = "debug")]
 async fn check_for_finished_and_vanished_jobs(
     conn: &mut AsyncPgConnection,
+    skip_kubernetes: bool,
 ) -> Result<()> {
-    let jobs = Job::find_by_status(Status::Running, conn).await?;
+    let mut jobs = Job::find_by_status(Status::Running, conn).await?;
+    jobs.extend(Job::find_by_status(Status::Streaming, conn).await?);
     let all_job_names = get_all_job_names().await?;
     for mut job in jobs {
         let all_job_names = &all_job_names;
-        conn.transaction(|conn| {
+        let job_name = job.job_name.clone();
+        let stopped_early = conn.transaction(|conn| {
             async move {
                 // We may be racing a second copy of the babysitter here, or a
                 // request from a worker, so start a transaction, take a lock, and
@@ -106,26 +353,49 @@ async fn check_for_finished_and_vanished_jobs(
                 // If the job has been running for a while, but it has no associated
                 // Kubernetes job, assume that either the job has exceeded
                 // `ttlAfterSecondsFinished`, or was manually deleted by someone.
+                //
+                // We check `updated_at` instead of `created_at`, because large
+                // jobs may spend a long time in `Status::Creating` before we
+                // mark them as running and create the Kubernetes job.
                 let cutoff = Utc::now().naive_utc() - chrono::Duration::minutes(15);
-                if job.status == Status::Running
-                    && job.created_at < cutoff
+                if job.status.is_active()
+                    && job.updated_at < cutoff
                     && !all_job_names.contains(&job.job_name)
                 {
-                    warn!("job {} is running but has no corresponding Kubernetes job, setting status to 'error'", job.job_name);
+                    warn!("job {} is {} but has no corresponding Kubernetes job, setting status to 'error'", job.job_name, job.status);
+                    let message = format!(
+                        "Kubernetes job disappeared while job was {}, marked as error",
+                        job.status
+                    );
                     job.mark_as_error(conn).await?;
+                    JobEvent::record(
+                        job.id,
+                        None,
+                        JobEventKind::JobVanished,
+                        &message,
+                        conn,
+                    )
+                    .await?;
                 }
-                Ok::<_, Error>(())
+                Ok::<_, Error>(job.was_stopped_early())
             }
             .scope_boxed()
         })
         .await?;
+        if stopped_early {
+            let job = Job::find_by_job_name(&job_name, conn).await?;
+            stop_batch_job(&job, skip_kubernetes).await?;
+        }
     }
     Ok(())
 }
 
 /// Check for datums which claim to be running in a pod that no longer exists.
 #[instrument(skip_all, level = "debug")]
-async fn check_for_zombie_datums(conn: &mut AsyncPgConnection) -> Result<()> {
+async fn check_for_zombie_datums(
+    conn: &mut AsyncPgConnection,
+    skip_kubernetes: bool,
+) -> Result<()> {
     let zombies = Datum::zombies(conn).await?;
     for mut zombie in zombies {
         let zombie_id = zombie.id;
@@ -141,14 +411,31 @@ async fn check_for_zombie_datums(conn: &mut AsyncPgConnection) -> Result<()> {
                         "found zombie datum {}, which was supposed to be running on pod {:?}",
                         zombie.id, zombie.pod_name
                     );
+                    let report = DatumReport {
+                        output: "(did not capture output)".to_owned(),
+                        ..DatumReport::default()
+                    };
                     zombie
                         .mark_as_error(
-                            "(did not capture output)",
+                            &report,
                             "worker pod disappeared while working on datum",
                             "(no backtrace available)",
+                            None,
                             conn,
                         )
                         .await?;
+                    let message = format!(
+                        "worker pod {} disappeared while working on datum, marked as error",
+                        zombie.pod_name.as_deref().unwrap_or("(unknown)")
+                    );
+                    JobEvent::record(
+                        zombie.job_id,
+                        Some(zombie.id),
+                        JobEventKind::DatumReaped,
+                        &message,
+                        conn,
+                    )
+                    .await?;
                 } else {
                     warn!("someone beat us to zombie datum {}", zombie.id);
                 }
@@ -162,6 +449,9 @@ async fn check_for_zombie_datums(conn: &mut AsyncPgConnection) -> Result<()> {
         // moved into the transaction.
         let mut job = Job::find(job_id, conn).await?;
         job.update_status_if_done(conn).await?;
+        if job.was_stopped_early() {
+            stop_batch_job(&job, skip_kubernetes).await?;
+        }
         debug!("finished processing zombie datum {}", zombie_id);
     }
     Ok(())
@@ -174,7 +464,26 @@ async fn check_for_datums_which_can_be_rerun(
     conn: &mut AsyncPgConnection,
 ) -> Result<()> {
     let rerunable_datums = Datum::rerunable(conn).await?;
+    let mut jobs = HashMap::new();
     for mut datum in rerunable_datums {
+        // Look up how long this datum's job wants us to wait between retries.
+        let job_id = datum.job_id;
+        if !jobs.contains_key(&job_id) {
+            let job = Job::find(job_id, conn).await?;
+            jobs.insert(job_id, job);
+        }
+        let job = &jobs[&job_id];
+
+        // Delete any files which the failed attempt left in our egress bucket.
+        // We do this before rescheduling the datum, so that we can't delete
+        // files uploaded by the next attempt.
+        if let Err(err) = delete_orphaned_output_files(job, &datum, conn).await {
+            warn!(
+                "could not delete output files of datum {}: {:?}",
+                datum.id, err
+            );
+        }
+
         // We may be racing a second copy of the babysitter here, so start a
         // transaction, take a lock, and double-check that we're still eligible
         // for a re-run.
@@ -189,28 +498,36 @@ async fn check_for_datums_which_can_be_rerun(
                         datum.attempted_run_count,
                         datum.maximum_allowed_run_count
                     );
-                    datum.mark_as_eligible_for_rerun(conn).await?;
+                    let retry_delay = job.retry_delay(datum.attempted_run_count);
+                    let message = match retry_delay {
+                        Some(delay) => format!(
+                            "rescheduled failed datum after try {}/{}, to run in {}s",
+                            datum.attempted_run_count,
+                            datum.maximum_allowed_run_count,
+                            delay.num_seconds()
+                        ),
+                        None => format!(
+                            "rescheduled failed datum after try {}/{}",
+                            datum.attempted_run_count, datum.maximum_allowed_run_count
+                        ),
+                    };
+                    datum.mark_as_eligible_for_rerun(retry_delay, conn).await?;
+                    JobEvent::record(
+                        datum.job_id,
+                        Some(datum.id),
+                        JobEventKind::DatumRescheduled,
+                        &message,
+                        conn,
+                    )
+                    .await?;
                 } else {
                     warn!("someone beat us to rerunable datum {}", datum.id);
                 }
 
                 // Remove `OutputFile` records for this datum, so we can upload the
-                // same output files again.
-                //
-                // TODO: Unfortunately, there's an issue here. It takes one of two
-                // forms:
-                //
-                // 1. Workers use deterministic file names. In this case, we
-                //    _should_ be fine, because we'll just overwrite any files we
-                //    did manage to upload.
-                // 2. Workers use random filenames. Here, there are two subcases: a.
-                //    We have successfully created an `OutputFile` record. b. We
-                //    have yet to create an `OutputFile` record.
-                //
-                // We need to fix (2b) by pre-creating all our `OutputFile` records
-                // _before_ uploading, and then updating them later to show that the
-                // output succeeded. Which them into case (2a). And then we can fix (2a)
-                // by deleting any S3/GCS files corresponding to `OutputFile::uri`.
+                // same output files again. Workers register each `OutputFile`
+                // before uploading it, so we've already deleted anything the
+                // failed attempt uploaded.
                 OutputFile::delete_for_datum(&datum, conn).await?;
                 Ok::<_, Error>(())
             }
@@ -220,3 +537,219 @@ async fn check_for_datums_which_can_be_rerun(
     }
     Ok(())
 }
+
+/// Delete the files uploaded by a failed attempt to process `datum`, using
+/// the `OutputFile` records which its worker registered before uploading.
+///
+/// Workers which use deterministic file names would overwrite these files
+/// anyway, but workers which use random file names would otherwise leave
+/// stale files in the egress bucket. We skip any file which another datum
+/// also registered, and any file outside of the job's egress URI.
+#[instrument(skip_all, fields(datum = %datum.id), level = "debug")]
+async fn delete_orphaned_output_files(
+    job: &Job,
+    datum: &Datum,
+    conn: &mut AsyncPgConnection,
+) -> Result<()> {
+    // Make sure nobody has rescheduled this datum since we found it, or we
+    // might delete files uploaded by the next attempt.
+    if !Datum::find(datum.id, conn).await?.is_rerunable() {
+        return Ok(());
+    }
+    let uris = OutputFile::orphaned_uris_for_datum(datum, conn).await?;
+    if uris.is_empty() {
+        return Ok(());
+    }
+    let secrets = job_pipeline_spec(job)?.transform.secrets;
+    let storage = <dyn CloudStorage>::for_uri(&job.egress_uri, &secrets).await?;
+    for uri in uris {
+        if !uri.starts_with(&job.egress_uri) {
+            warn!("not deleting {}, which is outside {}", uri, job.egress_uri);
+            continue;
+        }
+        debug!("deleting output file {} of failed datum {}", uri, datum.id);
+        storage.delete(&uri).await?;
+    }
+    Ok(())
+}
+
+/// Check for autoscaling jobs which have more workers than remaining datums,
+/// and reduce their Kubernetes parallelism so that idle workers don't hold on
+/// to nodes at the end of the job. If we can't scale down one job, we try
+/// again on our next pass, without holding up our other checks.
+#[instrument(skip_all, level = "debug")]
+async fn check_for_jobs_to_scale_down(conn: &mut AsyncPgConnection) -> Result<()> {
+    let jobs = Job::find_by_status(Status::Running, conn).await?;
+    for mut job in jobs {
+        if !job.autoscale_parallelism {
+            continue;
+        }
+        if let Err(err) = scale_down_job_if_needed(&mut job, conn).await {
+            warn!("could not scale down job {}: {:?}", job.job_name, err);
+        }
+    }
+    Ok(())
+}
+
+/// Reduce the parallelism of `job` if it has more workers than remaining
+/// datums.
+#[instrument(skip_all, fields(job = %job.id), level = "debug")]
+async fn scale_down_job_if_needed(
+    job: &mut Job,
+    conn: &mut AsyncPgConnection,
+) -> Result<()> {
+    let status_counts = job.datum_status_counts(conn).await?;
+    if let Some(parallelism) = job.scaled_down_parallelism(&status_counts) {
+        // If a second copy of the babysitter is doing the same thing, we
+        // may both patch the job, but we'll compute nearly the same value,
+        // and we never scale back up.
+        info!(
+            "scaling job {} down from {:?} to {} workers",
+            job.job_name, job.target_parallelism, parallelism
+        );
+        let message = match job.target_parallelism {
+            Some(old) => {
+                format!("scaled down from {} to {} workers", old, parallelism)
+            }
+            None => format!("scaled down to {} workers", parallelism),
+        };
+        set_job_parallelism(&job.job_name, parallelism).await?;
+        job.set_target_parallelism(parallelism, conn).await?;
+        JobEvent::record(job.id, None, JobEventKind::JobScaledDown, &message, conn)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Should we stop giving datums to pods on failing nodes? See
+/// [`EXCLUDE_FAILING_NODES_VAR`].
+fn exclude_failing_nodes() -> bool {
+    std::env::var(EXCLUDE_FAILING_NODES_VAR)
+        .map(|v| v == "true" || v == "1")
+        .unwrap_or(false)
+}
+
+/// Check for nodes which are failing far more of a running job's datums than
+/// other nodes, and if we've been asked to, stop giving that job's datums to
+/// pods on those nodes. We leave cordoning the node to a human, because the
+/// node might be fine for other jobs.
+#[instrument(skip_all, level = "debug")]
+async fn check_for_failing_nodes(conn: &mut AsyncPgConnection) -> Result<()> {
+    if !exclude_failing_nodes() {
+        return Ok(());
+    }
+    let mut jobs = Job::find_by_status(Status::Running, conn).await?;
+    jobs.extend(Job::find_by_status(Status::Streaming, conn).await?);
+    for mut job in jobs {
+        let nodes = job.node_failure_counts(conn).await?;
+        for node in NodeFailureCount::anomalous(&nodes) {
+            if job.excluded_node_names.contains(&node.node_name) {
+                continue;
+            }
+            warn!(
+                "excluding node {} from job {}, because it failed {} datums and finished {}",
+                node.node_name, job.job_name, node.error_count, node.done_count
+            );
+            let message = format!(
+                "stopped giving datums to node {}, which failed {} datums and finished {}",
+                node.node_name, node.error_count, node.done_count
+            );
+            let job = &mut job;
+            conn.transaction(|conn| {
+                async move {
+                    // A second copy of the babysitter may have beaten us to it.
+                    job.lock_for_update(conn).await?;
+                    if job.excluded_node_names.contains(&node.node_name) {
+                        return Ok(());
+                    }
+                    job.exclude_node(&node.node_name, conn).await?;
+                    JobEvent::record(
+                        job.id,
+                        None,
+                        JobEventKind::NodeExcluded,
+                        &message,
+                        conn,
+                    )
+                    .await
+                }
+                .scope_boxed()
+            })
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Record which image digest each active job is running, once one of its
+/// worker pods has started. We only look at pods when some job needs a digest.
+#[instrument(skip_all, level = "debug")]
+async fn check_for_unrecorded_image_digests(
+    conn: &mut AsyncPgConnection,
+) -> Result<()> {
+    let mut jobs = Job::find_by_status(Status::Running, conn).await?;
+    jobs.extend(Job::find_by_status(Status::Streaming, conn).await?);
+    jobs.retain(|job| job.image_digest.is_none());
+    if jobs.is_empty() {
+        return Ok(());
+    }
+    let digests = get_worker_image_digests().await?;
+    for mut job in jobs {
+        if let Some(digest) = digests.get(&job.job_name) {
+            debug!("job {} is running image {}", job.job_name, digest);
+            job.set_image_digest(digest, conn).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Write output manifests for jobs which have finished successfully. If we
+/// can't write a manifest, we try again on our next pass, without holding up
+/// our other checks.
+#[instrument(skip_all, level = "debug")]
+async fn check_for_unwritten_output_manifests(
+    conn: &mut AsyncPgConnection,
+) -> Result<()> {
+    let jobs = Job::find_needing_output_manifests(conn).await?;
+    for mut job in jobs {
+        if let Err(err) = write_output_manifest(&mut job, conn).await {
+            warn!(
+                "could not write output manifest for job {}: {:?}",
+                job.job_name, err
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Compress datum output and backtraces which were stored before we started
+/// compressing them, for up to [`COMPRESS_TIME_BUDGET`].
+#[instrument(skip_all, level = "debug")]
+async fn compress_old_datum_text(conn: &mut AsyncPgConnection) -> Result<()> {
+    let started = Instant::now();
+    let mut total = 0;
+    while started.elapsed() < COMPRESS_TIME_BUDGET {
+        let count = Datum::compress_stored_text(COMPRESS_BATCH_SIZE, conn).await?;
+        total += count;
+        if cast::i64(count)? < COMPRESS_BATCH_SIZE {
+            break;
+        }
+    }
+    if total > 0 {
+        debug!("compressed the output of {} older datums", total);
+    }
+    Ok(())
+}
+
+#[test]
+fn heartbeat_reports_leadership() {
+    let heartbeat = BabysitterHeartbeat::new();
+    assert!(heartbeat.is_alive());
+    let mut out = String::new();
+    heartbeat.render_metrics(&mut out);
+    assert!(out.contains("falconerid_babysitter_leader 0\n"));
+
+    heartbeat.beat(true);
+    let mut out = String::new();
+    heartbeat.render_metrics(&mut out);
+    assert!(out.contains("falconerid_babysitter_leader 1\n"));
+}
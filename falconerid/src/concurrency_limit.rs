@@ -0,0 +1,124 @@
+//! Limit how many worker requests we handle at once.
+//!
+//! When hundreds of workers finish datums at the same time, their requests
+//! can use up all our database connections, and everybody times out. So we
+//! only handle a limited number of worker requests at once, and reject the
+//! rest with `503 Service Unavailable` and a `Retry-After` header. Workers
+//! back off and retry these, so this smooths out bursts of traffic.
+
+use std::{
+    env,
+    fmt::Write as _,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use axum::{
+    extract::{Request, State},
+    http::{header::RETRY_AFTER, HeaderValue, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use falconeri_common::{prelude::*, rest_api::FalconeriApiError};
+use tokio::sync::Semaphore;
+
+use crate::util::error_response;
+
+/// Environment variable specifying how many worker requests we may handle at
+/// once. Set to 0 to disable this limit.
+const MAX_REQUESTS_VAR: &str = "FALCONERID_MAX_WORKER_REQUESTS";
+
+/// Default number of worker requests we may handle at once. This is twice
+/// our default database pool size, so that a few requests can wait for a
+/// connection, but not enough to time out.
+const DEFAULT_MAX_REQUESTS: usize = 64;
+
+/// Limits how many requests we handle at once.
+#[derive(Clone, Debug)]
+pub struct ConcurrencyLimiter {
+    /// One permit for each request we may handle, or `None` if unlimited.
+    permits: Option<Arc<Semaphore>>,
+    /// The number of requests we've rejected.
+    rejections: Arc<AtomicU64>,
+}
+
+impl ConcurrencyLimiter {
+    /// Create a limiter which allows `max_requests` at once. If
+    /// `max_requests` is 0, all requests are allowed.
+    pub fn new(max_requests: usize) -> Self {
+        ConcurrencyLimiter {
+            permits: (max_requests > 0)
+                .then(|| Arc::new(Semaphore::new(max_requests))),
+            rejections: Arc::default(),
+        }
+    }
+
+    /// Load our limit from the environment, falling back to the default.
+    pub fn from_env() -> Result<Self> {
+        let max_requests = match env::var(MAX_REQUESTS_VAR) {
+            Ok(value) => value.trim().parse::<usize>().with_context(|| {
+                format!("could not parse {}={:?}", MAX_REQUESTS_VAR, value)
+            })?,
+            Err(_) => DEFAULT_MAX_REQUESTS,
+        };
+        Ok(Self::new(max_requests))
+    }
+
+    /// Write our metrics in Prometheus text format.
+    pub fn render_metrics(&self, out: &mut String) {
+        let name = "falconerid_concurrency_limited_requests_total";
+        writeln!(
+            out,
+            "# HELP {} Worker requests rejected because we were too busy.",
+            name
+        )
+        .unwrap();
+        writeln!(out, "# TYPE {} counter", name).unwrap();
+        writeln!(out, "{} {}", name, self.rejections.load(Ordering::Relaxed)).unwrap();
+    }
+}
+
+/// Axum middleware which applies our concurrency limit. Install this using
+/// `axum::middleware::from_fn_with_state`.
+pub async fn limit_concurrency(
+    State(limiter): State<ConcurrencyLimiter>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(permits) = &limiter.permits else {
+        return next.run(request).await;
+    };
+    match permits.clone().try_acquire_owned() {
+        Ok(_permit) => next.run(request).await,
+        Err(_) => {
+            limiter.rejections.fetch_add(1, Ordering::Relaxed);
+            debug!(path = %request.uri().path(), "too busy for request");
+            let mut response = error_response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                FalconeriApiError::Unavailable,
+                "too many worker requests in progress".to_owned(),
+            );
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from_static("1"));
+            response
+        }
+    }
+}
+
+#[test]
+fn concurrency_limiter_counts_permits() {
+    let limiter = ConcurrencyLimiter::new(1);
+    let permits = limiter.permits.clone().unwrap();
+    let permit = permits.clone().try_acquire_owned().unwrap();
+    assert!(permits.clone().try_acquire_owned().is_err());
+    drop(permit);
+    assert!(permits.try_acquire_owned().is_ok());
+
+    assert!(ConcurrencyLimiter::new(0).permits.is_none());
+    let mut out = String::new();
+    limiter.render_metrics(&mut out);
+    assert!(out.contains("falconerid_concurrency_limited_requests_total 0"));
+}
@@ -0,0 +1,151 @@
+//! Checking out database connections, and reporting on our pool.
+//!
+//! When all our connections are busy, requests queue up waiting for one. If
+//! we let them wait forever, clients just see opaque timeouts. Instead, we
+//! give up after a deadline and return `503 Service Unavailable` with a
+//! `Retry-After` header, which workers know how to retry.
+
+use std::{
+    env,
+    fmt::Write as _,
+    result,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use falconeri_common::{db, prelude::*};
+
+use crate::util::FalconeridError;
+
+/// Environment variable specifying how many seconds a request may wait for a
+/// database connection.
+const CHECKOUT_TIMEOUT_VAR: &str = "FALCONERID_POOL_TIMEOUT_SECS";
+
+/// Default number of seconds a request may wait for a database connection.
+/// Workers time out after 30 seconds, so this needs to be a lot shorter.
+const DEFAULT_CHECKOUT_TIMEOUT_SECS: f64 = 5.0;
+
+/// Checks out connections from our pool, and keeps track of how often we
+/// couldn't get one.
+#[derive(Clone, Debug)]
+pub struct PoolMonitor {
+    /// How long to wait for a connection.
+    checkout_timeout: Duration,
+    /// The number of checkouts which timed out.
+    timeouts: Arc<AtomicU64>,
+}
+
+impl PoolMonitor {
+    /// Create a monitor which waits up to `checkout_timeout` for connections.
+    pub fn new(checkout_timeout: Duration) -> Self {
+        PoolMonitor {
+            checkout_timeout,
+            timeouts: Arc::default(),
+        }
+    }
+
+    /// Load our checkout timeout from the environment, falling back to the
+    /// default.
+    pub fn from_env() -> Result<Self> {
+        let secs = match env::var(CHECKOUT_TIMEOUT_VAR) {
+            Ok(value) => value.trim().parse::<f64>().with_context(|| {
+                format!("could not parse {}={:?}", CHECKOUT_TIMEOUT_VAR, value)
+            })?,
+            Err(_) => DEFAULT_CHECKOUT_TIMEOUT_SECS,
+        };
+        let checkout_timeout = Duration::try_from_secs_f64(secs)
+            .ok()
+            .filter(|timeout| !timeout.is_zero())
+            .ok_or_else(|| {
+                format_err!("{} must be a positive number", CHECKOUT_TIMEOUT_VAR)
+            })?;
+        Ok(Self::new(checkout_timeout))
+    }
+
+    /// Get a connection from `pool`, or fail with
+    /// [`FalconeridError::ServiceUnavailable`] if none is available before
+    /// our deadline.
+    pub async fn get(
+        &self,
+        pool: &db::AsyncPool,
+    ) -> result::Result<db::AsyncPooledConn, FalconeridError> {
+        match tokio::time::timeout(self.checkout_timeout, pool.get()).await {
+            Ok(Ok(conn)) => Ok(conn),
+            Ok(Err(err)) => Err(FalconeridError::Internal(format_err!(
+                "pool error: {}",
+                err
+            ))),
+            Err(_) => {
+                self.timeouts.fetch_add(1, Ordering::Relaxed);
+                let status = pool.status();
+                warn!(
+                    max_size = status.max_size,
+                    in_use = status.size - status.available,
+                    waiters = status.waiting,
+                    "database pool exhausted after waiting {:?}",
+                    self.checkout_timeout,
+                );
+                Err(FalconeridError::ServiceUnavailable(
+                    "no database connection available, please retry".to_owned(),
+                ))
+            }
+        }
+    }
+
+    /// Write metrics for `pool` in Prometheus text format.
+    pub fn render_metrics(&self, pool: &db::AsyncPool, out: &mut String) {
+        let status = pool.status();
+        let gauges = [
+            (
+                "falconerid_db_pool_max_size",
+                "Maximum number of database connections.",
+                status.max_size,
+            ),
+            (
+                "falconerid_db_pool_connections",
+                "Open database connections.",
+                status.size,
+            ),
+            (
+                "falconerid_db_pool_in_use",
+                "Database connections being used by requests.",
+                status.size - status.available,
+            ),
+            (
+                "falconerid_db_pool_waiters",
+                "Requests waiting for a database connection.",
+                status.waiting,
+            ),
+        ];
+        for (name, help, value) in gauges {
+            writeln!(out, "# HELP {} {}", name, help).unwrap();
+            writeln!(out, "# TYPE {} gauge", name).unwrap();
+            writeln!(out, "{} {}", name, value).unwrap();
+        }
+        let name = "falconerid_db_pool_timeouts_total";
+        writeln!(
+            out,
+            "# HELP {} Requests which gave up waiting for a database connection.",
+            name
+        )
+        .unwrap();
+        writeln!(out, "# TYPE {} counter", name).unwrap();
+        writeln!(out, "{} {}", name, self.timeouts.load(Ordering::Relaxed)).unwrap();
+    }
+}
+
+#[test]
+fn pool_metrics_describe_unused_pool() {
+    // Building a pool doesn't connect to anything.
+    let pool = db::async_pool_for_url(3, "postgres://localhost:1/falconeri").unwrap();
+    let monitor = PoolMonitor::new(Duration::from_secs(1));
+    let mut out = String::new();
+    monitor.render_metrics(&pool, &mut out);
+    assert!(out.contains("falconerid_db_pool_max_size 3\n"));
+    assert!(out.contains("falconerid_db_pool_in_use 0\n"));
+    assert!(out.contains("falconerid_db_pool_waiters 0\n"));
+    assert!(out.contains("falconerid_db_pool_timeouts_total 0\n"));
+}
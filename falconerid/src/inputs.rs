@@ -1,87 +1,180 @@
 //! Convert JSON `"input"` clauses to datums which will be assigned to workers.
 
-use std::{future::Future, pin::Pin};
+use std::{collections::HashSet, future::Future, pin::Pin};
 
 use falconeri_common::{
-    models::{NewDatum, NewInputFile},
-    pipeline::{Glob, Input},
+    inputs::{
+        atom_to_datums, cross_datums, uri_to_local_path, DatumData, InputFileData,
+    },
+    models::{NewDatum, NewInputFile, OutputFile},
+    pipeline::{Glob, Input, PartitionBy},
     prelude::*,
     secret::Secret,
-    storage::CloudStorage,
+    storage::{CloudStorage, ListedObject},
 };
 
-/// (Local helper type.) This is essentially just a `NewDatum` and a
-/// `Vec<NewInputFile>`, but in a more convenient format that works better with
-/// the algorithm in this file, so we don't need to carry around UUIDs
-/// everywhere.
-#[derive(Clone, Debug)]
-struct DatumData {
-    input_files: Vec<InputFileData>,
+/// Given an `Input` from a JSON pipeline spec, convert to an actual set of
+/// "datums" (work chunks) to be assigned to a worker.
+///
+/// Returns each datum along with its input files, so that callers can insert
+/// them into the database in batches.
+#[instrument(skip_all, fields(job_id = %job_id), level = "trace")]
+pub async fn input_to_datums(
+    secrets: &[Secret],
+    job_id: Uuid,
+    maximum_allowed_run_count: i32,
+    input: &Input,
+    conn: &mut AsyncPgConnection,
+) -> Result<Vec<(NewDatum, Vec<NewInputFile>)>> {
+    Ok(input_to_datums_helper(secrets, input, conn)
+        .await?
+        .into_iter()
+        .map(|datum_data| {
+            datum_data
+                .into_new_datum_and_input_files(job_id, maximum_allowed_run_count)
+        })
+        .collect())
 }
 
-impl DatumData {
-    /// Convert this into an actual `NewDatum` and a `Vec<NewInputFile>`.
-    fn into_new_datum_and_input_files(
-        self,
-        job_id: Uuid,
-        maximum_allowed_run_count: i32,
-    ) -> (NewDatum, Vec<NewInputFile>) {
-        let datum_id = Uuid::new_v4();
-        let datum = NewDatum {
-            id: datum_id,
-            job_id,
-            maximum_allowed_run_count,
-        };
-        let input_files = self
-            .input_files
-            .into_iter()
-            .map(|f| f.into_new_input_file(job_id, datum_id))
-            .collect();
-        (datum, input_files)
-    }
+/// Like [`input_to_datums`], but also return the size of each input file, if
+/// we know it. This is used to plan jobs without creating them.
+#[instrument(skip_all, fields(job_id = %job_id), level = "trace")]
+pub async fn input_to_datums_with_sizes(
+    secrets: &[Secret],
+    job_id: Uuid,
+    maximum_allowed_run_count: i32,
+    input: &Input,
+    conn: &mut AsyncPgConnection,
+) -> Result<Vec<(NewDatum, Vec<(NewInputFile, Option<u64>)>)>> {
+    Ok(input_to_datums_helper(secrets, input, conn)
+        .await?
+        .into_iter()
+        .map(|datum_data| {
+            let sizes = datum_data
+                .input_files
+                .iter()
+                .map(|f| f.size)
+                .collect::<Vec<_>>();
+            let (datum, input_files) = datum_data
+                .into_new_datum_and_input_files(job_id, maximum_allowed_run_count);
+            (datum, input_files.into_iter().zip(sizes).collect())
+        })
+        .collect())
 }
 
-/// (Local helper type.) This is essentially a `NewInputFile`, but in a more
-/// convenient format.
-#[derive(Clone, Debug)]
-struct InputFileData {
-    uri: String,
-    local_path: String,
+/// List the input of a streaming job, and return datums for any files which
+/// aren't in `known_uris`. Streaming jobs must have a single `Input::Atom`
+/// with `Glob::TopLevelDirectoryEntries`.
+#[instrument(skip_all, fields(job_id = %job_id), level = "trace")]
+pub async fn new_streaming_datums(
+    secrets: &[Secret],
+    job_id: Uuid,
+    maximum_allowed_run_count: i32,
+    input: &Input,
+    known_uris: &HashSet<String>,
+) -> Result<Vec<(NewDatum, Vec<NewInputFile>)>> {
+    check_streaming_input(input)?;
+    Ok(atom_to_datums(secrets, input)
+        .await?
+        .into_iter()
+        .filter(|datum_data| {
+            datum_data
+                .input_files
+                .iter()
+                .all(|input_file| !known_uris.contains(&input_file.uri))
+        })
+        .map(|datum_data| {
+            datum_data
+                .into_new_datum_and_input_files(job_id, maximum_allowed_run_count)
+        })
+        .collect())
 }
 
-impl InputFileData {
-    /// Convert this into an actual `NewInputFile`.
-    fn into_new_input_file(self, job_id: Uuid, datum_id: Uuid) -> NewInputFile {
-        NewInputFile {
-            job_id,
-            datum_id,
-            uri: self.uri,
-            local_path: self.local_path,
+/// Compute `input_hash` for each of `datums` with directory inputs, which
+/// [`input_to_datums`] leaves without one. This lists each directory
+/// recursively, so that the hash changes when anything inside it changes.
+#[instrument(skip_all, level = "trace")]
+pub async fn hash_directory_inputs(
+    secrets: &[Secret],
+    input: &Input,
+    datums: &mut [(NewDatum, Vec<NewInputFile>)],
+) -> Result<()> {
+    let requester_pays_uris = input.requester_pays_uris();
+    let mut directory_contents = HashMap::<String, Vec<ListedObject>>::new();
+    for (datum, input_files) in datums {
+        if datum.input_hash.is_some() {
+            continue;
         }
+        for input_file in input_files.iter().filter(|f| f.is_directory()) {
+            if directory_contents.contains_key(&input_file.uri) {
+                continue;
+            }
+            let requester_pays = requester_pays_uris
+                .iter()
+                .find(|(uri, _)| input_file.uri.starts_with(uri.as_str()))
+                .map(|(_, requester_pays)| requester_pays);
+            let storage = <dyn CloudStorage>::for_input_uri(
+                &input_file.uri,
+                secrets,
+                requester_pays,
+            )
+            .await?;
+            let contents =
+                list_objects_recursively(&*storage, &input_file.uri).await?;
+            directory_contents.insert(input_file.uri.clone(), contents);
+        }
+        datum.input_hash = Some(NewInputFile::input_hash_with_directory_contents(
+            input_files,
+            &directory_contents,
+        ));
     }
+    Ok(())
 }
 
-/// Given an `Input` from a JSON pipeline spec, convert to an actual set of
-/// "datums" (work chunks) to be assigned to a worker.
-///
-/// Returns the datums and associated input files in a form well-suited to bulk
-/// database insert.
-#[instrument(skip_all, fields(job_id = %job_id), level = "trace")]
-pub async fn input_to_datums(
-    secrets: &[Secret],
-    job_id: Uuid,
-    maximum_allowed_run_count: i32,
-    input: &Input,
-) -> Result<(Vec<NewDatum>, Vec<NewInputFile>)> {
-    let mut all_datums = vec![];
-    let mut all_input_files = vec![];
-    for datum_data in input_to_datums_helper(secrets, input).await? {
-        let (datum, input_files) = datum_data
-            .into_new_datum_and_input_files(job_id, maximum_allowed_run_count);
-        all_datums.push(datum);
-        all_input_files.extend(input_files);
+/// List every object under the directory `uri`, including those in
+/// subdirectories.
+#[instrument(skip_all, fields(uri = %uri), level = "trace")]
+async fn list_objects_recursively(
+    storage: &dyn CloudStorage,
+    uri: &str,
+) -> Result<Vec<ListedObject>> {
+    let mut objects = vec![];
+    let mut pending = vec![uri.to_owned()];
+    while let Some(dir) = pending.pop() {
+        for object in storage.list_objects(&dir).await? {
+            if object.uri == dir {
+                // Some backends list a directory's placeholder object.
+                continue;
+            } else if object.uri.ends_with('/') {
+                pending.push(object.uri);
+            } else {
+                objects.push(object);
+            }
+        }
+    }
+    Ok(objects)
+}
+
+/// Make sure that `input` is something we can stream. We need to be able to
+/// turn each new file into a separate datum.
+pub fn check_streaming_input(input: &Input) -> Result<()> {
+    match input {
+        Input::Atom {
+            glob: Glob::TopLevelDirectoryEntries,
+            partitions: None,
+            ..
+        } => Ok(()),
+        Input::Atom {
+            partitions: Some(_),
+            ..
+        } => Err(format_err!(
+            "streaming jobs cannot use \"partitions\", because each new file \
+             needs its own datum"
+        )),
+        _ => Err(format_err!(
+            "streaming jobs require a single atom input with glob \"/*\""
+        )),
     }
-    Ok((all_datums, all_input_files))
 }
 
 /// Given an `Input` from a JSON pipeline spec, convert to an actual set of
@@ -92,20 +185,30 @@ pub async fn input_to_datums(
 fn input_to_datums_helper<'a>(
     secrets: &'a [Secret],
     input: &'a Input,
+    conn: &'a mut AsyncPgConnection,
 ) -> Pin<Box<dyn Future<Output = Result<Vec<DatumData>>> + Send + 'a>> {
     Box::pin(async move {
         match input {
-            Input::Atom { uri, repo, glob } => {
-                atom_to_datums_helper(secrets, uri, repo, *glob).await
+            Input::Atom { .. } => atom_to_datums(secrets, input).await,
+            Input::Job {
+                job_name,
+                repo,
+                glob,
+            } => job_to_datums_helper(job_name, repo.as_deref(), *glob, conn).await,
+            Input::Cross(inputs) => {
+                let mut datums = vec![];
+                for child in inputs {
+                    datums.push(input_to_datums_helper(secrets, child, conn).await?);
+                }
+                Ok(cross_datums(datums))
             }
-            Input::Cross(inputs) => cross_to_datums_helper(secrets, inputs).await,
             Input::Union(inputs) => {
                 // Merge all our inputs. We could do this cleverly using `flat_map`
                 // and `collect` to manage the errors, but it's clearer with a `for`
                 // loop.
                 let mut datums = vec![];
                 for child in inputs {
-                    datums.extend(input_to_datums_helper(secrets, child).await?);
+                    datums.extend(input_to_datums_helper(secrets, child, conn).await?);
                 }
                 Ok(datums)
             }
@@ -113,134 +216,82 @@ fn input_to_datums_helper<'a>(
     })
 }
 
-/// Convert a single `Input::Atom` to a list of datums.
-#[instrument(skip_all, fields(uri = %uri, repo = %repo, glob = ?glob), level = "trace")]
-async fn atom_to_datums_helper(
-    secrets: &[Secret],
-    uri: &str,
-    repo: &str,
+/// Convert a single `Input::Job` to a list of datums, using the output files
+/// which the job successfully uploaded.
+#[instrument(skip_all, fields(job_name = %job_name, glob = ?glob), level = "trace")]
+async fn job_to_datums_helper(
+    job_name: &str,
+    repo: Option<&str>,
     glob: Glob,
+    conn: &mut AsyncPgConnection,
 ) -> Result<Vec<DatumData>> {
-    // Normalize our URI to always include a slash, because repositories must
-    // currently be directories.
-    let mut base = uri.to_owned();
+    let job = Job::find_by_job_name(job_name, conn).await?;
+    if !job.status.has_succeeded() {
+        return Err(format_err!(
+            "cannot read the output of job {}, which has status {}",
+            job_name,
+            job.status
+        ));
+    }
+    let repo = repo
+        .or_else(|| job.pipeline_spec["pipeline"]["name"].as_str())
+        .unwrap_or(job_name);
+
+    // Output files are normally uploaded somewhere under the job's egress
+    // URI, so we use that to build our local paths.
+    let mut base = job.egress_uri.clone();
     if !base.ends_with('/') {
         base.push('/');
     }
-
-    // Figure out what files to process. We do this for _both_
-    // `Glob::TopLevelDirectoryEntries` and `Glob::WholeRepo`, because we want
-    // to verify that we can actually list the contents of a `Glob::WholeRepo`
-    // _before_ spinning up a big cluster job.
-    let storage = <dyn CloudStorage>::for_uri(uri, secrets).await?;
-    let file_uris = storage.list(uri).await?;
-
-    match glob {
-        // Our input file is just the entire repo, as a directory.
-        Glob::WholeRepo => Ok(vec![DatumData {
-            input_files: vec![InputFileData {
-                uri: base,
-                local_path: format!("/pfs/{}/", repo),
-            }],
-        }]),
-
-        // Each top-level file or directory in `base` should be translated into
-        // a separate datum.
-        Glob::TopLevelDirectoryEntries => {
-            let mut datums = vec![];
-            for file_uri in file_uris {
-                let local_path = uri_to_local_path(uri, &file_uri, repo)?;
-                datums.push(DatumData {
-                    input_files: vec![InputFileData {
-                        uri: file_uri,
-                        local_path,
-                    }],
-                });
-            }
-            Ok(datums)
-        }
+    let mut input_files = vec![];
+    for output_file in OutputFile::done_for_job(job.id, conn).await? {
+        let local_path = uri_to_local_path(&base, &output_file.uri, repo)?;
+        input_files.push(InputFileData {
+            uri: output_file.uri,
+            local_path,
+            etag: None,
+            generation: None,
+            size: output_file.size.and_then(|size| u64::try_from(size).ok()),
+            last_modified: None,
+            decompress: false,
+        });
     }
-}
-
-/// Convert a cross product into a list of datums.
-///
-/// SECURITY: This assumes it runs on reasonably trusted and plausible inputs.
-/// You can cause a denial-of-service by calculating the cross product of
-/// enormous repos, or by passing in so many repos that the stack overflows. But
-/// since our input comes from a local user, this is fine for now.
-fn cross_to_datums_helper<'a>(
-    secrets: &'a [Secret],
-    inputs: &'a [Input],
-) -> Pin<Box<dyn Future<Output = Result<Vec<DatumData>>> + Send + 'a>> {
-    Box::pin(async move {
-        match inputs.len() {
-            // Base cases.
-            0 => Ok(vec![]),
-            1 => input_to_datums_helper(secrets, &inputs[0]).await,
-
-            // Recursive case.
-            n => {
-                // Recursively calculate the cross product of all but our last input.
-                let datums_0 =
-                    cross_to_datums_helper(secrets, &inputs[0..n - 1]).await?;
-
-                // Process our last input.
-                let datums_1 = input_to_datums_helper(secrets, &inputs[n - 1]).await?;
-
-                // Build our cross product between the recursive `datums_0` and our
-                // local `datums_1`.
-                let mut output = vec![];
-                for datum_0 in &datums_0 {
-                    for datum_1 in &datums_1 {
-                        let input_files_0 = &datum_0.input_files;
-                        let input_files_1 = &datum_1.input_files;
-                        let len_0 = input_files_0.len();
-                        let len_1 = input_files_1.len();
-                        let mut combined = Vec::with_capacity(len_0 + len_1);
-                        combined.extend(input_files_0.iter().cloned());
-                        combined.extend(input_files_1.iter().cloned());
-                        output.push(DatumData {
-                            input_files: combined,
-                        })
-                    }
-                }
-                Ok(output)
-            }
-        }
-    })
-}
 
-/// Given a URI and a repo name, construct a local path starting with "/pfs"
-/// pointing to where we should download the file.
-fn uri_to_local_path(base_uri: &str, uri: &str, repo: &str) -> Result<String> {
-    // Check some preconditions. These could probably be assertions; other code
-    // should ensure that these are always true.
-    if !base_uri.ends_with('/') {
-        return Err(format_err!("expected {} to end with a '/'", base_uri));
-    }
-    if !uri.starts_with(base_uri) {
-        return Err(format_err!("expected {} to be in {}", uri, base_uri));
-    }
+    match glob {
+        // Put all the output files in a single datum.
+        Glob::WholeRepo => Ok(vec![DatumData { input_files }]),
 
-    // Extract just the local portion of `uri` not included in `base_uri`.
-    let rel_uri = &uri[base_uri.len()..];
-    if rel_uri.is_empty() {
-        Err(format_err!("{:?} ends with '/'", uri))
-    } else {
-        Ok(format!("/pfs/{}/{}", repo, rel_uri))
+        // Put each output file in its own datum.
+        Glob::TopLevelDirectoryEntries => Ok(input_files
+            .into_iter()
+            .map(|input_file| DatumData {
+                input_files: vec![input_file],
+            })
+            .collect()),
     }
 }
 
 #[test]
-fn uri_to_local_path_works() {
-    let path =
-        uri_to_local_path("gs://bucket/path/", "gs://bucket/path/data1.csv", "myrepo")
-            .unwrap();
-    assert_eq!(path, "/pfs/myrepo/data1.csv");
-
-    // Directories use this convention for now?
-    let dpath =
-        uri_to_local_path("gs://bucket/path/", "gs://bucket/path/data1/", "myrepo")
-            .unwrap();
-    assert_eq!(dpath, "/pfs/myrepo/data1/");
+fn check_streaming_input_requires_top_level_atom() {
+    let atom = |glob| Input::Atom {
+        uri: "gs://bucket/incoming/".to_owned(),
+        repo: "incoming".to_owned(),
+        glob,
+        requester_pays: false,
+        billing_project: None,
+        decompress: false,
+        partitions: None,
+        partition_by: PartitionBy::Count,
+    };
+    assert!(check_streaming_input(&atom(Glob::TopLevelDirectoryEntries)).is_ok());
+    assert!(check_streaming_input(&atom(Glob::WholeRepo)).is_err());
+    let mut partitioned = atom(Glob::TopLevelDirectoryEntries);
+    if let Input::Atom { partitions, .. } = &mut partitioned {
+        *partitions = Some(10);
+    }
+    assert!(check_streaming_input(&partitioned).is_err());
+    assert!(check_streaming_input(&Input::Union(vec![atom(
+        Glob::TopLevelDirectoryEntries
+    )]))
+    .is_err());
 }
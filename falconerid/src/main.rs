@@ -1,45 +1,79 @@
 #![deny(unsafe_code)]
 
-use std::{collections::HashSet, env};
+use std::{collections::HashSet, env, net::SocketAddr, time::Duration};
 
 use axum::{
-    extract::{Path, Query},
-    http::StatusCode,
-    routing::{get, patch, post},
+    extract::{Path, Query, State},
+    http::{
+        header::{ACCEPT, CONTENT_TYPE},
+        HeaderMap, StatusCode,
+    },
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{delete, get, patch, post, put},
     Json, Router,
 };
 use falconeri_common::{
-    db,
+    cast, db,
     diesel::BelongingToDsl,
     diesel_async::{scoped_futures::ScopedFutureExt, AsyncConnection, RunQueryDsl},
     falconeri_common_version,
+    kubernetes::set_job_parallelism,
     models::DatumStateError,
     pipeline::PipelineSpec,
     prelude::*,
     rest_api::{
-        CreateJobRequest, CreateOutputFilesRequest, DatumDescribeResponse, DatumPatch,
-        DatumReservationRequest, DatumReservationResponse, DatumResponse,
-        JobDescribeResponse, JobResponse, JobsResponse, OutputFilesResponse,
-        UpdateDatumRequest, UpdateOutputFilesRequest,
+        ApiTokenResponse, ApiTokensResponse, AppendDatumOutputRequest,
+        CreateApiTokenRequest, CreateApiTokenResponse, CreateJobRequest,
+        CreateOutputFilesRequest, CreatePipelineRequest, DatumDescribeResponse,
+        DatumPatch, DatumReservationRequest, DatumReservationResponse, DatumResponse,
+        ErrorResponse, JobAuditResponse, JobCreationProgress, JobDatumsResponse,
+        JobDescribeResponse, JobEventsResponse, JobLineageResponse,
+        JobOutputFilesResponse, JobPatch, JobPlanResponse, JobResponse,
+        JobSearchResponse, JobStatsResponse, JobsResponse, OutputFilePatch,
+        OutputFilePost, OutputFilesResponse, PlanJobRequest, QuotaResponse,
+        QuotasResponse, RecommendedResourcesResponse, RegisteredPipelineResponse,
+        RegisteredPipelinesResponse, ReleaseDatumRequest, RerunJobRequest,
+        ReservationsRequest, ReservationsResponse, SetQuotaRequest,
+        UpdateDatumRequest, UpdateOutputFilesRequest, VersionResponse,
     },
     tracing_support::initialize_tracing,
+    version::supported_client_versions,
 };
 use serde::Deserialize;
-use tower_http::{limit::RequestBodyLimitLayer, trace::TraceLayer};
+use tower_http::{
+    compression::CompressionLayer, limit::RequestBodyLimitLayer, trace::TraceLayer,
+};
 use utoipa::OpenApi;
 
 mod babysitter;
+mod concurrency_limit;
+mod db_pool;
 pub(crate) mod inputs;
+mod output_manifest;
+mod rate_limit;
+mod request_id;
+mod scheduler;
 mod start_job;
+mod streaming;
 mod util;
 
 use crate::{
     babysitter::start_babysitter,
-    start_job::{retry_job, run_job},
-    util::{AppState, DbConn, FalconeridError, FalconeridResult, User},
+    concurrency_limit::{limit_concurrency, ConcurrencyLimiter},
+    db_pool::PoolMonitor,
+    rate_limit::{rate_limit, RateLimitConfig, RateLimiter},
+    request_id::propagate_request_id,
+    scheduler::start_scheduler,
+    start_job::{
+        choose_job_name, job_pipeline_spec, max_datums_per_job, plan_job, rerun_job,
+        retry_job, run_job, run_registered_pipeline, skip_kubernetes_from_env,
+        stop_batch_job, use_upstream_egress_as_input,
+    },
+    util::{Admin, AppState, Caller, DbConn, FalconeridError, FalconeridResult, User},
 };
 
-/// OpenAPI specification for CLI-facing endpoints.
+/// OpenAPI specification for CLI-facing and worker-facing endpoints.
 #[derive(OpenApi)]
 #[openapi(
     info(
@@ -49,31 +83,139 @@ use crate::{
     ),
     paths(
         version,
+        healthz,
+        readyz,
         post_job,
+        post_job_plan,
         get_job_by_name,
         list_jobs,
+        search_jobs,
         get_job,
+        patch_job,
         describe_job,
+        job_running_datums,
+        job_error_datums,
+        job_stats,
+        recommended_resources,
+        job_audit,
+        job_lineage,
+        job_events,
+        job_output_files,
+        job_spec,
         job_retry,
+        job_rerun,
+        job_stop_streaming,
+        job_reserve_next_datum,
+        patch_datum,
+        append_datum_output,
         describe_datum,
+        release_datum,
+        create_output_files,
+        patch_output_files,
+        put_reservations,
+        post_pipeline,
+        list_pipelines,
+        delete_pipeline,
+        trigger_pipeline,
+        list_quotas,
+        put_quota,
+        delete_quota,
+        list_api_tokens,
+        create_api_token,
+        revoke_api_token,
     ),
     components(schemas(
         Job,
         Datum,
         DatumStatusCount,
+        DatumSummary,
+        DatumTimings,
+        DatumStreams,
+        DatumByteCounts,
+        DatumResourceUsage,
+        DatumTimingStats,
         InputFile,
+        InputFileTotals,
+        OutputFile,
         Status,
+        FailureClass,
         JobDescribeResponse,
+        JobCreationProgress,
+        PlanJobRequest,
+        JobPlanResponse,
+        falconeri_common::rest_api::JobPlan,
+        falconeri_common::rest_api::PlannedDatum,
+        falconeri_common::rest_api::PlannedInputFile,
+        JobStatsResponse,
+        JobStats,
+        RecommendedResourcesResponse,
+        ResourceRecommendation,
+        JobAuditResponse,
+        JobAudit,
+        AuditCheck,
+        AuditViolation,
+        JobLineageResponse,
+        JobLineage,
+        JobEventsResponse,
+        JobEvent,
+        JobOutputFilesResponse,
+        JobDatumsResponse,
+        JobEventKind,
+        JobSearchResponse,
+        JobSearchResult,
+        ThroughputBucket,
+        NodeFailureCount,
         DatumDescribeResponse,
+        DatumReservationRequest,
+        DatumReservationResponse,
+        DatumPatch,
+        UpdateDatumRequest,
+        DatumResponse,
+        ReleaseDatumRequest,
+        AppendDatumOutputRequest,
+        OutputFilePost,
+        OutputFilePatch,
+        CreateOutputFilesRequest,
+        UpdateOutputFilesRequest,
+        OutputFilesResponse,
+        ReservationsRequest,
+        ReservationsResponse,
+        VersionResponse,
+        ErrorResponse,
+        falconeri_common::rest_api::FalconeriApiError,
+        CreatePipelineRequest,
+        RegisteredPipeline,
+        RegisteredPipelineResponse,
+        RegisteredPipelinesResponse,
+        Quota,
+        QuotaResponse,
+        QuotasResponse,
+        SetQuotaRequest,
+        ApiToken,
+        ApiTokenResponse,
+        ApiTokensResponse,
+        CreateApiTokenRequest,
+        CreateApiTokenResponse,
         PipelineSpec,
         falconeri_common::pipeline::Pipeline,
         falconeri_common::pipeline::Transform,
+        falconeri_common::pipeline::IoniceClass,
+        falconeri_common::pipeline::ArchiveFormat,
+        falconeri_common::pipeline::PartitionBy,
         falconeri_common::pipeline::ParallelismSpec,
         falconeri_common::pipeline::ResourceRequests,
+        falconeri_common::pipeline::ScratchVolume,
+        falconeri_common::pipeline::InputCache,
+        falconeri_common::pipeline::SharedVolume,
+        falconeri_common::pipeline::ExtraContainer,
+        falconeri_common::pipeline::VolumeMount,
         falconeri_common::pipeline::Input,
         falconeri_common::pipeline::Glob,
         falconeri_common::pipeline::Egress,
+        falconeri_common::pipeline::EgressEncryption,
+        falconeri_common::pipeline::DatumCountRange,
         falconeri_common::secret::Secret,
+        falconeri_common::secret::VaultConfig,
     ))
 )]
 struct ApiDoc;
@@ -89,8 +231,12 @@ async fn initialize_server() -> Result<()> {
     // Print our some information about our environment.
     eprintln!("Running in {}", env::current_dir()?.display());
 
-    // Initialize the database and run migrations.
-    eprintln!("Connecting to database.");
+    // Initialize the database and run migrations. We check our database URL
+    // first, so that TLS misconfigurations produce a clear error.
+    let database_url = db::database_url(ConnectVia::Cluster).await?;
+    let description = db::check_database_url(&database_url)
+        .context("invalid database configuration")?;
+    eprintln!("Connecting to database: {}", description);
     let conn = db::async_connect(ConnectVia::Cluster).await?;
     eprintln!("Running any pending migrations.");
     // run_pending_migrations takes ownership and returns the connection.
@@ -101,19 +247,102 @@ async fn initialize_server() -> Result<()> {
     Ok(())
 }
 
-/// Return our `falconeri_common` version, which should match the client
-/// exactly (for now).
+/// Return our `falconeri_common` version. If the client accepts JSON, we also
+/// tell it which client versions we support. Older clients expect plain text.
 ///
 /// Used by: CLI, Worker
 #[utoipa::path(
     get,
     path = "/version",
     responses(
-        (status = 200, description = "Server version", body = String)
+        (status = 200, description = "Server version, as JSON if requested using `Accept: application/json`, and otherwise as plain text", body = VersionResponse)
+    )
+)]
+async fn version(headers: HeaderMap) -> Response {
+    let version = falconeri_common_version().to_string();
+    let wants_json = headers
+        .get(ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("application/json"));
+    if wants_json {
+        Json(VersionResponse {
+            version,
+            supported_client_versions: supported_client_versions().to_string(),
+        })
+        .into_response()
+    } else {
+        version.into_response()
+    }
+}
+
+/// Liveness probe. If we can answer this at all, the process is alive.
+///
+/// Used by: Kubernetes
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    responses(
+        (status = 200, description = "Server process is alive", body = String)
+    )
+)]
+async fn healthz() -> &'static str {
+    "ok"
+}
+
+/// Readiness probe. Checks that we can get a database connection, that our
+/// schema migrations are current, and that our babysitter is still running.
+///
+/// Used by: Kubernetes
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    responses(
+        (status = 200, description = "Server is ready to handle requests", body = String),
+        (status = 503, description = "Server is not ready", body = String)
     )
 )]
-async fn version() -> String {
-    falconeri_common_version().to_string()
+async fn readyz(State(state): State<AppState>) -> (StatusCode, String) {
+    match check_readiness(&state).await {
+        Ok(()) => (StatusCode::OK, "ok".to_owned()),
+        Err(err) => {
+            warn!("readiness check failed: {:#}", err);
+            (StatusCode::SERVICE_UNAVAILABLE, format!("{:#}", err))
+        }
+    }
+}
+
+/// Prometheus metrics for `falconerid` itself.
+///
+/// Used by: Prometheus
+async fn metrics(
+    State(state): State<AppState>,
+) -> ([(http::HeaderName, &'static str); 1], String) {
+    let mut out = String::new();
+    state.rate_limiter.render_metrics(&mut out);
+    state.concurrency_limiter.render_metrics(&mut out);
+    state.pool_monitor.render_metrics(&state.pool, &mut out);
+    state.babysitter_heartbeat.render_metrics(&mut out);
+    ([(CONTENT_TYPE, "text/plain; version=0.0.4")], out)
+}
+
+/// Helper for `readyz` which returns an error describing why we're not ready.
+#[instrument(skip_all, level = "trace")]
+async fn check_readiness(state: &AppState) -> Result<()> {
+    if !state.babysitter_heartbeat.is_alive() {
+        return Err(format_err!("babysitter has not run recently"));
+    }
+
+    // Don't wait forever if our pool is exhausted. That's exactly the
+    // situation where we want Kubernetes to stop sending us traffic.
+    let mut conn = tokio::time::timeout(Duration::from_secs(2), state.pool.get())
+        .await
+        .map_err(|_| format_err!("timed out waiting for database connection"))?
+        .map_err(|e| format_err!("pool error: {}", e))?;
+    let pending = db::pending_migration_versions(&mut conn).await?;
+    if !pending.is_empty() {
+        return Err(format_err!("pending migrations: {}", pending.join(", ")));
+    }
+    Ok(())
 }
 
 /// Create a new job from a JSON pipeline spec.
@@ -123,19 +352,224 @@ async fn version() -> String {
     post,
     path = "/jobs",
     request_body = CreateJobRequest,
+    params(
+        ("Idempotency-Key" = Option<String>, Header, description = "A unique key for this submission. If a job was already submitted with this key, it is returned instead of creating a new job.")
+    ),
     responses(
-        (status = 200, description = "Job created successfully", body = JobResponse)
+        (status = 200, description = "Job created successfully (datums are created in the background), or the existing job with the same idempotency key", body = JobResponse),
+        (status = 400, description = "Invalid idempotency key or job name, or the job would have an implausible number of datums"),
+        (status = 409, description = "An identical job is already running, a job with the requested name already exists, or the idempotency key was used for a different pipeline spec"),
+        (status = 403, description = "The API token belongs to a different team"),
+        (status = 429, description = "The job's team is over its quota")
     )
 )]
 async fn post_job(
-    _user: User,
+    user: User,
+    State(state): State<AppState>,
     DbConn(mut conn): DbConn,
+    headers: HeaderMap,
     Json(request): Json<CreateJobRequest>,
 ) -> FalconeridResult<Json<JobResponse>> {
-    let job = run_job(&request.job, &mut conn).await?;
+    user.check_team(request.job.team.as_deref())?;
+
+    // Look up any jobs we need to wait for, including jobs whose output we
+    // read.
+    let mut depends_on = request.depends_on.clone();
+    depends_on.extend(
+        request
+            .job
+            .input
+            .upstream_job_names()
+            .into_iter()
+            .map(|job_name| job_name.to_owned()),
+    );
+    let upstream_jobs = find_upstream_jobs(&depends_on, &mut conn).await?;
+    let mut pipeline_spec = request.job.clone();
+    if request.input_from_upstream {
+        use_upstream_egress_as_input(&mut pipeline_spec, &upstream_jobs)
+            .map_err(|err| FalconeridError::BadRequest(format!("{:#}", err)))?;
+    }
+
+    // Hash the spec we'll actually run, so that jobs which read the output of
+    // different upstream jobs aren't mistaken for duplicates.
+    let spec_hash = pipeline_spec.canonical_hash()?;
+
+    // If this submission has already been made, return the existing job.
+    let idempotency_key = idempotency_key(&headers, &request)?;
+    if let Some(key) = &idempotency_key {
+        if let Some(existing) = Job::find_by_idempotency_key(key, &mut conn).await? {
+            return existing_idempotent_job(existing, &spec_hash);
+        }
+    }
+
+    // Choose a name for our job, and make sure it isn't taken.
+    let job_name = choose_job_name(
+        &request.job,
+        request.name.as_deref(),
+        request.generate_name.as_deref(),
+    )
+    .map_err(|err| FalconeridError::BadRequest(format!("{:#}", err)))?;
+    if request.name.is_some() && Job::job_name_exists(&job_name, &mut conn).await? {
+        return Err(job_name_conflict(&job_name));
+    }
+
+    // `run_job` refuses to start a second copy of an identical running job,
+    // unless asked.
+    let job = run_job(
+        state.pool.clone(),
+        &pipeline_spec,
+        &job_name,
+        &spec_hash,
+        request.force,
+        request.override_datum_cap,
+        idempotency_key.as_deref(),
+        &upstream_jobs,
+        None,
+        state.skip_kubernetes,
+        &mut conn,
+    )
+    .await;
+    let job = match job {
+        Ok(job) => job,
+        Err(err) => {
+            // If a concurrent request with the same key won the race to
+            // insert its job, return that job instead.
+            if let Some(key) = &idempotency_key {
+                if let Some(existing) =
+                    Job::find_by_idempotency_key(key, &mut conn).await?
+                {
+                    return existing_idempotent_job(existing, &spec_hash);
+                }
+            }
+            // Likewise, if somebody else took our name, say so.
+            if request.name.is_some()
+                && Job::job_name_exists(&job_name, &mut conn).await?
+            {
+                return Err(job_name_conflict(&job_name));
+            }
+            return Err(err.into());
+        }
+    };
     Ok(Json(JobResponse { job }))
 }
 
+/// List a pipeline spec's inputs and divide them into datums, without creating
+/// a job.
+///
+/// Used by: CLI (job plan)
+#[utoipa::path(
+    post,
+    path = "/jobs/plan",
+    request_body = PlanJobRequest,
+    responses(
+        (status = 200, description = "How the job's inputs would be divided into datums", body = JobPlanResponse)
+    )
+)]
+async fn post_job_plan(
+    _user: User,
+    DbConn(mut conn): DbConn,
+    Json(request): Json<PlanJobRequest>,
+) -> FalconeridResult<Json<JobPlanResponse>> {
+    let job_plan = plan_job(&request.job, &mut conn).await?;
+    Ok(Json(JobPlanResponse { job_plan }))
+}
+
+/// Look up the jobs in `depends_on`, which may be job names or UUIDs. We
+/// refuse to depend on jobs which have already failed.
+async fn find_upstream_jobs(
+    depends_on: &[String],
+    conn: &mut AsyncPgConnection,
+) -> FalconeridResult<Vec<Job>> {
+    let mut upstream_jobs: Vec<Job> = vec![];
+    for id_or_name in depends_on {
+        let job = Job::find_by_id_or_name(id_or_name, conn)
+            .await?
+            .ok_or_else(|| {
+                FalconeridError::BadRequest(format!(
+                    "cannot depend on unknown job {}",
+                    id_or_name
+                ))
+            })?;
+        if job.status.has_finished() && !job.status.has_succeeded() {
+            return Err(FalconeridError::BadRequest(format!(
+                "cannot depend on job {}, which finished with status {}",
+                job.job_name, job.status
+            )));
+        }
+        if !upstream_jobs
+            .iter()
+            .any(|upstream_job| upstream_job.id == job.id)
+        {
+            upstream_jobs.push(job);
+        }
+    }
+    Ok(upstream_jobs)
+}
+
+/// The error we return when a user asks for a job name that's already taken.
+fn job_name_conflict(job_name: &str) -> FalconeridError {
+    FalconeridError::Conflict(format!(
+        "a job named {} already exists (use --generate-name to add a unique suffix)",
+        job_name
+    ))
+}
+
+/// The longest idempotency key we accept.
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+
+/// Get the idempotency key for a `POST /jobs` request, from either the
+/// `Idempotency-Key` header or the request body.
+fn idempotency_key(
+    headers: &HeaderMap,
+    request: &CreateJobRequest,
+) -> FalconeridResult<Option<String>> {
+    let header = headers
+        .get("idempotency-key")
+        .map(|value| {
+            value.to_str().map_err(|_| {
+                FalconeridError::BadRequest(
+                    "Idempotency-Key header must be ASCII".to_owned(),
+                )
+            })
+        })
+        .transpose()?;
+    let key = match (header, request.idempotency_key.as_deref()) {
+        (Some(header), Some(body)) if header != body => {
+            return Err(FalconeridError::BadRequest(
+                "Idempotency-Key header does not match idempotency_key".to_owned(),
+            ));
+        }
+        (Some(key), _) | (None, Some(key)) => key,
+        (None, None) => return Ok(None),
+    };
+    if key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY_LEN {
+        return Err(FalconeridError::BadRequest(format!(
+            "idempotency key must be between 1 and {} bytes long",
+            MAX_IDEMPOTENCY_KEY_LEN
+        )));
+    }
+    Ok(Some(key.to_owned()))
+}
+
+/// Return a job that was previously submitted with the same idempotency key,
+/// as long as it was submitted with the same pipeline spec.
+fn existing_idempotent_job(
+    existing: Job,
+    spec_hash: &str,
+) -> FalconeridResult<Json<JobResponse>> {
+    if existing.spec_hash.as_deref() != Some(spec_hash) {
+        return Err(FalconeridError::Conflict(format!(
+            "idempotency key was already used to submit {} with a different pipeline spec",
+            existing.job_name
+        )));
+    }
+    debug!(
+        "returning existing job {} for idempotency key",
+        existing.job_name
+    );
+    Ok(Json(JobResponse { job: existing }))
+}
+
 /// Query parameters for get_job_by_name.
 #[derive(Deserialize, utoipa::IntoParams)]
 struct JobNameQuery {
@@ -181,6 +615,56 @@ async fn list_jobs(
     Ok(Json(JobsResponse { jobs }))
 }
 
+/// The default number of jobs returned by `search_jobs`.
+const DEFAULT_SEARCH_LIMIT: i64 = 100;
+
+/// The maximum number of jobs returned by `search_jobs`.
+const MAX_SEARCH_LIMIT: i64 = 1000;
+
+/// Query parameters for search_jobs.
+#[derive(Deserialize, utoipa::IntoParams)]
+struct JobSearchQuery {
+    /// Text to look for in job names and error messages. Case-insensitive.
+    q: String,
+    /// Only return jobs created at or after this time (UTC).
+    since: Option<NaiveDateTime>,
+    /// The maximum number of jobs to return.
+    limit: Option<i64>,
+}
+
+/// Search for jobs by name, or by job and datum error messages.
+///
+/// Used by: CLI (job search)
+#[utoipa::path(
+    get,
+    path = "/jobs/search",
+    params(JobSearchQuery),
+    responses(
+        (status = 200, description = "Matching jobs, newest first", body = JobSearchResponse),
+        (status = 400, description = "Invalid search parameters")
+    )
+)]
+async fn search_jobs(
+    _user: User,
+    DbConn(mut conn): DbConn,
+    Query(query): Query<JobSearchQuery>,
+) -> FalconeridResult<Json<JobSearchResponse>> {
+    if query.q.trim().is_empty() {
+        return Err(FalconeridError::BadRequest(
+            "search query must not be empty".to_owned(),
+        ));
+    }
+    let limit = query.limit.unwrap_or(DEFAULT_SEARCH_LIMIT);
+    if !(1..=MAX_SEARCH_LIMIT).contains(&limit) {
+        return Err(FalconeridError::BadRequest(format!(
+            "limit must be between 1 and {}",
+            MAX_SEARCH_LIMIT
+        )));
+    }
+    let jobs = Job::search(query.q.trim(), query.since, limit, &mut conn).await?;
+    Ok(Json(JobSearchResponse { jobs }))
+}
+
 /// Look up a job by ID and return it as JSON.
 ///
 /// Used by: CLI (job wait), Worker
@@ -195,14 +679,34 @@ async fn list_jobs(
     )
 )]
 async fn get_job(
-    _user: User,
+    caller: Caller,
     DbConn(mut conn): DbConn,
     Path(job_id): Path<Uuid>,
 ) -> FalconeridResult<Json<JobResponse>> {
+    caller.check_job(job_id)?;
     let job = Job::find(job_id, &mut conn).await?;
     Ok(Json(JobResponse { job }))
 }
 
+/// How many recent events `describe_job` includes.
+const DESCRIBE_EVENT_LIMIT: i64 = 10;
+
+/// The default number of running and error datums included by
+/// `describe_job`.
+const DEFAULT_DESCRIBE_DATUM_LIMIT: i64 = 100;
+
+/// The maximum number of running and error datums included by
+/// `describe_job`. Use `job_running_datums` and `job_error_datums` to get
+/// more.
+const MAX_DESCRIBE_DATUM_LIMIT: i64 = 10_000;
+
+/// Query parameters for describe_job.
+#[derive(Deserialize, utoipa::IntoParams)]
+struct JobDescribeQuery {
+    /// Include at most this many running datums, and this many error datums.
+    limit: Option<i64>,
+}
+
 /// Get detailed job information for display.
 ///
 /// Used by: CLI (job describe)
@@ -210,183 +714,1297 @@ async fn get_job(
     get,
     path = "/jobs/{job_id}/describe",
     params(
-        ("job_id" = Uuid, Path, description = "The job UUID")
+        ("job_id" = Uuid, Path, description = "The job UUID"),
+        JobDescribeQuery
     ),
     responses(
-        (status = 200, description = "Job description", body = JobDescribeResponse)
+        (status = 200, description = "Job description", body = JobDescribeResponse),
+        (status = 400, description = "Invalid limit")
     )
 )]
 async fn describe_job(
     _user: User,
     DbConn(mut conn): DbConn,
     Path(job_id): Path<Uuid>,
+    Query(query): Query<JobDescribeQuery>,
 ) -> FalconeridResult<Json<JobDescribeResponse>> {
+    let limit = query.limit.unwrap_or(DEFAULT_DESCRIBE_DATUM_LIMIT);
+    if !(0..=MAX_DESCRIBE_DATUM_LIMIT).contains(&limit) {
+        return Err(FalconeridError::BadRequest(format!(
+            "limit must be between 0 and {}",
+            MAX_DESCRIBE_DATUM_LIMIT
+        )));
+    }
     let job = Job::find(job_id, &mut conn).await?;
     let datum_status_counts = job.datum_status_counts(&mut conn).await?;
-    let running_datums = job.datums_with_status(Status::Running, &mut conn).await?;
-    let error_datums = job.datums_with_status(Status::Error, &mut conn).await?;
+    let (running_datums, running_datums_truncated) =
+        limited_datums_with_status(&job, Status::Running, limit, &mut conn).await?;
+    let (error_datums, error_datums_truncated) =
+        limited_datums_with_status(&job, Status::Error, limit, &mut conn).await?;
+    let datum_timing_stats = job.datum_timing_stats(&mut conn).await?;
+    let input_file_totals = InputFile::totals_for_job(job.id, &mut conn).await?;
+    let lineage = job.lineage(&mut conn).await?;
+    let node_failure_counts = job.node_failure_counts(&mut conn).await?;
+    let anomalous_nodes = NodeFailureCount::anomalous(&node_failure_counts)
+        .into_iter()
+        .cloned()
+        .collect();
+    let recent_events =
+        JobEvent::recent_for_job(job.id, Some(DESCRIBE_EVENT_LIMIT), &mut conn)
+            .await?;
+    let creation_progress = if job.status == Status::Creating {
+        Some(JobCreationProgress {
+            datums_created: datum_status_counts.iter().map(|c| c.count).sum(),
+            total_datum_count: job
+                .total_datum_count
+                .map(cast::u64)
+                .transpose()
+                .context("invalid total_datum_count")?,
+        })
+    } else {
+        None
+    };
     Ok(Json(JobDescribeResponse {
         job,
         datum_status_counts,
         running_datums,
+        running_datums_truncated,
         error_datums,
+        error_datums_truncated,
+        datum_timing_stats,
+        input_file_totals,
+        creation_progress,
+        lineage,
+        recent_events,
+        anomalous_nodes,
     }))
 }
 
-/// Retry a job, and return the new job as JSON.
+/// Get at most `limit` of the datums of `job` with `status`, and whether
+/// there were any more.
+async fn limited_datums_with_status(
+    job: &Job,
+    status: Status,
+    limit: i64,
+    conn: &mut AsyncPgConnection,
+) -> Result<(Vec<DatumSummary>, bool)> {
+    // Ask for one extra datum, so we know whether we left any out.
+    let mut datums = job
+        .datum_summaries_with_status(status, Some(limit + 1), conn)
+        .await?;
+    let truncated = cast::i64(datums.len())? > limit;
+    datums.truncate(cast::usize(limit)?);
+    Ok((datums, truncated))
+}
+
+/// The default number of datums returned by `job_running_datums` and
+/// `job_error_datums`.
+const DEFAULT_JOB_DATUMS_LIMIT: i64 = 1000;
+
+/// The maximum number of datums returned by `job_running_datums` and
+/// `job_error_datums`.
+const MAX_JOB_DATUMS_LIMIT: i64 = 10_000;
+
+/// Query parameters for job_running_datums and job_error_datums.
+#[derive(Deserialize, utoipa::IntoParams)]
+struct JobDatumsQuery {
+    /// Only return datums whose IDs sort after this one. Use the previous
+    /// page's `next_after`.
+    after: Option<Uuid>,
+    /// The maximum number of datums to return.
+    limit: Option<i64>,
+}
+
+/// Get a page of a job's running datums, sorted by ID. `describe_job` only
+/// includes some of them, so this is how to list them all.
 ///
-/// Used by: CLI (job retry)
+/// Used by: CLI (job describe --all-datums)
 #[utoipa::path(
-    post,
-    path = "/jobs/{job_id}/retry",
+    get,
+    path = "/jobs/{job_id}/running_datums",
     params(
-        ("job_id" = Uuid, Path, description = "The job UUID to retry")
+        ("job_id" = Uuid, Path, description = "The job UUID"),
+        JobDatumsQuery
     ),
     responses(
-        (status = 200, description = "New job created from retry", body = JobResponse)
+        (status = 200, description = "A page of running datums, sorted by ID", body = JobDatumsResponse),
+        (status = 400, description = "Invalid limit")
     )
 )]
-async fn job_retry(
+async fn job_running_datums(
     _user: User,
     DbConn(mut conn): DbConn,
     Path(job_id): Path<Uuid>,
-) -> FalconeridResult<Json<JobResponse>> {
-    let job = Job::find(job_id, &mut conn).await?;
-    let new_job = retry_job(&job, &mut conn).await?;
-    Ok(Json(JobResponse { job: new_job }))
+    Query(query): Query<JobDatumsQuery>,
+) -> FalconeridResult<Json<JobDatumsResponse>> {
+    job_datums_page(job_id, Status::Running, query, &mut conn).await
 }
 
-/// Reserve the next available datum for a job, and return it along with a list
-/// of input files.
+/// Get a page of a job's error datums, sorted by ID. `describe_job` only
+/// includes some of them, so this is how to list them all.
 ///
-/// Used by: Worker
-#[instrument(skip_all, fields(job = %job_id, pod_name = %request.pod_name), level = "debug")]
-async fn job_reserve_next_datum(
+/// Used by: CLI (job describe --all-datums)
+#[utoipa::path(
+    get,
+    path = "/jobs/{job_id}/error_datums",
+    params(
+        ("job_id" = Uuid, Path, description = "The job UUID"),
+        JobDatumsQuery
+    ),
+    responses(
+        (status = 200, description = "A page of error datums, sorted by ID", body = JobDatumsResponse),
+        (status = 400, description = "Invalid limit")
+    )
+)]
+async fn job_error_datums(
     _user: User,
     DbConn(mut conn): DbConn,
     Path(job_id): Path<Uuid>,
-    Json(request): Json<DatumReservationRequest>,
-) -> FalconeridResult<Json<Option<DatumReservationResponse>>> {
-    let job = Job::find(job_id, &mut conn).await?;
-    let reserved = job
-        .reserve_next_datum(&request.node_name, &request.pod_name, &mut conn)
+    Query(query): Query<JobDatumsQuery>,
+) -> FalconeridResult<Json<JobDatumsResponse>> {
+    job_datums_page(job_id, Status::Error, query, &mut conn).await
+}
+
+/// Get a page of the datums of `job_id` with `status`.
+async fn job_datums_page(
+    job_id: Uuid,
+    status: Status,
+    query: JobDatumsQuery,
+    conn: &mut AsyncPgConnection,
+) -> FalconeridResult<Json<JobDatumsResponse>> {
+    let limit = query.limit.unwrap_or(DEFAULT_JOB_DATUMS_LIMIT);
+    if !(1..=MAX_JOB_DATUMS_LIMIT).contains(&limit) {
+        return Err(FalconeridError::BadRequest(format!(
+            "limit must be between 1 and {}",
+            MAX_JOB_DATUMS_LIMIT
+        )));
+    }
+    let job = Job::find(job_id, conn).await?;
+    let datums = job
+        .datums_with_status_page(status, query.after, limit, conn)
         .await?;
-    let result = reserved
-        .map(|(datum, input_files)| DatumReservationResponse { datum, input_files });
-    if let Some(ref res) = result {
-        debug!(datum = %res.datum.id, "reserved datum");
+    // If we got a full page, there may be more.
+    let next_after = if cast::i64(datums.len())? == limit {
+        datums.last().map(|d| d.id)
     } else {
-        debug!("no datums available to reserve");
-    }
-    Ok(Json(result))
+        None
+    };
+    Ok(Json(JobDatumsResponse { datums, next_after }))
 }
 
-/// Update a datum when it's done.
+/// Query parameters for job_events.
+#[derive(Deserialize, utoipa::IntoParams)]
+struct JobEventsQuery {
+    /// Only return this many of the most recent events.
+    limit: Option<i64>,
+}
+
+/// Get the actions taken on a job's behalf outside of the normal flow of
+/// datums, such as reaping zombie datums or rescheduling failed datums,
+/// oldest first.
 ///
-/// Used by: Worker
-#[instrument(skip_all, fields(datum = %datum_id, pod_name = %request.pod_name), level = "debug")]
-async fn patch_datum(
+/// Used by: CLI (job describe)
+#[utoipa::path(
+    get,
+    path = "/jobs/{job_id}/events",
+    params(
+        ("job_id" = Uuid, Path, description = "The job UUID"),
+        JobEventsQuery
+    ),
+    responses(
+        (status = 200, description = "Events for the job, oldest first", body = JobEventsResponse),
+        (status = 400, description = "Invalid limit")
+    )
+)]
+async fn job_events(
     _user: User,
     DbConn(mut conn): DbConn,
-    Path(datum_id): Path<Uuid>,
-    Json(request): Json<UpdateDatumRequest>,
-) -> FalconeridResult<Json<DatumResponse>> {
-    let patch = request.datum.clone();
-    debug!(status = ?patch.status, "updating datum");
-
-    // Wrap everything in a transaction for the ownership lock.
-    let datum = conn
-        .transaction(|conn| {
-            async move {
-                // Lock datum and verify ownership and status (returns 403 if mismatch).
-                let mut datum = Datum::lock_and_verify_owner(
-                    datum_id,
-                    &request.pod_name,
-                    Status::Running,
-                    conn,
-                )
-                .await
-                .map_err(FalconeridError::from)?;
-
-                // We only support a few very specific types of patches.
-                match &patch {
-                    // Set status to `Status::Done`.
-                    DatumPatch {
-                        status: Status::Done,
-                        output,
-                        error_message: None,
-                        backtrace: None,
-                    } => {
-                        datum.mark_as_done(output, conn).await?;
-                    }
-
-                    // Set status to `Status::Error`.
-                    DatumPatch {
-                        status: Status::Error,
-                        output,
-                        error_message: Some(error_message),
-                        backtrace: Some(backtrace),
-                    } => {
-                        datum
-                            .mark_as_error(output, error_message, backtrace, conn)
-                            .await?;
-                    }
-
-                    // All other combinations are forbidden.
-                    other => {
-                        return Err(FalconeridError::Internal(format_err!(
-                            "cannot update datum with {:?}",
-                            other
-                        )));
-                    }
-                }
+    Path(job_id): Path<Uuid>,
+    Query(query): Query<JobEventsQuery>,
+) -> FalconeridResult<Json<JobEventsResponse>> {
+    if query.limit.is_some_and(|limit| limit < 1) {
+        return Err(FalconeridError::BadRequest(
+            "limit must be at least 1".to_owned(),
+        ));
+    }
+    let job = Job::find(job_id, &mut conn).await?;
+    let job_events = JobEvent::recent_for_job(job.id, query.limit, &mut conn).await?;
+    Ok(Json(JobEventsResponse { job_events }))
+}
 
-                // If there are no more datums, mark the job as finished (either
-                // done or error).
-                datum.update_job_status_if_done(conn).await?;
+/// The default number of files returned by `job_output_files`.
+const DEFAULT_OUTPUT_FILES_LIMIT: i64 = 1000;
 
-                Ok::<_, FalconeridError>(datum)
-            }
-            .scope_boxed()
-        })
-        .await?;
+/// The maximum number of files returned by `job_output_files`.
+const MAX_OUTPUT_FILES_LIMIT: i64 = 10_000;
 
-    Ok(Json(DatumResponse { datum }))
+/// Query parameters for job_output_files.
+#[derive(Deserialize, utoipa::IntoParams)]
+struct JobOutputFilesQuery {
+    /// Only return files whose URIs sort after this one. Use the previous
+    /// page's `next_after`.
+    after: Option<String>,
+    /// The maximum number of files to return.
+    limit: Option<i64>,
 }
 
-/// Get detailed datum information for display.
+/// Get a page of the output files which a job uploaded successfully, sorted
+/// by URI.
 ///
-/// Used by: CLI (datum describe)
+/// Used by: API clients
 #[utoipa::path(
     get,
-    path = "/datums/{datum_id}/describe",
+    path = "/jobs/{job_id}/output_files",
     params(
-        ("datum_id" = Uuid, Path, description = "The datum UUID")
+        ("job_id" = Uuid, Path, description = "The job UUID"),
+        JobOutputFilesQuery
     ),
     responses(
-        (status = 200, description = "Datum description", body = DatumDescribeResponse)
+        (status = 200, description = "A page of output files, sorted by URI", body = JobOutputFilesResponse),
+        (status = 400, description = "Invalid limit")
     )
 )]
-async fn describe_datum(
+async fn job_output_files(
     _user: User,
     DbConn(mut conn): DbConn,
-    Path(datum_id): Path<Uuid>,
-) -> FalconeridResult<Json<DatumDescribeResponse>> {
-    let datum = Datum::find(datum_id, &mut conn).await?;
-    let input_files = datum.input_files(&mut conn).await?;
-    Ok(Json(DatumDescribeResponse { datum, input_files }))
-}
-
-/// Create a batch of output files for a datum.
-///
-/// Used by: Worker
-#[instrument(skip_all, fields(datum = %datum_id, pod_name = %request.pod_name), level = "debug")]
-async fn create_output_files(
+    Path(job_id): Path<Uuid>,
+    Query(query): Query<JobOutputFilesQuery>,
+) -> FalconeridResult<Json<JobOutputFilesResponse>> {
+    let limit = query.limit.unwrap_or(DEFAULT_OUTPUT_FILES_LIMIT);
+    if !(1..=MAX_OUTPUT_FILES_LIMIT).contains(&limit) {
+        return Err(FalconeridError::BadRequest(format!(
+            "limit must be between 1 and {}",
+            MAX_OUTPUT_FILES_LIMIT
+        )));
+    }
+    let job = Job::find(job_id, &mut conn).await?;
+    let output_files = OutputFile::done_page_for_jobs(
+        &[job.id],
+        query.after.as_deref(),
+        limit,
+        &mut conn,
+    )
+    .await?;
+    // If we got a full page, there may be more.
+    let next_after = if cast::i64(output_files.len())? == limit {
+        output_files.last().map(|f| f.uri.clone())
+    } else {
+        None
+    };
+    Ok(Json(JobOutputFilesResponse {
+        output_files,
+        next_after,
+    }))
+}
+
+/// Get the jobs which a job was retried or rerun from, and the jobs which
+/// were retried or rerun from it.
+///
+/// Used by: CLI (job describe)
+#[utoipa::path(
+    get,
+    path = "/jobs/{job_id}/lineage",
+    params(
+        ("job_id" = Uuid, Path, description = "The job UUID")
+    ),
+    responses(
+        (status = 200, description = "Ancestors and descendants of the job", body = JobLineageResponse)
+    )
+)]
+async fn job_lineage(
+    _user: User,
+    DbConn(mut conn): DbConn,
+    Path(job_id): Path<Uuid>,
+) -> FalconeridResult<Json<JobLineageResponse>> {
+    let job = Job::find(job_id, &mut conn).await?;
+    let job_lineage = job.lineage(&mut conn).await?;
+    Ok(Json(JobLineageResponse { job_lineage }))
+}
+
+/// Get the pipeline spec which was submitted to create a job. This can be
+/// saved and passed to `falconeri job run` to reproduce the job.
+///
+/// Used by: CLI (job spec)
+#[utoipa::path(
+    get,
+    path = "/jobs/{job_id}/spec",
+    params(
+        ("job_id" = Uuid, Path, description = "The job UUID")
+    ),
+    responses(
+        (status = 200, description = "The job's pipeline spec", body = PipelineSpec)
+    )
+)]
+async fn job_spec(
+    _user: User,
+    DbConn(mut conn): DbConn,
+    Path(job_id): Path<Uuid>,
+) -> FalconeridResult<Json<PipelineSpec>> {
+    let job = Job::find(job_id, &mut conn).await?;
+    Ok(Json(job_pipeline_spec(&job)?))
+}
+
+/// Get statistics about a job's datums.
+///
+/// Used by: CLI (job stats)
+#[utoipa::path(
+    get,
+    path = "/jobs/{job_id}/stats",
+    params(
+        ("job_id" = Uuid, Path, description = "The job UUID")
+    ),
+    responses(
+        (status = 200, description = "Job statistics", body = JobStatsResponse)
+    )
+)]
+async fn job_stats(
+    _user: User,
+    DbConn(mut conn): DbConn,
+    Path(job_id): Path<Uuid>,
+) -> FalconeridResult<Json<JobStatsResponse>> {
+    let job = Job::find(job_id, &mut conn).await?;
+    let job_stats = job.stats(&mut conn).await?;
+    Ok(Json(JobStatsResponse { job_stats }))
+}
+
+/// The default value of `headroom_percent` for `recommended_resources`.
+const DEFAULT_HEADROOM_PERCENT: u32 = 20;
+
+/// The largest value of `headroom_percent` we accept.
+const MAX_HEADROOM_PERCENT: u32 = 1000;
+
+/// Query parameters for recommended_resources.
+#[derive(Deserialize, utoipa::IntoParams)]
+struct RecommendedResourcesQuery {
+    /// How much to add to the 95th percentile of what previous runs used, as
+    /// a percentage. Defaults to 20.
+    headroom_percent: Option<u32>,
+}
+
+/// Suggest resource requests for a job's pipeline, based on the memory and
+/// CPU time used by the datums of every job with the same pipeline name.
+///
+/// Used by: CLI (job recommend-resources)
+#[utoipa::path(
+    get,
+    path = "/jobs/{job_id}/recommended_resources",
+    params(
+        ("job_id" = Uuid, Path, description = "The job UUID"),
+        RecommendedResourcesQuery
+    ),
+    responses(
+        (status = 200, description = "Recommended resource requests", body = RecommendedResourcesResponse),
+        (status = 400, description = "Invalid headroom_percent")
+    )
+)]
+async fn recommended_resources(
+    _user: User,
+    DbConn(mut conn): DbConn,
+    Path(job_id): Path<Uuid>,
+    Query(query): Query<RecommendedResourcesQuery>,
+) -> FalconeridResult<Json<RecommendedResourcesResponse>> {
+    let headroom_percent = query.headroom_percent.unwrap_or(DEFAULT_HEADROOM_PERCENT);
+    if headroom_percent > MAX_HEADROOM_PERCENT {
+        return Err(FalconeridError::BadRequest(format!(
+            "headroom_percent must be at most {}",
+            MAX_HEADROOM_PERCENT
+        )));
+    }
+    let job = Job::find(job_id, &mut conn).await?;
+    let recommended_resources = job
+        .recommended_resources(headroom_percent, &mut conn)
+        .await?;
+    Ok(Json(RecommendedResourcesResponse {
+        recommended_resources,
+    }))
+}
+
+/// Check that a job's datums were accounted for exactly once: every input
+/// file belongs to exactly one datum, every done datum has output files or
+/// reported writing none, and no done datum is still reserved.
+///
+/// Used by: CLI (job audit)
+#[utoipa::path(
+    get,
+    path = "/jobs/{job_id}/audit",
+    params(
+        ("job_id" = Uuid, Path, description = "The job UUID")
+    ),
+    responses(
+        (status = 200, description = "Consistency violations, if any", body = JobAuditResponse)
+    )
+)]
+async fn job_audit(
+    _user: User,
+    DbConn(mut conn): DbConn,
+    Path(job_id): Path<Uuid>,
+) -> FalconeridResult<Json<JobAuditResponse>> {
+    let job = Job::find(job_id, &mut conn).await?;
+    let job_audit = job.audit(&mut conn).await?;
+    if !job_audit.violations.is_empty() {
+        warn!(
+            job = %job_id,
+            count = job_audit.violations.len(),
+            "job audit found violations"
+        );
+    }
+    Ok(Json(JobAuditResponse { job_audit }))
+}
+
+/// Retry a job, and return the new job as JSON.
+///
+/// Used by: CLI (job retry)
+#[utoipa::path(
+    post,
+    path = "/jobs/{job_id}/retry",
+    params(
+        ("job_id" = Uuid, Path, description = "The job UUID to retry")
+    ),
+    responses(
+        (status = 200, description = "New job created from retry", body = JobResponse)
+    )
+)]
+async fn job_retry(
+    user: User,
+    State(state): State<AppState>,
+    DbConn(mut conn): DbConn,
+    Path(job_id): Path<Uuid>,
+) -> FalconeridResult<Json<JobResponse>> {
+    let job = Job::find(job_id, &mut conn).await?;
+    user.check_team(job.team.as_deref())?;
+    let new_job = retry_job(&job, state.skip_kubernetes, &mut conn).await?;
+    Ok(Json(JobResponse { job: new_job }))
+}
+
+/// Submit a fresh copy of a past job, optionally overriding its image tag,
+/// command or egress URI, and return the new job as JSON.
+///
+/// Used by: CLI (job rerun)
+#[utoipa::path(
+    post,
+    path = "/jobs/{job_id}/rerun",
+    request_body = RerunJobRequest,
+    params(
+        ("job_id" = Uuid, Path, description = "The job UUID to rerun")
+    ),
+    responses(
+        (status = 200, description = "New job created from the original job's spec", body = JobResponse)
+    )
+)]
+#[instrument(skip_all, fields(job = %job_id), level = "debug")]
+async fn job_rerun(
+    user: User,
+    State(state): State<AppState>,
+    DbConn(mut conn): DbConn,
+    Path(job_id): Path<Uuid>,
+    Json(request): Json<RerunJobRequest>,
+) -> FalconeridResult<Json<JobResponse>> {
+    let job = Job::find(job_id, &mut conn).await?;
+    user.check_team(job.team.as_deref())?;
+    let new_job = rerun_job(
+        state.pool.clone(),
+        &job,
+        &request,
+        state.skip_kubernetes,
+        &mut conn,
+    )
+    .await?;
+    Ok(Json(JobResponse { job: new_job }))
+}
+
+/// Change a running job. For now, this can only change the job's target
+/// parallelism, which we apply to the Kubernetes job and enforce when workers
+/// reserve datums.
+///
+/// Used by: CLI (job scale)
+#[utoipa::path(
+    patch,
+    path = "/jobs/{job_id}",
+    params(
+        ("job_id" = Uuid, Path, description = "The job UUID to change")
+    ),
+    request_body = JobPatch,
+    responses(
+        (status = 200, description = "The updated job", body = JobResponse),
+        (status = 400, description = "The requested parallelism is invalid"),
+        (status = 409, description = "Job is not running"),
+        (status = 429, description = "Scaling up would exceed the team's quota")
+    )
+)]
+#[instrument(skip_all, fields(job = %job_id), level = "debug")]
+async fn patch_job(
+    user: User,
+    DbConn(mut conn): DbConn,
+    Path(job_id): Path<Uuid>,
+    Json(patch): Json<JobPatch>,
+) -> FalconeridResult<Json<JobResponse>> {
+    let mut job = Job::find(job_id, &mut conn).await?;
+    user.check_team(job.team.as_deref())?;
+    if let Some(parallelism) = patch.target_parallelism {
+        let parallelism = match i32::try_from(parallelism) {
+            Ok(parallelism) if parallelism > 0 => parallelism,
+            _ => {
+                return Err(FalconeridError::BadRequest(format!(
+                    "target_parallelism must be between 1 and {}",
+                    i32::MAX
+                )))
+            }
+        };
+        if !matches!(job.status, Status::Running | Status::Streaming) {
+            return Err(FalconeridError::Conflict(format!(
+                "job {} has status {}, so it can't be scaled",
+                job.job_name, job.status
+            )));
+        }
+
+        let message = match job.target_parallelism {
+            Some(old) => format!("scaled from {} to {} workers", old, parallelism),
+            None => format!("scaled to {} workers", parallelism),
+        };
+        let scaled_job = &mut job;
+        conn.transaction(|conn| {
+            async move {
+                // Only check quotas when we're adding workers, so that teams
+                // which are over quota can always scale down. This locks the
+                // team's quota until we've recorded the new parallelism.
+                let additional =
+                    parallelism - scaled_job.target_parallelism.unwrap_or(0);
+                if additional > 0 {
+                    Quota::enforce_scale_up(
+                        scaled_job.team.as_deref(),
+                        additional.unsigned_abs(),
+                        conn,
+                    )
+                    .await?;
+                }
+                scaled_job.set_target_parallelism(parallelism, conn).await?;
+                set_job_parallelism(&scaled_job.job_name, parallelism).await?;
+                Ok::<_, Error>(())
+            }
+            .scope_boxed()
+        })
+        .await?;
+        info!("job {} {}", job.job_name, message);
+        JobEvent::record(job.id, None, JobEventKind::JobScaled, &message, &mut conn)
+            .await?;
+    }
+    Ok(Json(JobResponse { job }))
+}
+
+/// Stop adding datums to a streaming job. The job will finish once its
+/// remaining datums have been processed.
+///
+/// Used by: CLI (job stop-streaming)
+#[utoipa::path(
+    post,
+    path = "/jobs/{job_id}/stop_streaming",
+    params(
+        ("job_id" = Uuid, Path, description = "The job UUID to stop streaming")
+    ),
+    responses(
+        (status = 200, description = "Job is no longer streaming", body = JobResponse),
+        (status = 409, description = "Job is not streaming")
+    )
+)]
+#[instrument(skip_all, fields(job = %job_id), level = "debug")]
+async fn job_stop_streaming(
+    user: User,
+    DbConn(mut conn): DbConn,
+    Path(job_id): Path<Uuid>,
+) -> FalconeridResult<Json<JobResponse>> {
+    let mut job = Job::find(job_id, &mut conn).await?;
+    user.check_team(job.team.as_deref())?;
+    if job.status != Status::Streaming {
+        return Err(FalconeridError::Conflict(format!(
+            "job {} has status {}, not streaming",
+            job.job_name, job.status
+        )));
+    }
+    job.stop_streaming(&mut conn).await?;
+    Ok(Json(JobResponse { job }))
+}
+
+/// Reserve the next available datum for a job, and return it along with a list
+/// of input files.
+///
+/// Used by: Worker
+#[utoipa::path(
+    post,
+    path = "/jobs/{job_id}/reserve_next_datum",
+    params(
+        ("job_id" = Uuid, Path, description = "The job UUID")
+    ),
+    request_body = DatumReservationRequest,
+    responses(
+        (status = 200, description = "The reserved datum and its input files, or `null` if no datum is available right now", body = Option<DatumReservationResponse>),
+        (status = 429, description = "This worker is making too many requests", body = String)
+    )
+)]
+#[instrument(skip_all, fields(job = %job_id, pod_name = %request.pod_name), level = "debug")]
+async fn job_reserve_next_datum(
+    caller: Caller,
+    DbConn(mut conn): DbConn,
+    Path(job_id): Path<Uuid>,
+    Json(request): Json<DatumReservationRequest>,
+) -> FalconeridResult<Json<Option<DatumReservationResponse>>> {
+    caller.check_job(job_id)?;
+
+    // While we're being upgraded, tell workers that there's nothing to do, so
+    // that they wait and try again later.
+    if ServerSettings::load(&mut conn).await?.reservations_paused {
+        debug!("datum reservations are paused");
+        return Ok(Json(None));
+    }
+
+    let job = Job::find(job_id, &mut conn).await?;
+    let reserved = job
+        .reserve_next_datum(
+            &request.node_name,
+            &request.pod_name,
+            request.prefetch,
+            &mut conn,
+        )
+        .await?;
+    let result = reserved
+        .map(|(datum, input_files)| DatumReservationResponse { datum, input_files });
+    if let Some(ref res) = result {
+        debug!(datum = %res.datum.id, "reserved datum");
+    } else {
+        debug!("no datums available to reserve");
+    }
+    Ok(Json(result))
+}
+
+/// Pause or resume datum reservations for all jobs. While reservations are
+/// paused, workers are told that there are no datums available, so they wait
+/// and try again.
+///
+/// Used by: CLI (deploy --upgrade)
+#[utoipa::path(
+    put,
+    path = "/admin/reservations",
+    request_body = ReservationsRequest,
+    responses(
+        (status = 200, description = "Reservations paused or resumed", body = ReservationsResponse)
+    )
+)]
+#[instrument(skip_all, fields(paused = request.paused), level = "debug")]
+async fn put_reservations(
+    _user: User,
+    DbConn(mut conn): DbConn,
+    Json(request): Json<ReservationsRequest>,
+) -> FalconeridResult<Json<ReservationsResponse>> {
+    let settings =
+        ServerSettings::set_reservations_paused(request.paused, &mut conn).await?;
+    if settings.reservations_paused {
+        warn!("datum reservations are now paused");
+    } else {
+        info!("datum reservations have been resumed");
+    }
+    Ok(Json(ReservationsResponse {
+        paused: settings.reservations_paused,
+    }))
+}
+
+/// Register a pipeline, which may run on a cron schedule.
+///
+/// Used by: CLI (pipeline create)
+#[utoipa::path(
+    post,
+    path = "/pipelines",
+    request_body = CreatePipelineRequest,
+    responses(
+        (status = 200, description = "Pipeline registered", body = RegisteredPipelineResponse),
+        (status = 400, description = "Invalid pipeline name or schedule"),
+        (status = 409, description = "A pipeline with this name already exists")
+    )
+)]
+#[instrument(skip_all, fields(name = %request.name), level = "debug")]
+async fn post_pipeline(
+    user: User,
+    DbConn(mut conn): DbConn,
+    Json(request): Json<CreatePipelineRequest>,
+) -> FalconeridResult<Json<RegisteredPipelineResponse>> {
+    user.check_team(request.pipeline_spec.team.as_deref())?;
+    let new_pipeline = NewRegisteredPipeline::new(
+        &request.name,
+        &request.pipeline_spec,
+        request.schedule.as_deref(),
+        Utc::now().naive_utc(),
+    )
+    .map_err(|err| FalconeridError::BadRequest(format!("{:#}", err)))?;
+    let pipeline = if request.replace {
+        // Don't let a team replace another team's pipeline.
+        if let Some(existing) =
+            RegisteredPipeline::find_by_name(&request.name, &mut conn).await?
+        {
+            user.check_team(existing.pipeline_spec()?.team.as_deref())?;
+        }
+        new_pipeline.upsert(&mut conn).await?
+    } else {
+        new_pipeline.insert(&mut conn).await?.ok_or_else(|| {
+            FalconeridError::Conflict(format!(
+                "a pipeline named {} already exists (use --replace to update it)",
+                request.name
+            ))
+        })?
+    };
+    Ok(Json(RegisteredPipelineResponse { pipeline }))
+}
+
+/// List all registered pipelines.
+///
+/// Used by: CLI (pipeline list)
+#[utoipa::path(
+    get,
+    path = "/pipelines",
+    responses(
+        (status = 200, description = "All registered pipelines", body = RegisteredPipelinesResponse)
+    )
+)]
+async fn list_pipelines(
+    _user: User,
+    DbConn(mut conn): DbConn,
+) -> FalconeridResult<Json<RegisteredPipelinesResponse>> {
+    let pipelines = RegisteredPipeline::list(&mut conn).await?;
+    Ok(Json(RegisteredPipelinesResponse { pipelines }))
+}
+
+/// List all team quotas.
+///
+/// Used by: CLI (quota list)
+#[utoipa::path(
+    get,
+    path = "/quotas",
+    responses(
+        (status = 200, description = "All team quotas", body = QuotasResponse)
+    )
+)]
+async fn list_quotas(
+    _user: User,
+    DbConn(mut conn): DbConn,
+) -> FalconeridResult<Json<QuotasResponse>> {
+    let quotas = Quota::list(&mut conn).await?;
+    Ok(Json(QuotasResponse { quotas }))
+}
+
+/// Create or replace a team's quota.
+///
+/// Used by: CLI (quota set)
+#[utoipa::path(
+    put,
+    path = "/quotas/{team}",
+    request_body = SetQuotaRequest,
+    params(
+        ("team" = String, Path, description = "The team name")
+    ),
+    responses(
+        (status = 200, description = "Quota created or replaced", body = QuotaResponse),
+        (status = 400, description = "Invalid quota"),
+        (status = 403, description = "Not using the admin password")
+    )
+)]
+#[instrument(skip_all, fields(team = %team), level = "debug")]
+async fn put_quota(
+    _admin: Admin,
+    DbConn(mut conn): DbConn,
+    Path(team): Path<String>,
+    Json(request): Json<SetQuotaRequest>,
+) -> FalconeridResult<Json<QuotaResponse>> {
+    if team.is_empty() {
+        return Err(FalconeridError::BadRequest(
+            "team name must not be empty".to_owned(),
+        ));
+    }
+    let limits = [request.max_running_jobs, request.max_parallelism];
+    if limits.iter().flatten().any(|&limit| limit < 0) {
+        return Err(FalconeridError::BadRequest(
+            "quota limits must not be negative".to_owned(),
+        ));
+    }
+    let quota = NewQuota {
+        team,
+        max_running_jobs: request.max_running_jobs,
+        max_parallelism: request.max_parallelism,
+    }
+    .upsert(&mut conn)
+    .await?;
+    info!("set quota for team {}", quota.team);
+    Ok(Json(QuotaResponse { quota }))
+}
+
+/// Delete a team's quota, so that its jobs are no longer limited.
+///
+/// Used by: CLI (quota delete)
+#[utoipa::path(
+    delete,
+    path = "/quotas/{team}",
+    params(
+        ("team" = String, Path, description = "The team name")
+    ),
+    responses(
+        (status = 204, description = "Quota deleted"),
+        (status = 403, description = "Not using the admin password"),
+        (status = 404, description = "No quota for this team")
+    )
+)]
+#[instrument(skip_all, fields(team = %team), level = "debug")]
+async fn delete_quota(
+    _admin: Admin,
+    DbConn(mut conn): DbConn,
+    Path(team): Path<String>,
+) -> FalconeridResult<StatusCode> {
+    let quota = Quota::find(&team, &mut conn).await?.ok_or_else(|| {
+        FalconeridError::NotFound(format!("no quota for team {}", team))
+    })?;
+    quota.delete(&mut conn).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// List all API tokens, including revoked ones. Only admins may do this.
+///
+/// Used by: CLI (api-token list)
+#[utoipa::path(
+    get,
+    path = "/api_tokens",
+    responses(
+        (status = 200, description = "All API tokens", body = ApiTokensResponse),
+        (status = 403, description = "Not using the admin password")
+    )
+)]
+async fn list_api_tokens(
+    _admin: Admin,
+    DbConn(mut conn): DbConn,
+) -> FalconeridResult<Json<ApiTokensResponse>> {
+    let api_tokens = ApiToken::list(&mut conn).await?;
+    Ok(Json(ApiTokensResponse { api_tokens }))
+}
+
+/// Create an API token, which may be sent as a bearer token instead of
+/// using the admin password. Only admins may do this.
+///
+/// Used by: CLI (api-token create)
+#[utoipa::path(
+    post,
+    path = "/api_tokens",
+    request_body = CreateApiTokenRequest,
+    responses(
+        (status = 200, description = "Token created", body = CreateApiTokenResponse),
+        (status = 400, description = "Invalid token or team name"),
+        (status = 403, description = "Not using the admin password")
+    )
+)]
+#[instrument(skip_all, fields(name = %request.name), level = "debug")]
+async fn create_api_token(
+    _admin: Admin,
+    DbConn(mut conn): DbConn,
+    Json(request): Json<CreateApiTokenRequest>,
+) -> FalconeridResult<Json<CreateApiTokenResponse>> {
+    if request.name.is_empty() {
+        return Err(FalconeridError::BadRequest(
+            "token name must not be empty".to_owned(),
+        ));
+    }
+    if request.team.as_deref() == Some("") {
+        return Err(FalconeridError::BadRequest(
+            "team name must not be empty".to_owned(),
+        ));
+    }
+    let (api_token, token) =
+        ApiToken::mint(&request.name, request.team.as_deref(), &mut conn).await?;
+    info!("created API token {} ({})", api_token.id, api_token.name);
+    Ok(Json(CreateApiTokenResponse { api_token, token }))
+}
+
+/// Revoke an API token, so that it can no longer be used. Only admins may do
+/// this.
+///
+/// Used by: CLI (api-token revoke)
+#[utoipa::path(
+    delete,
+    path = "/api_tokens/{id}",
+    params(
+        ("id" = Uuid, Path, description = "The token's ID")
+    ),
+    responses(
+        (status = 200, description = "Token revoked", body = ApiTokenResponse),
+        (status = 403, description = "Not using the admin password"),
+        (status = 404, description = "No such token")
+    )
+)]
+#[instrument(skip_all, fields(id = %id), level = "debug")]
+async fn revoke_api_token(
+    _admin: Admin,
+    DbConn(mut conn): DbConn,
+    Path(id): Path<Uuid>,
+) -> FalconeridResult<Json<ApiTokenResponse>> {
+    let api_token = ApiToken::revoke(id, &mut conn)
+        .await?
+        .ok_or_else(|| FalconeridError::NotFound(format!("no API token {}", id)))?;
+    info!("revoked API token {} ({})", api_token.id, api_token.name);
+    Ok(Json(ApiTokenResponse { api_token }))
+}
+
+/// Look up a registered pipeline by name, or return a 404 error.
+async fn find_registered_pipeline(
+    name: &str,
+    conn: &mut AsyncPgConnection,
+) -> FalconeridResult<RegisteredPipeline> {
+    RegisteredPipeline::find_by_name(name, conn)
+        .await?
+        .ok_or_else(|| {
+            FalconeridError::NotFound(format!("no pipeline named {}", name))
+        })
+}
+
+/// Delete a registered pipeline. Jobs which it already started are not
+/// affected.
+///
+/// Used by: CLI (pipeline delete)
+#[utoipa::path(
+    delete,
+    path = "/pipelines/{name}",
+    params(
+        ("name" = String, Path, description = "The pipeline name")
+    ),
+    responses(
+        (status = 204, description = "Pipeline deleted"),
+        (status = 404, description = "No such pipeline")
+    )
+)]
+#[instrument(skip_all, fields(name = %name), level = "debug")]
+async fn delete_pipeline(
+    user: User,
+    DbConn(mut conn): DbConn,
+    Path(name): Path<String>,
+) -> FalconeridResult<StatusCode> {
+    let pipeline = find_registered_pipeline(&name, &mut conn).await?;
+    user.check_team(pipeline.pipeline_spec()?.team.as_deref())?;
+    pipeline.delete(&mut conn).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Query parameters for trigger_pipeline.
+#[derive(Deserialize, utoipa::IntoParams)]
+struct TriggerPipelineQuery {
+    /// Start the job even if an identical job is already running.
+    #[serde(default)]
+    force: bool,
+}
+
+/// Start a job from a registered pipeline right away.
+///
+/// Used by: CLI (pipeline trigger)
+#[utoipa::path(
+    post,
+    path = "/pipelines/{name}/trigger",
+    params(
+        ("name" = String, Path, description = "The pipeline name"),
+        TriggerPipelineQuery
+    ),
+    responses(
+        (status = 200, description = "Job created (datums are created in the background)", body = JobResponse),
+        (status = 404, description = "No such pipeline"),
+        (status = 409, description = "An identical job is already running")
+    )
+)]
+#[instrument(skip_all, fields(name = %name), level = "debug")]
+async fn trigger_pipeline(
+    user: User,
+    State(state): State<AppState>,
+    DbConn(mut conn): DbConn,
+    Path(name): Path<String>,
+    Query(query): Query<TriggerPipelineQuery>,
+) -> FalconeridResult<Json<JobResponse>> {
+    let mut pipeline = find_registered_pipeline(&name, &mut conn).await?;
+    let pipeline_spec = pipeline.pipeline_spec()?;
+    user.check_team(pipeline_spec.team.as_deref())?;
+    let spec_hash = pipeline_spec.canonical_hash()?;
+    let job = run_registered_pipeline(
+        state.pool.clone(),
+        &mut pipeline,
+        &pipeline_spec,
+        &spec_hash,
+        query.force,
+        state.skip_kubernetes,
+        &mut conn,
+    )
+    .await?;
+    Ok(Json(JobResponse { job }))
+}
+
+/// Update a datum when it's done.
+///
+/// Used by: Worker
+#[utoipa::path(
+    patch,
+    path = "/datums/{datum_id}",
+    params(
+        ("datum_id" = Uuid, Path, description = "The datum UUID")
+    ),
+    request_body = UpdateDatumRequest,
+    responses(
+        (status = 200, description = "Datum updated", body = DatumResponse),
+        (status = 403, description = "The datum is not running, or belongs to another worker", body = String),
+        (status = 429, description = "This worker is making too many requests", body = String)
+    )
+)]
+#[instrument(skip_all, fields(datum = %datum_id, pod_name = %request.pod_name), level = "debug")]
+async fn patch_datum(
+    caller: Caller,
+    State(state): State<AppState>,
+    DbConn(mut conn): DbConn,
+    Path(datum_id): Path<Uuid>,
+    Json(request): Json<UpdateDatumRequest>,
+) -> FalconeridResult<Json<DatumResponse>> {
+    caller.check_datum(datum_id, &mut conn).await?;
+    let patch = request.datum.clone();
+    debug!(status = ?patch.status, "updating datum");
+
+    // Wrap everything in a transaction for the ownership lock.
+    let datum = conn
+        .transaction(|conn| {
+            async move {
+                // Lock datum and verify ownership and status (returns 403 if mismatch).
+                let mut datum = Datum::lock_and_verify_owner(
+                    datum_id,
+                    &request.pod_name,
+                    Status::Running,
+                    conn,
+                )
+                .await
+                .map_err(FalconeridError::from)?;
+
+                // We only support a few very specific types of patches.
+                let report = patch.report();
+                match &patch {
+                    // Set status to `Status::Done`.
+                    DatumPatch {
+                        status: Status::Done,
+                        error_message: None,
+                        backtrace: None,
+                        failure_class: None,
+                        ..
+                    } => {
+                        datum.mark_as_done(&report, conn).await?;
+                    }
+
+                    // Set status to `Status::Error`.
+                    DatumPatch {
+                        status: Status::Error,
+                        error_message: Some(error_message),
+                        backtrace: Some(backtrace),
+                        failure_class,
+                        ..
+                    } => {
+                        datum
+                            .mark_as_error(
+                                &report,
+                                error_message,
+                                backtrace,
+                                *failure_class,
+                                conn,
+                            )
+                            .await?;
+                    }
+
+                    // All other combinations are forbidden.
+                    other => {
+                        return Err(FalconeridError::Internal(format_err!(
+                            "cannot update datum with {:?}",
+                            other
+                        )));
+                    }
+                }
+
+                // If this worker already reserved its next datum, that datum
+                // now becomes its current one.
+                if !datum.prefetched {
+                    Datum::promote_prefetched_for_pod(
+                        datum.job_id,
+                        &request.pod_name,
+                        conn,
+                    )
+                    .await?;
+                }
+
+                // If there are no more datums, mark the job as finished (either
+                // done or error).
+                datum.update_job_status_if_done(conn).await?;
+
+                Ok::<_, FalconeridError>(datum)
+            }
+            .scope_boxed()
+        })
+        .await?;
+
+    // If this failure means we should give up on the whole job, stop the
+    // Kubernetes job now instead of waiting for the workers to notice.
+    if datum.status == Status::Error {
+        let job = Job::find(datum.job_id, &mut conn).await?;
+        if job.was_stopped_early() {
+            stop_batch_job(&job, state.skip_kubernetes).await?;
+        }
+    }
+
+    Ok(Json(DatumResponse { datum }))
+}
+
+/// Append to the output of a running datum, so that people can watch it with
+/// `datum logs --follow`. The worker replaces the output when the datum
+/// finishes.
+///
+/// Used by: Worker
+#[utoipa::path(
+    patch,
+    path = "/datums/{datum_id}/append_output",
+    params(
+        ("datum_id" = Uuid, Path, description = "The datum UUID")
+    ),
+    request_body = AppendDatumOutputRequest,
+    responses(
+        (status = 204, description = "Output appended"),
+        (status = 403, description = "The datum is not running, or belongs to another worker", body = String),
+        (status = 409, description = "The offset doesn't match the datum's output", body = String),
+        (status = 429, description = "This worker is making too many requests", body = String)
+    )
+)]
+#[instrument(skip_all, fields(datum = %datum_id, pod_name = %request.pod_name), level = "debug")]
+async fn append_datum_output(
+    caller: Caller,
+    DbConn(mut conn): DbConn,
+    Path(datum_id): Path<Uuid>,
+    Json(request): Json<AppendDatumOutputRequest>,
+) -> FalconeridResult<StatusCode> {
+    caller.check_datum(datum_id, &mut conn).await?;
+    let offset = usize::try_from(request.offset)
+        .map_err(|_| FalconeridError::BadRequest("offset is too large".to_owned()))?;
+    conn.transaction(|conn| {
+        async move {
+            // Lock datum and verify ownership and status (returns 403 if mismatch).
+            let mut datum = Datum::lock_and_verify_owner(
+                datum_id,
+                &request.pod_name,
+                Status::Running,
+                conn,
+            )
+            .await
+            .map_err(FalconeridError::from)?;
+
+            let output = datum
+                .output_after_append(offset, &request.output)
+                .map_err(|err| FalconeridError::Conflict(format!("{:#}", err)))?;
+            if let Some(output) = output {
+                datum.update_running_output(output, conn).await?;
+            }
+            Ok::<_, FalconeridError>(())
+        }
+        .scope_boxed()
+    })
+    .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Return a running datum to the queue without counting an attempt, because
+/// the worker processing it is being shut down (for example, because its spot
+/// instance is being preempted).
+///
+/// Used by: Worker
+#[utoipa::path(
+    post,
+    path = "/datums/{datum_id}/release",
+    params(
+        ("datum_id" = Uuid, Path, description = "The datum UUID")
+    ),
+    request_body = ReleaseDatumRequest,
+    responses(
+        (status = 200, description = "Datum released", body = DatumResponse),
+        (status = 403, description = "The datum is not running, or belongs to another worker", body = String)
+    )
+)]
+#[instrument(skip_all, fields(datum = %datum_id, pod_name = %request.pod_name), level = "debug")]
+async fn release_datum(
+    caller: Caller,
+    DbConn(mut conn): DbConn,
+    Path(datum_id): Path<Uuid>,
+    Json(request): Json<ReleaseDatumRequest>,
+) -> FalconeridResult<Json<DatumResponse>> {
+    caller.check_datum(datum_id, &mut conn).await?;
+    let datum = conn
+        .transaction(|conn| {
+            async move {
+                // Lock datum and verify ownership and status (returns 403 if mismatch).
+                let mut datum = Datum::lock_and_verify_owner(
+                    datum_id,
+                    &request.pod_name,
+                    Status::Running,
+                    conn,
+                )
+                .await
+                .map_err(FalconeridError::from)?;
+
+                warn!("releasing datum {} from pod {}", datum.id, request.pod_name);
+                datum.release(conn).await?;
+
+                // Remove any `OutputFile` records, so that the next worker can
+                // upload the same output files again.
+                OutputFile::delete_for_datum(&datum, conn).await?;
+                Ok::<_, FalconeridError>(datum)
+            }
+            .scope_boxed()
+        })
+        .await?;
+    Ok(Json(DatumResponse { datum }))
+}
+
+/// Get detailed datum information for display.
+///
+/// Used by: CLI (datum describe)
+#[utoipa::path(
+    get,
+    path = "/datums/{datum_id}/describe",
+    params(
+        ("datum_id" = Uuid, Path, description = "The datum UUID")
+    ),
+    responses(
+        (status = 200, description = "Datum description", body = DatumDescribeResponse)
+    )
+)]
+async fn describe_datum(
     _user: User,
     DbConn(mut conn): DbConn,
     Path(datum_id): Path<Uuid>,
+) -> FalconeridResult<Json<DatumDescribeResponse>> {
+    let datum = Datum::find(datum_id, &mut conn).await?;
+    let input_files = datum.input_files(&mut conn).await?;
+    Ok(Json(DatumDescribeResponse { datum, input_files }))
+}
+
+/// Create a batch of output files for a datum.
+///
+/// Used by: Worker
+#[utoipa::path(
+    post,
+    path = "/datums/{datum_id}/output_files",
+    params(
+        ("datum_id" = Uuid, Path, description = "The datum UUID")
+    ),
+    request_body = CreateOutputFilesRequest,
+    responses(
+        (status = 200, description = "Output files created", body = OutputFilesResponse),
+        (status = 403, description = "The datum is not running, or belongs to another worker", body = String),
+        (status = 409, description = "Another datum in this job has already written one of these URIs", body = String),
+        (status = 429, description = "This worker is making too many requests", body = String)
+    )
+)]
+#[instrument(skip_all, fields(datum = %datum_id, pod_name = %request.pod_name), level = "debug")]
+async fn create_output_files(
+    caller: Caller,
+    DbConn(mut conn): DbConn,
+    Path(datum_id): Path<Uuid>,
     Json(request): Json<CreateOutputFilesRequest>,
 ) -> FalconeridResult<Json<OutputFilesResponse>> {
+    caller.check_datum(datum_id, &mut conn).await?;
     let output_files = conn
         .transaction(|conn| {
             async move {
@@ -451,6 +2069,11 @@ async fn create_output_files(
                     }
                 }
 
+                // Never let two datums write the same URI, or one will silently
+                // overwrite the other (returns 409 if they would).
+                let uris: Vec<&str> = new_files.iter().map(|f| f.uri.as_str()).collect();
+                OutputFile::check_uris_unclaimed(&datum, &uris, conn).await?;
+
                 let output_files = NewOutputFile::insert_all(&new_files, conn).await?;
                 Ok::<_, FalconeridError>(output_files)
             }
@@ -464,13 +2087,27 @@ async fn create_output_files(
 /// Update a batch of output files for a datum.
 ///
 /// Used by: Worker
+#[utoipa::path(
+    patch,
+    path = "/datums/{datum_id}/output_files",
+    params(
+        ("datum_id" = Uuid, Path, description = "The datum UUID")
+    ),
+    request_body = UpdateOutputFilesRequest,
+    responses(
+        (status = 204, description = "Output files updated"),
+        (status = 403, description = "The datum is not running, or belongs to another worker", body = String),
+        (status = 429, description = "This worker is making too many requests", body = String)
+    )
+)]
 #[instrument(skip_all, fields(datum = %datum_id, pod_name = %request.pod_name), level = "debug")]
 async fn patch_output_files(
-    _user: User,
+    caller: Caller,
     DbConn(mut conn): DbConn,
     Path(datum_id): Path<Uuid>,
     Json(request): Json<UpdateOutputFilesRequest>,
 ) -> FalconeridResult<StatusCode> {
+    caller.check_datum(datum_id, &mut conn).await?;
     // Separate patches by status.
     let mut done_ids = vec![];
     let mut error_ids = vec![];
@@ -489,6 +2126,18 @@ async fn patch_output_files(
     let done_count = done_ids.len();
     let error_count = error_ids.len();
 
+    // Collect any sizes and checksums which the worker reported.
+    let mut checksum_ids = vec![];
+    let mut sizes = vec![];
+    let mut sha256s = vec![];
+    for patch in &request.output_files {
+        if patch.size.is_some() || patch.sha256.is_some() {
+            checksum_ids.push(patch.id);
+            sizes.push(patch.size.and_then(|size| i64::try_from(size).ok()));
+            sha256s.push(patch.sha256.clone());
+        }
+    }
+
     // Apply our updates within a transaction that verifies ownership.
     conn.transaction(|conn| {
         async move {
@@ -504,6 +2153,13 @@ async fn patch_output_files(
 
             OutputFile::mark_ids_as_done(&done_ids, conn).await?;
             OutputFile::mark_ids_as_error(&error_ids, conn).await?;
+            OutputFile::record_sizes_and_checksums(
+                &checksum_ids,
+                &sizes,
+                &sha256s,
+                conn,
+            )
+            .await?;
             Ok::<_, FalconeridError>(())
         }
         .scope_boxed()
@@ -518,6 +2174,88 @@ async fn patch_output_files(
     Ok(StatusCode::NO_CONTENT)
 }
 
+/// Build our router, with all our routes and middleware.
+fn router(state: AppState) -> Router {
+    // Routes which workers call in a loop. A misbehaving worker can hammer
+    // these, so we rate limit each client separately. We also limit how many
+    // of these we handle at once, so that hundreds of well-behaved workers
+    // can't use up all our database connections.
+    let worker_routes = Router::new()
+        .route(
+            "/jobs/{job_id}/reserve_next_datum",
+            post(job_reserve_next_datum),
+        )
+        .route("/datums/{datum_id}", patch(patch_datum))
+        .route(
+            "/datums/{datum_id}/append_output",
+            patch(append_datum_output),
+        )
+        .route(
+            "/datums/{datum_id}/output_files",
+            post(create_output_files).patch(patch_output_files),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            state.concurrency_limiter.clone(),
+            limit_concurrency,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            state.rate_limiter.clone(),
+            rate_limit,
+        ));
+
+    Router::new()
+        .route("/version", get(version))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/metrics", get(metrics))
+        .route("/jobs", post(post_job).get(get_job_by_name))
+        .route("/jobs/list", get(list_jobs))
+        .route("/jobs/plan", post(post_job_plan))
+        .route("/jobs/search", get(search_jobs))
+        .route("/jobs/{job_id}", get(get_job).patch(patch_job))
+        .route("/jobs/{job_id}/describe", get(describe_job))
+        .route("/jobs/{job_id}/running_datums", get(job_running_datums))
+        .route("/jobs/{job_id}/error_datums", get(job_error_datums))
+        .route("/jobs/{job_id}/stats", get(job_stats))
+        .route(
+            "/jobs/{job_id}/recommended_resources",
+            get(recommended_resources),
+        )
+        .route("/jobs/{job_id}/audit", get(job_audit))
+        .route("/jobs/{job_id}/lineage", get(job_lineage))
+        .route("/jobs/{job_id}/events", get(job_events))
+        .route("/jobs/{job_id}/output_files", get(job_output_files))
+        .route("/jobs/{job_id}/spec", get(job_spec))
+        .route("/jobs/{job_id}/retry", post(job_retry))
+        .route("/jobs/{job_id}/rerun", post(job_rerun))
+        .route("/jobs/{job_id}/stop_streaming", post(job_stop_streaming))
+        .route("/admin/reservations", put(put_reservations))
+        .route("/pipelines", post(post_pipeline).get(list_pipelines))
+        .route("/pipelines/{name}", delete(delete_pipeline))
+        .route("/pipelines/{name}/trigger", post(trigger_pipeline))
+        .route("/quotas", get(list_quotas))
+        .route("/quotas/{team}", put(put_quota).delete(delete_quota))
+        .route("/api_tokens", get(list_api_tokens).post(create_api_token))
+        .route("/api_tokens/{id}", delete(revoke_api_token))
+        .route("/datums/{datum_id}/describe", get(describe_datum))
+        .route("/datums/{datum_id}/release", post(release_datum))
+        .merge(worker_routes)
+        // OpenAPI JSON endpoint for CLI-facing API documentation.
+        .route("/api-docs/openapi.json", get(openapi_json))
+        // HTTP request/response tracing for debugging.
+        .layer(TraceLayer::new_for_http())
+        // Compress responses for clients which send `Accept-Encoding`. Large
+        // job descriptions are very repetitive JSON, so this helps a lot over
+        // `kubectl proxy`.
+        .layer(CompressionLayer::new())
+        // 50 MB limit to match previous Rocket.toml configuration
+        .layer(RequestBodyLimitLayer::new(52_428_800))
+        // Give each request an ID. This goes outside our other layers, so
+        // that their logs include it.
+        .layer(middleware::from_fn(propagate_request_id))
+        .with_state(state)
+}
+
 #[tokio::main]
 #[instrument(level = "debug")]
 async fn main() -> Result<()> {
@@ -533,7 +2271,12 @@ async fn main() -> Result<()> {
         .and_then(|s| s.parse().ok())
         .unwrap_or(32);
     let pool = db::async_pool(pool_size, ConnectVia::Cluster).await?;
+
+    // Check our job limits now, instead of when somebody runs a job.
+    max_datums_per_job()?;
+    let pool_monitor = PoolMonitor::from_env()?;
     let admin_password = db::postgres_password(ConnectVia::Cluster).await?;
+    let skip_kubernetes = skip_kubernetes_from_env();
 
     // Start babysitter tokio task to monitor jobs. Give it its own pool so it
     // can't be starved by heavy API traffic - the babysitter is critical
@@ -543,44 +2286,559 @@ async fn main() -> Result<()> {
     // because a failed babysitter means we need to abort() the whole process.
     eprintln!("Starting babysitter task to monitor jobs.");
     let babysitter_pool = db::async_pool(1, ConnectVia::Cluster).await?;
-    let _babysitter_handle = start_babysitter(babysitter_pool);
+    let (_babysitter_handle, babysitter_heartbeat) =
+        start_babysitter(babysitter_pool, skip_kubernetes);
     eprintln!("Babysitter started.");
 
+    // Start our scheduler for registered pipelines. This needs a pool big
+    // enough to create datums for the jobs it starts in the background.
+    let scheduler_pool = db::async_pool(2, ConnectVia::Cluster).await?;
+    let _scheduler_handle = start_scheduler(scheduler_pool, skip_kubernetes);
+
+    // Limit how fast each worker may hit our busiest endpoints.
+    let rate_limiter = RateLimiter::new(RateLimitConfig::from_env()?);
+    let concurrency_limiter = ConcurrencyLimiter::from_env()?;
+
     let state = AppState {
         pool,
+        pool_monitor,
         admin_password,
+        babysitter_heartbeat,
+        rate_limiter,
+        concurrency_limiter,
+        skip_kubernetes,
     };
 
-    // Build our router.
-    let app = Router::new()
-        .route("/version", get(version))
-        .route("/jobs", post(post_job).get(get_job_by_name))
-        .route("/jobs/list", get(list_jobs))
-        .route("/jobs/{job_id}", get(get_job))
-        .route("/jobs/{job_id}/describe", get(describe_job))
-        .route("/jobs/{job_id}/retry", post(job_retry))
-        .route(
-            "/jobs/{job_id}/reserve_next_datum",
-            post(job_reserve_next_datum),
-        )
-        .route("/datums/{datum_id}", patch(patch_datum))
-        .route("/datums/{datum_id}/describe", get(describe_datum))
-        .route(
-            "/datums/{datum_id}/output_files",
-            post(create_output_files).patch(patch_output_files),
-        )
-        // OpenAPI JSON endpoint for CLI-facing API documentation.
-        .route("/api-docs/openapi.json", get(openapi_json))
-        // HTTP request/response tracing for debugging.
-        .layer(TraceLayer::new_for_http())
-        // 50 MB limit to match previous Rocket.toml configuration
-        .layer(RequestBodyLimitLayer::new(52_428_800))
-        .with_state(state);
+    let app = router(state);
 
     // Start the server.
     eprintln!("Will listen on 0.0.0.0:8089.");
     let listener = tokio::net::TcpListener::bind("0.0.0.0:8089").await?;
-    axum::serve(listener, app).await?;
+    // We need to know client addresses for rate limiting.
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
 
     Ok(())
 }
+
+#[test]
+fn openapi_schema_refs_resolve() {
+    use falconeri_common::serde_json::{self, Value};
+
+    fn collect_refs(value: &Value, refs: &mut Vec<String>) {
+        match value {
+            Value::Object(map) => {
+                if let Some(Value::String(r)) = map.get("$ref") {
+                    refs.push(r.clone());
+                }
+                map.values().for_each(|v| collect_refs(v, refs));
+            }
+            Value::Array(items) => items.iter().for_each(|v| collect_refs(v, refs)),
+            _ => {}
+        }
+    }
+
+    let doc = serde_json::to_value(ApiDoc::openapi()).unwrap();
+    let mut refs = vec![];
+    collect_refs(&doc, &mut refs);
+    for r in refs {
+        let name = r
+            .strip_prefix("#/components/schemas/")
+            .unwrap_or_else(|| panic!("unexpected $ref {}", r));
+        assert!(
+            doc["components"]["schemas"].get(name).is_some(),
+            "missing schema for {}",
+            name
+        );
+    }
+}
+
+#[test]
+fn openapi_matches_worker_client_types() {
+    use falconeri_common::serde_json::{self, Value};
+
+    let doc = serde_json::to_value(ApiDoc::openapi()).unwrap();
+
+    // Look up a named schema, following `$ref`s.
+    fn resolve<'a>(doc: &'a Value, schema: &'a Value) -> &'a Value {
+        match schema.get("$ref").and_then(|r| r.as_str()) {
+            Some(r) => {
+                let name = r.trim_start_matches("#/components/schemas/");
+                resolve(doc, &doc["components"]["schemas"][name])
+            }
+            None => schema,
+        }
+    }
+
+    // Check that `value` has all the properties that `schema` requires, and
+    // no properties that it doesn't know about.
+    fn check(doc: &Value, schema: &Value, value: &Value, path: &str) -> Result<()> {
+        let schema = resolve(doc, schema);
+        for key in ["oneOf", "anyOf"] {
+            if let Some(alternatives) = schema.get(key).and_then(|a| a.as_array()) {
+                if alternatives
+                    .iter()
+                    .any(|alt| check(doc, alt, value, path).is_ok())
+                {
+                    return Ok(());
+                }
+                return Err(format_err!("{}: no {} alternative matched", path, key));
+            }
+        }
+        if let Some(all) = schema.get("allOf").and_then(|a| a.as_array()) {
+            for alt in all {
+                check(doc, alt, value, path)?;
+            }
+        }
+        match value {
+            Value::Object(map) => {
+                if let Some(properties) =
+                    schema.get("properties").and_then(|p| p.as_object())
+                {
+                    for (key, v) in map {
+                        let property = properties.get(key).ok_or_else(|| {
+                            format_err!("{}.{} is not in the schema", path, key)
+                        })?;
+                        check(doc, property, v, &format!("{}.{}", path, key))?;
+                    }
+                }
+                if let Some(required) =
+                    schema.get("required").and_then(|r| r.as_array())
+                {
+                    for key in required.iter().filter_map(|k| k.as_str()) {
+                        if !map.contains_key(key) {
+                            return Err(format_err!("{}.{} is missing", path, key));
+                        }
+                    }
+                }
+            }
+            Value::Array(items) => {
+                if let Some(item_schema) = schema.get("items") {
+                    for (idx, item) in items.iter().enumerate() {
+                        check(doc, item_schema, item, &format!("{}[{}]", path, idx))?;
+                    }
+                }
+            }
+            Value::Null => {
+                let nullable = schema.get("type").is_some_and(|t| {
+                    t == "null"
+                        || t.as_array().is_some_and(|ts| ts.contains(&"null".into()))
+                });
+                if !nullable && schema.get("properties").is_some() {
+                    return Err(format_err!("{} should not be null", path));
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // Check a request or response body for `method` and `path` against what
+    // our client sends or expects.
+    fn check_body<T: Serialize>(
+        doc: &Value,
+        operation: &Value,
+        body: &str,
+        value: &T,
+    ) -> Result<()> {
+        let schema = match body {
+            "request" => {
+                &operation["requestBody"]["content"]["application/json"]["schema"]
+            }
+            _ => {
+                &operation["responses"]["200"]["content"]["application/json"]["schema"]
+            }
+        };
+        if schema.is_null() {
+            return Err(format_err!("no {} schema", body));
+        }
+        check(doc, schema, &serde_json::to_value(value)?, body)
+    }
+
+    let job = Job::factory();
+    let datum = Datum::factory(&job);
+    let input_file = InputFile::factory(&datum);
+    let pod_name = "worker-abc12".to_owned();
+
+    let operation = |method: &str, path: &str| {
+        let operation = &doc["paths"][path][method];
+        assert!(!operation.is_null(), "missing {} {}", method, path);
+        operation.clone()
+    };
+
+    let reserve = operation("post", "/jobs/{job_id}/reserve_next_datum");
+    check_body(
+        &doc,
+        &reserve,
+        "request",
+        &DatumReservationRequest {
+            node_name: "node-1".to_owned(),
+            pod_name: pod_name.clone(),
+            prefetch: true,
+        },
+    )
+    .unwrap();
+    check_body(
+        &doc,
+        &reserve,
+        "response",
+        &Some(DatumReservationResponse {
+            datum: Datum::factory(&job),
+            input_files: vec![InputFile::factory(&datum)],
+        }),
+    )
+    .unwrap();
+    check_body(
+        &doc,
+        &reserve,
+        "response",
+        &None::<DatumReservationResponse>,
+    )
+    .unwrap();
+
+    let patch = operation("patch", "/datums/{datum_id}");
+    check_body(
+        &doc,
+        &patch,
+        "request",
+        &UpdateDatumRequest {
+            pod_name: pod_name.clone(),
+            datum: DatumPatch {
+                status: Status::Error,
+                output: "output".to_owned(),
+                output_uri: Some("gs://bucket/logs/datum.log".to_owned()),
+                streams: DatumStreams {
+                    stdout: Some("stdout".to_owned()),
+                    stderr: Some("stderr".to_owned()),
+                },
+                error_message: Some("failed".to_owned()),
+                backtrace: Some("backtrace".to_owned()),
+                failure_class: Some(FailureClass::Permanent),
+                timings: DatumTimings::default(),
+                byte_counts: DatumByteCounts::default(),
+                resource_usage: DatumResourceUsage {
+                    peak_memory_bytes: Some(512 * 1024 * 1024),
+                    cpu_seconds: Some(12.5),
+                },
+            },
+        },
+    )
+    .unwrap();
+    check_body(
+        &doc,
+        &patch,
+        "response",
+        &DatumResponse {
+            datum: Datum::factory(&job),
+        },
+    )
+    .unwrap();
+
+    let release = operation("post", "/datums/{datum_id}/release");
+    check_body(
+        &doc,
+        &release,
+        "request",
+        &ReleaseDatumRequest {
+            pod_name: pod_name.clone(),
+        },
+    )
+    .unwrap();
+
+    let append = operation("patch", "/datums/{datum_id}/append_output");
+    check_body(
+        &doc,
+        &append,
+        "request",
+        &AppendDatumOutputRequest {
+            pod_name: pod_name.clone(),
+            offset: 0,
+            output: "output".to_owned(),
+        },
+    )
+    .unwrap();
+
+    let create = operation("post", "/datums/{datum_id}/output_files");
+    check_body(
+        &doc,
+        &create,
+        "request",
+        &CreateOutputFilesRequest {
+            pod_name: pod_name.clone(),
+            output_files: vec![OutputFilePost {
+                uri: input_file.uri.clone(),
+            }],
+        },
+    )
+    .unwrap();
+    check_body(
+        &doc,
+        &create,
+        "response",
+        &OutputFilesResponse {
+            output_files: vec![],
+        },
+    )
+    .unwrap();
+
+    let update = operation("patch", "/datums/{datum_id}/output_files");
+    check_body(
+        &doc,
+        &update,
+        "request",
+        &UpdateOutputFilesRequest {
+            pod_name,
+            output_files: vec![OutputFilePatch {
+                id: Uuid::new_v4(),
+                status: Status::Done,
+                size: Some(5),
+                sha256: None,
+            }],
+        },
+    )
+    .unwrap();
+
+    // Make sure our checker actually catches mistakes.
+    let mut bad = serde_json::to_value(ReleaseDatumRequest {
+        pod_name: "worker".to_owned(),
+    })
+    .unwrap();
+    bad["unexpected"] = Value::Bool(true);
+    assert!(check_body(&doc, &release, "request", &bad).is_err());
+    assert!(check_body(&doc, &release, "request", &serde_json::json!({})).is_err());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs Docker; run using `just test-e2e`"]
+async fn jobs_run_end_to_end_without_kubernetes() {
+    use std::fs;
+
+    use falconeri_common::{
+        rest_api::Client,
+        serde_json::{self, json},
+        testing::{FakeWorker, TestDatabase, TestStorage},
+    };
+
+    use crate::babysitter::BabysitterHeartbeat;
+
+    let database = TestDatabase::start().await.unwrap();
+    let storage = TestStorage::new().unwrap();
+    storage.write("books/a.txt", "alpha").unwrap();
+    storage.write("books/b.txt", "beta").unwrap();
+
+    // Serve our API on a random local port.
+    let password = "test-password";
+    let state = AppState {
+        pool: database.pool(8).unwrap(),
+        pool_monitor: PoolMonitor::new(Duration::from_secs(5)),
+        admin_password: password.to_owned(),
+        babysitter_heartbeat: BabysitterHeartbeat::new(),
+        rate_limiter: RateLimiter::new(None),
+        concurrency_limiter: ConcurrencyLimiter::new(0),
+        skip_kubernetes: true,
+    };
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        axum::serve(
+            listener,
+            router(state).into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await
+    });
+    let client = Client::new_with_url(&url, password).unwrap();
+    client.check_server_version().await.unwrap();
+
+    // Start a job reading from our fake bucket.
+    let mut spec: serde_json::Value = serde_json::from_str(include_str!(
+        "../../falconeri_common/src/example_pipeline_spec.json"
+    ))
+    .unwrap();
+    spec["transform"]["secrets"] = json!([]);
+    spec["input"]["atom"]["URI"] = json!(storage.uri("books/"));
+    spec["egress"]["URI"] = json!(storage.uri("words/"));
+    let request: CreateJobRequest =
+        serde_json::from_value(json!({ "job": spec })).unwrap();
+    let job = client.new_job(&request).await.unwrap();
+
+    // Run the job using a worker which shouts everything.
+    let worker = FakeWorker::new(client).unwrap();
+    let job = worker
+        .run(&job, |input_dir, output_dir| {
+            for entry in fs::read_dir(input_dir.join("books"))? {
+                let path = entry?.path();
+                let text = fs::read_to_string(&path)?;
+                let name = path.file_name().expect("input should have a name");
+                fs::write(output_dir.join(name), text.to_uppercase())?;
+            }
+            Ok(())
+        })
+        .await
+        .unwrap();
+    assert_eq!(job.status, Status::Done);
+    assert_eq!(storage.read_to_string("words/a.txt").unwrap(), "ALPHA");
+    assert_eq!(storage.read_to_string("words/b.txt").unwrap(), "BETA");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs Docker; run using `just test-e2e`"]
+async fn reservation_queries_use_indexes() {
+    use falconeri_common::testing::TestDatabase;
+
+    let database = TestDatabase::start().await.unwrap();
+    let mut conn = db::async_connect_to_url(database.url()).await.unwrap();
+
+    // Build a job which looks like a big job near the end of its run, plus a
+    // second job, so that the planner has to pick datums by job and status.
+    let job_ids = [Uuid::new_v4(), Uuid::new_v4()];
+    for (job_id, job_name) in job_ids.iter().zip(["explain-big", "explain-other"]) {
+        diesel::sql_query(format!(
+            "INSERT INTO jobs (id, pipeline_spec, job_name, command, egress_uri) \
+             VALUES ('{}', '{{}}', '{}', '{{true}}', 'gs://bucket/')",
+            job_id, job_name,
+        ))
+        .execute(&mut conn)
+        .await
+        .unwrap();
+    }
+    for (job_id, status, count) in [
+        (job_ids[0], "done", 50_000),
+        (job_ids[0], "error", 100),
+        (job_ids[0], "running", 10),
+        (job_ids[0], "ready", 10),
+        (job_ids[1], "done", 50_000),
+    ] {
+        diesel::sql_query(format!(
+            "INSERT INTO datums (job_id, status, pod_name) \
+             SELECT '{}', '{}', 'pod-' || (i % 10) FROM generate_series(1, {}) i",
+            job_id, status, count,
+        ))
+        .execute(&mut conn)
+        .await
+        .unwrap();
+    }
+    diesel::sql_query("ANALYZE datums")
+        .execute(&mut conn)
+        .await
+        .unwrap();
+
+    // Explain the same diesel queries which `reserve_next_datum` runs.
+    let job = Job::find(job_ids[0], &mut conn).await.unwrap();
+    let explained = job
+        .explain_reservation_queries("node", "pod-0", &mut conn)
+        .await
+        .unwrap();
+    assert_eq!(explained.len(), 2);
+    for (query, plan) in explained {
+        assert!(
+            plan.contains("datums_active_job_id_status"),
+            "expected {} to use datums_active_job_id_status:\n{}",
+            query,
+            plan,
+        );
+        assert!(!plan.contains("Seq Scan"), "unexpected scan:\n{}", plan);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs Docker; run using `just test-e2e`"]
+async fn concurrent_pipeline_registration_conflicts() {
+    use falconeri_common::{pipeline::PipelineSpec, testing::TestDatabase};
+
+    let database = TestDatabase::start().await.unwrap();
+    let pool = database.pool(2).unwrap();
+    let pipeline_spec: PipelineSpec = serde_json::from_str(include_str!(
+        "../../falconeri_common/src/example_pipeline_spec.json"
+    ))
+    .unwrap();
+    let new_pipeline = NewRegisteredPipeline::new(
+        "racy",
+        &pipeline_spec,
+        None,
+        Utc::now().naive_utc(),
+    )
+    .unwrap();
+
+    // Register the same pipeline from two connections at once. Exactly one
+    // insert should win, and the other should see the conflict.
+    let insert = || async {
+        let mut conn = pool.get().await.unwrap();
+        new_pipeline.insert(&mut conn).await.unwrap()
+    };
+    let (first, second) = tokio::join!(insert(), insert());
+    assert_eq!(
+        [first.is_some(), second.is_some()]
+            .iter()
+            .filter(|&&inserted| inserted)
+            .count(),
+        1
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs Docker; run using `just test-e2e`"]
+async fn lineage_follows_retries_in_both_directions() {
+    use falconeri_common::testing::TestDatabase;
+
+    let database = TestDatabase::start().await.unwrap();
+    let mut conn = db::async_connect_to_url(database.url()).await.unwrap();
+
+    // Build a chain of retries, `a` -> `b` -> `c`, where `c` was retried
+    // twice, as `d1` and `d2`.
+    let names = ["a", "b", "c", "d1", "d2"];
+    let ids = names.map(|_| Uuid::new_v4());
+    let parents = [None, Some(ids[0]), Some(ids[1]), Some(ids[2]), Some(ids[2])];
+    for (i, name) in names.iter().enumerate() {
+        NewJob {
+            id: ids[i],
+            status: Status::Done,
+            pipeline_spec: falconeri_common::serde_json::json!({}),
+            job_name: format!("lineage-{}", name),
+            command: vec!["true".to_owned()],
+            egress_uri: "gs://bucket/".to_owned(),
+            stop_on_first_error: false,
+            spec_hash: None,
+            max_inline_output_bytes: DEFAULT_MAX_INLINE_OUTPUT_BYTES,
+            output_log_uri: None,
+            autoscale_parallelism: false,
+            target_parallelism: None,
+            idempotency_key: None,
+            deferred_start: None,
+            parent_job_id: parents[i],
+            team: None,
+            image_digest: None,
+            submitted_pipeline_spec: None,
+            retry_backoff_seconds: None,
+            max_failed_datums: None,
+            max_failed_datum_percent: None,
+            output_manifest_uri: None,
+        }
+        .insert(&mut conn)
+        .await
+        .unwrap();
+    }
+
+    let job_names = |jobs: &[Job]| {
+        jobs.iter()
+            .map(|job| job.job_name.clone())
+            .collect::<Vec<_>>()
+    };
+    let b = Job::find(ids[1], &mut conn).await.unwrap();
+    let lineage = b.lineage(&mut conn).await.unwrap();
+    assert_eq!(job_names(&lineage.ancestors), ["lineage-a"]);
+    assert_eq!(
+        job_names(&lineage.descendants),
+        ["lineage-c", "lineage-d1", "lineage-d2"],
+    );
+
+    let d2 = Job::find(ids[4], &mut conn).await.unwrap();
+    let lineage = d2.lineage(&mut conn).await.unwrap();
+    assert_eq!(
+        job_names(&lineage.ancestors),
+        ["lineage-a", "lineage-b", "lineage-c"],
+    );
+    assert!(lineage.descendants.is_empty());
+}
@@ -0,0 +1,162 @@
+//! Manifests of the files which a job uploaded.
+//!
+//! Downstream systems often want a single list of everything a job produced,
+//! instead of listing the egress bucket themselves. If a pipeline sets
+//! `egress.manifest_uri`, the babysitter writes a manifest there once the job
+//! has finished successfully, with one JSON object per line.
+
+use std::env;
+
+use falconeri_common::{cast, prelude::*, serde_json, storage::CloudStorage};
+use tokio::{
+    fs as async_fs,
+    io::{AsyncWriteExt, BufWriter},
+};
+
+use crate::start_job::job_pipeline_spec;
+
+/// How many output files to load from the database at once.
+const PAGE_SIZE: i64 = 10_000;
+
+/// One line of an output manifest.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct OutputManifestEntry {
+    /// The URI of the output file.
+    pub uri: String,
+    /// The size of the file in bytes, if the worker reported it.
+    pub size: Option<i64>,
+    /// The SHA-256 hash of the file, if the worker reported it.
+    pub sha256: Option<String>,
+    /// The datum which created this file.
+    pub datum_id: Uuid,
+}
+
+impl From<&OutputFile> for OutputManifestEntry {
+    fn from(output_file: &OutputFile) -> Self {
+        OutputManifestEntry {
+            uri: output_file.uri.clone(),
+            size: output_file.size,
+            sha256: output_file.sha256.clone(),
+            datum_id: output_file.datum_id,
+        }
+    }
+}
+
+/// Write the output manifest for `job`, if it wants one, and record that
+/// we've done so.
+#[instrument(skip_all, fields(job = %job.id), level = "debug")]
+pub async fn write_output_manifest(
+    job: &mut Job,
+    conn: &mut AsyncPgConnection,
+) -> Result<()> {
+    let Some(manifest_uri) = job.output_manifest_uri.clone() else {
+        return Ok(());
+    };
+    let job_ids = manifest_job_ids(job, conn).await?;
+
+    // Build our manifest in a local file, because a job may have millions of
+    // output files.
+    let local_path =
+        env::temp_dir().join(format!("falconeri-manifest-{}.jsonl", job.id));
+    let result = async {
+        let file = async_fs::File::create(&local_path)
+            .await
+            .with_context(|| format!("cannot create {}", local_path.display()))?;
+        let mut writer = BufWriter::new(file);
+        let mut count = 0;
+        let mut after = None;
+        loop {
+            let page = OutputFile::done_page_for_jobs(
+                &job_ids,
+                after.as_deref(),
+                PAGE_SIZE,
+                conn,
+            )
+            .await?;
+            for output_file in &page {
+                let mut line =
+                    serde_json::to_vec(&OutputManifestEntry::from(output_file))?;
+                line.push(b'\n');
+                writer.write_all(&line).await?;
+            }
+            count += page.len();
+            if cast::i64(page.len())? < PAGE_SIZE {
+                break;
+            }
+            after = page.last().map(|f| f.uri.clone());
+        }
+        writer.flush().await?;
+
+        // Upload it using the same secrets as the job itself.
+        let secrets = job_pipeline_spec(job)?.transform.secrets;
+        let storage = <dyn CloudStorage>::for_uri(&manifest_uri, &secrets).await?;
+        let mut file = async_fs::File::open(&local_path)
+            .await
+            .with_context(|| format!("cannot open {}", local_path.display()))?;
+        storage.upload_from_reader(&mut file, &manifest_uri).await?;
+        Ok::<_, Error>(count)
+    }
+    .await;
+    if let Err(err) = async_fs::remove_file(&local_path).await {
+        warn!("could not remove {}: {}", local_path.display(), err);
+    }
+    let count = result
+        .with_context(|| format!("could not write manifest to {}", manifest_uri))?;
+
+    info!(
+        "wrote manifest of {} output files for job {} to {}",
+        count, job.job_name, manifest_uri
+    );
+    job.mark_output_manifest_written(conn).await
+}
+
+/// The jobs whose output belongs in `job`'s manifest. This is `job` itself,
+/// plus any jobs it was retried from which uploaded to the same egress URI,
+/// so that the manifest of a retry also lists the files uploaded before the
+/// retry.
+async fn manifest_job_ids(
+    job: &Job,
+    conn: &mut AsyncPgConnection,
+) -> Result<Vec<Uuid>> {
+    let mut job_ids = vec![job.id];
+    let mut parent_job_id = job.parent_job_id;
+    while let Some(id) = parent_job_id {
+        let parent = Job::find(id, conn).await?;
+        if parent.egress_uri != job.egress_uri || job_ids.contains(&parent.id) {
+            break;
+        }
+        job_ids.push(parent.id);
+        parent_job_id = parent.parent_job_id;
+    }
+    Ok(job_ids)
+}
+
+#[test]
+fn manifest_entries_describe_output_files() {
+    let job = Job::factory();
+    let datum = Datum::factory(&job);
+    let now = Utc::now().naive_utc();
+    let output_file = OutputFile {
+        id: Uuid::new_v4(),
+        created_at: now,
+        updated_at: now,
+        status: Status::Done,
+        job_id: job.id,
+        datum_id: datum.id,
+        uri: "gs://bucket/out/a.csv".to_owned(),
+        size: Some(5),
+        sha256: Some(
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+                .to_owned(),
+        ),
+    };
+    assert_eq!(
+        serde_json::to_value(OutputManifestEntry::from(&output_file)).unwrap(),
+        serde_json::json!({
+            "uri": "gs://bucket/out/a.csv",
+            "size": 5,
+            "sha256": "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824",
+            "datum_id": datum.id,
+        })
+    );
+}
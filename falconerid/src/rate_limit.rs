@@ -0,0 +1,297 @@
+//! Per-client rate limiting for worker-facing endpoints.
+//!
+//! A worker stuck in a tight retry loop can keep `falconerid` and PostgreSQL
+//! too busy to serve anybody else. We give each client (identified by its pod
+//! name, or failing that its IP address) a token bucket, and reject requests
+//! with `429 Too Many Requests` once the bucket is empty. Workers already back
+//! off and retry when requests fail, so this just slows them down.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    env,
+    fmt::Write as _,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use axum::{
+    extract::{ConnectInfo, MatchedPath, Request, State},
+    http::{header::RETRY_AFTER, HeaderValue, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use falconeri_common::{
+    prelude::*,
+    rest_api::{FalconeriApiError, POD_NAME_HEADER},
+};
+
+use crate::util::error_response;
+
+/// Environment variable specifying how many requests per second each client
+/// may make to worker-facing endpoints. Set to 0 to disable rate limiting.
+const RATE_VAR: &str = "FALCONERID_WORKER_RATE_LIMIT";
+
+/// Environment variable specifying how many requests each client may make in
+/// a burst, before being limited to `FALCONERID_WORKER_RATE_LIMIT`.
+const BURST_VAR: &str = "FALCONERID_WORKER_RATE_LIMIT_BURST";
+
+/// Default requests per second per client. A healthy worker makes a handful
+/// of requests per datum, so this is very generous.
+const DEFAULT_RATE: f64 = 20.0;
+
+/// Default burst size per client.
+const DEFAULT_BURST: f64 = 100.0;
+
+/// Once we're tracking this many clients, forget about any whose buckets have
+/// completely refilled.
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+/// Rate limits for each client.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimitConfig {
+    /// Tokens added to each bucket per second.
+    pub rate: f64,
+    /// The maximum number of tokens in each bucket.
+    pub burst: f64,
+}
+
+impl RateLimitConfig {
+    /// Load our configuration from the environment, falling back to the
+    /// defaults. Returns `None` if rate limiting is disabled.
+    pub fn from_env() -> Result<Option<Self>> {
+        let rate = env_var_or(RATE_VAR, DEFAULT_RATE)?;
+        let burst = env_var_or(BURST_VAR, DEFAULT_BURST)?;
+        Self::new(rate, burst)
+    }
+
+    /// Validate a configuration. Returns `None` if `rate` is 0.
+    fn new(rate: f64, burst: f64) -> Result<Option<Self>> {
+        if !rate.is_finite() || rate < 0.0 {
+            return Err(format_err!("{} must be a non-negative number", RATE_VAR));
+        }
+        if !burst.is_finite() || burst < 1.0 {
+            return Err(format_err!("{} must be at least 1", BURST_VAR));
+        }
+        if rate == 0.0 {
+            Ok(None)
+        } else {
+            Ok(Some(RateLimitConfig { rate, burst }))
+        }
+    }
+}
+
+/// Parse `name` as a number, or use `default` if it isn't set.
+fn env_var_or(name: &str, default: f64) -> Result<f64> {
+    match env::var(name) {
+        Ok(value) => value
+            .trim()
+            .parse::<f64>()
+            .with_context(|| format!("could not parse {}={:?}", name, value)),
+        Err(_) => Ok(default),
+    }
+}
+
+/// A token bucket for a single client.
+#[derive(Clone, Copy, Debug)]
+struct TokenBucket {
+    /// How many requests the client may make right now.
+    tokens: f64,
+    /// When we last added tokens.
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    /// Create a full bucket.
+    fn new(config: &RateLimitConfig, now: Instant) -> Self {
+        TokenBucket {
+            tokens: config.burst,
+            refilled_at: now,
+        }
+    }
+
+    /// Add any tokens earned since we were last refilled.
+    fn refill(&mut self, config: &RateLimitConfig, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled_at);
+        self.tokens =
+            (self.tokens + elapsed.as_secs_f64() * config.rate).min(config.burst);
+        self.refilled_at = now;
+    }
+
+    /// Try to take a token for a request, returning true if we succeeded.
+    fn try_take(&mut self, config: &RateLimitConfig, now: Instant) -> bool {
+        self.refill(config, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Is this bucket full, so that forgetting it would change nothing?
+    fn is_full(&self, config: &RateLimitConfig, now: Instant) -> bool {
+        let mut bucket = *self;
+        bucket.refill(config, now);
+        bucket.tokens >= config.burst
+    }
+}
+
+/// Mutable state shared between requests.
+#[derive(Debug, Default)]
+struct RateLimiterState {
+    /// Token buckets, by client.
+    buckets: HashMap<String, TokenBucket>,
+    /// Rejected requests, by route.
+    rejections: BTreeMap<String, u64>,
+}
+
+/// Rate limits requests from each client.
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    /// Our limits, or `None` if rate limiting is disabled.
+    config: Option<RateLimitConfig>,
+    /// Our buckets and metrics.
+    state: Arc<Mutex<RateLimiterState>>,
+}
+
+impl RateLimiter {
+    /// Create a new rate limiter. If `config` is `None`, all requests are
+    /// allowed.
+    pub fn new(config: Option<RateLimitConfig>) -> Self {
+        RateLimiter {
+            config,
+            state: Arc::default(),
+        }
+    }
+
+    /// Should we allow a request from `client` to `route` at `now`?
+    fn check(&self, client: &str, route: &str, now: Instant) -> bool {
+        let Some(config) = &self.config else {
+            return true;
+        };
+        let mut state = self.state.lock().expect("rate limiter lock poisoned");
+        if state.buckets.len() >= MAX_TRACKED_CLIENTS
+            && !state.buckets.contains_key(client)
+        {
+            state
+                .buckets
+                .retain(|_, bucket| !bucket.is_full(config, now));
+        }
+        let allowed = state
+            .buckets
+            .entry(client.to_owned())
+            .or_insert_with(|| TokenBucket::new(config, now))
+            .try_take(config, now);
+        if !allowed {
+            *state.rejections.entry(route.to_owned()).or_default() += 1;
+        }
+        allowed
+    }
+
+    /// Write our metrics in Prometheus text format.
+    pub fn render_metrics(&self, out: &mut String) {
+        let state = self.state.lock().expect("rate limiter lock poisoned");
+        let name = "falconerid_rate_limited_requests_total";
+        writeln!(
+            out,
+            "# HELP {} Worker requests rejected by rate limiting.",
+            name
+        )
+        .unwrap();
+        writeln!(out, "# TYPE {} counter", name).unwrap();
+        for (route, count) in &state.rejections {
+            writeln!(out, "{}{{route=\"{}\"}} {}", name, route, count).unwrap();
+        }
+        let name = "falconerid_rate_limited_clients";
+        writeln!(
+            out,
+            "# HELP {} Clients being tracked for rate limiting.",
+            name
+        )
+        .unwrap();
+        writeln!(out, "# TYPE {} gauge", name).unwrap();
+        writeln!(out, "{} {}", name, state.buckets.len()).unwrap();
+    }
+}
+
+/// Axum middleware which applies our rate limits. Install this using
+/// `axum::middleware::from_fn_with_state`.
+pub async fn rate_limit(
+    State(limiter): State<RateLimiter>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let client = client_key(&request);
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or_else(|| request.uri().path().to_owned());
+    if limiter.check(&client, &route, Instant::now()) {
+        next.run(request).await
+    } else {
+        debug!(client = %client, route = %route, "rate limited request");
+        let mut response = error_response(
+            StatusCode::TOO_MANY_REQUESTS,
+            FalconeriApiError::RateLimited,
+            format!("too many requests from {}", client),
+        );
+        response
+            .headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from_static("1"));
+        response
+    }
+}
+
+/// Identify the client making `request`. Workers send their pod name, but we
+/// fall back to the client's IP address.
+fn client_key(request: &Request) -> String {
+    if let Some(pod_name) = request
+        .headers()
+        .get(POD_NAME_HEADER)
+        .and_then(|v| v.to_str().ok())
+    {
+        format!("pod:{}", pod_name)
+    } else if let Some(ConnectInfo(addr)) =
+        request.extensions().get::<ConnectInfo<SocketAddr>>()
+    {
+        format!("ip:{}", addr.ip())
+    } else {
+        "unknown".to_owned()
+    }
+}
+
+#[test]
+fn token_buckets_limit_each_client() {
+    use std::time::Duration;
+
+    let config = RateLimitConfig::new(2.0, 3.0).unwrap();
+    assert_eq!(RateLimitConfig::new(0.0, 3.0).unwrap(), None);
+    assert!(RateLimitConfig::new(-1.0, 3.0).is_err());
+    assert!(RateLimitConfig::new(1.0, 0.0).is_err());
+
+    let limiter = RateLimiter::new(config);
+    let start = Instant::now();
+    for _ in 0..3 {
+        assert!(limiter.check("pod:a", "/datums/{datum_id}", start));
+    }
+    assert!(!limiter.check("pod:a", "/datums/{datum_id}", start));
+    // Other clients have their own buckets.
+    assert!(limiter.check("pod:b", "/datums/{datum_id}", start));
+    // We earn 2 tokens per second.
+    let later = start + Duration::from_millis(500);
+    assert!(limiter.check("pod:a", "/datums/{datum_id}", later));
+    assert!(!limiter.check("pod:a", "/datums/{datum_id}", later));
+
+    let mut metrics = String::new();
+    limiter.render_metrics(&mut metrics);
+    assert!(metrics.contains(
+        "falconerid_rate_limited_requests_total{route=\"/datums/{datum_id}\"} 2"
+    ));
+
+    let unlimited = RateLimiter::new(None);
+    for _ in 0..1000 {
+        assert!(unlimited.check("pod:a", "/datums/{datum_id}", start));
+    }
+}
@@ -0,0 +1,69 @@
+//! Request IDs, which let us match up client errors with our logs.
+//!
+//! We use the caller's `x-request-id` header if it sent a reasonable one, and
+//! otherwise we generate a new ID. We record the ID in a tracing span around
+//! the request, return it in our response headers, and include it in JSON
+//! error bodies.
+
+use axum::{
+    extract::Request,
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use falconeri_common::{prelude::*, rest_api::REQUEST_ID_HEADER};
+use tracing::Instrument;
+
+/// The longest request ID we'll accept from a caller.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+tokio::task_local! {
+    /// The ID of the request we're currently handling.
+    static REQUEST_ID: String;
+}
+
+/// The ID of the request we're currently handling, if any.
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+/// Axum middleware which assigns each request an ID. Install this using
+/// `axum::middleware::from_fn`, outside of any layers which log.
+pub async fn propagate_request_id(mut request: Request, next: Next) -> Response {
+    let header = HeaderName::from_static(REQUEST_ID_HEADER);
+    let request_id = request
+        .headers()
+        .get(&header)
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| is_valid_request_id(id))
+        .map(|id| id.to_owned())
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    let value =
+        HeaderValue::from_str(&request_id).expect("request ID should be valid header");
+    request.headers_mut().insert(header.clone(), value.clone());
+
+    let span = info_span!("request", request_id = %request_id);
+    let mut response = REQUEST_ID
+        .scope(request_id, next.run(request).instrument(span))
+        .await;
+    response.headers_mut().insert(header, value);
+    response
+}
+
+/// Is `id` a reasonable request ID to accept from a caller?
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b))
+}
+
+#[test]
+fn only_reasonable_request_ids_are_accepted() {
+    assert!(is_valid_request_id("0b6c8f5e-7a4f-4b1e-9b53-3c0e7c6c2a51"));
+    assert!(is_valid_request_id("lb:1234.5_6"));
+    assert!(!is_valid_request_id(""));
+    assert!(!is_valid_request_id("has spaces"));
+    assert!(!is_valid_request_id(&"x".repeat(MAX_REQUEST_ID_LEN + 1)));
+}
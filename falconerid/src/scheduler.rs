@@ -0,0 +1,181 @@
+//! A background task which starts jobs from registered pipelines with cron
+//! schedules, starts jobs which were waiting for their upstream jobs to
+//! finish, and adds datums to streaming jobs.
+//!
+//! Jobs whose team is over its quota are left waiting (or due), and we try
+//! again on our next pass.
+//!
+//! Like the babysitter, more than one copy of the scheduler will normally be
+//! running. Each scheduled run or waiting job is claimed using a conditional
+//! `UPDATE`, so only one copy will start a job for it.
+
+use std::time::Duration;
+
+use falconeri_common::{db, prelude::*};
+
+use crate::{
+    start_job::{run_registered_pipeline, start_waiting_job},
+    streaming::ingest_new_files,
+};
+
+/// How often should we check for pipelines which are due to run, for waiting
+/// jobs which can start, and for new input files for streaming jobs?
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Spawn a tokio task which runs scheduled pipelines and waiting jobs, and
+/// feeds streaming jobs. This should run indefinitely. If `skip_kubernetes` is
+/// true, the jobs we start won't create Kubernetes jobs.
+#[instrument(skip_all, level = "trace")]
+pub fn start_scheduler(
+    pool: db::AsyncPool,
+    skip_kubernetes: bool,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            // As with the babysitter, we retry all errors, so that we recover
+            // once PostgreSQL is available again.
+            if let Err(err) = run_due_pipelines(&pool, skip_kubernetes).await {
+                error!(
+                    "error running scheduled pipelines (will retry later): {:?}",
+                    err
+                );
+            }
+            if let Err(err) = start_waiting_jobs(&pool, skip_kubernetes).await {
+                error!("error starting waiting jobs (will retry later): {:?}", err);
+            }
+            if let Err(err) = ingest_streaming_jobs(&pool).await {
+                error!("error feeding streaming jobs (will retry later): {:?}", err);
+            }
+            tokio::time::sleep(CHECK_INTERVAL).await;
+        }
+    })
+}
+
+/// Start jobs for any pipelines which are due to run.
+#[instrument(skip_all, level = "debug")]
+async fn run_due_pipelines(pool: &db::AsyncPool, skip_kubernetes: bool) -> Result<()> {
+    let mut conn = pool
+        .get()
+        .await
+        .context("could not get connection from pool")?;
+    let now = Utc::now().naive_utc();
+    for mut pipeline in RegisteredPipeline::find_due(now, &mut conn).await? {
+        if let Err(err) =
+            run_due_pipeline(pool, &mut pipeline, now, skip_kubernetes, &mut conn)
+                .await
+        {
+            error!(
+                "could not run scheduled pipeline {}: {:?}",
+                pipeline.name, err
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Start any waiting jobs whose upstream jobs have all finished, and fail any
+/// whose upstream jobs have failed.
+#[instrument(skip_all, level = "debug")]
+async fn start_waiting_jobs(
+    pool: &db::AsyncPool,
+    skip_kubernetes: bool,
+) -> Result<()> {
+    let mut conn = pool
+        .get()
+        .await
+        .context("could not get connection from pool")?;
+    for mut job in Job::find_by_status(Status::Waiting, &mut conn).await? {
+        if let Err(err) =
+            start_waiting_job(pool, &mut job, skip_kubernetes, &mut conn).await
+        {
+            error!("could not start waiting job {}: {:?}", job.job_name, err);
+        }
+    }
+    Ok(())
+}
+
+/// Add datums for any new input files to our streaming jobs.
+#[instrument(skip_all, level = "debug")]
+async fn ingest_streaming_jobs(pool: &db::AsyncPool) -> Result<()> {
+    let mut conn = pool
+        .get()
+        .await
+        .context("could not get connection from pool")?;
+    for mut job in Job::find_by_status(Status::Streaming, &mut conn).await? {
+        match ingest_new_files(&mut job, &mut conn).await {
+            Ok(0) => {}
+            Ok(count) => {
+                info!("added {} datums to streaming job {}", count, job.job_name)
+            }
+            Err(err) => {
+                error!("could not feed streaming job {}: {:?}", job.job_name, err)
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Start a job for `pipeline`, which is due to run.
+#[instrument(skip_all, fields(pipeline = %pipeline.name), level = "debug")]
+async fn run_due_pipeline(
+    pool: &db::AsyncPool,
+    pipeline: &mut RegisteredPipeline,
+    now: NaiveDateTime,
+    skip_kubernetes: bool,
+    conn: &mut AsyncPgConnection,
+) -> Result<()> {
+    // If the pipeline's team is over quota, leave this run due, so that we
+    // try again later.
+    let pipeline_spec = pipeline.pipeline_spec()?;
+    if let Err(err) = Quota::enforce(
+        pipeline_spec.team.as_deref(),
+        pipeline_spec.parallelism_spec.constant,
+        conn,
+    )
+    .await
+    {
+        if let Some(exceeded) = err.downcast_ref::<QuotaExceeded>() {
+            warn!("delaying scheduled run of {}: {}", pipeline.name, exceeded);
+            return Ok(());
+        }
+        return Err(err);
+    }
+
+    // Claim this run and schedule the next one. If we missed several runs
+    // (perhaps because `falconerid` was down), we only make up one of them.
+    let next_run_at = match pipeline.cron_schedule()? {
+        Some(schedule) => schedule.next_after(now),
+        None => None,
+    };
+    if !pipeline.claim_scheduled_run(next_run_at, conn).await? {
+        debug!("another falconerid already claimed {}", pipeline.name);
+        return Ok(());
+    }
+
+    // Don't start a second copy of a job that's still running, just as we
+    // wouldn't for `job run` without `--force`.
+    let spec_hash = pipeline_spec.canonical_hash()?;
+    if let Some(existing) = Job::find_running_by_spec_hash(&spec_hash, conn).await? {
+        warn!(
+            "skipping scheduled run of {} because {} is still running",
+            pipeline.name, existing.job_name
+        );
+        return Ok(());
+    }
+
+    let job = run_registered_pipeline(
+        pool.clone(),
+        pipeline,
+        &pipeline_spec,
+        &spec_hash,
+        false,
+        skip_kubernetes,
+        conn,
+    )
+    .await?;
+    info!(
+        "started {} for scheduled pipeline {}",
+        job.job_name, pipeline.name
+    );
+    Ok(())
+}
@@ -1,28 +1,147 @@
 // ! Code for starting a job on the server.
 
-use std::cmp::min;
+use std::{cmp::min, collections::HashSet, error, result, time::Duration};
 
 use falconeri_common::{
-    cast,
+    cast, db,
     diesel_async::{scoped_futures::ScopedFutureExt, AsyncConnection},
     kubernetes,
     manifest::render_manifest,
     pipeline::*,
     prelude::*,
+    rest_api::{JobPlan, PlannedDatum, PlannedInputFile, RerunJobRequest},
     serde_json::{self, json},
+    vault::VaultSecrets,
 };
 
-use crate::inputs::input_to_datums;
+use crate::inputs::{
+    check_streaming_input, hash_directory_inputs, input_to_datums,
+    input_to_datums_with_sizes,
+};
+
+/// The environment variable which overrides [`DEFAULT_MAX_DATUMS_PER_JOB`].
+const MAX_DATUMS_PER_JOB_VAR: &str = "FALCONERID_MAX_DATUMS_PER_JOB";
+
+/// The maximum number of datums we allow in a single job, unless overridden by
+/// `FALCONERID_MAX_DATUMS_PER_JOB`.
+const DEFAULT_MAX_DATUMS_PER_JOB: u64 = 1_000_000;
+
+/// How many datums should we insert in each transaction when creating a job?
+/// We use `COPY`, so this doesn't need to fit within PostgreSQL's limit on
+/// bind parameters, but we still want to report progress regularly.
+const DATUM_INSERT_BATCH_SIZE: usize = 10_000;
+
+/// How many example datums should we include in a [`JobPlan`]?
+const PLAN_EXAMPLE_DATUM_COUNT: usize = 5;
+
+/// Environment variable which tells us not to create or delete Kubernetes
+/// jobs, because somebody else is running the workers.
+const SKIP_KUBERNETES_VAR: &str = "FALCONERID_SKIP_KUBERNETES";
+
+/// Should we leave Kubernetes alone? See [`SKIP_KUBERNETES_VAR`]. We check
+/// this once at startup, and pass it to everything which needs it.
+pub fn skip_kubernetes_from_env() -> bool {
+    std::env::var(SKIP_KUBERNETES_VAR)
+        .map(|v| v == "true" || v == "1")
+        .unwrap_or(false)
+}
+
+/// Everything we need to start a job with status `Status::Waiting` once its
+/// upstream jobs have finished. Stored in `Job::deferred_start`.
+#[derive(Deserialize, Serialize)]
+struct DeferredStart {
+    /// The original pipeline spec.
+    pipeline_spec: PipelineSpec,
+    /// Should we allow more datums than the server normally allows?
+    override_datum_cap: bool,
+}
+
+/// Make sure that the service account used by `pipeline_spec` exists.
+/// Otherwise, Kubernetes would quietly fail to create any worker pods.
+async fn check_service_account(
+    pipeline_spec: &PipelineSpec,
+    skip_kubernetes: bool,
+) -> Result<()> {
+    let Some(service_account) = &pipeline_spec.transform.service_account else {
+        return Ok(());
+    };
+    if skip_kubernetes {
+        return Ok(());
+    }
+    if !kubernetes::resource_exists(&format!("serviceaccount/{}", service_account))
+        .await?
+    {
+        return Err(format_err!(
+            "service account {:?} does not exist in falconeri's namespace",
+            service_account
+        ));
+    }
+    Ok(())
+}
 
 /// Run a new job on our cluster.
+///
+/// We list the job's inputs before creating it, so that we can refuse jobs
+/// with an implausible number of datums. But inserting the datums of a large
+/// job can take a long time, so this only creates the job record, with status
+/// `Status::Creating`. The datums are inserted by a background task, which
+/// starts the job on the cluster once it's done.
+///
+/// If any of `upstream_jobs` haven't finished yet, the job is created with
+/// status `Status::Waiting` instead, and `start_waiting_job` will start it
+/// later. We can't list its inputs until then, so it fails at that point if
+/// it has too many datums.
+///
+/// Jobs created by `rerun_job` pass the job they were cloned from as
+/// `parent_job_id`. If `skip_kubernetes` is true, we never create a
+/// Kubernetes job, and somebody else needs to run the workers.
+#[allow(clippy::too_many_arguments)]
 #[instrument(skip_all, level = "debug")]
 pub async fn run_job(
+    pool: db::AsyncPool,
     pipeline_spec: &PipelineSpec,
+    job_name: &str,
+    spec_hash: &str,
+    allow_duplicate: bool,
+    override_datum_cap: bool,
+    idempotency_key: Option<&str>,
+    upstream_jobs: &[Job],
+    parent_job_id: Option<Uuid>,
+    skip_kubernetes: bool,
     conn: &mut AsyncPgConnection,
 ) -> Result<Job> {
+    check_pod_containers(pipeline_spec)?;
+    vault_secrets(pipeline_spec)?;
+    check_service_account(pipeline_spec, skip_kubernetes).await?;
+    check_image_tag(&pipeline_spec.transform.image, reject_latest_images())?;
+    check_failure_thresholds(pipeline_spec)?;
+    check_archive_outputs(pipeline_spec)?;
+    if pipeline_spec.streaming {
+        check_streaming_input(&pipeline_spec.input)?;
+    }
+
+    // If we need to wait for upstream jobs, remember how to start this job
+    // once they're done.
+    let waiting = upstream_jobs.iter().any(|job| !job.status.has_succeeded());
+    let deferred_start = if waiting {
+        Some(serde_json::to_value(DeferredStart {
+            pipeline_spec: pipeline_spec.clone(),
+            override_datum_cap,
+        })?)
+    } else {
+        None
+    };
+
     // Build our job.
     let job_id = Uuid::new_v4();
-    let job_name = unique_kubernetes_job_name(&pipeline_spec.pipeline.name);
+
+    // List our datums now, so that we can reject a bad glob before creating
+    // anything.
+    let datums = if waiting {
+        None
+    } else {
+        Some(list_datums(pipeline_spec, job_id, override_datum_cap, conn).await?)
+    };
 
     // If nobody specified RUST_LOG, default it sensibly.
     let mut transform = pipeline_spec.transform.clone();
@@ -35,87 +154,826 @@ pub async fn run_job(
 
     let new_job = NewJob {
         id: job_id,
+        status: if waiting {
+            Status::Waiting
+        } else {
+            Status::Creating
+        },
         pipeline_spec: json!({
             "pipeline": pipeline_spec.pipeline,
             "transform": transform,
             "parallelism_spec": pipeline_spec.parallelism_spec,
             "resource_requests": pipeline_spec.resource_requests,
+            "datum_tries": pipeline_spec.datum_tries,
+            "retry_backoff_seconds": pipeline_spec.retry_backoff_seconds,
             "job_timeout": pipeline_spec.job_timeout.map(|timeout| timeout.as_secs()),
+            "expected_datum_count": pipeline_spec.expected_datum_count,
+            "stop_on_first_error": pipeline_spec.stop_on_first_error,
+            "max_failed_datums": pipeline_spec.max_failed_datums,
+            "max_failed_datum_percent": pipeline_spec.max_failed_datum_percent,
+            "streaming": pipeline_spec.streaming,
+            "skip_processed": pipeline_spec.skip_processed,
+            "team": pipeline_spec.team,
+            "max_inline_output_bytes": pipeline_spec.max_inline_output_bytes,
+            "output_log_uri": pipeline_spec.output_log_uri,
             "node_selector": pipeline_spec.node_selector,
+            "pod_template_patch": pipeline_spec.pod_template_patch,
+            "scratch_volume": pipeline_spec.scratch_volume,
+            "shared_volumes": pipeline_spec.shared_volumes,
+            "init_containers": pipeline_spec.init_containers,
+            "sidecars": pipeline_spec.sidecars,
             "input": pipeline_spec.input,
             "egress": pipeline_spec.egress,
         }),
-        job_name,
+        job_name: job_name.to_owned(),
         command: pipeline_spec.transform.cmd.clone(),
         egress_uri: pipeline_spec.egress.uri.clone(),
+        stop_on_first_error: pipeline_spec.stop_on_first_error,
+        spec_hash: Some(spec_hash.to_owned()),
+        max_inline_output_bytes: match pipeline_spec.max_inline_output_bytes {
+            Some(max) => cast::i64(max)?,
+            None => DEFAULT_MAX_INLINE_OUTPUT_BYTES,
+        },
+        output_log_uri: Some(
+            pipeline_spec
+                .output_log_uri
+                .clone()
+                .unwrap_or_else(|| default_output_log_uri(&pipeline_spec.egress.uri)),
+        ),
+        autoscale_parallelism: pipeline_spec.parallelism_spec.autoscale,
+        target_parallelism: Some(cast::i32(pipeline_spec.parallelism_spec.constant)?),
+        idempotency_key: idempotency_key.map(|key| key.to_owned()),
+        deferred_start,
+        parent_job_id,
+        team: pipeline_spec.team.clone(),
+        image_digest: pinned_image_digest(&pipeline_spec.transform.image),
+        submitted_pipeline_spec: Some(serde_json::to_value(pipeline_spec)?),
+        retry_backoff_seconds: pipeline_spec
+            .retry_backoff_seconds
+            .map(cast::i32)
+            .transpose()?,
+        max_failed_datums: pipeline_spec
+            .max_failed_datums
+            .map(cast::i32)
+            .transpose()?,
+        max_failed_datum_percent: pipeline_spec.max_failed_datum_percent,
+        output_manifest_uri: pipeline_spec.egress.manifest_uri.clone(),
     };
+    let dependencies = upstream_jobs
+        .iter()
+        .map(|upstream_job| NewJobDependency {
+            job_id,
+            upstream_job_id: upstream_job.id,
+        })
+        .collect::<Vec<_>>();
+
+    let team = pipeline_spec.team.as_deref();
+    let parallelism = pipeline_spec.parallelism_spec.constant;
+    let job = conn
+        .transaction(|conn| {
+            async move {
+                if !allow_duplicate {
+                    Job::refuse_duplicate(spec_hash, conn).await?;
+                }
+                // Make sure our team has room to run this job. Waiting jobs
+                // are checked when they start.
+                if !waiting {
+                    Quota::enforce(team, parallelism, conn).await?;
+                }
+                let job = new_job.insert(conn).await?;
+                if !dependencies.is_empty() {
+                    NewJobDependency::insert_all(&dependencies, conn).await?;
+                }
+                Ok::<_, Error>(job)
+            }
+            .scope_boxed()
+        })
+        .await?;
+    if job.status == Status::Creating {
+        spawn_datum_creation(
+            pool,
+            pipeline_spec.clone(),
+            job.clone(),
+            datums,
+            override_datum_cap,
+            skip_kubernetes,
+        );
+    }
+    Ok(job)
+}
+
+/// Check on a job with status `Status::Waiting`. If all its upstream jobs
+/// have succeeded, start creating it. If any of them failed, mark it as
+/// failed, too.
+#[instrument(skip_all, fields(job = %job.id), level = "debug")]
+pub async fn start_waiting_job(
+    pool: &db::AsyncPool,
+    job: &mut Job,
+    skip_kubernetes: bool,
+    conn: &mut AsyncPgConnection,
+) -> Result<()> {
+    let upstream_jobs = job.upstream_jobs(conn).await?;
+    if let Some(failed) = upstream_jobs.iter().find(|upstream_job| {
+        upstream_job.status.has_finished() && !upstream_job.status.has_succeeded()
+    }) {
+        let message = format!(
+            "upstream job {} finished with status {}",
+            failed.job_name, failed.status
+        );
+        return conn
+            .transaction(|conn| {
+                async move {
+                    // We may be racing another copy of the scheduler, so lock
+                    // this job and check again.
+                    job.lock_for_update(conn).await?;
+                    if job.status == Status::Waiting {
+                        warn!("job {} will not run: {}", job.job_name, message);
+                        job.mark_as_error_with_message(&message, conn).await?;
+                    }
+                    Ok::<_, Error>(())
+                }
+                .scope_boxed()
+            })
+            .await;
+    }
+    if upstream_jobs
+        .iter()
+        .any(|upstream_job| !upstream_job.status.has_succeeded())
+    {
+        return Ok(());
+    }
 
+    let deferred_start = job.deferred_start.clone().ok_or_else(|| {
+        format_err!("waiting job {} cannot be started", job.job_name)
+    })?;
+    let deferred_start: DeferredStart = serde_json::from_value(deferred_start)
+        .context("could not parse deferred start")?;
+
+    // If our team is over quota, keep waiting until it has room. We check the
+    // quota in the same transaction which starts the job, so that two jobs
+    // can't both take the team's last slot.
+    let team = deferred_start.pipeline_spec.team.as_deref();
+    let parallelism = deferred_start.pipeline_spec.parallelism_spec.constant;
+    let waiting_job = &mut *job;
+    let started = conn
+        .transaction(|conn| {
+            async move {
+                if let Err(err) = Quota::enforce(team, parallelism, conn).await {
+                    if let Some(exceeded) = err.downcast_ref::<QuotaExceeded>() {
+                        debug!(
+                            "waiting job {} cannot start yet: {}",
+                            waiting_job.job_name, exceeded
+                        );
+                        return Ok(false);
+                    }
+                    return Err(err);
+                }
+                if !waiting_job.mark_waiting_job_as_creating(conn).await? {
+                    debug!(
+                        "someone beat us to starting waiting job {}",
+                        waiting_job.job_name
+                    );
+                    return Ok(false);
+                }
+                Ok::<_, Error>(true)
+            }
+            .scope_boxed()
+        })
+        .await?;
+    if !started {
+        return Ok(());
+    }
+    info!(
+        "upstream jobs of {} have finished, starting it",
+        job.job_name
+    );
+    spawn_datum_creation(
+        pool.clone(),
+        deferred_start.pipeline_spec,
+        job.clone(),
+        None,
+        deferred_start.override_datum_cap,
+        skip_kubernetes,
+    );
+    Ok(())
+}
+
+/// Replace the URI of `pipeline_spec`'s input with the egress URI of its only
+/// upstream job, so that it processes whatever the upstream job produced.
+pub fn use_upstream_egress_as_input(
+    pipeline_spec: &mut PipelineSpec,
+    upstream_jobs: &[Job],
+) -> Result<()> {
+    let upstream_job = match upstream_jobs {
+        [upstream_job] => upstream_job,
+        _ => {
+            return Err(format_err!(
+                "input_from_upstream requires exactly one upstream job"
+            ));
+        }
+    };
+    match &mut pipeline_spec.input {
+        Input::Atom { uri, .. } => {
+            *uri = upstream_job.egress_uri.clone();
+            Ok(())
+        }
+        _ => Err(format_err!(
+            "input_from_upstream requires a pipeline with a single atom input"
+        )),
+    }
+}
+
+/// Create the datums for `job` in a background task, which starts the job on
+/// the cluster once it's done. If we've already listed the job's `datums`, we
+/// use those.
+fn spawn_datum_creation(
+    pool: db::AsyncPool,
+    pipeline_spec: PipelineSpec,
+    mut background_job: Job,
+    datums: Option<Vec<(NewDatum, Vec<NewInputFile>)>>,
+    override_datum_cap: bool,
+    skip_kubernetes: bool,
+) {
+    tokio::spawn(async move {
+        if let Err(err) = create_datums_and_start_job(
+            &pool,
+            &pipeline_spec,
+            &mut background_job,
+            datums,
+            override_datum_cap,
+            skip_kubernetes,
+        )
+        .await
+        {
+            error!(
+                "could not create job {}: {:?}",
+                background_job.job_name, err
+            );
+            if let Err(err) =
+                mark_job_creation_as_error(&pool, &mut background_job, &err).await
+            {
+                error!(
+                    "could not mark job {} as failed: {:?}",
+                    background_job.job_name, err
+                );
+            }
+        }
+    });
+}
+
+/// Start a job from a registered pipeline, naming it after the pipeline. The
+/// caller should check for identical running jobs first, if it cares.
+#[instrument(skip_all, fields(pipeline = %pipeline.name), level = "debug")]
+pub async fn run_registered_pipeline(
+    pool: db::AsyncPool,
+    pipeline: &mut RegisteredPipeline,
+    pipeline_spec: &PipelineSpec,
+    spec_hash: &str,
+    allow_duplicate: bool,
+    skip_kubernetes: bool,
+    conn: &mut AsyncPgConnection,
+) -> Result<Job> {
+    let prefix = format!("{}-", pipeline.name);
+    let job_name = choose_job_name(pipeline_spec, None, Some(&prefix))?;
+    let job = run_job(
+        pool,
+        pipeline_spec,
+        &job_name,
+        spec_hash,
+        allow_duplicate,
+        false,
+        None,
+        &[],
+        None,
+        skip_kubernetes,
+        conn,
+    )
+    .await?;
+    pipeline.record_run(&job, conn).await?;
+    Ok(job)
+}
+
+/// List the datums and input files of the job `job_id`, and make sure there's
+/// a plausible number of them.
+#[instrument(skip_all, fields(job = %job_id), level = "debug")]
+async fn list_datums(
+    pipeline_spec: &PipelineSpec,
+    job_id: Uuid,
+    override_datum_cap: bool,
+    conn: &mut AsyncPgConnection,
+) -> Result<Vec<(NewDatum, Vec<NewInputFile>)>> {
     // Calculate how many times we're allowed to retry a datum.
     let maximum_allowed_run_count = cast::i32(pipeline_spec.datum_tries.unwrap_or(1))?;
 
-    // Get our datums and input files.
-    let (new_datums, new_input_files) = input_to_datums(
+    // We need a database connection to look up the output files of any
+    // upstream jobs we read.
+    let datums = input_to_datums(
         &pipeline_spec.transform.secrets,
         job_id,
         maximum_allowed_run_count,
         &pipeline_spec.input,
+        conn,
     )
     .await?;
+    check_datum_count(
+        pipeline_spec,
+        cast::u64(datums.len())?,
+        max_datums_per_job()?,
+        override_datum_cap,
+    )?;
+    Ok(datums)
+}
 
-    // Insert everthing into the database.
-    let job = conn
-        .transaction(|conn| {
+/// Create the datums for a job with status `Status::Creating`, and start it
+/// running on the cluster. If the caller has already listed the job's
+/// `datums`, we use those.
+#[instrument(skip_all, fields(job = %job.id), level = "debug")]
+async fn create_datums_and_start_job(
+    pool: &db::AsyncPool,
+    pipeline_spec: &PipelineSpec,
+    job: &mut Job,
+    datums: Option<Vec<(NewDatum, Vec<NewInputFile>)>>,
+    override_datum_cap: bool,
+    skip_kubernetes: bool,
+) -> Result<()> {
+    let mut conn = pool
+        .get()
+        .await
+        .context("could not get connection from pool")?;
+    let datums = match datums {
+        Some(datums) => datums,
+        None => {
+            list_datums(pipeline_spec, job.id, override_datum_cap, &mut conn).await?
+        }
+    };
+
+    job.set_total_datum_count(cast::i64(datums.len())?, &mut conn)
+        .await?;
+
+    // Skip any datums that this pipeline has already processed.
+    let mut datums = datums;
+    let datum_count = datums.len();
+    let mut skipped_count = 0;
+    if pipeline_spec.skip_processed {
+        skipped_count =
+            skip_processed_datums(pipeline_spec, &mut datums, &mut conn).await?;
+        info!(
+            "skipping {} of {} datums which were already processed",
+            skipped_count, datum_count,
+        );
+    }
+
+    insert_datums_in_batches(job.id, datums, DATUM_INSERT_BATCH_SIZE, &mut conn)
+        .await?;
+
+    // Launch our batch job on the cluster. Streaming jobs stay open, and the
+    // scheduler adds datums to them as new input files appear.
+    if pipeline_spec.streaming {
+        job.mark_as_streaming(&mut conn).await?;
+    } else {
+        job.mark_as_running(&mut conn).await?;
+
+        // If we skipped everything, there's no need to start any workers.
+        if datum_count > 0 && skipped_count == datum_count {
+            job.update_status_if_done(&mut conn).await?;
+            return Ok(());
+        }
+    }
+    start_batch_job(pipeline_spec, job, skip_kubernetes, &mut conn).await?;
+    Ok(())
+}
+
+/// Insert `datums` for `job_id` in batches of `batch_size`, so that progress
+/// is visible and no single transaction gets too large.
+///
+/// If this fails, any batches we already inserted stay committed, so the
+/// caller must clean them up using [`mark_job_creation_as_error`].
+#[instrument(skip_all, fields(job = %job_id), level = "debug")]
+async fn insert_datums_in_batches(
+    job_id: Uuid,
+    datums: Vec<(NewDatum, Vec<NewInputFile>)>,
+    batch_size: usize,
+    conn: &mut AsyncPgConnection,
+) -> Result<()> {
+    let mut datums = datums.into_iter();
+    loop {
+        let (new_datums, new_input_files): (Vec<_>, Vec<_>) =
+            datums.by_ref().take(batch_size).unzip();
+        if new_datums.is_empty() {
+            break;
+        }
+        let new_input_files =
+            new_input_files.into_iter().flatten().collect::<Vec<_>>();
+        conn.transaction(|conn| {
             async move {
-                let job = new_job.insert(conn).await?;
-                NewDatum::insert_all(&new_datums, conn).await?;
-                NewInputFile::insert_all(&new_input_files, conn).await?;
-                Ok::<_, Error>(job)
+                // If the babysitter decided that we'd stalled, it will have
+                // deleted our datums, so don't add any more.
+                let job = Job::find_and_lock_for_update(job_id, conn).await?;
+                if job.status != Status::Creating {
+                    return Err(format_err!(
+                        "job {} is {}, so not adding more datums",
+                        job.job_name,
+                        job.status
+                    ));
+                }
+                NewDatum::copy_in(&new_datums, conn).await?;
+                NewInputFile::copy_in(&new_input_files, conn).await?;
+                Job::touch(job_id, conn).await?;
+                Ok::<_, Error>(())
             }
             .scope_boxed()
         })
         .await?;
+    }
+    Ok(())
+}
 
-    // Launch our batch job on the cluster.
-    start_batch_job(pipeline_spec, &job).await?;
-    Ok(job)
+/// Mark each of `datums` whose inputs were already processed successfully by
+/// a job for the same pipeline as `Status::Skipped`. Returns the number of
+/// datums skipped.
+///
+/// This also hashes the contents of any directory inputs, so that we only
+/// skip directories whose contents haven't changed.
+#[instrument(skip_all, fields(pipeline = %pipeline_spec.pipeline.name), level = "debug")]
+async fn skip_processed_datums(
+    pipeline_spec: &PipelineSpec,
+    datums: &mut [(NewDatum, Vec<NewInputFile>)],
+    conn: &mut AsyncPgConnection,
+) -> Result<usize> {
+    let pipeline_name = &pipeline_spec.pipeline.name;
+    hash_directory_inputs(
+        &pipeline_spec.transform.secrets,
+        &pipeline_spec.input,
+        datums,
+    )
+    .await?;
+    let mut skipped_count = 0;
+    for chunk in datums.chunks_mut(DATUM_INSERT_BATCH_SIZE) {
+        let hashes = chunk
+            .iter()
+            .filter_map(|(datum, _)| datum.input_hash.clone())
+            .collect::<Vec<_>>();
+        let processed =
+            Datum::processed_input_hashes(pipeline_name, &hashes, conn).await?;
+        for (datum, _) in chunk {
+            let already_processed = datum
+                .input_hash
+                .as_ref()
+                .is_some_and(|hash| processed.contains(hash));
+            if already_processed {
+                datum.status = Status::Skipped;
+                skipped_count += 1;
+            }
+        }
+    }
+    Ok(skipped_count)
+}
+
+/// List the inputs of `pipeline_spec` and divide them into datums, as if we
+/// were creating a job, but without creating anything.
+#[instrument(skip_all, fields(pipeline = %pipeline_spec.pipeline.name), level = "debug")]
+pub async fn plan_job(
+    pipeline_spec: &PipelineSpec,
+    conn: &mut AsyncPgConnection,
+) -> Result<JobPlan> {
+    let datums = input_to_datums_with_sizes(
+        &pipeline_spec.transform.secrets,
+        Uuid::nil(),
+        1,
+        &pipeline_spec.input,
+        conn,
+    )
+    .await?;
+
+    let mut plan = JobPlan::default();
+    let mut unsized_datums = Vec::with_capacity(datums.len());
+    for (datum, input_files) in datums {
+        add_datum_to_plan(&mut plan, &input_files);
+        unsized_datums.push((
+            datum,
+            input_files.into_iter().map(|(f, _)| f).collect::<Vec<_>>(),
+        ));
+    }
+    plan.datum_count_error = check_datum_count(
+        pipeline_spec,
+        plan.datum_count,
+        max_datums_per_job()?,
+        false,
+    )
+    .err()
+    .map(|err| err.to_string());
+    if pipeline_spec.skip_processed {
+        plan.skipped_datum_count = cast::u64(
+            skip_processed_datums(pipeline_spec, &mut unsized_datums, conn).await?,
+        )?;
+    }
+    Ok(plan)
+}
+
+/// Add a datum with `input_files` to `plan`.
+fn add_datum_to_plan(plan: &mut JobPlan, input_files: &[(NewInputFile, Option<u64>)]) {
+    let bytes = input_files
+        .iter()
+        .filter_map(|(_, size)| *size)
+        .sum::<u64>();
+    plan.datum_count += 1;
+    for (_, size) in input_files {
+        plan.input_file_count += 1;
+        if size.is_none() {
+            plan.unknown_size_file_count += 1;
+        }
+    }
+    plan.total_bytes += bytes;
+    plan.largest_datum_bytes = plan.largest_datum_bytes.max(bytes);
+    if plan.example_datums.len() < PLAN_EXAMPLE_DATUM_COUNT {
+        plan.example_datums.push(PlannedDatum {
+            bytes,
+            input_files: input_files
+                .iter()
+                .map(|(f, size)| PlannedInputFile {
+                    uri: f.uri.clone(),
+                    local_path: f.local_path.clone(),
+                    size: *size,
+                })
+                .collect(),
+        });
+    }
+}
+
+/// Record that we failed to create `job`, and delete any datums we already
+/// inserted.
+#[instrument(skip_all, fields(job = %job.id), level = "debug")]
+async fn mark_job_creation_as_error(
+    pool: &db::AsyncPool,
+    job: &mut Job,
+    err: &Error,
+) -> Result<()> {
+    let mut conn = pool
+        .get()
+        .await
+        .context("could not get connection from pool")?;
+    let message = format!("{:#}", err);
+    conn.transaction(|conn| {
+        async move {
+            // We insert datums in batches, so some of them may already have
+            // been committed. Delete them, so that nobody processes, retries
+            // or counts the datums of a half-created job.
+            job.lock_for_update(conn).await?;
+            let deleted = job.delete_datums(conn).await?;
+            if deleted > 0 {
+                warn!(
+                    "deleted {} datums of partially created job {}",
+                    deleted, job.job_name
+                );
+            }
+            // The babysitter may already have given up on this job.
+            if job.status == Status::Creating {
+                job.mark_as_error_with_message(&message, conn).await?;
+            }
+            Ok::<_, Error>(())
+        }
+        .scope_boxed()
+    })
+    .await
+}
+
+/// How many datums will we allow in a single job? `main` calls this at
+/// startup, so that a bad value stops `falconerid` from starting.
+pub fn max_datums_per_job() -> Result<u64> {
+    match std::env::var(MAX_DATUMS_PER_JOB_VAR) {
+        Ok(value) => value.trim().parse::<u64>().with_context(|| {
+            format!("could not parse {}={:?}", MAX_DATUMS_PER_JOB_VAR, value)
+        }),
+        Err(_) => Ok(DEFAULT_MAX_DATUMS_PER_JOB),
+    }
+}
+
+/// A job would have an implausible number of datums.
+#[derive(Debug)]
+pub enum DatumCountError {
+    /// The datum count is outside the pipeline's `expected_datum_count`.
+    Unexpected {
+        /// How many datums the job would have.
+        datum_count: u64,
+        /// The range the pipeline expected.
+        expected: DatumCountRange,
+    },
+    /// The datum count is over the server's limit.
+    TooMany {
+        /// How many datums the job would have.
+        datum_count: u64,
+        /// The server's limit.
+        max_datums: u64,
+    },
+}
+
+impl fmt::Display for DatumCountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DatumCountError::Unexpected {
+                datum_count,
+                expected,
+            } => write!(
+                f,
+                "job would have {} datums, but expected_datum_count is {:?}",
+                datum_count, expected,
+            ),
+            DatumCountError::TooMany {
+                datum_count,
+                max_datums,
+            } => write!(
+                f,
+                "job would have {} datums, but the server allows at most {} (use --override-datum-cap if you really mean it)",
+                datum_count, max_datums,
+            ),
+        }
+    }
+}
+
+impl error::Error for DatumCountError {}
+
+/// Make sure that `datum_count` is plausible, so that a bad glob can't
+/// accidentally create millions of datums and overload the database.
+fn check_datum_count(
+    pipeline_spec: &PipelineSpec,
+    datum_count: u64,
+    max_datums: u64,
+    override_datum_cap: bool,
+) -> result::Result<(), DatumCountError> {
+    if let Some(expected) = &pipeline_spec.expected_datum_count {
+        if !expected.contains(datum_count) {
+            return Err(DatumCountError::Unexpected {
+                datum_count,
+                expected: expected.clone(),
+            });
+        }
+    }
+    if datum_count > max_datums && !override_datum_cap {
+        return Err(DatumCountError::TooMany {
+            datum_count,
+            max_datums,
+        });
+    }
+    Ok(())
+}
+
+/// Volumes which are always present in our worker pods, and which extra
+/// containers may mount.
+const SHAREABLE_WORKER_VOLUMES: &[&str] = &["pfs", "scratch"];
+
+/// Volumes and containers which are always present in our worker pods, and
+/// whose names can't be reused.
+const RESERVED_POD_NAMES: &[&str] = &[
+    "pfs",
+    "scratch",
+    "falconeri-bin",
+    "worker-token",
+    "worker",
+    "copy-worker",
+    "input-cache",
+];
+
+/// Make sure that the extra containers and volumes in `pipeline_spec` fit
+/// into our worker pods.
+fn check_pod_containers(pipeline_spec: &PipelineSpec) -> Result<()> {
+    if let Some(input_cache) = &pipeline_spec.input_cache {
+        input_cache.max_bytes()?;
+        if let Some(host_path) = &input_cache.host_path {
+            if !host_path.starts_with('/') {
+                return Err(format_err!(
+                    "input cache host_path {:?} must be absolute",
+                    host_path
+                ));
+            }
+        }
+    }
+
+    let mut volume_names = HashSet::new();
+    for volume in &pipeline_spec.shared_volumes {
+        if RESERVED_POD_NAMES.contains(&volume.name.as_str())
+            || volume.name.starts_with("transform-secret-")
+            || !volume_names.insert(volume.name.as_str())
+        {
+            return Err(format_err!(
+                "shared volume name {:?} is already in use",
+                volume.name
+            ));
+        }
+    }
+
+    let mut container_names = HashSet::new();
+    for container in pipeline_spec
+        .init_containers
+        .iter()
+        .chain(&pipeline_spec.sidecars)
+    {
+        if RESERVED_POD_NAMES.contains(&container.name.as_str())
+            || !container_names.insert(container.name.as_str())
+        {
+            return Err(format_err!(
+                "container name {:?} is already in use",
+                container.name
+            ));
+        }
+        for mount in &container.volume_mounts {
+            if !volume_names.contains(mount.name.as_str())
+                && !SHAREABLE_WORKER_VOLUMES.contains(&mount.name.as_str())
+            {
+                return Err(format_err!(
+                    "container {:?} mounts unknown volume {:?}",
+                    container.name,
+                    mount.name
+                ));
+            }
+        }
+    }
+    Ok(())
 }
 
 /// The `job retry` subcommand.
 #[instrument(skip_all, fields(job = %job.id), level = "debug")]
-pub async fn retry_job(job: &Job, conn: &mut AsyncPgConnection) -> Result<Job> {
+pub async fn retry_job(
+    job: &Job,
+    skip_kubernetes: bool,
+    conn: &mut AsyncPgConnection,
+) -> Result<Job> {
     // Load the original job, failed datums, and input files.
-    if job.status != Status::Error {
-        return Err(format_err!("can only retry jobs with status 'error'"));
+    if job.status != Status::Error && job.status != Status::DoneWithErrors {
+        return Err(format_err!(
+            "can only retry jobs with status 'error' or 'done_with_errors'"
+        ));
     }
 
     let job_pipeline_spec = job.pipeline_spec.clone();
+    let job_submitted_pipeline_spec = job.submitted_pipeline_spec.clone();
     let job_command = job.command.clone();
     let job_egress_uri = job.egress_uri.clone();
+    let job_stop_on_first_error = job.stop_on_first_error;
+    let job_spec_hash = job.spec_hash.clone();
+    let job_max_inline_output_bytes = job.max_inline_output_bytes;
+    let job_output_log_uri = job.output_log_uri.clone();
+    let job_autoscale_parallelism = job.autoscale_parallelism;
+    let job_retry_backoff_seconds = job.retry_backoff_seconds;
+    let job_max_failed_datums = job.max_failed_datums;
+    let job_max_failed_datum_percent = job.max_failed_datum_percent;
+    let job_output_manifest_uri = job.output_manifest_uri.clone();
+    let job_target_parallelism = job.target_parallelism.map(cast::u32).transpose()?;
 
     let (pipeline_spec, new_job) = conn
         .transaction(|conn| {
             async move {
-                let error_datums = job.datums_with_status(Status::Error, conn).await?;
+                let error_datums =
+                    job.datums_with_status(Status::Error, None, conn).await?;
                 let input_files = InputFile::for_datums(&error_datums, conn).await?;
 
                 // Recover the original pipeline specification.
-                let mut pipeline_spec: PipelineSpec =
-                    serde_json::from_value(job_pipeline_spec.clone())
-                        .context("could not parse original pipeline spec")?;
+                let mut pipeline_spec = recover_pipeline_spec(&job_pipeline_spec)?;
+
+                // Run with the parallelism the job actually had, which may
+                // have been scaled since it was submitted, and charge that
+                // to the team's quota.
                 pipeline_spec.parallelism_spec.constant = min(
-                    pipeline_spec.parallelism_spec.constant,
+                    job_target_parallelism
+                        .unwrap_or(pipeline_spec.parallelism_spec.constant),
                     cast::u32(error_datums.len())?,
                 );
+                Quota::enforce(
+                    pipeline_spec.team.as_deref(),
+                    pipeline_spec.parallelism_spec.constant,
+                    conn,
+                )
+                .await?;
 
                 // Create a new job record.
                 let job_name =
                     unique_kubernetes_job_name(&pipeline_spec.pipeline.name);
                 let new_job = NewJob {
                     id: Uuid::new_v4(),
+                    status: Status::Running,
                     pipeline_spec: job_pipeline_spec.clone(),
                     job_name,
                     command: job_command.clone(),
                     egress_uri: job_egress_uri.clone(),
+                    stop_on_first_error: job_stop_on_first_error,
+                    spec_hash: job_spec_hash.clone(),
+                    max_inline_output_bytes: job_max_inline_output_bytes,
+                    output_log_uri: job_output_log_uri.clone(),
+                    autoscale_parallelism: job_autoscale_parallelism,
+                    target_parallelism: Some(cast::i32(
+                        pipeline_spec.parallelism_spec.constant,
+                    )?),
+                    // Retries are new submissions, so they don't share the
+                    // original job's idempotency key.
+                    idempotency_key: None,
+                    deferred_start: None,
+                    parent_job_id: Some(job.id),
+                    team: pipeline_spec.team.clone(),
+                    image_digest: pinned_image_digest(&pipeline_spec.transform.image),
+                    submitted_pipeline_spec: job_submitted_pipeline_spec.clone(),
+                    retry_backoff_seconds: job_retry_backoff_seconds,
+                    max_failed_datums: job_max_failed_datums,
+                    max_failed_datum_percent: job_max_failed_datum_percent,
+                    output_manifest_uri: job_output_manifest_uri.clone(),
                 }
                 .insert(conn)
                 .await?;
@@ -133,6 +991,9 @@ pub async fn retry_job(job: &Job, conn: &mut AsyncPgConnection) -> Result<Job> {
                         // I guess we'll give this the same number of retries it was
                         // allowed before?
                         maximum_allowed_run_count: old_datum.maximum_allowed_run_count,
+                        status: Status::Ready,
+                        input_hash: old_datum.input_hash.clone(),
+                        listed_input_bytes: old_datum.listed_input_bytes,
                     });
                     for input_file in input_files {
                         new_input_files.push(NewInputFile {
@@ -140,11 +1001,16 @@ pub async fn retry_job(job: &Job, conn: &mut AsyncPgConnection) -> Result<Job> {
                             uri: input_file.uri.clone(),
                             local_path: input_file.local_path.clone(),
                             job_id: new_job.id,
+                            etag: input_file.etag.clone(),
+                            generation: input_file.generation.clone(),
+                            decompress: input_file.decompress,
+                            size: input_file.size,
+                            last_modified: input_file.last_modified,
                         });
                     }
                 }
-                NewDatum::insert_all(&new_datums, conn).await?;
-                NewInputFile::insert_all(&new_input_files, conn).await?;
+                NewDatum::copy_in(&new_datums, conn).await?;
+                NewInputFile::copy_in(&new_input_files, conn).await?;
 
                 Ok::<_, Error>((pipeline_spec, new_job))
             }
@@ -153,20 +1019,219 @@ pub async fn retry_job(job: &Job, conn: &mut AsyncPgConnection) -> Result<Job> {
         .await?;
 
     // Start a new batch job.
-    start_batch_job(&pipeline_spec, &new_job).await?;
+    start_batch_job(&pipeline_spec, &new_job, skip_kubernetes, conn).await?;
     Ok(new_job)
 }
 
+/// The `job rerun` subcommand. Submits a fresh copy of `job`, with any
+/// overrides from `request`, which records `job` as its parent.
+#[instrument(skip_all, fields(job = %job.id), level = "debug")]
+pub async fn rerun_job(
+    pool: db::AsyncPool,
+    job: &Job,
+    request: &RerunJobRequest,
+    skip_kubernetes: bool,
+    conn: &mut AsyncPgConnection,
+) -> Result<Job> {
+    let mut pipeline_spec = job_pipeline_spec(job)?;
+    if let Some(image_tag) = &request.image_tag {
+        pipeline_spec.transform.image =
+            replace_image_tag(&pipeline_spec.transform.image, image_tag);
+    }
+    if let Some(cmd) = &request.cmd {
+        pipeline_spec.transform.cmd = cmd.clone();
+    }
+    if let Some(egress_uri) = &request.egress_uri {
+        pipeline_spec.egress.uri = egress_uri.clone();
+    }
+
+    // Wait for the same upstream jobs as the original, as long as they can
+    // still succeed.
+    let upstream_jobs = job.upstream_jobs(conn).await?;
+    if let Some(failed) = upstream_jobs.iter().find(|upstream| {
+        upstream.status.has_finished() && !upstream.status.has_succeeded()
+    }) {
+        return Err(format_err!(
+            "cannot rerun {}, because upstream job {} finished with status {}",
+            job.job_name,
+            failed.job_name,
+            failed.status,
+        ));
+    }
+
+    let spec_hash = pipeline_spec.canonical_hash()?;
+    let job_name = unique_kubernetes_job_name(&pipeline_spec.pipeline.name);
+    run_job(
+        pool,
+        &pipeline_spec,
+        &job_name,
+        &spec_hash,
+        true,
+        false,
+        None,
+        &upstream_jobs,
+        Some(job.id),
+        skip_kubernetes,
+        conn,
+    )
+    .await
+}
+
+/// The pipeline spec which was submitted for `job`. For older jobs, which
+/// didn't store a complete copy, we do our best to recover it from
+/// `Job::pipeline_spec`.
+pub fn job_pipeline_spec(job: &Job) -> Result<PipelineSpec> {
+    match &job.submitted_pipeline_spec {
+        Some(submitted) => serde_json::from_value(submitted.clone())
+            .context("could not parse submitted pipeline spec"),
+        None => recover_pipeline_spec(&job.pipeline_spec),
+    }
+}
+
+/// Recover a `PipelineSpec` from the JSON stored in `Job::pipeline_spec`.
+///
+/// We store `job_timeout` as a number of seconds, but `PipelineSpec` expects
+/// a human-readable duration, so we need to convert it back.
+fn recover_pipeline_spec(stored: &serde_json::Value) -> Result<PipelineSpec> {
+    let mut stored = stored.clone();
+    if let Some(secs) = stored["job_timeout"].as_u64() {
+        stored["job_timeout"] = json!(format!("{}s", secs));
+    }
+    serde_json::from_value(stored).context("could not parse original pipeline spec")
+}
+
+/// Replace the tag of a Docker `image`, removing any digest. Registry ports
+/// like `localhost:5000/image` are not mistaken for tags.
+fn replace_image_tag(image: &str, tag: &str) -> String {
+    let image = image.split('@').next().unwrap_or(image);
+    let name_start = image.rfind('/').map_or(0, |slash| slash + 1);
+    let repository = match image[name_start..].find(':') {
+        Some(colon) => &image[..name_start + colon],
+        None => image,
+    };
+    format!("{}:{}", repository, tag)
+}
+
+/// The tag of a Docker `image`, if it has one. Registry ports like
+/// `localhost:5000/image` are not mistaken for tags.
+fn image_tag(image: &str) -> Option<&str> {
+    let image = image.split('@').next().unwrap_or(image);
+    let name_start = image.rfind('/').map_or(0, |slash| slash + 1);
+    image[name_start..]
+        .find(':')
+        .map(|colon| &image[name_start + colon + 1..])
+}
+
+/// The digest of a Docker `image`, if it was pinned using `image@digest`.
+pub fn pinned_image_digest(image: &str) -> Option<String> {
+    image
+        .split_once('@')
+        .map(|(_, digest)| digest.to_owned())
+        .filter(|digest| !digest.is_empty())
+}
+
+/// Environment variable which tells us to refuse jobs whose images use the
+/// `latest` tag.
+const REJECT_LATEST_IMAGES_VAR: &str = "FALCONERID_REJECT_LATEST_IMAGES";
+
+/// Should we refuse jobs whose images use the `latest` tag? See
+/// [`REJECT_LATEST_IMAGES_VAR`].
+fn reject_latest_images() -> bool {
+    std::env::var(REJECT_LATEST_IMAGES_VAR)
+        .map(|v| v == "true" || v == "1")
+        .unwrap_or(false)
+}
+
+/// If `reject_latest` is true, make sure `image` doesn't use the `latest`
+/// tag, either explicitly or by default. Images pinned by digest are fine.
+fn check_image_tag(image: &str, reject_latest: bool) -> Result<()> {
+    if !reject_latest || pinned_image_digest(image).is_some() {
+        return Ok(());
+    }
+    match image_tag(image) {
+        Some(tag) if tag != "latest" => Ok(()),
+        _ => Err(format_err!(
+            "image {:?} uses the `latest` tag, but this server requires images to have a specific tag or digest",
+            image
+        )),
+    }
+}
+
+/// Make sure that `max_failed_datum_percent` is a sensible percentage.
+fn check_failure_thresholds(pipeline_spec: &PipelineSpec) -> Result<()> {
+    match pipeline_spec.max_failed_datum_percent {
+        Some(percent) if !(0.0..=100.0).contains(&percent) => Err(format_err!(
+            "max_failed_datum_percent must be between 0 and 100, not {}",
+            percent
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Make sure that `transform.archive_outputs` is only used when our command
+/// writes its output to `/pfs/out`.
+fn check_archive_outputs(pipeline_spec: &PipelineSpec) -> Result<()> {
+    let transform = &pipeline_spec.transform;
+    if transform.archive_outputs.is_some() && transform.stdin_files {
+        return Err(format_err!(
+            "cannot use transform.archive_outputs with transform.stdin_files"
+        ));
+    }
+    Ok(())
+}
+
+/// Stop a batch job which we've given up on, deleting the Kubernetes job and
+/// any worker pods which are still running.
+#[instrument(skip_all, fields(job = %job.id), level = "debug")]
+pub async fn stop_batch_job(job: &Job, skip_kubernetes: bool) -> Result<()> {
+    warn!("stopping batch job {} early", job.job_name);
+    if skip_kubernetes {
+        return Ok(());
+    }
+    kubernetes::delete_job(&job.job_name).await
+}
+
 /// Generate a unique name for our job. To keep Kubernetes happy, this
 /// must be a legal DNS name component (but we have a database constraint
 /// to enforce that).
 pub fn unique_kubernetes_job_name(pipeline_name: &str) -> String {
+    generate_kubernetes_job_name(&format!("{}-", pipeline_name))
+}
+
+/// Generate a unique job name by adding a random tag to `prefix`, like
+/// Kubernetes does for `metadata.generateName`. Long prefixes are truncated
+/// to leave room for the tag.
+pub fn generate_kubernetes_job_name(prefix: &str) -> String {
     let tag = kubernetes::resource_tag();
-    format!("{}-{}", pipeline_name, tag)
+    let prefix = prefix
+        .chars()
+        .take(MAX_JOB_NAME_LEN - tag.len())
+        .collect::<String>();
+    format!("{}{}", prefix, tag)
         .replace('_', "-")
         .to_lowercase()
 }
 
+/// Choose a name for a new job. Users may specify either an exact `name`, or
+/// a `generate_name` prefix. Otherwise, we generate a name from the pipeline
+/// name.
+pub fn choose_job_name(
+    pipeline_spec: &PipelineSpec,
+    name: Option<&str>,
+    generate_name: Option<&str>,
+) -> Result<String> {
+    let job_name = match (name, generate_name) {
+        (Some(_), Some(_)) => {
+            return Err(format_err!("cannot specify both name and generate_name"));
+        }
+        (Some(name), None) => name.to_owned(),
+        (None, Some(prefix)) => generate_kubernetes_job_name(prefix),
+        (None, None) => unique_kubernetes_job_name(&pipeline_spec.pipeline.name),
+    };
+    check_job_name(&job_name)?;
+    Ok(job_name)
+}
+
 /// The manifest to use to run a job.
 const RUN_MANIFEST_TEMPLATE: &str = include_str!("job_manifest.yml.hbs");
 
@@ -180,49 +1245,224 @@ struct JobParams<'a> {
     falconeri_image: String,
     /// Whether to use `imagePullPolicy: Never` for the init container (for local dev).
     use_local_image: bool,
+    /// Our requester-pays inputs, formatted as JSON for workers, if we have
+    /// any.
+    requester_pays: Option<String>,
+    /// The Kubernetes secret containing our workers' API token.
+    worker_token_secret: String,
+    /// The secrets our workers should fetch from Vault, formatted as JSON,
+    /// if we have any.
+    vault_secrets: Option<String>,
 }
 
 impl<'a> JobParams<'a> {
-    fn new(pipeline_spec: &'a PipelineSpec, job: &'a Job) -> JobParams<'a> {
+    fn new(pipeline_spec: &'a PipelineSpec, job: &'a Job) -> Result<JobParams<'a>> {
         let job_timeout = pipeline_spec.job_timeout.map(|timeout| timeout.as_secs());
         let falconeri_image = std::env::var("FALCONERI_IMAGE").unwrap_or_else(|_| {
             format!("ghcr.io/dbcrossbar/falconeri:{}", env!("CARGO_PKG_VERSION"))
         });
         let use_local_image = kubernetes::use_local_image();
-        Self {
+        let requester_pays_uris = pipeline_spec.input.requester_pays_uris();
+        let requester_pays = (!requester_pays_uris.is_empty()).then(|| {
+            serde_json::to_string(&requester_pays_uris)
+                .expect("could not serialize requester-pays inputs")
+        });
+        let vault_secrets = vault_secrets(pipeline_spec)?.map(|vault_secrets| {
+            serde_json::to_string(&vault_secrets)
+                .expect("could not serialize Vault secrets")
+        });
+        Ok(Self {
             pipeline_spec,
             job_timeout,
             job,
             falconeri_image,
             use_local_image,
-        }
+            requester_pays,
+            worker_token_secret: worker_token_secret_name(job),
+            vault_secrets,
+        })
     }
 }
 
+/// The secrets which workers for `pipeline_spec` should fetch from Vault, if
+/// any. Fails if Vault isn't configured correctly.
+fn vault_secrets(pipeline_spec: &PipelineSpec) -> Result<Option<VaultSecrets>> {
+    VaultSecrets::for_secrets(
+        pipeline_spec.transform.vault.as_ref(),
+        &pipeline_spec.transform.secrets,
+    )
+}
+
+/// Environment variable specifying how many hours worker tokens last, for
+/// jobs without a `job_timeout`.
+const WORKER_TOKEN_LIFETIME_VAR: &str = "FALCONERID_WORKER_TOKEN_LIFETIME_HOURS";
+
+/// Default number of hours which worker tokens last, for jobs without a
+/// `job_timeout`.
+const DEFAULT_WORKER_TOKEN_LIFETIME_HOURS: u64 = 7 * 24;
+
+/// How long after a job's `job_timeout` should its worker token keep working?
+/// This leaves time for workers to report on their final datums.
+const WORKER_TOKEN_GRACE_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+/// How long should the worker token for a job using `pipeline_spec` last?
+/// Returns `None` if it should never expire.
+fn worker_token_lifetime(pipeline_spec: &PipelineSpec) -> Result<Option<Duration>> {
+    if let Some(job_timeout) = pipeline_spec.job_timeout {
+        return Ok(Some(job_timeout + WORKER_TOKEN_GRACE_PERIOD));
+    }
+    // Streaming jobs run until they're stopped.
+    if pipeline_spec.streaming {
+        return Ok(None);
+    }
+    let hours = match std::env::var(WORKER_TOKEN_LIFETIME_VAR) {
+        Ok(value) => value.trim().parse::<u64>().with_context(|| {
+            format!("could not parse {}={:?}", WORKER_TOKEN_LIFETIME_VAR, value)
+        })?,
+        Err(_) => DEFAULT_WORKER_TOKEN_LIFETIME_HOURS,
+    };
+    Ok(Some(Duration::from_secs(hours * 60 * 60)))
+}
+
+/// The name of the Kubernetes secret containing the worker token for `job`.
+fn worker_token_secret_name(job: &Job) -> String {
+    format!("{}-worker-token", job.job_name)
+}
+
+/// Store `token` in a Kubernetes secret which our workers can mount.
+#[instrument(skip_all, fields(job = %job.id), level = "trace")]
+async fn deploy_worker_token_secret(job: &Job, token: &str) -> Result<()> {
+    let secret = json!({
+        "apiVersion": "v1",
+        "kind": "Secret",
+        "metadata": {
+            "name": worker_token_secret_name(job),
+            "labels": { "created-by": "falconeri" },
+        },
+        "type": "Opaque",
+        "stringData": { "token": token },
+    });
+    kubernetes::deploy(&secret.to_string())
+        .await
+        .context("could not create worker token secret")
+}
+
+/// Make our Kubernetes job the owner of its worker token secret, so that
+/// Kubernetes deletes the secret when it cleans up the job.
+#[instrument(skip_all, fields(job = %job.id), level = "trace")]
+async fn set_worker_token_secret_owner(job: &Job) -> Result<()> {
+    let k8s_job = kubernetes::kubectl_parse_json::<serde_json::Value>(&[
+        "get",
+        "job",
+        &job.job_name,
+        "-o",
+        "json",
+    ])
+    .await?;
+    let uid = k8s_job["metadata"]["uid"]
+        .as_str()
+        .ok_or_else(|| format_err!("Kubernetes job {} has no uid", job.job_name))?;
+    let patch = json!({
+        "metadata": {
+            "ownerReferences": [{
+                "apiVersion": "batch/v1",
+                "kind": "Job",
+                "name": job.job_name,
+                "uid": uid,
+            }],
+        },
+    });
+    kubernetes::kubectl(&[
+        "patch",
+        "secret",
+        &worker_token_secret_name(job),
+        "--type=merge",
+        "-p",
+        &patch.to_string(),
+    ])
+    .await
+}
+
 /// Start a new batch job running.
 #[instrument(skip_all, fields(job = %job.id), level = "debug")]
-pub async fn start_batch_job(pipeline_spec: &PipelineSpec, job: &Job) -> Result<()> {
+pub async fn start_batch_job(
+    pipeline_spec: &PipelineSpec,
+    job: &Job,
+    skip_kubernetes: bool,
+    conn: &mut AsyncPgConnection,
+) -> Result<()> {
     debug!("starting batch job on cluster");
+    if skip_kubernetes {
+        debug!("skipping Kubernetes, so not starting Kubernetes job");
+        return Ok(());
+    }
+
+    // Give our workers a token which only works for this job, so that they
+    // don't need our database password.
+    let lifetime = worker_token_lifetime(pipeline_spec)?;
+    let token = WorkerToken::mint(job, lifetime, conn).await?;
+    deploy_worker_token_secret(job, &token).await?;
 
     // Set up our template parameters, rendder our template, and deploy it.
-    let params = JobParams::new(pipeline_spec, job);
-    let manifest = render_manifest(RUN_MANIFEST_TEMPLATE, &params)
+    let params = JobParams::new(pipeline_spec, job)?;
+    let mut manifest = render_manifest(RUN_MANIFEST_TEMPLATE, &params)
         .context("error rendering job template")?;
+
+    // Apply any custom changes to our pod template.
+    if let Some(pod_template_patch) = &pipeline_spec.pod_template_patch {
+        if !pod_template_patch.is_object() {
+            return Err(format_err!("pod_template_patch must be a JSON object"));
+        }
+        let patch = json!({ "spec": { "template": pod_template_patch } });
+        manifest = kubernetes::strategic_merge_patch_local(&manifest, &patch)
+            .await
+            .context("could not apply pod_template_patch")?;
+    }
+
     kubernetes::deploy(&manifest).await?;
 
+    // If this fails, we'll leave the secret behind, but our job will work.
+    if let Err(err) = set_worker_token_secret_owner(job).await {
+        warn!(
+            "could not make job {} own its worker token secret: {:?}",
+            job.job_name, err
+        );
+    }
+
     Ok(())
 }
 
 #[test]
-fn render_template() {
-    use falconeri_common::serde_json;
-    use serde_yaml;
+fn check_datum_count_enforces_limits() {
+    let mut pipeline_spec = example_pipeline_spec();
 
-    let json = include_str!("../../falconeri_common/src/example_pipeline_spec.json");
-    let pipeline_spec: PipelineSpec = serde_json::from_str(json).expect("parse error");
+    // The server-wide cap can be overridden.
+    assert!(check_datum_count(&pipeline_spec, 100, 100, false).is_ok());
+    assert!(matches!(
+        check_datum_count(&pipeline_spec, 101, 100, false),
+        Err(DatumCountError::TooMany { .. })
+    ));
+    assert!(check_datum_count(&pipeline_spec, 101, 100, true).is_ok());
+
+    // But the spec's own expectations can't.
+    pipeline_spec.expected_datum_count = Some(DatumCountRange {
+        min: Some(5),
+        max: Some(10),
+    });
+    assert!(check_datum_count(&pipeline_spec, 7, 100, false).is_ok());
+    assert!(matches!(
+        check_datum_count(&pipeline_spec, 11, 100, true),
+        Err(DatumCountError::Unexpected { .. })
+    ));
+    assert!(check_datum_count(&pipeline_spec, 4, 100, true).is_err());
+}
+
+#[test]
+fn render_template() {
+    let pipeline_spec = example_pipeline_spec();
 
     let job = Job::factory();
-    let params = JobParams::new(&pipeline_spec, &job);
+    let params = JobParams::new(&pipeline_spec, &job).unwrap();
 
     let manifest = render_manifest(RUN_MANIFEST_TEMPLATE, &params)
         .expect("error rendering job template");
@@ -230,3 +1470,566 @@ fn render_template() {
     let _parsed: serde_json::Value =
         serde_yaml::from_str(&manifest).expect("rendered invalid YAML");
 }
+
+#[test]
+fn render_indexed_template() {
+    let mut pipeline_spec = example_pipeline_spec();
+    pipeline_spec.parallelism_spec.indexed = true;
+
+    let job = Job::factory();
+    let params = JobParams::new(&pipeline_spec, &job).unwrap();
+
+    let manifest = render_manifest(RUN_MANIFEST_TEMPLATE, &params)
+        .expect("error rendering job template");
+    let parsed: serde_json::Value =
+        serde_yaml::from_str(&manifest).expect("rendered invalid YAML");
+    assert_eq!(parsed["spec"]["completionMode"], "Indexed");
+    assert_eq!(
+        parsed["spec"]["completions"],
+        pipeline_spec.parallelism_spec.constant
+    );
+}
+
+#[test]
+fn check_pod_containers_rejects_conflicts() {
+    let mut pipeline_spec = example_pipeline_spec();
+    pipeline_spec.shared_volumes = vec![SharedVolume {
+        name: "models".to_owned(),
+        mount_path: "/models".to_owned(),
+    }];
+    let container = |name: &str, volume: &str| ExtraContainer {
+        name: name.to_owned(),
+        image: "busybox".to_owned(),
+        command: vec![],
+        resources: None,
+        volume_mounts: vec![VolumeMount {
+            name: volume.to_owned(),
+            mount_path: "/data".to_owned(),
+        }],
+    };
+
+    pipeline_spec.init_containers = vec![container("warm-cache", "models")];
+    pipeline_spec.sidecars = vec![container("proxy", "scratch")];
+    assert!(check_pod_containers(&pipeline_spec).is_ok());
+
+    // Names must be unique.
+    pipeline_spec.sidecars = vec![container("warm-cache", "scratch")];
+    assert!(check_pod_containers(&pipeline_spec).is_err());
+    pipeline_spec.sidecars = vec![container("worker", "scratch")];
+    assert!(check_pod_containers(&pipeline_spec).is_err());
+
+    // Volumes must exist.
+    pipeline_spec.sidecars = vec![container("proxy", "missing")];
+    assert!(check_pod_containers(&pipeline_spec).is_err());
+    pipeline_spec.sidecars = vec![];
+
+    // Input caches need a valid size and an absolute host path.
+    pipeline_spec.input_cache = Some(InputCache {
+        size: "20Gi".to_owned(),
+        host_path: Some("/var/cache/falconeri".to_owned()),
+    });
+    assert!(check_pod_containers(&pipeline_spec).is_ok());
+    pipeline_spec.input_cache = Some(InputCache {
+        size: "lots".to_owned(),
+        host_path: None,
+    });
+    assert!(check_pod_containers(&pipeline_spec).is_err());
+    pipeline_spec.input_cache = Some(InputCache {
+        size: "20Gi".to_owned(),
+        host_path: Some("cache".to_owned()),
+    });
+    assert!(check_pod_containers(&pipeline_spec).is_err());
+}
+
+#[test]
+fn render_template_with_extra_containers() {
+    let mut pipeline_spec = example_pipeline_spec();
+    pipeline_spec.shared_volumes = vec![SharedVolume {
+        name: "models".to_owned(),
+        mount_path: "/models".to_owned(),
+    }];
+    pipeline_spec.init_containers = vec![ExtraContainer {
+        name: "warm-cache".to_owned(),
+        image: "busybox".to_owned(),
+        command: vec!["sh".to_owned(), "-c".to_owned(), "echo \"hi\"".to_owned()],
+        resources: None,
+        volume_mounts: vec![VolumeMount {
+            name: "models".to_owned(),
+            mount_path: "/cache".to_owned(),
+        }],
+    }];
+    pipeline_spec.sidecars = vec![ExtraContainer {
+        name: "proxy".to_owned(),
+        image: "squid".to_owned(),
+        command: vec![],
+        resources: Some(ResourceRequests {
+            memory: "100Mi".to_owned(),
+            cpu: 0.1,
+        }),
+        volume_mounts: vec![],
+    }];
+
+    let pod_spec = render_pod_spec(&pipeline_spec);
+    let init_containers = pod_spec["initContainers"].as_array().unwrap();
+    assert_eq!(init_containers.len(), 3);
+    assert_eq!(init_containers[1]["name"], "proxy");
+    assert_eq!(init_containers[1]["restartPolicy"], "Always");
+    assert_eq!(init_containers[2]["command"][2], "echo \"hi\"");
+    assert_eq!(init_containers[2]["volumeMounts"][0]["mountPath"], "/cache");
+    let worker_mounts = pod_spec["containers"][0]["volumeMounts"]
+        .as_array()
+        .unwrap();
+    assert!(worker_mounts.iter().any(|m| m["name"] == "models"));
+}
+
+#[test]
+fn render_template_with_scratch_volume() {
+    let mut pipeline_spec = example_pipeline_spec();
+    pipeline_spec.scratch_volume = Some(ScratchVolume {
+        size: "50Gi".to_owned(),
+        storage_class_name: None,
+    });
+    let pod_spec = render_pod_spec(&pipeline_spec);
+    assert_eq!(
+        volume(&pod_spec, "scratch")["emptyDir"]["sizeLimit"],
+        "50Gi"
+    );
+
+    pipeline_spec.scratch_volume = Some(ScratchVolume {
+        size: "50Gi".to_owned(),
+        storage_class_name: Some("fast-ssd".to_owned()),
+    });
+    let pod_spec = render_pod_spec(&pipeline_spec);
+    let claim_spec =
+        &volume(&pod_spec, "scratch")["ephemeral"]["volumeClaimTemplate"]["spec"];
+    assert_eq!(claim_spec["storageClassName"], "fast-ssd");
+    assert_eq!(claim_spec["resources"]["requests"]["storage"], "50Gi");
+}
+
+#[test]
+fn render_template_with_egress_options() {
+    let mut pipeline_spec = example_pipeline_spec();
+    let pod_spec = render_pod_spec(&pipeline_spec);
+    assert_eq!(env_var(&pod_spec, "FALCONERI_EGRESS_KMS_KEY"), None);
+    assert_eq!(env_var(&pod_spec, "FALCONERI_EGRESS_STORAGE_CLASS"), None);
+
+    pipeline_spec.egress.encryption = Some(EgressEncryption {
+        kms_key: "arn:aws:kms:us-east-1:111122223333:key/example".to_owned(),
+    });
+    pipeline_spec.egress.storage_class = Some("STANDARD_IA".to_owned());
+    let pod_spec = render_pod_spec(&pipeline_spec);
+    assert_eq!(
+        env_var(&pod_spec, "FALCONERI_EGRESS_KMS_KEY").unwrap(),
+        "arn:aws:kms:us-east-1:111122223333:key/example"
+    );
+    assert_eq!(
+        env_var(&pod_spec, "FALCONERI_EGRESS_STORAGE_CLASS").unwrap(),
+        "STANDARD_IA"
+    );
+}
+
+#[test]
+fn render_template_with_requester_pays_inputs() {
+    use falconeri_common::storage::{requester_pays_for_uri, RequesterPays};
+
+    let mut pipeline_spec = example_pipeline_spec();
+    pipeline_spec.input = Input::Atom {
+        uri: "gs://public-data/genomes/".to_owned(),
+        repo: "genomes".to_owned(),
+        glob: Glob::TopLevelDirectoryEntries,
+        requester_pays: true,
+        billing_project: Some("research".to_owned()),
+        decompress: false,
+        partitions: None,
+        partition_by: PartitionBy::Count,
+    };
+    let pod_spec = render_pod_spec(&pipeline_spec);
+    let value = env_var(&pod_spec, "FALCONERI_REQUESTER_PAYS")
+        .expect("missing FALCONERI_REQUESTER_PAYS");
+    assert_eq!(
+        requester_pays_for_uri(value.as_str(), "gs://public-data/genomes/1.vcf")
+            .unwrap(),
+        Some(RequesterPays {
+            billing_project: Some("research".to_owned()),
+        })
+    );
+}
+
+#[test]
+fn render_template_with_input_cache() {
+    // Without a host path, the cache lives in `/pfs`.
+    let mut pipeline_spec = example_pipeline_spec();
+    pipeline_spec.input_cache = Some(InputCache {
+        size: "20Gi".to_owned(),
+        host_path: None,
+    });
+    let pod_spec = render_pod_spec(&pipeline_spec);
+    assert_eq!(
+        env_var(&pod_spec, "FALCONERI_INPUT_CACHE_SIZE").unwrap(),
+        "20Gi"
+    );
+    assert_eq!(env_var(&pod_spec, "FALCONERI_INPUT_CACHE_DIR"), None);
+    let volumes = pod_spec["volumes"].as_array().unwrap();
+    assert!(!volumes.iter().any(|v| v["name"] == "input-cache"));
+
+    // With a host path, we mount it.
+    pipeline_spec.input_cache = Some(InputCache {
+        size: "20Gi".to_owned(),
+        host_path: Some("/var/cache/falconeri".to_owned()),
+    });
+    let pod_spec = render_pod_spec(&pipeline_spec);
+    assert_eq!(
+        env_var(&pod_spec, "FALCONERI_INPUT_CACHE_DIR").unwrap(),
+        "/input-cache"
+    );
+    assert_eq!(
+        volume(&pod_spec, "input-cache")["hostPath"]["path"],
+        "/var/cache/falconeri"
+    );
+    let mounts = pod_spec["containers"][0]["volumeMounts"]
+        .as_array()
+        .unwrap();
+    assert!(mounts
+        .iter()
+        .any(|m| m["name"] == "input-cache" && m["mountPath"] == "/input-cache"));
+}
+
+#[test]
+fn choose_job_name_handles_names_and_prefixes() {
+    let pipeline_spec = example_pipeline_spec();
+
+    assert_eq!(
+        choose_job_name(&pipeline_spec, Some("nightly-etl"), None).unwrap(),
+        "nightly-etl"
+    );
+    assert!(choose_job_name(&pipeline_spec, Some("Nightly_ETL"), None).is_err());
+    assert!(choose_job_name(&pipeline_spec, Some("a"), Some("b-")).is_err());
+
+    let generated = choose_job_name(&pipeline_spec, None, Some("nightly-")).unwrap();
+    assert!(generated.starts_with("nightly-"));
+    assert_eq!(generated.len(), "nightly-".len() + 10);
+
+    // Long prefixes are truncated to leave room for our tag.
+    let long_prefix = "a".repeat(100);
+    let generated = choose_job_name(&pipeline_spec, None, Some(&long_prefix)).unwrap();
+    assert_eq!(generated.len(), MAX_JOB_NAME_LEN);
+}
+
+#[test]
+fn use_upstream_egress_as_input_replaces_atom_uri() {
+    let mut pipeline_spec = example_pipeline_spec();
+    let mut upstream_job = Job::factory();
+    upstream_job.egress_uri = "gs://example-bucket/upstream/".to_owned();
+
+    use_upstream_egress_as_input(&mut pipeline_spec, &[upstream_job.clone()]).unwrap();
+    match &pipeline_spec.input {
+        Input::Atom { uri, repo, .. } => {
+            assert_eq!(uri, "gs://example-bucket/upstream/");
+            assert_eq!(repo, "books");
+        }
+        other => panic!("unexpected input {:?}", other),
+    }
+
+    // We need exactly one upstream job.
+    assert!(use_upstream_egress_as_input(&mut pipeline_spec, &[]).is_err());
+    assert!(use_upstream_egress_as_input(
+        &mut pipeline_spec,
+        &[upstream_job.clone(), upstream_job]
+    )
+    .is_err());
+}
+
+#[test]
+fn recover_pipeline_spec_converts_job_timeout() {
+    let pipeline_spec = example_pipeline_spec();
+    let mut stored = serde_json::to_value(&pipeline_spec).unwrap();
+    stored["job_timeout"] = json!(3600);
+    let recovered = recover_pipeline_spec(&stored).unwrap();
+    assert_eq!(
+        recovered.job_timeout,
+        Some(std::time::Duration::from_secs(3600))
+    );
+    assert_eq!(recovered.transform.cmd, pipeline_spec.transform.cmd);
+}
+
+#[test]
+fn failure_threshold_percent_must_be_sensible() {
+    let mut pipeline_spec = example_pipeline_spec();
+    assert!(check_failure_thresholds(&pipeline_spec).is_ok());
+    pipeline_spec.max_failed_datum_percent = Some(2.5);
+    assert!(check_failure_thresholds(&pipeline_spec).is_ok());
+    pipeline_spec.max_failed_datum_percent = Some(150.0);
+    assert!(check_failure_thresholds(&pipeline_spec).is_err());
+}
+
+#[test]
+fn archive_outputs_requires_output_dir() {
+    let mut pipeline_spec = example_pipeline_spec();
+    pipeline_spec.transform.archive_outputs = Some(ArchiveFormat::Zip);
+    assert!(check_archive_outputs(&pipeline_spec).is_ok());
+    pipeline_spec.transform.stdin_files = true;
+    assert!(check_archive_outputs(&pipeline_spec).is_err());
+}
+
+#[test]
+fn job_pipeline_spec_prefers_submitted_spec() {
+    let pipeline_spec = example_pipeline_spec();
+    let mut job = Job::factory();
+    job.pipeline_spec = serde_json::to_value(&pipeline_spec).unwrap();
+    job.pipeline_spec["job_timeout"] = json!(3600);
+    assert_eq!(
+        job_pipeline_spec(&job).unwrap().job_timeout,
+        Some(std::time::Duration::from_secs(3600))
+    );
+
+    job.submitted_pipeline_spec = Some(serde_json::to_value(&pipeline_spec).unwrap());
+    assert_eq!(job_pipeline_spec(&job).unwrap(), pipeline_spec);
+}
+
+#[test]
+fn replace_image_tag_handles_registries_and_digests() {
+    assert_eq!(replace_image_tag("alpine", "3.20"), "alpine:3.20");
+    assert_eq!(replace_image_tag("alpine:latest", "3.20"), "alpine:3.20");
+    assert_eq!(
+        replace_image_tag("localhost:5000/tools/worker:v1", "v2"),
+        "localhost:5000/tools/worker:v2"
+    );
+    assert_eq!(
+        replace_image_tag("localhost:5000/worker", "v2"),
+        "localhost:5000/worker:v2"
+    );
+    assert_eq!(
+        replace_image_tag("ghcr.io/org/worker:v1@sha256:abcd", "v2"),
+        "ghcr.io/org/worker:v2"
+    );
+}
+
+#[test]
+fn latest_images_can_be_rejected() {
+    assert_eq!(image_tag("localhost:5000/worker"), None);
+    assert_eq!(
+        image_tag("localhost:5000/worker:v1@sha256:abcd"),
+        Some("v1")
+    );
+    assert_eq!(
+        pinned_image_digest("ghcr.io/org/worker@sha256:abcd").as_deref(),
+        Some("sha256:abcd")
+    );
+    assert_eq!(pinned_image_digest("alpine:3.20"), None);
+
+    assert!(check_image_tag("alpine", false).is_ok());
+    assert!(check_image_tag("alpine", true).is_err());
+    assert!(check_image_tag("alpine:latest", true).is_err());
+    assert!(check_image_tag("localhost:5000/alpine", true).is_err());
+    assert!(check_image_tag("alpine:3.20", true).is_ok());
+    assert!(check_image_tag("alpine@sha256:abcd", true).is_ok());
+}
+
+#[test]
+fn add_datum_to_plan_counts_bytes() {
+    let file = |uri: &str, size: Option<u64>| {
+        let input_file = NewInputFile {
+            datum_id: Uuid::nil(),
+            uri: uri.to_owned(),
+            local_path: "/pfs/in/file".to_owned(),
+            job_id: Uuid::nil(),
+            etag: None,
+            generation: None,
+            decompress: false,
+            size: None,
+            last_modified: None,
+        };
+        (input_file, size)
+    };
+    let mut plan = JobPlan::default();
+    add_datum_to_plan(
+        &mut plan,
+        &[file("gs://b/a", Some(10)), file("gs://b/c/", None)],
+    );
+    for i in 0..PLAN_EXAMPLE_DATUM_COUNT {
+        add_datum_to_plan(&mut plan, &[file(&format!("gs://b/{}", i), Some(3))]);
+    }
+    assert_eq!(
+        plan.datum_count,
+        cast::u64(PLAN_EXAMPLE_DATUM_COUNT).unwrap() + 1
+    );
+    assert_eq!(plan.input_file_count, plan.datum_count + 1);
+    assert_eq!(plan.unknown_size_file_count, 1);
+    assert_eq!(
+        plan.total_bytes,
+        10 + 3 * cast::u64(PLAN_EXAMPLE_DATUM_COUNT).unwrap()
+    );
+    assert_eq!(plan.largest_datum_bytes, 10);
+    assert_eq!(plan.example_datums.len(), PLAN_EXAMPLE_DATUM_COUNT);
+    assert_eq!(plan.example_datums[0].bytes, 10);
+    assert_eq!(plan.example_datums[0].input_files[1].size, None);
+}
+
+#[test]
+fn render_template_with_worker_token() {
+    // Workers get their own token, and not our database password.
+    let pod_spec = render_pod_spec(&example_pipeline_spec());
+    let secret_names = pod_spec["volumes"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter_map(|volume| volume["secret"]["secretName"].as_str())
+        .collect::<Vec<_>>();
+    assert!(secret_names.contains(&worker_token_secret_name(&Job::factory()).as_str()));
+    assert!(!secret_names.contains(&"falconeri"));
+}
+
+#[test]
+fn worker_tokens_outlive_job_timeouts() {
+    let mut pipeline_spec = example_pipeline_spec();
+    pipeline_spec.job_timeout = Some(Duration::from_secs(600));
+    assert_eq!(
+        worker_token_lifetime(&pipeline_spec).unwrap(),
+        Some(Duration::from_secs(600) + WORKER_TOKEN_GRACE_PERIOD)
+    );
+    pipeline_spec.job_timeout = None;
+    pipeline_spec.streaming = true;
+    assert_eq!(worker_token_lifetime(&pipeline_spec).unwrap(), None);
+}
+
+#[test]
+fn render_template_with_vault_secrets() {
+    use falconeri_common::secret::{Secret, VaultConfig};
+
+    let mut pipeline_spec = example_pipeline_spec();
+    let vault_secret = Secret::Vault {
+        vault_path: "secret/data/s3".to_owned(),
+        field: "secret_access_key".to_owned(),
+        env_var: "AWS_SECRET_ACCESS_KEY".to_owned(),
+    };
+    pipeline_spec.transform.secrets.push(vault_secret.clone());
+    assert!(JobParams::new(&pipeline_spec, &Job::factory()).is_err());
+
+    pipeline_spec.transform.vault = Some(VaultConfig {
+        address: "https://vault.example.com:8200".to_owned(),
+        role: Some("falconeri".to_owned()),
+        auth_mount: "kubernetes".to_owned(),
+        token_path: None,
+    });
+    let pod_spec = render_pod_spec(&pipeline_spec);
+
+    // Vault secrets are fetched by workers, not Kubernetes.
+    assert!(env_var(&pod_spec, "AWS_SECRET_ACCESS_KEY").is_none());
+    let value = env_var(&pod_spec, "FALCONERI_VAULT_SECRETS")
+        .expect("missing FALCONERI_VAULT_SECRETS");
+    let vault_secrets =
+        serde_json::from_str::<VaultSecrets>(value.as_str().unwrap()).unwrap();
+    assert_eq!(vault_secrets.secrets, vec![vault_secret]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs Docker; run using `just test-e2e`"]
+async fn failed_datum_creation_leaves_no_datums() {
+    use falconeri_common::{diesel, diesel_async::RunQueryDsl, testing::TestDatabase};
+
+    let database = TestDatabase::start().await.unwrap();
+    let pool = db::async_pool_for_url(2, database.url()).unwrap();
+    let mut conn = pool.get().await.unwrap();
+    let job_id = Uuid::new_v4();
+    diesel::sql_query(format!(
+        "INSERT INTO jobs (id, pipeline_spec, job_name, command, egress_uri, status) \
+         VALUES ('{}', '{{}}', 'partial', '{{true}}', 'gs://bucket/', 'creating')",
+        job_id,
+    ))
+    .execute(&mut *conn)
+    .await
+    .unwrap();
+    let mut job = Job::find(job_id, &mut conn).await.unwrap();
+
+    // Five datums, inserted two at a time. The fourth datum's input file
+    // points at a datum which doesn't exist, so the second batch fails after
+    // the first has been committed.
+    let datums = (0..5)
+        .map(|i| {
+            let datum = NewDatum {
+                id: Uuid::new_v4(),
+                job_id,
+                maximum_allowed_run_count: 1,
+                status: Status::Ready,
+                input_hash: None,
+                listed_input_bytes: None,
+            };
+            let input_file = NewInputFile {
+                datum_id: if i == 3 { Uuid::new_v4() } else { datum.id },
+                uri: format!("gs://bucket/{}.txt", i),
+                local_path: format!("/pfs/in/{}.txt", i),
+                job_id,
+                etag: None,
+                generation: None,
+                decompress: false,
+                size: None,
+                last_modified: None,
+            };
+            (datum, vec![input_file])
+        })
+        .collect::<Vec<_>>();
+    let err = insert_datums_in_batches(job_id, datums, 2, &mut conn)
+        .await
+        .unwrap_err();
+    let datum_count = |counts: Vec<DatumStatusCount>| -> u64 {
+        counts.iter().map(|c| c.count).sum()
+    };
+    assert_eq!(
+        datum_count(job.datum_status_counts(&mut conn).await.unwrap()),
+        2
+    );
+
+    mark_job_creation_as_error(&pool, &mut job, &err)
+        .await
+        .unwrap();
+    assert_eq!(
+        datum_count(job.datum_status_counts(&mut conn).await.unwrap()),
+        0
+    );
+    assert_eq!(
+        Job::find(job_id, &mut conn).await.unwrap().status,
+        Status::Error
+    );
+}
+
+/// Parse our example pipeline spec, for tests.
+#[cfg(test)]
+fn example_pipeline_spec() -> PipelineSpec {
+    let json = include_str!("../../falconeri_common/src/example_pipeline_spec.json");
+    serde_json::from_str(json).expect("parse error")
+}
+
+/// Render the pod spec of the Kubernetes job we'd create for
+/// `pipeline_spec`, for tests.
+#[cfg(test)]
+fn render_pod_spec(pipeline_spec: &PipelineSpec) -> serde_json::Value {
+    let params = JobParams::new(pipeline_spec, &Job::factory()).unwrap();
+    let manifest = render_manifest(RUN_MANIFEST_TEMPLATE, &params)
+        .expect("error rendering job template");
+    let parsed: serde_json::Value =
+        serde_yaml::from_str(&manifest).expect("rendered invalid YAML");
+    parsed["spec"]["template"]["spec"].clone()
+}
+
+/// Look up the value of the environment variable `name` in the worker
+/// container of `pod_spec`.
+#[cfg(test)]
+fn env_var(pod_spec: &serde_json::Value, name: &str) -> Option<serde_json::Value> {
+    pod_spec["containers"][0]["env"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|v| v["name"] == name)
+        .map(|v| v["value"].clone())
+}
+
+/// Look up the volume `name` in `pod_spec`.
+#[cfg(test)]
+fn volume<'a>(pod_spec: &'a serde_json::Value, name: &str) -> &'a serde_json::Value {
+    pod_spec["volumes"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|v| v["name"] == name)
+        .unwrap_or_else(|| panic!("missing volume {}", name))
+}
@@ -0,0 +1,81 @@
+//! Adding datums to streaming jobs as new input files appear.
+//!
+//! A streaming job stays open until somebody stops it. Each time the scheduler
+//! runs, we list the job's input again, and create a datum for each file we
+//! haven't seen before. Diffing listings works with every storage backend,
+//! without needing to configure bucket notifications, at the cost of listing
+//! the whole input prefix each time.
+
+use falconeri_common::{
+    cast,
+    diesel_async::{scoped_futures::ScopedFutureExt, AsyncConnection},
+    pipeline::{Input, Transform},
+    prelude::*,
+    serde_json,
+};
+
+use crate::inputs::new_streaming_datums;
+
+/// Check the input of a streaming job for new files, and add a datum for each
+/// of them. Returns the number of datums added.
+#[instrument(skip_all, fields(job = %job.id), level = "debug")]
+pub async fn ingest_new_files(
+    job: &mut Job,
+    conn: &mut AsyncPgConnection,
+) -> Result<usize> {
+    // We only need part of the original pipeline spec.
+    let input: Input = serde_json::from_value(job.pipeline_spec["input"].clone())
+        .context("could not parse job input")?;
+    let transform: Transform =
+        serde_json::from_value(job.pipeline_spec["transform"].clone())
+            .context("could not parse job transform")?;
+    let maximum_allowed_run_count =
+        cast::i32(job.pipeline_spec["datum_tries"].as_u64().unwrap_or(1))?;
+
+    // List our input before we take any locks, because this may be slow.
+    let known_uris = InputFile::uris_for_job(job.id, conn).await?;
+    let datums = new_streaming_datums(
+        &transform.secrets,
+        job.id,
+        maximum_allowed_run_count,
+        &input,
+        &known_uris,
+    )
+    .await?;
+    if datums.is_empty() {
+        return Ok(0);
+    }
+
+    // Another copy of the scheduler may be doing the same thing, so lock our
+    // job and check again before inserting anything.
+    conn.transaction(|conn| {
+        async move {
+            job.lock_for_update(conn).await?;
+            if job.status != Status::Streaming {
+                return Ok(0);
+            }
+            let known_uris = InputFile::uris_for_job(job.id, conn).await?;
+            let (new_datums, new_input_files): (Vec<_>, Vec<_>) = datums
+                .into_iter()
+                .filter(|(_, input_files)| {
+                    input_files
+                        .iter()
+                        .all(|input_file| !known_uris.contains(&input_file.uri))
+                })
+                .unzip();
+            if new_datums.is_empty() {
+                return Ok(0);
+            }
+            let new_input_files =
+                new_input_files.into_iter().flatten().collect::<Vec<_>>();
+            NewDatum::copy_in(&new_datums, conn).await?;
+            NewInputFile::copy_in(&new_input_files, conn).await?;
+            let total_datum_count =
+                job.total_datum_count.unwrap_or(0) + cast::i64(new_datums.len())?;
+            job.set_total_datum_count(total_datum_count, conn).await?;
+            Ok::<_, Error>(new_datums.len())
+        }
+        .scope_boxed()
+    })
+    .await
+}
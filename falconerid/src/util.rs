@@ -4,14 +4,22 @@ use std::result;
 
 use axum::{
     extract::FromRequestParts,
-    http::{request::Parts, StatusCode},
+    http::{header::RETRY_AFTER, request::Parts, HeaderValue, StatusCode},
     response::{IntoResponse, Response},
+    Json,
 };
 use falconeri_common::{
     base64::{prelude::BASE64_STANDARD, Engine},
     db, diesel,
-    models::DatumStateError,
+    models::{DatumStateError, DuplicateJob, OutputUriConflict, QuotaExceeded},
     prelude::*,
+    rest_api::{ErrorResponse, FalconeriApiError, WORKER_USERNAME},
+};
+
+use crate::{
+    babysitter::BabysitterHeartbeat, concurrency_limit::ConcurrencyLimiter,
+    db_pool::PoolMonitor, rate_limit::RateLimiter, request_id::current_request_id,
+    start_job::DatumCountError,
 };
 
 /// Shared application state.
@@ -19,17 +27,52 @@ use falconeri_common::{
 pub struct AppState {
     /// Database connection pool.
     pub pool: db::AsyncPool,
+    /// Checks out connections from `pool`, with a deadline.
+    pub pool_monitor: PoolMonitor,
     /// Admin password for authentication.
     pub admin_password: String,
+    /// Lets us check whether our babysitter is still running.
+    pub babysitter_heartbeat: BabysitterHeartbeat,
+    /// Rate limits for worker-facing endpoints.
+    pub rate_limiter: RateLimiter,
+    /// Limits how many worker requests we handle at once.
+    pub concurrency_limiter: ConcurrencyLimiter,
+    /// Should we leave Kubernetes alone, because somebody else is running
+    /// our workers? End-to-end tests set this.
+    pub skip_kubernetes: bool,
+}
+
+/// An authenticated user. Users who know our admin password may do anything,
+/// but users with an API token which belongs to a team may only submit and
+/// change that team's jobs.
+pub struct User {
+    /// The team this user's API token belongs to, if any.
+    team: Option<String>,
 }
 
-/// An authenticated user. For now, this carries no identity information,
-/// because we only distinguish between "authenticated" and "not authenticated",
-/// and we therefore just need a placeholder that represents authentication.
-pub struct User;
+impl User {
+    /// Make sure this user may submit or change jobs belonging to `team`.
+    /// This keeps teams from escaping their quotas by claiming to be another
+    /// team, or no team at all.
+    pub fn check_team(&self, team: Option<&str>) -> FalconeridResult<()> {
+        match &self.team {
+            Some(token_team) if Some(token_team.as_str()) != team => {
+                Err(FalconeridError::Forbidden(format!(
+                    "API token for team {} may not be used for {}",
+                    token_team,
+                    match team {
+                        Some(team) => format!("team {}", team),
+                        None => "jobs without a team".to_owned(),
+                    }
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+}
 
 impl FromRequestParts<AppState> for User {
-    type Rejection = (StatusCode, &'static str);
+    type Rejection = FalconeridError;
 
     async fn from_request_parts(
         parts: &mut Parts,
@@ -40,27 +83,175 @@ impl FromRequestParts<AppState> for User {
             .headers
             .get(http::header::AUTHORIZATION)
             .and_then(|v| v.to_str().ok())
-            .ok_or((StatusCode::UNAUTHORIZED, "missing auth"))?;
+            .ok_or_else(|| FalconeridError::Unauthorized("missing auth".to_owned()))?;
+
+        let credentials = parse_auth_header(header).ok_or_else(|| {
+            FalconeridError::BadRequest("invalid auth header".to_owned())
+        })?;
+
+        // Validate our user. Clients talking to us through an ingress send
+        // an API token instead of our password.
+        let user = match credentials {
+            AuthHeader::Basic { username, password }
+                if is_admin_password(state, &username, &password) =>
+            {
+                Some(User { team: None })
+            }
+            AuthHeader::Basic { .. } => None,
+            AuthHeader::Bearer(token) => {
+                // Give back our connection before the handler asks for one.
+                let mut conn = state.pool_monitor.get(&state.pool).await?;
+                ApiToken::find_valid(&token, &mut conn)
+                    .await?
+                    .map(|api_token| User {
+                        team: api_token.team,
+                    })
+            }
+        };
+        user.ok_or_else(|| {
+            FalconeridError::Unauthorized("invalid credentials".to_owned())
+        })
+    }
+}
+
+/// A user who knows our admin password. Only admins may manage API tokens,
+/// so that a leaked token can't be used to create more.
+pub struct Admin;
+
+impl FromRequestParts<AppState> for Admin {
+    type Rejection = FalconeridError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> result::Result<Self, Self::Rejection> {
+        let header = parts
+            .headers
+            .get(http::header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok());
+        if let Some(AuthHeader::Basic { username, password }) =
+            header.and_then(parse_auth_header)
+        {
+            if is_admin_password(state, &username, &password) {
+                return Ok(Admin);
+            }
+        }
+
+        // Report bad credentials as usual, and anybody else as forbidden.
+        User::from_request_parts(parts, state).await?;
+        Err(FalconeridError::Forbidden(
+            "only the admin password may be used for this".to_owned(),
+        ))
+    }
+}
+
+/// Are `username` and `password` our admin credentials?
+fn is_admin_password(state: &AppState, username: &str, password: &str) -> bool {
+    username == "falconeri"
+        && constant_time_eq(password.as_bytes(), state.admin_password.as_bytes())
+}
+
+/// Compare `a` and `b` without stopping at the first difference, so that
+/// response times don't reveal how much of a password was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Somebody who may call our worker-facing endpoints: either an
+/// authenticated [`User`], or a worker using a token for a single job.
+pub enum Caller {
+    /// The caller is an authenticated [`User`], and may do anything.
+    Admin,
+    /// The caller has a worker token, and may only work on `job_id`.
+    Worker { job_id: Uuid },
+}
+
+impl Caller {
+    /// Make sure the caller may work on `job_id`.
+    pub fn check_job(&self, job_id: Uuid) -> FalconeridResult<()> {
+        match self {
+            Caller::Worker {
+                job_id: token_job_id,
+            } if *token_job_id != job_id => Err(FalconeridError::Forbidden(format!(
+                "worker token for job {} may not be used for job {}",
+                token_job_id, job_id
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Make sure the caller may work on `datum_id`.
+    pub async fn check_datum(
+        &self,
+        datum_id: Uuid,
+        conn: &mut AsyncPgConnection,
+    ) -> FalconeridResult<()> {
+        if let Caller::Worker { .. } = self {
+            let datum = Datum::find(datum_id, conn).await?;
+            self.check_job(datum.job_id)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromRequestParts<AppState> for Caller {
+    type Rejection = FalconeridError;
 
-        let (username, password) = parse_basic_auth(header)
-            .ok_or((StatusCode::BAD_REQUEST, "invalid auth header"))?;
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> result::Result<Self, Self::Rejection> {
+        let header = parts
+            .headers
+            .get(http::header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .ok_or_else(|| FalconeridError::Unauthorized("missing auth".to_owned()))?;
+        let password = match parse_auth_header(header) {
+            Some(AuthHeader::Basic { username, password })
+                if username == WORKER_USERNAME =>
+            {
+                password
+            }
+            _ => {
+                User::from_request_parts(parts, state).await?;
+                return Ok(Caller::Admin);
+            }
+        };
 
-        // Validate our user.
-        if username == "falconeri" && password == state.admin_password {
-            Ok(User)
-        } else {
-            Err((StatusCode::UNAUTHORIZED, "invalid credentials"))
+        // Look up the token, and then give back our connection before the
+        // handler asks for one.
+        let mut conn = state.pool_monitor.get(&state.pool).await?;
+        match WorkerToken::job_id_for_token(&password, &mut conn).await? {
+            Some(job_id) => Ok(Caller::Worker { job_id }),
+            None => Err(FalconeridError::Unauthorized(
+                "invalid or expired worker token".to_owned(),
+            )),
         }
     }
 }
 
-/// Parse HTTP Basic Auth credentials from a header value.
-fn parse_basic_auth(header: &str) -> Option<(String, String)> {
+/// Credentials from an `Authorization` header.
+#[derive(Debug, PartialEq)]
+enum AuthHeader {
+    /// HTTP Basic Auth.
+    Basic { username: String, password: String },
+    /// A bearer token.
+    Bearer(String),
+}
+
+/// Parse HTTP Basic Auth credentials or a bearer token from a header value.
+fn parse_auth_header(header: &str) -> Option<AuthHeader> {
+    if let Some(token) = header.strip_prefix("Bearer ") {
+        return Some(AuthHeader::Bearer(token.trim().to_owned()));
+    }
     let encoded = header.strip_prefix("Basic ")?;
     let decoded = BASE64_STANDARD.decode(encoded).ok()?;
     let credentials = String::from_utf8(decoded).ok()?;
     let (user, pass) = credentials.split_once(':')?;
-    Some((user.to_owned(), pass.to_owned()))
+    Some(AuthHeader::Basic {
+        username: user.to_owned(),
+        password: pass.to_owned(),
+    })
 }
 
 /// A database connection from the pool, extracted automatically by Axum.
@@ -73,9 +264,7 @@ impl FromRequestParts<AppState> for DbConn {
         _parts: &mut Parts,
         state: &AppState,
     ) -> result::Result<Self, Self::Rejection> {
-        let conn = state.pool.get().await.map_err(|e| {
-            FalconeridError::Internal(format_err!("pool error: {}", e))
-        })?;
+        let conn = state.pool_monitor.get(&state.pool).await?;
         Ok(DbConn(conn))
     }
 }
@@ -85,31 +274,148 @@ impl FromRequestParts<AppState> for DbConn {
 pub enum FalconeridError {
     /// Internal server error (500).
     Internal(Error),
+    /// Unauthorized - missing or invalid credentials (401).
+    Unauthorized(String),
     /// Forbidden - ownership verification failed (403).
     Forbidden(String),
+    /// Conflict - the request duplicates existing work (409).
+    Conflict(String),
+    /// Bad request - the request's parameters are invalid (400).
+    BadRequest(String),
+    /// Not found - the requested resource does not exist (404).
+    NotFound(String),
+    /// Too many requests - the caller is over a quota (429).
+    TooManyRequests(String),
+    /// Service unavailable - we're too busy, but the caller may retry (503).
+    ServiceUnavailable(String),
 }
 
 impl IntoResponse for FalconeridError {
     fn into_response(self) -> Response {
-        match self {
+        let (status, code, message) = match self {
             FalconeridError::Internal(err) => {
                 // Log our full error with the error chain using Debug formatting.
                 error!("{:?}", err);
                 // Use Display to avoid leaking backtraces to clients.
                 let payload = format!("{}", err);
-                (StatusCode::INTERNAL_SERVER_ERROR, payload).into_response()
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    FalconeriApiError::Internal,
+                    payload,
+                )
+            }
+            FalconeridError::Unauthorized(msg) => {
+                warn!("Unauthorized: {}", msg);
+                (
+                    StatusCode::UNAUTHORIZED,
+                    FalconeriApiError::Unauthorized,
+                    msg,
+                )
             }
             FalconeridError::Forbidden(msg) => {
                 warn!("Forbidden: {}", msg);
-                (StatusCode::FORBIDDEN, msg).into_response()
+                (StatusCode::FORBIDDEN, FalconeriApiError::Forbidden, msg)
+            }
+            FalconeridError::Conflict(msg) => {
+                warn!("Conflict: {}", msg);
+                (StatusCode::CONFLICT, FalconeriApiError::Conflict, msg)
             }
+            FalconeridError::BadRequest(msg) => {
+                warn!("Bad request: {}", msg);
+                (StatusCode::BAD_REQUEST, FalconeriApiError::BadRequest, msg)
+            }
+            FalconeridError::NotFound(msg) => {
+                warn!("Not found: {}", msg);
+                (StatusCode::NOT_FOUND, FalconeriApiError::NotFound, msg)
+            }
+            FalconeridError::TooManyRequests(msg) => {
+                warn!("Too many requests: {}", msg);
+                (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    FalconeriApiError::RateLimited,
+                    msg,
+                )
+            }
+            FalconeridError::ServiceUnavailable(msg) => {
+                warn!("Service unavailable: {}", msg);
+                (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    FalconeriApiError::Unavailable,
+                    msg,
+                )
+            }
+        };
+        let mut response = error_response(status, code, message);
+        if status == StatusCode::SERVICE_UNAVAILABLE {
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from_static("1"));
         }
+        response
     }
 }
 
+/// Build a JSON [`ErrorResponse`] for the current request.
+pub fn error_response(
+    status: StatusCode,
+    code: FalconeriApiError,
+    message: String,
+) -> Response {
+    let body = ErrorResponse {
+        code,
+        message,
+        request_id: current_request_id(),
+    };
+    (status, Json(body)).into_response()
+}
+
 impl From<Error> for FalconeridError {
     fn from(err: Error) -> Self {
-        FalconeridError::Internal(err)
+        // Quota errors may come from deep inside job creation, so look for
+        // them here. Likewise for output URI conflicts, which may be caught
+        // by a database constraint, duplicate jobs, which we check for while
+        // inserting the new job, and implausible datum counts, which we check
+        // while listing its inputs.
+        let err = match err.downcast::<QuotaExceeded>() {
+            Ok(exceeded) => return exceeded.into(),
+            Err(err) => err,
+        };
+        let err = match err.downcast::<DuplicateJob>() {
+            Ok(duplicate) => return duplicate.into(),
+            Err(err) => err,
+        };
+        let err = match err.downcast::<DatumCountError>() {
+            Ok(count_err) => return count_err.into(),
+            Err(err) => err,
+        };
+        match err.downcast::<OutputUriConflict>() {
+            Ok(conflict) => conflict.into(),
+            Err(err) => FalconeridError::Internal(err),
+        }
+    }
+}
+
+impl From<DatumCountError> for FalconeridError {
+    fn from(err: DatumCountError) -> Self {
+        FalconeridError::BadRequest(err.to_string())
+    }
+}
+
+impl From<DuplicateJob> for FalconeridError {
+    fn from(err: DuplicateJob) -> Self {
+        FalconeridError::Conflict(err.to_string())
+    }
+}
+
+impl From<OutputUriConflict> for FalconeridError {
+    fn from(err: OutputUriConflict) -> Self {
+        FalconeridError::Conflict(err.to_string())
+    }
+}
+
+impl From<QuotaExceeded> for FalconeridError {
+    fn from(err: QuotaExceeded) -> Self {
+        FalconeridError::TooManyRequests(err.to_string())
     }
 }
 
@@ -127,3 +433,54 @@ impl From<diesel::result::Error> for FalconeridError {
 
 /// The result type of `falconerid` handler.
 pub type FalconeridResult<T> = result::Result<T, FalconeridError>;
+
+#[test]
+fn parse_auth_headers() {
+    assert_eq!(
+        parse_auth_header("Basic ZmFsY29uZXJpOnNlY3JldA=="),
+        Some(AuthHeader::Basic {
+            username: "falconeri".to_owned(),
+            password: "secret".to_owned(),
+        })
+    );
+    assert_eq!(
+        parse_auth_header("Bearer secret"),
+        Some(AuthHeader::Bearer("secret".to_owned()))
+    );
+    assert_eq!(parse_auth_header("Basic !!!"), None);
+    assert_eq!(parse_auth_header("Digest secret"), None);
+}
+
+#[test]
+fn constant_time_eq_compares_bytes() {
+    assert!(constant_time_eq(b"secret", b"secret"));
+    assert!(!constant_time_eq(b"secret", b"secreT"));
+    assert!(!constant_time_eq(b"secret", b"secret2"));
+    assert!(constant_time_eq(b"", b""));
+}
+
+#[test]
+fn duplicate_jobs_are_conflicts() {
+    let err = Error::from(DuplicateJob {
+        job_name: "my-job-abc12".to_owned(),
+    })
+    .context("could not start job");
+    match FalconeridError::from(err) {
+        FalconeridError::Conflict(msg) => assert!(msg.contains("my-job-abc12")),
+        other => panic!("expected conflict, got {:?}", other),
+    }
+}
+
+#[test]
+fn team_tokens_may_only_be_used_for_their_team() {
+    let admin = User { team: None };
+    assert!(admin.check_team(None).is_ok());
+    assert!(admin.check_team(Some("search")).is_ok());
+
+    let search = User {
+        team: Some("search".to_owned()),
+    };
+    assert!(search.check_team(Some("search")).is_ok());
+    assert!(search.check_team(Some("ads")).is_err());
+    assert!(search.check_team(None).is_err());
+}